@@ -65,6 +65,9 @@ def external_crates_repository(name, annotations):
             "backoff": crate.spec(
                 version = "^0.3.0",
             ),
+            "backtrace": crate.spec(
+                version = "^0.3.61",
+            ),
             "base32": crate.spec(
                 version = "^0.4.0",
             ),
@@ -117,6 +120,10 @@ def external_crates_repository(name, annotations):
                 ],
                 default_features = False,
             ),
+            "bs58": crate.spec(
+                version = "^0.4.0",
+                features = ["check"],
+            ),
             "build-info": crate.spec(
                 git = "https://github.com/dfinity-lab/build-info",
                 rev = "abb2971c5d07a9b40d41a0c84b63a3156f2ff764",
@@ -303,7 +310,7 @@ def external_crates_repository(name, annotations):
                 version = "^1.0",
             ),
             "hyper": crate.spec(
-                version = "^0.14.18",
+                version = "^0.14.19",
                 features = [
                     "client",
                     "full",
@@ -430,6 +437,9 @@ def external_crates_repository(name, annotations):
             "maplit": crate.spec(
                 version = "^1.0.2",
             ),
+            "maxminddb": crate.spec(
+                version = "^0.23.0",
+            ),
             "mersenne_twister": crate.spec(
                 version = "^1.1.1",
             ),
@@ -1015,6 +1025,9 @@ def external_crates_repository(name, annotations):
                     "zeroize_derive",
                 ],
             ),
+            "zstd": crate.spec(
+                version = "^0.11.2",
+            ),
         },
         splicing_config = splicing_config(
             resolver_version = "2",
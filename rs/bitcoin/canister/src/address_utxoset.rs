@@ -1,6 +1,10 @@
-use crate::{state::UtxoSet, types::Storable, utxos::UtxosTrait};
+use crate::{
+    state::UtxoSet,
+    types::{Height, Storable},
+    utxos::UtxosTrait,
+};
 use bitcoin::{Address, OutPoint, Transaction, TxOut};
-use ic_btc_types::{Address as AddressStr, Height, Utxo};
+use ic_btc_types::{Address as AddressStr, Utxo};
 use std::collections::{BTreeMap, BTreeSet};
 
 /// A struct that tracks the UTXO set of a given address.
@@ -164,7 +168,7 @@ impl<'a> AddressUtxoSet<'a> {
                         vout: outpoint.vout,
                     },
                     value: txout.value,
-                    height,
+                    height: height.into(),
                 }
             })
             .collect()
@@ -211,7 +215,7 @@ mod test {
                     vout: 0
                 },
                 value: 1000,
-                height: 0
+                height: 0.into()
             }]
         );
     }
@@ -265,7 +269,7 @@ mod test {
                     vout: 0
                 },
                 value: 1000,
-                height: 1
+                height: 1.into()
             }]
         );
     }
@@ -340,7 +344,7 @@ mod test {
                     vout: 1
                 },
                 value: 400,
-                height: 1
+                height: 1.into()
             }]
         );
 
@@ -353,7 +357,7 @@ mod test {
                     vout: 0
                 },
                 value: 1500,
-                height: 1
+                height: 1.into()
             }]
         );
     }
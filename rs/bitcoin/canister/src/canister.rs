@@ -1,6 +1,5 @@
 pub use crate::fees::get_current_fee_percentiles;
 use crate::{metrics::BitcoinCanisterMetrics, state::State, store};
-use bitcoin::{util::psbt::serialize::Deserialize, Transaction};
 use ic_btc_types::{
     GetBalanceError, GetUtxosError, GetUtxosResponse, SendTransactionError, SendTransactionRequest,
     UtxosFilter,
@@ -87,9 +86,7 @@ pub fn send_transaction(
     state: &mut State,
     request: SendTransactionRequest,
 ) -> Result<(), SendTransactionError> {
-    if Transaction::deserialize(&request.transaction).is_err() {
-        return Err(SendTransactionError::MalformedTransaction);
-    }
+    request.decode_transaction()?;
 
     match state
         .adapter_queues
@@ -163,11 +160,14 @@ mod test {
                             vout: 0
                         },
                         value: 1000,
-                        height: 0,
+                        height: 0.into(),
                     }],
                     tip_block_hash: genesis_block.block_hash().to_vec(),
                     tip_height: 0,
                     next_page: None,
+                    stability_count: Some(0),
+                    total_count: Some(1),
+                    has_more: Some(false),
                 })
             );
         }
@@ -328,11 +328,14 @@ mod test {
                                 vout: 0,
                             },
                             value: 1000,
-                            height: 1,
+                            height: 1.into(),
                         }],
                         tip_block_hash: block_1.block_hash().to_vec(),
                         tip_height: 1,
                         next_page: None,
+                        stability_count: Some(0),
+                        total_count: Some(1),
+                        has_more: Some(false),
                     })
                 );
 
@@ -347,6 +350,9 @@ mod test {
                         tip_block_hash: block_1.block_hash().to_vec(),
                         tip_height: 1,
                         next_page: None,
+                        stability_count: Some(0),
+                        total_count: Some(0),
+                        has_more: Some(false),
                     })
                 );
             }
@@ -364,6 +370,9 @@ mod test {
                     tip_block_hash: block_0.block_hash().to_vec(),
                     tip_height: 0,
                     next_page: None,
+                    stability_count: Some(1),
+                    total_count: Some(0),
+                    has_more: Some(false),
                 })
             );
             assert_eq!(
@@ -379,11 +388,14 @@ mod test {
                             vout: 0,
                         },
                         value: 1000,
-                        height: 0,
+                        height: 0.into(),
                     }],
                     tip_block_hash: block_0.block_hash().to_vec(),
                     tip_height: 0,
                     next_page: None,
+                    stability_count: Some(1),
+                    total_count: Some(1),
+                    has_more: Some(false),
                 })
             );
 
@@ -494,6 +506,9 @@ mod test {
                     tip_block_hash: blocks.last().unwrap().block_hash().to_vec(),
                     tip_height: num_blocks as u32 - 1,
                     next_page: None,
+                    stability_count: Some(0),
+                    total_count: Some((num_blocks + 1) / 2),
+                    has_more: Some(false),
                 })
             );
 
@@ -504,6 +519,9 @@ mod test {
                     tip_block_hash: blocks.last().unwrap().block_hash().to_vec(),
                     tip_height: num_blocks as u32 - 1,
                     next_page: None,
+                    stability_count: Some(0),
+                    total_count: Some(num_blocks / 2),
+                    has_more: Some(false),
                 })
             );
         }
@@ -519,7 +537,10 @@ mod test {
                     network: BtcTypesNetwork::Testnet,
                 }
             ),
-            Err(SendTransactionError::MalformedTransaction)
+            Err(SendTransactionError::MalformedTransaction {
+                offset: 3,
+                reason: "unexpected end of transaction".to_string(),
+            })
         );
     }
 
@@ -577,11 +598,14 @@ mod test {
                             vout: 0,
                         },
                         value: 1000,
-                        height: 0,
+                        height: 0.into(),
                     }],
                     tip_block_hash: block_0.block_hash().to_vec(),
                     tip_height: 0,
                     next_page: None,
+                    stability_count: Some(0),
+                    total_count: Some(1),
+                    has_more: Some(false),
                 })
             );
         }
@@ -90,9 +90,9 @@ fn main() {
                 }
             };
             if args.network == Network::Bitcoin {
-                assert_eq!(magic, 0xD9B4BEF9);
+                assert_eq!(magic, ic_btc_types::consts::MAINNET.magic_u32());
             } else {
-                assert_eq!(magic, 0x0709110b);
+                assert_eq!(magic, ic_btc_types::consts::TESTNET.magic_u32());
             }
 
             let _block_size = blk_file.read_u32::<LittleEndian>().unwrap();
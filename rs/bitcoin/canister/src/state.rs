@@ -1,6 +1,5 @@
-use crate::{proto, PageMapMemory};
+use crate::{proto, types::Height, PageMapMemory};
 use bitcoin::{hashes::Hash, Block, Network, OutPoint, Script, TxOut, Txid};
-use ic_btc_types::Height;
 use ic_protobuf::bitcoin::v1;
 use ic_replicated_state::bitcoin_state::{
     AdapterQueues, BitcoinState as ReplicatedBitcoinState, FeePercentilesCache, UnstableBlocks,
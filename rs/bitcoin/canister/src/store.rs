@@ -1,11 +1,11 @@
 use crate::{
     blocktree::{BlockChain, BlockDoesNotExtendTree},
     state::State,
-    types::Page,
+    types::{Height, Page},
     unstable_blocks, utxoset,
 };
 use bitcoin::{hashes::Hash, Address, Block, OutPoint, Txid};
-use ic_btc_types::{GetBalanceError, GetUtxosError, GetUtxosResponse, Height, Satoshi};
+use ic_btc_types::{GetBalanceError, GetUtxosError, GetUtxosResponse, Satoshi};
 use lazy_static::lazy_static;
 use serde_bytes::ByteBuf;
 use std::str::FromStr;
@@ -78,6 +78,20 @@ pub fn get_utxos(
                     .ok_or(GetUtxosError::UnknownTipBlockHash {
                         tip_block_hash: tip_block_hash.to_vec(),
                     })?;
+            // Pages computed on a chain that is no longer the main chain
+            // would be inconsistent with the previous pages.
+            let main_chain = unstable_blocks::get_main_chain(&state.unstable_blocks);
+            let current_tip_block_hash = main_chain.tip().block_hash();
+            if !main_chain
+                .into_chain()
+                .iter()
+                .any(|block| block.block_hash() == tip_block_hash)
+            {
+                return Err(GetUtxosError::TipChanged {
+                    tip_block_hash: tip_block_hash.to_vec(),
+                    current_tip_block_hash: current_tip_block_hash.to_vec(),
+                });
+            }
             get_utxos_from_chain(
                 state,
                 address,
@@ -123,7 +137,8 @@ fn get_utxos_from_chain(
     // Apply unstable blocks to the UTXO set.
     for (i, block) in chain.into_chain().iter().enumerate() {
         let block_height = state.height + (i as u32);
-        let confirmations = chain_height - block_height + 1;
+        let confirmations =
+            ic_btc_types::Height::from(block_height).confirmations(chain_height.into());
 
         if confirmations < min_confirmations {
             // The block has fewer confirmations than requested.
@@ -139,7 +154,11 @@ fn get_utxos_from_chain(
         tip_block_height = block_height;
     }
 
+    let is_first_page = offset.is_none();
     let all_utxos = address_utxos.into_vec(offset);
+    // Counting the UTXOs of the previous pages would mean reading them again,
+    // so the total is only reported on the first page.
+    let total_count = is_first_page.then(|| all_utxos.len() as u64);
     let mut next_page = None;
 
     let utxos = match utxo_limit {
@@ -154,7 +173,7 @@ fn get_utxos_from_chain(
                 next_page = Some(
                     Page {
                         tip_block_hash,
-                        height: rest[0].height,
+                        height: rest[0].height.get(),
                         outpoint: OutPoint {
                             txid: Txid::from_hash(
                                 Hash::from_slice(&rest[0].outpoint.txid)
@@ -175,7 +194,10 @@ fn get_utxos_from_chain(
         utxos,
         tip_block_hash: tip_block_hash.to_vec(),
         tip_height: tip_block_height,
+        has_more: Some(next_page.is_some()),
         next_page: next_page.map(ByteBuf::from),
+        stability_count: Some(chain_height - tip_block_height),
+        total_count,
     })
 }
 
@@ -246,7 +268,7 @@ mod test {
                     magic
                 }
             };
-            assert_eq!(magic, 0xD9B4BEF9);
+            assert_eq!(magic, ic_btc_types::consts::MAINNET.magic_u32());
 
             let _block_size = blk_file.read_u32::<LittleEndian>().unwrap();
 
@@ -373,11 +395,14 @@ mod test {
                     vout: 0,
                 },
                 value: 1000,
-                height: 0,
+                height: 0.into(),
             }],
             tip_block_hash: block_0.block_hash().to_vec(),
             tip_height: 0,
             next_page: None,
+            stability_count: Some(0),
+            total_count: Some(1),
+            has_more: Some(false),
         };
 
         // Assert that the UTXOs of address 1 are present.
@@ -407,11 +432,14 @@ mod test {
                         vout: 0,
                     },
                     value: 1000,
-                    height: 1,
+                    height: 1.into(),
                 }],
                 tip_block_hash: block_1.block_hash().to_vec(),
                 tip_height: 1,
                 next_page: None,
+                stability_count: Some(0),
+                total_count: Some(1),
+                has_more: Some(false),
             })
         );
 
@@ -422,6 +450,9 @@ mod test {
                 tip_block_hash: block_1.block_hash().to_vec(),
                 tip_height: 1,
                 next_page: None,
+                stability_count: Some(0),
+                total_count: Some(0),
+                has_more: Some(false),
             })
         );
 
@@ -445,6 +476,9 @@ mod test {
                 tip_block_hash: block_0.block_hash().to_vec(),
                 tip_height: 0,
                 next_page: None,
+                stability_count: Some(0),
+                total_count: Some(0),
+                has_more: Some(false),
             })
         );
         assert_eq!(
@@ -454,6 +488,9 @@ mod test {
                 tip_block_hash: block_0.block_hash().to_vec(),
                 tip_height: 0,
                 next_page: None,
+                stability_count: Some(0),
+                total_count: Some(0),
+                has_more: Some(false),
             })
         );
         assert_eq!(
@@ -481,6 +518,9 @@ mod test {
                 tip_block_hash: block_2_prime.block_hash().to_vec(),
                 tip_height: 2,
                 next_page: None,
+                stability_count: Some(0),
+                total_count: Some(0),
+                has_more: Some(false),
             })
         );
         assert_eq!(
@@ -490,6 +530,9 @@ mod test {
                 tip_block_hash: block_2_prime.block_hash().to_vec(),
                 tip_height: 2,
                 next_page: None,
+                stability_count: Some(0),
+                total_count: Some(0),
+                has_more: Some(false),
             })
         );
         assert_eq!(
@@ -499,6 +542,9 @@ mod test {
                 tip_block_hash: block_2_prime.block_hash().to_vec(),
                 tip_height: 2,
                 next_page: None,
+                stability_count: Some(0),
+                total_count: Some(0),
+                has_more: Some(false),
             })
         );
         // The funds are now with address 4.
@@ -511,11 +557,14 @@ mod test {
                         vout: 0,
                     },
                     value: 1000,
-                    height: 2,
+                    height: 2.into(),
                 }],
                 tip_block_hash: block_2_prime.block_hash().to_vec(),
                 tip_height: 2,
                 next_page: None,
+                stability_count: Some(0),
+                total_count: Some(1),
+                has_more: Some(false),
             })
         );
     }
@@ -559,7 +608,7 @@ mod test {
                         vout: 1,
                     },
                     value: 4000000,
-                    height: 75361,
+                    height: 75361.into(),
                 }],
                 // The tip should be the block hash at height 100,000
                 // https://bitcoinchain.com/block_explorer/block/100000/
@@ -570,6 +619,9 @@ mod test {
                 .to_vec(),
                 tip_height: 100_000,
                 next_page: None,
+                stability_count: Some(0),
+                total_count: Some(1),
+                has_more: Some(false),
             })
         );
 
@@ -591,7 +643,7 @@ mod test {
                         vout: 0,
                     },
                     value: 500000000,
-                    height: 66184,
+                    height: 66184.into(),
                 }],
                 // The tip should be the block hash at height 100,000
                 // https://bitcoinchain.com/block_explorer/block/100000/
@@ -602,6 +654,9 @@ mod test {
                 .to_vec(),
                 tip_height: 100_000,
                 next_page: None,
+                stability_count: Some(0),
+                total_count: Some(1),
+                has_more: Some(false),
             })
         );
 
@@ -637,7 +692,7 @@ mod test {
                         vout: 1,
                     },
                     value: 48_0000_0000,
-                    height: 96778,
+                    height: 96778.into(),
                 }],
                 // The tip should be the block hash at height 99,995
                 // https://blockchair.com/bitcoin/block/99995
@@ -648,6 +703,9 @@ mod test {
                 .to_vec(),
                 tip_height: 99_995,
                 next_page: None,
+                stability_count: Some(5),
+                total_count: Some(1),
+                has_more: Some(false),
             })
         );
 
@@ -715,11 +773,14 @@ mod test {
                             vout: 0
                         },
                         value: 1000,
-                        height: 0,
+                        height: 0.into(),
                     }],
                     tip_block_hash: block_0.block_hash().to_vec(),
                     tip_height: 0,
                     next_page: None,
+                    stability_count: Some(0),
+                    total_count: Some(1),
+                    has_more: Some(false),
                 })
             );
             assert_eq!(
@@ -779,11 +840,59 @@ mod test {
                     tip_block_hash: block_1.block_hash().to_vec(),
                     tip_height: 1,
                     next_page: None,
+                    stability_count: Some(0),
+                    total_count: Some(0),
+                    has_more: Some(false),
                 })
             );
         }
     }
 
+    #[test]
+    fn get_utxos_with_page_on_reorged_chain_returns_tip_changed() {
+        let address = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2pkh(
+                &PublicKey::new(secp.generate_keypair(&mut rng).1),
+                Network::Bitcoin,
+            )
+        };
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header).build();
+        let block_1_prime = BlockBuilder::with_prev_header(block_0.header).build();
+        let block_2_prime = BlockBuilder::with_prev_header(block_1_prime.header).build();
+
+        let mut state = State::new(3, Network::Bitcoin, block_0);
+        insert_block(&mut state, block_1.clone()).unwrap();
+
+        // A page computed while block 1 was the tip.
+        let page = Page {
+            tip_block_hash: block_1.block_hash(),
+            height: 0,
+            outpoint: bitcoin::OutPoint::new(coinbase_tx.txid(), 0),
+        }
+        .to_bytes();
+        assert!(get_utxos(&state, &address.to_string(), 0, Some(page.clone()), None).is_ok());
+
+        // The fork of [block 1', block 2'] becomes the main chain.
+        insert_block(&mut state, block_1_prime).unwrap();
+        insert_block(&mut state, block_2_prime.clone()).unwrap();
+
+        assert_eq!(
+            get_utxos(&state, &address.to_string(), 0, Some(page), None),
+            Err(GetUtxosError::TipChanged {
+                tip_block_hash: block_1.block_hash().to_vec(),
+                current_tip_block_hash: block_2_prime.block_hash().to_vec(),
+            })
+        );
+    }
+
     #[test]
     fn get_utxos_for_address_with_many_of_them_respects_utxo_limit() {
         for network in [
@@ -856,6 +965,21 @@ mod test {
             assert_eq!(response.tip_block_hash, tip_block_hash.clone().to_vec());
             assert_eq!(response.tip_height, 0);
             assert!(response.next_page.is_some());
+            assert_eq!(response.total_count, Some(num_transactions));
+            assert_eq!(response.has_more, Some(true));
+
+            // The total is only reported on the first page.
+            let response = get_utxos(
+                &state,
+                &address.to_string(),
+                0,
+                response.next_page.map(|page| page.to_vec()),
+                Some(4),
+            )
+            .unwrap();
+            assert_eq!(response.utxos.len(), 4);
+            assert_eq!(response.total_count, None);
+            assert_eq!(response.has_more, Some(true));
 
             // A very big limit will result in the same as requesting UTXOs without any limit.
             let response = get_utxos(&state, &address.to_string(), 0, None, Some(1000)).unwrap();
@@ -865,6 +989,8 @@ mod test {
             assert_eq!(response.tip_block_hash, tip_block_hash.clone().to_vec());
             assert_eq!(response.tip_height, 0);
             assert!(response.next_page.is_none());
+            assert_eq!(response.total_count, Some(num_transactions));
+            assert_eq!(response.has_more, Some(false));
         }
     }
 
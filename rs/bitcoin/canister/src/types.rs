@@ -1,9 +1,13 @@
 //! Types that are private to the crate.
 use crate::state::UTXO_KEY_SIZE;
 use bitcoin::{hashes::Hash, BlockHash, OutPoint, Script, TxOut, Txid};
-use ic_btc_types::{Address, Height};
+use ic_btc_types::Address;
 use std::convert::TryInto;
 
+/// The height of a block as stored in the state of the canister. Converted to
+/// [`ic_btc_types::Height`] in responses.
+pub type Height = u32;
+
 /// Used to signal the cut-off point for returning chunked UTXOs results.
 pub struct Page {
     pub tip_block_hash: BlockHash,
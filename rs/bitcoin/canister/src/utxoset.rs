@@ -1,10 +1,12 @@
 use crate::address_utxoset::AddressUtxoSet;
-use crate::{state::UtxoSet, types::Storable, utxos::UtxosTrait};
+use crate::{
+    state::UtxoSet,
+    types::{Height, Storable},
+    utxos::UtxosTrait,
+};
 use bitcoin::{Address, OutPoint, Transaction, TxOut, Txid};
 use std::str::FromStr;
 
-type Height = u32;
-
 lazy_static::lazy_static! {
     static ref DUPLICATE_TX_IDS: [Txid; 2] = [
         Txid::from_str("d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599").unwrap(),
@@ -143,7 +145,7 @@ mod test {
                         vout: 0,
                     },
                     value: 1000,
-                    height: 0,
+                    height: 0.into(),
                 }]
             );
         }
@@ -205,7 +207,7 @@ mod test {
                     vout: 0,
                 },
                 value: 1000,
-                height: 0,
+                height: 0.into(),
             }];
 
             assert_eq!(
@@ -244,7 +246,7 @@ mod test {
                         vout: 0
                     },
                     value: 1000,
-                    height: 1
+                    height: 1.into()
                 }]
             );
             assert_eq!(
@@ -51,11 +51,7 @@ fn derive_public_key(account: Account) -> ECDSAPublicKey {
 
 /// Returns the human-readable part of a bech32 address
 pub fn hrp<'a>(network: Network) -> &'a str {
-    match network {
-        ic_btc_types::Network::Mainnet => "bc",
-        ic_btc_types::Network::Testnet => "tb",
-        ic_btc_types::Network::Regtest => "bcrt",
-    }
+    ic_btc_types::consts::consts(network).bech32_hrp
 }
 
 /// Calculates the p2wpkh address as described in [BIP-0173](https://github.com/bitcoin/bips/blob/master/bip-0173.mediawiki).
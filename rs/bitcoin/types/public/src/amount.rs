@@ -0,0 +1,181 @@
+//! Conversions of amounts in satoshi from and to decimal amounts in bitcoin.
+//!
+//! Amounts are converted digit by digit, without going through floats, which
+//! can't represent most amounts of satoshi exactly:
+//!
+//! ```
+//! use ic_btc_types::{amount::BtcAmount, Satoshi};
+//!
+//! let balance: Satoshi = 10_000_000;
+//! assert_eq!(balance.to_btc_string(), "0.1");
+//! assert_eq!(Satoshi::from_btc_str("0.1"), Ok(balance));
+//! ```
+
+use crate::Satoshi;
+
+/// The number of satoshi in a bitcoin.
+pub const SATOSHI_PER_BTC: Satoshi = 100_000_000;
+
+/// The number of decimal places of an amount in bitcoin.
+const BTC_DECIMAL_PLACES: usize = 8;
+
+/// Formatting and parsing of amounts in satoshi as amounts in bitcoin.
+pub trait BtcAmount: Sized {
+    /// Returns the amount in bitcoin, without trailing zeros, e.g. `"1.5"` for
+    /// 150'000'000 satoshi and `"2"` for 200'000'000.
+    fn to_btc_string(&self) -> String;
+
+    /// Parses an amount in bitcoin, with at most 8 decimal places, e.g.
+    /// `"0.00012"` as 12'000 satoshi.
+    fn from_btc_str(s: &str) -> Result<Self, ParseAmountError>;
+}
+
+impl BtcAmount for Satoshi {
+    fn to_btc_string(&self) -> String {
+        Btc(*self).to_string()
+    }
+
+    fn from_btc_str(s: &str) -> Result<Self, ParseAmountError> {
+        let (whole, fraction) = match s.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (s, ""),
+        };
+        let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if whole.is_empty()
+            || !is_digits(whole)
+            || !is_digits(fraction)
+            || (s.ends_with('.') && fraction.is_empty())
+        {
+            return Err(ParseAmountError::Invalid);
+        }
+        // Trailing zeros beyond the last decimal place don't add precision.
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.len() > BTC_DECIMAL_PLACES {
+            return Err(ParseAmountError::TooPrecise);
+        }
+        let digits = whole
+            .bytes()
+            .chain(fraction.bytes())
+            .chain(std::iter::repeat(b'0').take(BTC_DECIMAL_PLACES - fraction.len()));
+        digits.fold(Ok(0), |amount: Result<Satoshi, _>, digit| {
+            amount?
+                .checked_mul(10)
+                .and_then(|amount| amount.checked_add(Satoshi::from(digit - b'0')))
+                .ok_or(ParseAmountError::TooLarge)
+        })
+    }
+}
+
+/// An amount in satoshi, displayed in bitcoin as by
+/// [`BtcAmount::to_btc_string`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Btc(pub Satoshi);
+
+impl std::fmt::Display for Btc {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let whole = self.0 / SATOSHI_PER_BTC;
+        let fraction = self.0 % SATOSHI_PER_BTC;
+        if fraction == 0 {
+            write!(f, "{}", whole)
+        } else {
+            let fraction = format!("{:08}", fraction);
+            write!(f, "{}.{}", whole, fraction.trim_end_matches('0'))
+        }
+    }
+}
+
+/// An error parsing an amount in bitcoin.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseAmountError {
+    /// The amount is not a decimal number, e.g. it is empty, signed, or has
+    /// an exponent.
+    Invalid,
+    /// The amount has more than 8 decimal places.
+    TooPrecise,
+    /// The amount does not fit in a `u64` of satoshi.
+    TooLarge,
+}
+
+impl std::fmt::Display for ParseAmountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "invalid amount"),
+            Self::TooPrecise => write!(f, "amount has more than 8 decimal places"),
+            Self::TooLarge => write!(f, "amount too large"),
+        }
+    }
+}
+
+impl std::error::Error for ParseAmountError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_are_formatted_without_trailing_zeros() {
+        let cases: [(Satoshi, &str); 6] = [
+            (0, "0"),
+            (1, "0.00000001"),
+            (12_000, "0.00012"),
+            (150_000_000, "1.5"),
+            (200_000_000, "2"),
+            (Satoshi::MAX, "184467440737.09551615"),
+        ];
+        for (amount, btc) in cases {
+            assert_eq!(amount.to_btc_string(), btc);
+            assert_eq!(Btc(amount).to_string(), btc);
+        }
+    }
+
+    #[test]
+    fn amounts_are_parsed_exactly() {
+        assert_eq!(Satoshi::from_btc_str("0"), Ok(0));
+        assert_eq!(Satoshi::from_btc_str("0.00000001"), Ok(1));
+        assert_eq!(Satoshi::from_btc_str("0.1"), Ok(10_000_000));
+        assert_eq!(Satoshi::from_btc_str("1.50"), Ok(150_000_000));
+        assert_eq!(
+            Satoshi::from_btc_str("21000000"),
+            Ok(21_000_000 * SATOSHI_PER_BTC)
+        );
+        // 0.29 has no exact float representation.
+        assert_eq!(Satoshi::from_btc_str("0.29"), Ok(29_000_000));
+        assert_eq!(
+            Satoshi::from_btc_str("0.000000010"),
+            Ok(1),
+            "trailing zeros are not too precise"
+        );
+        assert_eq!(
+            Satoshi::from_btc_str("184467440737.09551615"),
+            Ok(Satoshi::MAX)
+        );
+    }
+
+    #[test]
+    fn formatted_amounts_are_parsed_back() {
+        let amounts: [Satoshi; 6] = [0, 1, 12_000, 29_000_000, 150_000_000, Satoshi::MAX];
+        for amount in amounts {
+            assert_eq!(Satoshi::from_btc_str(&amount.to_btc_string()), Ok(amount));
+        }
+    }
+
+    #[test]
+    fn invalid_amounts_are_rejected() {
+        for s in ["", ".", ".5", "1.", "-1", "+1", "1e8", "1.2.3", " 1", "1,5"] {
+            assert_eq!(
+                Satoshi::from_btc_str(s),
+                Err(ParseAmountError::Invalid),
+                "{:?}",
+                s
+            );
+        }
+        assert_eq!(
+            Satoshi::from_btc_str("0.000000001"),
+            Err(ParseAmountError::TooPrecise)
+        );
+        assert_eq!(
+            Satoshi::from_btc_str("184467440737.09551616"),
+            Err(ParseAmountError::TooLarge)
+        );
+    }
+}
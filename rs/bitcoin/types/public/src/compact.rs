@@ -0,0 +1,292 @@
+//! A compact encoding of UTXOs, for addresses with many of them.
+//!
+//! Candid encodes every `Utxo` with its full transaction id and fixed-width
+//! integers, i.e. in ~50 bytes. Addresses of exchanges hold hundreds of
+//! thousands of UTXOs, many of them created by the same transactions and in
+//! nearby blocks. [`CompactUtxos`] lists every transaction id once, in a table
+//! referenced by index, and encodes the UTXOs as LEB128 varints, with their
+//! heights as deltas to the height of the previous UTXO.
+//!
+//! Callers opt in by setting `compact` in the `GetUtxosRequest`, and then
+//! receive a [`GetUtxosCompactResponse`].
+
+use crate::{BlockHash, GetUtxosResponse, Height, OutPoint, Page, Satoshi, Utxo};
+use candid::{CandidType, Deserialize};
+use serde_bytes::ByteBuf;
+use std::{collections::HashMap, convert::TryFrom};
+
+/// A UTXO, with its transaction id and height relative to a [`CompactUtxos`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CompactUtxo {
+    /// The index of the transaction id in `CompactUtxos::txids`.
+    pub txid_index: u32,
+    pub vout: u32,
+    pub value: Satoshi,
+    /// The height of the UTXO minus the height of the previous one, or minus
+    /// zero for the first UTXO.
+    pub height_delta: i64,
+}
+
+impl CompactUtxo {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        write_varint(bytes, self.txid_index as u64);
+        write_varint(bytes, self.vout as u64);
+        write_varint(bytes, self.value);
+        // Zigzag encoding, so that small negative deltas are small varints.
+        write_varint(
+            bytes,
+            ((self.height_delta << 1) ^ (self.height_delta >> 63)) as u64,
+        );
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Self, CompactUtxosError> {
+        let txid_index = read_varint(bytes)?;
+        let vout = read_varint(bytes)?;
+        let value = read_varint(bytes)?;
+        let zigzag = read_varint(bytes)?;
+        Ok(Self {
+            txid_index: u32::try_from(txid_index).map_err(|_| CompactUtxosError::Malformed)?,
+            vout: u32::try_from(vout).map_err(|_| CompactUtxosError::Malformed)?,
+            value,
+            height_delta: ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64),
+        })
+    }
+}
+
+/// A list of UTXOs in the compact encoding.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+pub struct CompactUtxos {
+    /// The distinct transaction ids of the UTXOs, in order of first use.
+    pub txids: Vec<ByteBuf>,
+    /// The concatenated encodings of the [`CompactUtxo`]s, in order.
+    pub utxos: ByteBuf,
+}
+
+impl CompactUtxos {
+    /// Encodes `utxos`, preserving their order.
+    pub fn from_utxos(utxos: &[Utxo]) -> Self {
+        let mut txids = vec![];
+        let mut txid_indexes = HashMap::new();
+        let mut bytes = vec![];
+        let mut previous_height = 0;
+        for utxo in utxos {
+            let txid = &utxo.outpoint.txid;
+            let txid_index = *txid_indexes.entry(txid.as_slice()).or_insert_with(|| {
+                txids.push(ByteBuf::from(txid.clone()));
+                (txids.len() - 1) as u32
+            });
+            let height = utxo.height.get() as i64;
+            CompactUtxo {
+                txid_index,
+                vout: utxo.outpoint.vout,
+                value: utxo.value,
+                height_delta: height - previous_height,
+            }
+            .encode(&mut bytes);
+            previous_height = height;
+        }
+        Self {
+            txids,
+            utxos: ByteBuf::from(bytes),
+        }
+    }
+
+    /// Decodes the UTXOs, in order.
+    pub fn to_utxos(&self) -> Result<Vec<Utxo>, CompactUtxosError> {
+        let mut utxos = vec![];
+        let mut bytes = self.utxos.as_slice();
+        let mut previous_height = 0i64;
+        while !bytes.is_empty() {
+            let utxo = CompactUtxo::decode(&mut bytes)?;
+            let txid = self.txids.get(utxo.txid_index as usize).ok_or(
+                CompactUtxosError::TxidIndexOutOfBounds {
+                    index: utxo.txid_index,
+                    txids: self.txids.len() as u32,
+                },
+            )?;
+            let height = previous_height
+                .checked_add(utxo.height_delta)
+                .and_then(|height| u32::try_from(height).ok())
+                .ok_or(CompactUtxosError::Malformed)?;
+            utxos.push(Utxo {
+                outpoint: OutPoint {
+                    txid: txid.to_vec(),
+                    vout: utxo.vout,
+                },
+                value: utxo.value,
+                height: Height::new(height),
+            });
+            previous_height = height as i64;
+        }
+        Ok(utxos)
+    }
+}
+
+/// Errors when decoding [`CompactUtxos`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CompactUtxosError {
+    /// The encoding is truncated, or a value is out of range.
+    Malformed,
+    /// A UTXO references a transaction id not in the table.
+    TxidIndexOutOfBounds { index: u32, txids: u32 },
+}
+
+impl std::fmt::Display for CompactUtxosError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "The compact UTXOs are malformed."),
+            Self::TxidIndexOutOfBounds { index, txids } => write!(
+                f,
+                "The txid index {} is out of bounds of the {} txids.",
+                index, txids
+            ),
+        }
+    }
+}
+
+/// The response to a `GetUtxosRequest` with `compact` set. Identical to a
+/// `GetUtxosResponse`, except for the encoding of the UTXOs.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub struct GetUtxosCompactResponse {
+    pub utxos: CompactUtxos,
+    pub tip_block_hash: BlockHash,
+    pub tip_height: u32,
+    pub next_page: Option<Page>,
+    pub stability_count: Option<u32>,
+    pub total_count: Option<u64>,
+    pub has_more: Option<bool>,
+}
+
+impl From<GetUtxosResponse> for GetUtxosCompactResponse {
+    fn from(response: GetUtxosResponse) -> Self {
+        Self {
+            utxos: CompactUtxos::from_utxos(&response.utxos),
+            tip_block_hash: response.tip_block_hash,
+            tip_height: response.tip_height,
+            next_page: response.next_page,
+            stability_count: response.stability_count,
+            total_count: response.total_count,
+            has_more: response.has_more,
+        }
+    }
+}
+
+impl TryFrom<GetUtxosCompactResponse> for GetUtxosResponse {
+    type Error = CompactUtxosError;
+
+    fn try_from(response: GetUtxosCompactResponse) -> Result<Self, Self::Error> {
+        Ok(Self {
+            utxos: response.utxos.to_utxos()?,
+            tip_block_hash: response.tip_block_hash,
+            tip_height: response.tip_height,
+            next_page: response.next_page,
+            stability_count: response.stability_count,
+            total_count: response.total_count,
+            has_more: response.has_more,
+        })
+    }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, CompactUtxosError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or(CompactUtxosError::Malformed)?;
+        *bytes = rest;
+        let bits = (byte & 0x7f) as u64;
+        if bits << shift >> shift != bits {
+            return Err(CompactUtxosError::Malformed);
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(CompactUtxosError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utxo(txid: u8, vout: u32, value: Satoshi, height: u32) -> Utxo {
+        Utxo {
+            outpoint: OutPoint {
+                txid: vec![txid; 32],
+                vout,
+            },
+            value,
+            height: Height::new(height),
+        }
+    }
+
+    #[test]
+    fn utxos_round_trip() {
+        let utxos = vec![
+            utxo(1, 0, 1_000, 700_000),
+            utxo(1, 1, u64::MAX, 700_000),
+            utxo(2, 5, 0, 699_990),
+            utxo(3, u32::MAX, 42, u32::MAX),
+            utxo(1, 2, 7, 0),
+        ];
+        let compact = CompactUtxos::from_utxos(&utxos);
+        assert_eq!(compact.txids.len(), 3);
+        assert_eq!(compact.to_utxos(), Ok(utxos));
+        assert_eq!(CompactUtxos::from_utxos(&[]).to_utxos(), Ok(vec![]));
+    }
+
+    #[test]
+    fn responses_are_smaller() {
+        let response = GetUtxosResponse {
+            utxos: (0..1_000)
+                .map(|i| utxo((i / 10) as u8, i % 10, 10_000 + i as u64, 750_000 - i / 4))
+                .collect(),
+            tip_block_hash: vec![0; 32],
+            tip_height: 750_000,
+            next_page: None,
+            stability_count: Some(6),
+            total_count: Some(1_000),
+            has_more: Some(false),
+        };
+        let compact = GetUtxosCompactResponse::from(response.clone());
+        let size = candid::encode_one(&response).unwrap().len();
+        let compact_size = candid::encode_one(&compact).unwrap().len();
+        assert!(compact_size * 3 < size, "{} vs {}", compact_size, size);
+        assert_eq!(GetUtxosResponse::try_from(compact), Ok(response));
+    }
+
+    #[test]
+    fn malformed_utxos_are_rejected() {
+        let mut compact = CompactUtxos::from_utxos(&[utxo(1, 0, 1_000, 10), utxo(2, 0, 1, 5)]);
+        let bytes = compact.utxos.to_vec();
+
+        compact.utxos = ByteBuf::from(bytes[..bytes.len() - 1].to_vec());
+        assert_eq!(compact.to_utxos(), Err(CompactUtxosError::Malformed));
+
+        compact.utxos = ByteBuf::from(bytes);
+        compact.txids.pop();
+        assert_eq!(
+            compact.to_utxos(),
+            Err(CompactUtxosError::TxidIndexOutOfBounds { index: 1, txids: 1 })
+        );
+
+        // A height delta below zero.
+        let mut bytes = vec![];
+        CompactUtxo {
+            txid_index: 0,
+            vout: 0,
+            value: 0,
+            height_delta: -1,
+        }
+        .encode(&mut bytes);
+        compact.utxos = ByteBuf::from(bytes);
+        assert_eq!(compact.to_utxos(), Err(CompactUtxosError::Malformed));
+    }
+}
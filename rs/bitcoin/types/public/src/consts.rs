@@ -0,0 +1,114 @@
+//! Constants of the Bitcoin networks, as defined by Bitcoin Core.
+
+use crate::Network;
+
+/// The constants of a Bitcoin network.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NetworkConsts {
+    /// The bytes starting every message of the peer-to-peer protocol, and
+    /// every block in `blk*.dat` files.
+    pub magic: [u8; 4],
+    /// The hash of the genesis block, in internal byte order, i.e. reversed
+    /// with respect to its usual hex representation.
+    pub genesis_block_hash: [u8; 32],
+    /// The human-readable part of bech32 (segwit) addresses.
+    pub bech32_hrp: &'static str,
+    /// The version byte of base58 P2PKH addresses.
+    pub p2pkh_version: u8,
+    /// The version byte of base58 P2SH addresses.
+    pub p2sh_version: u8,
+    /// The default port of the peer-to-peer protocol.
+    pub default_port: u16,
+}
+
+impl NetworkConsts {
+    /// Returns the magic bytes as the little-endian `u32` they are usually
+    /// read as.
+    pub fn magic_u32(&self) -> u32 {
+        u32::from_le_bytes(self.magic)
+    }
+}
+
+pub const MAINNET: NetworkConsts = NetworkConsts {
+    magic: [0xf9, 0xbe, 0xb4, 0xd9],
+    genesis_block_hash: [
+        0x6f, 0xe2, 0x8c, 0x0a, 0xb6, 0xf1, 0xb3, 0x72, 0xc1, 0xa6, 0xa2, 0x46, 0xae, 0x63, 0xf7,
+        0x4f, 0x93, 0x1e, 0x83, 0x65, 0xe1, 0x5a, 0x08, 0x9c, 0x68, 0xd6, 0x19, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ],
+    bech32_hrp: "bc",
+    p2pkh_version: 0x00,
+    p2sh_version: 0x05,
+    default_port: 8333,
+};
+
+/// The constants of testnet3.
+pub const TESTNET: NetworkConsts = NetworkConsts {
+    magic: [0x0b, 0x11, 0x09, 0x07],
+    genesis_block_hash: [
+        0x43, 0x49, 0x7f, 0xd7, 0xf8, 0x26, 0x95, 0x71, 0x08, 0xf4, 0xa3, 0x0f, 0xd9, 0xce, 0xc3,
+        0xae, 0xba, 0x79, 0x97, 0x20, 0x84, 0xe9, 0x0e, 0xad, 0x01, 0xea, 0x33, 0x09, 0x00, 0x00,
+        0x00, 0x00,
+    ],
+    bech32_hrp: "tb",
+    p2pkh_version: 0x6f,
+    p2sh_version: 0xc4,
+    default_port: 18333,
+};
+
+pub const REGTEST: NetworkConsts = NetworkConsts {
+    magic: [0xfa, 0xbf, 0xb5, 0xda],
+    genesis_block_hash: [
+        0x06, 0x22, 0x6e, 0x46, 0x11, 0x1a, 0x0b, 0x59, 0xca, 0xaf, 0x12, 0x60, 0x43, 0xeb, 0x5b,
+        0xbf, 0x28, 0xc3, 0x4f, 0x3a, 0x5e, 0x33, 0x2a, 0x1f, 0xc7, 0xb2, 0xb7, 0x3c, 0xf1, 0x88,
+        0x91, 0x0f,
+    ],
+    bech32_hrp: "bcrt",
+    p2pkh_version: 0x6f,
+    p2sh_version: 0xc4,
+    default_port: 18444,
+};
+
+/// Returns the constants of the given network.
+pub fn consts(network: Network) -> &'static NetworkConsts {
+    match network {
+        Network::Mainnet => &MAINNET,
+        Network::Testnet => &TESTNET,
+        Network::Regtest => &REGTEST,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display_hash(hash: &[u8; 32]) -> String {
+        hash.iter()
+            .rev()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    #[test]
+    fn genesis_block_hashes_match_bitcoin_core() {
+        assert_eq!(
+            display_hash(&consts(Network::Mainnet).genesis_block_hash),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert_eq!(
+            display_hash(&consts(Network::Testnet).genesis_block_hash),
+            "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"
+        );
+        assert_eq!(
+            display_hash(&consts(Network::Regtest).genesis_block_hash),
+            "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"
+        );
+    }
+
+    #[test]
+    fn magic_is_read_little_endian() {
+        assert_eq!(MAINNET.magic_u32(), 0xD9B4BEF9);
+        assert_eq!(TESTNET.magic_u32(), 0x0709110B);
+        assert_eq!(REGTEST.magic_u32(), 0xDAB5BFFA);
+    }
+}
@@ -0,0 +1,187 @@
+//! The cycles charged for calls to the Bitcoin API, per network.
+//!
+//! Canisters must attach at least the cost of a request to the call. Cycles
+//! in excess of the cost are refunded.
+
+use crate::{
+    fee_target::GetFeeForTargetRequest, GetBalanceRequest, GetCurrentFeePercentilesRequest,
+    GetFeePercentilesAtHeightRequest, GetUtxosRequest, Network, SendTransactionRequest,
+};
+
+/// The cycles charged for each endpoint of the Bitcoin API.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Fees {
+    pub get_utxos: u128,
+    pub get_balance: u128,
+    pub get_current_fee_percentiles: u128,
+    /// Charged for every `send_transaction` call, including ones with a
+    /// malformed transaction.
+    pub send_transaction_base: u128,
+    /// Charged per byte of the transaction, in addition to the base fee.
+    pub send_transaction_per_byte: u128,
+}
+
+pub const MAINNET_FEES: Fees = Fees {
+    get_utxos: 100_000_000,
+    get_balance: 100_000_000,
+    get_current_fee_percentiles: 100_000_000,
+    send_transaction_base: 5_000_000_000,
+    send_transaction_per_byte: 20_000_000,
+};
+
+pub const TESTNET_FEES: Fees = MAINNET_FEES;
+
+pub const REGTEST_FEES: Fees = MAINNET_FEES;
+
+/// Returns the fees charged on the given network.
+pub fn fees(network: Network) -> &'static Fees {
+    match network {
+        Network::Mainnet => &MAINNET_FEES,
+        Network::Testnet => &TESTNET_FEES,
+        Network::Regtest => &REGTEST_FEES,
+    }
+}
+
+/// A request to the Bitcoin API with a known cost.
+pub trait Cost {
+    /// Returns the cycles charged for the request.
+    fn cost(&self) -> u128;
+}
+
+impl Cost for GetUtxosRequest {
+    fn cost(&self) -> u128 {
+        fees(self.network.into()).get_utxos
+    }
+}
+
+impl Cost for GetBalanceRequest {
+    fn cost(&self) -> u128 {
+        fees(self.network.into()).get_balance
+    }
+}
+
+impl Cost for GetCurrentFeePercentilesRequest {
+    fn cost(&self) -> u128 {
+        fees(self.network.into()).get_current_fee_percentiles
+    }
+}
+
+/// Historical fee percentiles are charged like the current ones.
+impl Cost for GetFeePercentilesAtHeightRequest {
+    fn cost(&self) -> u128 {
+        fees(self.network.into()).get_current_fee_percentiles
+    }
+}
+
+/// Fee rates for a confirmation target are computed from the current fee
+/// percentiles, and charged alike.
+impl Cost for GetFeeForTargetRequest {
+    fn cost(&self) -> u128 {
+        fees(self.network.into()).get_current_fee_percentiles
+    }
+}
+
+impl Cost for SendTransactionRequest {
+    fn cost(&self) -> u128 {
+        send_transaction_cost(self.network.into(), self.transaction.len())
+    }
+}
+
+/// Returns the cycles to attach to a call with the given request.
+pub fn cost_of<R: Cost>(request: &R) -> u128 {
+    request.cost()
+}
+
+/// Returns the cycles charged for sending a transaction of
+/// `transaction_len` bytes on `network`.
+pub fn send_transaction_cost(network: Network, transaction_len: usize) -> u128 {
+    let fees = fees(network);
+    fees.send_transaction_base + fees.send_transaction_per_byte * transaction_len as u128
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fee_target::ConfirmationTarget, BlockRef, Height, NetworkInRequest};
+
+    const NETWORKS: [NetworkInRequest; 4] = [
+        NetworkInRequest::Mainnet,
+        NetworkInRequest::mainnet,
+        NetworkInRequest::Testnet,
+        NetworkInRequest::Regtest,
+    ];
+
+    #[test]
+    fn endpoints_are_charged_their_fees() {
+        for network in NETWORKS {
+            let fees = fees(network.into());
+            let address = String::from("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq");
+            assert_eq!(
+                cost_of(&GetUtxosRequest {
+                    address: address.clone(),
+                    network,
+                    filter: None,
+                    compact: None,
+                }),
+                fees.get_utxos
+            );
+            assert_eq!(
+                cost_of(&GetBalanceRequest {
+                    address,
+                    network,
+                    min_confirmations: Some(6),
+                }),
+                fees.get_balance
+            );
+            assert_eq!(
+                cost_of(&GetCurrentFeePercentilesRequest { network }),
+                fees.get_current_fee_percentiles
+            );
+            assert_eq!(
+                cost_of(&GetFeePercentilesAtHeightRequest {
+                    network,
+                    block: BlockRef::Height(Height::new(700_000)),
+                }),
+                fees.get_current_fee_percentiles
+            );
+            assert_eq!(
+                cost_of(&GetFeeForTargetRequest {
+                    network,
+                    target: ConfirmationTarget::new(6).unwrap(),
+                }),
+                fees.get_current_fee_percentiles
+            );
+        }
+    }
+
+    #[test]
+    fn mainnet_fees_are_charged() {
+        let request = GetCurrentFeePercentilesRequest {
+            network: NetworkInRequest::mainnet,
+        };
+        assert_eq!(cost_of(&request), 100_000_000);
+        assert_eq!(fees(Network::Mainnet), &MAINNET_FEES);
+    }
+
+    #[test]
+    fn transactions_are_charged_by_size() {
+        assert_eq!(send_transaction_cost(Network::Mainnet, 0), 5_000_000_000);
+        assert_eq!(send_transaction_cost(Network::Mainnet, 1), 5_020_000_000);
+        assert_eq!(send_transaction_cost(Network::Mainnet, 250), 10_000_000_000);
+
+        for network in NETWORKS {
+            for transaction_len in [0, 1, 250, 100_000] {
+                let request = SendTransactionRequest {
+                    transaction: vec![0; transaction_len],
+                    network,
+                };
+                let fees = fees(network.into());
+                assert_eq!(
+                    cost_of(&request),
+                    fees.send_transaction_base
+                        + fees.send_transaction_per_byte * transaction_len as u128
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,362 @@
+//! Stable numeric codes of the errors of the Bitcoin API endpoints.
+//!
+//! Every endpoint owns a range of codes, see [`ERROR_CODE_RANGES`], and every
+//! variant of its error enum maps to a code in that range, so that clients
+//! in any language can tell errors apart without matching on their messages.
+//!
+//! Codes are frozen: a code is never changed or reused, even if its variant
+//! is removed. New variants get the next unused code of their endpoint's
+//! range, and new endpoints the next unused range.
+
+use crate::{
+    fee_target::GetFeeForTargetError, ownership::AddressOwnershipProofError, GenerateBlocksError,
+    GetBalanceError, GetFeePercentilesAtHeightError, GetUtxosError, SendTransactionError,
+    SubscribeUtxoChangesError,
+};
+use std::ops::Range;
+
+pub const GET_UTXOS: Range<u32> = 100..200;
+pub const GET_BALANCE: Range<u32> = 200..300;
+pub const SEND_TRANSACTION: Range<u32> = 300..400;
+pub const GET_FEE_PERCENTILES_AT_HEIGHT: Range<u32> = 400..500;
+pub const GENERATE_BLOCKS: Range<u32> = 500..600;
+pub const SUBSCRIBE_UTXO_CHANGES: Range<u32> = 600..700;
+pub const ADDRESS_OWNERSHIP_PROOF: Range<u32> = 700..800;
+pub const GET_FEE_FOR_TARGET: Range<u32> = 800..900;
+
+/// The ranges of error codes by endpoint.
+pub const ERROR_CODE_RANGES: &[(&str, Range<u32>)] = &[
+    ("get_utxos", GET_UTXOS),
+    ("get_balance", GET_BALANCE),
+    ("send_transaction", SEND_TRANSACTION),
+    (
+        "get_fee_percentiles_at_height",
+        GET_FEE_PERCENTILES_AT_HEIGHT,
+    ),
+    ("generate_blocks", GENERATE_BLOCKS),
+    ("subscribe_utxo_changes", SUBSCRIBE_UTXO_CHANGES),
+    ("address_ownership_proof", ADDRESS_OWNERSHIP_PROOF),
+    ("get_fee_for_target", GET_FEE_FOR_TARGET),
+];
+
+/// Returns the endpoint whose range holds `code`, if any.
+pub fn endpoint_of(code: u32) -> Option<&'static str> {
+    ERROR_CODE_RANGES
+        .iter()
+        .find(|(_, range)| range.contains(&code))
+        .map(|(endpoint, _)| *endpoint)
+}
+
+impl GetUtxosError {
+    /// The stable code of the error, in [`GET_UTXOS`].
+    ///
+    /// | Code | Variant                    |
+    /// |------|----------------------------|
+    /// | 100  | `MalformedAddress`         |
+    /// | 101  | `MinConfirmationsTooLarge` |
+    /// | 102  | `UnknownTipBlockHash`      |
+    /// | 103  | `MalformedPage`            |
+    /// | 104  | `TipChanged`               |
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::MalformedAddress => 100,
+            Self::MinConfirmationsTooLarge { .. } => 101,
+            Self::UnknownTipBlockHash { .. } => 102,
+            Self::MalformedPage { .. } => 103,
+            Self::TipChanged { .. } => 104,
+        }
+    }
+}
+
+impl GetBalanceError {
+    /// The stable code of the error, in [`GET_BALANCE`].
+    ///
+    /// | Code | Variant                    |
+    /// |------|----------------------------|
+    /// | 200  | `MalformedAddress`         |
+    /// | 201  | `MinConfirmationsTooLarge` |
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::MalformedAddress => 200,
+            Self::MinConfirmationsTooLarge { .. } => 201,
+        }
+    }
+}
+
+impl SendTransactionError {
+    /// The stable code of the error, in [`SEND_TRANSACTION`].
+    ///
+    /// | Code | Variant                |
+    /// |------|------------------------|
+    /// | 300  | `MalformedTransaction` |
+    /// | 301  | `QueueFull`            |
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::MalformedTransaction { .. } => 300,
+            Self::QueueFull => 301,
+        }
+    }
+}
+
+impl GetFeePercentilesAtHeightError {
+    /// The stable code of the error, in [`GET_FEE_PERCENTILES_AT_HEIGHT`].
+    ///
+    /// | Code | Variant            |
+    /// |------|--------------------|
+    /// | 400  | `UnknownHeight`    |
+    /// | 401  | `UnknownBlockHash` |
+    /// | 402  | `HeightPruned`     |
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::UnknownHeight { .. } => 400,
+            Self::UnknownBlockHash { .. } => 401,
+            Self::HeightPruned { .. } => 402,
+        }
+    }
+}
+
+impl GenerateBlocksError {
+    /// The stable code of the error, in [`GENERATE_BLOCKS`].
+    ///
+    /// | Code | Variant            |
+    /// |------|--------------------|
+    /// | 500  | `NotRegtest`       |
+    /// | 501  | `TooManyBlocks`    |
+    /// | 502  | `MalformedAddress` |
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::NotRegtest { .. } => 500,
+            Self::TooManyBlocks { .. } => 501,
+            Self::MalformedAddress { .. } => 502,
+        }
+    }
+}
+
+impl SubscribeUtxoChangesError {
+    /// The stable code of the error, in [`SUBSCRIBE_UTXO_CHANGES`].
+    ///
+    /// | Code | Variant                    |
+    /// |------|----------------------------|
+    /// | 600  | `MalformedAddress`         |
+    /// | 601  | `TooManyAddresses`         |
+    /// | 602  | `MinConfirmationsTooLarge` |
+    /// | 603  | `UnknownSubscription`      |
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::MalformedAddress { .. } => 600,
+            Self::TooManyAddresses { .. } => 601,
+            Self::MinConfirmationsTooLarge { .. } => 602,
+            Self::UnknownSubscription { .. } => 603,
+        }
+    }
+}
+
+impl AddressOwnershipProofError {
+    /// The stable code of the error, in [`ADDRESS_OWNERSHIP_PROOF`].
+    ///
+    /// | Code | Variant              |
+    /// |------|----------------------|
+    /// | 700  | `MalformedSignature` |
+    /// | 701  | `InvalidSignature`   |
+    /// | 702  | `AddressMismatch`    |
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::MalformedSignature => 700,
+            Self::InvalidSignature => 701,
+            Self::AddressMismatch { .. } => 702,
+        }
+    }
+}
+
+impl GetFeeForTargetError {
+    /// The stable code of the error, in [`GET_FEE_FOR_TARGET`].
+    ///
+    /// | Code | Variant            |
+    /// |------|--------------------|
+    /// | 800  | `TargetOutOfRange` |
+    /// | 801  | `NoFeeData`        |
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::TargetOutOfRange { .. } => 800,
+            Self::NoFeeData => 801,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Height, Network};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn ranges_do_not_overlap() {
+        for (i, (_, a)) in ERROR_CODE_RANGES.iter().enumerate() {
+            for (_, b) in &ERROR_CODE_RANGES[i + 1..] {
+                assert!(a.end <= b.start || b.end <= a.start);
+            }
+        }
+    }
+
+    // Pins every code, as they must never change.
+    #[test]
+    fn codes_are_frozen() {
+        let codes = vec![
+            (GetUtxosError::MalformedAddress.code(), 100, "get_utxos"),
+            (
+                GetUtxosError::MinConfirmationsTooLarge { given: 1, max: 0 }.code(),
+                101,
+                "get_utxos",
+            ),
+            (
+                GetUtxosError::UnknownTipBlockHash {
+                    tip_block_hash: vec![],
+                }
+                .code(),
+                102,
+                "get_utxos",
+            ),
+            (
+                GetUtxosError::MalformedPage { err: String::new() }.code(),
+                103,
+                "get_utxos",
+            ),
+            (
+                GetUtxosError::TipChanged {
+                    tip_block_hash: vec![],
+                    current_tip_block_hash: vec![],
+                }
+                .code(),
+                104,
+                "get_utxos",
+            ),
+            (GetBalanceError::MalformedAddress.code(), 200, "get_balance"),
+            (
+                GetBalanceError::MinConfirmationsTooLarge { given: 1, max: 0 }.code(),
+                201,
+                "get_balance",
+            ),
+            (
+                SendTransactionError::MalformedTransaction {
+                    offset: 0,
+                    reason: String::new(),
+                }
+                .code(),
+                300,
+                "send_transaction",
+            ),
+            (
+                SendTransactionError::QueueFull.code(),
+                301,
+                "send_transaction",
+            ),
+            (
+                GetFeePercentilesAtHeightError::UnknownHeight {
+                    height: Height::from(1),
+                    tip_height: Height::from(0),
+                }
+                .code(),
+                400,
+                "get_fee_percentiles_at_height",
+            ),
+            (
+                GetFeePercentilesAtHeightError::UnknownBlockHash { block_hash: vec![] }.code(),
+                401,
+                "get_fee_percentiles_at_height",
+            ),
+            (
+                GetFeePercentilesAtHeightError::HeightPruned {
+                    height: Height::from(0),
+                    min_retained_height: Height::from(1),
+                }
+                .code(),
+                402,
+                "get_fee_percentiles_at_height",
+            ),
+            (
+                GenerateBlocksError::NotRegtest {
+                    network: Network::Mainnet,
+                }
+                .code(),
+                500,
+                "generate_blocks",
+            ),
+            (
+                GenerateBlocksError::TooManyBlocks { given: 1, max: 0 }.code(),
+                501,
+                "generate_blocks",
+            ),
+            (
+                GenerateBlocksError::MalformedAddress {
+                    address: String::new(),
+                }
+                .code(),
+                502,
+                "generate_blocks",
+            ),
+            (
+                SubscribeUtxoChangesError::MalformedAddress {
+                    address: String::new(),
+                }
+                .code(),
+                600,
+                "subscribe_utxo_changes",
+            ),
+            (
+                SubscribeUtxoChangesError::TooManyAddresses { given: 1, max: 0 }.code(),
+                601,
+                "subscribe_utxo_changes",
+            ),
+            (
+                SubscribeUtxoChangesError::MinConfirmationsTooLarge { given: 1, max: 0 }.code(),
+                602,
+                "subscribe_utxo_changes",
+            ),
+            (
+                SubscribeUtxoChangesError::UnknownSubscription { subscription_id: 1 }.code(),
+                603,
+                "subscribe_utxo_changes",
+            ),
+            (
+                AddressOwnershipProofError::MalformedSignature.code(),
+                700,
+                "address_ownership_proof",
+            ),
+            (
+                AddressOwnershipProofError::InvalidSignature.code(),
+                701,
+                "address_ownership_proof",
+            ),
+            (
+                AddressOwnershipProofError::AddressMismatch {
+                    signer: String::new(),
+                }
+                .code(),
+                702,
+                "address_ownership_proof",
+            ),
+            (
+                GetFeeForTargetError::TargetOutOfRange {
+                    given: 0,
+                    min: 1,
+                    max: 2,
+                }
+                .code(),
+                800,
+                "get_fee_for_target",
+            ),
+            (
+                GetFeeForTargetError::NoFeeData.code(),
+                801,
+                "get_fee_for_target",
+            ),
+        ];
+        for (code, expected, endpoint) in &codes {
+            assert_eq!(code, expected);
+            assert_eq!(endpoint_of(*code), Some(*endpoint));
+        }
+        let unique: BTreeSet<_> = codes.iter().map(|(code, _, _)| code).collect();
+        assert_eq!(unique.len(), codes.len());
+        assert_eq!(endpoint_of(0), None);
+    }
+}
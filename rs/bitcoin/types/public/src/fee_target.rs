@@ -0,0 +1,223 @@
+//! Fee rates for a confirmation target, computed from the fee percentiles.
+//!
+//! Wallets usually ask for a fee rate that gets a transaction confirmed
+//! within a number of blocks, rather than for a percentile. Every wallet
+//! picking its own percentile for a target makes the same transaction pay
+//! different fees depending on the wallet, so the mapping is fixed here.
+//!
+//! The fee percentiles are those of the recent transactions of the main
+//! chain, i.e. of transactions that were confirmed. The sooner a transaction
+//! is to be confirmed, the more miners must prefer it over the others, so the
+//! higher the percentile its fee rate is taken from:
+//!
+//! | Target (blocks) | Percentile |
+//! |-----------------|------------|
+//! | 1               | 90         |
+//! | 2               | 75         |
+//! | 3 to 6          | 50         |
+//! | 7 to 24         | 25         |
+//! | 25 to 1008      | 10         |
+//!
+//! The fee rate for a target is thus never lower than the fee rate for a
+//! later target. It is an estimate: blocks arrive irregularly and the fees of
+//! pending transactions may rise, so confirmation within the target is not
+//! guaranteed.
+
+use crate::{MillisatoshiPerByte, NetworkInRequest};
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+/// The percentile of the fee rate for confirmation targets up to the given
+/// number of blocks, by increasing target.
+const PERCENTILES_BY_TARGET: [(u32, u8); 5] = [(1, 90), (2, 75), (6, 50), (24, 25), (1008, 10)];
+
+/// The number of blocks within which a transaction is to be confirmed.
+///
+/// Encoded as a plain `nat32` in candid.
+#[derive(
+    CandidType, Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(transparent)]
+pub struct ConfirmationTarget(u32);
+
+impl ConfirmationTarget {
+    /// The earliest target, the next block.
+    pub const MIN: u32 = 1;
+
+    /// The latest target, about a week of blocks.
+    pub const MAX: u32 = 1008;
+
+    /// Returns the target of confirmation within `blocks` blocks, if it is
+    /// between [`Self::MIN`] and [`Self::MAX`].
+    pub fn new(blocks: u32) -> Result<Self, GetFeeForTargetError> {
+        let target = Self(blocks);
+        target.validate()?;
+        Ok(target)
+    }
+
+    pub const fn blocks(self) -> u32 {
+        self.0
+    }
+
+    /// Returns the percentile of the fee rate for this target, see the module
+    /// documentation.
+    pub fn percentile(self) -> Result<u8, GetFeeForTargetError> {
+        self.validate()?;
+        Ok(PERCENTILES_BY_TARGET
+            .iter()
+            .find(|(max_blocks, _)| self.0 <= *max_blocks)
+            .map(|(_, percentile)| *percentile)
+            // The last entry covers all valid targets.
+            .unwrap())
+    }
+
+    // Targets decoded from candid are not checked on decoding.
+    fn validate(self) -> Result<(), GetFeeForTargetError> {
+        if (Self::MIN..=Self::MAX).contains(&self.0) {
+            Ok(())
+        } else {
+            Err(GetFeeForTargetError::TargetOutOfRange {
+                given: self.0,
+                min: Self::MIN,
+                max: Self::MAX,
+            })
+        }
+    }
+}
+
+/// A request for the fee rate that gets a transaction confirmed within a
+/// number of blocks.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GetFeeForTargetRequest {
+    pub network: NetworkInRequest,
+    pub target: ConfirmationTarget,
+}
+
+/// The fee rate for the requested confirmation target.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct GetFeeForTargetResponse {
+    pub fee_rate: MillisatoshiPerByte,
+    /// The percentile of the fee percentiles that `fee_rate` is taken from.
+    pub percentile: u8,
+    pub target: ConfirmationTarget,
+}
+
+/// Errors when processing a `GetFeeForTargetRequest`.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq)]
+pub enum GetFeeForTargetError {
+    TargetOutOfRange {
+        given: u32,
+        min: u32,
+        max: u32,
+    },
+    /// There are no recent transactions to compute fee rates from, e.g. right
+    /// after the canister synced the chain.
+    NoFeeData,
+}
+
+impl std::fmt::Display for GetFeeForTargetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TargetOutOfRange { given, min, max } => {
+                write!(
+                    f,
+                    "The confirmation target is out of range. Given: {}, min: {}, max: {}",
+                    given, min, max
+                )
+            }
+            Self::NoFeeData => {
+                write!(f, "There are no recent transactions to compute fees from.")
+            }
+        }
+    }
+}
+
+/// Returns the fee rate for `target` out of `fee_percentiles`, as returned
+/// by `get_current_fee_percentiles`, i.e. the fee rates at the 0th to the
+/// 99th percentile in increasing order. Percentiles are looked up like they
+/// are computed if there are fewer or more fee rates.
+pub fn fee_for_target(
+    fee_percentiles: &[MillisatoshiPerByte],
+    target: ConfirmationTarget,
+) -> Result<GetFeeForTargetResponse, GetFeeForTargetError> {
+    let percentile = target.percentile()?;
+    if fee_percentiles.is_empty() {
+        return Err(GetFeeForTargetError::NoFeeData);
+    }
+    let index = (percentile as usize * fee_percentiles.len() / 100).min(fee_percentiles.len() - 1);
+    Ok(GetFeeForTargetResponse {
+        fee_rate: fee_percentiles[index],
+        percentile,
+        target,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::{Decode, Encode};
+
+    fn target(blocks: u32) -> ConfirmationTarget {
+        ConfirmationTarget::new(blocks).unwrap()
+    }
+
+    #[test]
+    fn targets_map_to_the_documented_percentiles() {
+        for (blocks, percentile) in [
+            (1, 90),
+            (2, 75),
+            (3, 50),
+            (6, 50),
+            (7, 25),
+            (24, 25),
+            (25, 10),
+            (1008, 10),
+        ] {
+            assert_eq!(target(blocks).percentile(), Ok(percentile));
+        }
+    }
+
+    #[test]
+    fn sooner_targets_never_pay_less() {
+        let fee_percentiles: Vec<MillisatoshiPerByte> = (0..100).map(|i| 1_000 * i).collect();
+        let fee_rates: Vec<_> = (ConfirmationTarget::MIN..=ConfirmationTarget::MAX)
+            .map(|blocks| fee_for_target(&fee_percentiles, target(blocks)).unwrap())
+            .map(|response| response.fee_rate)
+            .collect();
+        assert!(fee_rates.windows(2).all(|w| w[0] >= w[1]));
+        assert_eq!(fee_rates[0], 90_000);
+        assert_eq!(fee_rates[fee_rates.len() - 1], 10_000);
+    }
+
+    #[test]
+    fn fewer_fee_percentiles_are_looked_up_like_they_are_computed() {
+        assert_eq!(
+            fee_for_target(&[1, 2, 3, 4, 5], target(1)).map(|r| r.fee_rate),
+            Ok(5)
+        );
+        assert_eq!(
+            fee_for_target(&[1, 2, 3, 4, 5], target(6)).map(|r| r.fee_rate),
+            Ok(3)
+        );
+        assert_eq!(
+            fee_for_target(&[], target(1)),
+            Err(GetFeeForTargetError::NoFeeData)
+        );
+    }
+
+    #[test]
+    fn out_of_range_targets_are_rejected() {
+        for blocks in [0, ConfirmationTarget::MAX + 1] {
+            let err = GetFeeForTargetError::TargetOutOfRange {
+                given: blocks,
+                min: ConfirmationTarget::MIN,
+                max: ConfirmationTarget::MAX,
+            };
+            assert_eq!(ConfirmationTarget::new(blocks), Err(err.clone()));
+
+            // Targets are not checked when decoded.
+            let decoded = Decode!(&Encode!(&blocks).unwrap(), ConfirmationTarget).unwrap();
+            assert_eq!(fee_for_target(&[1, 2, 3], decoded), Err(err));
+        }
+    }
+}
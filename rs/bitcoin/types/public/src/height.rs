@@ -0,0 +1,122 @@
+//! The height of a block in the Bitcoin chain.
+
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+/// The height of a block, i.e. the number of blocks preceding it in the chain.
+///
+/// Encoded as a plain `nat32` in candid.
+#[derive(
+    CandidType,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Eq,
+    Hash,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Serialize,
+)]
+#[serde(transparent)]
+pub struct Height(u32);
+
+impl Height {
+    pub const fn new(height: u32) -> Self {
+        Self(height)
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+
+    /// Returns the number of confirmations of a block at this height in a
+    /// chain with its tip at `tip`, i.e. the number of blocks from this one
+    /// to the tip, both included. A block at the tip has one confirmation, a
+    /// block above the tip has none. The genesis block saturates at
+    /// `u32::MAX` confirmations with the tip at `u32::MAX`.
+    pub fn confirmations(self, tip: Height) -> u32 {
+        if self.0 > tip.0 {
+            0
+        } else {
+            (tip.0 - self.0).saturating_add(1)
+        }
+    }
+
+    /// Returns the height of the next block, or `None` on overflow.
+    pub fn checked_increment(self) -> Option<Self> {
+        self.0.checked_add(1).map(Self)
+    }
+
+    /// Returns the height of the previous block, or `None` at the genesis
+    /// block.
+    pub fn checked_decrement(self) -> Option<Self> {
+        self.0.checked_sub(1).map(Self)
+    }
+}
+
+impl From<u32> for Height {
+    fn from(height: u32) -> Self {
+        Self(height)
+    }
+}
+
+impl From<Height> for u32 {
+    fn from(height: Height) -> Self {
+        height.0
+    }
+}
+
+impl std::fmt::Display for Height {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::{Decode, Encode};
+
+    const MAX: Height = Height::new(u32::MAX);
+
+    #[test]
+    fn blocks_are_confirmed_by_the_blocks_up_to_the_tip() {
+        let genesis = Height::new(0);
+        assert_eq!(genesis.confirmations(genesis), 1);
+        assert_eq!(genesis.confirmations(Height::new(5)), 6);
+        assert_eq!(Height::new(5).confirmations(Height::new(5)), 1);
+        assert_eq!(Height::new(6).confirmations(Height::new(5)), 0);
+        assert_eq!(MAX.confirmations(genesis), 0);
+        assert_eq!(MAX.confirmations(MAX), 1);
+        assert_eq!(Height::new(1).confirmations(MAX), u32::MAX);
+        assert_eq!(genesis.confirmations(MAX), u32::MAX);
+    }
+
+    #[test]
+    fn increments_stop_at_the_maximum_height() {
+        assert_eq!(Height::new(0).checked_increment(), Some(Height::new(1)));
+        assert_eq!(Height::new(u32::MAX - 1).checked_increment(), Some(MAX));
+        assert_eq!(MAX.checked_increment(), None);
+    }
+
+    #[test]
+    fn decrements_stop_at_the_genesis_block() {
+        assert_eq!(Height::new(0).checked_decrement(), None);
+        assert_eq!(Height::new(1).checked_decrement(), Some(Height::new(0)));
+        assert_eq!(MAX.checked_decrement(), Some(Height::new(u32::MAX - 1)));
+    }
+
+    #[test]
+    fn heights_are_encoded_as_nat32() {
+        assert_eq!(Height::ty(), u32::ty());
+        for height in [0, 1, u32::MAX] {
+            let bytes = Encode!(&Height::new(height)).unwrap();
+            assert_eq!(Decode!(&bytes, u32).unwrap(), height);
+            let bytes = Encode!(&height).unwrap();
+            assert_eq!(Decode!(&bytes, Height).unwrap(), Height::new(height));
+        }
+    }
+}
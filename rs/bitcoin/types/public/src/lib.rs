@@ -4,11 +4,29 @@ use candid::{CandidType, Deserialize};
 use serde::Serialize;
 use serde_bytes::ByteBuf;
 
+pub mod amount;
+pub mod compact;
+pub mod consts;
+pub mod cost;
+pub mod error_code;
+pub mod fee_target;
+mod height;
+pub mod ownership;
+pub mod pagination;
+pub mod response;
+#[cfg(feature = "rust-bitcoin")]
+pub mod rust_bitcoin;
+pub mod signing_cost;
+pub mod standardness;
+#[cfg(feature = "tx")]
+pub mod tx;
+
+pub use height::Height;
+
 pub type Address = String;
 pub type Satoshi = u64;
 pub type MillisatoshiPerByte = u64;
 pub type BlockHash = Vec<u8>;
-pub type Height = u32;
 pub type Page = ByteBuf;
 
 #[derive(CandidType, Clone, Copy, Deserialize, Debug, Eq, PartialEq, Serialize, Hash)]
@@ -44,7 +62,10 @@ impl From<NetworkInRequest> for Network {
 /// A network enum that allows both upper and lowercase variants.
 /// Supporting both variants allows us to be compatible with the spec (lowercase)
 /// while not breaking current dapps that are using uppercase variants.
-#[derive(CandidType, Clone, Copy, Deserialize, Debug, Eq, PartialEq, Serialize, Hash)]
+///
+/// Both variants are accepted when decoding, but the lowercase one is always
+/// emitted when encoding, so that forwarded requests are spec-compliant.
+#[derive(Clone, Copy, Deserialize, Debug, Eq, PartialEq, Hash)]
 pub enum NetworkInRequest {
     Mainnet,
     #[allow(non_camel_case_types)]
@@ -70,6 +91,53 @@ impl std::fmt::Display for NetworkInRequest {
     }
 }
 
+// The encoded form of `NetworkInRequest`. Its type keeps the uppercase
+// variants, so that the candid type of requests is unchanged, but only the
+// lowercase ones are ever constructed.
+#[derive(CandidType, Serialize)]
+#[serde(rename = "NetworkInRequest")]
+#[allow(dead_code, non_camel_case_types)]
+enum NetworkInRequestRepr {
+    Mainnet,
+    mainnet,
+    Testnet,
+    testnet,
+    Regtest,
+    regtest,
+}
+
+impl From<NetworkInRequest> for NetworkInRequestRepr {
+    fn from(network: NetworkInRequest) -> Self {
+        match network {
+            NetworkInRequest::Mainnet | NetworkInRequest::mainnet => Self::mainnet,
+            NetworkInRequest::Testnet | NetworkInRequest::testnet => Self::testnet,
+            NetworkInRequest::Regtest | NetworkInRequest::regtest => Self::regtest,
+        }
+    }
+}
+
+impl CandidType for NetworkInRequest {
+    fn _ty() -> candid::types::Type {
+        NetworkInRequestRepr::_ty()
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: candid::types::Serializer,
+    {
+        NetworkInRequestRepr::from(*self).idl_serialize(serializer)
+    }
+}
+
+impl Serialize for NetworkInRequest {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        NetworkInRequestRepr::from(*self).serialize(serializer)
+    }
+}
+
 /// A reference to a transaction output.
 #[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
 pub struct OutPoint {
@@ -83,7 +151,7 @@ pub struct OutPoint {
 pub struct Utxo {
     pub outpoint: OutPoint,
     pub value: Satoshi,
-    pub height: u32,
+    pub height: Height,
 }
 
 /// A filter used when requesting UTXOs.
@@ -107,7 +175,9 @@ impl From<UtxosFilterInRequest> for UtxosFilter {
 /// A UtxosFilter enum that allows both upper and lowercase variants.
 /// Supporting both variants allows us to be compatible with the spec (lowercase)
 /// while not breaking current dapps that are using uppercase variants.
-#[derive(CandidType, Debug, Deserialize, PartialEq)]
+///
+/// As for [`NetworkInRequest`], the lowercase variant is always emitted.
+#[derive(Debug, Deserialize, PartialEq)]
 pub enum UtxosFilterInRequest {
     MinConfirmations(u32),
     #[allow(non_camel_case_types)]
@@ -117,12 +187,58 @@ pub enum UtxosFilterInRequest {
     page(Page),
 }
 
+// The encoded form of `UtxosFilterInRequest`, see `NetworkInRequestRepr`.
+#[derive(CandidType, Serialize)]
+#[serde(rename = "UtxosFilterInRequest")]
+#[allow(dead_code, non_camel_case_types)]
+enum UtxosFilterInRequestRepr {
+    MinConfirmations(u32),
+    min_confirmations(u32),
+    Page(Page),
+    page(Page),
+}
+
+impl From<&UtxosFilterInRequest> for UtxosFilterInRequestRepr {
+    fn from(filter: &UtxosFilterInRequest) -> Self {
+        match filter {
+            UtxosFilterInRequest::MinConfirmations(x)
+            | UtxosFilterInRequest::min_confirmations(x) => Self::min_confirmations(*x),
+            UtxosFilterInRequest::Page(p) | UtxosFilterInRequest::page(p) => Self::page(p.clone()),
+        }
+    }
+}
+
+impl CandidType for UtxosFilterInRequest {
+    fn _ty() -> candid::types::Type {
+        UtxosFilterInRequestRepr::_ty()
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: candid::types::Serializer,
+    {
+        UtxosFilterInRequestRepr::from(self).idl_serialize(serializer)
+    }
+}
+
+impl Serialize for UtxosFilterInRequest {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        UtxosFilterInRequestRepr::from(self).serialize(serializer)
+    }
+}
+
 /// A request for getting the UTXOs for a given address.
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
 pub struct GetUtxosRequest {
     pub address: Address,
     pub network: NetworkInRequest,
     pub filter: Option<UtxosFilterInRequest>,
+    /// Whether to reply with a `GetUtxosCompactResponse` instead of a
+    /// `GetUtxosResponse`, see the `compact` module. Defaults to `false`.
+    pub compact: Option<bool>,
 }
 
 /// The response returned for a request to get the UTXOs of a given address.
@@ -132,15 +248,38 @@ pub struct GetUtxosResponse {
     pub tip_block_hash: BlockHash,
     pub tip_height: u32,
     pub next_page: Option<Page>,
+    /// The number of blocks on top of `tip_height` in the chain the response
+    /// was computed from. The lower the count, the more likely the response
+    /// is invalidated by a reorg. `None` if not reported.
+    pub stability_count: Option<u32>,
+    /// The number of UTXOs of the address over all pages, reported on the
+    /// first page only. `None` if not reported.
+    pub total_count: Option<u64>,
+    /// Whether more UTXOs can be retrieved with `next_page`. `None` if not
+    /// reported.
+    pub has_more: Option<bool>,
 }
 
 /// Errors when processing a `get_utxos` request.
 #[derive(CandidType, Debug, Deserialize, PartialEq, Clone)]
 pub enum GetUtxosError {
     MalformedAddress,
-    MinConfirmationsTooLarge { given: u32, max: u32 },
-    UnknownTipBlockHash { tip_block_hash: BlockHash },
-    MalformedPage { err: String },
+    MinConfirmationsTooLarge {
+        given: u32,
+        max: u32,
+    },
+    UnknownTipBlockHash {
+        tip_block_hash: BlockHash,
+    },
+    MalformedPage {
+        err: String,
+    },
+    /// The tip of the page is no longer on the main chain, e.g. because of a
+    /// reorg. Pagination must restart with a request without a page.
+    TipChanged {
+        tip_block_hash: BlockHash,
+        current_tip_block_hash: BlockHash,
+    },
 }
 
 /// A request for getting the current fee percentiles.
@@ -149,6 +288,74 @@ pub struct GetCurrentFeePercentilesRequest {
     pub network: NetworkInRequest,
 }
 
+/// A block of the main chain, by height or by hash.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub enum BlockRef {
+    Height(Height),
+    BlockHash(BlockHash),
+}
+
+/// A request for getting the fee percentiles of the window ending at a past
+/// block, rather than at the current tip.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct GetFeePercentilesAtHeightRequest {
+    pub network: NetworkInRequest,
+    pub block: BlockRef,
+}
+
+/// The fee percentiles of the window ending at the requested block.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub struct GetFeePercentilesAtHeightResponse {
+    pub fee_percentiles: Vec<MillisatoshiPerByte>,
+    pub block_hash: BlockHash,
+    pub height: Height,
+}
+
+/// Errors when processing a `GetFeePercentilesAtHeightRequest`.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub enum GetFeePercentilesAtHeightError {
+    /// The height is above the tip of the main chain.
+    UnknownHeight { height: Height, tip_height: Height },
+    /// The block hash is unknown, or not on the main chain.
+    UnknownBlockHash { block_hash: BlockHash },
+    /// The block is known, but its fee percentiles are no longer retained.
+    HeightPruned {
+        height: Height,
+        min_retained_height: Height,
+    },
+}
+
+impl std::fmt::Display for GetFeePercentilesAtHeightError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownHeight { height, tip_height } => {
+                write!(
+                    f,
+                    "The height {} is above the tip of the main chain at height {}.",
+                    height, tip_height
+                )
+            }
+            Self::UnknownBlockHash { block_hash } => {
+                write!(
+                    f,
+                    "The block hash {:?} is not on the main chain.",
+                    block_hash
+                )
+            }
+            Self::HeightPruned {
+                height,
+                min_retained_height,
+            } => {
+                write!(
+                    f,
+                    "The fee percentiles at height {} are pruned, the lowest retained height is {}.",
+                    height, min_retained_height
+                )
+            }
+        }
+    }
+}
+
 impl std::fmt::Display for GetUtxosError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -172,6 +379,16 @@ impl std::fmt::Display for GetUtxosError {
             Self::MalformedPage { err } => {
                 write!(f, "The provided page is malformed {}", err)
             }
+            Self::TipChanged {
+                tip_block_hash,
+                current_tip_block_hash,
+            } => {
+                write!(
+                    f,
+                    "The tip block hash {:?} of the provided page is no longer on the main chain, whose tip is {:?}. Restart without a page.",
+                    tip_block_hash, current_tip_block_hash
+                )
+            }
         }
     }
 }
@@ -215,8 +432,9 @@ pub struct SendTransactionRequest {
 
 #[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
 pub enum SendTransactionError {
-    /// Can't deserialize transaction.
-    MalformedTransaction,
+    /// Can't deserialize transaction. The offset is the one of the byte at
+    /// which decoding failed, and the reason why it did.
+    MalformedTransaction { offset: u64, reason: String },
     /// Enqueueing a request failed due to full queue to the Bitcoin adapter.
     QueueFull,
 }
@@ -224,8 +442,12 @@ pub enum SendTransactionError {
 impl std::fmt::Display for SendTransactionError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::MalformedTransaction => {
-                write!(f, "Can't deserialize transaction because it's malformed.")
+            Self::MalformedTransaction { offset, reason } => {
+                write!(
+                    f,
+                    "Can't deserialize transaction because it's malformed at byte {}: {}.",
+                    offset, reason
+                )
             }
             Self::QueueFull => {
                 write!(
@@ -236,3 +458,283 @@ impl std::fmt::Display for SendTransactionError {
         }
     }
 }
+
+/// A request for mining blocks on a regtest network, e.g. to confirm
+/// transactions in local development environments running `bitcoind` in
+/// regtest mode. Only served on regtest.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub struct GenerateBlocksRequest {
+    /// The number of blocks to mine.
+    pub count: u32,
+    /// The address the coinbase outputs are paid to. Defaults to an address
+    /// of the node if not set.
+    pub address: Option<Address>,
+    pub network: NetworkInRequest,
+}
+
+/// The response returned for a successful `GenerateBlocksRequest`.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub struct GenerateBlocksResponse {
+    /// The hashes of the mined blocks, in the order they were mined.
+    pub block_hashes: Vec<BlockHash>,
+    pub tip_height: Height,
+}
+
+/// Errors when processing a `GenerateBlocksRequest`.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub enum GenerateBlocksError {
+    /// Blocks can only be generated on regtest.
+    NotRegtest {
+        network: Network,
+    },
+    TooManyBlocks {
+        given: u32,
+        max: u32,
+    },
+    MalformedAddress {
+        address: Address,
+    },
+}
+
+impl std::fmt::Display for GenerateBlocksError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotRegtest { network } => {
+                write!(
+                    f,
+                    "Blocks can only be generated on regtest, not {}.",
+                    network
+                )
+            }
+            Self::TooManyBlocks { given, max } => {
+                write!(
+                    f,
+                    "Too many blocks to generate. Given: {}, max supported: {}",
+                    given, max
+                )
+            }
+            Self::MalformedAddress { address } => {
+                write!(f, "Malformed address {}.", address)
+            }
+        }
+    }
+}
+
+/// A request for being notified of changes to the UTXOs of the given
+/// addresses. Notifications are delivered by calling `callback_method` on the
+/// subscribing canister with a `UtxoChangeNotification`.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub struct SubscribeUtxoChangesRequest {
+    pub addresses: Vec<Address>,
+    pub network: NetworkInRequest,
+    /// UTXOs are reported once they have at least this many confirmations.
+    /// Defaults to 0, i.e. UTXOs are reported as soon as they are in a block.
+    pub min_confirmations: Option<u32>,
+    pub callback_method: String,
+}
+
+/// The response returned for a successful `SubscribeUtxoChangesRequest`. The
+/// first notification reports the changes after the given tip.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub struct SubscribeUtxoChangesResponse {
+    pub subscription_id: u64,
+    pub tip_block_hash: BlockHash,
+    pub tip_height: Height,
+}
+
+/// A request for cancelling a subscription.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub struct UnsubscribeUtxoChangesRequest {
+    pub subscription_id: u64,
+}
+
+/// Errors when processing a `SubscribeUtxoChangesRequest`.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub enum SubscribeUtxoChangesError {
+    MalformedAddress { address: Address },
+    TooManyAddresses { given: u32, max: u32 },
+    MinConfirmationsTooLarge { given: u32, max: u32 },
+    UnknownSubscription { subscription_id: u64 },
+}
+
+impl std::fmt::Display for SubscribeUtxoChangesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MalformedAddress { address } => {
+                write!(f, "Malformed address {}.", address)
+            }
+            Self::TooManyAddresses { given, max } => {
+                write!(
+                    f,
+                    "Too many addresses to subscribe to. Given: {}, max supported: {}",
+                    given, max
+                )
+            }
+            Self::MinConfirmationsTooLarge { given, max } => {
+                write!(
+                    f,
+                    "The requested min_confirmations is too large. Given: {}, max supported: {}",
+                    given, max
+                )
+            }
+            Self::UnknownSubscription { subscription_id } => {
+                write!(f, "The subscription {} is unknown.", subscription_id)
+            }
+        }
+    }
+}
+
+/// A change to the UTXOs of a subscribed address.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub enum UtxoChangeEvent {
+    /// A new UTXO reached the subscription's `min_confirmations`.
+    Added { address: Address, utxo: Utxo },
+    /// A UTXO was spent by a transaction in the block at `height`.
+    Removed {
+        address: Address,
+        outpoint: OutPoint,
+        height: Height,
+    },
+    /// The chain was reorganized. Events reported for blocks after
+    /// `common_ancestor_height` are void, the `Added` and `Removed` events
+    /// following this one in the notification reflect the new chain.
+    Reorged {
+        common_ancestor_block_hash: BlockHash,
+        common_ancestor_height: Height,
+        depth: u32,
+    },
+}
+
+/// The argument of the callback of a subscription. Events are ordered as
+/// they happened on the chain, up to the given tip.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub struct UtxoChangeNotification {
+    pub subscription_id: u64,
+    pub network: Network,
+    pub tip_block_hash: BlockHash,
+    pub tip_height: Height,
+    pub events: Vec<UtxoChangeEvent>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::{Decode, Encode};
+
+    #[test]
+    fn networks_are_encoded_in_lowercase() {
+        for (legacy, canonical) in [
+            (NetworkInRequest::Mainnet, NetworkInRequest::mainnet),
+            (NetworkInRequest::Testnet, NetworkInRequest::testnet),
+            (NetworkInRequest::Regtest, NetworkInRequest::regtest),
+        ] {
+            let bytes = Encode!(&legacy).unwrap();
+            assert_eq!(bytes, Encode!(&canonical).unwrap());
+            assert_eq!(Decode!(&bytes, NetworkInRequest).unwrap(), canonical);
+        }
+
+        // Uppercase variants are still accepted.
+        let bytes = Encode!(&NetworkInRequestRepr::Mainnet).unwrap();
+        assert_eq!(
+            Decode!(&bytes, NetworkInRequest).unwrap(),
+            NetworkInRequest::Mainnet
+        );
+    }
+
+    #[test]
+    fn utxos_filters_are_encoded_in_lowercase() {
+        let page = Page::from(vec![1, 2, 3]);
+        for (legacy, canonical) in [
+            (
+                UtxosFilterInRequest::MinConfirmations(6),
+                UtxosFilterInRequest::min_confirmations(6),
+            ),
+            (
+                UtxosFilterInRequest::Page(page.clone()),
+                UtxosFilterInRequest::page(page.clone()),
+            ),
+        ] {
+            let bytes = Encode!(&legacy).unwrap();
+            assert_eq!(bytes, Encode!(&canonical).unwrap());
+            assert_eq!(Decode!(&bytes, UtxosFilterInRequest).unwrap(), canonical);
+        }
+
+        let bytes = Encode!(&UtxosFilterInRequestRepr::Page(page.clone())).unwrap();
+        assert_eq!(
+            Decode!(&bytes, UtxosFilterInRequest).unwrap(),
+            UtxosFilterInRequest::Page(page)
+        );
+    }
+
+    // `Utxo` before its height was a `Height`.
+    #[derive(CandidType, Debug, Deserialize, PartialEq)]
+    struct LegacyUtxo {
+        outpoint: OutPoint,
+        value: Satoshi,
+        height: u32,
+    }
+
+    #[test]
+    fn utxos_are_encoded_as_before() {
+        assert_eq!(Utxo::ty(), LegacyUtxo::ty());
+
+        let outpoint = OutPoint {
+            txid: vec![1; 32],
+            vout: 2,
+        };
+        let utxo = Utxo {
+            outpoint: outpoint.clone(),
+            value: 100_000,
+            height: Height::new(700_000),
+        };
+        let legacy = LegacyUtxo {
+            outpoint,
+            value: 100_000,
+            height: 700_000,
+        };
+        let bytes = Encode!(&utxo).unwrap();
+        assert_eq!(bytes, Encode!(&legacy).unwrap());
+        assert_eq!(Decode!(&bytes, LegacyUtxo).unwrap(), legacy);
+        assert_eq!(Decode!(&bytes, Utxo).unwrap(), utxo);
+    }
+
+    // `GetUtxosResponse` before `total_count` and `has_more`.
+    #[derive(CandidType, Debug, Deserialize, PartialEq)]
+    struct LegacyGetUtxosResponse {
+        utxos: Vec<Utxo>,
+        tip_block_hash: BlockHash,
+        tip_height: u32,
+        next_page: Option<Page>,
+        stability_count: Option<u32>,
+    }
+
+    #[test]
+    fn utxos_responses_are_backwards_compatible() {
+        let legacy = LegacyGetUtxosResponse {
+            utxos: vec![],
+            tip_block_hash: vec![1; 32],
+            tip_height: 100,
+            next_page: Some(Page::from(vec![1, 2, 3])),
+            stability_count: Some(2),
+        };
+        let response = GetUtxosResponse {
+            utxos: vec![],
+            tip_block_hash: vec![1; 32],
+            tip_height: 100,
+            next_page: Some(Page::from(vec![1, 2, 3])),
+            stability_count: Some(2),
+            total_count: None,
+            has_more: None,
+        };
+        let bytes = Encode!(&legacy).unwrap();
+        assert_eq!(Decode!(&bytes, GetUtxosResponse).unwrap(), response);
+
+        let bytes = Encode!(&GetUtxosResponse {
+            total_count: Some(1_000),
+            has_more: Some(true),
+            ..response
+        })
+        .unwrap();
+        assert_eq!(Decode!(&bytes, LegacyGetUtxosResponse).unwrap(), legacy);
+    }
+}
@@ -0,0 +1,326 @@
+//! Proofs of control of a Bitcoin address.
+//!
+//! The owner of an address proves control of it by signing a message, e.g. a
+//! challenge issued by a canister, with the key of the address. Signatures
+//! follow BIP-137 ("Bitcoin Signed Message"), as produced by most wallets,
+//! which covers P2PKH, P2SH-P2WPKH and P2WPKH addresses. Electrum signs
+//! with the headers of compressed P2PKH addresses for all of them, so these
+//! headers are accepted for segwit addresses of the same key too.
+//!
+//! Verification requires the `ownership` feature.
+
+use crate::{Address, Network};
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+
+/// The length of a BIP-137 signature: a header byte followed by the `r` and
+/// `s` values of the ECDSA signature.
+pub const SIGNATURE_LEN: usize = 65;
+
+/// A proof that the owner of `address` on `network` signed `message`.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct AddressOwnershipProofRequest {
+    pub address: Address,
+    pub network: Network,
+    pub message: String,
+    /// The BIP-137 signature of the message. Wallets usually display it
+    /// base64-encoded.
+    pub signature: ByteBuf,
+}
+
+/// The address whose ownership was proven.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct AddressOwnershipProofResponse {
+    pub address: Address,
+    pub network: Network,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum AddressOwnershipProofError {
+    /// The signature is not a BIP-137 signature.
+    MalformedSignature,
+    /// No public key can be recovered from the signature.
+    InvalidSignature,
+    /// The message was signed by the key of another address.
+    AddressMismatch { signer: Address },
+}
+
+impl std::fmt::Display for AddressOwnershipProofError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MalformedSignature => write!(
+                f,
+                "Malformed signature, expected a {}-byte BIP-137 signature",
+                SIGNATURE_LEN
+            ),
+            Self::InvalidSignature => write!(f, "Invalid signature"),
+            Self::AddressMismatch { signer } => {
+                write!(f, "The message was signed by the key of {}", signer)
+            }
+        }
+    }
+}
+
+/// The type of the address of the signer, as encoded in the header byte of a
+/// BIP-137 signature.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SignerAddressType {
+    /// P2PKH address of an uncompressed public key.
+    P2pkhUncompressed,
+    P2pkh,
+    /// P2WPKH address nested in a P2SH address.
+    P2shP2wpkh,
+    P2wpkh,
+}
+
+/// Returns the type of the address of the signer and the recovery id of the
+/// signature, given its header byte.
+pub fn parse_header(header: u8) -> Option<(SignerAddressType, u8)> {
+    let address_type = match header {
+        27..=30 => SignerAddressType::P2pkhUncompressed,
+        31..=34 => SignerAddressType::P2pkh,
+        35..=38 => SignerAddressType::P2shP2wpkh,
+        39..=42 => SignerAddressType::P2wpkh,
+        _ => return None,
+    };
+    Some((address_type, (header - 27) % 4))
+}
+
+#[cfg(feature = "ownership")]
+pub use verification::*;
+
+#[cfg(feature = "ownership")]
+mod verification {
+    use super::*;
+    use crate::consts::{consts, NetworkConsts};
+    use bech32::{u5, ToBase32, Variant};
+    use k256::ecdsa::{recoverable, Signature};
+    use std::convert::TryFrom;
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        use sha2::Digest;
+        sha2::Sha256::digest(data).into()
+    }
+
+    fn hash160(data: &[u8]) -> [u8; 20] {
+        use ripemd::Digest;
+        ripemd::Ripemd160::digest(&sha256(data)).into()
+    }
+
+    fn base58check(version: u8, payload: &[u8]) -> String {
+        let mut bytes = vec![version];
+        bytes.extend_from_slice(payload);
+        bs58::encode(bytes).with_check().into_string()
+    }
+
+    fn p2wpkh(network: Network, pubkey_hash: &[u8; 20]) -> String {
+        let hrp = consts(network).bech32_hrp;
+        let mut data = vec![u5::try_from_u8(0).expect("0 is a valid witness version")];
+        data.extend(pubkey_hash.to_base32());
+        bech32::encode(hrp, data, Variant::Bech32).expect("The human-readable part is valid")
+    }
+
+    /// Returns the hash that is signed to sign `message`, i.e. the double
+    /// SHA-256 of the message prefixed with "Bitcoin Signed Message:\n".
+    pub fn signed_message_hash(message: &str) -> [u8; 32] {
+        let mut bytes = b"\x18Bitcoin Signed Message:\n".to_vec();
+        let len = message.len() as u64;
+        match len {
+            0..=0xfc => bytes.push(len as u8),
+            0xfd..=0xffff => {
+                bytes.push(0xfd);
+                bytes.extend_from_slice(&(len as u16).to_le_bytes());
+            }
+            0x10000..=0xffff_ffff => {
+                bytes.push(0xfe);
+                bytes.extend_from_slice(&(len as u32).to_le_bytes());
+            }
+            _ => {
+                bytes.push(0xff);
+                bytes.extend_from_slice(&len.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(message.as_bytes());
+        sha256(&sha256(&bytes))
+    }
+
+    /// Returns the address of the given type of a public key, in SEC1
+    /// encoding, on `network`.
+    pub fn address(
+        network: Network,
+        address_type: SignerAddressType,
+        public_key: &[u8],
+    ) -> Address {
+        let NetworkConsts {
+            p2pkh_version,
+            p2sh_version,
+            ..
+        } = *consts(network);
+        let pubkey_hash = hash160(public_key);
+        match address_type {
+            SignerAddressType::P2pkhUncompressed | SignerAddressType::P2pkh => {
+                base58check(p2pkh_version, &pubkey_hash)
+            }
+            SignerAddressType::P2shP2wpkh => {
+                let mut redeem_script = vec![0x00, 0x14];
+                redeem_script.extend_from_slice(&pubkey_hash);
+                base58check(p2sh_version, &hash160(&redeem_script))
+            }
+            SignerAddressType::P2wpkh => p2wpkh(network, &pubkey_hash),
+        }
+    }
+
+    impl AddressOwnershipProofRequest {
+        /// Checks that the message was signed by the key of the address.
+        pub fn verify(&self) -> Result<AddressOwnershipProofResponse, AddressOwnershipProofError> {
+            if self.signature.len() != SIGNATURE_LEN {
+                return Err(AddressOwnershipProofError::MalformedSignature);
+            }
+            let (address_type, recovery_id) = parse_header(self.signature[0])
+                .ok_or(AddressOwnershipProofError::MalformedSignature)?;
+            let signature = Signature::try_from(&self.signature[1..])
+                .map_err(|_| AddressOwnershipProofError::MalformedSignature)?;
+            let recovery_id = recoverable::Id::new(recovery_id)
+                .map_err(|_| AddressOwnershipProofError::MalformedSignature)?;
+            let public_key = recoverable::Signature::new(&signature, recovery_id)
+                .and_then(|signature| {
+                    signature.recover_verifying_key_from_digest_bytes(
+                        &signed_message_hash(&self.message).into(),
+                    )
+                })
+                .map_err(|_| AddressOwnershipProofError::InvalidSignature)?;
+            let compressed = address_type != SignerAddressType::P2pkhUncompressed;
+            let public_key = public_key.to_encoded_point(compressed);
+            // Electrum uses the headers of P2PKH addresses for segwit
+            // addresses as well.
+            let address_types: &[SignerAddressType] = match address_type {
+                SignerAddressType::P2pkh => &[
+                    SignerAddressType::P2pkh,
+                    SignerAddressType::P2shP2wpkh,
+                    SignerAddressType::P2wpkh,
+                ],
+                _ => std::slice::from_ref(&address_type),
+            };
+            let matches = address_types.iter().any(|address_type| {
+                let signer = address(self.network, *address_type, public_key.as_bytes());
+                // Bech32 addresses are case-insensitive.
+                match address_type {
+                    SignerAddressType::P2wpkh => signer.eq_ignore_ascii_case(&self.address),
+                    _ => signer == self.address,
+                }
+            });
+            if !matches {
+                return Err(AddressOwnershipProofError::AddressMismatch {
+                    signer: address(self.network, address_type, public_key.as_bytes()),
+                });
+            }
+            Ok(AddressOwnershipProofResponse {
+                address: self.address.clone(),
+                network: self.network,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_encode_the_address_type_and_recovery_id() {
+        assert_eq!(
+            parse_header(27),
+            Some((SignerAddressType::P2pkhUncompressed, 0))
+        );
+        assert_eq!(parse_header(32), Some((SignerAddressType::P2pkh, 1)));
+        assert_eq!(parse_header(38), Some((SignerAddressType::P2shP2wpkh, 3)));
+        assert_eq!(parse_header(40), Some((SignerAddressType::P2wpkh, 1)));
+        assert_eq!(parse_header(26), None);
+        assert_eq!(parse_header(43), None);
+    }
+
+    #[cfg(feature = "ownership")]
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[cfg(feature = "ownership")]
+    #[test]
+    fn signatures_are_verified_against_the_address() {
+        // Signed with the key sha256("ic-btc-types ownership proof").
+        let signature = hex(
+            "eb5feb80198eb889e7a3a4075c09c538cbb89ab2491b79caae6dfd8e1729b74f\
+             4a2d6d6d13df0a98a90c3bb8f857c8cb32cd19d773c3c7431852dc843c4f455c",
+        );
+        let proof = |address: &str, header: u8| {
+            let mut bytes = vec![header];
+            bytes.extend_from_slice(&signature);
+            AddressOwnershipProofRequest {
+                address: address.to_string(),
+                network: Network::Mainnet,
+                message: "I own this address".to_string(),
+                signature: ByteBuf::from(bytes),
+            }
+        };
+
+        for (address, header) in [
+            ("bc1qn743kdaj3q9c4mqz070rrw2ka6n96zz7jc7np5", 40),
+            ("BC1QN743KDAJ3Q9C4MQZ070RRW2KA6N96ZZ7JC7NP5", 40),
+            ("1FZFRBEfq4fFsKeCcYuD4MPNCzHaDVtERK", 32),
+            ("3KZCmX2qGT8MR2cEVXPWnfHgBAZzqLWFbE", 36),
+        ] {
+            assert!(proof(address, header).verify().is_ok(), "{}", address);
+        }
+
+        // Electrum signs segwit addresses with P2PKH headers.
+        for address in [
+            "bc1qn743kdaj3q9c4mqz070rrw2ka6n96zz7jc7np5",
+            "3KZCmX2qGT8MR2cEVXPWnfHgBAZzqLWFbE",
+        ] {
+            assert!(proof(address, 32).verify().is_ok(), "{}", address);
+        }
+        // But segwit headers don't match P2PKH addresses.
+        assert_eq!(
+            proof("1FZFRBEfq4fFsKeCcYuD4MPNCzHaDVtERK", 40).verify(),
+            Err(AddressOwnershipProofError::AddressMismatch {
+                signer: "bc1qn743kdaj3q9c4mqz070rrw2ka6n96zz7jc7np5".to_string()
+            })
+        );
+
+        let mut other_message = proof("bc1qn743kdaj3q9c4mqz070rrw2ka6n96zz7jc7np5", 40);
+        other_message.message = "I own another address".to_string();
+        assert!(matches!(
+            other_message.verify(),
+            Err(AddressOwnershipProofError::AddressMismatch { .. })
+        ));
+
+        let mut testnet = proof("bc1qn743kdaj3q9c4mqz070rrw2ka6n96zz7jc7np5", 40);
+        testnet.network = Network::Testnet;
+        assert_eq!(
+            testnet.verify(),
+            Err(AddressOwnershipProofError::AddressMismatch {
+                signer: "tb1qn743kdaj3q9c4mqz070rrw2ka6n96zz7c79q68".to_string()
+            })
+        );
+
+        let mut truncated = proof("bc1qn743kdaj3q9c4mqz070rrw2ka6n96zz7jc7np5", 40);
+        truncated.signature.pop();
+        assert_eq!(
+            truncated.verify(),
+            Err(AddressOwnershipProofError::MalformedSignature)
+        );
+    }
+
+    #[cfg(feature = "ownership")]
+    #[test]
+    fn messages_are_hashed_as_bitcoin_signed_messages() {
+        assert_eq!(
+            signed_message_hash("I own this address").to_vec(),
+            hex("cfa5ee34b31f111e27557230ab378726abb56b4180615f730a1adbfd7d938c55")
+        );
+    }
+}
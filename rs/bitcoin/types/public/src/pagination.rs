@@ -0,0 +1,275 @@
+//! Pagination of `get_utxos` requests for client canisters.
+//!
+//! Addresses with many UTXOs are answered in pages, each request carrying the
+//! `next_page` of the previous response. If the tip the pages were computed
+//! from leaves the main chain in between, the canister rejects the next page
+//! with `TipChanged`, and pagination must restart from the first page, as the
+//! pages received so far are stale.
+//!
+//! [`UtxosPages`] chains the requests and restarts as needed, on top of a
+//! call function supplied by the canister, so that it does not depend on any
+//! particular CDK or executor:
+//!
+//! ```ignore
+//! let mut pages = utxos_pages(request, |request| async move {
+//!     let (response,) = call(management_canister, "bitcoin_get_utxos", (request,)).await?;
+//!     Ok(response)
+//! });
+//! let mut utxos = vec![];
+//! while let Some(page) = pages.next().await {
+//!     match page? {
+//!         UtxosPage::Page(response) => utxos.extend(response.utxos),
+//!         UtxosPage::Restarted => utxos.clear(),
+//!     }
+//! }
+//! ```
+
+use crate::{
+    Address, GetUtxosError, GetUtxosRequest, GetUtxosResponse, NetworkInRequest, Page,
+    UtxosFilterInRequest,
+};
+use std::future::Future;
+
+/// The maximum number of times pagination restarts from the first page before
+/// `TipChanged` is returned, so that a chain reorganizing faster than pages
+/// are retrieved does not make a canister spend its cycles indefinitely.
+pub const MAX_RESTARTS: u32 = 3;
+
+/// Errors of `get_utxos` calls, which tell whether pagination must restart.
+pub trait PaginationError {
+    /// Whether the tip of the pages left the main chain.
+    fn tip_changed(&self) -> bool;
+}
+
+impl PaginationError for GetUtxosError {
+    fn tip_changed(&self) -> bool {
+        matches!(self, GetUtxosError::TipChanged { .. })
+    }
+}
+
+/// The outcome of a `get_utxos` call of a pagination.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UtxosPage {
+    /// The next page of UTXOs.
+    Page(GetUtxosResponse),
+    /// The tip of the pages left the main chain. The UTXOs of the pages so
+    /// far must be discarded, pagination restarts from the first page.
+    Restarted,
+}
+
+/// The state of a pagination, independent of how requests are sent.
+#[derive(Clone, Debug)]
+pub struct UtxosPagination {
+    address: Address,
+    network: NetworkInRequest,
+    min_confirmations: Option<u32>,
+    next_page: Option<Page>,
+    restarts: u32,
+    finished: bool,
+}
+
+impl UtxosPagination {
+    /// Starts paginating the UTXOs requested by `request`, from its page if it
+    /// has one. Compact responses are not requested.
+    pub fn new(request: GetUtxosRequest) -> Self {
+        let (min_confirmations, next_page) = match request.filter {
+            None => (None, None),
+            Some(UtxosFilterInRequest::MinConfirmations(min_confirmations))
+            | Some(UtxosFilterInRequest::min_confirmations(min_confirmations)) => {
+                (Some(min_confirmations), None)
+            }
+            Some(UtxosFilterInRequest::Page(page)) | Some(UtxosFilterInRequest::page(page)) => {
+                (None, Some(page))
+            }
+        };
+        Self {
+            address: request.address,
+            network: request.network,
+            min_confirmations,
+            next_page,
+            restarts: 0,
+            finished: false,
+        }
+    }
+
+    /// The request for the next page, `None` once all pages were received or
+    /// after an error.
+    pub fn next_request(&self) -> Option<GetUtxosRequest> {
+        if self.finished {
+            return None;
+        }
+        let filter = match &self.next_page {
+            Some(page) => Some(UtxosFilterInRequest::page(page.clone())),
+            None => self
+                .min_confirmations
+                .map(UtxosFilterInRequest::min_confirmations),
+        };
+        Some(GetUtxosRequest {
+            address: self.address.clone(),
+            network: self.network,
+            filter,
+            compact: None,
+        })
+    }
+
+    /// Advances the pagination with the result of the request returned by
+    /// [`next_request`](Self::next_request).
+    pub fn on_response<E: PaginationError>(
+        &mut self,
+        result: Result<GetUtxosResponse, E>,
+    ) -> Result<UtxosPage, E> {
+        match result {
+            Ok(response) => {
+                self.next_page = response.next_page.clone();
+                self.finished = self.next_page.is_none();
+                Ok(UtxosPage::Page(response))
+            }
+            // Only pages after the first can have a stale tip.
+            Err(err)
+                if err.tip_changed()
+                    && self.next_page.is_some()
+                    && self.restarts < MAX_RESTARTS =>
+            {
+                self.restarts += 1;
+                self.next_page = None;
+                Ok(UtxosPage::Restarted)
+            }
+            Err(err) => {
+                self.finished = true;
+                Err(err)
+            }
+        }
+    }
+}
+
+/// The pages of UTXOs of an address, retrieved with a call function.
+pub struct UtxosPages<F> {
+    pagination: UtxosPagination,
+    call: F,
+}
+
+/// Paginates the UTXOs requested by `request`, sending requests with `call`.
+pub fn utxos_pages<F, Fut, E>(request: GetUtxosRequest, call: F) -> UtxosPages<F>
+where
+    F: FnMut(GetUtxosRequest) -> Fut,
+    Fut: Future<Output = Result<GetUtxosResponse, E>>,
+    E: PaginationError,
+{
+    UtxosPages {
+        pagination: UtxosPagination::new(request),
+        call,
+    }
+}
+
+impl<F, Fut, E> UtxosPages<F>
+where
+    F: FnMut(GetUtxosRequest) -> Fut,
+    Fut: Future<Output = Result<GetUtxosResponse, E>>,
+    E: PaginationError,
+{
+    /// Retrieves the next page, `None` once all pages were retrieved or after
+    /// an error.
+    pub async fn next(&mut self) -> Option<Result<UtxosPage, E>> {
+        let request = self.pagination.next_request()?;
+        let result = (self.call)(request).await;
+        Some(self.pagination.on_response(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(filter: Option<UtxosFilterInRequest>) -> GetUtxosRequest {
+        GetUtxosRequest {
+            address: "bc1q".to_string(),
+            network: NetworkInRequest::mainnet,
+            filter,
+            compact: None,
+        }
+    }
+
+    fn response(next_page: Option<&str>) -> GetUtxosResponse {
+        GetUtxosResponse {
+            utxos: vec![],
+            tip_block_hash: vec![1; 32],
+            tip_height: 100,
+            next_page: next_page.map(|page| Page::from(page.as_bytes().to_vec())),
+            stability_count: None,
+            total_count: None,
+            has_more: Some(next_page.is_some()),
+        }
+    }
+
+    fn tip_changed() -> GetUtxosError {
+        GetUtxosError::TipChanged {
+            tip_block_hash: vec![1; 32],
+            current_tip_block_hash: vec![2; 32],
+        }
+    }
+
+    #[test]
+    fn pages_are_chained() {
+        let mut pagination =
+            UtxosPagination::new(request(Some(UtxosFilterInRequest::MinConfirmations(6))));
+        assert_eq!(
+            pagination.next_request(),
+            Some(request(Some(UtxosFilterInRequest::min_confirmations(6))))
+        );
+        assert_eq!(
+            pagination.on_response::<GetUtxosError>(Ok(response(Some("page")))),
+            Ok(UtxosPage::Page(response(Some("page"))))
+        );
+        assert_eq!(
+            pagination.next_request(),
+            Some(request(Some(UtxosFilterInRequest::page(Page::from(
+                b"page".to_vec()
+            )))))
+        );
+        assert!(pagination
+            .on_response::<GetUtxosError>(Ok(response(None)))
+            .is_ok());
+        assert_eq!(pagination.next_request(), None);
+    }
+
+    #[test]
+    fn pagination_restarts_when_the_tip_changes() {
+        let mut pagination = UtxosPagination::new(request(None));
+        assert!(pagination
+            .on_response::<GetUtxosError>(Ok(response(Some("page"))))
+            .is_ok());
+        for _ in 0..MAX_RESTARTS {
+            assert_eq!(
+                pagination.on_response(Err(tip_changed())),
+                Ok(UtxosPage::Restarted)
+            );
+            assert_eq!(pagination.next_request(), Some(request(None)));
+            assert!(pagination
+                .on_response::<GetUtxosError>(Ok(response(Some("page"))))
+                .is_ok());
+        }
+        assert_eq!(
+            pagination.on_response(Err(tip_changed())),
+            Err(tip_changed())
+        );
+        assert_eq!(pagination.next_request(), None);
+    }
+
+    #[test]
+    fn errors_end_the_pagination() {
+        let mut pagination = UtxosPagination::new(request(None));
+        // The first page can't have a stale tip, the error is returned as is.
+        assert_eq!(
+            pagination.on_response(Err(tip_changed())),
+            Err(tip_changed())
+        );
+        assert_eq!(pagination.next_request(), None);
+
+        let mut pagination = UtxosPagination::new(request(None));
+        assert_eq!(
+            pagination.on_response::<GetUtxosError>(Err(GetUtxosError::MalformedAddress)),
+            Err(GetUtxosError::MalformedAddress)
+        );
+        assert_eq!(pagination.next_request(), None);
+    }
+}
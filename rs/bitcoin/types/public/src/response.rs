@@ -0,0 +1,130 @@
+//! Responses of the Bitcoin API along with the cycles charged for them.
+//!
+//! The management canister refunds the cycles attached to a call in excess
+//! of its cost. Wrapping replies in a [`Charged`] response keeps the cycles
+//! attached and refunded next to the response, so that canisters can
+//! reconcile their spending per request.
+
+use crate::{
+    compact::GetUtxosCompactResponse, GenerateBlocksResponse, GetFeePercentilesAtHeightResponse,
+    GetUtxosResponse, MillisatoshiPerByte, Satoshi,
+};
+use candid::{CandidType, Deserialize};
+
+/// A response of the Bitcoin API, with the cycles attached to the call and
+/// refunded by the management canister.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Charged<T> {
+    pub response: T,
+    pub cycles_attached: u128,
+    pub cycles_refunded: u128,
+}
+
+impl<T> Charged<T> {
+    /// Returns the cycles the management canister kept, i.e. the cost of the
+    /// call.
+    pub fn cycles_accepted(&self) -> u128 {
+        self.cycles_attached.saturating_sub(self.cycles_refunded)
+    }
+
+    /// Maps the response, keeping the cycles.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Charged<U> {
+        Charged {
+            response: f(self.response),
+            cycles_attached: self.cycles_attached,
+            cycles_refunded: self.cycles_refunded,
+        }
+    }
+}
+
+impl<T: Reply> Charged<T> {
+    /// Decodes the candid-encoded reply of the management canister to a call
+    /// with `cycles_attached`, of which `cycles_refunded` were refunded.
+    pub fn from_reply(
+        reply: &[u8],
+        cycles_attached: u128,
+        cycles_refunded: u128,
+    ) -> Result<Self, candid::Error> {
+        Ok(Self {
+            response: T::decode_reply(reply)?,
+            cycles_attached,
+            cycles_refunded,
+        })
+    }
+}
+
+/// A response type of the Bitcoin API, in the reply format of the
+/// management canister.
+pub trait Reply: Sized {
+    /// Decodes the candid-encoded reply of the management canister.
+    fn decode_reply(reply: &[u8]) -> Result<Self, candid::Error>;
+}
+
+fn decode_one<T>(reply: &[u8]) -> Result<T, candid::Error>
+where
+    T: CandidType + for<'de> Deserialize<'de>,
+{
+    candid::decode_args::<(T,)>(reply).map(|(response,)| response)
+}
+
+impl Reply for Satoshi {
+    fn decode_reply(reply: &[u8]) -> Result<Self, candid::Error> {
+        decode_one(reply)
+    }
+}
+
+impl Reply for GetUtxosResponse {
+    fn decode_reply(reply: &[u8]) -> Result<Self, candid::Error> {
+        decode_one(reply)
+    }
+}
+
+impl Reply for GetUtxosCompactResponse {
+    fn decode_reply(reply: &[u8]) -> Result<Self, candid::Error> {
+        decode_one(reply)
+    }
+}
+
+impl Reply for Vec<MillisatoshiPerByte> {
+    fn decode_reply(reply: &[u8]) -> Result<Self, candid::Error> {
+        decode_one(reply)
+    }
+}
+
+impl Reply for GetFeePercentilesAtHeightResponse {
+    fn decode_reply(reply: &[u8]) -> Result<Self, candid::Error> {
+        decode_one(reply)
+    }
+}
+
+impl Reply for GenerateBlocksResponse {
+    fn decode_reply(reply: &[u8]) -> Result<Self, candid::Error> {
+        decode_one(reply)
+    }
+}
+
+/// `send_transaction` replies with no value.
+impl Reply for () {
+    fn decode_reply(reply: &[u8]) -> Result<Self, candid::Error> {
+        candid::decode_args::<()>(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_are_decoded_with_the_cycles_charged() {
+        let reply = candid::encode_args((1_000u64,)).unwrap();
+        let balance = Charged::<Satoshi>::from_reply(&reply, 100_000_000, 40_000_000).unwrap();
+        assert_eq!(balance.response, 1_000);
+        assert_eq!(balance.cycles_accepted(), 60_000_000);
+
+        let reply = candid::encode_args(()).unwrap();
+        let sent = Charged::<()>::from_reply(&reply, 10, 0).unwrap();
+        assert_eq!(sent.cycles_accepted(), 10);
+
+        assert!(Charged::<Vec<MillisatoshiPerByte>>::from_reply(&[0], 0, 0).is_err());
+    }
+}
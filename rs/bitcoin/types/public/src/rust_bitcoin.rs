@@ -0,0 +1,200 @@
+//! Conversions to and from the types of the `bitcoin` crate.
+//!
+//! Hashes are kept in internal byte order on both sides, i.e. reversed with
+//! respect to their usual hex representation, so conversions copy the bytes
+//! as they are. Going through the hex representation instead, or reversing
+//! the bytes, produces txids and block hashes that do not match any on chain.
+
+use crate::{BlockHash, Height, Network, OutPoint, Utxo};
+use bitcoin::hashes::Hash;
+use std::convert::TryFrom;
+
+/// Errors when converting to a type of the `bitcoin` crate.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConversionError {
+    /// A hash is not 32 bytes long.
+    InvalidHashLength { length: usize },
+    /// The network has no counterpart in this crate.
+    UnsupportedNetwork { network: bitcoin::Network },
+}
+
+impl std::fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidHashLength { length } => {
+                write!(f, "The hash is {} bytes long instead of 32.", length)
+            }
+            Self::UnsupportedNetwork { network } => {
+                write!(f, "The network {} is not supported.", network)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl From<Network> for bitcoin::Network {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::Mainnet => Self::Bitcoin,
+            Network::Testnet => Self::Testnet,
+            Network::Regtest => Self::Regtest,
+        }
+    }
+}
+
+impl TryFrom<bitcoin::Network> for Network {
+    type Error = ConversionError;
+
+    fn try_from(network: bitcoin::Network) -> Result<Self, Self::Error> {
+        match network {
+            bitcoin::Network::Bitcoin => Ok(Self::Mainnet),
+            bitcoin::Network::Testnet => Ok(Self::Testnet),
+            bitcoin::Network::Regtest => Ok(Self::Regtest),
+            bitcoin::Network::Signet => Err(ConversionError::UnsupportedNetwork { network }),
+        }
+    }
+}
+
+impl From<bitcoin::OutPoint> for OutPoint {
+    fn from(outpoint: bitcoin::OutPoint) -> Self {
+        Self {
+            txid: outpoint.txid.to_vec(),
+            vout: outpoint.vout,
+        }
+    }
+}
+
+impl TryFrom<&OutPoint> for bitcoin::OutPoint {
+    type Error = ConversionError;
+
+    fn try_from(outpoint: &OutPoint) -> Result<Self, Self::Error> {
+        let txid = bitcoin::Txid::from_slice(&outpoint.txid).map_err(|_| {
+            ConversionError::InvalidHashLength {
+                length: outpoint.txid.len(),
+            }
+        })?;
+        Ok(Self::new(txid, outpoint.vout))
+    }
+}
+
+impl TryFrom<OutPoint> for bitcoin::OutPoint {
+    type Error = ConversionError;
+
+    fn try_from(outpoint: OutPoint) -> Result<Self, Self::Error> {
+        Self::try_from(&outpoint)
+    }
+}
+
+/// Builds a [`Utxo`] from an output of a transaction included at `height`.
+impl From<(bitcoin::OutPoint, &bitcoin::TxOut, Height)> for Utxo {
+    fn from((outpoint, txout, height): (bitcoin::OutPoint, &bitcoin::TxOut, Height)) -> Self {
+        Self {
+            outpoint: outpoint.into(),
+            value: txout.value,
+            height,
+        }
+    }
+}
+
+/// Converts a block hash of the `bitcoin` crate to a [`BlockHash`].
+pub fn block_hash_from_bitcoin(block_hash: &bitcoin::BlockHash) -> BlockHash {
+    block_hash.to_vec()
+}
+
+/// Converts a [`BlockHash`] to a block hash of the `bitcoin` crate. Fails if
+/// it is not 32 bytes long.
+pub fn block_hash_to_bitcoin(block_hash: &[u8]) -> Result<bitcoin::BlockHash, ConversionError> {
+    bitcoin::BlockHash::from_slice(block_hash).map_err(|_| ConversionError::InvalidHashLength {
+        length: block_hash.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::consts;
+    use bitcoin::blockdata::constants::genesis_block;
+    use std::str::FromStr;
+
+    #[test]
+    fn networks_round_trip() {
+        for network in [Network::Mainnet, Network::Testnet, Network::Regtest] {
+            assert_eq!(
+                Network::try_from(bitcoin::Network::from(network)),
+                Ok(network)
+            );
+        }
+        assert_eq!(
+            Network::try_from(bitcoin::Network::Signet),
+            Err(ConversionError::UnsupportedNetwork {
+                network: bitcoin::Network::Signet
+            })
+        );
+    }
+
+    #[test]
+    fn txids_keep_their_byte_order() {
+        let txid = bitcoin::Txid::from_str(
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+        )
+        .unwrap();
+        let outpoint = OutPoint::from(bitcoin::OutPoint::new(txid, 7));
+
+        // Internal byte order, i.e. the hex representation reversed.
+        assert_eq!(outpoint.txid[0], 0x3b);
+        assert_eq!(outpoint.txid[31], 0x4a);
+        assert_eq!(outpoint.vout, 7);
+        assert_eq!(
+            bitcoin::OutPoint::try_from(&outpoint),
+            Ok(bitcoin::OutPoint::new(txid, 7))
+        );
+    }
+
+    #[test]
+    fn malformed_txids_are_rejected() {
+        let outpoint = OutPoint {
+            txid: vec![0; 31],
+            vout: 0,
+        };
+        assert_eq!(
+            bitcoin::OutPoint::try_from(outpoint),
+            Err(ConversionError::InvalidHashLength { length: 31 })
+        );
+    }
+
+    #[test]
+    fn block_hashes_match_the_network_consts() {
+        for network in [Network::Mainnet, Network::Testnet, Network::Regtest] {
+            let block_hash = genesis_block(network.into()).block_hash();
+            let converted = block_hash_from_bitcoin(&block_hash);
+            assert_eq!(converted, consts(network).genesis_block_hash.to_vec());
+            assert_eq!(block_hash_to_bitcoin(&converted), Ok(block_hash));
+        }
+        assert_eq!(
+            block_hash_to_bitcoin(&[]),
+            Err(ConversionError::InvalidHashLength { length: 0 })
+        );
+    }
+
+    #[test]
+    fn utxos_are_built_from_outputs() {
+        let txid = bitcoin::Txid::from_slice(&[1; 32]).unwrap();
+        let txout = bitcoin::TxOut {
+            value: 5_000,
+            script_pubkey: bitcoin::Script::new(),
+        };
+        let utxo = Utxo::from((bitcoin::OutPoint::new(txid, 1), &txout, Height::from(10)));
+        assert_eq!(
+            utxo,
+            Utxo {
+                outpoint: OutPoint {
+                    txid: vec![1; 32],
+                    vout: 1,
+                },
+                value: 5_000,
+                height: Height::from(10),
+            }
+        );
+    }
+}
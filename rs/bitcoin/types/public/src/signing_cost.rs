@@ -0,0 +1,251 @@
+//! Estimates of the total cost of sending a transaction signed with threshold
+//! ECDSA, for fee UIs of wallet canisters.
+//!
+//! A canister holding bitcoin under a threshold ECDSA key pays for a
+//! transaction twice: the fee in satoshi paid to miners, and the cycles of one
+//! `sign_with_ecdsa` call per input and of the `send_transaction` call. The
+//! size of the transaction, which both the fee and the cycles depend on, is
+//! only known once signed, so it is estimated from the number of inputs and
+//! outputs, with signatures of the maximum length.
+
+use crate::{cost::send_transaction_cost, MillisatoshiPerByte, NetworkInRequest, Satoshi};
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::convert::TryFrom;
+
+/// The cycles charged for a `sign_with_ecdsa` call, on application subnets.
+pub const SIGNATURE_FEE: u128 = 10_000_000_000;
+
+/// A rough estimate of the time it takes to compute a threshold ECDSA
+/// signature, including the calls to and from the management canister.
+pub const SIGNATURE_LATENCY_SECONDS: u64 = 3;
+
+/// The length of a DER-encoded ECDSA signature with a sighash type, at most.
+/// Standardness rules require a low S value, which saves a byte over the
+/// maximum length of arbitrary signatures.
+const SIGNATURE_LEN: u64 = 72;
+
+/// The length of a compressed public key.
+const PUBLIC_KEY_LEN: u64 = 33;
+
+/// The type of the addresses spent from and sent to. Both are single-key
+/// types, with one signature per input.
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, Hash)]
+pub enum SigningAddressType {
+    P2pkh,
+    P2wpkh,
+}
+
+/// A request to estimate the cost of a transaction spending `num_inputs`
+/// outputs into `num_outputs` outputs, at `fee_rate`.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct EstimateSigningCostRequest {
+    pub network: NetworkInRequest,
+    pub address_type: SigningAddressType,
+    pub num_inputs: u32,
+    pub num_outputs: u32,
+    pub fee_rate: MillisatoshiPerByte,
+}
+
+/// The estimated cost of a transaction, in satoshi and cycles.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct EstimateSigningCostResponse {
+    /// The estimated virtual size of the transaction, which the fee is
+    /// computed from.
+    pub vsize: u64,
+    /// The estimated length of the serialized transaction, which the cycles
+    /// of `send_transaction` are computed from.
+    pub transaction_len: u64,
+    /// The fee paid to miners.
+    pub fee: Satoshi,
+    /// The cycles of the `sign_with_ecdsa` calls, one per input.
+    pub signing_cycles: u128,
+    /// The cycles of the `send_transaction` call.
+    pub send_transaction_cycles: u128,
+    /// The cycles of all calls.
+    pub total_cycles: u128,
+    /// The estimated time to sign all inputs one after the other.
+    pub estimated_latency_seconds: u64,
+}
+
+/// Errors when estimating the cost of a transaction.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq)]
+pub enum EstimateSigningCostError {
+    /// The size, the fee or the cycles of the transaction don't fit in their
+    /// types, e.g. because of a huge fee rate.
+    Overflow,
+}
+
+impl std::fmt::Display for EstimateSigningCostError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Overflow => {
+                write!(f, "The estimated cost of the transaction is too large.")
+            }
+        }
+    }
+}
+
+/// Estimates the cost of the transaction described by `request`.
+///
+/// The sizes are computed in `u64`, rather than `usize` which is only 32 bits
+/// wide in canisters, and all arithmetic is checked.
+pub fn estimate_signing_cost(
+    request: &EstimateSigningCostRequest,
+) -> Result<EstimateSigningCostResponse, EstimateSigningCostError> {
+    use EstimateSigningCostError::Overflow;
+
+    let num_inputs = u64::from(request.num_inputs);
+    let num_outputs = u64::from(request.num_outputs);
+    // Version, input and output counts, and lock time.
+    let overhead = 4 + compact_size_len(num_inputs) + compact_size_len(num_outputs) + 4;
+    // Outpoint, script length and sequence.
+    let input_base = 32 + 4 + 1 + 4;
+    // Signature and public key pushes.
+    let unlocking_len = 1 + SIGNATURE_LEN + 1 + PUBLIC_KEY_LEN;
+    let (input_base, input_witness, output_len, marker_len) = match request.address_type {
+        SigningAddressType::P2pkh => (input_base + unlocking_len, 0, 8 + 1 + 25, 0),
+        // The witness has an item count, and the transaction a marker and a
+        // flag byte.
+        SigningAddressType::P2wpkh => (input_base, 1 + unlocking_len, 8 + 1 + 22, 2),
+    };
+
+    let inputs_size = num_inputs.checked_mul(input_base).ok_or(Overflow)?;
+    let outputs_size = num_outputs.checked_mul(output_len).ok_or(Overflow)?;
+    let base_size = overhead
+        .checked_add(inputs_size)
+        .and_then(|size| size.checked_add(outputs_size))
+        .ok_or(Overflow)?;
+    let witness_size = if input_witness > 0 {
+        num_inputs
+            .checked_mul(input_witness)
+            .and_then(|witnesses| witnesses.checked_add(marker_len))
+            .ok_or(Overflow)?
+    } else {
+        0
+    };
+    let total_size = base_size.checked_add(witness_size).ok_or(Overflow)?;
+    let vsize = base_size
+        .checked_mul(3)
+        .and_then(|weight| weight.checked_add(total_size))
+        .and_then(|weight| weight.checked_add(3))
+        .ok_or(Overflow)?
+        / 4;
+    let fee = vsize
+        .checked_mul(request.fee_rate)
+        .and_then(|fee| fee.checked_add(999))
+        .ok_or(Overflow)?
+        / 1000;
+
+    let signing_cycles = SIGNATURE_FEE
+        .checked_mul(u128::from(request.num_inputs))
+        .ok_or(Overflow)?;
+    let send_transaction_cycles = send_transaction_cost(
+        request.network.into(),
+        usize::try_from(total_size).map_err(|_| Overflow)?,
+    );
+    Ok(EstimateSigningCostResponse {
+        vsize,
+        transaction_len: total_size,
+        fee,
+        signing_cycles,
+        send_transaction_cycles,
+        total_cycles: signing_cycles
+            .checked_add(send_transaction_cycles)
+            .ok_or(Overflow)?,
+        estimated_latency_seconds: SIGNATURE_LATENCY_SECONDS
+            .checked_mul(num_inputs)
+            .ok_or(Overflow)?,
+    })
+}
+
+// The length of `n` encoded as a compact size.
+fn compact_size_len(n: u64) -> u64 {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x10000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cost::MAINNET_FEES, Network};
+
+    fn request(address_type: SigningAddressType, num_inputs: u32) -> EstimateSigningCostRequest {
+        EstimateSigningCostRequest {
+            network: NetworkInRequest::mainnet,
+            address_type,
+            num_inputs,
+            num_outputs: 2,
+            fee_rate: 10_000,
+        }
+    }
+
+    #[test]
+    fn sizes_are_estimated_with_maximum_length_signatures() {
+        // 10 bytes of overhead, 148 bytes per input and 34 bytes per output.
+        let estimate = estimate_signing_cost(&request(SigningAddressType::P2pkh, 1)).unwrap();
+        assert_eq!(estimate.transaction_len, 226);
+        assert_eq!(estimate.vsize, 226);
+        assert_eq!(estimate.fee, 2_260);
+
+        // 10.5 virtual bytes of overhead, 68 per input and 31 per output.
+        let estimate = estimate_signing_cost(&request(SigningAddressType::P2wpkh, 1)).unwrap();
+        assert_eq!(estimate.transaction_len, 223);
+        assert_eq!(estimate.vsize, 141);
+        assert_eq!(estimate.fee, 1_410);
+        let estimate = estimate_signing_cost(&request(SigningAddressType::P2wpkh, 2)).unwrap();
+        assert_eq!(estimate.vsize, 209);
+    }
+
+    #[test]
+    fn cycles_cover_one_signature_per_input() {
+        let estimate = estimate_signing_cost(&request(SigningAddressType::P2wpkh, 3)).unwrap();
+        assert_eq!(estimate.signing_cycles, 3 * SIGNATURE_FEE);
+        assert_eq!(
+            estimate.send_transaction_cycles,
+            send_transaction_cost(Network::Mainnet, estimate.transaction_len as usize)
+        );
+        assert_eq!(
+            estimate.total_cycles,
+            estimate.signing_cycles + estimate.send_transaction_cycles
+        );
+        assert!(estimate.send_transaction_cycles > MAINNET_FEES.send_transaction_base);
+        assert_eq!(
+            estimate.estimated_latency_seconds,
+            3 * SIGNATURE_LATENCY_SECONDS
+        );
+    }
+
+    #[test]
+    fn counts_above_252_take_more_bytes() {
+        let estimate = |num_inputs| {
+            estimate_signing_cost(&request(SigningAddressType::P2pkh, num_inputs))
+                .unwrap()
+                .transaction_len
+        };
+        assert_eq!(estimate(253) - estimate(252), 148 + 2);
+    }
+
+    #[test]
+    fn huge_requests_are_rejected_instead_of_overflowing() {
+        let estimate = estimate_signing_cost(&EstimateSigningCostRequest {
+            num_inputs: u32::MAX,
+            num_outputs: u32::MAX,
+            ..request(SigningAddressType::P2wpkh, 1)
+        })
+        .unwrap();
+        assert_eq!(estimate.signing_cycles, u32::MAX as u128 * SIGNATURE_FEE);
+
+        assert_eq!(
+            estimate_signing_cost(&EstimateSigningCostRequest {
+                fee_rate: u64::MAX,
+                ..request(SigningAddressType::P2pkh, 1)
+            }),
+            Err(EstimateSigningCostError::Overflow)
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Standardness rules of Bitcoin Core, per network.
+//!
+//! Bitcoin nodes do not relay transactions that are valid but non-standard,
+//! e.g. with outputs too small to be worth spending ("dust") or too heavy.
+//! Such transactions are accepted by `send_transaction` but never make it
+//! into a block, so wallets should check them before sending.
+
+use crate::{Network, Satoshi};
+
+/// The type of the script of an output, which determines the size of the
+/// output and of the input spending it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum ScriptType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    /// An `OP_RETURN` output, which is never spent.
+    OpReturn,
+}
+
+impl ScriptType {
+    /// Returns the type of `script_pubkey`, if it is one of the standard
+    /// templates.
+    pub fn of(script_pubkey: &[u8]) -> Option<Self> {
+        match script_pubkey {
+            [0x76, 0xa9, 0x14, .., 0x88, 0xac] if script_pubkey.len() == 25 => Some(Self::P2pkh),
+            [0xa9, 0x14, .., 0x87] if script_pubkey.len() == 23 => Some(Self::P2sh),
+            [0x00, 0x14, ..] if script_pubkey.len() == 22 => Some(Self::P2wpkh),
+            [0x00, 0x20, ..] if script_pubkey.len() == 34 => Some(Self::P2wsh),
+            [0x51, 0x20, ..] if script_pubkey.len() == 34 => Some(Self::P2tr),
+            [0x6a, ..] => Some(Self::OpReturn),
+            _ => None,
+        }
+    }
+
+    // The length of scripts of this type, 0 for `OP_RETURN` outputs.
+    fn script_len(&self) -> usize {
+        match self {
+            Self::P2pkh => 25,
+            Self::P2sh => 23,
+            Self::P2wpkh => 22,
+            Self::P2wsh | Self::P2tr => 34,
+            Self::OpReturn => 0,
+        }
+    }
+
+    fn is_witness_program(&self) -> bool {
+        matches!(self, Self::P2wpkh | Self::P2wsh | Self::P2tr)
+    }
+}
+
+/// The standardness parameters of the nodes of a network.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Standardness {
+    /// If false, nodes relay non-standard transactions and the other
+    /// parameters do not apply.
+    pub require_standard: bool,
+    /// The fee rate in satoshi per 1000 virtual bytes below which spending an
+    /// output costs more than its value.
+    pub dust_relay_fee: u64,
+    /// The maximum weight of a transaction, as defined in BIP-141.
+    pub max_standard_tx_weight: usize,
+}
+
+pub const MAINNET_STANDARDNESS: Standardness = Standardness {
+    require_standard: true,
+    dust_relay_fee: 3_000,
+    max_standard_tx_weight: 400_000,
+};
+
+pub const TESTNET_STANDARDNESS: Standardness = Standardness {
+    require_standard: false,
+    ..MAINNET_STANDARDNESS
+};
+
+pub const REGTEST_STANDARDNESS: Standardness = MAINNET_STANDARDNESS;
+
+/// Returns the standardness parameters of the given network.
+pub fn standardness(network: Network) -> &'static Standardness {
+    match network {
+        Network::Mainnet => &MAINNET_STANDARDNESS,
+        Network::Testnet => &TESTNET_STANDARDNESS,
+        Network::Regtest => &REGTEST_STANDARDNESS,
+    }
+}
+
+impl Standardness {
+    /// Returns the smallest value of a standard output of the given type,
+    /// computed as in `GetDustThreshold` of Bitcoin Core.
+    pub fn dust_threshold(&self, script_type: ScriptType) -> Satoshi {
+        if !self.require_standard || script_type == ScriptType::OpReturn {
+            return 0;
+        }
+        // Value, script length and script.
+        let output_size = 8 + 1 + script_type.script_len();
+        // Outpoint, script length, signature script and sequence, with the
+        // witness discounted for witness programs.
+        let input_size = if script_type.is_witness_program() {
+            32 + 4 + 1 + 107 / 4 + 4
+        } else {
+            32 + 4 + 1 + 107 + 4
+        };
+        (output_size + input_size) as u64 * self.dust_relay_fee / 1000
+    }
+
+    /// Returns true if an output of `value` satoshi with a script of the given
+    /// type is dust, i.e. not relayed.
+    pub fn is_dust(&self, value: Satoshi, script_type: ScriptType) -> bool {
+        value < self.dust_threshold(script_type)
+    }
+
+    /// Returns the maximum weight of a relayed transaction, if limited.
+    pub fn max_standard_tx_weight(&self) -> Option<usize> {
+        if self.require_standard {
+            Some(self.max_standard_tx_weight)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns true if an output of `value` satoshi with a script of the given
+/// type is not relayed on `network`.
+pub fn is_dust(network: Network, value: Satoshi, script_type: ScriptType) -> bool {
+    standardness(network).is_dust(value, script_type)
+}
+
+/// Returns the maximum weight of a transaction relayed on `network`, if
+/// limited.
+pub fn max_standard_tx_weight(network: Network) -> Option<usize> {
+    standardness(network).max_standard_tx_weight()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dust_thresholds_match_bitcoin_core() {
+        let mainnet = standardness(Network::Mainnet);
+        assert_eq!(mainnet.dust_threshold(ScriptType::P2pkh), 546);
+        assert_eq!(mainnet.dust_threshold(ScriptType::P2sh), 540);
+        assert_eq!(mainnet.dust_threshold(ScriptType::P2wpkh), 294);
+        assert_eq!(mainnet.dust_threshold(ScriptType::P2wsh), 330);
+        assert_eq!(mainnet.dust_threshold(ScriptType::P2tr), 330);
+        assert_eq!(mainnet.dust_threshold(ScriptType::OpReturn), 0);
+
+        assert!(is_dust(Network::Mainnet, 293, ScriptType::P2wpkh));
+        assert!(!is_dust(Network::Mainnet, 294, ScriptType::P2wpkh));
+        assert!(!is_dust(Network::Testnet, 1, ScriptType::P2wpkh));
+        assert_eq!(max_standard_tx_weight(Network::Regtest), Some(400_000));
+        assert_eq!(max_standard_tx_weight(Network::Testnet), None);
+    }
+
+    #[test]
+    fn script_types_are_recognized() {
+        let mut p2pkh = vec![0x76, 0xa9, 0x14];
+        p2pkh.extend_from_slice(&[0; 20]);
+        p2pkh.extend_from_slice(&[0x88, 0xac]);
+        assert_eq!(ScriptType::of(&p2pkh), Some(ScriptType::P2pkh));
+
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend_from_slice(&[0; 20]);
+        assert_eq!(ScriptType::of(&p2wpkh), Some(ScriptType::P2wpkh));
+        p2wpkh.push(0);
+        assert_eq!(ScriptType::of(&p2wpkh), None);
+
+        assert_eq!(
+            ScriptType::of(&[0x6a, 0x01, 0xff]),
+            Some(ScriptType::OpReturn)
+        );
+    }
+}
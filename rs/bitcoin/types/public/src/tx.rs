@@ -0,0 +1,441 @@
+//! Lightweight decoding of Bitcoin transactions.
+//!
+//! Allows validating the payload of a [`SendTransactionRequest`] before
+//! sending it, without depending on the `bitcoin` crate. Only the structure of
+//! the transaction is checked, not its scripts or signatures.
+
+use crate::{
+    amount::SATOSHI_PER_BTC, OutPoint, Satoshi, SendTransactionError, SendTransactionRequest,
+};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+
+/// The maximum number of satoshis in existence, i.e. 21 million bitcoin.
+const MAX_MONEY: Satoshi = 21_000_000 * SATOSHI_PER_BTC;
+
+/// A transaction input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    pub script_sig: Vec<u8>,
+    pub sequence: u32,
+    /// The witness stack, empty for inputs without a witness.
+    pub witness: Vec<Vec<u8>>,
+}
+
+/// A transaction output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxOut {
+    pub value: Satoshi,
+    pub script_pubkey: Vec<u8>,
+}
+
+/// A decoded transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transaction {
+    pub version: i32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+    pub lock_time: u32,
+    txid: Vec<u8>,
+    base_size: usize,
+    total_size: usize,
+}
+
+impl Transaction {
+    /// Decodes a transaction in the consensus encoding, with or without
+    /// witnesses.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Decoder::new(bytes).transaction()
+    }
+
+    /// Returns the id of the transaction, i.e. the double SHA-256 hash of its
+    /// encoding without witnesses, in the byte order of [`OutPoint::txid`].
+    pub fn txid(&self) -> &[u8] {
+        &self.txid
+    }
+
+    /// Returns true if any input has a witness.
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    /// Returns the weight of the transaction, as defined in BIP-141.
+    pub fn weight(&self) -> usize {
+        self.base_size * 3 + self.total_size
+    }
+
+    /// Returns the virtual size of the transaction in bytes, which fees are
+    /// computed from.
+    pub fn vsize(&self) -> usize {
+        (self.weight() + 3) / 4
+    }
+}
+
+/// The reason for a transaction to be malformed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    /// The payload ends in the middle of the transaction.
+    UnexpectedEnd,
+    /// A length or count is not encoded in the shortest form.
+    NonMinimalVarInt,
+    /// The segwit marker is not followed by flag 1.
+    UnsupportedSegwitFlag(u8),
+    /// The segwit flag is set, but no input has a witness.
+    NoWitnesses,
+    /// The transaction has no inputs.
+    NoInputs,
+    /// The transaction has no outputs.
+    NoOutputs,
+    /// An output value, or the sum of all of them, exceeds 21 million bitcoin.
+    ValueTooLarge,
+    /// There are bytes after the end of the transaction.
+    TrailingBytes,
+}
+
+/// An error decoding a transaction, at the byte offset where decoding failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+impl std::fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeErrorKind::UnexpectedEnd => write!(f, "unexpected end of transaction"),
+            DecodeErrorKind::NonMinimalVarInt => write!(f, "non-minimal varint"),
+            DecodeErrorKind::UnsupportedSegwitFlag(flag) => {
+                write!(f, "unsupported segwit flag {}", flag)
+            }
+            DecodeErrorKind::NoWitnesses => write!(f, "segwit flag set but no witnesses"),
+            DecodeErrorKind::NoInputs => write!(f, "no inputs"),
+            DecodeErrorKind::NoOutputs => write!(f, "no outputs"),
+            DecodeErrorKind::ValueTooLarge => write!(f, "output value too large"),
+            DecodeErrorKind::TrailingBytes => write!(f, "trailing bytes"),
+        }
+    }
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Malformed transaction at byte {}: {}",
+            self.offset, self.kind
+        )
+    }
+}
+
+impl From<DecodeError> for SendTransactionError {
+    fn from(err: DecodeError) -> Self {
+        Self::MalformedTransaction {
+            offset: err.offset as u64,
+            reason: err.kind.to_string(),
+        }
+    }
+}
+
+impl SendTransactionRequest {
+    /// Decodes the transaction of the request, failing with the offset at
+    /// which it is malformed. Requests failing to decode are rejected by the
+    /// Bitcoin canister with [`SendTransactionError::MalformedTransaction`],
+    /// which keeps the offset.
+    pub fn decode_transaction(&self) -> Result<Transaction, DecodeError> {
+        Transaction::decode(&self.transaction)
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    fn error(&self, offset: usize, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError { offset, kind }
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.offset
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.remaining() < len {
+            return Err(self.error(self.bytes.len(), DecodeErrorKind::UnexpectedEnd));
+        }
+        let bytes = &self.bytes[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.bytes
+            .get(self.offset)
+            .copied()
+            .ok_or_else(|| self.error(self.offset, DecodeErrorKind::UnexpectedEnd))
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn var_int(&mut self) -> Result<u64, DecodeError> {
+        let start = self.offset;
+        let (value, min) = match self.u8()? {
+            0xff => (self.u64()?, 0x1_0000_0000),
+            0xfe => (self.u32()? as u64, 0x1_0000),
+            0xfd => {
+                let mut buf = [0; 2];
+                buf.copy_from_slice(self.take(2)?);
+                (u16::from_le_bytes(buf) as u64, 0xfd)
+            }
+            byte => return Ok(byte as u64),
+        };
+        if value < min {
+            return Err(self.error(start, DecodeErrorKind::NonMinimalVarInt));
+        }
+        Ok(value)
+    }
+
+    /// Reads a count of elements or bytes. Nothing is allocated upfront for
+    /// the elements, so a large count fails with `UnexpectedEnd` once the
+    /// payload is exhausted.
+    fn count(&mut self) -> Result<usize, DecodeError> {
+        Ok(usize::try_from(self.var_int()?).unwrap_or(usize::MAX))
+    }
+
+    fn var_bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.count()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn inputs(&mut self) -> Result<Vec<TxIn>, DecodeError> {
+        let count = self.count()?;
+        (0..count)
+            .map(|_| {
+                let txid = self.take(32)?.to_vec();
+                let vout = self.u32()?;
+                Ok(TxIn {
+                    previous_output: OutPoint { txid, vout },
+                    script_sig: self.var_bytes()?,
+                    sequence: self.u32()?,
+                    witness: vec![],
+                })
+            })
+            .collect()
+    }
+
+    fn outputs(&mut self) -> Result<Vec<TxOut>, DecodeError> {
+        let count = self.count()?;
+        let mut total: Satoshi = 0;
+        (0..count)
+            .map(|_| {
+                let start = self.offset;
+                let value = self.u64()?;
+                total = total.saturating_add(value);
+                if value > MAX_MONEY || total > MAX_MONEY {
+                    return Err(self.error(start, DecodeErrorKind::ValueTooLarge));
+                }
+                Ok(TxOut {
+                    value,
+                    script_pubkey: self.var_bytes()?,
+                })
+            })
+            .collect()
+    }
+
+    fn transaction(mut self) -> Result<Transaction, DecodeError> {
+        let version = self.u32()? as i32;
+
+        // A zero input count is the segwit marker, followed by the flag.
+        let segwit = self.peek()? == 0;
+        if segwit {
+            self.offset += 1;
+            let flag_offset = self.offset;
+            let flag = self.u8()?;
+            if flag != 1 {
+                return Err(self.error(flag_offset, DecodeErrorKind::UnsupportedSegwitFlag(flag)));
+            }
+        }
+        let inputs_offset = self.offset;
+
+        let mut inputs = self.inputs()?;
+        if inputs.is_empty() {
+            return Err(self.error(inputs_offset, DecodeErrorKind::NoInputs));
+        }
+        let outputs_offset = self.offset;
+        let outputs = self.outputs()?;
+        if outputs.is_empty() {
+            return Err(self.error(outputs_offset, DecodeErrorKind::NoOutputs));
+        }
+        let witnesses_offset = self.offset;
+
+        if segwit {
+            for input in inputs.iter_mut() {
+                let count = self.count()?;
+                input.witness = (0..count)
+                    .map(|_| self.var_bytes())
+                    .collect::<Result<_, _>>()?;
+            }
+            if inputs.iter().all(|input| input.witness.is_empty()) {
+                return Err(self.error(witnesses_offset, DecodeErrorKind::NoWitnesses));
+            }
+        }
+
+        let lock_time_offset = self.offset;
+        let lock_time = self.u32()?;
+        if self.remaining() > 0 {
+            return Err(self.error(self.offset, DecodeErrorKind::TrailingBytes));
+        }
+
+        // The encoding without witnesses is the version, the inputs and
+        // outputs, and the lock time.
+        let mut hasher = Sha256::new();
+        hasher.update(&self.bytes[..4]);
+        hasher.update(&self.bytes[inputs_offset..witnesses_offset]);
+        hasher.update(&self.bytes[lock_time_offset..]);
+        let txid = Sha256::digest(&hasher.finalize()).to_vec();
+        let base_size = 4 + (witnesses_offset - inputs_offset) + 4;
+
+        Ok(Transaction {
+            version,
+            inputs,
+            outputs,
+            lock_time,
+            txid,
+            base_size,
+            total_size: self.bytes.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A transaction spending one input to one P2WPKH output, with a witness
+    // of two items if `segwit` is set.
+    fn encode(segwit: bool) -> Vec<u8> {
+        let mut bytes = 2u32.to_le_bytes().to_vec();
+        if segwit {
+            bytes.extend_from_slice(&[0x00, 0x01]);
+        }
+        bytes.push(1);
+        bytes.extend_from_slice(&[0x11; 32]);
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(&0xffff_fffdu32.to_le_bytes());
+        bytes.push(1);
+        bytes.extend_from_slice(&50_000u64.to_le_bytes());
+        bytes.extend_from_slice(&[22, 0x00, 0x14]);
+        bytes.extend_from_slice(&[0x22; 20]);
+        if segwit {
+            bytes.extend_from_slice(&[2, 3, 0x01, 0x02, 0x03, 1, 0x04]);
+        }
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes
+    }
+
+    const TXID: [u8; 32] = [
+        0x41, 0xe3, 0xe2, 0x06, 0xf4, 0x27, 0x65, 0xcd, 0x85, 0xa7, 0x11, 0xcb, 0xb3, 0x8b, 0x1e,
+        0xae, 0x0b, 0xd0, 0x7c, 0x4c, 0xa0, 0x3f, 0x68, 0x9b, 0x2e, 0x68, 0x39, 0xe9, 0x28, 0x6b,
+        0x76, 0xf3,
+    ];
+
+    #[test]
+    fn decodes_legacy_and_segwit_transactions() {
+        let legacy = Transaction::decode(&encode(false)).unwrap();
+        assert_eq!(legacy.version, 2);
+        assert_eq!(legacy.outputs[0].value, 50_000);
+        assert!(!legacy.has_witness());
+        assert_eq!(legacy.txid(), &TXID[..]);
+        assert_eq!(legacy.vsize(), 82);
+
+        let segwit = Transaction::decode(&encode(true)).unwrap();
+        assert_eq!(segwit.inputs[0].witness, vec![vec![1, 2, 3], vec![4]]);
+        // Witnesses do not change the txid, and are discounted in the vsize.
+        assert_eq!(segwit.txid(), &TXID[..]);
+        assert_eq!(segwit.weight(), 337);
+        assert_eq!(segwit.vsize(), 85);
+    }
+
+    #[test]
+    fn errors_report_the_offset_of_the_malformed_bytes() {
+        let bytes = encode(false);
+        assert_eq!(
+            Transaction::decode(&bytes[..50]),
+            Err(DecodeError {
+                offset: 50,
+                kind: DecodeErrorKind::UnexpectedEnd
+            })
+        );
+
+        let mut bytes = encode(false);
+        bytes.push(0);
+        assert_eq!(
+            Transaction::decode(&bytes),
+            Err(DecodeError {
+                offset: 82,
+                kind: DecodeErrorKind::TrailingBytes
+            })
+        );
+
+        // A script length of 22 encoded in 3 bytes.
+        let mut bytes = encode(false);
+        bytes.splice(55..56, [0xfd, 22, 0]);
+        assert_eq!(
+            Transaction::decode(&bytes),
+            Err(DecodeError {
+                offset: 55,
+                kind: DecodeErrorKind::NonMinimalVarInt
+            })
+        );
+
+        let mut bytes = encode(true);
+        bytes[5] = 2;
+        assert_eq!(
+            Transaction::decode(&bytes),
+            Err(DecodeError {
+                offset: 5,
+                kind: DecodeErrorKind::UnsupportedSegwitFlag(2)
+            })
+        );
+    }
+
+    #[test]
+    fn send_transaction_errors_keep_the_offset() {
+        let request = SendTransactionRequest {
+            transaction: encode(false)[..50].to_vec(),
+            network: crate::NetworkInRequest::Testnet,
+        };
+        let err = SendTransactionError::from(request.decode_transaction().unwrap_err());
+        assert_eq!(
+            err,
+            SendTransactionError::MalformedTransaction {
+                offset: 50,
+                reason: "unexpected end of transaction".to_string(),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Can't deserialize transaction because it's malformed at byte 50: unexpected end of transaction."
+        );
+    }
+}
@@ -118,6 +118,9 @@ pub const SAMPLE_CONFIG: &str = r#"
     consensus: {
         // Whether or not to detect starvation. Should only be set to false in tests.
         detect_starvation: true,
+        // Whether or not to include statistics on building the batch payload
+        // in the block proposals made by this replica.
+        include_payload_build_stats: false,
     },
     // ============================================
     // Configuration of the node state persistence.
@@ -3,22 +3,40 @@ use serde::{Deserialize, Serialize};
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ConsensusConfig {
     detect_starvation: bool,
+    /// If true, block proposals made by this replica include statistics on
+    /// building their batch payload, i.e. the build duration, byte limit and
+    /// bytes included of each payload section.
+    #[serde(default)]
+    include_payload_build_stats: bool,
 }
 
 impl ConsensusConfig {
     pub fn new(detect_starvation: bool) -> Self {
-        Self { detect_starvation }
+        Self {
+            detect_starvation,
+            include_payload_build_stats: false,
+        }
+    }
+
+    pub fn with_include_payload_build_stats(mut self, enabled: bool) -> Self {
+        self.include_payload_build_stats = enabled;
+        self
     }
 
     pub fn detect_starvation(&self) -> bool {
         self.detect_starvation
     }
+
+    pub fn include_payload_build_stats(&self) -> bool {
+        self.include_payload_build_stats
+    }
 }
 
 impl Default for ConsensusConfig {
     fn default() -> Self {
         Self {
             detect_starvation: true,
+            include_payload_build_stats: false,
         }
     }
 }
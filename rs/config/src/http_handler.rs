@@ -1,11 +1,26 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
-use std::{convert::TryFrom, net::SocketAddr};
+use std::{collections::BTreeMap, convert::TryFrom, net::SocketAddr};
 
 const DEFAULT_IP_ADDR: &str = "0.0.0.0";
 
 const DEFAULT_PORT: u16 = 8080u16;
 
+const DEFAULT_MAX_QUEUED_QUERIES_PER_CANISTER: usize = 16;
+
+const DEFAULT_CONNECTION_DRAIN_GRACE_PERIOD_SECONDS: u64 = 10;
+
+const DEFAULT_TLS_HANDSHAKE_TIMEOUT_SECONDS: u64 = 10;
+
+const DEFAULT_MAX_ALTERNATE_NODES: usize = 3;
+
+const DEFAULT_MAINTENANCE_RETRY_AFTER_SECONDS: u64 = 30;
+
+const DEFAULT_MAX_BUFFERED_RESPONSE_BYTES: u64 = 256 * 1024 * 1024;
+
+const DEFAULT_DASHBOARD_CONTENT_SECURITY_POLICY: &str =
+    "default-src 'self'; frame-ancestors 'none'";
+
 #[derive(Debug, Clone, Serialize, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "snake_case")]
 /// The port configuration. Defaults to using port 8080.
@@ -20,6 +35,23 @@ pub enum PortConfig {
     WritePortTo(PathBuf),
 }
 
+/// The deprecation of a route of the HTTP handler, announced to clients in the
+/// headers of its responses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteDeprecation {
+    /// The pattern of the route, as in the route table of the HTTP handler,
+    /// e.g. `/api/v2/canister/:effective_canister_id/query`.
+    pub route: String,
+    /// The value of the `Deprecation` header, `true` if not set.
+    pub deprecation: Option<String>,
+    /// The value of the `Sunset` header, i.e. the HTTP-date after which the
+    /// route may no longer be served, if set.
+    pub sunset: Option<String>,
+    /// The URL of documentation of the deprecation, linked with
+    /// `rel="deprecation"` in a `Link` header, if set.
+    pub link: Option<String>,
+}
+
 /// The external configuration that can be loaded from a configuration file.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(default)]
@@ -85,6 +117,376 @@ pub struct ExternalConfig {
     //       major security risk for the IC, but developers should not be
     //       tempted to get the IC's root key from this insecure location.
     pub show_root_key_in_status: bool,
+
+    /// If set to `true`, the replica reports the number of canister ranges
+    /// assigned to its subnet and the number of canisters it hosts in the
+    /// `/status` endpoint, so monitoring can alert on unexpected routing
+    /// table changes.
+    pub show_canister_ranges_in_status: bool,
+
+    /// If set to `true`, calls to canisters that are stopped or stopping in
+    /// the latest certified state are rejected right away, instead of being
+    /// submitted and failing during execution. Set to `false` for the behavior
+    /// described in the interface specification.
+    pub reject_calls_to_stopped_canisters: bool,
+
+    /// If set to `true`, `query` and `read_state` requests with a nonce that
+    /// are received again within a few seconds, i.e. with the same sender,
+    /// nonce and content, are rejected with a `429 Too Many Requests`, to damp
+    /// agents retrying in a loop. Replays are counted either way.
+    pub reject_replayed_requests: bool,
+
+    /// CIDR ranges of reverse proxies (e.g. nginx or HAProxy) that are trusted
+    /// to report the original client address in the `Forwarded` or
+    /// `X-Forwarded-For` headers. The headers are ignored for all other peers.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     trusted_proxies: ["127.0.0.1/32", "10.0.0.0/8"]
+    ///   }
+    /// }
+    /// ```
+    pub trusted_proxies: Vec<String>,
+
+    /// The maximum number of queries executing concurrently for a single
+    /// canister. Further queries wait in a queue of up to
+    /// `max_queued_queries_per_canister` entries, and are rejected with
+    /// `429 Too Many Requests` once it is full. Defaults to the limit profile
+    /// of the subnet type, which caps queries on system subnets only.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     max_concurrent_queries_per_canister: 4,
+    ///     max_queued_queries_per_canister: 16
+    ///   }
+    /// }
+    /// ```
+    pub max_concurrent_queries_per_canister: Option<usize>,
+
+    /// The maximum number of queries waiting for a canister at its concurrent
+    /// query cap.
+    pub max_queued_queries_per_canister: usize,
+
+    /// The maximum number of live TCP connections. Further connections are
+    /// not accepted until one closes.
+    ///
+    /// This and the following limits default to the limit profile of the
+    /// subnet type, which is more conservative on system subnets.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     max_outstanding_connections: 20000,
+    ///     http_max_concurrent_streams: 256,
+    ///     max_request_size_bytes: 5242880,
+    ///     max_read_state_concurrent_requests: 100
+    ///   }
+    /// }
+    /// ```
+    pub max_outstanding_connections: Option<usize>,
+
+    /// The SETTINGS_MAX_CONCURRENT_STREAMS option for HTTP2 connections.
+    pub http_max_concurrent_streams: Option<u32>,
+
+    /// The maximum size of the body of `call`, `query` and `read_state`
+    /// requests, in bytes.
+    pub max_request_size_bytes: Option<u64>,
+
+    /// The maximum size of the body of requests of the given request types
+    /// (`call`, `query`, `read_state` or `catch_up_package`), in bytes, taking
+    /// precedence over `max_request_size_bytes`, e.g. to allow for calls
+    /// installing large Wasm modules while keeping other bodies small.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     max_request_size_bytes_by_request_type: {
+    ///       call: 10485760,
+    ///       catch_up_package: 1024
+    ///     }
+    ///   }
+    /// }
+    /// ```
+    pub max_request_size_bytes_by_request_type: BTreeMap<String, u64>,
+
+    /// The maximum number of `read_state` requests processed concurrently.
+    pub max_read_state_concurrent_requests: Option<usize>,
+
+    /// The maximum number of paths of a `read_state` request. Requests with
+    /// more paths are rejected with a `413 Payload Too Large`.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     max_read_state_paths: 1000,
+    ///     max_read_state_path_bytes: 65536
+    ///   }
+    /// }
+    /// ```
+    pub max_read_state_paths: Option<usize>,
+
+    /// The maximum total size of the labels of the paths of a `read_state`
+    /// request, in bytes.
+    pub max_read_state_path_bytes: Option<usize>,
+
+    /// The maximum number of headers of a request. Requests with more headers
+    /// are rejected with a `431 Request Header Fields Too Large`.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     max_request_header_count: 64,
+    ///     max_request_header_bytes: 65536
+    ///   }
+    /// }
+    /// ```
+    pub max_request_header_count: Option<usize>,
+
+    /// The maximum total size of the names and values of the headers of a
+    /// request, in bytes. Also bounds the buffer in which HTTP/1.1 request
+    /// heads are parsed, which is at least 8KiB.
+    pub max_request_header_bytes: Option<usize>,
+
+    /// The maximum number of bytes per second written to a single
+    /// connection, so that a client streaming large responses cannot
+    /// saturate the network interface of the node. Bursts of up to one
+    /// second worth of bytes are allowed. Unlimited if not set.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     max_connection_write_bytes_per_second: 10485760
+    ///   }
+    /// }
+    /// ```
+    pub max_connection_write_bytes_per_second: Option<u64>,
+
+    /// The time after which a connection is drained, i.e. told to close with
+    /// a GOAWAY frame on HTTP/2, so that long-lived client connections
+    /// spread over nodes again, e.g. after a node restarted. Connections are
+    /// kept open indefinitely if not set.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     max_connection_lifetime_seconds: 3600,
+    ///     connection_drain_grace_period_seconds: 10
+    ///   }
+    /// }
+    /// ```
+    pub max_connection_lifetime_seconds: Option<u64>,
+
+    /// The time requests in flight on a draining connection, on shutdown or
+    /// at the end of its lifetime, have to complete before the connection is
+    /// dropped.
+    pub connection_drain_grace_period_seconds: u64,
+
+    /// The time a client has to complete the TLS handshake of a connection
+    /// before the connection is closed. Connections count against
+    /// `max_outstanding_connections` while handshaking, so stalled handshakes
+    /// must not hold them indefinitely.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     tls_handshake_timeout_seconds: 10
+    ///   }
+    /// }
+    /// ```
+    pub tls_handshake_timeout_seconds: u64,
+
+    /// The time query and read_state requests are served for, once their
+    /// body is received, before they are answered with `503 Service
+    /// Unavailable`, if set. Calls are never timed out, as their message may
+    /// already be in the ingress pool.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     request_timeout_seconds: 30
+    ///   }
+    /// }
+    /// ```
+    pub request_timeout_seconds: Option<u64>,
+
+    /// If set to `true`, the HTTP server shuts down gracefully when the
+    /// replica receives `SIGTERM` or `SIGINT`, draining open connections, and logs how many connections were drained
+    /// or aborted and which requests were outstanding. This lets clients move
+    /// to other nodes before an orchestrated reboot.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     shutdown_on_sigterm: true
+    ///   }
+    /// }
+    /// ```
+    pub shutdown_on_sigterm: bool,
+
+    /// If set to `true`, the delegation of a subnet is fetched from the NNS
+    /// subnet over TLS, authenticating the NNS node against its certificate
+    /// in the registry, instead of over plain HTTP.
+    pub fetch_delegation_over_tls: bool,
+
+    /// Path to a file holding the token that requests to the `/_/pprof`
+    /// endpoints must carry as `Authorization: Bearer <token>`. If not set,
+    /// or if the file cannot be read, profiles are only served to clients
+    /// connecting from the loopback interface.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     pprof_token_file: "/run/ic-node/config/pprof_token"
+    ///   }
+    /// }
+    /// ```
+    pub pprof_token_file: Option<PathBuf>,
+
+    /// Routes announced as deprecated to clients, in the `Deprecation`,
+    /// `Sunset` and `Link` headers of their responses, so that they can
+    /// migrate to newer versions of the endpoints before the routes are
+    /// removed.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     deprecated_routes: [
+    ///       {
+    ///         route: "/api/v2/canister/:effective_canister_id/query",
+    ///         sunset: "Sat, 01 Jul 2023 00:00:00 GMT",
+    ///         link: "https://internetcomputer.org/docs/current/references/ic-interface-spec"
+    ///       }
+    ///     ]
+    ///   }
+    /// }
+    /// ```
+    pub deprecated_routes: Vec<RouteDeprecation>,
+
+    /// If set to `true`, requests whose framing may be interpreted differently
+    /// by a proxy in front of the replica are rejected with a
+    /// `400 Bad Request`: requests with both `Transfer-Encoding` and
+    /// `Content-Length`, with a `Transfer-Encoding` other than `chunked`, with
+    /// conflicting duplicates of headers that may only appear once, or with an
+    /// absolute-form target from a peer that is not a trusted proxy. Meant for
+    /// replicas directly exposed to the internet.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     reject_ambiguous_requests: true
+    ///   }
+    /// }
+    /// ```
+    pub reject_ambiguous_requests: bool,
+
+    /// The maximum number of other nodes of the subnet listed in the
+    /// `x-ic-alternate-nodes` header of `503 Service Unavailable` and
+    /// `429 Too Many Requests` responses, for clients to retry elsewhere. The
+    /// header is left out if set to `0`.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     max_alternate_nodes: 3
+    ///   }
+    /// }
+    /// ```
+    pub max_alternate_nodes: usize,
+
+    /// If set to `true`, `SIGUSR1` puts the HTTP handler in maintenance mode
+    /// and `SIGUSR2` takes it out again. In maintenance mode, calls are
+    /// rejected with `503 Service Unavailable`, while queries, `read_state`
+    /// and status requests are still served, so that a node can be drained of
+    /// calls before an upgrade.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     maintenance_mode_on_signals: true
+    ///   }
+    /// }
+    /// ```
+    pub maintenance_mode_on_signals: bool,
+
+    /// The `Retry-After` of calls rejected in maintenance mode, in seconds.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     maintenance_retry_after_seconds: 30
+    ///   }
+    /// }
+    /// ```
+    pub maintenance_retry_after_seconds: u64,
+
+    /// If set, the responses of the dashboard and status endpoints carry a
+    /// `Strict-Transport-Security` header with this `max-age`, in seconds, so
+    /// that browsers only connect to the node over HTTPS afterwards. Only set
+    /// it if the node serves HTTPS.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     hsts_max_age_seconds: 31536000
+    ///   }
+    /// }
+    /// ```
+    pub hsts_max_age_seconds: Option<u64>,
+
+    /// The `Content-Security-Policy` of the dashboard. The dashboard loads
+    /// nothing but its own assets, so the default policy only allows those,
+    /// and forbids framing the dashboard. Left out if set to `null`.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     dashboard_content_security_policy: "default-src 'self'; frame-ancestors 'none'"
+    ///   }
+    /// }
+    /// ```
+    pub dashboard_content_security_policy: Option<String>,
+
+    /// The maximum total size of the response bodies held in memory until
+    /// they are written to clients, in bytes. Responses that would exceed it
+    /// are replaced with a `503 Service Unavailable`, so that many large
+    /// responses to slow clients can't exhaust the memory of the node.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     max_buffered_response_bytes: 268435456
+    ///   }
+    /// }
+    /// ```
+    pub max_buffered_response_bytes: u64,
+
+    /// An MMDB file mapping IP addresses to autonomous systems, e.g.
+    /// GeoLite2-ASN. If set, connections are counted by the autonomous
+    /// system of their peer in the metrics. Addresses are never logged.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     client_asn_database_file: "/var/lib/ic/data/GeoLite2-ASN.mmdb"
+    ///   }
+    /// }
+    /// ```
+    pub client_asn_database_file: Option<PathBuf>,
+
+    /// An MMDB file mapping IP addresses to countries, e.g.
+    /// GeoLite2-Country. If set, connections are counted by the country of
+    /// their peer in the metrics. Addresses are never logged.
+    ///
+    /// ```json5
+    /// {
+    ///   http_handler: {
+    ///     client_country_database_file: "/var/lib/ic/data/GeoLite2-Country.mmdb"
+    ///   }
+    /// }
+    /// ```
+    pub client_country_database_file: Option<PathBuf>,
 }
 
 impl Default for ExternalConfig {
@@ -94,6 +496,41 @@ impl Default for ExternalConfig {
             allow_ipv6_my_users_have_no_privacy: None,
             port: None,
             show_root_key_in_status: true,
+            show_canister_ranges_in_status: false,
+            reject_calls_to_stopped_canisters: true,
+            reject_replayed_requests: false,
+            trusted_proxies: vec![],
+            max_concurrent_queries_per_canister: None,
+            max_queued_queries_per_canister: DEFAULT_MAX_QUEUED_QUERIES_PER_CANISTER,
+            max_outstanding_connections: None,
+            http_max_concurrent_streams: None,
+            max_request_size_bytes: None,
+            max_request_size_bytes_by_request_type: BTreeMap::new(),
+            max_read_state_concurrent_requests: None,
+            max_read_state_paths: None,
+            max_read_state_path_bytes: None,
+            max_request_header_count: None,
+            max_request_header_bytes: None,
+            max_connection_write_bytes_per_second: None,
+            max_connection_lifetime_seconds: None,
+            connection_drain_grace_period_seconds: DEFAULT_CONNECTION_DRAIN_GRACE_PERIOD_SECONDS,
+            tls_handshake_timeout_seconds: DEFAULT_TLS_HANDSHAKE_TIMEOUT_SECONDS,
+            request_timeout_seconds: None,
+            shutdown_on_sigterm: false,
+            fetch_delegation_over_tls: false,
+            pprof_token_file: None,
+            deprecated_routes: vec![],
+            reject_ambiguous_requests: false,
+            max_alternate_nodes: DEFAULT_MAX_ALTERNATE_NODES,
+            maintenance_mode_on_signals: false,
+            maintenance_retry_after_seconds: DEFAULT_MAINTENANCE_RETRY_AFTER_SECONDS,
+            hsts_max_age_seconds: None,
+            dashboard_content_security_policy: Some(
+                DEFAULT_DASHBOARD_CONTENT_SECURITY_POLICY.to_string(),
+            ),
+            max_buffered_response_bytes: DEFAULT_MAX_BUFFERED_RESPONSE_BYTES,
+            client_asn_database_file: None,
+            client_country_database_file: None,
         }
     }
 }
@@ -110,6 +547,83 @@ pub struct Config {
     pub port_file_path: Option<PathBuf>,
     /// True if the replica public key is returned from the `/status` endpoint
     pub show_root_key_in_status: bool,
+    /// True if a summary of the subnet's canister ranges is returned from the
+    /// `/status` endpoint
+    pub show_canister_ranges_in_status: bool,
+    /// True if calls to stopped or stopping canisters are rejected before
+    /// being submitted
+    pub reject_calls_to_stopped_canisters: bool,
+    /// True if replayed `query` and `read_state` requests are rejected
+    pub reject_replayed_requests: bool,
+    /// CIDR ranges of reverse proxies trusted to report the client address
+    pub trusted_proxies: Vec<String>,
+    /// The maximum number of queries executing concurrently per canister, if
+    /// set. Overrides the limit profile of the subnet type.
+    pub max_concurrent_queries_per_canister: Option<usize>,
+    /// The maximum number of queries waiting for a canister at its cap
+    pub max_queued_queries_per_canister: usize,
+    /// The maximum number of live TCP connections, if set. Overrides the
+    /// limit profile of the subnet type, as do the following limits.
+    pub max_outstanding_connections: Option<usize>,
+    /// The SETTINGS_MAX_CONCURRENT_STREAMS option for HTTP2 connections, if
+    /// set
+    pub http_max_concurrent_streams: Option<u32>,
+    /// The maximum request body size in bytes, if set
+    pub max_request_size_bytes: Option<u64>,
+    /// The maximum request body size in bytes, by request type
+    pub max_request_size_bytes_by_request_type: BTreeMap<String, u64>,
+    /// The maximum number of concurrent `read_state` requests, if set
+    pub max_read_state_concurrent_requests: Option<usize>,
+    /// The maximum number of paths of a `read_state` request, if set
+    pub max_read_state_paths: Option<usize>,
+    /// The maximum total size of the paths of a `read_state` request in
+    /// bytes, if set
+    pub max_read_state_path_bytes: Option<usize>,
+    /// The maximum number of headers of a request, if set
+    pub max_request_header_count: Option<usize>,
+    /// The maximum total size of the headers of a request in bytes, if set
+    pub max_request_header_bytes: Option<usize>,
+    /// The maximum number of bytes per second written to a single connection,
+    /// if set
+    pub max_connection_write_bytes_per_second: Option<u64>,
+    /// The time after which a connection is drained, if set
+    pub max_connection_lifetime_seconds: Option<u64>,
+    /// The time requests on a draining connection have to complete
+    pub connection_drain_grace_period_seconds: u64,
+    /// The time a client has to complete the TLS handshake
+    pub tls_handshake_timeout_seconds: u64,
+    /// The time query and read_state requests are served for, if set
+    pub request_timeout_seconds: Option<u64>,
+    /// True if the HTTP server shuts down gracefully on `SIGTERM`
+    pub shutdown_on_sigterm: bool,
+    /// True if the delegation is fetched from the NNS subnet over TLS
+    pub fetch_delegation_over_tls: bool,
+    /// The file holding the token required by the `/_/pprof` endpoints, if
+    /// set
+    pub pprof_token_file: Option<PathBuf>,
+    /// Routes announced as deprecated to clients
+    pub deprecated_routes: Vec<RouteDeprecation>,
+    /// True if requests with ambiguous framing are rejected
+    pub reject_ambiguous_requests: bool,
+    /// The maximum number of nodes listed in the `x-ic-alternate-nodes`
+    /// header
+    pub max_alternate_nodes: usize,
+    /// True if maintenance mode is switched on `SIGUSR1` and `SIGUSR2`
+    pub maintenance_mode_on_signals: bool,
+    /// The `Retry-After` of calls rejected in maintenance mode
+    pub maintenance_retry_after_seconds: u64,
+    /// The `max-age` of the `Strict-Transport-Security` header of the
+    /// dashboard and status endpoints, if sent
+    pub hsts_max_age_seconds: Option<u64>,
+    /// The `Content-Security-Policy` of the dashboard, if sent
+    pub dashboard_content_security_policy: Option<String>,
+    /// The maximum total size of the response bodies held in memory
+    pub max_buffered_response_bytes: u64,
+    /// The MMDB file connections are counted by autonomous system with, if
+    /// set
+    pub client_asn_database_file: Option<PathBuf>,
+    /// The MMDB file connections are counted by country with, if set
+    pub client_country_database_file: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -121,6 +635,41 @@ impl Default for Config {
             ),
             port_file_path: None,
             show_root_key_in_status: true,
+            show_canister_ranges_in_status: false,
+            reject_calls_to_stopped_canisters: true,
+            reject_replayed_requests: false,
+            trusted_proxies: vec![],
+            max_concurrent_queries_per_canister: None,
+            max_queued_queries_per_canister: DEFAULT_MAX_QUEUED_QUERIES_PER_CANISTER,
+            max_outstanding_connections: None,
+            http_max_concurrent_streams: None,
+            max_request_size_bytes: None,
+            max_request_size_bytes_by_request_type: BTreeMap::new(),
+            max_read_state_concurrent_requests: None,
+            max_read_state_paths: None,
+            max_read_state_path_bytes: None,
+            max_request_header_count: None,
+            max_request_header_bytes: None,
+            max_connection_write_bytes_per_second: None,
+            max_connection_lifetime_seconds: None,
+            connection_drain_grace_period_seconds: DEFAULT_CONNECTION_DRAIN_GRACE_PERIOD_SECONDS,
+            tls_handshake_timeout_seconds: DEFAULT_TLS_HANDSHAKE_TIMEOUT_SECONDS,
+            request_timeout_seconds: None,
+            shutdown_on_sigterm: false,
+            fetch_delegation_over_tls: false,
+            pprof_token_file: None,
+            deprecated_routes: vec![],
+            reject_ambiguous_requests: false,
+            max_alternate_nodes: DEFAULT_MAX_ALTERNATE_NODES,
+            maintenance_mode_on_signals: false,
+            maintenance_retry_after_seconds: DEFAULT_MAINTENANCE_RETRY_AFTER_SECONDS,
+            hsts_max_age_seconds: None,
+            dashboard_content_security_policy: Some(
+                DEFAULT_DASHBOARD_CONTENT_SECURITY_POLICY.to_string(),
+            ),
+            max_buffered_response_bytes: DEFAULT_MAX_BUFFERED_RESPONSE_BYTES,
+            client_asn_database_file: None,
+            client_country_database_file: None,
         }
     }
 }
@@ -155,6 +704,39 @@ impl TryFrom<ExternalConfig> for Config {
         }?;
 
         config.show_root_key_in_status = ec.show_root_key_in_status;
+        config.show_canister_ranges_in_status = ec.show_canister_ranges_in_status;
+        config.reject_calls_to_stopped_canisters = ec.reject_calls_to_stopped_canisters;
+        config.reject_replayed_requests = ec.reject_replayed_requests;
+        config.trusted_proxies = ec.trusted_proxies;
+        config.max_concurrent_queries_per_canister = ec.max_concurrent_queries_per_canister;
+        config.max_queued_queries_per_canister = ec.max_queued_queries_per_canister;
+        config.max_outstanding_connections = ec.max_outstanding_connections;
+        config.http_max_concurrent_streams = ec.http_max_concurrent_streams;
+        config.max_request_size_bytes = ec.max_request_size_bytes;
+        config.max_request_size_bytes_by_request_type = ec.max_request_size_bytes_by_request_type;
+        config.max_read_state_concurrent_requests = ec.max_read_state_concurrent_requests;
+        config.max_read_state_paths = ec.max_read_state_paths;
+        config.max_read_state_path_bytes = ec.max_read_state_path_bytes;
+        config.max_request_header_count = ec.max_request_header_count;
+        config.max_request_header_bytes = ec.max_request_header_bytes;
+        config.max_connection_write_bytes_per_second = ec.max_connection_write_bytes_per_second;
+        config.max_connection_lifetime_seconds = ec.max_connection_lifetime_seconds;
+        config.connection_drain_grace_period_seconds = ec.connection_drain_grace_period_seconds;
+        config.tls_handshake_timeout_seconds = ec.tls_handshake_timeout_seconds;
+        config.request_timeout_seconds = ec.request_timeout_seconds;
+        config.shutdown_on_sigterm = ec.shutdown_on_sigterm;
+        config.fetch_delegation_over_tls = ec.fetch_delegation_over_tls;
+        config.pprof_token_file = ec.pprof_token_file;
+        config.deprecated_routes = ec.deprecated_routes;
+        config.reject_ambiguous_requests = ec.reject_ambiguous_requests;
+        config.max_alternate_nodes = ec.max_alternate_nodes;
+        config.maintenance_mode_on_signals = ec.maintenance_mode_on_signals;
+        config.maintenance_retry_after_seconds = ec.maintenance_retry_after_seconds;
+        config.hsts_max_age_seconds = ec.hsts_max_age_seconds;
+        config.dashboard_content_security_policy = ec.dashboard_content_security_policy;
+        config.max_buffered_response_bytes = ec.max_buffered_response_bytes;
+        config.client_asn_database_file = ec.client_asn_database_file;
+        config.client_country_database_file = ec.client_country_database_file;
         Ok(config)
     }
 }
@@ -0,0 +1,443 @@
+//! Block fill simulation for the payload builder
+//!
+//! Drives [`PayloadBuilderImpl`] over a number of consecutive heights and
+//! prints, per section, how much of the block it filled and how long it took
+//! to build, as reported in the payload's build stats. This is meant to be run
+//! offline by protocol engineers when evaluating changes to the section
+//! scheduling, e.g.:
+//!
+//! ```text
+//! PAYLOAD_BUILDER_SIM_MIX=xnet_heavy cargo bench --features testing --bench payload_builder_sim
+//! ```
+//!
+//! or `bazel run //rs/consensus:payload_builder_sim_bench`.
+//!
+//! Setting:
+//! - `PAYLOAD_BUILDER_SIM_HEIGHTS`: Number of heights to simulate (default 1000).
+//! - `PAYLOAD_BUILDER_SIM_MIX`: Synthetic traffic mix, one of `balanced`
+//!   (default), `ingress_heavy` or `xnet_heavy`.
+//! - `PAYLOAD_BUILDER_SIM_CANARY_BYTES`: Size of the canary section (default
+//!   0, i.e. disabled).
+//! - `PAYLOAD_BUILDER_SIM_CAPTURE`: Optional path to a CBOR encoded
+//!   `Vec<BatchPayload>` (e.g. captured from a testnet). If set, the ingress
+//!   messages and stream slices contained in the captured payloads are
+//!   replayed instead of the synthetic traffic.
+//! - The self-validating and canister http sections are served by fakes
+//!   without traffic, so only their build time is of interest.
+//! - Past payloads of depth 4 are passed to every `get_payload()` call, just
+//!   like on a subnet with a small certification lag.
+
+use ic_consensus::{
+    consensus::payload_builder::{PayloadBuilder, PayloadBuilderImpl},
+    testing::subnet_records,
+};
+use ic_interfaces::{
+    ingress_manager::{IngressPayloadValidationError, IngressSelector, IngressSetQuery},
+    messaging::{XNetPayloadBuilder, XNetPayloadValidationError},
+    validation::ValidationResult,
+};
+use ic_logger::replica_logger::no_op_logger;
+use ic_metrics::MetricsRegistry;
+use ic_registry_subnet_features::SubnetFeatures;
+use ic_test_utilities::{
+    canister_http::FakeCanisterHttpPayloadBuilder,
+    consensus::fake::Fake,
+    mock_time,
+    self_validating_payload_builder::FakeSelfValidatingPayloadBuilder,
+    types::ids::{node_test_id, subnet_test_id},
+    types::messages::SignedIngressBuilder,
+};
+use ic_test_utilities_registry::{setup_registry, SubnetRecordBuilder};
+use ic_types::{
+    artifact::IngressMessageId,
+    batch::{BatchPayload, IngressPayload, PayloadSection, ValidationContext, XNetPayload},
+    consensus::{
+        certification::{Certification, CertificationContent},
+        dkg::Dealings,
+        Block, BlockPayload, DataPayload, Payload,
+    },
+    crypto::{CryptoHash, CryptoHashOf, Signed},
+    ingress::IngressSets,
+    messages::SignedIngress,
+    signature::ThresholdSignature,
+    time::UNIX_EPOCH,
+    xnet::CertifiedStreamSlice,
+    CountBytes, CryptoHashOfPartialState, Height, NumBytes, RegistryVersion, SubnetId, Time,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Default number of simulated heights.
+const DEFAULT_HEIGHTS: u64 = 1000;
+
+/// Number of past payloads passed into `get_payload()`.
+const PAST_PAYLOAD_DEPTH: usize = 4;
+
+/// Block payload size used for the simulated subnet.
+const MAX_BLOCK_PAYLOAD_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Messages (or slices) enqueued per height, and their size range in bytes.
+struct TrafficMix {
+    ingress_per_height: usize,
+    ingress_size: (usize, usize),
+    slices_per_height: usize,
+    slice_size: (usize, usize),
+}
+
+impl TrafficMix {
+    fn from_name(name: &str) -> Self {
+        match name {
+            "ingress_heavy" => Self {
+                ingress_per_height: 2000,
+                ingress_size: (256, 64 * 1024),
+                slices_per_height: 1,
+                slice_size: (1024, 64 * 1024),
+            },
+            "xnet_heavy" => Self {
+                ingress_per_height: 50,
+                ingress_size: (256, 4 * 1024),
+                slices_per_height: 12,
+                slice_size: (64 * 1024, 1024 * 1024),
+            },
+            "balanced" => Self {
+                ingress_per_height: 500,
+                ingress_size: (256, 16 * 1024),
+                slices_per_height: 4,
+                slice_size: (16 * 1024, 512 * 1024),
+            },
+            other => panic!("Unknown traffic mix: {}", other),
+        }
+    }
+}
+
+/// An `IngressSelector` serving queued messages in FIFO order, respecting the
+/// byte limit it is given.
+#[derive(Default)]
+struct SimIngressSelector {
+    queue: Mutex<VecDeque<SignedIngress>>,
+}
+
+impl IngressSelector for SimIngressSelector {
+    fn get_ingress_payload(
+        &self,
+        _past_payloads: &dyn IngressSetQuery,
+        _context: &ValidationContext,
+        byte_limit: NumBytes,
+    ) -> IngressPayload {
+        let mut queue = self.queue.lock().unwrap();
+        let mut selected = Vec::new();
+        let mut size = 0;
+        while let Some(msg) = queue.front() {
+            let msg_size = msg.count_bytes();
+            if (size + msg_size) as u64 > byte_limit.get() {
+                break;
+            }
+            size += msg_size;
+            selected.push(queue.pop_front().unwrap());
+        }
+        let mut payload = IngressPayload::from(selected);
+        // The payload encoding adds some overhead, drop messages until we fit.
+        while payload.count_bytes() as u64 > byte_limit.get() {
+            let mut msgs = Vec::<SignedIngress>::try_from(payload).unwrap();
+            queue.push_front(msgs.pop().unwrap());
+            payload = IngressPayload::from(msgs);
+        }
+        payload
+    }
+
+    fn validate_ingress_payload(
+        &self,
+        _payload: &IngressPayload,
+        _past_payloads: &dyn IngressSetQuery,
+        _context: &ValidationContext,
+    ) -> ValidationResult<IngressPayloadValidationError> {
+        Ok(())
+    }
+
+    fn filter_past_payloads(
+        &self,
+        _past_payloads: &[(Height, Time, Payload)],
+        _context: &ValidationContext,
+    ) -> IngressSets {
+        IngressSets::new(vec![], UNIX_EPOCH)
+    }
+
+    fn request_purge_finalized_messages(&self, _message_ids: Vec<IngressMessageId>) {}
+}
+
+/// An `XNetPayloadBuilder` serving queued stream slices in FIFO order,
+/// respecting the byte limit it is given.
+#[derive(Default)]
+struct SimXNetPayloadBuilder {
+    queue: Mutex<VecDeque<(SubnetId, CertifiedStreamSlice)>>,
+}
+
+impl XNetPayloadBuilder for SimXNetPayloadBuilder {
+    fn get_xnet_payload(
+        &self,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&XNetPayload],
+        byte_limit: NumBytes,
+    ) -> XNetPayload {
+        let mut queue = self.queue.lock().unwrap();
+        let mut payload = XNetPayload::default();
+        while let Some((subnet_id, slice)) = queue.pop_front() {
+            if payload.stream_slices.contains_key(&subnet_id) {
+                queue.push_front((subnet_id, slice));
+                break;
+            }
+            payload.stream_slices.insert(subnet_id, slice);
+            if payload.count_bytes() as u64 > byte_limit.get() {
+                let slice = payload.stream_slices.remove(&subnet_id).unwrap();
+                queue.push_front((subnet_id, slice));
+                break;
+            }
+        }
+        payload
+    }
+
+    fn validate_xnet_payload(
+        &self,
+        payload: &XNetPayload,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&XNetPayload],
+    ) -> Result<NumBytes, XNetPayloadValidationError> {
+        Ok(NumBytes::new(payload.count_bytes() as u64))
+    }
+}
+
+/// Source of traffic for the simulation.
+enum Traffic {
+    Synthetic(TrafficMix, ChaCha20Rng),
+    Replay(VecDeque<BatchPayload>),
+}
+
+impl Traffic {
+    /// Enqueues the traffic arriving at `height`. Returns `false` once a
+    /// replayed capture is exhausted.
+    fn arrive(
+        &mut self,
+        height: u64,
+        ingress: &SimIngressSelector,
+        xnet: &SimXNetPayloadBuilder,
+    ) -> bool {
+        match self {
+            Traffic::Synthetic(mix, rng) => {
+                let mut ingress_queue = ingress.queue.lock().unwrap();
+                for i in 0..mix.ingress_per_height {
+                    let size = rng.gen_range(mix.ingress_size.0..=mix.ingress_size.1);
+                    ingress_queue.push_back(
+                        SignedIngressBuilder::new()
+                            .method_payload(vec![0; size])
+                            .nonce(height * mix.ingress_per_height as u64 + i as u64)
+                            .build(),
+                    );
+                }
+                let mut xnet_queue = xnet.queue.lock().unwrap();
+                for i in 0..mix.slices_per_height {
+                    let size = rng.gen_range(mix.slice_size.0..=mix.slice_size.1);
+                    xnet_queue.push_back((
+                        subnet_test_id(1 + i as u64),
+                        make_certified_stream_slice(height, size),
+                    ));
+                }
+                true
+            }
+            Traffic::Replay(captured) => match captured.pop_front() {
+                Some(payload) => {
+                    let msgs = Vec::<SignedIngress>::try_from(payload.ingress)
+                        .expect("Captured ingress payload can't be decoded");
+                    ingress.queue.lock().unwrap().extend(msgs);
+                    xnet.queue
+                        .lock()
+                        .unwrap()
+                        .extend(payload.xnet.stream_slices.into_iter());
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+fn make_certified_stream_slice(height: u64, size: usize) -> CertifiedStreamSlice {
+    CertifiedStreamSlice {
+        payload: vec![0; size],
+        merkle_proof: vec![],
+        certification: Certification {
+            height: Height::from(height),
+            signed: Signed {
+                signature: ThresholdSignature::fake(),
+                content: CertificationContent::new(CryptoHashOfPartialState::from(CryptoHash(
+                    vec![],
+                ))),
+            },
+        },
+    }
+}
+
+fn wrap_batch_payload(height: u64, payload: BatchPayload) -> Payload {
+    Payload::new(
+        ic_crypto::crypto_hash,
+        BlockPayload::Data(DataPayload {
+            batch: payload,
+            dealings: Dealings::new_empty(Height::from(height)),
+            ecdsa: None,
+        }),
+    )
+}
+
+fn load_capture(path: &str) -> VecDeque<BatchPayload> {
+    let bytes = std::fs::read(path)
+        .unwrap_or_else(|err| panic!("Could not read capture file {}: {}", path, err));
+    serde_cbor::from_slice::<Vec<BatchPayload>>(&bytes)
+        .unwrap_or_else(|err| panic!("Could not decode capture file {}: {}", path, err))
+        .into()
+}
+
+/// Fill statistics of a single section over the whole simulation.
+#[derive(Default)]
+struct SectionStats {
+    bytes: u64,
+    max_bytes: u64,
+    build_time: Duration,
+}
+
+impl SectionStats {
+    fn record(&mut self, bytes: u64, build_time: Duration) {
+        self.bytes += bytes;
+        self.max_bytes = self.max_bytes.max(bytes);
+        self.build_time += build_time;
+    }
+}
+
+fn main() {
+    let heights = std::env::var("PAYLOAD_BUILDER_SIM_HEIGHTS")
+        .map(|h| h.parse::<u64>().expect("Invalid number of heights"))
+        .unwrap_or(DEFAULT_HEIGHTS);
+    let canary_payload_bytes = std::env::var("PAYLOAD_BUILDER_SIM_CANARY_BYTES")
+        .map(|b| b.parse::<u64>().expect("Invalid number of canary bytes"))
+        .ok();
+    let mix_name =
+        std::env::var("PAYLOAD_BUILDER_SIM_MIX").unwrap_or_else(|_| "balanced".to_string());
+    let mut traffic = match std::env::var("PAYLOAD_BUILDER_SIM_CAPTURE") {
+        Ok(path) => Traffic::Replay(load_capture(&path)),
+        Err(_) => Traffic::Synthetic(
+            TrafficMix::from_name(&mix_name),
+            ChaCha20Rng::seed_from_u64(0),
+        ),
+    };
+
+    let subnet_id = subnet_test_id(0);
+    let mut subnet_record = SubnetRecordBuilder::from(&[node_test_id(0)])
+        .with_features(
+            SubnetFeatures {
+                canary_payload_bytes,
+                ..SubnetFeatures::default()
+            }
+            .into(),
+        )
+        .build();
+    subnet_record.max_block_payload_size = MAX_BLOCK_PAYLOAD_SIZE;
+    let subnet_records = subnet_records(subnet_record.clone());
+    let registry = setup_registry(subnet_id, vec![(1, subnet_record)]);
+
+    let ingress_selector = Arc::new(SimIngressSelector::default());
+    let xnet_payload_builder = Arc::new(SimXNetPayloadBuilder::default());
+    let payload_builder = PayloadBuilderImpl::new(
+        subnet_id,
+        registry,
+        ingress_selector.clone(),
+        xnet_payload_builder.clone(),
+        Arc::new(FakeSelfValidatingPayloadBuilder::new()),
+        Arc::new(FakeCanisterHttpPayloadBuilder::new()),
+        MetricsRegistry::new(),
+        no_op_logger(),
+    )
+    .with_build_stats(Some(node_test_id(0)));
+
+    let context = ValidationContext {
+        certified_height: Height::from(0),
+        registry_version: RegistryVersion::from(1),
+        time: mock_time(),
+    };
+
+    let mut past_payloads: VecDeque<(Height, Time, Payload)> = VecDeque::new();
+    let mut section_stats: BTreeMap<PayloadSection, SectionStats> = BTreeMap::new();
+    let mut total_bytes = 0;
+    let mut total_time = Duration::default();
+    let mut simulated = 0;
+
+    for h in 1..=heights {
+        if !traffic.arrive(h, &ingress_selector, &xnet_payload_builder) {
+            break;
+        }
+        let height = Height::from(h);
+        let past: Vec<_> = past_payloads.iter().cloned().collect();
+
+        let start = Instant::now();
+        // Parent hashes vary with the height, and so does the order in which
+        // the sections are built.
+        let parent_hash = CryptoHashOf::from(CryptoHash(h.to_be_bytes().repeat(4)));
+        let payload =
+            payload_builder.get_payload(height, &parent_hash, &past, &context, &subnet_records);
+        total_time += start.elapsed();
+
+        let build_stats = payload
+            .build_stats
+            .as_ref()
+            .expect("The payload builder attaches build stats");
+        for stats in &build_stats.sections {
+            section_stats.entry(stats.section).or_default().record(
+                stats.bytes_included,
+                Duration::from_micros(stats.build_duration_micros),
+            );
+            total_bytes += stats.bytes_included;
+        }
+        simulated += 1;
+
+        // Past payloads are passed in descending height order.
+        past_payloads.push_front((height, mock_time(), wrap_batch_payload(h, payload)));
+        past_payloads.truncate(PAST_PAYLOAD_DEPTH);
+    }
+
+    if simulated == 0 {
+        println!("Nothing to simulate.");
+        return;
+    }
+
+    let capacity = (simulated * MAX_BLOCK_PAYLOAD_SIZE) as f64;
+    println!(
+        "Simulated {} heights (mix: {}), max block payload size {} bytes",
+        simulated, mix_name, MAX_BLOCK_PAYLOAD_SIZE
+    );
+    println!(
+        "{:<16} {:>12} {:>14} {:>16}",
+        "section", "fill ratio", "max bytes", "avg build time"
+    );
+    for (section, stats) in &section_stats {
+        println!(
+            "{:<16} {:>12.4} {:>14} {:>16?}",
+            section.as_str(),
+            stats.bytes as f64 / capacity,
+            stats.max_bytes,
+            stats.build_time / simulated as u32
+        );
+    }
+    println!(
+        "{:<16} {:>12.4} {:>14} {:>16?}",
+        "total",
+        total_bytes as f64 / capacity,
+        "",
+        total_time / simulated as u32
+    );
+    println!(
+        "Left over: {} ingress messages, {} stream slices",
+        ingress_selector.queue.lock().unwrap().len(),
+        xnet_payload_builder.queue.lock().unwrap().len()
+    );
+}
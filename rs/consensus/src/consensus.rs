@@ -3,6 +3,7 @@
 
 pub mod batch_delivery;
 pub(crate) mod block_maker;
+mod canary_payload_builder;
 mod catchup_package_maker;
 pub(crate) mod crypto;
 pub mod dkg_key_manager;
@@ -13,6 +14,7 @@ pub(crate) mod metrics;
 mod notary;
 mod payload;
 pub mod payload_builder;
+pub mod payload_simulation;
 pub mod pool_reader;
 mod prelude;
 mod priority;
@@ -22,6 +24,7 @@ mod random_tape_maker;
 mod share_aggregator;
 pub mod utils;
 pub mod validator;
+mod xnet_compression;
 
 pub use block_maker::SubnetRecords;
 pub use crypto::ConsensusCrypto;
@@ -150,16 +153,23 @@ impl ConsensusImpl {
         logger: ReplicaLogger,
         local_store_time_reader: Option<Arc<dyn LocalStoreCertifiedTimeReader>>,
     ) -> Self {
-        let payload_builder = Arc::new(PayloadBuilderImpl::new(
-            replica_config.subnet_id,
-            registry_client.clone(),
-            ingress_selector.clone(),
-            xnet_payload_builder,
-            self_validating_payload_builder,
-            canister_http_payload_builder,
-            metrics_registry.clone(),
-            logger.clone(),
-        ));
+        let payload_builder = Arc::new(
+            PayloadBuilderImpl::new(
+                replica_config.subnet_id,
+                registry_client.clone(),
+                ingress_selector.clone(),
+                xnet_payload_builder,
+                self_validating_payload_builder,
+                canister_http_payload_builder,
+                metrics_registry.clone(),
+                logger.clone(),
+            )
+            .with_build_stats(
+                consensus_config
+                    .include_payload_build_stats()
+                    .then(|| replica_config.node_id),
+            ),
+        );
 
         let current_time = time_source.get_relative_time();
         let mut last_invoked: BTreeMap<ConsensusSubcomponent, Time> = BTreeMap::new();
@@ -7,6 +7,7 @@ use crate::consensus::{
     pool_reader::PoolReader,
     prelude::*,
     utils::{crypto_hashable_to_seed, get_block_hash_string, lookup_replica_version},
+    xnet_compression::decompress_finalized_batch_payload,
 };
 use crate::ecdsa::utils::EcdsaBlockReaderImpl;
 use ic_artifact_pool::consensus_pool::build_consensus_block_chain;
@@ -147,7 +148,9 @@ pub fn deliver_batches(
                     payload: if block.payload.is_summary() {
                         BatchPayload::default()
                     } else {
-                        BlockPayload::from(block.payload).into_data().batch
+                        decompress_finalized_batch_payload(
+                            BlockPayload::from(block.payload).into_data().batch,
+                        )
                     },
                     randomness,
                     ecdsa_subnet_public_keys: ecdsa_subnet_public_key.into_iter().collect(),
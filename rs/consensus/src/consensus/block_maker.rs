@@ -3,7 +3,7 @@ use crate::{
     consensus::{
         membership::Membership,
         metrics::{BlockMakerMetrics, EcdsaPayloadMetrics},
-        payload_builder::PayloadBuilder,
+        payload_builder::{non_batch_payload_size, PayloadBuilder},
         pool_reader::PoolReader,
         prelude::*,
         utils::*,
@@ -20,7 +20,11 @@ use ic_logger::{debug, error, trace, warn, ReplicaLogger};
 use ic_metrics::MetricsRegistry;
 use ic_protobuf::registry::subnet::v1::SubnetRecord;
 use ic_replicated_state::ReplicatedState;
-use ic_types::{consensus::dkg, replica_config::ReplicaConfig, time::current_time};
+use ic_types::{
+    consensus::dkg,
+    replica_config::ReplicaConfig,
+    time::{current_time, MonotonicTime},
+};
 use std::{
     sync::{Arc, RwLock},
     time::Duration,
@@ -89,6 +93,8 @@ pub struct BlockMaker {
     // block. The older is the version, the higher is the probability, that it's universally
     // available across the subnet.
     stable_registry_version_age: Duration,
+    // The latest local time read so far, so that proposals do not go back with the local clock.
+    local_time: MonotonicTime,
 }
 
 impl BlockMaker {
@@ -108,6 +114,11 @@ impl BlockMaker {
         metrics_registry: MetricsRegistry,
         log: ReplicaLogger,
     ) -> Self {
+        let metrics = BlockMakerMetrics::new(metrics_registry.clone());
+        let local_time = MonotonicTime::with_regression_hook({
+            let local_time_regressions = metrics.local_time_regressions.clone();
+            move |_| local_time_regressions.inc()
+        });
         Self {
             time_source,
             replica_config,
@@ -119,9 +130,10 @@ impl BlockMaker {
             ecdsa_pool,
             state_manager,
             log,
-            metrics: BlockMakerMetrics::new(metrics_registry.clone()),
+            metrics,
             ecdsa_payload_metrics: EcdsaPayloadMetrics::new(metrics_registry),
             stable_registry_version_age,
+            local_time,
         }
     }
 
@@ -229,8 +241,13 @@ impl BlockMaker {
             registry_version: stable_registry_version,
             // Below we skip proposing the block if this context is behind the parent's context.
             // We set the time so that block making is not skipped due to local time being
-            // behind the network time.
-            time: std::cmp::max(self.time_source.get_relative_time(), parent.context.time),
+            // behind the network time. A local clock going back is clamped to the latest local
+            // time read so far.
+            time: std::cmp::max(
+                self.local_time
+                    .observe(self.time_source.get_relative_time()),
+                parent.context.time,
+            ),
         };
 
         if !context.greater_or_equal(&parent.context) {
@@ -318,18 +335,7 @@ impl BlockMaker {
                     (summary, ecdsa_summary).into()
                 }
                 dkg::Payload::Dealings(dealings) => {
-                    let batch_payload = match self.build_batch_payload(
-                        pool,
-                        height,
-                        certified_height,
-                        &context,
-                        &parent,
-                        subnet_records,
-                    ) {
-                        None => return None,
-                        Some(payload) => payload,
-                    };
-                    if is_upgrade_pending(
+                    let (dealings, ecdsa_data) = if is_upgrade_pending(
                         height,
                         self.registry_client.as_ref(),
                         &self.replica_config,
@@ -337,12 +343,7 @@ impl BlockMaker {
                         pool,
                     )? {
                         // Use empty DKG dealings if a replica upgrade is pending.
-                        let new_dealings = dkg::Dealings::new_empty(dealings.start_height);
-                        self.metrics.report_byte_estimate_metrics(
-                            batch_payload.xnet.count_bytes(),
-                            batch_payload.ingress.count_bytes(),
-                        );
-                        (batch_payload, new_dealings, None).into()
+                        (dkg::Dealings::new_empty(dealings.start_height), None)
                     } else {
                         let ecdsa_data = ecdsa::create_data_payload(
                             self.replica_config.subnet_id,
@@ -361,12 +362,28 @@ impl BlockMaker {
                         })
                         .ok()
                         .flatten();
-                        self.metrics.report_byte_estimate_metrics(
-                            batch_payload.xnet.count_bytes(),
-                            batch_payload.ingress.count_bytes(),
-                        );
-                        (batch_payload, dealings, ecdsa_data).into()
-                    }
+                        (dealings, ecdsa_data)
+                    };
+                    // The batch payload is built last, so that it fits into the
+                    // block along with the dealings and the ECDSA payload.
+                    let batch_payload = match self.build_batch_payload(
+                        pool,
+                        height,
+                        certified_height,
+                        &context,
+                        &parent,
+                        &parent_hash,
+                        subnet_records,
+                        non_batch_payload_size(&dealings, &ecdsa_data),
+                    ) {
+                        None => return None,
+                        Some(payload) => payload,
+                    };
+                    self.metrics.report_byte_estimate_metrics(
+                        batch_payload.xnet.count_bytes(),
+                        batch_payload.ingress.count_bytes(),
+                    );
+                    (batch_payload, dealings, ecdsa_data).into()
                 }
             },
         );
@@ -395,7 +412,9 @@ impl BlockMaker {
         certified_height: Height,
         context: &ValidationContext,
         parent: &Block,
+        parent_hash: &CryptoHashOf<Block>,
         subnet_records: &SubnetRecords,
+        reserved_bytes: NumBytes,
     ) -> Option<BatchPayload> {
         // Use empty payload if the (agreed) replica_version is not supported.
         let upgrade_pending = is_upgrade_pending(
@@ -426,9 +445,14 @@ impl BlockMaker {
         } else {
             let past_payloads =
                 pool.get_payloads_from_height(certified_height.increment(), parent.clone());
-            let payload =
-                self.payload_builder
-                    .get_payload(height, &past_payloads, context, subnet_records);
+            let payload = self.payload_builder.get_payload_with_reserved_bytes(
+                height,
+                parent_hash,
+                &past_payloads,
+                context,
+                subnet_records,
+                reserved_bytes,
+            );
 
             self.metrics
                 .get_payload_calls
@@ -770,7 +794,7 @@ mod tests {
 
             payload_builder
                 .expect_get_payload()
-                .withf(move |_, payloads, context, _| {
+                .withf(move |_, _, payloads, context, _| {
                     matches_expected_payloads(payloads) && context == &expected_context
                 })
                 .return_const(BatchPayload::default());
@@ -847,6 +871,106 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_block_time_survives_local_clock_regression() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let node_ids = [node_test_id(0)];
+            let Dependencies {
+                mut pool,
+                registry,
+                crypto,
+                time_source,
+                replica_config,
+                state_manager,
+                dkg_pool,
+                ecdsa_pool,
+                ..
+            } = dependencies_with_subnet_params(
+                pool_config,
+                subnet_test_id(0),
+                vec![(
+                    1,
+                    SubnetRecordBuilder::from(&node_ids)
+                        .with_dkg_interval_length(300)
+                        .build(),
+                )],
+            );
+            state_manager
+                .get_mut()
+                .expect_get_state_hash_at()
+                .return_const(Ok(CryptoHashOfState::from(CryptoHash(Vec::new()))));
+            state_manager
+                .get_mut()
+                .expect_latest_certified_height()
+                .return_const(Height::from(1));
+            state_manager
+                .get_mut()
+                .expect_get_state_at()
+                .return_const(Ok(ic_interfaces_state_manager::Labeled::new(
+                    Height::new(0),
+                    Arc::new(ic_test_utilities::state::get_initial_state(0, 0)),
+                )));
+
+            let mut payload_builder = MockPayloadBuilder::new();
+            payload_builder
+                .expect_get_payload()
+                .return_const(BatchPayload::default());
+            let membership = Arc::new(Membership::new(
+                pool.get_cache(),
+                registry.clone(),
+                replica_config.subnet_id,
+            ));
+            let block_maker = BlockMaker::new(
+                Arc::clone(&time_source) as Arc<_>,
+                replica_config,
+                Arc::clone(&registry) as Arc<dyn RegistryClient>,
+                membership,
+                crypto,
+                Arc::new(payload_builder),
+                dkg_pool,
+                ecdsa_pool,
+                state_manager,
+                Duration::from_millis(0),
+                MetricsRegistry::new(),
+                no_op_logger(),
+            );
+
+            pool.advance_round_normal_operation_n(4);
+            let parent = pool.get_cache().finalized_block();
+            let parent_time = parent.context.time;
+            let propose = |parent: &Block| {
+                block_maker
+                    .propose_block(&PoolReader::new(&pool), Rank(0), parent.clone())
+                    .expect("Expected a new block proposal")
+                    .content
+                    .as_ref()
+                    .context
+                    .time
+            };
+
+            // The local time is used when it is ahead of the parent's time.
+            let local_time = parent_time + Duration::from_secs(10);
+            time_source.set_time(local_time).unwrap();
+            assert_eq!(propose(&parent), local_time);
+
+            // A local clock going back is clamped to the latest local time read and counted.
+            time_source.reset();
+            time_source
+                .set_time(parent_time + Duration::from_secs(5))
+                .unwrap();
+            assert_eq!(propose(&parent), local_time);
+            assert_eq!(block_maker.metrics.local_time_regressions.get(), 1);
+
+            // The time of a parent ahead of the local time is used for its child only, and
+            // does not hold back the proposals on top of other parents.
+            let mut parent_ahead = parent.clone();
+            parent_ahead.context.time = parent_time + Duration::from_secs(3600);
+            assert_eq!(propose(&parent_ahead), parent_ahead.context.time);
+            assert_eq!(propose(&parent), local_time);
+            assert_eq!(block_maker.metrics.local_time_regressions.get(), 2);
+        })
+    }
+
     // We expect block maker to correctly detect version change and start
     // making only empty blocks.
     #[test]
@@ -0,0 +1,204 @@
+//! Builder of the canary payload section.
+//!
+//! The canary section has no function. Its size is set by the
+//! `canary_payload_bytes` subnet feature, which allows exercising the
+//! serialization, size accounting and validation of a new payload section on
+//! a live subnet before rolling out a section with actual content.
+
+use crate::consensus::utils::get_subnet_record;
+use ic_interfaces::{
+    consensus::{InvalidCanaryPayload, PayloadPermanentError, PayloadValidationError},
+    registry::RegistryClient,
+    validation::ValidationError,
+};
+use ic_logger::ReplicaLogger;
+use ic_registry_subnet_features::SubnetFeatures;
+use ic_types::{
+    batch::{CanaryPayload, ValidationContext, MAX_CANARY_PAYLOAD_SIZE},
+    CountBytes, Height, NumBytes, SubnetId,
+};
+use std::sync::Arc;
+
+pub(crate) struct CanaryPayloadBuilder {
+    subnet_id: SubnetId,
+    registry_client: Arc<dyn RegistryClient>,
+    logger: ReplicaLogger,
+}
+
+impl CanaryPayloadBuilder {
+    pub(crate) fn new(
+        subnet_id: SubnetId,
+        registry_client: Arc<dyn RegistryClient>,
+        logger: ReplicaLogger,
+    ) -> Self {
+        Self {
+            subnet_id,
+            registry_client,
+            logger,
+        }
+    }
+
+    /// Returns the canary payload for the block at `height`, of the size
+    /// configured in the registry, truncated to `max_size`.
+    pub(crate) fn get_canary_payload(
+        &self,
+        height: Height,
+        validation_context: &ValidationContext,
+        max_size: NumBytes,
+    ) -> CanaryPayload {
+        match self.get_max_size(validation_context) {
+            Ok(size) => CanaryPayload::new(height, size.min(max_size).get() as usize),
+            Err(_) => CanaryPayload::default(),
+        }
+    }
+
+    /// Validates the canary payload of the block at `height`. The payload may
+    /// be smaller than configured, as the block maker truncates it to the
+    /// space left in the block.
+    pub(crate) fn validate_canary_payload(
+        &self,
+        height: Height,
+        payload: &CanaryPayload,
+        validation_context: &ValidationContext,
+    ) -> Result<NumBytes, PayloadValidationError> {
+        let size = NumBytes::new(payload.count_bytes() as u64);
+        if payload.is_empty() {
+            return Ok(size);
+        }
+        let max = self.get_max_size(validation_context)?;
+        if size > max {
+            return Err(ValidationError::Permanent(
+                PayloadPermanentError::CanaryPayloadValidationError(
+                    InvalidCanaryPayload::TooLarge {
+                        max,
+                        received: size,
+                    },
+                ),
+            ));
+        }
+        if !payload.is_valid_at(height) {
+            return Err(ValidationError::Permanent(
+                PayloadPermanentError::CanaryPayloadValidationError(
+                    InvalidCanaryPayload::UnexpectedContent,
+                ),
+            ));
+        }
+        Ok(size)
+    }
+
+    // Returns the size of the canary payload configured in the registry
+    // version of the validation context, 0 if the feature is disabled.
+    fn get_max_size(
+        &self,
+        validation_context: &ValidationContext,
+    ) -> Result<NumBytes, PayloadValidationError> {
+        let subnet_record = get_subnet_record(
+            self.registry_client.as_ref(),
+            self.subnet_id,
+            validation_context.registry_version,
+            &self.logger,
+        )?;
+        let features: SubnetFeatures = subnet_record.features.unwrap_or_default().into();
+        Ok(NumBytes::new(
+            features
+                .canary_payload_bytes
+                .unwrap_or(0)
+                .min(MAX_CANARY_PAYLOAD_SIZE as u64),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::mocks::{dependencies_with_subnet_params, Dependencies};
+    use ic_logger::replica_logger::no_op_logger;
+    use ic_test_utilities::{
+        mock_time,
+        types::ids::{node_test_id, subnet_test_id},
+    };
+    use ic_test_utilities_registry::SubnetRecordBuilder;
+    use ic_types::RegistryVersion;
+
+    fn make_builder(
+        pool_config: ic_config::artifact_pool::ArtifactPoolConfig,
+        canary_payload_bytes: Option<u64>,
+    ) -> CanaryPayloadBuilder {
+        let mut subnet_record = SubnetRecordBuilder::from(&[node_test_id(0)]).build();
+        subnet_record.features = Some(
+            SubnetFeatures {
+                canary_payload_bytes,
+                ..SubnetFeatures::default()
+            }
+            .into(),
+        );
+        let Dependencies { registry, .. } = dependencies_with_subnet_params(
+            pool_config,
+            subnet_test_id(0),
+            vec![(1, subnet_record)],
+        );
+        CanaryPayloadBuilder::new(subnet_test_id(0), registry, no_op_logger())
+    }
+
+    fn context() -> ValidationContext {
+        ValidationContext {
+            certified_height: Height::from(0),
+            registry_version: RegistryVersion::from(1),
+            time: mock_time(),
+        }
+    }
+
+    #[test]
+    fn canary_payload_has_configured_size_and_validates() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let builder = make_builder(pool_config, Some(100));
+            let height = Height::from(5);
+
+            let payload = builder.get_canary_payload(height, &context(), NumBytes::new(1000));
+            assert_eq!(payload.count_bytes(), 100);
+            assert_eq!(
+                builder
+                    .validate_canary_payload(height, &payload, &context())
+                    .unwrap(),
+                NumBytes::new(100)
+            );
+
+            // The payload is truncated to the space left in the block.
+            let payload = builder.get_canary_payload(height, &context(), NumBytes::new(10));
+            assert_eq!(payload.count_bytes(), 10);
+            assert!(builder
+                .validate_canary_payload(height, &payload, &context())
+                .is_ok());
+
+            // The content depends on the height.
+            assert!(matches!(
+                builder.validate_canary_payload(Height::from(6), &payload, &context()),
+                Err(ValidationError::Permanent(
+                    PayloadPermanentError::CanaryPayloadValidationError(
+                        InvalidCanaryPayload::UnexpectedContent
+                    )
+                ))
+            ));
+        });
+    }
+
+    #[test]
+    fn canary_payload_is_rejected_if_disabled() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let builder = make_builder(pool_config, None);
+            let height = Height::from(5);
+
+            assert!(builder
+                .get_canary_payload(height, &context(), NumBytes::new(1000))
+                .is_empty());
+            assert!(matches!(
+                builder.validate_canary_payload(height, &CanaryPayload::new(height, 1), &context()),
+                Err(ValidationError::Permanent(
+                    PayloadPermanentError::CanaryPayloadValidationError(
+                        InvalidCanaryPayload::TooLarge { .. }
+                    )
+                ))
+            ));
+        });
+    }
+}
@@ -5,15 +5,15 @@ use ic_metrics::{
     MetricsRegistry,
 };
 use ic_types::{
-    batch::Batch,
+    batch::{Batch, IngressPayload},
     consensus::{
         ecdsa::{CompletedReshareRequest, CompletedSignature, EcdsaPayload, KeyTranscriptCreation},
         Block, BlockProposal, HasHeight, HasRank,
     },
-    CountBytes,
+    CountBytes, Time,
 };
 use prometheus::{
-    GaugeVec, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    Gauge, GaugeVec, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
 };
 use std::sync::RwLock;
 
@@ -29,6 +29,7 @@ pub(crate) const CRITICAL_ERROR_SUBNET_RECORD_ISSUE: &str = "consensus_subnet_re
 pub struct BlockMakerMetrics {
     pub get_payload_calls: IntCounterVec,
     pub block_size_bytes_estimate: IntGaugeVec,
+    pub local_time_regressions: IntCounter,
 }
 
 impl BlockMakerMetrics {
@@ -42,7 +43,11 @@ impl BlockMakerMetrics {
             block_size_bytes_estimate: metrics_registry.int_gauge_vec(
                 "consensus_block_size_bytes_estimate",
                 "An estimate about the block size produced by the block maker.",
-                &["payload_type"])
+                &["payload_type"]),
+            local_time_regressions: metrics_registry.int_counter(
+                "consensus_block_maker_local_time_regressions",
+                "The number of times the local time went back between reads by the block maker.",
+            ),
         }
     }
 
@@ -374,6 +379,16 @@ pub struct PayloadBuilderMetrics {
     pub get_payload_duration: Histogram,
     pub validate_payload_duration: Histogram,
     pub past_payloads_length: Histogram,
+    pub validate_payload_section_retries: IntCounterVec,
+    pub section_bytes_included: IntCounterVec,
+    pub max_block_payload_size: IntGauge,
+    pub accumulated_size: IntGauge,
+    pub byte_budget_utilization: Histogram,
+    pub xnet_compression_ratio: Histogram,
+    pub xnet_compression_duration: HistogramVec,
+    pub ingress_expiry_margin: Histogram,
+    pub ingress_expiry_margin_min: Gauge,
+    pub ingress_expiry_margin_max: Gauge,
 
     /// Critical error for payloads above the maximum supported size
     pub cricital_error_payload_too_large: IntCounter,
@@ -386,6 +401,35 @@ pub struct PayloadBuilderMetrics {
 }
 
 impl PayloadBuilderMetrics {
+    /// Records the time left until the expiry of the ingress messages
+    /// included in a built payload, relative to the time of its block.
+    /// Short margins indicate clients setting expiries that risk expiring in
+    /// flight.
+    pub fn observe_ingress_expiries(&self, block_time: Time, ingress: &IngressPayload) {
+        let margins: Vec<f64> = ingress
+            .message_ids()
+            .iter()
+            .map(|id| {
+                id.expiry()
+                    .as_nanos_since_unix_epoch()
+                    .saturating_sub(block_time.as_nanos_since_unix_epoch()) as f64
+                    / 1e9
+            })
+            .collect();
+        for margin in &margins {
+            self.ingress_expiry_margin.observe(*margin);
+        }
+        // Blocks without ingress messages leave the gauges of the latest block
+        // with some.
+        if let (Some(min), Some(max)) = (
+            margins.iter().copied().reduce(f64::min),
+            margins.iter().copied().reduce(f64::max),
+        ) {
+            self.ingress_expiry_margin_min.set(min);
+            self.ingress_expiry_margin_max.set(max);
+        }
+    }
+
     pub fn new(metrics_registry: MetricsRegistry) -> Self {
         Self {
             get_payload_duration: metrics_registry.histogram(
@@ -407,6 +451,55 @@ impl PayloadBuilderMetrics {
                 "The length of past_payloads in payload selection",
                 linear_buckets(0.0, 1.0, 6),
             ),
+            validate_payload_section_retries: metrics_registry.int_counter_vec(
+                "consensus_validate_payload_section_retries_total",
+                "The number of times the validation of a payload section failed with a transient error and was retried in a later round, by section and final result (valid, permanent, or transient if its height was finalized first)",
+                &["section", "result"],
+            ),
+            section_bytes_included: metrics_registry.int_counter_vec(
+                "consensus_payload_section_bytes_included_total",
+                "The number of bytes included in built payloads, by section. The share of a section over time shows whether it gets its fair share of the byte budget",
+                &["section"],
+            ),
+            max_block_payload_size: metrics_registry.int_gauge(
+                "consensus_payload_max_block_payload_size_bytes",
+                "The byte budget of the batch payload of the latest built block, i.e. its maximum size per the subnet record",
+            ),
+            accumulated_size: metrics_registry.int_gauge(
+                "consensus_payload_accumulated_size_bytes",
+                "The size of the batch payload of the latest built block, including the bytes reserved for the rest of the block",
+            ),
+            byte_budget_utilization: metrics_registry.histogram(
+                "consensus_payload_byte_budget_utilization",
+                "The share of the byte budget used by the batch payloads of built blocks. Persistently high values indicate that the maximum block payload size limits throughput",
+                // 0.1, 0.2, ..., 1.0
+                linear_buckets(0.1, 0.1, 10),
+            ),
+            xnet_compression_ratio: metrics_registry.histogram(
+                "consensus_xnet_compression_ratio",
+                "The ratio of the uncompressed to the compressed size of the stream slices in the XNet section of built payloads",
+                vec![1.0, 1.5, 2.0, 3.0, 4.0, 6.0, 8.0, 12.0, 16.0],
+            ),
+            xnet_compression_duration: metrics_registry.histogram_vec(
+                "consensus_xnet_compression_duration_seconds",
+                "The time it took to compress or decompress the XNet section of a payload, in seconds",
+                // 10µs, 20µs, 50µs, 100µs, ..., 1s, 2s, 5s
+                decimal_buckets(-5, 0),
+                &["operation"],
+            ),
+            ingress_expiry_margin: metrics_registry.histogram(
+                "consensus_payload_ingress_expiry_margin_seconds",
+                "The time left until the expiry of the ingress messages included in built payloads, relative to the time of their block, in seconds",
+                vec![5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 180.0, 240.0, 300.0],
+            ),
+            ingress_expiry_margin_min: metrics_registry.gauge(
+                "consensus_payload_ingress_expiry_margin_min_seconds",
+                "The shortest time left until expiry of the ingress messages of the latest built payload with ingress messages, in seconds",
+            ),
+            ingress_expiry_margin_max: metrics_registry.gauge(
+                "consensus_payload_ingress_expiry_margin_max_seconds",
+                "The longest time left until expiry of the ingress messages of the latest built payload with ingress messages, in seconds",
+            ),
             cricital_error_payload_too_large: metrics_registry
                 .error_counter(CRITICAL_ERROR_PAYLOAD_TOO_LARGE),
             critical_error_validation_not_passed: metrics_registry
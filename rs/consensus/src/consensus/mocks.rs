@@ -19,7 +19,8 @@ use ic_test_utilities::{
 use ic_test_utilities_registry::{setup_registry_non_final, SubnetRecordBuilder};
 use ic_types::{
     batch::{BatchPayload, ValidationContext},
-    consensus::Payload,
+    consensus::{Block, Payload},
+    crypto::CryptoHashOf,
     replica_config::ReplicaConfig,
     Height, RegistryVersion, SubnetId, Time,
 };
@@ -36,6 +37,7 @@ mock! {
         fn get_payload<'a>(
             &self,
             height: Height,
+            parent_hash: &CryptoHashOf<Block>,
             past_payloads: &[(Height, Time, Payload)],
             context: &ValidationContext,
             subnet_records: &SubnetRecords,
@@ -1,15 +1,24 @@
-use crate::consensus::metrics::{
-    PayloadBuilderMetrics, CRITICAL_ERROR_PAYLOAD_TOO_LARGE, CRITICAL_ERROR_VALIDATION_NOT_PASSED,
+use crate::consensus::{
+    canary_payload_builder::CanaryPayloadBuilder,
+    metrics::{
+        PayloadBuilderMetrics, CRITICAL_ERROR_PAYLOAD_TOO_LARGE,
+        CRITICAL_ERROR_VALIDATION_NOT_PASSED,
+    },
+    xnet_compression::DecompressedXNetPayloads,
 };
 use ic_interfaces::{
-    canister_http::CanisterHttpPayloadBuilder, consensus::PayloadValidationError,
-    ingress_manager::IngressSelector, messaging::XNetPayloadBuilder,
+    canister_http::CanisterHttpPayloadBuilder,
+    consensus::{PayloadPermanentError, PayloadValidationError},
+    ingress_manager::IngressSelector,
+    messaging::XNetPayloadBuilder,
     self_validating_payload::SelfValidatingPayloadBuilder,
 };
 use ic_logger::{error, warn, ReplicaLogger};
+use ic_registry_subnet_features::{DisabledPayloadSections, SubnetFeatures};
 use ic_types::{
     batch::{
-        BatchPayload, CanisterHttpPayload, IngressPayload, SelfValidatingPayload, ValidationContext,
+        BatchPayload, CanaryPayload, CanisterHttpPayload, IngressPayload, PayloadSection,
+        SelfValidatingPayload, ValidationContext, XNetPayload,
     },
     consensus::Payload,
     CountBytes, Height, NumBytes, Time,
@@ -37,28 +46,89 @@ use std::sync::Arc;
 // [validate_payload]: (BatchPayloadSectionBuilder::validate_payload)
 pub(crate) enum BatchPayloadSectionBuilder {
     Ingress(Arc<dyn IngressSelector>),
-    XNet(Arc<dyn XNetPayloadBuilder>),
+    XNet(Arc<dyn XNetPayloadBuilder>, DecompressedXNetPayloads),
     SelfValidating(Arc<dyn SelfValidatingPayloadBuilder>),
     CanisterHttp(Arc<dyn CanisterHttpPayloadBuilder>),
+    Canary(CanaryPayloadBuilder),
 }
 
 impl BatchPayloadSectionBuilder {
+    /// Returns the section of the [`BatchPayload`] built by this builder.
+    pub(crate) fn section(&self) -> PayloadSection {
+        match self {
+            Self::Ingress(_) => PayloadSection::Ingress,
+            Self::XNet(..) => PayloadSection::XNet,
+            Self::SelfValidating(_) => PayloadSection::SelfValidating,
+            Self::CanisterHttp(_) => PayloadSection::CanisterHttp,
+            Self::Canary(_) => PayloadSection::Canary,
+        }
+    }
+
+    /// Returns true if the section built by this builder is disabled, in
+    /// which case it must be empty.
+    pub(crate) fn is_disabled(&self, disabled_sections: &DisabledPayloadSections) -> bool {
+        is_section_disabled(self.section(), disabled_sections)
+    }
+
+    /// Checks the section built by this builder in `payload` against the
+    /// `features` of the subnet, see [`check_section_features`].
+    pub(crate) fn check_features(
+        &self,
+        payload: &BatchPayload,
+        features: &SubnetFeatures,
+    ) -> Result<(), PayloadPermanentError> {
+        check_section_features(self.section(), payload, features)
+    }
+
+    /// Sorts the contents of the section built by this builder in `payload`
+    /// in canonical order. Reordering does not change the size of a section.
+    pub(crate) fn sort_canonically(
+        &self,
+        payload: &mut BatchPayload,
+        metrics: &PayloadBuilderMetrics,
+        logger: &ReplicaLogger,
+    ) {
+        match self {
+            Self::Ingress(_) => {
+                let ingress = std::mem::take(&mut payload.ingress);
+                match ingress.into_canonical_order() {
+                    Ok(ingress) => payload.ingress = ingress,
+                    Err(err) => {
+                        error!(
+                            logger,
+                            "Failed to sort the ingress payload, this is a bug, {:?} @{}",
+                            err,
+                            CRITICAL_ERROR_VALIDATION_NOT_PASSED
+                        );
+                        metrics.critical_error_validation_not_passed.inc();
+                    }
+                }
+            }
+            Self::SelfValidating(_) => payload.self_validating.sort_canonically(),
+            Self::CanisterHttp(_) => payload.canister_http.sort_canonically(),
+            Self::XNet(..) | Self::Canary(_) => (),
+        }
+    }
+
     /// Called to build the payload.
     ///
     /// # Arguments:
     /// - `validation_context`: The [`ValidationContext`], under which the payload must be valid.
     /// - `max_size`: The maximum size in [`NumBytes`], that the payload section has available in the current block.
+    /// - `max_block_payload_size`: The maximum block payload size, which bounds the sections of `past_payloads`.
     /// - `past_payloads`: All [`BatchPayload`]s from the certified height to the tip.
     /// - `logger`: Access to a [`ReplicaLogger`]
     ///
     /// # Returns:
     /// - The size of the payload in [`NumBytes`]
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn build_payload(
         &self,
         payload: &mut BatchPayload,
         height: Height,
         validation_context: &ValidationContext,
         max_size: NumBytes,
+        max_block_payload_size: NumBytes,
         past_payloads: &[(Height, Time, Payload)],
         metrics: &PayloadBuilderMetrics,
         logger: &ReplicaLogger,
@@ -99,11 +169,20 @@ impl BatchPayloadSectionBuilder {
                     return NumBytes::new(0);
                 }
 
+                metrics.observe_ingress_expiries(validation_context.time, &ingress);
                 payload.ingress = ingress;
                 size
             }
-            Self::XNet(builder) => {
-                let past_payloads = builder.filter_past_payloads(past_payloads);
+            Self::XNet(builder, decompressed) => {
+                let past_payloads = decompressed.past_payloads(
+                    builder.as_ref(),
+                    past_payloads,
+                    max_block_payload_size,
+                );
+                let past_payloads: Vec<&XNetPayload> = past_payloads
+                    .iter()
+                    .map(|payload| payload.as_ref())
+                    .collect();
                 let xnet = builder.get_xnet_payload(validation_context, &past_payloads, max_size);
                 let size = NumBytes::new(xnet.count_bytes() as u64);
 
@@ -194,6 +273,41 @@ impl BatchPayloadSectionBuilder {
                     }
                 }
             }
+            Self::Canary(builder) => {
+                let canary = builder.get_canary_payload(height, validation_context, max_size);
+
+                // Validate the canary payload as a safety measure, exercising
+                // the same checks as a new section would.
+                match builder.validate_canary_payload(height, &canary, validation_context) {
+                    Ok(size) if size <= max_size => {
+                        payload.canary = canary;
+                        size
+                    }
+                    Ok(_) => {
+                        error!(
+                            logger,
+                            "CanaryPayload is larger than byte_limit. This is a bug, @{}",
+                            CRITICAL_ERROR_PAYLOAD_TOO_LARGE
+                        );
+
+                        metrics.cricital_error_payload_too_large.inc();
+                        payload.canary = CanaryPayload::default();
+                        NumBytes::new(0)
+                    }
+                    Err(err) => {
+                        error!(
+                            logger,
+                            "Canary payload did not pass validation, this is a bug, {:?} @{}",
+                            err,
+                            CRITICAL_ERROR_VALIDATION_NOT_PASSED
+                        );
+
+                        metrics.critical_error_validation_not_passed.inc();
+                        payload.canary = CanaryPayload::default();
+                        NumBytes::new(0)
+                    }
+                }
+            }
         }
     }
 
@@ -203,6 +317,7 @@ impl BatchPayloadSectionBuilder {
     /// - `payload`: The payload to verify.
     /// - `validation_context`: The [`ValidationContext`], under which to validate the payload.
     /// - `past_payloads`: All [`Payload`]s from the certified height to the tip.
+    /// - `max_block_payload_size`: The maximum block payload size, which bounds the sections of `past_payloads`.
     ///
     /// # Returns:
     /// **On success:** The size of the section as [`NumBytes`].
@@ -213,6 +328,7 @@ impl BatchPayloadSectionBuilder {
         payload: &BatchPayload,
         validation_context: &ValidationContext,
         past_payloads: &[(Height, Time, Payload)],
+        max_block_payload_size: NumBytes,
     ) -> Result<NumBytes, PayloadValidationError> {
         match self {
             Self::Ingress(builder) => {
@@ -224,8 +340,16 @@ impl BatchPayloadSectionBuilder {
                 )?;
                 Ok(NumBytes::new(payload.ingress.count_bytes() as u64))
             }
-            Self::XNet(builder) => {
-                let past_payloads = builder.filter_past_payloads(past_payloads);
+            Self::XNet(builder, decompressed) => {
+                let past_payloads = decompressed.past_payloads(
+                    builder.as_ref(),
+                    past_payloads,
+                    max_block_payload_size,
+                );
+                let past_payloads: Vec<&XNetPayload> = past_payloads
+                    .iter()
+                    .map(|payload| payload.as_ref())
+                    .collect();
                 Ok(builder.validate_xnet_payload(
                     &payload.xnet,
                     validation_context,
@@ -249,6 +373,65 @@ impl BatchPayloadSectionBuilder {
                     &past_payloads,
                 )?)
             }
+            Self::Canary(builder) => {
+                builder.validate_canary_payload(height, &payload.canary, validation_context)
+            }
         }
     }
 }
+
+/// Returns true if `section` is disabled, in which case it must be empty.
+pub(crate) fn is_section_disabled(
+    section: PayloadSection,
+    disabled_sections: &DisabledPayloadSections,
+) -> bool {
+    match section {
+        PayloadSection::XNet => disabled_sections.xnet,
+        PayloadSection::SelfValidating => disabled_sections.bitcoin,
+        PayloadSection::CanisterHttp => disabled_sections.canister_http,
+        PayloadSection::Ingress | PayloadSection::Canary => false,
+    }
+}
+
+/// Checks `section` of `payload` against the `features` of the subnet: a
+/// disabled section must be empty, and the contents of an enabled one must be
+/// in canonical order if required.
+pub(crate) fn check_section_features(
+    section: PayloadSection,
+    payload: &BatchPayload,
+    features: &SubnetFeatures,
+) -> Result<(), PayloadPermanentError> {
+    if is_section_disabled(section, &features.disabled_payload_sections()) {
+        if !is_section_empty(section, payload) {
+            return Err(PayloadPermanentError::SectionDisabled(section));
+        }
+    } else if features.canonical_payload_order && !is_section_canonically_ordered(section, payload)
+    {
+        return Err(PayloadPermanentError::NonCanonicalOrder(section));
+    }
+    Ok(())
+}
+
+/// Returns true if `section` is empty in `payload`.
+fn is_section_empty(section: PayloadSection, payload: &BatchPayload) -> bool {
+    match section {
+        PayloadSection::Ingress => payload.ingress.is_empty(),
+        PayloadSection::XNet => payload.xnet.stream_slices.is_empty(),
+        PayloadSection::SelfValidating => payload.self_validating.is_empty(),
+        PayloadSection::CanisterHttp => payload.canister_http.is_empty(),
+        PayloadSection::Canary => payload.canary.is_empty(),
+    }
+}
+
+/// Returns true if the contents of `section` are in canonical order in
+/// `payload`.
+fn is_section_canonically_ordered(section: PayloadSection, payload: &BatchPayload) -> bool {
+    match section {
+        PayloadSection::Ingress => payload.ingress.is_canonically_ordered(),
+        PayloadSection::SelfValidating => payload.self_validating.is_canonically_ordered(),
+        PayloadSection::CanisterHttp => payload.canister_http.is_canonically_ordered(),
+        // Stream slices are kept in a map ordered by subnet id, and the
+        // canary payload is a single blob.
+        PayloadSection::XNet | PayloadSection::Canary => true,
+    }
+}
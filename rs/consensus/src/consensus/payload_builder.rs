@@ -2,13 +2,21 @@
 
 use crate::consensus::{
     block_maker::SubnetRecords,
+    canary_payload_builder::CanaryPayloadBuilder,
     metrics::{PayloadBuilderMetrics, CRITICAL_ERROR_SUBNET_RECORD_ISSUE},
     payload::BatchPayloadSectionBuilder,
     utils::get_subnet_record,
+    xnet_compression::{
+        compress_xnet_payload, decompress_enabled_xnet_payload, is_compressed,
+        DecompressedXNetPayloads,
+    },
 };
 use ic_interfaces::{
     canister_http::CanisterHttpPayloadBuilder,
-    consensus::{PayloadPermanentError, PayloadValidationError},
+    consensus::{
+        InvalidPayloadBuildStats, PayloadPermanentError, PayloadSizeBreakdown,
+        PayloadValidationError,
+    },
     ingress_manager::IngressSelector,
     messaging::XNetPayloadBuilder,
     registry::RegistryClient,
@@ -17,33 +25,65 @@ use ic_interfaces::{
 };
 use ic_logger::{warn, ReplicaLogger};
 use ic_metrics::MetricsRegistry;
-use ic_protobuf::registry::subnet::v1::SubnetRecord;
+use ic_protobuf::{registry::subnet::v1::SubnetRecord, types::v1 as pb};
+use ic_registry_subnet_features::SubnetFeatures;
 use ic_types::{
-    batch::{BatchPayload, ValidationContext, MAX_BITCOIN_BLOCK_SIZE},
-    consensus::Payload,
+    batch::{
+        BatchPayload, PayloadBuildStats, PayloadSection, SectionBuildStats, ValidationContext,
+        MAX_BITCOIN_BLOCK_SIZE,
+    },
+    consensus::{dkg, ecdsa, Block, Payload},
+    crypto::CryptoHashOf,
     messages::MAX_XNET_PAYLOAD_IN_BYTES,
-    Height, NumBytes, SubnetId, Time,
+    time::{Clock, Stopwatch, SystemClock},
+    Height, NodeId, NumBytes, SubnetId, Time,
 };
-use std::sync::Arc;
+use prometheus::Histogram;
+use prost::Message;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
 
 /// The [`PayloadBuilder`] is responsible for creating and validating payload that
 /// is included in consensus blocks.
 pub trait PayloadBuilder: Send + Sync {
     /// Produces a payload that is valid given `past_payloads` and `context`.
     ///
-    /// `past_payloads` contains the `Payloads` from all blocks above the
-    /// certified height provided in `context`, in descending block height
-    /// order.
+    /// `parent_hash` is the hash of the parent of the block the payload is
+    /// built for, and `past_payloads` contains the `Payloads` from all blocks
+    /// above the certified height provided in `context`, in descending block
+    /// height order.
     fn get_payload(
         &self,
         height: Height,
+        parent_hash: &CryptoHashOf<Block>,
         past_payloads: &[(Height, Time, Payload)],
         context: &ValidationContext,
         subnet_records: &SubnetRecords,
     ) -> BatchPayload;
 
+    /// Produces a payload like [`PayloadBuilder::get_payload`], leaving
+    /// `reserved_bytes` of the maximum block payload size to the parts of the
+    /// block payload built by the block maker itself, i.e. the DKG dealings
+    /// and the ECDSA payload, see [`non_batch_payload_size`]. The bytes are
+    /// only reserved as far as they are counted, see
+    /// [`counted_non_batch_payload_size`].
+    fn get_payload_with_reserved_bytes(
+        &self,
+        height: Height,
+        parent_hash: &CryptoHashOf<Block>,
+        past_payloads: &[(Height, Time, Payload)],
+        context: &ValidationContext,
+        subnet_records: &SubnetRecords,
+        _reserved_bytes: NumBytes,
+    ) -> BatchPayload {
+        self.get_payload(height, parent_hash, past_payloads, context, subnet_records)
+    }
+
     /// Checks whether the provided `payload` is valid given `past_payloads` and
-    /// `context`.
+    /// `context`. The batch payload must fit into the maximum block payload
+    /// size along with the other parts of the block payload.
     ///
     /// `past_payloads` contains the `Payloads` from all blocks above the
     /// certified height provided in `context`, in descending block height
@@ -55,6 +95,71 @@ pub trait PayloadBuilder: Send + Sync {
         past_payloads: &[(Height, Time, Payload)],
         context: &ValidationContext,
     ) -> ValidationResult<PayloadValidationError>;
+
+    /// Notifies the builder that the block at `height` with context time `time`
+    /// holding `payload` was finalized. Called for every finalized height, in
+    /// ascending order.
+    fn on_payload_finalized(&self, _height: Height, _time: Time, _payload: &Payload) {}
+}
+
+/// The number of heights below the latest finalized height for which
+/// finalized payloads are remembered until they are certified.
+const PENDING_CERTIFICATION_HEIGHTS: u64 = 100;
+
+/// Returns the size of the parts of a data block payload other than the batch
+/// payload, i.e. of the DKG dealings and the ECDSA payload, in their protobuf
+/// encoding. Counts against the maximum block payload size along with the
+/// batch payload.
+pub fn non_batch_payload_size(dealings: &dkg::Dealings, ecdsa: &ecdsa::Payload) -> NumBytes {
+    let dealings_size = pb::DkgPayload::from(dealings).encoded_len();
+    let ecdsa_size = ecdsa.as_ref().map_or(0, |ecdsa| {
+        pb::EcdsaSummaryPayload::from(ecdsa).encoded_len()
+    });
+    NumBytes::new((dealings_size + ecdsa_size) as u64)
+}
+
+/// Returns the bytes of the maximum block payload size taken by the DKG
+/// dealings and the ECDSA payload of size `non_batch_size`, see
+/// [`non_batch_payload_size`]. They are only counted on subnets with the
+/// `count_non_batch_payload_size` feature, and never beyond the maximum block
+/// payload size: the block maker can't leave them out, so they must not make
+/// a block with an empty batch payload invalid and stall the subnet.
+pub fn counted_non_batch_payload_size(
+    features: &SubnetFeatures,
+    non_batch_size: NumBytes,
+    max_block_payload_size: NumBytes,
+) -> NumBytes {
+    if features.count_non_batch_payload_size {
+        non_batch_size.min(max_block_payload_size)
+    } else {
+        NumBytes::new(0)
+    }
+}
+
+// Returns the ids of the enabled section builders in the order they are
+// called for the block with parent `parent_hash`. The order is a pseudo-random
+// permutation seeded by the parent hash, so that each section is first on the
+// same share of blocks and no section can starve the others of the byte
+// budget, while no section always follows the same other one, as it would in
+// a rotation by height. Disabled sections are left out before ordering, so
+// that the remaining ones share the first places evenly.
+fn section_order(parent_hash: &CryptoHashOf<Block>, enabled_sections: Vec<usize>) -> Vec<usize> {
+    let mut seed = [0; 32];
+    let hash = &parent_hash.get_ref().0;
+    let len = hash.len().min(seed.len());
+    seed[..len].copy_from_slice(&hash[..len]);
+    let mut rng = ChaCha20Rng::from_seed(seed);
+    // Sorting by random keys is a uniform permutation, and unlike a shuffle
+    // only depends on the output of the seeded generator.
+    let mut keyed: Vec<_> = enabled_sections
+        .into_iter()
+        .map(|section_id| (rng.next_u64(), section_id))
+        .collect();
+    keyed.sort_unstable();
+    keyed
+        .into_iter()
+        .map(|(_, section_id)| section_id)
+        .collect()
 }
 
 /// Implementation of PayloadBuilder.
@@ -64,6 +169,29 @@ pub struct PayloadBuilderImpl {
     section_builder: Vec<BatchPayloadSectionBuilder>,
     metrics: PayloadBuilderMetrics,
     logger: ReplicaLogger,
+    clock: Arc<dyn Clock>,
+    build_stats_block_maker: Option<NodeId>,
+    // Finalized payloads above the highest certified height seen so far. A
+    // `Payload` shares its contents with the block in the consensus pool
+    // through an `Arc`, so the messages are not copied.
+    pending_certification: Mutex<BTreeMap<Height, (Time, Payload)>>,
+    // The transient errors of the validation of payload sections at heights
+    // not finalized yet, counted as retries once the validation completes.
+    transient_section_errors: Mutex<BTreeMap<(Height, PayloadSection), u64>>,
+}
+
+// Observes the time elapsed on the payload builder's clock into a histogram
+// when dropped, like `prometheus::HistogramTimer`.
+struct DurationTimer<'a> {
+    histogram: &'a Histogram,
+    stopwatch: Stopwatch,
+}
+
+impl Drop for DurationTimer<'_> {
+    fn drop(&mut self) {
+        self.histogram
+            .observe(self.stopwatch.elapsed().as_secs_f64());
+    }
 }
 
 impl PayloadBuilderImpl {
@@ -81,8 +209,16 @@ impl PayloadBuilderImpl {
         let section_builder = vec![
             BatchPayloadSectionBuilder::Ingress(ingress_selector),
             BatchPayloadSectionBuilder::SelfValidating(self_validating_payload_builder),
-            BatchPayloadSectionBuilder::XNet(xnet_payload_builder),
+            BatchPayloadSectionBuilder::XNet(
+                xnet_payload_builder,
+                DecompressedXNetPayloads::default(),
+            ),
             BatchPayloadSectionBuilder::CanisterHttp(canister_http_payload_builder),
+            BatchPayloadSectionBuilder::Canary(CanaryPayloadBuilder::new(
+                subnet_id,
+                Arc::clone(&registry_client),
+                logger.clone(),
+            )),
         ];
 
         Self {
@@ -91,57 +227,140 @@ impl PayloadBuilderImpl {
             section_builder,
             metrics: PayloadBuilderMetrics::new(metrics),
             logger,
+            clock: Arc::new(SystemClock::new()),
+            build_stats_block_maker: None,
+            pending_certification: Mutex::new(BTreeMap::new()),
+            transient_section_errors: Mutex::new(BTreeMap::new()),
         }
     }
+
+    /// Measures the durations of building and validating payloads on `clock`
+    /// instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// If `block_maker` is set, attaches [`PayloadBuildStats`] identifying
+    /// `block_maker` to the built payloads. Build stats of other block makers
+    /// are validated regardless.
+    pub fn with_build_stats(mut self, block_maker: Option<NodeId>) -> Self {
+        self.build_stats_block_maker = block_maker;
+        self
+    }
 }
 
 impl PayloadBuilder for PayloadBuilderImpl {
     fn get_payload(
         &self,
         height: Height,
+        parent_hash: &CryptoHashOf<Block>,
         past_payloads: &[(Height, Time, Payload)],
         context: &ValidationContext,
         subnet_records: &SubnetRecords,
     ) -> BatchPayload {
-        let _timer = self.metrics.get_payload_duration.start_timer();
+        self.get_payload_with_reserved_bytes(
+            height,
+            parent_hash,
+            past_payloads,
+            context,
+            subnet_records,
+            NumBytes::new(0),
+        )
+    }
+
+    fn get_payload_with_reserved_bytes(
+        &self,
+        height: Height,
+        parent_hash: &CryptoHashOf<Block>,
+        past_payloads: &[(Height, Time, Payload)],
+        context: &ValidationContext,
+        subnet_records: &SubnetRecords,
+        reserved_bytes: NumBytes,
+    ) -> BatchPayload {
+        let _timer = self.start_timer(&self.metrics.get_payload_duration);
         self.metrics
             .past_payloads_length
             .observe(past_payloads.len() as f64);
 
-        // To call the section builders in a somewhat fair manner,
-        // we call them in a rotation. Note that this is not really fair,
-        // as payload builders that yield a lot always give precendence to the
-        // same next payload builder. This might give an advantage to a particular
-        // payload builder.
-        let num_sections = self.section_builder.len();
-        let mut section_select = (0..num_sections).collect::<Vec<_>>();
-        section_select.rotate_right(height.get() as usize % num_sections);
-
         // Fetch Subnet Record for Consensus registry version, return empty batch payload is not available
         let max_block_payload_size =
             self.get_max_block_payload_size_bytes(&subnet_records.context_version);
 
+        let features = subnet_features(&subnet_records.context_version);
+        let disabled_sections = features.disabled_payload_sections();
+        let in_flight_payloads = self.in_flight_payloads(height, past_payloads, context);
+
+        let enabled_sections: Vec<usize> = (0..self.section_builder.len())
+            .filter(|section_id| !self.section_builder[*section_id].is_disabled(&disabled_sections))
+            .collect();
         let mut batch_payload = BatchPayload::default();
-        let mut accumulated_size = 0;
+        let mut accumulated_size =
+            counted_non_batch_payload_size(&features, reserved_bytes, max_block_payload_size).get();
+        let mut section_stats = Vec::with_capacity(enabled_sections.len());
 
-        for section_id in section_select {
-            accumulated_size += self.section_builder[section_id]
+        for section_id in section_order(parent_hash, enabled_sections) {
+            let builder = &self.section_builder[section_id];
+            let byte_limit = max_block_payload_size
+                .get()
+                .saturating_sub(accumulated_size);
+            let past_payloads = match builder.section() {
+                PayloadSection::Ingress => &in_flight_payloads,
+                _ => past_payloads,
+            };
+            let stopwatch = Stopwatch::start(Arc::clone(&self.clock));
+            let size = builder
                 .build_payload(
                     &mut batch_payload,
                     height,
                     context,
-                    NumBytes::new(
-                        max_block_payload_size
-                            .get()
-                            .saturating_sub(accumulated_size),
-                    ),
+                    NumBytes::new(byte_limit),
+                    max_block_payload_size,
                     past_payloads,
                     &self.metrics,
                     &self.logger,
                 )
                 .get();
+            if features.canonical_payload_order {
+                builder.sort_canonically(&mut batch_payload, &self.metrics, &self.logger);
+            }
+            section_stats.push(SectionBuildStats {
+                section: builder.section(),
+                build_duration_micros: stopwatch.elapsed().as_micros() as u64,
+                byte_limit,
+                bytes_included: size,
+            });
+            self.metrics
+                .section_bytes_included
+                .with_label_values(&[builder.section().as_str()])
+                .inc_by(size);
+            accumulated_size += size;
+        }
+
+        // The bytes of each section are counted by `section_bytes_included`
+        // above, the budget as a whole is recorded here.
+        self.metrics
+            .max_block_payload_size
+            .set(max_block_payload_size.get() as i64);
+        self.metrics.accumulated_size.set(accumulated_size as i64);
+        if max_block_payload_size.get() > 0 {
+            self.metrics
+                .byte_budget_utilization
+                .observe(accumulated_size as f64 / max_block_payload_size.get() as f64);
+        }
+
+        // Sections are built and sized uncompressed, compression only shrinks
+        // the finished XNet section.
+        if features.xnet_compression {
+            compress_xnet_payload(&mut batch_payload.xnet, &self.metrics, &self.logger);
         }
 
+        batch_payload.build_stats =
+            self.build_stats_block_maker
+                .map(|block_maker| PayloadBuildStats {
+                    block_maker,
+                    sections: section_stats,
+                });
         batch_payload
     }
 
@@ -152,34 +371,210 @@ impl PayloadBuilder for PayloadBuilderImpl {
         past_payloads: &[(Height, Time, Payload)],
         context: &ValidationContext,
     ) -> ValidationResult<PayloadValidationError> {
-        let _timer = self.metrics.validate_payload_duration.start_timer();
+        let _timer = self.start_timer(&self.metrics.validate_payload_duration);
         if payload.is_summary() {
             return Ok(());
         }
-        let batch_payload = &payload.as_ref().as_data().batch;
+        let data_payload = payload.as_ref().as_data();
+        let batch_payload = &data_payload.batch;
         let subnet_record = self.get_subnet_record(context)?;
 
         // Retrieve max_block_payload_size from subnet
         let max_block_payload_size = self.get_max_block_payload_size_bytes(&subnet_record);
+        let features = subnet_features(&subnet_record);
+        let disabled_sections = features.disabled_payload_sections();
 
-        let mut accumulated_size = NumBytes::new(0);
+        // Sections are validated uncompressed, the way they were built.
+        let decompressed_payload;
+        let batch_payload = if is_compressed(&batch_payload.xnet) {
+            let histogram = self
+                .metrics
+                .xnet_compression_duration
+                .with_label_values(&["decompress"]);
+            let timer = self.start_timer(&histogram);
+            let xnet = decompress_enabled_xnet_payload(
+                &batch_payload.xnet,
+                &features,
+                max_block_payload_size,
+            )
+            .map_err(|err| {
+                ValidationError::Permanent(PayloadPermanentError::InvalidXNetCompression(err))
+            })?
+            .into_owned();
+            drop(timer);
+            decompressed_payload = BatchPayload {
+                xnet,
+                ..batch_payload.clone()
+            };
+            &decompressed_payload
+        } else {
+            batch_payload
+        };
+
+        // The dealings and the ECDSA payload may count against the limit as
+        // well, but never beyond it.
+        let non_batch_size = counted_non_batch_payload_size(
+            &features,
+            non_batch_payload_size(&data_payload.dealings, &data_payload.ecdsa),
+            max_block_payload_size,
+        );
+        let mut accumulated_size = non_batch_size;
+        let mut section_sizes = BTreeMap::new();
         for builder in &self.section_builder {
-            accumulated_size +=
-                builder.validate_payload(height, batch_payload, context, past_payloads)?;
+            builder
+                .check_features(batch_payload, &features)
+                .map_err(ValidationError::Permanent)?;
+            let size = if builder.is_disabled(&disabled_sections) {
+                NumBytes::new(0)
+            } else {
+                self.validate_section(
+                    builder,
+                    height,
+                    batch_payload,
+                    context,
+                    past_payloads,
+                    max_block_payload_size,
+                )?
+            };
+            section_sizes.insert(builder.section(), size);
+            accumulated_size += size;
             if accumulated_size > max_block_payload_size {
                 return Err(ValidationError::Permanent(
-                    PayloadPermanentError::PayloadTooBig {
+                    PayloadPermanentError::PayloadSizeExceeded(PayloadSizeBreakdown {
                         expected: max_block_payload_size,
                         received: accumulated_size,
-                    },
+                        non_batch: non_batch_size,
+                        sections: section_sizes,
+                        exceeded_by: builder.section(),
+                    }),
                 ));
             }
         }
+
+        if let Some(build_stats) = &batch_payload.build_stats {
+            validate_build_stats(build_stats, &section_sizes, max_block_payload_size).map_err(
+                |err| {
+                    ValidationError::Permanent(
+                        PayloadPermanentError::PayloadBuildStatsValidationError(err),
+                    )
+                },
+            )?;
+        }
+
         Ok(())
     }
+
+    fn on_payload_finalized(&self, height: Height, time: Time, payload: &Payload) {
+        // Sections still failing at or below the finalized height are never
+        // validated at it again.
+        self.transient_section_errors.lock().unwrap().retain(
+            |(section_height, section), errors| {
+                if *section_height > height {
+                    return true;
+                }
+                self.observe_section_retries(*section, "transient", *errors);
+                false
+            },
+        );
+        if !payload.is_summary() {
+            let mut pending_certification = self.pending_certification.lock().unwrap();
+            pending_certification.insert(height, (time, payload.clone()));
+            let min_height =
+                Height::from(height.get().saturating_sub(PENDING_CERTIFICATION_HEIGHTS));
+            *pending_certification = pending_certification.split_off(&min_height);
+        }
+    }
 }
 
 impl PayloadBuilderImpl {
+    fn start_timer<'a>(&self, histogram: &'a Histogram) -> DurationTimer<'a> {
+        DurationTimer {
+            histogram,
+            stopwatch: Stopwatch::start(Arc::clone(&self.clock)),
+        }
+    }
+
+    /// Returns `past_payloads` extended with the finalized payloads above the
+    /// certified height of `context` and below the lowest of `past_payloads`,
+    /// in descending height order.
+    ///
+    /// The messages of these payloads are included, but not yet in the
+    /// certified state, so the ingress section is built against them as well.
+    /// This makes including a message twice impossible even if `past_payloads`
+    /// do not reach down to the certified height, e.g. because the chain was
+    /// cut off at a catch-up package. Only used for building payloads, as
+    /// validation must only depend on inputs all replicas agree on.
+    fn in_flight_payloads(
+        &self,
+        height: Height,
+        past_payloads: &[(Height, Time, Payload)],
+        context: &ValidationContext,
+    ) -> Vec<(Height, Time, Payload)> {
+        let mut pending_certification = self.pending_certification.lock().unwrap();
+        // Payloads at or below the certified height are in the certified state.
+        *pending_certification =
+            pending_certification.split_off(&context.certified_height.increment());
+
+        let lowest_past_height = past_payloads
+            .last()
+            .map_or(height, |(past_height, _, _)| *past_height);
+        let mut payloads = past_payloads.to_vec();
+        if lowest_past_height > context.certified_height.increment() {
+            payloads.extend(
+                pending_certification
+                    .range(context.certified_height.increment()..lowest_past_height)
+                    .rev()
+                    .map(|(height, (time, payload))| (*height, *time, payload.clone())),
+            );
+        }
+        payloads
+    }
+
+    /// Validates the section of `builder`. Transient errors are returned
+    /// right away, as waiting for them to clear would hold up consensus, and
+    /// the validator retries the payload in a later round anyway. They are
+    /// counted as retries once the validation of the section completes.
+    fn validate_section(
+        &self,
+        builder: &BatchPayloadSectionBuilder,
+        height: Height,
+        batch_payload: &BatchPayload,
+        context: &ValidationContext,
+        past_payloads: &[(Height, Time, Payload)],
+        max_block_payload_size: NumBytes,
+    ) -> Result<NumBytes, PayloadValidationError> {
+        let result = builder.validate_payload(
+            height,
+            batch_payload,
+            context,
+            past_payloads,
+            max_block_payload_size,
+        );
+        let key = (height, builder.section());
+        let mut transient_section_errors = self.transient_section_errors.lock().unwrap();
+        let result_label = match &result {
+            Err(ValidationError::Transient(_)) => {
+                *transient_section_errors.entry(key).or_default() += 1;
+                return result;
+            }
+            Ok(_) => "valid",
+            Err(ValidationError::Permanent(_)) => "permanent",
+        };
+        if let Some(errors) = transient_section_errors.remove(&key) {
+            self.observe_section_retries(builder.section(), result_label, errors);
+        }
+        result
+    }
+
+    // Records `retries` of the validation of `section` that completed with
+    // `result`.
+    fn observe_section_retries(&self, section: PayloadSection, result: &str, retries: u64) {
+        self.metrics
+            .validate_payload_section_retries
+            .with_label_values(&[section.as_str(), result])
+            .inc_by(retries);
+    }
+
     /// Fetches the [`SubnetRecord`] corresponding to the registry version provided
     /// by the [`ValidationContext`]
     fn get_subnet_record(
@@ -198,9 +593,7 @@ impl PayloadBuilderImpl {
     /// checks the invariants. Emits a warning in case the invariants are not
     /// met.
     fn get_max_block_payload_size_bytes(&self, subnet_record: &SubnetRecord) -> NumBytes {
-        let required_min_size = MAX_BITCOIN_BLOCK_SIZE
-            .max(MAX_XNET_PAYLOAD_IN_BYTES.get())
-            .max(subnet_record.max_ingress_bytes_per_message);
+        let required_min_size = required_min_block_payload_size(subnet_record);
 
         let mut max_block_payload_size = subnet_record.max_block_payload_size;
         // In any case, ensure the value is bigger than inter canister payload and
@@ -218,14 +611,86 @@ impl PayloadBuilderImpl {
         NumBytes::new(max_block_payload_size)
     }
 }
+
+/// Returns the size below which the maximum block payload size of
+/// `subnet_record` is raised, as a block must fit the largest ingress message,
+/// XNet payload and Bitcoin block.
+pub(crate) fn required_min_block_payload_size(subnet_record: &SubnetRecord) -> u64 {
+    MAX_BITCOIN_BLOCK_SIZE
+        .max(MAX_XNET_PAYLOAD_IN_BYTES.get())
+        .max(subnet_record.max_ingress_bytes_per_message)
+}
+
+/// Returns the features of `subnet_record`, which determine the payload
+/// sections that block makers leave empty and the order of their contents.
+pub(crate) fn subnet_features(subnet_record: &SubnetRecord) -> SubnetFeatures {
+    subnet_record.features.clone().unwrap_or_default().into()
+}
+
+/// Checks that `build_stats` are consistent with the validated sizes of the
+/// payload sections: every section is reported at most once, with a byte
+/// limit within the block size limit and at least as many bytes included as
+/// the section holds (validated sizes may be smaller than built ones), but no
+/// more than its byte limit.
+fn validate_build_stats(
+    build_stats: &PayloadBuildStats,
+    section_sizes: &BTreeMap<PayloadSection, NumBytes>,
+    max_block_payload_size: NumBytes,
+) -> Result<(), InvalidPayloadBuildStats> {
+    let mut seen = BTreeSet::new();
+    for stats in &build_stats.sections {
+        if !seen.insert(stats.section) {
+            return Err(InvalidPayloadBuildStats::DuplicateSection(stats.section));
+        }
+        let byte_limit = NumBytes::new(stats.byte_limit);
+        if byte_limit > max_block_payload_size {
+            return Err(InvalidPayloadBuildStats::ByteLimitTooLarge {
+                section: stats.section,
+                max: max_block_payload_size,
+                byte_limit,
+            });
+        }
+        let bytes_included = NumBytes::new(stats.bytes_included);
+        let size = section_sizes
+            .get(&stats.section)
+            .copied()
+            .unwrap_or_else(|| NumBytes::new(0));
+        if bytes_included > byte_limit || bytes_included < size {
+            return Err(InvalidPayloadBuildStats::UnexpectedBytesIncluded {
+                section: stats.section,
+                byte_limit,
+                bytes_included,
+                size,
+            });
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::consensus::mocks::{dependencies, dependencies_with_subnet_params, Dependencies};
+    use crate::{
+        consensus::{
+            mocks::{dependencies, dependencies_with_subnet_params, Dependencies},
+            payload::is_section_disabled,
+        },
+        testing::faults::{Fault, FaultInjector},
+    };
+    use assert_matches::assert_matches;
     use ic_btc_types_internal::{
         BitcoinAdapterResponse, BitcoinAdapterResponseWrapper, GetSuccessorsResponse,
     };
+    use ic_config::artifact_pool::ArtifactPoolConfig;
+    use ic_interfaces::{
+        canister_http::CanisterHttpPayloadValidationError,
+        consensus::{InvalidXNetCompression, PayloadTransientError},
+        ingress_manager::{IngressPayloadValidationError, IngressSetQuery},
+        messaging::{XNetPayloadValidationError, XNetTransientValidationError},
+        self_validating_payload::SelfValidatingPayloadValidationError,
+    };
     use ic_logger::replica_logger::no_op_logger;
+    use ic_registry_subnet_features::DisabledPayloadSections;
     use ic_test_utilities::{
         canister_http::FakeCanisterHttpPayloadBuilder,
         consensus::fake::Fake,
@@ -238,19 +703,27 @@ mod test {
     };
     use ic_test_utilities_registry::SubnetRecordBuilder;
     use ic_types::{
-        canister_http::CanisterHttpResponseWithConsensus,
+        artifact::IngressMessageId,
+        batch::{CanisterHttpPayload, IngressPayload, SelfValidatingPayload, XNetPayload},
+        canister_http::{
+            CanisterHttpResponse, CanisterHttpResponseContent, CanisterHttpResponseMetadata,
+            CanisterHttpResponseWithConsensus,
+        },
         consensus::{
             certification::{Certification, CertificationContent},
             dkg::Dealings,
             BlockPayload, DataPayload, Payload,
         },
         crypto::{CryptoHash, Signed},
-        messages::SignedIngress,
-        signature::ThresholdSignature,
+        ingress::IngressSets,
+        messages::{CallbackId, SignedIngress},
+        signature::{BasicSignatureBatch, ThresholdSignature},
+        time::{ManualClock, UNIX_EPOCH},
         xnet::CertifiedStreamSlice,
-        CryptoHashOfPartialState, RegistryVersion,
+        CanisterId, CountBytes, CryptoHashOfPartialState, RegistryVersion,
     };
-    use std::collections::BTreeMap;
+    use proptest::prelude::*;
+    use std::{collections::BTreeMap, time::Duration};
     /// Builds a `PayloadBuilderImpl` wrapping fake ingress and XNet payload
     /// builders that return the supplied ingress and XNet data.
     fn make_test_payload_impl(
@@ -283,6 +756,37 @@ mod test {
         )
     }
 
+    /// Returns the record of a single node subnet with `features`.
+    fn subnet_record_with_features(features: SubnetFeatures) -> SubnetRecord {
+        let mut subnet_record = SubnetRecordBuilder::from(&[node_test_id(0)]).build();
+        subnet_record.features = Some(features.into());
+        subnet_record
+    }
+
+    /// Returns a registry holding `subnet_record` at version 1, the subnet
+    /// records to build payloads with, and a validation context at that
+    /// version.
+    fn test_subnet(
+        pool_config: ArtifactPoolConfig,
+        subnet_record: SubnetRecord,
+    ) -> (Arc<dyn RegistryClient>, SubnetRecords, ValidationContext) {
+        let subnet_records = SubnetRecords {
+            membership_version: subnet_record.clone(),
+            context_version: subnet_record.clone(),
+        };
+        let Dependencies { registry, .. } = dependencies_with_subnet_params(
+            pool_config,
+            subnet_test_id(0),
+            vec![(1, subnet_record)],
+        );
+        let context = ValidationContext {
+            certified_height: Height::from(0),
+            registry_version: RegistryVersion::from(1),
+            time: mock_time(),
+        };
+        (registry, subnet_records, context)
+    }
+
     /// Builds a `CertifiedStreamSlice` from the supplied `payload` and
     /// `merkle_proof` bytes, without a valid certification.
     fn make_certified_stream_slice(
@@ -348,7 +852,13 @@ mod test {
             };
 
             let (ingress_msgs, stream_msgs, responses_from_adapter) = payload_builder
-                .get_payload(Height::from(1), &prev_payloads, &context, &subnet_records)
+                .get_payload(
+                    Height::from(1),
+                    &test_parent_hash(),
+                    &prev_payloads,
+                    &context,
+                    &subnet_records,
+                )
                 .into_messages()
                 .unwrap();
 
@@ -390,6 +900,180 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_payload_durations_are_measured_on_the_given_clock() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let Dependencies { registry, .. } = dependencies(pool_config, 1);
+            let clock = Arc::new(ManualClock::new());
+            let payload_builder = make_test_payload_impl(registry, vec![], vec![], vec![], vec![])
+                .with_clock(clock.clone());
+            let context = ValidationContext {
+                certified_height: Height::from(0),
+                registry_version: RegistryVersion::from(1),
+                time: mock_time(),
+            };
+            let subnet_records = SubnetRecords {
+                membership_version: SubnetRecordBuilder::from(&[node_test_id(0)]).build(),
+                context_version: SubnetRecordBuilder::from(&[node_test_id(0)]).build(),
+            };
+
+            let payload = wrap_batch_payload(
+                1,
+                payload_builder.get_payload(
+                    Height::from(1),
+                    &test_parent_hash(),
+                    &[],
+                    &context,
+                    &subnet_records,
+                ),
+            );
+            clock.advance(std::time::Duration::from_secs(3));
+            payload_builder
+                .validate_payload(Height::from(1), &payload, &[], &context)
+                .unwrap();
+
+            // The clock only advanced in between, so neither duration includes
+            // the time actually spent building or validating.
+            let metrics = &payload_builder.metrics;
+            assert_eq!(metrics.get_payload_duration.get_sample_count(), 1);
+            assert_eq!(metrics.get_payload_duration.get_sample_sum(), 0.0);
+            assert_eq!(metrics.validate_payload_duration.get_sample_count(), 1);
+            assert_eq!(metrics.validate_payload_duration.get_sample_sum(), 0.0);
+        })
+    }
+
+    #[test]
+    fn test_ingress_is_built_against_payloads_pending_certification() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let Dependencies { registry, .. } = dependencies(pool_config, 1);
+            let payload_builder = make_test_payload_impl(registry, vec![], vec![], vec![], vec![]);
+            let payloads: Vec<_> = (1..=3)
+                .map(|height| {
+                    (
+                        Height::from(height),
+                        mock_time(),
+                        wrap_batch_payload(height, BatchPayload::default()),
+                    )
+                })
+                .collect();
+            for (height, time, payload) in &payloads {
+                payload_builder.on_payload_finalized(*height, *time, payload);
+            }
+            let context = |certified_height| ValidationContext {
+                certified_height: Height::from(certified_height),
+                registry_version: RegistryVersion::from(1),
+                time: mock_time(),
+            };
+            let in_flight_heights = |past_payloads: &[(Height, Time, Payload)],
+                                     certified_height| {
+                payload_builder
+                    .in_flight_payloads(Height::from(4), past_payloads, &context(certified_height))
+                    .into_iter()
+                    .map(|(height, _, _)| height.get())
+                    .collect::<Vec<_>>()
+            };
+            let past_payloads: Vec<_> = payloads.iter().rev().cloned().collect();
+
+            // Past payloads reaching down to the certified height are complete.
+            assert_eq!(in_flight_heights(&past_payloads, 0), vec![3, 2, 1]);
+            // Finalized payloads above the certified height that are missing
+            // from the past payloads are added.
+            assert_eq!(in_flight_heights(&past_payloads[..1], 0), vec![3, 2, 1]);
+            assert_eq!(in_flight_heights(&[], 1), vec![3, 2]);
+            // Certified payloads are forgotten.
+            assert_eq!(in_flight_heights(&[], 2), vec![3]);
+            assert_eq!(in_flight_heights(&[], 0), vec![3]);
+        })
+    }
+
+    #[test]
+    fn test_build_stats_are_attached_and_validated() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let Dependencies { registry, .. } = dependencies(pool_config, 1);
+            let ingress = SignedIngressBuilder::new().nonce(1).build();
+            let payload_builder =
+                make_test_payload_impl(registry, vec![vec![ingress]], vec![], vec![], vec![])
+                    .with_build_stats(Some(node_test_id(0)));
+            let context = ValidationContext {
+                certified_height: Height::from(0),
+                registry_version: RegistryVersion::from(1),
+                time: mock_time(),
+            };
+            let subnet_records = SubnetRecords {
+                membership_version: SubnetRecordBuilder::from(&[node_test_id(0)]).build(),
+                context_version: SubnetRecordBuilder::from(&[node_test_id(0)]).build(),
+            };
+            let height = Height::from(1);
+            let batch_payload = payload_builder.get_payload(
+                height,
+                &test_parent_hash(),
+                &[],
+                &context,
+                &subnet_records,
+            );
+
+            let build_stats = batch_payload.build_stats.clone().unwrap();
+            assert_eq!(build_stats.block_maker, node_test_id(0));
+            assert_eq!(build_stats.sections.len(), 5);
+            let ingress_stats = build_stats.section(PayloadSection::Ingress).unwrap();
+            assert_eq!(
+                ingress_stats.bytes_included,
+                batch_payload.ingress.count_bytes() as u64
+            );
+            assert!(ingress_stats.bytes_included > 0);
+            payload_builder
+                .validate_payload(
+                    height,
+                    &wrap_batch_payload(1, batch_payload.clone()),
+                    &[],
+                    &context,
+                )
+                .unwrap();
+
+            // Claiming less than the included bytes is rejected.
+            let mut underreported = batch_payload.clone();
+            underreported
+                .build_stats
+                .as_mut()
+                .unwrap()
+                .sections
+                .iter_mut()
+                .filter(|stats| stats.section == PayloadSection::Ingress)
+                .for_each(|stats| stats.bytes_included = 0);
+            assert_matches!(
+                payload_builder.validate_payload(
+                    height,
+                    &wrap_batch_payload(1, underreported),
+                    &[],
+                    &context
+                ),
+                Err(ValidationError::Permanent(
+                    PayloadPermanentError::PayloadBuildStatsValidationError(
+                        InvalidPayloadBuildStats::UnexpectedBytesIncluded { .. }
+                    )
+                ))
+            );
+
+            // Reporting a section twice is rejected.
+            let mut duplicated = batch_payload;
+            let stats = duplicated.build_stats.as_mut().unwrap();
+            stats.sections.push(stats.sections[0].clone());
+            assert_matches!(
+                payload_builder.validate_payload(
+                    height,
+                    &wrap_batch_payload(1, duplicated),
+                    &[],
+                    &context
+                ),
+                Err(ValidationError::Permanent(
+                    PayloadPermanentError::PayloadBuildStatsValidationError(
+                        InvalidPayloadBuildStats::DuplicateSection(_)
+                    )
+                ))
+            );
+        })
+    }
+
     /// This test executes the `get_payload` and `validate_payload` functions
     /// in `PayloadBuilderImpl`.
     /// It builds the following blocks:
@@ -411,21 +1095,7 @@ mod test {
             subnet_record.max_block_payload_size = MAX_SIZE;
             subnet_record.max_ingress_bytes_per_message = MAX_SIZE;
 
-            let subnet_records = SubnetRecords {
-                membership_version: subnet_record.clone(),
-                context_version: subnet_record.clone(),
-            };
-
-            let Dependencies { registry, .. } = dependencies_with_subnet_params(
-                pool_config,
-                subnet_test_id(0),
-                vec![(1, subnet_record)],
-            );
-            let context = ValidationContext {
-                certified_height: Height::from(0),
-                registry_version: RegistryVersion::from(1),
-                time: mock_time(),
-            };
+            let (registry, subnet_records, context) = test_subnet(pool_config, subnet_record);
 
             let certified_streams: Vec<BTreeMap<SubnetId, CertifiedStreamSlice>> = vec![
                 make_slice(0, THREE_QUARTER),
@@ -441,8 +1111,13 @@ mod test {
                 make_test_payload_impl(registry, ingress, certified_streams, vec![], vec![]);
 
             // Build first payload and then validate it
-            let payload0 =
-                payload_builder.get_payload(Height::from(0), &[], &context, &subnet_records);
+            let payload0 = payload_builder.get_payload(
+                Height::from(0),
+                &test_parent_hash(),
+                &[],
+                &context,
+                &subnet_records,
+            );
             assert_eq!(count_payload_msgs(&payload0), 2);
             let wrapped_payload0 = wrap_batch_payload(0, payload0);
 
@@ -454,6 +1129,7 @@ mod test {
             let past_payload0 = [(Height::from(0), mock_time(), wrapped_payload0)];
             let payload1 = payload_builder.get_payload(
                 Height::from(1),
+                &test_parent_hash(),
                 &past_payload0,
                 &context,
                 &subnet_records,
@@ -470,6 +1146,7 @@ mod test {
             let past_payload1 = [(Height::from(1), mock_time(), wrapped_payload1)];
             let payload2 = payload_builder.get_payload(
                 Height::from(2),
+                &test_parent_hash(),
                 &past_payload1,
                 &context,
                 &subnet_records,
@@ -483,6 +1160,970 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_disabled_sections_are_left_empty_and_rejected() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let subnet_record = subnet_record_with_features(SubnetFeatures {
+                disabled_payload_sections: Some(DisabledPayloadSections {
+                    xnet: true,
+                    ..DisabledPayloadSections::default()
+                }),
+                ..SubnetFeatures::default()
+            });
+            let (registry, subnet_records, context) = test_subnet(pool_config, subnet_record);
+            let payload_builder = make_test_payload_impl(
+                registry,
+                vec![make_ingress(0, 100)],
+                vec![make_slice(0, 100)],
+                vec![],
+                vec![],
+            );
+
+            let mut payload = payload_builder.get_payload(
+                Height::from(1),
+                &test_parent_hash(),
+                &[],
+                &context,
+                &subnet_records,
+            );
+            assert_eq!(payload.ingress.message_count(), 1);
+            assert!(payload.xnet.stream_slices.is_empty());
+            payload_builder
+                .validate_payload(
+                    Height::from(1),
+                    &wrap_batch_payload(1, payload.clone()),
+                    &[],
+                    &context,
+                )
+                .unwrap();
+
+            payload.xnet.stream_slices = make_slice(0, 100);
+            assert_matches!(
+                payload_builder.validate_payload(
+                    Height::from(1),
+                    &wrap_batch_payload(1, payload),
+                    &[],
+                    &context,
+                ),
+                Err(ValidationError::Permanent(
+                    PayloadPermanentError::SectionDisabled(PayloadSection::XNet)
+                ))
+            );
+        });
+    }
+
+    #[test]
+    fn test_oversize_payloads_are_broken_down_by_section() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let mut subnet_record = SubnetRecordBuilder::from(&[node_test_id(0)]).build();
+            subnet_record.max_ingress_bytes_per_message = subnet_record.max_block_payload_size;
+            let Dependencies { registry, .. } = dependencies_with_subnet_params(
+                pool_config,
+                subnet_test_id(0),
+                vec![(1, subnet_record.clone())],
+            );
+            let context = ValidationContext {
+                certified_height: Height::from(0),
+                registry_version: RegistryVersion::from(1),
+                time: mock_time(),
+            };
+            let payload_builder = make_test_payload_impl(registry, vec![], vec![], vec![], vec![]);
+            let max_size = payload_builder
+                .get_max_block_payload_size_bytes(&subnet_record)
+                .get();
+
+            // Each section fits, but not both of them.
+            let xnet_size = max_size as usize * 2 / 3;
+            let payload = BatchPayload {
+                ingress: IngressPayload::from(make_ingress(0, max_size as usize / 2)),
+                xnet: XNetPayload {
+                    stream_slices: make_slice(0, xnet_size),
+                },
+                ..BatchPayload::default()
+            };
+            let ingress_size = NumBytes::new(payload.ingress.count_bytes() as u64);
+            let breakdown = match payload_builder.validate_payload(
+                Height::from(1),
+                &wrap_batch_payload(1, payload),
+                &[],
+                &context,
+            ) {
+                Err(ValidationError::Permanent(PayloadPermanentError::PayloadSizeExceeded(
+                    breakdown,
+                ))) => breakdown,
+                result => panic!("Expected PayloadSizeExceeded, got {:?}", result),
+            };
+            assert_eq!(breakdown.expected, NumBytes::new(max_size));
+            assert_eq!(breakdown.exceeded_by, PayloadSection::XNet);
+            assert_eq!(
+                breakdown.sections.get(&PayloadSection::XNet),
+                Some(&NumBytes::new(xnet_size as u64))
+            );
+            assert_eq!(
+                breakdown.sections.get(&PayloadSection::Ingress),
+                Some(&ingress_size)
+            );
+            assert_eq!(
+                breakdown.received,
+                breakdown
+                    .sections
+                    .values()
+                    .fold(breakdown.non_batch, |total, size| total + *size)
+            );
+        });
+    }
+
+    #[test]
+    fn test_reserved_bytes_are_left_to_non_batch_parts() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let subnet_record = subnet_record_with_features(SubnetFeatures {
+                count_non_batch_payload_size: true,
+                ..SubnetFeatures::default()
+            });
+            let (registry, subnet_records, context) = test_subnet(pool_config, subnet_record);
+            let payload_builder = make_test_payload_impl(
+                registry,
+                vec![make_ingress(0, 1000), make_ingress(1, 1000)],
+                vec![],
+                vec![],
+                vec![],
+            )
+            .with_build_stats(Some(node_test_id(0)));
+            let max_size =
+                payload_builder.get_max_block_payload_size_bytes(&subnet_records.context_version);
+
+            let payload = payload_builder.get_payload_with_reserved_bytes(
+                Height::from(1),
+                &test_parent_hash(),
+                &[],
+                &context,
+                &subnet_records,
+                max_size - NumBytes::new(100),
+            );
+            assert_eq!(payload.ingress.message_count(), 0);
+            let build_stats = payload.build_stats.as_ref().unwrap();
+            assert!(build_stats
+                .sections
+                .iter()
+                .all(|stats| stats.byte_limit <= 100));
+
+            let payload = payload_builder.get_payload_with_reserved_bytes(
+                Height::from(1),
+                &test_parent_hash(),
+                &[],
+                &context,
+                &subnet_records,
+                NumBytes::new(0),
+            );
+            assert_eq!(payload.ingress.message_count(), 1);
+            let wrapped_payload = wrap_batch_payload(1, payload);
+            let data_payload = wrapped_payload.as_ref().as_data();
+            assert!(non_batch_payload_size(&data_payload.dealings, &data_payload.ecdsa).get() > 0);
+            payload_builder
+                .validate_payload(Height::from(1), &wrapped_payload, &[], &context)
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_non_batch_parts_never_exceed_the_limit() {
+        let max_size = NumBytes::new(1000);
+        let enabled = SubnetFeatures {
+            count_non_batch_payload_size: true,
+            ..SubnetFeatures::default()
+        };
+        assert_eq!(
+            counted_non_batch_payload_size(&enabled, NumBytes::new(10), max_size),
+            NumBytes::new(10)
+        );
+        // Dealings and an ECDSA payload beyond the limit leave no bytes to the
+        // batch payload, but an empty one still fits.
+        assert_eq!(
+            counted_non_batch_payload_size(&enabled, NumBytes::new(5000), max_size),
+            max_size
+        );
+        assert_eq!(
+            counted_non_batch_payload_size(
+                &SubnetFeatures::default(),
+                NumBytes::new(5000),
+                max_size
+            ),
+            NumBytes::new(0)
+        );
+    }
+
+    #[test]
+    fn test_reserved_bytes_beyond_the_limit_leave_an_empty_valid_payload() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let subnet_record = subnet_record_with_features(SubnetFeatures {
+                count_non_batch_payload_size: true,
+                ..SubnetFeatures::default()
+            });
+            let (registry, subnet_records, context) = test_subnet(pool_config, subnet_record);
+            let payload_builder = make_test_payload_impl(
+                registry,
+                vec![make_ingress(0, 1000)],
+                vec![],
+                vec![],
+                vec![],
+            );
+            let max_size =
+                payload_builder.get_max_block_payload_size_bytes(&subnet_records.context_version);
+
+            let payload = payload_builder.get_payload_with_reserved_bytes(
+                Height::from(1),
+                &test_parent_hash(),
+                &[],
+                &context,
+                &subnet_records,
+                max_size + NumBytes::new(1),
+            );
+            assert_eq!(payload.ingress.message_count(), 0);
+            payload_builder
+                .validate_payload(
+                    Height::from(1),
+                    &wrap_batch_payload(1, payload),
+                    &[],
+                    &context,
+                )
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_byte_budget_is_recorded() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let subnet_record = subnet_record_with_features(SubnetFeatures {
+                count_non_batch_payload_size: true,
+                ..SubnetFeatures::default()
+            });
+            let (registry, subnet_records, context) = test_subnet(pool_config, subnet_record);
+            let payload_builder = make_test_payload_impl(
+                registry,
+                vec![make_ingress(0, 1000)],
+                vec![],
+                vec![],
+                vec![],
+            )
+            .with_build_stats(Some(node_test_id(0)));
+            let max_size =
+                payload_builder.get_max_block_payload_size_bytes(&subnet_records.context_version);
+
+            let payload = payload_builder.get_payload_with_reserved_bytes(
+                Height::from(1),
+                &test_parent_hash(),
+                &[],
+                &context,
+                &subnet_records,
+                NumBytes::new(100),
+            );
+            let ingress_size = payload.ingress.count_bytes() as u64;
+            assert!(ingress_size > 0);
+
+            let metrics = &payload_builder.metrics;
+            let section_bytes = |section: PayloadSection| {
+                metrics
+                    .section_bytes_included
+                    .with_label_values(&[section.as_str()])
+                    .get()
+            };
+            assert_eq!(section_bytes(PayloadSection::Ingress), ingress_size);
+            assert_eq!(section_bytes(PayloadSection::XNet), 0);
+            let sections_size: u64 = [
+                PayloadSection::Ingress,
+                PayloadSection::XNet,
+                PayloadSection::SelfValidating,
+                PayloadSection::CanisterHttp,
+                PayloadSection::Canary,
+            ]
+            .iter()
+            .map(|section| section_bytes(*section))
+            .sum();
+            // The reserved bytes count against the budget too.
+            assert_eq!(metrics.accumulated_size.get() as u64, 100 + sections_size);
+            assert_eq!(metrics.max_block_payload_size.get(), max_size.get() as i64);
+            assert_eq!(metrics.byte_budget_utilization.get_sample_count(), 1);
+            assert_eq!(
+                metrics.byte_budget_utilization.get_sample_sum(),
+                metrics.accumulated_size.get() as f64 / max_size.get() as f64
+            );
+        });
+    }
+
+    #[test]
+    fn test_ingress_expiries_are_recorded() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let subnet_record = SubnetRecordBuilder::from(&[node_test_id(0)]).build();
+            let (registry, subnet_records, context) = test_subnet(pool_config, subnet_record);
+            let ingress = |nonce, expiry_seconds| {
+                SignedIngressBuilder::new()
+                    .nonce(nonce)
+                    .expiry_time(mock_time() + Duration::from_secs(expiry_seconds))
+                    .build()
+            };
+            let payload_builder = make_test_payload_impl(
+                registry,
+                vec![vec![ingress(0, 20), ingress(1, 240)]],
+                vec![],
+                vec![],
+                vec![],
+            );
+
+            let payload = payload_builder.get_payload(
+                Height::from(1),
+                &test_parent_hash(),
+                &[],
+                &context,
+                &subnet_records,
+            );
+            assert_eq!(payload.ingress.message_count(), 2);
+
+            let metrics = &payload_builder.metrics;
+            assert_eq!(metrics.ingress_expiry_margin.get_sample_count(), 2);
+            assert_eq!(metrics.ingress_expiry_margin.get_sample_sum(), 260.0);
+            assert_eq!(metrics.ingress_expiry_margin_min.get(), 20.0);
+            assert_eq!(metrics.ingress_expiry_margin_max.get(), 240.0);
+        });
+    }
+
+    #[test]
+    fn test_sections_are_canonically_ordered_if_required() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let subnet_record = subnet_record_with_features(SubnetFeatures {
+                canonical_payload_order: true,
+                ..SubnetFeatures::default()
+            });
+            let (registry, subnet_records, context) = test_subnet(pool_config, subnet_record);
+            // Messages expiring later are selected first.
+            let messages: Vec<_> = (0..3)
+                .map(|i| {
+                    SignedIngressBuilder::new()
+                        .nonce(i)
+                        .expiry_time(mock_time() + std::time::Duration::from_secs(60 - i))
+                        .build()
+                })
+                .collect();
+            let payload_builder =
+                make_test_payload_impl(registry, vec![messages.clone()], vec![], vec![], vec![]);
+
+            let mut payload = payload_builder.get_payload(
+                Height::from(1),
+                &test_parent_hash(),
+                &[],
+                &context,
+                &subnet_records,
+            );
+            assert_eq!(payload.ingress.message_count(), 3);
+            assert!(payload.ingress.is_canonically_ordered());
+            payload_builder
+                .validate_payload(
+                    Height::from(1),
+                    &wrap_batch_payload(1, payload.clone()),
+                    &[],
+                    &context,
+                )
+                .unwrap();
+
+            payload.ingress = IngressPayload::from(messages);
+            assert_matches!(
+                payload_builder.validate_payload(
+                    Height::from(1),
+                    &wrap_batch_payload(1, payload),
+                    &[],
+                    &context,
+                ),
+                Err(ValidationError::Permanent(
+                    PayloadPermanentError::NonCanonicalOrder(PayloadSection::Ingress)
+                ))
+            );
+        });
+    }
+
+    #[test]
+    fn test_xnet_section_is_compressed_if_enabled() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let disabled_record = SubnetRecordBuilder::from(&[node_test_id(0)]).build();
+            let mut enabled_record = disabled_record.clone();
+            enabled_record.features = Some(
+                SubnetFeatures {
+                    xnet_compression: true,
+                    ..SubnetFeatures::default()
+                }
+                .into(),
+            );
+            let subnet_records = SubnetRecords {
+                membership_version: enabled_record.clone(),
+                context_version: enabled_record.clone(),
+            };
+            // Compression is enabled at registry version 1 and disabled at 2.
+            let Dependencies { registry, .. } = dependencies_with_subnet_params(
+                pool_config,
+                subnet_test_id(0),
+                vec![(1, enabled_record), (2, disabled_record)],
+            );
+            let context = ValidationContext {
+                certified_height: Height::from(0),
+                registry_version: RegistryVersion::from(1),
+                time: mock_time(),
+            };
+            let payload_builder =
+                make_test_payload_impl(registry, vec![], vec![make_slice(0, 1000)], vec![], vec![]);
+
+            let mut payload = payload_builder.get_payload(
+                Height::from(1),
+                &test_parent_hash(),
+                &[],
+                &context,
+                &subnet_records,
+            );
+            assert!(is_compressed(&payload.xnet));
+            let uncompressed = XNetPayload {
+                stream_slices: make_slice(0, 1000),
+            };
+            assert!(payload.xnet.count_bytes() < uncompressed.count_bytes());
+            payload_builder
+                .validate_payload(
+                    Height::from(1),
+                    &wrap_batch_payload(1, payload.clone()),
+                    &[],
+                    &context,
+                )
+                .unwrap();
+
+            let disabled_context = ValidationContext {
+                registry_version: RegistryVersion::from(2),
+                ..context.clone()
+            };
+            assert_matches!(
+                payload_builder.validate_payload(
+                    Height::from(1),
+                    &wrap_batch_payload(1, payload.clone()),
+                    &[],
+                    &disabled_context,
+                ),
+                Err(ValidationError::Permanent(
+                    PayloadPermanentError::InvalidXNetCompression(
+                        InvalidXNetCompression::NotEnabled
+                    )
+                ))
+            );
+
+            let slice = payload
+                .xnet
+                .stream_slices
+                .get_mut(&subnet_test_id(1))
+                .unwrap();
+            slice.payload.truncate(8);
+            assert_matches!(
+                payload_builder.validate_payload(
+                    Height::from(1),
+                    &wrap_batch_payload(1, payload),
+                    &[],
+                    &context,
+                ),
+                Err(ValidationError::Permanent(
+                    PayloadPermanentError::InvalidXNetCompression(
+                        InvalidXNetCompression::Malformed { .. }
+                    )
+                ))
+            );
+        });
+    }
+
+    /// An XNet payload builder whose validation fails with a transient error
+    /// the given number of times before passing.
+    struct FlakyXNetPayloadBuilder(std::sync::atomic::AtomicU32);
+
+    impl XNetPayloadBuilder for FlakyXNetPayloadBuilder {
+        fn get_xnet_payload(
+            &self,
+            _validation_context: &ValidationContext,
+            _past_payloads: &[&XNetPayload],
+            _byte_limit: NumBytes,
+        ) -> XNetPayload {
+            XNetPayload::default()
+        }
+
+        fn validate_xnet_payload(
+            &self,
+            _payload: &XNetPayload,
+            _validation_context: &ValidationContext,
+            _past_payloads: &[&XNetPayload],
+        ) -> Result<NumBytes, XNetPayloadValidationError> {
+            use std::sync::atomic::Ordering;
+            if self.0.load(Ordering::SeqCst) == 0 {
+                return Ok(NumBytes::new(0));
+            }
+            self.0.fetch_sub(1, Ordering::SeqCst);
+            Err(ValidationError::Transient(
+                XNetTransientValidationError::StateNotCommittedYet(Height::from(0)),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_transient_section_errors_are_retried_in_later_rounds() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let Dependencies { registry, .. } = dependencies(pool_config, 1);
+            let context = ValidationContext {
+                certified_height: Height::from(0),
+                registry_version: RegistryVersion::from(1),
+                time: mock_time(),
+            };
+            let make_payload_builder = |failures: u32| {
+                PayloadBuilderImpl::new(
+                    subnet_test_id(0),
+                    Arc::clone(&registry),
+                    Arc::new(FakeIngressSelector::new()),
+                    Arc::new(FlakyXNetPayloadBuilder(failures.into())),
+                    Arc::new(FakeSelfValidatingPayloadBuilder::new()),
+                    Arc::new(FakeCanisterHttpPayloadBuilder::new()),
+                    MetricsRegistry::new(),
+                    no_op_logger(),
+                )
+            };
+            let payload = wrap_batch_payload(1, BatchPayload::default());
+            let retries = |payload_builder: &PayloadBuilderImpl, result| {
+                payload_builder
+                    .metrics
+                    .validate_payload_section_retries
+                    .with_label_values(&["XNet", result])
+                    .get()
+            };
+
+            // Transient errors are returned right away, and counted as
+            // retries once the section is valid.
+            let payload_builder = make_payload_builder(2);
+            for _ in 0..2 {
+                assert_matches!(
+                    payload_builder.validate_payload(Height::from(1), &payload, &[], &context),
+                    Err(ValidationError::Transient(
+                        PayloadTransientError::XNetPayloadValidationError(_)
+                    ))
+                );
+            }
+            assert_eq!(retries(&payload_builder, "valid"), 0);
+            assert_matches!(
+                payload_builder.validate_payload(Height::from(1), &payload, &[], &context),
+                Ok(())
+            );
+            assert_eq!(retries(&payload_builder, "valid"), 2);
+
+            // Sections still failing when their height is finalized are
+            // counted as transient.
+            let payload_builder = make_payload_builder(3);
+            assert_matches!(
+                payload_builder.validate_payload(Height::from(1), &payload, &[], &context),
+                Err(ValidationError::Transient(_))
+            );
+            payload_builder.on_payload_finalized(Height::from(2), mock_time(), &payload);
+            assert_eq!(retries(&payload_builder, "transient"), 1);
+            assert!(payload_builder
+                .transient_section_errors
+                .lock()
+                .unwrap()
+                .is_empty());
+        });
+    }
+
+    #[test]
+    fn test_oversize_sections_are_dropped_from_the_payload() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let (registry, subnet_records, context) = test_subnet(
+                pool_config,
+                SubnetRecordBuilder::from(&[node_test_id(0)]).build(),
+            );
+            let faults = FaultInjector::new();
+            let ingress_selector = Arc::new(FakeIngressSelector::new());
+            let payload_builder = PayloadBuilderImpl::new(
+                subnet_test_id(0),
+                registry,
+                faults.wrap(Arc::clone(&ingress_selector)),
+                faults.wrap(Arc::new(FakeXNetPayloadBuilder::new())),
+                faults.wrap(Arc::new(FakeSelfValidatingPayloadBuilder::new())),
+                faults.wrap(Arc::new(FakeCanisterHttpPayloadBuilder::new())),
+                MetricsRegistry::new(),
+                no_op_logger(),
+            );
+            ingress_selector.enqueue(make_ingress(0, 1024));
+            // The XNet section is not checked against its byte limit once
+            // built, its builder is trusted to respect it.
+            let sections = [
+                PayloadSection::Ingress,
+                PayloadSection::SelfValidating,
+                PayloadSection::CanisterHttp,
+            ];
+            for section in sections {
+                faults.inject(section, Fault::Oversize);
+            }
+
+            let batch = payload_builder.get_payload(
+                Height::from(1),
+                &test_parent_hash(),
+                &[],
+                &context,
+                &subnet_records,
+            );
+            assert!(faults.is_exhausted());
+            assert_eq!(
+                payload_builder
+                    .metrics
+                    .cricital_error_payload_too_large
+                    .get(),
+                sections.len() as u64
+            );
+            assert_eq!(count_payload_msgs(&batch), 0);
+            assert_matches!(
+                payload_builder.validate_payload(
+                    Height::from(1),
+                    &wrap_batch_payload(1, batch),
+                    &[],
+                    &context
+                ),
+                Ok(())
+            );
+        });
+    }
+
+    const NUM_SECTIONS: usize = 5;
+    const FAIR_SHARE_TOLERANCE: f64 = 0.075;
+    const NUM_HEIGHTS: usize = 1000;
+
+    // The sections that `DemandingSections` fill, i.e. all but the canary
+    // section, which is empty without the `canary_payload_bytes` feature.
+    const DEMANDING_SECTIONS: [PayloadSection; 4] = [
+        PayloadSection::Ingress,
+        PayloadSection::XNet,
+        PayloadSection::SelfValidating,
+        PayloadSection::CanisterHttp,
+    ];
+
+    // A parent hash for the block at `height`, so that the order of the
+    // sections differs from height to height.
+    fn parent_hash(height: u64) -> CryptoHashOf<Block> {
+        CryptoHashOf::from(CryptoHash(height.to_be_bytes().repeat(4)))
+    }
+
+    fn test_parent_hash() -> CryptoHashOf<Block> {
+        parent_hash(0)
+    }
+
+    // Section builders filling their sections with the bytes they demand at
+    // the height being built, as far as their byte limits allow.
+    struct DemandingSections {
+        demands: Mutex<BTreeMap<PayloadSection, u64>>,
+        // The message the ingress section is filled with, as building
+        // messages is slow. The section takes as many copies as fit, so the
+        // message is small enough for the rounding not to skew its share.
+        message: SignedIngress,
+    }
+
+    impl DemandingSections {
+        fn new() -> Self {
+            Self {
+                demands: Mutex::new(BTreeMap::new()),
+                message: SignedIngressBuilder::new()
+                    .method_payload(vec![0; 4 * 1024])
+                    .build(),
+            }
+        }
+
+        // Sets the demands of the sections at the next height.
+        fn demand(&self, demands: BTreeMap<PayloadSection, u64>) {
+            *self.demands.lock().unwrap() = demands;
+        }
+
+        // The bytes `section` fills within `byte_limit`.
+        fn take(&self, section: PayloadSection, byte_limit: NumBytes) -> usize {
+            let demand = self.demands.lock().unwrap().get(&section).copied();
+            demand.unwrap_or_default().min(byte_limit.get()) as usize
+        }
+    }
+
+    impl IngressSelector for DemandingSections {
+        fn get_ingress_payload(
+            &self,
+            _past_payloads: &dyn IngressSetQuery,
+            _context: &ValidationContext,
+            byte_limit: NumBytes,
+        ) -> IngressPayload {
+            let bytes = self.take(PayloadSection::Ingress, byte_limit);
+            let message_bytes = IngressPayload::from(vec![self.message.clone()]).count_bytes();
+            vec![self.message.clone(); bytes / message_bytes].into()
+        }
+
+        fn validate_ingress_payload(
+            &self,
+            _payload: &IngressPayload,
+            _past_payloads: &dyn IngressSetQuery,
+            _context: &ValidationContext,
+        ) -> ValidationResult<IngressPayloadValidationError> {
+            Ok(())
+        }
+
+        fn filter_past_payloads(
+            &self,
+            _past_payloads: &[(Height, Time, Payload)],
+            _context: &ValidationContext,
+        ) -> IngressSets {
+            IngressSets::new(vec![], UNIX_EPOCH)
+        }
+
+        fn request_purge_finalized_messages(&self, _message_ids: Vec<IngressMessageId>) {}
+    }
+
+    impl XNetPayloadBuilder for DemandingSections {
+        fn get_xnet_payload(
+            &self,
+            _validation_context: &ValidationContext,
+            _past_payloads: &[&XNetPayload],
+            byte_limit: NumBytes,
+        ) -> XNetPayload {
+            let bytes = self.take(PayloadSection::XNet, byte_limit);
+            let mut slice = make_certified_stream_slice(1, vec![], vec![]);
+            let certification_bytes = slice.certification.count_bytes();
+            if bytes <= certification_bytes {
+                return XNetPayload::default();
+            }
+            slice.payload = vec![0; bytes - certification_bytes];
+            XNetPayload {
+                stream_slices: vec![(subnet_test_id(1), slice)].into_iter().collect(),
+            }
+        }
+
+        fn validate_xnet_payload(
+            &self,
+            payload: &XNetPayload,
+            _validation_context: &ValidationContext,
+            _past_payloads: &[&XNetPayload],
+        ) -> Result<NumBytes, XNetPayloadValidationError> {
+            Ok(NumBytes::new(payload.count_bytes() as u64))
+        }
+    }
+
+    impl SelfValidatingPayloadBuilder for DemandingSections {
+        // The size is reported rather than derived from the payload.
+        fn get_self_validating_payload(
+            &self,
+            _validation_context: &ValidationContext,
+            _past_payloads: &[&SelfValidatingPayload],
+            byte_limit: NumBytes,
+        ) -> (SelfValidatingPayload, NumBytes) {
+            let bytes = self.take(PayloadSection::SelfValidating, byte_limit);
+            (
+                SelfValidatingPayload::default(),
+                NumBytes::new(bytes as u64),
+            )
+        }
+
+        fn validate_self_validating_payload(
+            &self,
+            _payload: &SelfValidatingPayload,
+            _validation_context: &ValidationContext,
+            _past_payloads: &[&SelfValidatingPayload],
+        ) -> Result<NumBytes, SelfValidatingPayloadValidationError> {
+            Ok(NumBytes::new(0))
+        }
+    }
+
+    impl CanisterHttpPayloadBuilder for DemandingSections {
+        fn get_canister_http_payload(
+            &self,
+            _height: Height,
+            _validation_context: &ValidationContext,
+            _past_payloads: &[&CanisterHttpPayload],
+            byte_limit: NumBytes,
+        ) -> CanisterHttpPayload {
+            let bytes = self.take(PayloadSection::CanisterHttp, byte_limit);
+            let mut response = make_canister_http_response(vec![]);
+            let overhead_bytes = response.count_bytes();
+            let responses = if bytes > overhead_bytes {
+                response.content.content =
+                    CanisterHttpResponseContent::Success(vec![0; bytes - overhead_bytes]);
+                vec![response]
+            } else {
+                vec![]
+            };
+            CanisterHttpPayload {
+                responses,
+                timeouts: vec![],
+            }
+        }
+
+        fn validate_canister_http_payload(
+            &self,
+            _height: Height,
+            payload: &CanisterHttpPayload,
+            _validation_context: &ValidationContext,
+            _past_payloads: &[&CanisterHttpPayload],
+        ) -> Result<NumBytes, CanisterHttpPayloadValidationError> {
+            Ok(NumBytes::new(payload.count_bytes() as u64))
+        }
+    }
+
+    /// Mock up a successful [`CanisterHttpResponseWithConsensus`] with the
+    /// supplied `body` and no signatures.
+    fn make_canister_http_response(body: Vec<u8>) -> CanisterHttpResponseWithConsensus {
+        let content = CanisterHttpResponse {
+            id: CallbackId::from(0),
+            timeout: mock_time(),
+            canister_id: CanisterId::from_u64(0),
+            content: CanisterHttpResponseContent::Success(body),
+        };
+        CanisterHttpResponseWithConsensus {
+            proof: Signed {
+                content: CanisterHttpResponseMetadata {
+                    id: content.id,
+                    timeout: content.timeout,
+                    content_hash: CryptoHashOf::from(CryptoHash(vec![])),
+                    registry_version: RegistryVersion::from(1),
+                },
+                signature: BasicSignatureBatch {
+                    signatures_map: BTreeMap::new(),
+                },
+            },
+            content,
+        }
+    }
+
+    // Builds payloads at the heights starting at `first_height`, one per chunk
+    // of `demands`, with the sections in `disabled` disabled. The demands are
+    // in percent of the byte budget, in the order of `DEMANDING_SECTIONS`.
+    // Returns the bytes included in each enabled section, as counted by the
+    // payload builder.
+    fn built_section_bytes(
+        disabled: DisabledPayloadSections,
+        first_height: u64,
+        demands: &[u64],
+    ) -> BTreeMap<PayloadSection, u64> {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let subnet_record = subnet_record_with_features(SubnetFeatures {
+                disabled_payload_sections: Some(disabled),
+                ..SubnetFeatures::default()
+            });
+            let (registry, subnet_records, context) = test_subnet(pool_config, subnet_record);
+            let sections = Arc::new(DemandingSections::new());
+            let payload_builder = PayloadBuilderImpl::new(
+                subnet_test_id(0),
+                registry,
+                sections.clone(),
+                sections.clone(),
+                sections.clone(),
+                sections.clone(),
+                MetricsRegistry::new(),
+                no_op_logger(),
+            );
+            let byte_budget = payload_builder
+                .get_max_block_payload_size_bytes(&subnet_records.context_version)
+                .get();
+            for (height, demands) in (first_height..).zip(demands.chunks(DEMANDING_SECTIONS.len()))
+            {
+                sections.demand(
+                    DEMANDING_SECTIONS
+                        .iter()
+                        .zip(demands)
+                        .map(|(section, percent)| (*section, byte_budget * percent / 100))
+                        .collect(),
+                );
+                payload_builder.get_payload(
+                    Height::from(height),
+                    &parent_hash(height),
+                    &[],
+                    &context,
+                    &subnet_records,
+                );
+            }
+            DEMANDING_SECTIONS
+                .iter()
+                .filter(|section| !is_section_disabled(**section, &disabled))
+                .map(|section| {
+                    let bytes = payload_builder
+                        .metrics
+                        .section_bytes_included
+                        .with_label_values(&[section.as_str()])
+                        .get();
+                    (*section, bytes)
+                })
+                .collect()
+        })
+    }
+
+    // Asserts that each section got its fair share of `bytes` in total.
+    fn assert_fair_shares(bytes: &BTreeMap<PayloadSection, u64>) {
+        let total: u64 = bytes.values().sum();
+        let fair_share = 1.0 / bytes.len() as f64;
+        for (section, taken) in bytes {
+            let share = *taken as f64 / total as f64;
+            assert!(
+                (share - fair_share).abs() <= FAIR_SHARE_TOLERANCE,
+                "Section {:?} got a share of {}, its fair share is {}",
+                section,
+                share,
+                fair_share
+            );
+        }
+    }
+
+    #[test]
+    fn test_saturated_sections_get_equal_shares() {
+        for disabled in [
+            DisabledPayloadSections::default(),
+            DisabledPayloadSections {
+                xnet: true,
+                ..DisabledPayloadSections::default()
+            },
+            DisabledPayloadSections {
+                bitcoin: true,
+                canister_http: true,
+                xnet: true,
+            },
+        ] {
+            let demands = vec![100; DEMANDING_SECTIONS.len() * NUM_HEIGHTS];
+            assert_fair_shares(&built_section_bytes(disabled, 7, &demands));
+        }
+    }
+
+    #[test]
+    fn test_section_order_is_determined_by_the_parent_hash() {
+        let sections: Vec<usize> = (0..NUM_SECTIONS).collect();
+        let mut successors: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+        for height in 0..100 {
+            let order = section_order(&parent_hash(height), sections.clone());
+            assert_eq!(order, section_order(&parent_hash(height), sections.clone()));
+            assert_eq!(
+                order.iter().copied().collect::<BTreeSet<_>>().len(),
+                NUM_SECTIONS
+            );
+            for pair in order.windows(2) {
+                successors.entry(pair[0]).or_default().insert(pair[1]);
+            }
+        }
+        // Unlike in a rotation, sections are followed by any other section.
+        for section_id in sections {
+            assert_eq!(successors[&section_id].len(), NUM_SECTIONS - 1);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(8))]
+
+        // Over a thousand heights, sections with the same demand get the same
+        // share of the byte budget, however many sections are enabled.
+        #[test]
+        fn sections_get_fair_shares_of_the_byte_budget(
+            (bitcoin, canister_http, xnet) in any::<(bool, bool, bool)>(),
+            first_height in 0..1_000_000u64,
+            demands in prop::collection::vec(0..=200u64, DEMANDING_SECTIONS.len() * NUM_HEIGHTS),
+        ) {
+            let disabled = DisabledPayloadSections {
+                bitcoin,
+                canister_http,
+                xnet,
+            };
+            assert_fair_shares(&built_section_bytes(disabled, first_height, &demands));
+        }
+    }
+
     /// Mock up a map of [`CertifiedStreamSlice`] of specified size
     fn make_slice(height: u64, size: usize) -> BTreeMap<SubnetId, CertifiedStreamSlice> {
         let mut map = BTreeMap::new();
@@ -0,0 +1,305 @@
+//! Simulation of the acceptance of payloads under a prospective subnet record.
+//!
+//! Changes to the subnet record, e.g. lowering the maximum block payload size
+//! or the maximum number of ingress messages per block, can make payloads of
+//! the current traffic invalid, stalling the subnet until the change is
+//! reverted. [`simulate_payload_acceptance`] checks recent payloads against
+//! the limits and features of a prospective subnet record, so that tooling
+//! preparing NNS proposals can preflight such changes.
+//!
+//! Only the checks that depend on the subnet record are simulated, sharing
+//! their implementation with the payload builder where they match. The
+//! contents of the payloads are not validated again, as they were validated
+//! against the state at the time and would not be valid against the current
+//! one anymore.
+
+use crate::consensus::{
+    payload::{check_section_features, is_section_disabled},
+    payload_builder::{
+        counted_non_batch_payload_size, non_batch_payload_size, required_min_block_payload_size,
+        subnet_features,
+    },
+    xnet_compression::decompress_enabled_xnet_payload,
+};
+use ic_interfaces::consensus::PayloadPermanentError;
+use ic_protobuf::registry::subnet::v1::SubnetRecord;
+use ic_types::{batch::PayloadSection, consensus::Payload, CountBytes, Height, NumBytes};
+use std::collections::BTreeMap;
+
+/// The sections of the batch payload, in the order they are validated.
+const SECTIONS: [PayloadSection; 5] = [
+    PayloadSection::Ingress,
+    PayloadSection::SelfValidating,
+    PayloadSection::XNet,
+    PayloadSection::CanisterHttp,
+    PayloadSection::Canary,
+];
+
+/// The reason for a payload to be invalid under a prospective subnet record.
+#[derive(Debug)]
+pub enum PayloadRejection {
+    /// The payload exceeds the maximum block payload size.
+    SizeExceeded { size: NumBytes, max: NumBytes },
+    /// The payload fails a check of the payload builder against the features
+    /// of the subnet: a section is disabled but not empty, or not in
+    /// canonical order although required, or the XNet section is compressed
+    /// but compression is not enabled, or it decompresses to more than the
+    /// maximum block payload size.
+    Invalid(PayloadPermanentError),
+    /// The ingress section holds more messages than allowed per block.
+    TooManyIngressMessages { count: usize, max: u64 },
+    /// The ingress message at `index` is larger than allowed.
+    IngressMessageTooLarge { index: usize, size: usize, max: u64 },
+    /// The ingress message at `index` can't be decoded.
+    MalformedIngressMessage { index: usize },
+}
+
+/// The outcome of simulating the acceptance of payloads.
+#[derive(Debug, Default)]
+pub struct PayloadAcceptanceReport {
+    /// The number of data payloads that would still be valid.
+    pub accepted: usize,
+    /// The data payloads that would be invalid, by height.
+    pub rejected: BTreeMap<Height, PayloadRejection>,
+    /// The maximum block payload size in effect under the subnet record.
+    pub max_block_payload_size: NumBytes,
+    /// True if the maximum block payload size of the subnet record is below
+    /// the required minimum, and would be raised to it.
+    pub max_block_payload_size_raised: bool,
+    /// The size of the largest data payload, to tell how close traffic comes
+    /// to the maximum block payload size.
+    pub max_payload_size: NumBytes,
+}
+
+impl PayloadAcceptanceReport {
+    /// Returns true if all payloads would still be valid.
+    pub fn all_accepted(&self) -> bool {
+        self.rejected.is_empty()
+    }
+}
+
+/// Reports which of `payloads`, e.g. the payloads finalized recently, would
+/// still be valid under `subnet_record`. Summary payloads are skipped.
+pub fn simulate_payload_acceptance(
+    subnet_record: &SubnetRecord,
+    payloads: &[(Height, Payload)],
+) -> PayloadAcceptanceReport {
+    let required_min_size = required_min_block_payload_size(subnet_record);
+    let max_block_payload_size =
+        NumBytes::new(subnet_record.max_block_payload_size.max(required_min_size));
+    let mut report = PayloadAcceptanceReport {
+        max_block_payload_size,
+        max_block_payload_size_raised: subnet_record.max_block_payload_size < required_min_size,
+        ..PayloadAcceptanceReport::default()
+    };
+    for (height, payload) in payloads {
+        if payload.is_summary() {
+            continue;
+        }
+        match check_payload(subnet_record, max_block_payload_size, payload) {
+            Ok(size) => {
+                report.accepted += 1;
+                report.max_payload_size = report.max_payload_size.max(size);
+            }
+            Err(rejection) => {
+                if let PayloadRejection::SizeExceeded { size, .. } = rejection {
+                    report.max_payload_size = report.max_payload_size.max(size);
+                }
+                report.rejected.insert(*height, rejection);
+            }
+        }
+    }
+    report
+}
+
+// Checks the data `payload` like `PayloadBuilderImpl::validate_payload`, but
+// only as far as `subnet_record` is concerned, returning its size.
+fn check_payload(
+    subnet_record: &SubnetRecord,
+    max_block_payload_size: NumBytes,
+    payload: &Payload,
+) -> Result<NumBytes, PayloadRejection> {
+    let data_payload = payload.as_ref().as_data();
+    let features = subnet_features(subnet_record);
+    let disabled_sections = features.disabled_payload_sections();
+
+    let xnet = decompress_enabled_xnet_payload(
+        &data_payload.batch.xnet,
+        &features,
+        max_block_payload_size,
+    )
+    .map_err(|err| PayloadRejection::Invalid(PayloadPermanentError::InvalidXNetCompression(err)))?;
+
+    let batch_payload = &data_payload.batch;
+    let mut size = counted_non_batch_payload_size(
+        &features,
+        non_batch_payload_size(&data_payload.dealings, &data_payload.ecdsa),
+        max_block_payload_size,
+    );
+    for section in SECTIONS {
+        check_section_features(section, batch_payload, &features)
+            .map_err(PayloadRejection::Invalid)?;
+        if is_section_disabled(section, &disabled_sections) {
+            continue;
+        }
+        let section_size = match section {
+            PayloadSection::Ingress => batch_payload.ingress.count_bytes(),
+            PayloadSection::SelfValidating => batch_payload.self_validating.count_bytes(),
+            PayloadSection::XNet => xnet.count_bytes(),
+            PayloadSection::CanisterHttp => batch_payload.canister_http.count_bytes(),
+            PayloadSection::Canary => batch_payload.canary.count_bytes(),
+        };
+        size += NumBytes::new(section_size as u64);
+    }
+
+    let ingress = &batch_payload.ingress;
+    if ingress.message_count() as u64 > subnet_record.max_ingress_messages_per_block {
+        return Err(PayloadRejection::TooManyIngressMessages {
+            count: ingress.message_count(),
+            max: subnet_record.max_ingress_messages_per_block,
+        });
+    }
+    for index in 0..ingress.message_count() {
+        let (_, message) = ingress
+            .get(index)
+            .map_err(|_| PayloadRejection::MalformedIngressMessage { index })?;
+        if message.count_bytes() as u64 > subnet_record.max_ingress_bytes_per_message {
+            return Err(PayloadRejection::IngressMessageTooLarge {
+                index,
+                size: message.count_bytes(),
+                max: subnet_record.max_ingress_bytes_per_message,
+            });
+        }
+    }
+
+    if size > max_block_payload_size {
+        return Err(PayloadRejection::SizeExceeded {
+            size,
+            max: max_block_payload_size,
+        });
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use ic_registry_subnet_features::SubnetFeatures;
+    use ic_test_utilities::{
+        mock_time,
+        types::{ids::node_test_id, messages::SignedIngressBuilder},
+    };
+    use ic_test_utilities_registry::SubnetRecordBuilder;
+    use ic_types::{
+        batch::{BatchPayload, IngressPayload},
+        consensus::{dkg::Dealings, BlockPayload, DataPayload},
+        messages::SignedIngress,
+    };
+    use std::time::Duration;
+
+    fn ingress(nonce: u64, expiry_seconds: u64) -> SignedIngress {
+        SignedIngressBuilder::new()
+            .nonce(nonce)
+            .expiry_time(mock_time() + Duration::from_secs(expiry_seconds))
+            .build()
+    }
+
+    fn payload(height: u64, messages: Vec<SignedIngress>) -> (Height, Payload) {
+        let payload = Payload::new(
+            ic_crypto::crypto_hash,
+            BlockPayload::Data(DataPayload {
+                batch: BatchPayload {
+                    ingress: IngressPayload::from(messages),
+                    ..BatchPayload::default()
+                },
+                dealings: Dealings::new_empty(Height::from(height)),
+                ecdsa: None,
+            }),
+        );
+        (Height::from(height), payload)
+    }
+
+    #[test]
+    fn payloads_are_accepted_under_the_current_record() {
+        let subnet_record = SubnetRecordBuilder::from(&[node_test_id(0)]).build();
+        let payloads = vec![
+            payload(1, vec![ingress(1, 10)]),
+            payload(2, vec![ingress(2, 10), ingress(3, 20)]),
+            payload(3, vec![]),
+        ];
+
+        let report = simulate_payload_acceptance(&subnet_record, &payloads);
+        assert!(report.all_accepted());
+        assert_eq!(report.accepted, 3);
+        assert!(!report.max_block_payload_size_raised);
+        assert!(report.max_payload_size > NumBytes::new(0));
+        assert!(report.max_payload_size <= report.max_block_payload_size);
+    }
+
+    #[test]
+    fn payloads_exceeding_lowered_ingress_limits_are_rejected() {
+        let mut subnet_record = SubnetRecordBuilder::from(&[node_test_id(0)]).build();
+        let payloads = vec![
+            payload(1, vec![ingress(1, 10)]),
+            payload(2, vec![ingress(2, 10), ingress(3, 20)]),
+        ];
+
+        subnet_record.max_ingress_messages_per_block = 1;
+        let report = simulate_payload_acceptance(&subnet_record, &payloads);
+        assert_eq!(report.accepted, 1);
+        assert_matches!(
+            report.rejected.get(&Height::from(2)),
+            Some(PayloadRejection::TooManyIngressMessages { count: 2, max: 1 })
+        );
+
+        subnet_record.max_ingress_messages_per_block = 1000;
+        subnet_record.max_ingress_bytes_per_message = 10;
+        let report = simulate_payload_acceptance(&subnet_record, &payloads);
+        assert_eq!(report.accepted, 0);
+        assert_matches!(
+            report.rejected.get(&Height::from(1)),
+            Some(PayloadRejection::IngressMessageTooLarge {
+                index: 0,
+                max: 10,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn payloads_out_of_canonical_order_are_rejected_once_required() {
+        let mut subnet_record = SubnetRecordBuilder::from(&[node_test_id(0)]).build();
+        let payloads = vec![payload(1, vec![ingress(1, 20), ingress(2, 10)])];
+        assert!(simulate_payload_acceptance(&subnet_record, &payloads).all_accepted());
+
+        subnet_record.features = Some(
+            SubnetFeatures {
+                canonical_payload_order: true,
+                ..SubnetFeatures::default()
+            }
+            .into(),
+        );
+        let report = simulate_payload_acceptance(&subnet_record, &payloads);
+        assert_matches!(
+            report.rejected.get(&Height::from(1)),
+            Some(PayloadRejection::Invalid(
+                PayloadPermanentError::NonCanonicalOrder(PayloadSection::Ingress)
+            ))
+        );
+    }
+
+    #[test]
+    fn too_small_max_block_payload_sizes_are_raised() {
+        let mut subnet_record = SubnetRecordBuilder::from(&[node_test_id(0)]).build();
+        subnet_record.max_block_payload_size = 1;
+
+        let report = simulate_payload_acceptance(&subnet_record, &[payload(1, vec![])]);
+        assert!(report.all_accepted());
+        assert!(report.max_block_payload_size_raised);
+        assert_eq!(
+            report.max_block_payload_size.get(),
+            required_min_block_payload_size(&subnet_record)
+        );
+    }
+}
@@ -20,7 +20,7 @@ use crate::{
 };
 use ic_interfaces::time_source::TimeSource;
 use ic_interfaces::{
-    consensus::{PayloadPermanentError, PayloadTransientError},
+    consensus::{InvalidPayloadBuildStats, PayloadPermanentError, PayloadTransientError},
     consensus_pool::*,
     dkg::DkgPool,
     messaging::MessageRouting,
@@ -36,6 +36,7 @@ use ic_types::{
     replica_config::ReplicaConfig,
     ReplicaVersion,
 };
+use std::cell::RefCell;
 use std::collections::{BTreeMap, HashSet};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
@@ -529,6 +530,9 @@ pub struct Validator {
     metrics: ValidatorMetrics,
     schedule: RoundRobin,
     time_source: Arc<dyn TimeSource>,
+    // The highest height whose payload was passed to the payload builder as
+    // finalized.
+    prev_finalized_height: RefCell<Height>,
 }
 
 impl Validator {
@@ -560,6 +564,7 @@ impl Validator {
             metrics,
             schedule: RoundRobin::default(),
             time_source,
+            prev_finalized_height: RefCell::new(Height::from(0)),
         }
     }
 
@@ -755,6 +760,29 @@ impl Validator {
         }
     }
 
+    // Passes the payloads finalized since the last call to the payload builder,
+    // lowest first. Finalization may advance by several heights at once, e.g.
+    // when catching up.
+    fn notify_finalized_payloads(&self, pool_reader: &PoolReader<'_>, finalized_height: Height) {
+        let prev_finalized_height = *self.prev_finalized_height.borrow();
+        if finalized_height <= prev_finalized_height {
+            return;
+        }
+        let block = match pool_reader.get_finalized_block(finalized_height) {
+            Some(block) => block,
+            None => return,
+        };
+        for (height, time, payload) in pool_reader
+            .get_payloads_from_height(prev_finalized_height.increment(), block)
+            .into_iter()
+            .rev()
+        {
+            self.payload_builder
+                .on_payload_finalized(height, time, &payload);
+        }
+        *self.prev_finalized_height.borrow_mut() = finalized_height;
+    }
+
     /// Return a `ChangeSet` containing status updates concerning any currently
     /// unvalidated blocks that can now be marked valid or invalid. See
     /// `check_block_validity`.
@@ -765,6 +793,7 @@ impl Validator {
         let finalized_height = pool_reader.get_finalized_height();
         let max_height = notarization_height.increment();
         let range = HeightRange::new(finalized_height.increment(), max_height);
+        self.notify_finalized_payloads(pool_reader, finalized_height);
         // Collect the min of validated block proposal ranks in the range.
         let mut known_ranks: BTreeMap<Height, Option<Rank>> =
             get_min_validated_ranks(pool_reader, &range);
@@ -945,6 +974,25 @@ impl Validator {
         let parent = get_notarized_parent(pool_reader, proposal)?;
         self.verify_signature(pool_reader, proposal)?;
 
+        // The payload builder checks the build stats against the payload, but
+        // only here the block maker is known.
+        let payload = proposal.as_ref().payload.as_ref();
+        if !payload.is_summary() {
+            if let Some(stats) = &payload.as_data().batch.build_stats {
+                let signer = proposal.signature.signer;
+                if stats.block_maker != signer {
+                    Err(PermanentError::PayloadValidationError(
+                        PayloadPermanentError::PayloadBuildStatsValidationError(
+                            InvalidPayloadBuildStats::UnexpectedBlockMaker {
+                                block_maker: stats.block_maker,
+                                signer,
+                            },
+                        ),
+                    ))?
+                }
+            }
+        }
+
         // Ensure registry_version, certified_height and time are non-decreasing.
         let proposal = proposal.as_ref();
         if !proposal.context.greater_or_equal(&parent.context) {
@@ -1469,8 +1517,11 @@ pub mod test {
         dependencies_with_subnet_params, Dependencies, MockPayloadBuilder,
     };
     use crate::consensus::utils::get_block_maker_delay;
+    use crate::consensus::SubnetRecords;
     use ic_artifact_pool::dkg_pool::DkgPoolImpl;
-    use ic_interfaces::messaging::XNetTransientValidationError;
+    use ic_interfaces::{
+        consensus::PayloadValidationError, messaging::XNetTransientValidationError,
+    };
     use ic_logger::replica_logger::no_op_logger;
     use ic_metrics::MetricsRegistry;
     use ic_registry_client_fake::FakeRegistryClient;
@@ -1564,6 +1615,89 @@ pub mod test {
         )
     }
 
+    // Records the heights of the payloads passed as finalized.
+    #[derive(Default)]
+    struct FinalizedHeightsRecorder(std::sync::Mutex<Vec<Height>>);
+
+    impl PayloadBuilder for FinalizedHeightsRecorder {
+        fn get_payload(
+            &self,
+            _height: Height,
+            _parent_hash: &CryptoHashOf<Block>,
+            _past_payloads: &[(Height, Time, Payload)],
+            _context: &ValidationContext,
+            _subnet_records: &SubnetRecords,
+        ) -> BatchPayload {
+            BatchPayload::default()
+        }
+
+        fn validate_payload(
+            &self,
+            _height: Height,
+            _payload: &Payload,
+            _past_payloads: &[(Height, Time, Payload)],
+            _context: &ValidationContext,
+        ) -> ValidationResult<PayloadValidationError> {
+            Ok(())
+        }
+
+        fn on_payload_finalized(&self, height: Height, _time: Time, _payload: &Payload) {
+            self.0.lock().unwrap().push(height);
+        }
+    }
+
+    #[test]
+    fn test_every_finalized_payload_is_passed_to_the_payload_builder() {
+        ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
+            let (
+                _payload_builder,
+                membership,
+                state_manager,
+                message_routing,
+                crypto,
+                _data_provider,
+                registry_client,
+                mut pool,
+                dkg_pool,
+                time_source,
+                replica_config,
+            ) = setup_dependencies(pool_config, &(0..4).map(node_test_id).collect::<Vec<_>>());
+            let recorder = Arc::new(FinalizedHeightsRecorder::default());
+            let validator = Validator::new(
+                replica_config,
+                membership,
+                registry_client,
+                crypto,
+                Arc::clone(&recorder) as Arc<_>,
+                state_manager,
+                message_routing,
+                dkg_pool,
+                no_op_logger(),
+                ValidatorMetrics::new(MetricsRegistry::new()),
+                Arc::clone(&time_source) as Arc<_>,
+            );
+            let finalized_heights = || recorder.0.lock().unwrap().clone();
+
+            // Finalization advances by several heights between calls.
+            pool.advance_round_normal_operation_n(3);
+            validator.validate_blocks(&PoolReader::new(&pool));
+            assert_eq!(
+                finalized_heights(),
+                (1..=3).map(Height::from).collect::<Vec<_>>()
+            );
+
+            validator.validate_blocks(&PoolReader::new(&pool));
+            assert_eq!(finalized_heights().len(), 3);
+
+            pool.advance_round_normal_operation_n(2);
+            validator.validate_blocks(&PoolReader::new(&pool));
+            assert_eq!(
+                finalized_heights(),
+                (1..=5).map(Height::from).collect::<Vec<_>>()
+            );
+        })
+    }
+
     #[test]
     fn test_validate_catch_up_package_shares() {
         ic_test_utilities::artifact_pool_config::with_test_pool_config(|pool_config| {
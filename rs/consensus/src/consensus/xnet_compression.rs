@@ -0,0 +1,382 @@
+//! Optional zstd compression of the certified stream slices in the XNet
+//! section.
+//!
+//! With the `xnet_compression` subnet feature, block makers compress the
+//! payloads of the stream slices in the XNet section, reducing the size of
+//! blocks on subnets with heavy cross-net traffic. Compression is transparent
+//! to the XNet payload builder and to message routing: slices are
+//! decompressed before they are validated, before past payloads are passed to
+//! the builder (once per past payload, see [`DecompressedXNetPayloads`]), and
+//! before batches are delivered. Section sizes are accounted for
+//! uncompressed, so compression does not change what fits into a block.
+//!
+//! A compressed payload is told apart from a plain one by the zstd frame magic
+//! number, which can't start the CBOR encoding of a stream tree.
+
+use crate::consensus::metrics::PayloadBuilderMetrics;
+use ic_interfaces::{consensus::InvalidXNetCompression, messaging::XNetPayloadBuilder};
+use ic_logger::{warn, ReplicaLogger};
+use ic_registry_subnet_features::SubnetFeatures;
+use ic_types::{
+    batch::{BatchPayload, XNetPayload},
+    consensus::{BlockPayload, Payload},
+    crypto::CryptoHashOf,
+    xnet::CertifiedStreamSlice,
+    Height, NumBytes, Time,
+};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    io::Read,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// Favors speed, as block makers compress on the critical path.
+const COMPRESSION_LEVEL: i32 = 3;
+
+fn is_slice_compressed(slice: &CertifiedStreamSlice) -> bool {
+    slice.payload.starts_with(&ZSTD_MAGIC)
+}
+
+/// Returns true if any stream slice of `payload` is compressed.
+pub(crate) fn is_compressed(payload: &XNetPayload) -> bool {
+    payload.stream_slices.values().any(is_slice_compressed)
+}
+
+/// Compresses the payloads of the stream slices of `payload`. Slices that do
+/// not shrink are left as they are.
+pub(crate) fn compress_xnet_payload(
+    payload: &mut XNetPayload,
+    metrics: &PayloadBuilderMetrics,
+    logger: &ReplicaLogger,
+) {
+    if payload.stream_slices.is_empty() {
+        return;
+    }
+    let start = Instant::now();
+    let mut uncompressed_bytes = 0;
+    let mut compressed_bytes = 0;
+    for (subnet_id, slice) in payload.stream_slices.iter_mut() {
+        uncompressed_bytes += slice.payload.len();
+        match zstd::bulk::compress(&slice.payload, COMPRESSION_LEVEL) {
+            Ok(compressed) if compressed.len() < slice.payload.len() => {
+                slice.payload = compressed;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                warn!(
+                    logger,
+                    "Failed to compress the stream slice from {}: {}", subnet_id, err
+                );
+            }
+        }
+        compressed_bytes += slice.payload.len();
+    }
+    metrics
+        .xnet_compression_duration
+        .with_label_values(&["compress"])
+        .observe(start.elapsed().as_secs_f64());
+    if compressed_bytes > 0 {
+        metrics
+            .xnet_compression_ratio
+            .observe(uncompressed_bytes as f64 / compressed_bytes as f64);
+    }
+}
+
+/// Returns `payload` with the payloads of its stream slices decompressed,
+/// borrowed if none is compressed. Fails if a slice can't be decompressed, or
+/// if the decompressed slices exceed `max_size` in total.
+pub(crate) fn decompress_xnet_payload(
+    payload: &XNetPayload,
+    max_size: NumBytes,
+) -> Result<Cow<'_, XNetPayload>, InvalidXNetCompression> {
+    if !is_compressed(payload) {
+        return Ok(Cow::Borrowed(payload));
+    }
+    let mut decompressed = payload.clone();
+    let mut remaining = max_size.get();
+    for (subnet_id, slice) in decompressed.stream_slices.iter_mut() {
+        if !is_slice_compressed(slice) {
+            continue;
+        }
+        let malformed = |reason: String| InvalidXNetCompression::Malformed {
+            subnet_id: *subnet_id,
+            max: max_size,
+            reason,
+        };
+        slice.payload = decompress_bounded(&slice.payload, remaining).map_err(malformed)?;
+        remaining -= slice.payload.len() as u64;
+    }
+    Ok(Cow::Owned(decompressed))
+}
+
+/// Returns `payload` decompressed like [`decompress_xnet_payload`], failing
+/// if it is compressed but compression is not enabled in `features`.
+pub(crate) fn decompress_enabled_xnet_payload<'a>(
+    payload: &'a XNetPayload,
+    features: &SubnetFeatures,
+    max_size: NumBytes,
+) -> Result<Cow<'a, XNetPayload>, InvalidXNetCompression> {
+    if is_compressed(payload) && !features.xnet_compression {
+        return Err(InvalidXNetCompression::NotEnabled);
+    }
+    decompress_xnet_payload(payload, max_size)
+}
+
+/// A past XNet section, decompressed if it was compressed.
+pub(crate) enum PastXNetPayload<'a> {
+    Plain(&'a XNetPayload),
+    Decompressed(Arc<XNetPayload>),
+}
+
+impl AsRef<XNetPayload> for PastXNetPayload<'_> {
+    fn as_ref(&self) -> &XNetPayload {
+        match self {
+            Self::Plain(payload) => payload,
+            Self::Decompressed(payload) => payload,
+        }
+    }
+}
+
+/// The decompressed XNet sections of past payloads, by height and payload
+/// hash, so that a section is decompressed once rather than for every block
+/// built or validated on top of it.
+#[derive(Default)]
+pub(crate) struct DecompressedXNetPayloads(
+    Mutex<BTreeMap<(Height, CryptoHashOf<BlockPayload>), Arc<XNetPayload>>>,
+);
+
+impl DecompressedXNetPayloads {
+    /// Returns the XNet sections extracted from `past_payloads` by `builder`,
+    /// decompressed. Sections were validated already, so one that can't be
+    /// decompressed within `max_size` is returned as it is.
+    ///
+    /// Sections below the lowest height of `past_payloads` are evicted, as
+    /// past payloads start above the certified height, which only increases.
+    pub(crate) fn past_payloads<'a>(
+        &self,
+        builder: &dyn XNetPayloadBuilder,
+        past_payloads: &'a [(Height, Time, Payload)],
+        max_size: NumBytes,
+    ) -> Vec<PastXNetPayload<'a>> {
+        let mut cache = self.0.lock().unwrap();
+        match past_payloads.iter().map(|(height, _, _)| *height).min() {
+            Some(min_height) => cache.retain(|(height, _), _| *height >= min_height),
+            None => cache.clear(),
+        }
+        builder
+            .filter_past_payloads(past_payloads)
+            .into_iter()
+            .map(|xnet| {
+                if !is_compressed(xnet) {
+                    return PastXNetPayload::Plain(xnet);
+                }
+                // The builder extracts the sections from `past_payloads`, which
+                // hold their heights and hashes.
+                let key = past_payloads
+                    .iter()
+                    .find(|(_, _, payload)| {
+                        !payload.is_summary()
+                            && std::ptr::eq(&payload.as_ref().as_data().batch.xnet, xnet)
+                    })
+                    .map(|(height, _, payload)| (*height, payload.get_hash().clone()));
+                if let Some(decompressed) = key.as_ref().and_then(|key| cache.get(key)) {
+                    return PastXNetPayload::Decompressed(Arc::clone(decompressed));
+                }
+                match decompress_xnet_payload(xnet, max_size) {
+                    Ok(decompressed) => {
+                        let decompressed = Arc::new(decompressed.into_owned());
+                        if let Some(key) = key {
+                            cache.insert(key, Arc::clone(&decompressed));
+                        }
+                        PastXNetPayload::Decompressed(decompressed)
+                    }
+                    Err(_) => PastXNetPayload::Plain(xnet),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Decompresses the XNet section of the batch payload of a finalized block
+/// before it is delivered to message routing.
+///
+/// # Panics
+///
+/// If the section can't be decompressed, which the validation of the block
+/// rules out.
+pub(crate) fn decompress_finalized_batch_payload(mut payload: BatchPayload) -> BatchPayload {
+    if is_compressed(&payload.xnet) {
+        payload.xnet = decompress_xnet_payload(&payload.xnet, NumBytes::new(u64::MAX))
+            .unwrap_or_else(|err| {
+                panic!(
+                    "Failed to decompress the XNet section of a finalized block: {:?}",
+                    err
+                )
+            })
+            .into_owned();
+    }
+    payload
+}
+
+// Decompresses `data`, failing if it decompresses to more than `max_size`
+// bytes, without allocating more than that.
+fn decompress_bounded(data: &[u8], max_size: u64) -> Result<Vec<u8>, String> {
+    let mut decompressed = Vec::new();
+    zstd::stream::read::Decoder::new(data)
+        .and_then(|decoder| {
+            decoder
+                .take(max_size.saturating_add(1))
+                .read_to_end(&mut decompressed)
+        })
+        .map_err(|err| err.to_string())?;
+    if decompressed.len() as u64 > max_size {
+        return Err(format!("decompresses to more than {} bytes", max_size));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_logger::replica_logger::no_op_logger;
+    use ic_metrics::MetricsRegistry;
+    use ic_test_utilities::{
+        consensus::fake::Fake, mock_time, types::ids::subnet_test_id,
+        xnet_payload_builder::FakeXNetPayloadBuilder,
+    };
+    use ic_types::{
+        consensus::{
+            certification::{Certification, CertificationContent},
+            dkg::Dealings,
+            DataPayload,
+        },
+        crypto::{CryptoHash, Signed},
+        signature::ThresholdSignature,
+        CryptoHashOfPartialState, SubnetId,
+    };
+
+    fn slice(payload: Vec<u8>) -> CertifiedStreamSlice {
+        CertifiedStreamSlice {
+            payload,
+            merkle_proof: vec![],
+            certification: Certification {
+                height: Height::from(1),
+                signed: Signed {
+                    signature: ThresholdSignature::fake(),
+                    content: CertificationContent::new(CryptoHashOfPartialState::from(CryptoHash(
+                        vec![],
+                    ))),
+                },
+            },
+        }
+    }
+
+    fn compressed_subnets(payload: &XNetPayload) -> Vec<SubnetId> {
+        payload
+            .stream_slices
+            .iter()
+            .filter(|(_, slice)| is_slice_compressed(slice))
+            .map(|(subnet_id, _)| *subnet_id)
+            .collect()
+    }
+
+    fn payload() -> XNetPayload {
+        XNetPayload {
+            stream_slices: vec![
+                (subnet_test_id(1), slice(vec![0xa2; 10_000])),
+                (subnet_test_id(2), slice(vec![0xa1, 0x01])),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn compressed_slices_decompress_to_the_original() {
+        let metrics = PayloadBuilderMetrics::new(MetricsRegistry::new());
+        let mut compressed = payload();
+        compress_xnet_payload(&mut compressed, &metrics, &no_op_logger());
+
+        // Slices that do not shrink are left uncompressed.
+        assert_eq!(compressed_subnets(&compressed), vec![subnet_test_id(1)]);
+        assert!(compressed.stream_slices[&subnet_test_id(1)].payload.len() < 1_000);
+        assert_eq!(metrics.xnet_compression_ratio.get_sample_count(), 1);
+
+        let decompressed = decompress_xnet_payload(&compressed, NumBytes::new(10_000)).unwrap();
+        assert_eq!(decompressed.into_owned(), payload());
+
+        let plain = payload();
+        assert!(matches!(
+            decompress_xnet_payload(&plain, NumBytes::new(0)),
+            Ok(Cow::Borrowed(_))
+        ));
+    }
+
+    #[test]
+    fn decompression_is_bounded() {
+        let metrics = PayloadBuilderMetrics::new(MetricsRegistry::new());
+        let mut compressed = payload();
+        compress_xnet_payload(&mut compressed, &metrics, &no_op_logger());
+        assert!(matches!(
+            decompress_xnet_payload(&compressed, NumBytes::new(9_999)),
+            Err(InvalidXNetCompression::Malformed { subnet_id, .. }) if subnet_id == subnet_test_id(1)
+        ));
+
+        let mut garbage = payload();
+        garbage.stream_slices.insert(
+            subnet_test_id(3),
+            slice([&ZSTD_MAGIC[..], &[0; 16]].concat()),
+        );
+        assert!(decompress_xnet_payload(&garbage, NumBytes::new(u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn past_payloads_are_decompressed_once() {
+        let metrics = PayloadBuilderMetrics::new(MetricsRegistry::new());
+        let mut compressed = payload();
+        compress_xnet_payload(&mut compressed, &metrics, &no_op_logger());
+        let past_payload = |height: u64, xnet: XNetPayload| {
+            let payload = Payload::new(
+                ic_crypto::crypto_hash,
+                BlockPayload::Data(DataPayload {
+                    batch: BatchPayload {
+                        xnet,
+                        ..BatchPayload::default()
+                    },
+                    dealings: Dealings::new_empty(Height::from(0)),
+                    ecdsa: None,
+                }),
+            );
+            (Height::from(height), mock_time(), payload)
+        };
+        let past_payloads = vec![past_payload(2, payload()), past_payload(1, compressed)];
+        let builder = FakeXNetPayloadBuilder::new();
+        let decompressed = DecompressedXNetPayloads::default();
+        let decompress = |past_payloads: &[(Height, Time, Payload)]| {
+            decompressed.past_payloads(&builder, past_payloads, NumBytes::new(10_000))
+        };
+
+        let first = decompress(&past_payloads);
+        assert!(matches!(first[0], PastXNetPayload::Plain(_)));
+        assert_eq!(first[1].as_ref(), &payload());
+        let second = decompress(&past_payloads);
+        match (&first[1], &second[1]) {
+            (PastXNetPayload::Decompressed(first), PastXNetPayload::Decompressed(second)) => {
+                assert!(Arc::ptr_eq(first, second))
+            }
+            _ => panic!("Expected decompressed payloads"),
+        }
+
+        // Sections below the past payloads are evicted.
+        decompress(&past_payloads[..1]);
+        assert!(decompressed.0.lock().unwrap().is_empty());
+
+        // Sections that exceed the bound are left compressed.
+        let bounded = decompressed.past_payloads(&builder, &past_payloads, NumBytes::new(9_999));
+        assert!(matches!(bounded[1], PastXNetPayload::Plain(payload) if is_compressed(payload)));
+    }
+}
@@ -10,3 +10,5 @@ pub mod certification;
 pub mod consensus;
 pub mod dkg;
 pub mod ecdsa;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
@@ -0,0 +1,250 @@
+//! Deterministic fakes of the section payload builders, for tests of
+//! components embedding a [`PayloadBuilderImpl`].
+//!
+//! The fakes return canned payloads and accept every payload. For inputs that
+//! vary per height, and for validation failures, see [`scenario`]. The unit
+//! tests of this crate can also inject faults into other section builders,
+//! see `faults`.
+//!
+//! Only available with the `testing` feature, which is meant to be enabled
+//! on dev-dependencies only, and in the unit tests of this crate.
+//!
+//! [`PayloadBuilderImpl`]: crate::consensus::payload_builder::PayloadBuilderImpl
+
+#[cfg(test)]
+pub(crate) mod faults;
+pub mod scenario;
+
+use crate::consensus::SubnetRecords;
+use ic_btc_types_internal::BitcoinAdapterResponse;
+use ic_interfaces::{
+    canister_http::{CanisterHttpPayloadBuilder, CanisterHttpPayloadValidationError},
+    ingress_manager::{IngressPayloadValidationError, IngressSelector, IngressSetQuery},
+    messaging::{XNetPayloadBuilder, XNetPayloadValidationError},
+    self_validating_payload::{SelfValidatingPayloadBuilder, SelfValidatingPayloadValidationError},
+    validation::ValidationResult,
+};
+use ic_protobuf::registry::subnet::v1::SubnetRecord;
+use ic_types::{
+    artifact::IngressMessageId,
+    batch::{
+        CanisterHttpPayload, IngressPayload, SelfValidatingPayload, ValidationContext, XNetPayload,
+    },
+    canister_http::CanisterHttpResponseWithConsensus,
+    consensus::Payload,
+    ingress::IngressSets,
+    messages::SignedIngress,
+    time::UNIX_EPOCH,
+    xnet::CertifiedStreamSlice,
+    CountBytes, Height, NumBytes, SubnetId, Time,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Mutex,
+};
+
+/// Returns the [`SubnetRecords`] of a subnet whose record did not change
+/// between the membership and the context registry versions.
+pub fn subnet_records(subnet_record: SubnetRecord) -> SubnetRecords {
+    SubnetRecords {
+        membership_version: subnet_record.clone(),
+        context_version: subnet_record,
+    }
+}
+
+/// A fake [`IngressSelector`] returning one enqueued batch of ingress messages
+/// per payload.
+#[derive(Default)]
+pub struct FakeIngressSelector(Mutex<VecDeque<Vec<SignedIngress>>>);
+
+impl FakeIngressSelector {
+    /// Creates a selector returning empty payloads.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Enqueues the messages of the next payload.
+    pub fn enqueue(&self, messages: Vec<SignedIngress>) {
+        self.0.lock().unwrap().push_back(messages);
+    }
+
+    /// Dequeues the messages of the next payload, if any.
+    pub fn dequeue(&self) -> Option<Vec<SignedIngress>> {
+        self.0.lock().unwrap().pop_front()
+    }
+}
+
+impl IngressSelector for FakeIngressSelector {
+    fn get_ingress_payload(
+        &self,
+        _past_payloads: &dyn IngressSetQuery,
+        _context: &ValidationContext,
+        _byte_limit: NumBytes,
+    ) -> IngressPayload {
+        self.dequeue().unwrap_or_default().into()
+    }
+
+    fn validate_ingress_payload(
+        &self,
+        _payload: &IngressPayload,
+        _past_payloads: &dyn IngressSetQuery,
+        _context: &ValidationContext,
+    ) -> ValidationResult<IngressPayloadValidationError> {
+        Ok(())
+    }
+
+    fn filter_past_payloads(
+        &self,
+        _past_payloads: &[(Height, Time, Payload)],
+        _context: &ValidationContext,
+    ) -> IngressSets {
+        // NOTE: This is valid, since we never look at the past_payloads in
+        // `get_ingress_payload` and `validate_ingress_payload`
+        IngressSets::new(vec![], UNIX_EPOCH)
+    }
+
+    fn request_purge_finalized_messages(&self, _message_ids: Vec<IngressMessageId>) {}
+}
+
+/// A fake [`XNetPayloadBuilder`] returning one enqueued set of stream slices
+/// per payload.
+#[derive(Default)]
+pub struct FakeXNetPayloadBuilder(Mutex<VecDeque<BTreeMap<SubnetId, CertifiedStreamSlice>>>);
+
+impl FakeXNetPayloadBuilder {
+    /// Creates a builder returning empty payloads.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a builder returning the given stream slices, one set per
+    /// payload.
+    pub fn make(provided_streams: VecDeque<BTreeMap<SubnetId, CertifiedStreamSlice>>) -> Self {
+        Self(Mutex::new(provided_streams))
+    }
+}
+
+impl XNetPayloadBuilder for FakeXNetPayloadBuilder {
+    fn get_xnet_payload(
+        &self,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&XNetPayload],
+        _byte_limit: NumBytes,
+    ) -> XNetPayload {
+        XNetPayload {
+            stream_slices: self.0.lock().unwrap().pop_front().unwrap_or_default(),
+        }
+    }
+
+    fn validate_xnet_payload(
+        &self,
+        payload: &XNetPayload,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&XNetPayload],
+    ) -> Result<NumBytes, XNetPayloadValidationError> {
+        let size: usize = payload
+            .stream_slices
+            .iter()
+            .map(|(_, stream_slice)| stream_slice.payload.len() + stream_slice.merkle_proof.len())
+            .sum();
+
+        Ok(NumBytes::from(size as u64))
+    }
+}
+
+/// A fake [`SelfValidatingPayloadBuilder`] returning the same Bitcoin adapter
+/// responses in every payload.
+#[derive(Default)]
+pub struct FakeSelfValidatingPayloadBuilder(Vec<BitcoinAdapterResponse>);
+
+impl FakeSelfValidatingPayloadBuilder {
+    /// Creates a builder returning empty payloads.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the responses returned in every payload.
+    pub fn with_responses(mut self, responses: Vec<BitcoinAdapterResponse>) -> Self {
+        self.0 = responses;
+        self
+    }
+
+    /// Returns the payload returned by the builder.
+    pub fn build(&self) -> SelfValidatingPayload {
+        SelfValidatingPayload::new(self.0.clone())
+    }
+}
+
+impl SelfValidatingPayloadBuilder for FakeSelfValidatingPayloadBuilder {
+    fn get_self_validating_payload(
+        &self,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&SelfValidatingPayload],
+        _byte_limit: NumBytes,
+    ) -> (SelfValidatingPayload, NumBytes) {
+        let size: usize = self.0.iter().map(|response| response.count_bytes()).sum();
+
+        (
+            SelfValidatingPayload::new(self.0.clone()),
+            NumBytes::new(size as u64),
+        )
+    }
+
+    fn validate_self_validating_payload(
+        &self,
+        _payload: &SelfValidatingPayload,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&SelfValidatingPayload],
+    ) -> Result<NumBytes, SelfValidatingPayloadValidationError> {
+        Ok(0.into())
+    }
+}
+
+/// A fake [`CanisterHttpPayloadBuilder`] returning the same responses in every
+/// payload.
+// TODO: Allow for timeouts in payload builder
+#[derive(Default)]
+pub struct FakeCanisterHttpPayloadBuilder(Vec<CanisterHttpResponseWithConsensus>);
+
+impl FakeCanisterHttpPayloadBuilder {
+    /// Creates a builder returning empty payloads.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the responses returned in every payload.
+    pub fn with_responses(mut self, responses: Vec<CanisterHttpResponseWithConsensus>) -> Self {
+        self.0 = responses;
+        self
+    }
+
+    /// Returns the payload returned by the builder.
+    pub fn build(&self) -> CanisterHttpPayload {
+        CanisterHttpPayload {
+            responses: self.0.clone(),
+            timeouts: vec![],
+        }
+    }
+}
+
+impl CanisterHttpPayloadBuilder for FakeCanisterHttpPayloadBuilder {
+    fn get_canister_http_payload(
+        &self,
+        _height: Height,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&CanisterHttpPayload],
+        _byte_limit: NumBytes,
+    ) -> CanisterHttpPayload {
+        self.build()
+    }
+
+    fn validate_canister_http_payload(
+        &self,
+        _height: Height,
+        payload: &CanisterHttpPayload,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&CanisterHttpPayload],
+    ) -> Result<NumBytes, CanisterHttpPayloadValidationError> {
+        Ok(NumBytes::new(payload.count_bytes() as u64))
+    }
+}
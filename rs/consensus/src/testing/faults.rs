@@ -0,0 +1,488 @@
+//! Fault injection into section payload builders.
+//!
+//! A [`FaultInjector`] wraps section builders, real or fake, into [`Faulty`]
+//! builders that behave like the wrapped ones until a fault is injected into
+//! their section. Faults are consumed in the order they were injected, each by
+//! the next call it applies to, so that tests can make a builder misbehave
+//! exactly once, at a known point:
+//!
+//! ```ignore
+//! let faults = FaultInjector::new();
+//! let payload_builder = PayloadBuilderImpl::new(
+//!     subnet_id,
+//!     registry_client,
+//!     faults.wrap(ingress_selector),
+//!     faults.wrap(xnet_payload_builder),
+//!     faults.wrap(self_validating_payload_builder),
+//!     faults.wrap(canister_http_payload_builder),
+//!     metrics,
+//!     logger,
+//! );
+//! faults.inject(PayloadSection::Ingress, Fault::Oversize);
+//! ```
+//!
+//! This lets tests check that consensus copes with buggy section builders,
+//! i.e. that it drops what they build instead of stalling or building blocks
+//! that other replicas reject. Only available in the unit tests of this
+//! crate.
+
+use super::scenario::{certified_stream_slice, signed_ingress};
+use ic_interfaces::{
+    canister_http::{
+        CanisterHttpPayloadBuilder, CanisterHttpPayloadValidationError,
+        CanisterHttpPermanentValidationError,
+    },
+    ingress_manager::{
+        IngressPayloadValidationError, IngressPermanentError, IngressSelector, IngressSetQuery,
+    },
+    messaging::{InvalidXNetPayload, XNetPayloadBuilder, XNetPayloadValidationError},
+    self_validating_payload::{
+        InvalidSelfValidatingPayload, SelfValidatingPayloadBuilder,
+        SelfValidatingPayloadValidationError,
+    },
+    validation::{ValidationError, ValidationResult},
+};
+use ic_types::{
+    artifact::IngressMessageId,
+    batch::{
+        CanisterHttpPayload, IngressPayload, PayloadSection, SelfValidatingPayload,
+        ValidationContext, XNetPayload,
+    },
+    consensus::Payload,
+    ingress::IngressSets,
+    messages::SignedIngress,
+    CountBytes, Height, NumBytes, PrincipalId, SubnetId, Time,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// A fault of a section builder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The next payload built exceeds its byte limit. The ingress and XNet
+    /// payloads are padded with a message or stream slice larger than the
+    /// limit. The self-validating payload reports a size over the limit, and
+    /// the canister HTTP payload is validated at a size over the limit, as
+    /// their sizes are not derived from their contents.
+    Oversize,
+    /// The next payload is built only after the given delay, as if the
+    /// builder was waiting for a slow component.
+    Delay(Duration),
+    /// The next payload validated is rejected as invalid, even though it was
+    /// built by the same builder.
+    ValidationMismatch,
+}
+
+impl Fault {
+    fn applies_to_validation(&self) -> bool {
+        matches!(self, Self::ValidationMismatch)
+    }
+}
+
+/// Injects faults into the section builders it wraps.
+#[derive(Default)]
+pub struct FaultInjector {
+    pending: Mutex<BTreeMap<PayloadSection, VecDeque<Fault>>>,
+    injected: Mutex<BTreeMap<PayloadSection, usize>>,
+}
+
+impl FaultInjector {
+    /// Creates an injector without pending faults.
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    /// Wraps `inner`, so that the faults injected into its section apply to
+    /// it.
+    pub fn wrap<T: ?Sized>(self: &Arc<Self>, inner: Arc<T>) -> Arc<Faulty<T>> {
+        Arc::new(Faulty {
+            inner,
+            faults: Arc::clone(self),
+            oversize: Mutex::new(None),
+        })
+    }
+
+    /// Injects `fault` into the builder of `section`, for the next call it
+    /// applies to.
+    ///
+    /// Panics for [`PayloadSection::Canary`], whose builder is not wrapped.
+    pub fn inject(&self, section: PayloadSection, fault: Fault) {
+        self.inject_times(section, fault, 1);
+    }
+
+    /// Injects `fault` into the builder of `section`, for the next `times`
+    /// calls it applies to.
+    pub fn inject_times(&self, section: PayloadSection, fault: Fault, times: usize) {
+        assert_ne!(
+            section,
+            PayloadSection::Canary,
+            "Faults cannot be injected into the canary section"
+        );
+        self.pending
+            .lock()
+            .unwrap()
+            .entry(section)
+            .or_default()
+            .extend(std::iter::repeat(fault).take(times));
+    }
+
+    /// Returns the number of faults injected into the builder of `section`
+    /// so far, i.e. those consumed by a call.
+    pub fn injected(&self, section: PayloadSection) -> usize {
+        self.injected
+            .lock()
+            .unwrap()
+            .get(&section)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns true if no injected fault is left to be consumed.
+    pub fn is_exhausted(&self) -> bool {
+        self.pending
+            .lock()
+            .unwrap()
+            .values()
+            .all(VecDeque::is_empty)
+    }
+
+    // Consumes the first pending fault of `section` applying to a build or a
+    // validation.
+    fn take(&self, section: PayloadSection, validation: bool) -> Option<Fault> {
+        let mut pending = self.pending.lock().unwrap();
+        let faults = pending.get_mut(&section)?;
+        let index = faults
+            .iter()
+            .position(|fault| fault.applies_to_validation() == validation)?;
+        let fault = faults.remove(index);
+        *self.injected.lock().unwrap().entry(section).or_default() += 1;
+        fault
+    }
+
+    // Consumes the first pending build fault of `section`, sleeping right away
+    // if it is a delay.
+    fn take_build_fault(&self, section: PayloadSection) -> Option<Fault> {
+        let fault = self.take(section, false);
+        if let Some(Fault::Delay(delay)) = fault {
+            std::thread::sleep(delay);
+        }
+        fault
+    }
+
+    fn take_validation_fault(&self, section: PayloadSection) -> Option<Fault> {
+        self.take(section, true)
+    }
+}
+
+/// A section builder into which a [`FaultInjector`] injects faults.
+pub struct Faulty<T: ?Sized> {
+    inner: Arc<T>,
+    faults: Arc<FaultInjector>,
+    // The byte limit of the last payload built with an oversize fault, for
+    // builders whose oversize fault shows in the validation after the build.
+    oversize: Mutex<Option<NumBytes>>,
+}
+
+// A byte limit exceeded by one byte, as a size.
+fn over(byte_limit: NumBytes) -> usize {
+    byte_limit.get() as usize + 1
+}
+
+impl<T: IngressSelector + ?Sized> IngressSelector for Faulty<T> {
+    fn get_ingress_payload(
+        &self,
+        past_payloads: &dyn IngressSetQuery,
+        context: &ValidationContext,
+        byte_limit: NumBytes,
+    ) -> IngressPayload {
+        let fault = self.faults.take_build_fault(PayloadSection::Ingress);
+        let payload = self
+            .inner
+            .get_ingress_payload(past_payloads, context, byte_limit);
+        match fault {
+            Some(Fault::Oversize) => {
+                let mut messages = Vec::<SignedIngress>::try_from(payload)
+                    .expect("Failed to decode the ingress payload");
+                messages.push(signed_ingress(b"oversize".to_vec(), over(byte_limit)));
+                messages.into()
+            }
+            _ => payload,
+        }
+    }
+
+    fn validate_ingress_payload(
+        &self,
+        payload: &IngressPayload,
+        past_payloads: &dyn IngressSetQuery,
+        context: &ValidationContext,
+    ) -> ValidationResult<IngressPayloadValidationError> {
+        match self.faults.take_validation_fault(PayloadSection::Ingress) {
+            Some(_) => Err(ValidationError::Permanent(
+                IngressPermanentError::IngressPayloadTooBig(payload.count_bytes(), 0),
+            )),
+            None => self
+                .inner
+                .validate_ingress_payload(payload, past_payloads, context),
+        }
+    }
+
+    fn filter_past_payloads(
+        &self,
+        past_payloads: &[(Height, Time, Payload)],
+        context: &ValidationContext,
+    ) -> IngressSets {
+        self.inner.filter_past_payloads(past_payloads, context)
+    }
+
+    fn request_purge_finalized_messages(&self, message_ids: Vec<IngressMessageId>) {
+        self.inner.request_purge_finalized_messages(message_ids)
+    }
+}
+
+impl<T: XNetPayloadBuilder + ?Sized> XNetPayloadBuilder for Faulty<T> {
+    fn get_xnet_payload(
+        &self,
+        validation_context: &ValidationContext,
+        past_payloads: &[&XNetPayload],
+        byte_limit: NumBytes,
+    ) -> XNetPayload {
+        let fault = self.faults.take_build_fault(PayloadSection::XNet);
+        let mut payload =
+            self.inner
+                .get_xnet_payload(validation_context, past_payloads, byte_limit);
+        if fault == Some(Fault::Oversize) {
+            payload.stream_slices.insert(
+                SubnetId::from(PrincipalId::new_subnet_test_id(u64::MAX)),
+                certified_stream_slice(validation_context.certified_height, over(byte_limit)),
+            );
+        }
+        payload
+    }
+
+    fn validate_xnet_payload(
+        &self,
+        payload: &XNetPayload,
+        validation_context: &ValidationContext,
+        past_payloads: &[&XNetPayload],
+    ) -> Result<NumBytes, XNetPayloadValidationError> {
+        match self.faults.take_validation_fault(PayloadSection::XNet) {
+            Some(_) => Err(ValidationError::Permanent(
+                InvalidXNetPayload::InvalidSlice("Rejected by fault injection".to_string()),
+            )),
+            None => self
+                .inner
+                .validate_xnet_payload(payload, validation_context, past_payloads),
+        }
+    }
+
+    fn filter_past_payloads<'a>(
+        &self,
+        past_payloads: &'a [(Height, Time, Payload)],
+    ) -> Vec<&'a XNetPayload> {
+        self.inner.filter_past_payloads(past_payloads)
+    }
+}
+
+impl<T: SelfValidatingPayloadBuilder + ?Sized> SelfValidatingPayloadBuilder for Faulty<T> {
+    fn get_self_validating_payload(
+        &self,
+        validation_context: &ValidationContext,
+        past_payloads: &[&SelfValidatingPayload],
+        byte_limit: NumBytes,
+    ) -> (SelfValidatingPayload, NumBytes) {
+        let fault = self.faults.take_build_fault(PayloadSection::SelfValidating);
+        let (payload, size) =
+            self.inner
+                .get_self_validating_payload(validation_context, past_payloads, byte_limit);
+        match fault {
+            Some(Fault::Oversize) => (payload, NumBytes::from(over(byte_limit) as u64)),
+            _ => (payload, size),
+        }
+    }
+
+    fn validate_self_validating_payload(
+        &self,
+        payload: &SelfValidatingPayload,
+        validation_context: &ValidationContext,
+        past_payloads: &[&SelfValidatingPayload],
+    ) -> Result<NumBytes, SelfValidatingPayloadValidationError> {
+        match self
+            .faults
+            .take_validation_fault(PayloadSection::SelfValidating)
+        {
+            Some(_) => Err(ValidationError::Permanent(
+                InvalidSelfValidatingPayload::PayloadTooBig,
+            )),
+            None => self.inner.validate_self_validating_payload(
+                payload,
+                validation_context,
+                past_payloads,
+            ),
+        }
+    }
+
+    fn filter_past_payloads<'a>(
+        &self,
+        past_payloads: &'a [(Height, Time, Payload)],
+    ) -> Vec<&'a SelfValidatingPayload> {
+        self.inner.filter_past_payloads(past_payloads)
+    }
+}
+
+// The oversize fault of the canister HTTP builder applies to the validation
+// that follows the build, see `Fault::Oversize`.
+impl<T: CanisterHttpPayloadBuilder + ?Sized> CanisterHttpPayloadBuilder for Faulty<T> {
+    fn get_canister_http_payload(
+        &self,
+        height: Height,
+        validation_context: &ValidationContext,
+        past_payloads: &[&CanisterHttpPayload],
+        byte_limit: NumBytes,
+    ) -> CanisterHttpPayload {
+        if self.faults.take_build_fault(PayloadSection::CanisterHttp) == Some(Fault::Oversize) {
+            *self.oversize.lock().unwrap() = Some(byte_limit);
+        }
+        self.inner
+            .get_canister_http_payload(height, validation_context, past_payloads, byte_limit)
+    }
+
+    fn validate_canister_http_payload(
+        &self,
+        height: Height,
+        payload: &CanisterHttpPayload,
+        validation_context: &ValidationContext,
+        past_payloads: &[&CanisterHttpPayload],
+    ) -> Result<NumBytes, CanisterHttpPayloadValidationError> {
+        if let Some(byte_limit) = self.oversize.lock().unwrap().take() {
+            return Ok(NumBytes::from(over(byte_limit) as u64));
+        }
+        match self
+            .faults
+            .take_validation_fault(PayloadSection::CanisterHttp)
+        {
+            Some(_) => Err(ValidationError::Permanent(
+                CanisterHttpPermanentValidationError::PayloadTooBig {
+                    expected: 0,
+                    received: payload.count_bytes(),
+                },
+            )),
+            None => self.inner.validate_canister_http_payload(
+                height,
+                payload,
+                validation_context,
+                past_payloads,
+            ),
+        }
+    }
+
+    fn filter_past_payloads<'a>(
+        &self,
+        past_payloads: &'a [(Height, Time, Payload)],
+    ) -> Vec<&'a CanisterHttpPayload> {
+        self.inner.filter_past_payloads(past_payloads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consensus::payload_builder::{PayloadBuilder, PayloadBuilderImpl},
+        testing::{
+            subnet_records, FakeCanisterHttpPayloadBuilder, FakeIngressSelector,
+            FakeSelfValidatingPayloadBuilder, FakeXNetPayloadBuilder,
+        },
+    };
+    use assert_matches::assert_matches;
+    use ic_interfaces::consensus::PayloadPermanentError;
+    use ic_logger::replica_logger::no_op_logger;
+    use ic_metrics::MetricsRegistry;
+    use ic_test_utilities::{
+        mock_time,
+        types::ids::{node_test_id, subnet_test_id},
+    };
+    use ic_test_utilities_registry::{setup_registry, SubnetRecordBuilder};
+    use ic_types::{
+        consensus::{dkg::Dealings, BlockPayload, DataPayload},
+        crypto::{CryptoHash, CryptoHashOf},
+        RegistryVersion,
+    };
+    use std::time::Instant;
+
+    #[test]
+    fn test_faults_are_injected_once() {
+        let subnet_id = subnet_test_id(0);
+        let subnet_record = SubnetRecordBuilder::from(&[node_test_id(0)]).build();
+        let registry = setup_registry(subnet_id, vec![(1, subnet_record.clone())]);
+        let faults = FaultInjector::new();
+        let ingress_selector = Arc::new(FakeIngressSelector::new());
+        let payload_builder = PayloadBuilderImpl::new(
+            subnet_id,
+            registry,
+            faults.wrap(Arc::clone(&ingress_selector)),
+            faults.wrap(Arc::new(FakeXNetPayloadBuilder::new())),
+            faults.wrap(Arc::new(FakeSelfValidatingPayloadBuilder::new())),
+            faults.wrap(Arc::new(FakeCanisterHttpPayloadBuilder::new())),
+            MetricsRegistry::new(),
+            no_op_logger(),
+        );
+        let context = ValidationContext {
+            registry_version: RegistryVersion::from(1),
+            certified_height: Height::from(0),
+            time: mock_time(),
+        };
+        let subnet_records = subnet_records(subnet_record);
+        let parent_hash = CryptoHashOf::from(CryptoHash(vec![0; 32]));
+        let get_payload = || {
+            ingress_selector.enqueue(vec![signed_ingress(vec![], 10)]);
+            payload_builder.get_payload(
+                Height::from(1),
+                &parent_hash,
+                &[],
+                &context,
+                &subnet_records,
+            )
+        };
+
+        // Oversize and mismatching ingress payloads are dropped by the
+        // payload builder.
+        faults.inject(PayloadSection::Ingress, Fault::Oversize);
+        assert!(get_payload().ingress.is_empty());
+        faults.inject(PayloadSection::Ingress, Fault::ValidationMismatch);
+        assert!(get_payload().ingress.is_empty());
+        assert_eq!(faults.injected(PayloadSection::Ingress), 2);
+
+        let delay = Duration::from_millis(10);
+        faults.inject(PayloadSection::Ingress, Fault::Delay(delay));
+        let start = Instant::now();
+        let batch = get_payload();
+        assert!(start.elapsed() >= delay);
+        assert_eq!(batch.ingress.message_count(), 1);
+        assert!(faults.is_exhausted());
+
+        // A mismatch when validating the payload of another replica.
+        let payload = Payload::new(
+            ic_crypto::crypto_hash,
+            BlockPayload::Data(DataPayload {
+                batch,
+                dealings: Dealings::new_empty(Height::from(0)),
+                ecdsa: None,
+            }),
+        );
+        faults.inject(PayloadSection::XNet, Fault::ValidationMismatch);
+        assert_matches!(
+            payload_builder.validate_payload(Height::from(1), &payload, &[], &context),
+            Err(ValidationError::Permanent(
+                PayloadPermanentError::XNetPayloadValidationError(_)
+            ))
+        );
+        assert!(payload_builder
+            .validate_payload(Height::from(1), &payload, &[], &context)
+            .is_ok());
+        assert_eq!(faults.injected(PayloadSection::XNet), 1);
+    }
+}
@@ -0,0 +1,638 @@
+//! A DSL scripting the section payload builders of a [`PayloadBuilderImpl`]
+//! per height.
+//!
+//! A [`Scenario`] lists, per height, what the section builders return (ingress
+//! messages and stream slices of given sizes, Bitcoin adapter and canister
+//! HTTP responses) and which sections fail validation. It builds a
+//! [`ScenarioPayloadBuilder`], i.e. a `PayloadBuilderImpl` whose section
+//! builders follow the script:
+//!
+//! ```ignore
+//! let payload_builder = Scenario::new()
+//!     .at(Height::new(1), |inputs| inputs.ingress(3, 1024))
+//!     .at(Height::new(2), |inputs| inputs.xnet_slice(subnet_test_id(1), 4096))
+//!     .at(Height::new(3), |inputs| {
+//!         inputs.fail(PayloadSection::XNet, Failure::Transient)
+//!     })
+//!     .build(subnet_id, registry_client, MetricsRegistry::new(), no_op_logger());
+//! ```
+//!
+//! Inputs are generated deterministically, so the same scenario always builds
+//! the same payloads. Section builders return nothing at heights without
+//! inputs, and leave out inputs that exceed their byte limit.
+
+use crate::consensus::{
+    payload_builder::{PayloadBuilder, PayloadBuilderImpl},
+    SubnetRecords,
+};
+use ic_btc_types_internal::BitcoinAdapterResponse;
+use ic_constants::MAX_INGRESS_TTL;
+use ic_interfaces::{
+    canister_http::{
+        CanisterHttpPayloadBuilder, CanisterHttpPayloadValidationError,
+        CanisterHttpPermanentValidationError, CanisterHttpTransientValidationError,
+    },
+    consensus::PayloadValidationError,
+    ingress_manager::{
+        IngressPayloadValidationError, IngressPermanentError, IngressSelector, IngressSetQuery,
+        IngressTransientError,
+    },
+    messaging::{
+        InvalidXNetPayload, XNetPayloadBuilder, XNetPayloadValidationError,
+        XNetTransientValidationError,
+    },
+    registry::RegistryClient,
+    self_validating_payload::{
+        InvalidSelfValidatingPayload, SelfValidatingPayloadBuilder,
+        SelfValidatingPayloadValidationError, SelfValidatingTransientValidationError,
+    },
+    validation::{ValidationError, ValidationResult},
+};
+use ic_interfaces_state_manager::StateManagerError;
+use ic_logger::ReplicaLogger;
+use ic_metrics::MetricsRegistry;
+use ic_types::{
+    artifact::IngressMessageId,
+    batch::{
+        BatchPayload, CanisterHttpPayload, IngressPayload, PayloadSection, SelfValidatingPayload,
+        ValidationContext, XNetPayload,
+    },
+    canister_http::CanisterHttpResponseWithConsensus,
+    consensus::{
+        certification::{Certification, CertificationContent},
+        Block, Payload,
+    },
+    crypto::{
+        threshold_sig::ni_dkg::{NiDkgId, NiDkgTag, NiDkgTargetSubnet},
+        CombinedThresholdSig, CombinedThresholdSigOf, CryptoHash, CryptoHashOf, Signed,
+    },
+    ingress::IngressSets,
+    messages::{Blob, HttpCallContent, HttpCanisterUpdate, HttpRequestEnvelope, SignedIngress},
+    signature::ThresholdSignature,
+    time::UNIX_EPOCH,
+    xnet::CertifiedStreamSlice,
+    CanisterId, CountBytes, CryptoHashOfPartialState, Height, NumBytes, PrincipalId, SubnetId,
+    Time,
+};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+/// How the validation of a section fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The section is invalid, e.g. too big.
+    Permanent,
+    /// The section cannot be validated yet, as if the state at the certified
+    /// height was not available.
+    Transient,
+}
+
+/// The inputs of the section builders at one height.
+#[derive(Clone, Debug, Default)]
+pub struct HeightInputs {
+    height: Height,
+    ingress: Vec<SignedIngress>,
+    stream_slices: BTreeMap<SubnetId, CertifiedStreamSlice>,
+    bitcoin_responses: Vec<BitcoinAdapterResponse>,
+    canister_http_responses: Vec<CanisterHttpResponseWithConsensus>,
+    failures: BTreeMap<PayloadSection, Failure>,
+}
+
+impl HeightInputs {
+    /// Adds `count` ingress messages with an argument of `size` bytes each.
+    pub fn ingress(mut self, count: usize, size: usize) -> Self {
+        for _ in 0..count {
+            let nonce = [
+                self.height.get().to_be_bytes(),
+                (self.ingress.len() as u64).to_be_bytes(),
+            ]
+            .concat();
+            self.ingress.push(signed_ingress(nonce, size));
+        }
+        self
+    }
+
+    /// Adds a slice of `size` bytes of the stream from `subnet_id`.
+    pub fn xnet_slice(mut self, subnet_id: SubnetId, size: usize) -> Self {
+        self.stream_slices
+            .insert(subnet_id, certified_stream_slice(self.height, size));
+        self
+    }
+
+    /// Adds responses of the Bitcoin adapter.
+    pub fn bitcoin_responses(mut self, responses: Vec<BitcoinAdapterResponse>) -> Self {
+        self.bitcoin_responses.extend(responses);
+        self
+    }
+
+    /// Adds canister HTTP responses.
+    pub fn canister_http_responses(
+        mut self,
+        responses: Vec<CanisterHttpResponseWithConsensus>,
+    ) -> Self {
+        self.canister_http_responses.extend(responses);
+        self
+    }
+
+    /// Fails the validation of `section` at this height.
+    ///
+    /// Panics for [`PayloadSection::Canary`], which is not scripted.
+    pub fn fail(mut self, section: PayloadSection, failure: Failure) -> Self {
+        assert_ne!(
+            section,
+            PayloadSection::Canary,
+            "The canary section cannot be scripted"
+        );
+        self.failures.insert(section, failure);
+        self
+    }
+}
+
+/// The inputs of the section builders over a number of heights.
+#[derive(Clone, Debug, Default)]
+pub struct Scenario {
+    heights: BTreeMap<Height, HeightInputs>,
+}
+
+impl Scenario {
+    /// Creates a scenario without inputs.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds inputs at `height`.
+    pub fn at(mut self, height: Height, f: impl FnOnce(HeightInputs) -> HeightInputs) -> Self {
+        let inputs = self.heights.remove(&height).unwrap_or(HeightInputs {
+            height,
+            ..Default::default()
+        });
+        self.heights.insert(height, f(inputs));
+        self
+    }
+
+    /// Builds a [`PayloadBuilderImpl`] whose section builders follow the
+    /// scenario.
+    pub fn build(
+        self,
+        subnet_id: SubnetId,
+        registry_client: Arc<dyn RegistryClient>,
+        metrics: MetricsRegistry,
+        logger: ReplicaLogger,
+    ) -> ScenarioPayloadBuilder {
+        let script = Arc::new(Script {
+            heights: self.heights,
+            height: Mutex::new(Height::from(0)),
+        });
+        let sections = Arc::new(ScriptedSections(Arc::clone(&script)));
+        ScenarioPayloadBuilder {
+            payload_builder: PayloadBuilderImpl::new(
+                subnet_id,
+                registry_client,
+                sections.clone(),
+                sections.clone(),
+                sections.clone(),
+                sections,
+                metrics,
+                logger,
+            ),
+            script,
+            calls: Mutex::new(()),
+        }
+    }
+}
+
+/// A [`PayloadBuilderImpl`] whose section builders follow a [`Scenario`].
+///
+/// Calls are serialized, so that section builders see the height of the
+/// payload being built or validated.
+pub struct ScenarioPayloadBuilder {
+    payload_builder: PayloadBuilderImpl,
+    script: Arc<Script>,
+    calls: Mutex<()>,
+}
+
+impl PayloadBuilder for ScenarioPayloadBuilder {
+    fn get_payload(
+        &self,
+        height: Height,
+        parent_hash: &CryptoHashOf<Block>,
+        past_payloads: &[(Height, Time, Payload)],
+        context: &ValidationContext,
+        subnet_records: &SubnetRecords,
+    ) -> BatchPayload {
+        let _call = self.calls.lock().unwrap();
+        *self.script.height.lock().unwrap() = height;
+        self.payload_builder.get_payload(
+            height,
+            parent_hash,
+            past_payloads,
+            context,
+            subnet_records,
+        )
+    }
+
+    fn get_payload_with_reserved_bytes(
+        &self,
+        height: Height,
+        parent_hash: &CryptoHashOf<Block>,
+        past_payloads: &[(Height, Time, Payload)],
+        context: &ValidationContext,
+        subnet_records: &SubnetRecords,
+        reserved_bytes: NumBytes,
+    ) -> BatchPayload {
+        let _call = self.calls.lock().unwrap();
+        *self.script.height.lock().unwrap() = height;
+        self.payload_builder.get_payload_with_reserved_bytes(
+            height,
+            parent_hash,
+            past_payloads,
+            context,
+            subnet_records,
+            reserved_bytes,
+        )
+    }
+
+    fn validate_payload(
+        &self,
+        height: Height,
+        payload: &Payload,
+        past_payloads: &[(Height, Time, Payload)],
+        context: &ValidationContext,
+    ) -> ValidationResult<PayloadValidationError> {
+        let _call = self.calls.lock().unwrap();
+        *self.script.height.lock().unwrap() = height;
+        self.payload_builder
+            .validate_payload(height, payload, past_payloads, context)
+    }
+
+    fn on_payload_finalized(&self, height: Height, time: Time, payload: &Payload) {
+        self.payload_builder
+            .on_payload_finalized(height, time, payload)
+    }
+}
+
+// The scenario, along with the height of the payload being built or
+// validated.
+struct Script {
+    heights: BTreeMap<Height, HeightInputs>,
+    height: Mutex<Height>,
+}
+
+impl Script {
+    fn with_inputs<T: Default>(&self, f: impl FnOnce(&HeightInputs) -> T) -> T {
+        let height = *self.height.lock().unwrap();
+        self.heights.get(&height).map(f).unwrap_or_default()
+    }
+
+    fn failure(&self, section: PayloadSection) -> Option<Failure> {
+        self.with_inputs(|inputs| inputs.failures.get(&section).copied())
+    }
+}
+
+// Implements all scripted section builders.
+struct ScriptedSections(Arc<Script>);
+
+// Returns the longest prefix of `items` whose total size is within
+// `byte_limit`.
+fn within_limit<T>(
+    items: impl Iterator<Item = T>,
+    size: impl Fn(&T) -> usize,
+    byte_limit: NumBytes,
+) -> Vec<T> {
+    let mut total = 0;
+    items
+        .take_while(|item| {
+            total += size(item) as u64;
+            total <= byte_limit.get()
+        })
+        .collect()
+}
+
+fn slice_size(slice: &CertifiedStreamSlice) -> usize {
+    slice.payload.len() + slice.merkle_proof.len()
+}
+
+impl IngressSelector for ScriptedSections {
+    fn get_ingress_payload(
+        &self,
+        _past_payloads: &dyn IngressSetQuery,
+        _context: &ValidationContext,
+        byte_limit: NumBytes,
+    ) -> IngressPayload {
+        self.0
+            .with_inputs(|inputs| {
+                within_limit(
+                    inputs.ingress.iter().cloned(),
+                    |m| m.count_bytes(),
+                    byte_limit,
+                )
+            })
+            .into()
+    }
+
+    fn validate_ingress_payload(
+        &self,
+        payload: &IngressPayload,
+        _past_payloads: &dyn IngressSetQuery,
+        context: &ValidationContext,
+    ) -> ValidationResult<IngressPayloadValidationError> {
+        match self.0.failure(PayloadSection::Ingress) {
+            Some(Failure::Permanent) => Err(ValidationError::Permanent(
+                IngressPermanentError::IngressPayloadTooBig(payload.count_bytes(), 0),
+            )),
+            Some(Failure::Transient) => Err(ValidationError::Transient(
+                IngressTransientError::StateNotCommittedYet(context.certified_height),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn filter_past_payloads(
+        &self,
+        _past_payloads: &[(Height, Time, Payload)],
+        _context: &ValidationContext,
+    ) -> IngressSets {
+        // Past payloads are never looked at.
+        IngressSets::new(vec![], UNIX_EPOCH)
+    }
+
+    fn request_purge_finalized_messages(&self, _message_ids: Vec<IngressMessageId>) {}
+}
+
+impl XNetPayloadBuilder for ScriptedSections {
+    fn get_xnet_payload(
+        &self,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&XNetPayload],
+        byte_limit: NumBytes,
+    ) -> XNetPayload {
+        let stream_slices = self.0.with_inputs(|inputs| {
+            within_limit(
+                inputs.stream_slices.clone().into_iter(),
+                |(_, slice)| slice_size(slice),
+                byte_limit,
+            )
+        });
+        XNetPayload {
+            stream_slices: stream_slices.into_iter().collect(),
+        }
+    }
+
+    fn validate_xnet_payload(
+        &self,
+        payload: &XNetPayload,
+        validation_context: &ValidationContext,
+        _past_payloads: &[&XNetPayload],
+    ) -> Result<NumBytes, XNetPayloadValidationError> {
+        match self.0.failure(PayloadSection::XNet) {
+            Some(Failure::Permanent) => Err(ValidationError::Permanent(
+                InvalidXNetPayload::InvalidSlice("Rejected by the scenario".to_string()),
+            )),
+            Some(Failure::Transient) => Err(ValidationError::Transient(
+                XNetTransientValidationError::StateNotCommittedYet(
+                    validation_context.certified_height,
+                ),
+            )),
+            None => {
+                let size: usize = payload.stream_slices.values().map(slice_size).sum();
+                Ok(NumBytes::from(size as u64))
+            }
+        }
+    }
+}
+
+impl SelfValidatingPayloadBuilder for ScriptedSections {
+    fn get_self_validating_payload(
+        &self,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&SelfValidatingPayload],
+        byte_limit: NumBytes,
+    ) -> (SelfValidatingPayload, NumBytes) {
+        let responses = self.0.with_inputs(|inputs| {
+            within_limit(
+                inputs.bitcoin_responses.iter().cloned(),
+                |response| response.count_bytes(),
+                byte_limit,
+            )
+        });
+        let size: usize = responses
+            .iter()
+            .map(|response| response.count_bytes())
+            .sum();
+        (
+            SelfValidatingPayload::new(responses),
+            NumBytes::from(size as u64),
+        )
+    }
+
+    fn validate_self_validating_payload(
+        &self,
+        payload: &SelfValidatingPayload,
+        validation_context: &ValidationContext,
+        _past_payloads: &[&SelfValidatingPayload],
+    ) -> Result<NumBytes, SelfValidatingPayloadValidationError> {
+        let certified_height = validation_context.certified_height;
+        match self.0.failure(PayloadSection::SelfValidating) {
+            Some(Failure::Permanent) => Err(ValidationError::Permanent(
+                InvalidSelfValidatingPayload::PayloadTooBig,
+            )),
+            Some(Failure::Transient) => Err(ValidationError::Transient(
+                SelfValidatingTransientValidationError::GetStateFailed(
+                    certified_height,
+                    StateManagerError::StateNotCommittedYet(certified_height),
+                ),
+            )),
+            None => Ok(NumBytes::from(payload.count_bytes() as u64)),
+        }
+    }
+}
+
+impl CanisterHttpPayloadBuilder for ScriptedSections {
+    fn get_canister_http_payload(
+        &self,
+        _height: Height,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&CanisterHttpPayload],
+        byte_limit: NumBytes,
+    ) -> CanisterHttpPayload {
+        CanisterHttpPayload {
+            responses: self.0.with_inputs(|inputs| {
+                within_limit(
+                    inputs.canister_http_responses.iter().cloned(),
+                    |response| response.count_bytes(),
+                    byte_limit,
+                )
+            }),
+            timeouts: vec![],
+        }
+    }
+
+    fn validate_canister_http_payload(
+        &self,
+        _height: Height,
+        payload: &CanisterHttpPayload,
+        _validation_context: &ValidationContext,
+        _past_payloads: &[&CanisterHttpPayload],
+    ) -> Result<NumBytes, CanisterHttpPayloadValidationError> {
+        match self.0.failure(PayloadSection::CanisterHttp) {
+            Some(Failure::Permanent) => Err(ValidationError::Permanent(
+                CanisterHttpPermanentValidationError::PayloadTooBig {
+                    expected: 0,
+                    received: payload.count_bytes(),
+                },
+            )),
+            Some(Failure::Transient) => Err(ValidationError::Transient(
+                CanisterHttpTransientValidationError::StateUnavailable,
+            )),
+            None => Ok(NumBytes::from(payload.count_bytes() as u64)),
+        }
+    }
+}
+
+// An anonymous ingress message to canister 0 with an argument of `size` bytes.
+pub(super) fn signed_ingress(nonce: Vec<u8>, size: usize) -> SignedIngress {
+    let update = HttpCanisterUpdate {
+        canister_id: Blob(CanisterId::from_u64(0).get().into_vec()),
+        method_name: String::new(),
+        arg: Blob(vec![0; size]),
+        sender: Blob(PrincipalId::new_anonymous().into_vec()),
+        ingress_expiry: (UNIX_EPOCH + MAX_INGRESS_TTL).as_nanos_since_unix_epoch(),
+        nonce: Some(Blob(nonce)),
+    };
+    SignedIngress::try_from(HttpRequestEnvelope::<HttpCallContent> {
+        content: HttpCallContent::Call { update },
+        sender_pubkey: None,
+        sender_sig: None,
+        sender_delegation: None,
+    })
+    .expect("Failed to build an anonymous ingress message")
+}
+
+// A stream slice of `size` bytes, with an invalid certification at `height`.
+pub(super) fn certified_stream_slice(height: Height, size: usize) -> CertifiedStreamSlice {
+    CertifiedStreamSlice {
+        payload: vec![0; size],
+        merkle_proof: vec![],
+        certification: Certification {
+            height,
+            signed: Signed {
+                content: CertificationContent::new(CryptoHashOfPartialState::from(CryptoHash(
+                    vec![],
+                ))),
+                signature: ThresholdSignature {
+                    signer: NiDkgId {
+                        start_block_height: Height::from(0),
+                        dealer_subnet: SubnetId::from(PrincipalId::new_subnet_test_id(0)),
+                        dkg_tag: NiDkgTag::LowThreshold,
+                        target_subnet: NiDkgTargetSubnet::Local,
+                    },
+                    signature: CombinedThresholdSigOf::new(CombinedThresholdSig(vec![])),
+                },
+            },
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::subnet_records;
+    use assert_matches::assert_matches;
+    use ic_interfaces::consensus::PayloadPermanentError;
+    use ic_logger::replica_logger::no_op_logger;
+    use ic_test_utilities::{
+        mock_time,
+        types::ids::{node_test_id, subnet_test_id},
+    };
+    use ic_test_utilities_registry::{setup_registry, SubnetRecordBuilder};
+    use ic_types::{
+        consensus::{dkg::Dealings, BlockPayload, DataPayload},
+        RegistryVersion,
+    };
+
+    #[test]
+    fn test_scenario_is_followed_per_height() {
+        let subnet_id = subnet_test_id(0);
+        let subnet_record = SubnetRecordBuilder::from(&[node_test_id(0)]).build();
+        let registry = setup_registry(subnet_id, vec![(1, subnet_record.clone())]);
+        let scenario = Scenario::new()
+            .at(Height::from(1), |inputs| inputs.ingress(3, 1024))
+            .at(Height::from(2), |inputs| {
+                inputs.xnet_slice(subnet_test_id(1), 4096)
+            })
+            .at(Height::from(3), |inputs| {
+                inputs
+                    .ingress(1, 10)
+                    .fail(PayloadSection::Ingress, Failure::Permanent)
+            });
+        let payload_builder = scenario.clone().build(
+            subnet_id,
+            registry.clone(),
+            MetricsRegistry::new(),
+            no_op_logger(),
+        );
+        let context = ValidationContext {
+            registry_version: RegistryVersion::from(1),
+            certified_height: Height::from(0),
+            time: mock_time(),
+        };
+        let subnet_records = subnet_records(subnet_record);
+        let parent_hash = CryptoHashOf::from(CryptoHash(vec![0; 32]));
+        let get_payload = |height: u64| {
+            payload_builder.get_payload(
+                Height::from(height),
+                &parent_hash,
+                &[],
+                &context,
+                &subnet_records,
+            )
+        };
+        let validate_payload = |height: u64, batch: BatchPayload| {
+            let payload = Payload::new(
+                ic_crypto::crypto_hash,
+                BlockPayload::Data(DataPayload {
+                    batch,
+                    dealings: Dealings::new_empty(Height::from(0)),
+                    ecdsa: None,
+                }),
+            );
+            payload_builder.validate_payload(Height::from(height), &payload, &[], &context)
+        };
+
+        let payload = get_payload(1);
+        assert_eq!(payload.ingress.message_count(), 3);
+        assert!(payload.xnet.stream_slices.is_empty());
+        assert!(validate_payload(1, payload.clone()).is_ok());
+
+        // The same scenario builds the same payloads.
+        let other_payload_builder =
+            scenario.build(subnet_id, registry, MetricsRegistry::new(), no_op_logger());
+        assert_eq!(
+            other_payload_builder.get_payload(
+                Height::from(1),
+                &parent_hash,
+                &[],
+                &context,
+                &subnet_records
+            ),
+            payload
+        );
+
+        let payload = get_payload(2);
+        assert!(payload.ingress.is_empty());
+        assert_eq!(payload.xnet.stream_slices.len(), 1);
+        assert!(validate_payload(2, payload).is_ok());
+
+        let payload = get_payload(3);
+        assert_eq!(payload.ingress.message_count(), 1);
+        assert_matches!(
+            validate_payload(3, payload),
+            Err(ValidationError::Permanent(
+                PayloadPermanentError::IngressPayloadValidationError(_)
+            ))
+        );
+
+        assert!(get_payload(4).is_empty());
+    }
+}
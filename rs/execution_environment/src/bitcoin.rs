@@ -1,6 +1,10 @@
 use crate::util::candid_error_to_user_error;
 use candid::Encode;
 use ic_btc_canister::state::State as BitcoinCanisterState;
+use ic_btc_types::{
+    compact::GetUtxosCompactResponse,
+    cost::{fees, send_transaction_cost},
+};
 use ic_error_types::{ErrorCode, UserError};
 use ic_ic00_types::{
     BitcoinGetBalanceArgs, BitcoinGetCurrentFeePercentilesArgs, BitcoinGetUtxosArgs,
@@ -17,23 +21,18 @@ use ic_types::Cycles;
 // does not support `number_of_transactions` multiple values.
 const NUMBER_OF_TRANSACTIONS_FOR_CALCULATING_FEES: u32 = 10_000;
 
-const GET_BALANCE_FEE: Cycles = Cycles::new(100_000_000);
-const GET_UTXOS_FEE: Cycles = Cycles::new(100_000_000);
-const GET_CURRENT_FEE_PERCENTILES_FEE: Cycles = Cycles::new(100_000_000);
-const SEND_TRANSACTION_FEE_BASE: Cycles = Cycles::new(5_000_000_000);
-const SEND_TRANSACTION_FEE_PER_BYTE: Cycles = Cycles::new(20_000_000);
-
 /// Handles a `bitcoin_get_balance` request.
 pub fn get_balance(
     payload: &[u8],
     state: &mut ReplicatedState,
     payment: Cycles,
 ) -> (Result<Vec<u8>, UserError>, Cycles) {
+    let fee = Cycles::new(fees(state.bitcoin().network()).get_balance);
     execute_bitcoin_endpoint(
         payload,
         state,
         payment,
-        GET_BALANCE_FEE,
+        fee,
         |payload: &[u8], state: &mut ReplicatedState| -> Result<Vec<u8>, UserError> {
             match BitcoinGetBalanceArgs::decode(payload) {
                 Err(err) => Err(candid_error_to_user_error(err)),
@@ -70,11 +69,12 @@ pub fn get_utxos(
     state: &mut ReplicatedState,
     payment: Cycles,
 ) -> (Result<Vec<u8>, UserError>, Cycles) {
+    let fee = Cycles::new(fees(state.bitcoin().network()).get_utxos);
     execute_bitcoin_endpoint(
         payload,
         state,
         payment,
-        GET_UTXOS_FEE,
+        fee,
         |payload: &[u8], state: &mut ReplicatedState| -> Result<Vec<u8>, UserError> {
             match BitcoinGetUtxosArgs::decode(payload) {
                 Err(err) => Err(candid_error_to_user_error(err)),
@@ -91,7 +91,13 @@ pub fn get_utxos(
                     state.put_bitcoin_state(btc_canister_state.into());
 
                     utxos_response
-                        .map(|response| Encode!(&response).unwrap())
+                        .map(|response| {
+                            if args.compact.unwrap_or(false) {
+                                Encode!(&GetUtxosCompactResponse::from(response)).unwrap()
+                            } else {
+                                Encode!(&response).unwrap()
+                            }
+                        })
                         .map_err(|err| {
                             UserError::new(
                                 ErrorCode::CanisterRejectedMessage,
@@ -110,11 +116,12 @@ pub fn get_current_fee_percentiles(
     state: &mut ReplicatedState,
     payment: Cycles,
 ) -> (Result<Vec<u8>, UserError>, Cycles) {
+    let fee = Cycles::new(fees(state.bitcoin().network()).get_current_fee_percentiles);
     execute_bitcoin_endpoint(
         payload,
         state,
         payment,
-        GET_CURRENT_FEE_PERCENTILES_FEE,
+        fee,
         |payload: &[u8], state: &mut ReplicatedState| -> Result<Vec<u8>, UserError> {
             match BitcoinGetCurrentFeePercentilesArgs::decode(payload) {
                 Err(err) => Err(candid_error_to_user_error(err)),
@@ -143,19 +150,19 @@ pub fn send_transaction(
     state: &mut ReplicatedState,
     payment: Cycles,
 ) -> (Result<Vec<u8>, UserError>, Cycles) {
+    let network = state.bitcoin().network();
     let args = match BitcoinSendTransactionArgs::decode(payload) {
         Err(err) => {
             // Failed to parse payload. Charge the base fee and return.
             return (
                 Err(candid_error_to_user_error(err)),
-                payment - SEND_TRANSACTION_FEE_BASE,
+                payment - Cycles::new(fees(network).send_transaction_base),
             );
         }
         Ok(args) => args,
     };
 
-    let fee =
-        SEND_TRANSACTION_FEE_BASE + SEND_TRANSACTION_FEE_PER_BYTE * args.transaction.len() as u64;
+    let fee = Cycles::new(send_transaction_cost(network, args.transaction.len()));
 
     execute_bitcoin_endpoint(
         payload,
@@ -4,8 +4,8 @@ use bitcoin::{
 use candid::Encode;
 use ic_btc_test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder};
 use ic_btc_types::{
-    GetUtxosResponse, NetworkInRequest as BitcoinNetwork, OutPoint, Satoshi, Utxo,
-    UtxosFilterInRequest,
+    compact::GetUtxosCompactResponse, GetUtxosResponse, NetworkInRequest as BitcoinNetwork,
+    OutPoint, Satoshi, Utxo, UtxosFilterInRequest,
 };
 use ic_ic00_types::{
     BitcoinGetBalanceArgs, BitcoinGetCurrentFeePercentilesArgs, BitcoinGetUtxosArgs,
@@ -339,6 +339,7 @@ fn fake_get_utxos_args() -> BitcoinGetUtxosArgs {
         address: random_p2pkh_address(Network::Testnet).to_string(),
         network: BitcoinNetwork::Testnet,
         filter: None,
+        compact: None,
     }
 }
 
@@ -460,11 +461,14 @@ fn get_utxos_succeeds() {
                             vout: 0
                         },
                         value: 1000,
-                        height: 0,
+                        height: 0.into(),
                     }],
                     tip_block_hash: block_0.block_hash().to_vec(),
                     tip_height: 0,
                     next_page: None,
+                    stability_count: Some(0),
+                    total_count: Some(1),
+                    has_more: Some(false),
                 })
                 .unwrap(),
             ),
@@ -472,6 +476,53 @@ fn get_utxos_succeeds() {
     }
 }
 
+#[test]
+fn get_utxos_replies_compact_response_if_requested() {
+    let address = random_p2pkh_address(Network::Testnet);
+    let coinbase_tx = TransactionBuilder::coinbase()
+        .with_output(&address, 1000)
+        .build();
+    let block_0 = BlockBuilder::genesis()
+        .with_transaction(coinbase_tx.clone())
+        .build();
+
+    execute_check_payload_and_refund(
+        BitcoinState::from(ic_btc_canister::state::State::new(
+            2,
+            Network::Testnet,
+            block_0.clone(),
+        )),
+        Method::BitcoinGetUtxos,
+        BitcoinGetUtxosArgs {
+            address: address.to_string(),
+            compact: Some(true),
+            ..fake_get_utxos_args()
+        }
+        .encode(),
+        Cycles::new(100_000_000),
+        Cycles::zero(),
+        Payload::Data(
+            Encode!(&GetUtxosCompactResponse::from(GetUtxosResponse {
+                utxos: vec![Utxo {
+                    outpoint: OutPoint {
+                        txid: coinbase_tx.txid().to_vec(),
+                        vout: 0
+                    },
+                    value: 1000,
+                    height: 0.into(),
+                }],
+                tip_block_hash: block_0.block_hash().to_vec(),
+                tip_height: 0,
+                next_page: None,
+                stability_count: Some(0),
+                total_count: Some(1),
+                has_more: Some(false),
+            }))
+            .unwrap(),
+        ),
+    );
+}
+
 fn fake_get_current_fee_percentiles_args() -> BitcoinGetCurrentFeePercentilesArgs {
     BitcoinGetCurrentFeePercentilesArgs {
         network: BitcoinNetwork::Testnet,
@@ -822,10 +873,9 @@ fn send_transaction_cycles_charging() {
             );
 
             assert_eq!(response.refund, expected_refund); // Refund the rest of cycles left.
-            assert_eq!(
-                get_reject_message(&response),
-                "bitcoin_send_transaction failed: Can't deserialize transaction because it's malformed.".to_string()
-            );
+            assert!(get_reject_message(&response).starts_with(
+                "bitcoin_send_transaction failed: Can't deserialize transaction because it's malformed at byte "
+            ));
         }
     }
 }
@@ -845,7 +895,7 @@ fn send_transaction_malformed_transaction() {
         .encode(),
         payment + expected_refund,
         expected_refund,
-        "bitcoin_send_transaction failed: Can't deserialize transaction because it's malformed.",
+        "bitcoin_send_transaction failed: Can't deserialize transaction because it's malformed at byte 3: unexpected end of transaction.",
     );
 }
 
@@ -1,6 +1,6 @@
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 // Build reproducibility. askama adds a include_bytes! call when it's generating
 // a template impl so that rustc will recompile the module when the file changes
@@ -12,8 +12,8 @@ use std::path::PathBuf;
 // inconsistency is introduced.
 fn main() {
     println!("cargo:rerun-if-changed=templates/dashboard.html");
-    let mut f = File::create(PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("dashboard.rs"))
-        .unwrap();
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let mut f = File::create(out_dir.join("dashboard.rs")).unwrap();
     f.write_all(
         format!(
             r#"
@@ -35,4 +35,40 @@ struct Dashboard<'a> {{
         .as_bytes(),
     )
     .unwrap();
+    write_dashboard_assets(&out_dir);
+}
+
+// Embeds the static assets of the dashboard, served under `/_/assets`, the
+// same way as the template.
+fn write_dashboard_assets(out_dir: &Path) {
+    println!("cargo:rerun-if-changed=templates/assets");
+    let mut names: Vec<String> = std::fs::read_dir("templates/assets")
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    let assets: String = names
+        .iter()
+        .map(|name| {
+            let content_type = match Path::new(name).extension().and_then(|ext| ext.to_str()) {
+                Some("css") => "text/css; charset=utf-8",
+                Some("js") => "text/javascript; charset=utf-8",
+                _ => panic!("Unsupported dashboard asset {}.", name),
+            };
+            let content = std::fs::read(Path::new("templates/assets").join(name)).unwrap();
+            format!(
+                "    DashboardAsset {{ name: {:?}, content_type: {:?}, content: &{:?} }},\n",
+                name, content_type, content
+            )
+        })
+        .collect();
+    let mut f = File::create(out_dir.join("dashboard_assets.rs")).unwrap();
+    f.write_all(
+        format!(
+            "const DASHBOARD_ASSETS: &[DashboardAsset] = &[\n{}];\n",
+            assets
+        )
+        .as_bytes(),
+    )
+    .unwrap();
 }
@@ -0,0 +1,15 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ic_http_handler::fuzzing::parse_delegation_response;
+
+/*
+Parse arbitrary bytes as the response of an NNS node to the request for the
+delegation of a subnet, down to the labeled tree of its certificate. The
+response is not trusted until the certificate is verified, so parsing must
+neither panic nor exhaust memory or the stack, whatever the response.
+*/
+
+fuzz_target!(|data: &[u8]| {
+    parse_delegation_response(data);
+});
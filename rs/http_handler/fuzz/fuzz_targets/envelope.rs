@@ -0,0 +1,32 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ic_types::messages::{
+    HttpCallContent, HttpQueryContent, HttpReadStateContent, HttpRequest, HttpRequestEnvelope,
+    ReadState, SignedIngress, SignedRequestBytes, UserQuery,
+};
+use std::convert::TryFrom;
+
+/*
+Deserialize arbitrary bytes as the CBOR envelopes accepted by the call, query
+and read_state endpoints, and run the structural validation the http handler
+does before verifying signatures. Decoding must fail gracefully, never panic.
+*/
+
+fuzz_target!(|data: &[u8]| {
+    let bytes = SignedRequestBytes::from(data.to_vec());
+
+    let _ = SignedIngress::try_from(bytes.clone());
+
+    if let Ok(envelope) = <HttpRequestEnvelope<HttpQueryContent>>::try_from(&bytes) {
+        let _ = HttpRequest::<UserQuery>::try_from(envelope);
+    }
+
+    if let Ok(envelope) = <HttpRequestEnvelope<HttpReadStateContent>>::try_from(&bytes) {
+        let _ = HttpRequest::<ReadState>::try_from(envelope);
+    }
+
+    if let Ok(envelope) = <HttpRequestEnvelope<HttpCallContent>>::try_from(&bytes) {
+        let _ = SignedIngress::try_from(envelope);
+    }
+});
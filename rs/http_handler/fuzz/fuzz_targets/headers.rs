@@ -0,0 +1,31 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ic_http_handler::fuzzing::serve_raw_request;
+use ic_registry_client_fake::FakeRegistryClient;
+use ic_registry_proto_data_provider::ProtoRegistryDataProvider;
+use ic_test_utilities::state_manager::FakeStateManager;
+use std::sync::Arc;
+use tokio::runtime::{Builder, Runtime};
+
+/*
+Send an HTTP/1.1 status request with arbitrary bytes as its header block. This
+focuses on header parsing and the header count and size limits, which the
+router target rarely reaches with large or numerous headers.
+*/
+
+thread_local! {
+    static RUNTIME: Runtime = Builder::new_current_thread().enable_all().build().unwrap();
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut request = b"GET /api/v2/status HTTP/1.1\r\n".to_vec();
+    request.extend_from_slice(data);
+    request.extend_from_slice(b"\r\n\r\n");
+
+    let registry_client = Arc::new(FakeRegistryClient::new(Arc::new(
+        ProtoRegistryDataProvider::new(),
+    )));
+    let state_reader = Arc::new(FakeStateManager::new());
+    RUNTIME.with(|rt| rt.block_on(serve_raw_request(registry_client, state_reader, &request)));
+});
@@ -0,0 +1,27 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ic_http_handler::fuzzing::serve_raw_request;
+use ic_registry_client_fake::FakeRegistryClient;
+use ic_registry_proto_data_provider::ProtoRegistryDataProvider;
+use ic_test_utilities::state_manager::FakeStateManager;
+use std::sync::Arc;
+use tokio::runtime::{Builder, Runtime};
+
+/*
+Feed raw bytes to the HTTP server as if received on a plaintext connection.
+This covers HTTP/1.1 and HTTP/2 parsing, the router, and receiving request
+bodies. The endpoint services are stubbed out, so no replica is needed.
+*/
+
+thread_local! {
+    static RUNTIME: Runtime = Builder::new_current_thread().enable_all().build().unwrap();
+}
+
+fuzz_target!(|data: &[u8]| {
+    let registry_client = Arc::new(FakeRegistryClient::new(Arc::new(
+        ProtoRegistryDataProvider::new(),
+    )));
+    let state_reader = Arc::new(FakeStateManager::new());
+    RUNTIME.with(|rt| rt.block_on(serve_raw_request(registry_client, state_reader, data)));
+});
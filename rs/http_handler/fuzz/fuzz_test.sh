@@ -0,0 +1,14 @@
+#!/usr/bin/env bash
+
+set -euo pipefail
+
+# Demo how to execute tests. The seeds are requests captured from agent
+# traffic; copy them to a scratch corpus directory so they aren't modified.
+# mkdir -p corpus/router && cp seeds/router/* corpus/router/
+# rs/http_handler/fuzz/router -max_total_time=15 corpus/router
+# mkdir -p corpus/envelope && cp seeds/envelope/* corpus/envelope/
+# rs/http_handler/fuzz/envelope -max_total_time=15 corpus/envelope
+# mkdir -p corpus/headers && cp seeds/headers/* corpus/headers/
+# rs/http_handler/fuzz/headers -max_total_time=15 corpus/headers
+# mkdir -p corpus/delegation && cp seeds/delegation/* corpus/delegation/
+# rs/http_handler/fuzz/delegation -max_total_time=15 corpus/delegation
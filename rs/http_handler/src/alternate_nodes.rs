@@ -0,0 +1,162 @@
+//! Failover hints for clients of an overloaded or unhealthy replica.
+//!
+//! Responses with a `503 Service Unavailable` or `429 Too Many Requests`
+//! carry an `x-ic-alternate-nodes` header listing the HTTP endpoints of a few
+//! other nodes of the subnet, so that agents can retry elsewhere without
+//! looking up the topology of the subnet in the registry themselves.
+//!
+//! Each node lists the nodes following it in the order of their ids, so that
+//! the clients of different overloaded nodes are spread over different nodes
+//! rather than all sent to the same ones.
+
+use hyper::{header::HeaderValue, Body, Response, StatusCode};
+use ic_interfaces::registry::RegistryClient;
+use ic_registry_client_helpers::{node::NodeRegistry, subnet::SubnetRegistry};
+use ic_types::{NodeId, RegistryVersion, SubnetId};
+use std::{
+    convert::TryFrom,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+pub(crate) const X_IC_ALTERNATE_NODES: &str = "x-ic-alternate-nodes";
+
+/// Adds the `x-ic-alternate-nodes` header to responses.
+pub(crate) struct AlternateNodes {
+    registry_client: Arc<dyn RegistryClient>,
+    subnet_id: SubnetId,
+    node_id: NodeId,
+    max_nodes: usize,
+    // The header value at the registry version it was looked up at, so that
+    // the registry is not read again for every rejected request.
+    cached: Mutex<Option<(RegistryVersion, Option<HeaderValue>)>>,
+}
+
+impl AlternateNodes {
+    /// Lists up to `max_nodes` nodes other than `node_id`, none if zero.
+    pub(crate) fn new(
+        registry_client: Arc<dyn RegistryClient>,
+        subnet_id: SubnetId,
+        node_id: NodeId,
+        max_nodes: usize,
+    ) -> Self {
+        Self {
+            registry_client,
+            subnet_id,
+            node_id,
+            max_nodes,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Adds the header to `response` if it tells the client that this node is
+    /// overloaded or unhealthy.
+    pub(crate) fn add_header(&self, response: &mut Response<Body>) {
+        if self.max_nodes == 0
+            || !matches!(
+                response.status(),
+                StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS
+            )
+        {
+            return;
+        }
+        if let Some(value) = self.header_value() {
+            response.headers_mut().insert(X_IC_ALTERNATE_NODES, value);
+        }
+    }
+
+    fn header_value(&self) -> Option<HeaderValue> {
+        let version = self.registry_client.get_latest_version();
+        let mut cached = self.cached.lock().unwrap();
+        if let Some((cached_version, value)) = cached.as_ref() {
+            if *cached_version == version {
+                return value.clone();
+            }
+        }
+        let value = self.look_up(version);
+        *cached = Some((version, value.clone()));
+        value
+    }
+
+    // Looks up the endpoints of the alternate nodes at `version`. Nodes
+    // without a valid HTTP endpoint are skipped.
+    fn look_up(&self, version: RegistryVersion) -> Option<HeaderValue> {
+        let node_ids = self
+            .registry_client
+            .get_node_ids_on_subnet(self.subnet_id, version)
+            .ok()
+            .flatten()?;
+        let endpoints: Vec<_> = following(node_ids, &self.node_id)
+            .into_iter()
+            .filter_map(|node_id| {
+                let http = self
+                    .registry_client
+                    .get_transport_info(node_id, version)
+                    .ok()
+                    .flatten()?
+                    .http?;
+                endpoint_url(&http.ip_addr, http.port)
+            })
+            .take(self.max_nodes)
+            .collect();
+        if endpoints.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&endpoints.join(", ")).ok()
+    }
+}
+
+// Returns the ids other than `id`, starting with those following it in
+// order and wrapping around.
+fn following<T: Ord>(mut ids: Vec<T>, id: &T) -> Vec<T> {
+    ids.sort();
+    ids.dedup();
+    let start = match ids.binary_search(id) {
+        Ok(index) => {
+            ids.remove(index);
+            index
+        }
+        Err(index) => index,
+    };
+    let len = ids.len();
+    ids.rotate_left(start % len.max(1));
+    ids
+}
+
+// The URL of an HTTP endpoint in the registry, whose IPv6 addresses are not
+// enclosed in brackets.
+fn endpoint_url(ip_addr: &str, port: u32) -> Option<String> {
+    let ip_addr = ip_addr.parse::<IpAddr>().ok()?;
+    let port = u16::try_from(port).ok()?;
+    Some(format!("http://{}", SocketAddr::new(ip_addr, port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn following_nodes_wrap_around() {
+        assert_eq!(following(vec![4, 1, 3, 2], &2), vec![3, 4, 1]);
+        assert_eq!(following(vec![4, 1, 3, 2], &4), vec![1, 2, 3]);
+        assert_eq!(following(vec![1, 2, 3], &1), vec![2, 3]);
+        // A node missing from the subnet lists all of its nodes.
+        assert_eq!(following(vec![1, 3, 5], &4), vec![5, 1, 3]);
+        assert_eq!(following(vec![1, 3, 5], &6), vec![1, 3, 5]);
+        assert_eq!(following(vec![1], &1), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn endpoint_urls() {
+        assert_eq!(
+            endpoint_url("192.0.2.1", 8080).as_deref(),
+            Some("http://192.0.2.1:8080")
+        );
+        assert_eq!(
+            endpoint_url("2001:db8::1", 8080).as_deref(),
+            Some("http://[2001:db8::1]:8080")
+        );
+        assert_eq!(endpoint_url("not an ip", 8080), None);
+        assert_eq!(endpoint_url("192.0.2.1", 65536), None);
+    }
+}
@@ -1,34 +1,237 @@
+//! Module that receives the body of a request, enforcing size and time limits.
 use crate::{
     common::{make_plaintext_response, poll_ready},
+    metrics::HttpHandlerMetrics,
+    types::ApiReqType,
     MAX_REQUEST_RECEIVE_DURATION, MAX_REQUEST_SIZE_BYTES,
 };
 use byte_unit::Byte;
-use hyper::{Body, Response, StatusCode};
-use ic_async_utils::{receive_body, BodyReceiveError};
-use std::convert::Infallible;
+use futures_util::{stream, StreamExt};
+use hyper::{body::HttpBody, header::HeaderValue, Body, Response, StatusCode};
+use ic_crypto_sha::Sha256;
+use std::convert::{Infallible, TryFrom};
+use std::error::Error;
+use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::time::Duration;
+use strum::IntoStaticStr;
+use tokio::time::timeout;
 use tower::{BoxError, Layer, Service};
 
+/// The reason a request body was rejected.
+#[derive(Debug, PartialEq, Eq, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum BodyError {
+    /// The body exceeds the size limit. `observed` is the number of bytes seen
+    /// when the limit was hit (or the `Content-Length`), not necessarily the
+    /// full size of the body.
+    TooLarge { limit: u64, observed: u64 },
+    /// The body wasn't received in time.
+    Timeout { elapsed: Duration },
+    /// The body couldn't be read from the connection.
+    Malformed(String),
+    /// The SHA-256 digest of the body doesn't match its `Content-Digest`.
+    DigestMismatch(DigestMismatch),
+}
+
+impl BodyError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            BodyError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            BodyError::Timeout { .. } => StatusCode::REQUEST_TIMEOUT,
+            BodyError::Malformed(_) => StatusCode::BAD_REQUEST,
+            BodyError::DigestMismatch(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::TooLarge { limit, observed } => write!(
+                f,
+                "Request body of at least {} bytes exceeds the limit of {} bytes.",
+                observed, limit
+            ),
+            BodyError::Timeout { elapsed } => write!(
+                f,
+                "Timeout of {}s reached while receiving the request body.",
+                elapsed.as_secs()
+            ),
+            BodyError::Malformed(err) => {
+                write!(
+                    f,
+                    "Failed to read the request body from the connection: {}",
+                    err
+                )
+            }
+            BodyError::DigestMismatch(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// The name of the header carrying the digest of the request body, as
+/// specified in RFC 9530.
+pub(crate) const CONTENT_DIGEST: &str = "content-digest";
+
+/// The SHA-256 digest of a received body differs from the expected one.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct DigestMismatch {
+    expected: [u8; 32],
+    observed: [u8; 32],
+}
+
+impl fmt::Display for DigestMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Request body has the SHA-256 digest :{}:, but the Content-Digest is :{}:.",
+            base64::encode(self.observed),
+            base64::encode(self.expected)
+        )
+    }
+}
+
+impl Error for DigestMismatch {}
+
+/// Parses a `Content-Digest` header, e.g. `sha-256=:<base64>:`, and returns
+/// the SHA-256 digest it lists. Digests with other algorithms are ignored,
+/// so `None` is returned if there is no SHA-256 digest.
+pub(crate) fn parse_content_digest(value: &HeaderValue) -> Result<Option<[u8; 32]>, String> {
+    let value = value
+        .to_str()
+        .map_err(|_| "Content-Digest is not ASCII.".to_string())?;
+    for member in value.split(',') {
+        let (algorithm, digest) = match member.split_once('=') {
+            Some((algorithm, digest)) => (algorithm.trim(), digest.trim()),
+            None => return Err(format!("Malformed Content-Digest member {:?}.", member)),
+        };
+        if !algorithm.eq_ignore_ascii_case("sha-256") {
+            continue;
+        }
+        return digest
+            .strip_prefix(':')
+            .and_then(|digest| digest.strip_suffix(':'))
+            .and_then(|digest| base64::decode(digest).ok())
+            .and_then(|digest| <[u8; 32]>::try_from(digest.as_slice()).ok())
+            .map(Some)
+            .ok_or_else(|| format!("Malformed SHA-256 Content-Digest {:?}.", digest));
+    }
+    Ok(None)
+}
+
+/// Wraps `body` so that its SHA-256 digest is computed while it is streamed,
+/// and a [`DigestMismatch`] error is yielded after the last chunk if it
+/// differs from `expected`. `receive_body` turns that error into
+/// `BodyError::DigestMismatch`.
+///
+/// The wrapped body has no size hint, as `Body::wrap_stream` drops it, so the
+/// `Content-Length` of `body` is to be checked with [`check_content_length`]
+/// beforehand.
+pub(crate) fn verify_content_digest(body: Body, expected: [u8; 32]) -> Body {
+    let chunks = stream::unfold(
+        (body, Some(Sha256::new())),
+        move |(mut body, hasher)| async move {
+            let mut hasher = hasher?;
+            match body.next().await {
+                Some(Ok(bytes)) => {
+                    hasher.write(&bytes);
+                    Some((Ok(bytes), (body, Some(hasher))))
+                }
+                Some(Err(err)) => Some((Err(BoxError::from(err)), (body, None))),
+                None => {
+                    let observed = hasher.finish();
+                    if observed == expected {
+                        return None;
+                    }
+                    let err = DigestMismatch { expected, observed };
+                    Some((Err(BoxError::from(err)), (body, None)))
+                }
+            }
+        },
+    );
+    Body::wrap_stream(chunks)
+}
+
+// Maps an error reading the body to a `BodyError`, recovering the digest
+// mismatches yielded by `verify_content_digest`.
+fn map_chunk_error(err: hyper::Error) -> BodyError {
+    let message = err.to_string();
+    match err
+        .into_cause()
+        .map(|cause| cause.downcast::<DigestMismatch>())
+    {
+        Some(Ok(mismatch)) => BodyError::DigestMismatch(*mismatch),
+        _ => BodyError::Malformed(message),
+    }
+}
+
+/// Fails if the `Content-Length` of `body`, i.e. the lower bound of its size
+/// hint, exceeds `max_request_body_size`.
+pub(crate) fn check_content_length(
+    body: &Body,
+    max_request_body_size: Byte,
+) -> Result<(), BodyError> {
+    let limit = max_request_body_size.get_bytes() as u64;
+    let observed = body.size_hint().lower();
+    if observed > limit {
+        return Err(BodyError::TooLarge { limit, observed });
+    }
+    Ok(())
+}
+
+/// Receives `body`, failing if it is larger than `max_request_body_size` or
+/// takes longer than `max_request_receive_duration` to arrive.
+pub(crate) async fn receive_body(
+    mut body: Body,
+    max_request_receive_duration: Duration,
+    max_request_body_size: Byte,
+) -> Result<Vec<u8>, BodyError> {
+    let limit = max_request_body_size.get_bytes() as u64;
+    let receive = async move {
+        check_content_length(&body, max_request_body_size)?;
+        let mut received_body = Vec::<u8>::with_capacity(body.size_hint().lower() as usize);
+        while let Some(chunk) = body.next().await {
+            let bytes = chunk.map_err(map_chunk_error)?;
+            let observed = (received_body.len() + bytes.len()) as u64;
+            if observed > limit {
+                return Err(BodyError::TooLarge { limit, observed });
+            }
+            received_body.extend_from_slice(&bytes);
+        }
+        Ok(received_body)
+    };
+    match timeout(max_request_receive_duration, receive).await {
+        Ok(res) => res,
+        Err(_) => Err(BodyError::Timeout {
+            elapsed: max_request_receive_duration,
+        }),
+    }
+}
+
 pub(crate) struct BodyReceiverLayer {
+    metrics: HttpHandlerMetrics,
+    api_req_type: ApiReqType,
     max_request_receive_duration: Duration,
     max_request_body_size: Byte,
 }
 
 impl BodyReceiverLayer {
-    pub(crate) fn new(max_request_receive_duration: Duration, max_request_body_size: Byte) -> Self {
+    pub(crate) fn new(metrics: HttpHandlerMetrics, api_req_type: ApiReqType) -> Self {
         Self {
-            max_request_receive_duration,
-            max_request_body_size,
+            metrics,
+            api_req_type,
+            max_request_receive_duration: MAX_REQUEST_RECEIVE_DURATION,
+            max_request_body_size: MAX_REQUEST_SIZE_BYTES,
         }
     }
-}
 
-impl Default for BodyReceiverLayer {
-    fn default() -> Self {
-        BodyReceiverLayer::new(MAX_REQUEST_RECEIVE_DURATION, MAX_REQUEST_SIZE_BYTES)
+    /// Overrides the default maximum request body size.
+    pub(crate) fn with_max_request_body_size(mut self, max_request_body_size: Byte) -> Self {
+        self.max_request_body_size = max_request_body_size;
+        self
     }
 }
 
@@ -37,6 +240,8 @@ impl<S> Layer<S> for BodyReceiverLayer {
 
     fn layer(&self, inner: S) -> Self::Service {
         BodyReceiverService {
+            metrics: self.metrics.clone(),
+            api_req_type: self.api_req_type,
             max_request_receive_duration: self.max_request_receive_duration,
             max_request_body_size_bytes: self.max_request_body_size,
             inner,
@@ -46,6 +251,8 @@ impl<S> Layer<S> for BodyReceiverLayer {
 
 #[derive(Clone)]
 pub(crate) struct BodyReceiverService<S> {
+    metrics: HttpHandlerMetrics,
+    api_req_type: ApiReqType,
     max_request_receive_duration: Duration,
     max_request_body_size_bytes: Byte,
     inner: S,
@@ -89,6 +296,8 @@ where
         //  s2.call()
         let mut inner = std::mem::replace(&mut self.inner, inner);
 
+        let metrics = self.metrics.clone();
+        let api_req_type = self.api_req_type;
         let max_request_receive_duration = self.max_request_receive_duration;
         let max_request_body_size_bytes = self.max_request_body_size_bytes;
         Box::pin(async move {
@@ -99,19 +308,166 @@ where
             )
             .await
             {
-                Err(err) => match err {
-                    BodyReceiveError::TooLarge(e) => {
-                        Ok(make_plaintext_response(StatusCode::PAYLOAD_TOO_LARGE, e))
-                    }
-                    BodyReceiveError::Timeout(e) => {
-                        Ok(make_plaintext_response(StatusCode::REQUEST_TIMEOUT, e))
-                    }
-                    BodyReceiveError::Unavailable(e) => {
-                        Ok(make_plaintext_response(StatusCode::BAD_REQUEST, e))
-                    }
-                },
+                Err(err) => {
+                    metrics.observe_body_error(api_req_type, &err);
+                    Ok(make_plaintext_response(err.status(), err.to_string()))
+                }
                 Ok(body) => Ok(inner.call(body).await.expect("Can't panic on infallible.")),
             }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::Bytes;
+
+    #[tokio::test]
+    async fn body_within_limit_is_received() {
+        let body = receive_body(
+            Body::from(vec![1; 10]),
+            Duration::from_secs(1),
+            Byte::from_bytes(10),
+        )
+        .await;
+        assert_eq!(body, Ok(vec![1; 10]));
+    }
+
+    #[tokio::test]
+    async fn too_large_body_reports_limit() {
+        let err = receive_body(
+            Body::from(vec![1; 11]),
+            Duration::from_secs(1),
+            Byte::from_bytes(10),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err,
+            BodyError::TooLarge {
+                limit: 10,
+                observed: 11
+            }
+        );
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(err.to_string().contains("limit of 10 bytes"));
+    }
+
+    #[test]
+    fn content_length_is_checked_before_the_digest_is_verified() {
+        let content = vec![1; 11];
+        let digest = Sha256::hash(&content);
+        let body = Body::from(content);
+        assert_eq!(
+            check_content_length(&body, Byte::from_bytes(10)),
+            Err(BodyError::TooLarge {
+                limit: 10,
+                observed: 11
+            })
+        );
+        assert_eq!(check_content_length(&body, Byte::from_bytes(11)), Ok(()));
+        // The size hint is lost once the body is wrapped.
+        let body = verify_content_digest(body, digest);
+        assert_eq!(check_content_length(&body, Byte::from_bytes(10)), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn slow_body_times_out() {
+        let (mut sender, body) = Body::channel();
+        sender.send_data(Bytes::from_static(b"a")).await.unwrap();
+        let err = receive_body(body, Duration::from_millis(10), Byte::from_bytes(10))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            BodyError::Timeout {
+                elapsed: Duration::from_millis(10)
+            }
+        );
+        assert_eq!(err.status(), StatusCode::REQUEST_TIMEOUT);
+        // Keep the sender alive until the timeout fired.
+        drop(sender);
+    }
+
+    #[test]
+    fn test_label_values_do_not_change() {
+        type StaticStr = &'static str;
+        assert_eq!(
+            StaticStr::from(&BodyError::TooLarge {
+                limit: 0,
+                observed: 0
+            }),
+            "too_large"
+        );
+        assert_eq!(
+            StaticStr::from(&BodyError::Timeout {
+                elapsed: Duration::default()
+            }),
+            "timeout"
+        );
+        assert_eq!(
+            StaticStr::from(&BodyError::Malformed(String::new())),
+            "malformed"
+        );
+        assert_eq!(
+            StaticStr::from(&BodyError::DigestMismatch(DigestMismatch {
+                expected: [0; 32],
+                observed: [0; 32]
+            })),
+            "digest_mismatch"
+        );
+    }
+
+    #[tokio::test]
+    async fn body_digest_is_verified() {
+        let content = vec![1; 100];
+        let digest = Sha256::hash(&content);
+        let body = receive_body(
+            verify_content_digest(Body::from(content.clone()), digest),
+            Duration::from_secs(1),
+            Byte::from_bytes(100),
+        )
+        .await;
+        assert_eq!(body, Ok(content.clone()));
+
+        let err = receive_body(
+            verify_content_digest(Body::from(content), [0; 32]),
+            Duration::from_secs(1),
+            Byte::from_bytes(100),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err,
+            BodyError::DigestMismatch(DigestMismatch {
+                expected: [0; 32],
+                observed: digest
+            })
+        );
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn content_digest_is_parsed() {
+        let digest = Sha256::hash(b"body");
+        let header = |value: String| HeaderValue::from_str(&value).unwrap();
+        assert_eq!(
+            parse_content_digest(&header(format!("sha-256=:{}:", base64::encode(digest)))),
+            Ok(Some(digest))
+        );
+        assert_eq!(
+            parse_content_digest(&header(format!(
+                "sha-512=:AAAA:, SHA-256=:{}:",
+                base64::encode(digest)
+            ))),
+            Ok(Some(digest))
+        );
+        assert_eq!(
+            parse_content_digest(&header("sha-512=:AAAA:".into())),
+            Ok(None)
+        );
+        assert!(parse_content_digest(&header("sha-256=:AAAA:".into())).is_err());
+        assert!(parse_content_digest(&header("sha-256".into())).is_err());
+    }
+}
@@ -0,0 +1,369 @@
+//! Module that assembles the endpoint services and the routes of the HTTP
+//! handler.
+//!
+//! The call, query and read_state services only handle the received request
+//! body. The layers around them, i.e. panic containment, security headers,
+//! the body size limit, the concurrency limit and the timeout, are specified
+//! per request type in [`HttpHandlerBuilder::layers`], and applied by
+//! [`EndpointLayers`], which can be exercised with stub services. The canister
+//! info and subnet public key services are built per request, for the
+//! parameters of their path, and share the concurrency limit of
+//! [`HttpHandlerBuilder::concurrency_limit`] instead.
+use crate::{
+    body::BodyReceiverLayer, catch_panic::catch_panics, common::make_plaintext_response,
+    deprecate_routes, limits::LimitProfile, make_routes, metrics::HttpHandlerMetrics,
+    pprof::PprofAccess, read_state::CanisterInfoReader, routes::RouteTable,
+    security_headers::SecurityHeaders, subnet_public_key::SubnetPublicKeyReader, types::ApiReqType,
+    BaseEndpointService, EndpointService, EndpointServices, Handler,
+};
+use byte_unit::Byte;
+use hyper::StatusCode;
+use ic_config::http_handler::Config;
+use ic_logger::ReplicaLogger;
+use std::time::Duration;
+use tower::{
+    limit::concurrency::GlobalConcurrencyLimitLayer, service_fn, util::BoxCloneService,
+    ServiceBuilder, ServiceExt,
+};
+
+/// The layers an endpoint service is wrapped in. From the outermost, panics
+/// are converted into `500 Internal Server Error` responses, the security
+/// headers are added to responses, the request body is received within its
+/// size limit, and the request is limited in concurrency and timed out.
+#[derive(Clone, Copy)]
+pub(crate) struct EndpointLayers {
+    api_req_type: ApiReqType,
+    max_request_body_size: Option<Byte>,
+    max_concurrent_requests: Option<usize>,
+    timeout: Option<Duration>,
+    security_headers: bool,
+}
+
+impl EndpointLayers {
+    /// Returns the layers that only contain panics, and receive bodies
+    /// within the default size limit.
+    pub(crate) fn new(api_req_type: ApiReqType) -> Self {
+        Self {
+            api_req_type,
+            max_request_body_size: None,
+            max_concurrent_requests: None,
+            timeout: None,
+            security_headers: false,
+        }
+    }
+
+    /// Overrides the default maximum request body size.
+    pub(crate) fn with_max_request_body_size(mut self, max_request_body_size: Byte) -> Self {
+        self.max_request_body_size = Some(max_request_body_size);
+        self
+    }
+
+    /// Sheds requests beyond `max_concurrent_requests` served at once.
+    pub(crate) fn with_max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.max_concurrent_requests = Some(max_concurrent_requests);
+        self
+    }
+
+    /// Answers requests not served within `timeout` with `503 Service
+    /// Unavailable`. Requests are not timed out if `timeout` is `None`.
+    pub(crate) fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Adds the security headers to responses.
+    pub(crate) fn with_security_headers(mut self) -> Self {
+        self.security_headers = true;
+        self
+    }
+
+    /// Wraps `service` in all layers.
+    pub(crate) fn layer(
+        &self,
+        log: &ReplicaLogger,
+        metrics: &HttpHandlerMetrics,
+        security_headers: &SecurityHeaders,
+        service: BaseEndpointService,
+    ) -> EndpointService {
+        let mut service = service;
+        if let Some(timeout) = self.timeout {
+            service = with_timeout(service, timeout);
+        }
+        // Requests beyond the limit are shed by the router, which only calls
+        // ready services.
+        if let Some(max_concurrent_requests) = self.max_concurrent_requests {
+            service = BoxCloneService::new(
+                ServiceBuilder::new()
+                    .layer(GlobalConcurrencyLimitLayer::new(max_concurrent_requests))
+                    .service(service),
+            );
+        }
+        let mut body_receiver = BodyReceiverLayer::new(metrics.clone(), self.api_req_type);
+        if let Some(max_request_body_size) = self.max_request_body_size {
+            body_receiver = body_receiver.with_max_request_body_size(max_request_body_size);
+        }
+        let service =
+            BoxCloneService::new(ServiceBuilder::new().layer(body_receiver).service(service));
+        self.wrap(log, metrics, security_headers, service)
+    }
+
+    /// Wraps `service`, which receives request bodies itself, in the layers
+    /// outside of the body receiver only.
+    pub(crate) fn wrap(
+        &self,
+        log: &ReplicaLogger,
+        metrics: &HttpHandlerMetrics,
+        security_headers: &SecurityHeaders,
+        service: EndpointService,
+    ) -> EndpointService {
+        let service = if self.security_headers {
+            security_headers.layer(service)
+        } else {
+            service
+        };
+        catch_panics(log.clone(), metrics.clone(), self.api_req_type, service)
+    }
+}
+
+// Answers the requests not served by `service` within `timeout` with `503
+// Service Unavailable`, dropping them.
+fn with_timeout(service: BaseEndpointService, timeout: Duration) -> BaseEndpointService {
+    BoxCloneService::new(service_fn(move |body: Vec<u8>| {
+        let service = service.clone();
+        async move {
+            match tokio::time::timeout(timeout, service.oneshot(body)).await {
+                Ok(result) => result,
+                Err(_) => Ok(make_plaintext_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "The request timed out.".to_string(),
+                )),
+            }
+        }
+    }))
+}
+
+/// Assembles the endpoint services of the HTTP handler, applying the layers
+/// of their request type, and the routes serving them.
+pub(crate) struct HttpHandlerBuilder {
+    log: ReplicaLogger,
+    metrics: HttpHandlerMetrics,
+    config: Config,
+    limits: LimitProfile,
+    security_headers: SecurityHeaders,
+}
+
+impl HttpHandlerBuilder {
+    pub(crate) fn new(
+        log: ReplicaLogger,
+        metrics: HttpHandlerMetrics,
+        config: Config,
+        limits: LimitProfile,
+    ) -> Self {
+        let security_headers = SecurityHeaders::new(&config, &log);
+        Self {
+            log,
+            metrics,
+            config,
+            limits,
+            security_headers,
+        }
+    }
+
+    /// Returns the layers of the endpoint services of `api_req_type`.
+    pub(crate) fn layers(&self, api_req_type: ApiReqType) -> EndpointLayers {
+        let layers = EndpointLayers::new(api_req_type);
+        let timeout = self.config.request_timeout_seconds.map(Duration::from_secs);
+        match api_req_type {
+            ApiReqType::Call => layers
+                .with_max_request_body_size(self.limits.max_request_size_bytes_for(api_req_type)),
+            ApiReqType::Query => layers
+                .with_max_request_body_size(self.limits.max_request_size_bytes_for(api_req_type))
+                .with_timeout(timeout),
+            ApiReqType::ReadState => layers
+                .with_max_request_body_size(self.limits.max_request_size_bytes_for(api_req_type))
+                .with_max_concurrent_requests(self.limits.max_read_state_concurrent_requests)
+                .with_timeout(timeout),
+            // Served from the certified state, like `read_state` requests.
+            ApiReqType::CanisterInfo | ApiReqType::SubnetPublicKey => {
+                layers.with_max_concurrent_requests(self.limits.max_read_state_concurrent_requests)
+            }
+            ApiReqType::Status | ApiReqType::Dashboard => layers.with_security_headers(),
+            _ => layers,
+        }
+    }
+
+    /// Returns the concurrency limit of the requests of `api_req_type`, if
+    /// any, for the endpoints whose service is built per request, e.g. for
+    /// the parameters of its path. All services built with the returned
+    /// layer share the limit.
+    pub(crate) fn concurrency_limit(
+        &self,
+        api_req_type: ApiReqType,
+    ) -> Option<GlobalConcurrencyLimitLayer> {
+        self.layers(api_req_type)
+            .max_concurrent_requests
+            .map(GlobalConcurrencyLimitLayer::new)
+    }
+
+    /// Returns the endpoint service of `api_req_type`, given its base
+    /// service.
+    pub(crate) fn endpoint(
+        &self,
+        api_req_type: ApiReqType,
+        service: BaseEndpointService,
+    ) -> EndpointService {
+        self.layers(api_req_type)
+            .layer(&self.log, &self.metrics, &self.security_headers, service)
+    }
+
+    /// Returns the endpoint service of `api_req_type`, given a service that
+    /// receives request bodies itself.
+    pub(crate) fn wrap(
+        &self,
+        api_req_type: ApiReqType,
+        service: EndpointService,
+    ) -> EndpointService {
+        self.layers(api_req_type)
+            .wrap(&self.log, &self.metrics, &self.security_headers, service)
+    }
+
+    /// Returns the routes serving `services`, with the configured
+    /// deprecations.
+    pub(crate) fn routes(
+        &self,
+        services: EndpointServices,
+        canister_info: CanisterInfoReader,
+        subnet_public_key: SubnetPublicKeyReader,
+        pprof_access: PprofAccess,
+    ) -> RouteTable<Handler> {
+        let mut routes = make_routes(services, canister_info, subnet_public_key, pprof_access);
+        deprecate_routes(&self.log, &mut routes, &self.config.deprecated_routes);
+        routes
+    }
+
+    /// Returns the security headers added to the responses of the endpoints
+    /// that have them.
+    pub(crate) fn security_headers(&self) -> SecurityHeaders {
+        self.security_headers.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{header, Body, Response};
+    use ic_logger::replica_logger::no_op_logger;
+    use ic_metrics::MetricsRegistry;
+    use ic_registry_subnet_type::SubnetType;
+    use std::{convert::Infallible, future::Future, pin::Pin};
+
+    type StubFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+    // Replies with `200 OK` after `delay`.
+    fn stub_service(delay: Duration) -> BaseEndpointService {
+        BoxCloneService::new(service_fn(move |_body: Vec<u8>| {
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                Ok(Response::new(Body::empty()))
+            }) as StubFuture
+        }))
+    }
+
+    fn builder() -> HttpHandlerBuilder {
+        HttpHandlerBuilder::new(
+            no_op_logger(),
+            HttpHandlerMetrics::new(&MetricsRegistry::new()),
+            Config {
+                request_timeout_seconds: Some(1),
+                ..Config::default()
+            },
+            LimitProfile::for_subnet_type(SubnetType::Application),
+        )
+    }
+
+    async fn status(service: EndpointService, body: Vec<u8>) -> StatusCode {
+        service.oneshot(Body::from(body)).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn bodies_are_received_within_their_size_limit() {
+        let builder = builder();
+        let layers =
+            EndpointLayers::new(ApiReqType::Call).with_max_request_body_size(Byte::from_bytes(10));
+        let service = || {
+            layers.layer(
+                &builder.log,
+                &builder.metrics,
+                &builder.security_headers,
+                stub_service(Duration::ZERO),
+            )
+        };
+        assert_eq!(status(service(), vec![0; 10]).await, StatusCode::OK);
+        assert_eq!(
+            status(service(), vec![0; 11]).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_requests_time_out() {
+        let builder = builder();
+        let layers =
+            EndpointLayers::new(ApiReqType::Query).with_timeout(Some(Duration::from_millis(10)));
+        let service = |delay| {
+            layers.layer(
+                &builder.log,
+                &builder.metrics,
+                &builder.security_headers,
+                stub_service(delay),
+            )
+        };
+        assert_eq!(
+            status(service(Duration::ZERO), vec![]).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(service(Duration::from_secs(10)), vec![]).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[test]
+    fn queries_and_read_state_requests_time_out_but_calls_do_not() {
+        let builder = builder();
+        let timeout = Some(Duration::from_secs(1));
+        assert_eq!(builder.layers(ApiReqType::Call).timeout, None);
+        assert_eq!(builder.layers(ApiReqType::Query).timeout, timeout);
+        assert_eq!(builder.layers(ApiReqType::ReadState).timeout, timeout);
+    }
+
+    #[test]
+    fn certified_state_requests_share_a_concurrency_limit() {
+        let builder = builder();
+        for api_req_type in [ApiReqType::CanisterInfo, ApiReqType::SubnetPublicKey] {
+            assert_eq!(
+                builder.layers(api_req_type).max_concurrent_requests,
+                Some(builder.limits.max_read_state_concurrent_requests)
+            );
+            assert!(builder.concurrency_limit(api_req_type).is_some());
+        }
+        assert!(builder.concurrency_limit(ApiReqType::Status).is_none());
+    }
+
+    #[tokio::test]
+    async fn security_headers_are_added_to_status_responses_only() {
+        let builder = builder();
+        let status_endpoint = builder.endpoint(ApiReqType::Status, stub_service(Duration::ZERO));
+        let response = status_endpoint.oneshot(Body::empty()).await.unwrap();
+        assert_eq!(
+            response.headers()[header::X_CONTENT_TYPE_OPTIONS],
+            "nosniff"
+        );
+
+        let query = builder.endpoint(ApiReqType::Query, stub_service(Duration::ZERO));
+        let response = query.oneshot(Body::empty()).await.unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::X_CONTENT_TYPE_OPTIONS));
+    }
+}
@@ -1,33 +1,118 @@
 //! Module that deals with requests to /api/v2/canister/.../call
 
 use crate::{
-    body::BodyReceiverLayer,
-    common::{get_cors_headers, make_plaintext_response, make_response, map_box_error_to_response},
+    common::{
+        get_cors_headers, get_latest_certified_state, make_plaintext_response, make_response,
+        map_box_error_to_response, CONTENT_TYPE_CBOR,
+    },
+    ingress_filter_cache::{FilterInputs, IngressFilterCache},
+    state_reader_executor::StateReaderExecutor,
+    trace_context::current_trace_id,
     types::{to_legacy_request_type, ApiReqType},
     validator_executor::ValidatorExecutor,
-    EndpointService, HttpError, HttpHandlerMetrics, IngressFilterService, UNKNOWN_LABEL,
+    BaseEndpointService, HttpError, HttpHandlerMetrics, IngressFilterService, API_VERSION_V2,
+    UNKNOWN_LABEL,
 };
-use hyper::{Body, Response, StatusCode};
+use hyper::{header, Body, HeaderMap, Response, StatusCode, Uri};
+use ic_error_types::{ErrorCode, UserError};
 use ic_interfaces::registry::RegistryClient;
 use ic_interfaces_p2p::{IngressError, IngressIngestionService};
-use ic_logger::{error, info_sample, warn, ReplicaLogger};
+use ic_logger::{debug, error, info_sample, warn, ReplicaLogger};
 use ic_registry_client_helpers::{
     provisional_whitelist::ProvisionalWhitelistRegistry,
     subnet::{IngressMessageSettings, SubnetRegistry},
 };
 use ic_registry_provisional_whitelist::ProvisionalWhitelist;
+use ic_replicated_state::CanisterStatus;
 use ic_types::{
     malicious_flags::MaliciousFlags,
-    messages::{SignedIngress, SignedRequestBytes},
-    CountBytes, RegistryVersion, SubnetId,
+    messages::{MessageId, SignedIngress, SignedRequestBytes},
+    CanisterId, CountBytes, RegistryVersion, SubnetId,
 };
+use prometheus::IntGauge;
+use serde::Serialize;
 use std::convert::{Infallible, TryInto};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Instant;
 use tower::{load_shed::LoadShed, util::BoxCloneService, Service, ServiceBuilder, ServiceExt};
 
+/// The header requesting a preview of the size-based cost of a call in the
+/// body of its `202 Accepted` response. The `cost_preview` query parameter
+/// has the same effect.
+pub(crate) const COST_PREVIEW_HEADER: &str = "x-ic-cost-preview";
+const COST_PREVIEW_QUERY_PARAM: &str = "cost_preview";
+
+/// The size of a submitted message as accounted for in block payloads, along
+/// with the limit of the subnet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct CostPreview {
+    size_bytes: usize,
+    max_size_bytes: usize,
+    cost_class: CostClass,
+}
+
+/// Classes of message sizes, as fractions of the maximum message size, which
+/// messages compete for space in blocks with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CostClass {
+    /// At most 1/64th of the maximum size.
+    Small,
+    /// At most 1/8th of the maximum size.
+    Medium,
+    /// At most half of the maximum size.
+    Large,
+    Maximal,
+}
+
+impl CostPreview {
+    fn new(size_bytes: usize, max_size_bytes: usize) -> Self {
+        let cost_class = if size_bytes <= max_size_bytes / 64 {
+            CostClass::Small
+        } else if size_bytes <= max_size_bytes / 8 {
+            CostClass::Medium
+        } else if size_bytes <= max_size_bytes / 2 {
+            CostClass::Large
+        } else {
+            CostClass::Maximal
+        };
+        Self {
+            size_bytes,
+            max_size_bytes,
+            cost_class,
+        }
+    }
+}
+
+/// Returns true if a call requests a preview of its cost.
+pub(crate) fn wants_cost_preview(uri: &Uri, headers: &HeaderMap) -> bool {
+    headers.contains_key(COST_PREVIEW_HEADER)
+        || uri.query().map_or(false, |query| {
+            query
+                .split('&')
+                .any(|pair| pair.split('=').next() == Some(COST_PREVIEW_QUERY_PARAM))
+        })
+}
+
+/// Sets the body of a `202 Accepted` response to a call to the CBOR-encoded
+/// preview of its cost.
+pub(crate) fn add_cost_preview(response: &mut Response<Body>) {
+    if response.status() != StatusCode::ACCEPTED {
+        return;
+    }
+    if let Some(preview) = response.extensions().get::<CostPreview>() {
+        let body = serde_cbor::to_vec(preview).expect("The cost preview is serializable.");
+        *response.body_mut() = Body::from(body);
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static(CONTENT_TYPE_CBOR),
+        );
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct CallService {
     log: ReplicaLogger,
@@ -37,6 +122,9 @@ pub(crate) struct CallService {
     validator_executor: ValidatorExecutor,
     ingress_sender: IngressIngestionService,
     ingress_filter: LoadShed<IngressFilterService>,
+    ingress_filter_cache: Arc<IngressFilterCache>,
+    state_reader_executor: StateReaderExecutor,
+    reject_calls_to_stopped_canisters: bool,
     malicious_flags: MaliciousFlags,
 }
 
@@ -50,23 +138,69 @@ impl CallService {
         validator_executor: ValidatorExecutor,
         ingress_sender: IngressIngestionService,
         ingress_filter: IngressFilterService,
+        state_reader_executor: StateReaderExecutor,
+        reject_calls_to_stopped_canisters: bool,
         malicious_flags: MaliciousFlags,
-    ) -> EndpointService {
+    ) -> BaseEndpointService {
         let base_service = BoxCloneService::new(ServiceBuilder::new().service(Self {
             log,
-            metrics,
+            metrics: metrics.clone(),
             subnet_id,
             registry_client,
             validator_executor,
             ingress_sender,
             ingress_filter: ServiceBuilder::new().load_shed().service(ingress_filter),
+            ingress_filter_cache: Arc::new(IngressFilterCache::default()),
+            state_reader_executor,
+            reject_calls_to_stopped_canisters,
             malicious_flags,
         }));
-        BoxCloneService::new(
-            ServiceBuilder::new()
-                .layer(BodyReceiverLayer::default())
-                .service(base_service),
-        )
+        BoxCloneService::new(IngressQueueDepthService {
+            inner: base_service,
+            queue_depth: metrics.ingress_queue_depth.clone(),
+        })
+    }
+}
+
+/// Tracks the number of call requests from their arrival until their
+/// submission to the ingress pool completes, including the time spent waiting
+/// for the ingress ingestion service to become ready. The number is exported
+/// as a metric and reported in `/api/v2/status`.
+#[derive(Clone)]
+struct IngressQueueDepthService {
+    inner: BoxCloneService<Vec<u8>, Response<Body>, Infallible>,
+    queue_depth: IntGauge,
+}
+
+// Decrements the queue depth on drop, also if the request is cancelled.
+struct QueuedCall(IntGauge);
+
+impl Drop for QueuedCall {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Service<Vec<u8>> for IngressQueueDepthService {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Readiness of the inner service is awaited in the returned future, so
+        // that the waiting requests are counted.
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, body: Vec<u8>) -> Self::Future {
+        self.queue_depth.inc();
+        let queued = QueuedCall(self.queue_depth.clone());
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let _queued = queued;
+            inner.oneshot(body).await
+        })
     }
 }
 
@@ -137,6 +271,7 @@ impl Service<Vec<u8>> for CallService {
                 to_legacy_request_type(ApiReqType::Call),
                 ApiReqType::Call.into(),
                 UNKNOWN_LABEL,
+                API_VERSION_V2,
             ])
             .observe(body.len() as f64);
         let msg: SignedIngress = match SignedRequestBytes::from(body).try_into() {
@@ -162,6 +297,10 @@ impl Service<Vec<u8>> for CallService {
                 return Box::pin(async move { Ok(make_plaintext_response(status, message)) });
             }
         };
+        let cost_preview = CostPreview::new(
+            msg.count_bytes(),
+            ingress_registry_settings.max_ingress_bytes_per_message,
+        );
         if msg.count_bytes() > ingress_registry_settings.max_ingress_bytes_per_message {
             let res = make_plaintext_response(
                 StatusCode::PAYLOAD_TOO_LARGE,
@@ -197,9 +336,19 @@ impl Service<Vec<u8>> for CallService {
         let mut ingress_sender = std::mem::replace(&mut self.ingress_sender, ingress_sender);
 
         let mut ingress_filter = self.ingress_filter.clone();
+        let ingress_filter_cache = Arc::clone(&self.ingress_filter_cache);
+        let filter_inputs = FilterInputs {
+            registry_version,
+            state_height: self.state_reader_executor.latest_state_height(),
+        };
+        let addressed_to_subnet = msg.is_addressed_to_subnet(self.subnet_id);
+        let cache_hits = self.metrics.ingress_filter_cache_hits_total.clone();
         let log = self.log.clone();
         let validator_executor = self.validator_executor.clone();
         let malicious_flags = self.malicious_flags.clone();
+        let state_reader_executor = self
+            .reject_calls_to_stopped_canisters
+            .then(|| self.state_reader_executor.clone());
 
         Box::pin(async move {
             if let Err(http_err) = validator_executor
@@ -210,7 +359,21 @@ impl Service<Vec<u8>> for CallService {
                 return Ok(res);
             }
 
-            match ingress_filter
+            if let Some(state_reader_executor) = state_reader_executor {
+                if let Err(err) =
+                    check_canister_is_running(&state_reader_executor, msg.canister_id()).await
+                {
+                    return Ok(make_response(err));
+                }
+            }
+
+            if let Some(err) =
+                ingress_filter_cache.get(msg.content(), filter_inputs, Instant::now())
+            {
+                cache_hits.inc();
+                return Ok(make_response(err));
+            }
+            let verdict = match ingress_filter
                 .ready()
                 .await
                 .expect("The service must always be able to process requests")
@@ -220,10 +383,17 @@ impl Service<Vec<u8>> for CallService {
                 Err(err) => {
                     return Ok(map_box_error_to_response(err));
                 }
-                Ok(Err(err)) => {
-                    return Ok(make_response(err));
-                }
-                Ok(Ok(())) => (),
+                Ok(verdict) => verdict,
+            };
+            ingress_filter_cache.insert(
+                msg.content(),
+                addressed_to_subnet,
+                &verdict,
+                filter_inputs,
+                Instant::now(),
+            );
+            if let Err(err) = verdict {
+                return Ok(make_response(err));
             }
 
             let ingress_log_entry = msg.log_entry();
@@ -242,7 +412,13 @@ impl Service<Vec<u8>> for CallService {
                         "ingress_message_submit";
                         ingress_message => ingress_log_entry
                     );
-                    make_accepted_response()
+                    if let Some(trace_id) = current_trace_id() {
+                        debug!(
+                            log,
+                            "Submitted ingress message {} in trace {}", message_id, trace_id
+                        );
+                    }
+                    make_accepted_response(message_id, cost_preview)
                 }
             };
             Ok(response)
@@ -250,10 +426,41 @@ impl Service<Vec<u8>> for CallService {
     }
 }
 
-fn make_accepted_response() -> Response<Body> {
+// Rejects calls to canisters that are stopped or stopping in the latest
+// certified state. Such calls are guaranteed to fail during execution, so there
+// is no point in spending a consensus round on them. Calls to unknown canisters
+// and to the management canister are left to the ingress filter.
+async fn check_canister_is_running(
+    state_reader_executor: &StateReaderExecutor,
+    canister_id: CanisterId,
+) -> Result<(), UserError> {
+    let state = match get_latest_certified_state(state_reader_executor).await {
+        Some(state) => state,
+        None => return Ok(()),
+    };
+    let code = match state
+        .canister_state(&canister_id)
+        .map(|canister| &canister.system_state.status)
+    {
+        Some(CanisterStatus::Stopping { .. }) => ErrorCode::CanisterStopping,
+        Some(CanisterStatus::Stopped) => ErrorCode::CanisterStopped,
+        Some(CanisterStatus::Running { .. }) | None => return Ok(()),
+    };
+    Err(UserError::new(
+        code,
+        format!("Canister {} is not running", canister_id),
+    ))
+}
+
+// The message id is attached to the response as an extension, so that the
+// router can remember it for the idempotency key of the request, if any, as
+// is the cost preview, in case the request asks for it.
+fn make_accepted_response(message_id: MessageId, cost_preview: CostPreview) -> Response<Body> {
     let mut response = Response::new(Body::from(""));
     *response.status_mut() = StatusCode::ACCEPTED;
     *response.headers_mut() = get_cors_headers();
+    response.extensions_mut().insert(message_id);
+    response.extensions_mut().insert(cost_preview);
     response
 }
 
@@ -307,4 +514,25 @@ mod test {
         let message_id_2 = SignedIngress::try_from(request2).unwrap().id();
         assert_eq!(message_id_2, message_id);
     }
+
+    #[test]
+    fn cost_preview_is_requested_and_classified() {
+        let uri: Uri = "/api/v2/canister/aaaaa-aa/call?cost_preview"
+            .parse()
+            .unwrap();
+        assert!(wants_cost_preview(&uri, &HeaderMap::new()));
+        let uri: Uri = "/api/v2/canister/aaaaa-aa/call?cost_preview_x=1"
+            .parse()
+            .unwrap();
+        assert!(!wants_cost_preview(&uri, &HeaderMap::new()));
+        let mut headers = HeaderMap::new();
+        headers.insert(COST_PREVIEW_HEADER, header::HeaderValue::from_static("1"));
+        assert!(wants_cost_preview(&uri, &headers));
+
+        let max = 2 * 1024 * 1024;
+        assert_eq!(CostPreview::new(1024, max).cost_class, CostClass::Small);
+        assert_eq!(CostPreview::new(max / 8, max).cost_class, CostClass::Medium);
+        assert_eq!(CostPreview::new(max / 4, max).cost_class, CostClass::Large);
+        assert_eq!(CostPreview::new(max, max).cost_class, CostClass::Maximal);
+    }
 }
@@ -0,0 +1,205 @@
+//! Module that caps the number of queries executing concurrently per canister.
+//!
+//! Query execution threads are shared by all canisters on the subnet, so a
+//! single, extremely hot canister can otherwise occupy all of them. Queries
+//! exceeding the cap wait in a small per-canister queue and are rejected once
+//! the queue is full as well.
+use crate::HttpHandlerMetrics;
+use ic_types::CanisterId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Returned if both the executing and the queued queries of a canister are at
+/// their limits.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct QueueFull;
+
+pub(crate) struct CanisterConcurrencyLimiter {
+    max_concurrent: usize,
+    max_queued: usize,
+    metrics: HttpHandlerMetrics,
+    canisters: Mutex<HashMap<CanisterId, CanisterSlots>>,
+}
+
+// The queries of a canister that are executing or queued. The entry is
+// removed once the last of them completes, so idle canisters take no memory.
+struct CanisterSlots {
+    semaphore: Arc<Semaphore>,
+    users: usize,
+}
+
+/// Allows executing one query for the canister, until dropped.
+pub(crate) struct CanisterPermit {
+    _permit: OwnedSemaphorePermit,
+    _slot: Slot,
+}
+
+// Occupies a place in the canister's entry, executing or queued. Released on
+// drop, also if the query is cancelled while queued.
+struct Slot {
+    limiter: Arc<CanisterConcurrencyLimiter>,
+    canister_id: CanisterId,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut canisters = self.limiter.canisters.lock().unwrap();
+        if let Some(slots) = canisters.get_mut(&self.canister_id) {
+            slots.users -= 1;
+            if slots.users == 0 {
+                canisters.remove(&self.canister_id);
+            }
+        }
+    }
+}
+
+// Decrements the queued queries gauge on drop, also if the query is cancelled
+// while queued.
+struct QueuedGuard<'a>(&'a HttpHandlerMetrics);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.query_canister_queued.dec();
+    }
+}
+
+impl CanisterConcurrencyLimiter {
+    pub(crate) fn new(
+        max_concurrent: usize,
+        max_queued: usize,
+        metrics: HttpHandlerMetrics,
+    ) -> Self {
+        Self {
+            max_concurrent: max_concurrent.max(1),
+            max_queued,
+            metrics,
+            canisters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until a query for `canister_id` may execute. Returns `QueueFull`
+    /// right away if the canister has `max_queued` queries waiting already.
+    pub(crate) async fn acquire(
+        self: &Arc<Self>,
+        canister_id: CanisterId,
+    ) -> Result<CanisterPermit, QueueFull> {
+        let semaphore = {
+            let mut canisters = self.canisters.lock().unwrap();
+            let slots = canisters
+                .entry(canister_id)
+                .or_insert_with(|| CanisterSlots {
+                    semaphore: Arc::new(Semaphore::new(self.max_concurrent)),
+                    users: 0,
+                });
+            if slots.users >= self.max_concurrent + self.max_queued {
+                self.metrics.query_canister_rejections_total.inc();
+                return Err(QueueFull);
+            }
+            slots.users += 1;
+            Arc::clone(&slots.semaphore)
+        };
+        let slot = Slot {
+            limiter: Arc::clone(self),
+            canister_id,
+        };
+
+        let permit = match Arc::clone(&semaphore).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let start = Instant::now();
+                self.metrics.query_canister_queued.inc();
+                let _queued = QueuedGuard(&self.metrics);
+                let permit = semaphore
+                    .acquire_owned()
+                    .await
+                    .expect("The semaphore is never closed.");
+                self.metrics
+                    .query_canister_queue_duration
+                    .observe(start.elapsed().as_secs_f64());
+                permit
+            }
+        };
+        Ok(CanisterPermit {
+            _permit: permit,
+            _slot: slot,
+        })
+    }
+
+    #[cfg(test)]
+    fn num_tracked_canisters(&self) -> usize {
+        self.canisters.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+    use ic_metrics::MetricsRegistry;
+    use ic_test_utilities::types::ids::canister_test_id;
+
+    fn limiter(max_concurrent: usize, max_queued: usize) -> Arc<CanisterConcurrencyLimiter> {
+        Arc::new(CanisterConcurrencyLimiter::new(
+            max_concurrent,
+            max_queued,
+            HttpHandlerMetrics::new(&MetricsRegistry::new()),
+        ))
+    }
+
+    #[tokio::test]
+    async fn queries_beyond_the_cap_are_queued_then_rejected() {
+        let limiter = limiter(1, 1);
+        let executing = limiter.acquire(canister_test_id(0)).await.unwrap();
+
+        // The second query waits for the first one to complete.
+        let mut queued = Box::pin(limiter.acquire(canister_test_id(0)));
+        assert!((&mut queued).now_or_never().is_none());
+        assert_eq!(limiter.metrics.query_canister_queued.get(), 1);
+
+        // The queue is full.
+        assert_eq!(
+            limiter.acquire(canister_test_id(0)).await.err(),
+            Some(QueueFull)
+        );
+        assert_eq!(limiter.metrics.query_canister_rejections_total.get(), 1);
+
+        // Other canisters are not affected.
+        let other = limiter.acquire(canister_test_id(1)).await.unwrap();
+
+        drop(executing);
+        let executing = queued.await.unwrap();
+        assert_eq!(limiter.metrics.query_canister_queued.get(), 0);
+        assert_eq!(
+            limiter
+                .metrics
+                .query_canister_queue_duration
+                .get_sample_count(),
+            1
+        );
+
+        drop(executing);
+        drop(other);
+        assert_eq!(limiter.num_tracked_canisters(), 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_queued_queries_release_their_slot() {
+        let limiter = limiter(1, 1);
+        let executing = limiter.acquire(canister_test_id(0)).await.unwrap();
+
+        let mut queued = Box::pin(limiter.acquire(canister_test_id(0)));
+        assert!((&mut queued).now_or_never().is_none());
+        drop(queued);
+        assert_eq!(limiter.metrics.query_canister_queued.get(), 0);
+
+        // The cancelled query no longer occupies the queue.
+        let mut queued = Box::pin(limiter.acquire(canister_test_id(0)));
+        assert!((&mut queued).now_or_never().is_none());
+
+        drop(executing);
+        drop(queued.await.unwrap());
+        assert_eq!(limiter.num_tracked_canisters(), 0);
+    }
+}
@@ -0,0 +1,199 @@
+//! Module that contains panics in endpoint services.
+//!
+//! A panic while handling a request is converted into a `500 Internal Server
+//! Error` carrying an incident id, instead of silently tearing down the task
+//! serving the connection. The panic is counted and logged together with the
+//! incident id and the backtrace of the panicking thread, so that reports of
+//! clients can be matched with the logs of the replica.
+//!
+//! The process-wide panic hook is still called for contained panics. A panic
+//! may leave shared state, e.g. a poisoned mutex, broken for all later
+//! requests, so processes that abort on panics, like the replica, keep doing
+//! so. Panics are only turned into responses if the hook returns.
+use crate::{
+    common::make_plaintext_response, types::ApiReqType, EndpointService, HttpHandlerMetrics,
+};
+use backtrace::Backtrace;
+use hyper::{Body, Response, StatusCode};
+use ic_logger::{error, ReplicaLogger};
+use rand::Rng;
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    future::Future,
+    panic::{catch_unwind, AssertUnwindSafe},
+    pin::Pin,
+    sync::Once,
+    task::{Context, Poll},
+};
+use tower::{util::BoxCloneService, BoxError, Service};
+
+thread_local! {
+    // Number of wrapped services being polled on this thread.
+    static CONTAINING: Cell<usize> = Cell::new(0);
+    // The location and backtrace of the last contained panic on this thread.
+    static LAST_PANIC: RefCell<Option<(String, Backtrace)>> = RefCell::new(None);
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+// Installs a panic hook that records the location and backtrace of contained
+// panics, before deferring to the previously installed hook for all panics.
+fn install_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |panic_info| {
+            if CONTAINING.with(|containing| containing.get()) > 0 {
+                let location = panic_info
+                    .location()
+                    .map_or_else(|| "unknown".to_string(), |location| location.to_string());
+                LAST_PANIC.with(|last_panic| {
+                    *last_panic.borrow_mut() = Some((location, Backtrace::new()));
+                });
+            }
+            previous_hook(panic_info)
+        }));
+    });
+}
+
+// Runs `f`, catching the panic it raises if any.
+fn contain<R>(f: impl FnOnce() -> R) -> Result<R, Box<dyn Any + Send>> {
+    CONTAINING.with(|containing| containing.set(containing.get() + 1));
+    let result = catch_unwind(AssertUnwindSafe(f));
+    CONTAINING.with(|containing| containing.set(containing.get() - 1));
+    result
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+#[derive(Clone)]
+struct PanicReporter {
+    log: ReplicaLogger,
+    metrics: HttpHandlerMetrics,
+    api_req_type: ApiReqType,
+}
+
+impl PanicReporter {
+    fn panic_response(&self, payload: Box<dyn Any + Send>) -> Response<Body> {
+        let incident_id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let (location, backtrace) = LAST_PANIC
+            .with(|last_panic| last_panic.borrow_mut().take())
+            .unwrap_or_else(|| ("unknown".to_string(), Backtrace::new_unresolved()));
+        self.metrics
+            .panics_total
+            .with_label_values(&[self.api_req_type.into()])
+            .inc();
+        error!(
+            self.log,
+            "Panic while handling a {} request, incident id {}: '{}' at {}\n{:?}",
+            <&str>::from(self.api_req_type),
+            incident_id,
+            panic_message(&*payload),
+            location,
+            backtrace
+        );
+        make_plaintext_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Internal error, incident id {}.", incident_id),
+        )
+    }
+}
+
+#[derive(Clone)]
+struct CatchPanic {
+    reporter: PanicReporter,
+    inner: EndpointService,
+}
+
+impl Service<Body> for CatchPanic {
+    type Response = Response<Body>;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, body: Body) -> Self::Future {
+        let reporter = self.reporter.clone();
+        let inner = &mut self.inner;
+        let mut future = match contain(|| inner.call(body)) {
+            Ok(future) => future,
+            Err(payload) => {
+                let response = reporter.panic_response(payload);
+                return Box::pin(async move { Ok(response) });
+            }
+        };
+        Box::pin(futures::future::poll_fn(move |cx| {
+            match contain(|| future.as_mut().poll(cx)) {
+                Ok(poll) => poll,
+                Err(payload) => Poll::Ready(Ok(reporter.panic_response(payload))),
+            }
+        }))
+    }
+}
+
+/// Wraps `service`, converting panics while handling requests of type
+/// `api_req_type` into `500 Internal Server Error` responses.
+pub(crate) fn catch_panics(
+    log: ReplicaLogger,
+    metrics: HttpHandlerMetrics,
+    api_req_type: ApiReqType,
+    service: EndpointService,
+) -> EndpointService {
+    install_hook();
+    BoxCloneService::new(CatchPanic {
+        reporter: PanicReporter {
+            log,
+            metrics,
+            api_req_type,
+        },
+        inner: service,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_logger::replica_logger::no_op_logger;
+    use ic_metrics::MetricsRegistry;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn panics_are_converted_into_internal_errors() {
+        let metrics = HttpHandlerMetrics::new(&MetricsRegistry::new());
+        let panicking = BoxCloneService::new(service_fn(|_: Body| async {
+            if true {
+                panic!("boom");
+            }
+            Ok::<_, BoxError>(Response::new(Body::empty()))
+        }));
+        let service = catch_panics(
+            no_op_logger(),
+            metrics.clone(),
+            ApiReqType::Query,
+            panicking,
+        );
+
+        let response = service.oneshot(Body::empty()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).starts_with("Internal error, incident id "));
+        assert_eq!(
+            metrics
+                .panics_total
+                .with_label_values(&[ApiReqType::Query.into()])
+                .get(),
+            1
+        );
+    }
+}
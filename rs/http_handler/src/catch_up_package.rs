@@ -3,13 +3,23 @@
 use crate::{
     body::BodyReceiverLayer,
     common,
+    response_budget::ResponseBudget,
+    routes::NO_API_VERSION,
     types::{to_legacy_request_type, ApiReqType},
-    EndpointService, HttpHandlerMetrics, UNKNOWN_LABEL,
+    EndpointService, HttpHandlerMetrics, CONTENT_TYPE_CBOR, UNKNOWN_LABEL,
 };
-use hyper::{Body, Response, StatusCode};
+use byte_unit::Byte;
+use hyper::{header, Body, HeaderMap, Response, StatusCode};
 use ic_interfaces::consensus_pool::ConsensusPoolCache;
-use ic_types::consensus::catchup::CatchUpPackageParam;
+use ic_types::{
+    consensus::{
+        catchup::{CUPWithOriginalProtobuf, CatchUpPackageParam},
+        HasHeight,
+    },
+    messages::Blob,
+};
 use prost::Message;
+use serde::Serialize;
 use std::convert::Infallible;
 use std::future::Future;
 use std::pin::Pin;
@@ -19,33 +29,95 @@ use tower::{
     limit::concurrency::GlobalConcurrencyLimitLayer, util::BoxCloneService, Service, ServiceBuilder,
 };
 
-const MAX_CATCH_UP_PACKAGE_CONCURRENT_REQUESTS: usize = 100;
+pub(crate) const MAX_CATCH_UP_PACKAGE_CONCURRENT_REQUESTS: usize = 100;
+
+/// The version of the [`CborCatchUpPackage`] format.
+const CBOR_CATCH_UP_PACKAGE_VERSION: u32 = 1;
+
+/// An upper bound of the size of a [`CborCatchUpPackage`] beyond its blobs,
+/// i.e. of its field names, integers and blob lengths.
+const CBOR_CATCH_UP_PACKAGE_OVERHEAD_BYTES: usize = 128;
+
+/// The wire format a CUP is served in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CatchUpPackageFormat {
+    Protobuf,
+    Cbor,
+}
+
+impl CatchUpPackageFormat {
+    /// Returns the format requested by the `Accept` header: CBOR if
+    /// `application/cbor` is listed before `application/x-protobuf`, and
+    /// protobuf otherwise, for compatibility with existing clients.
+    pub(crate) fn from_accept_header(headers: &HeaderMap) -> Self {
+        headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|media_range| media_range.split(';').next())
+            .map(str::trim)
+            .find_map(|media_type| {
+                if media_type.eq_ignore_ascii_case(CONTENT_TYPE_CBOR) {
+                    Some(CatchUpPackageFormat::Cbor)
+                } else if media_type.eq_ignore_ascii_case(common::CONTENT_TYPE_PROTOBUF) {
+                    Some(CatchUpPackageFormat::Protobuf)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(CatchUpPackageFormat::Protobuf)
+    }
+}
+
+/// A CUP encoded as CBOR. The signed `content` and the `signer` are kept in
+/// their protobuf encoding, as the signature is over the protobuf-encoded
+/// content; `height` is provided for convenience only.
+#[derive(Serialize)]
+struct CborCatchUpPackage {
+    version: u32,
+    height: u64,
+    content: Blob,
+    signature: Blob,
+    signer: Option<Blob>,
+}
 
 #[derive(Clone)]
 pub(crate) struct CatchUpPackageService {
     metrics: HttpHandlerMetrics,
     consensus_pool_cache: Arc<dyn ConsensusPoolCache>,
+    format: CatchUpPackageFormat,
+    response_budget: ResponseBudget,
 }
 
 impl CatchUpPackageService {
+    /// Returns the service serving CUPs in `format`. The services of both
+    /// formats are passed the same `concurrency_limit`, so that they share it.
     pub(crate) fn new_service(
         metrics: HttpHandlerMetrics,
         consensus_pool_cache: Arc<dyn ConsensusPoolCache>,
+        format: CatchUpPackageFormat,
+        max_request_body_size: Byte,
+        response_budget: ResponseBudget,
+        concurrency_limit: GlobalConcurrencyLimitLayer,
     ) -> EndpointService {
         let base_service = BoxCloneService::new(
             ServiceBuilder::new()
-                .layer(GlobalConcurrencyLimitLayer::new(
-                    MAX_CATCH_UP_PACKAGE_CONCURRENT_REQUESTS,
-                ))
+                .layer(concurrency_limit)
                 .service(Self {
-                    metrics,
+                    metrics: metrics.clone(),
                     consensus_pool_cache,
+                    format,
+                    response_budget,
                 }),
         );
 
         BoxCloneService::new(
             ServiceBuilder::new()
-                .layer(BodyReceiverLayer::default())
+                .layer(
+                    BodyReceiverLayer::new(metrics, ApiReqType::CatchUpPackage)
+                        .with_max_request_body_size(max_request_body_size),
+                )
                 .service(base_service),
         )
     }
@@ -54,11 +126,7 @@ impl CatchUpPackageService {
 /// Write the provided prost::Message as a serialized protobuf into a Response
 /// object.
 fn protobuf_response<R: Message>(r: &R) -> Response<Body> {
-    use hyper::header;
-    let mut buf = Vec::<u8>::new();
-    r.encode(&mut buf)
-        .expect("impossible: Serialization failed");
-    let mut response = Response::new(Body::from(buf));
+    let mut response = Response::new(Body::from(encode_protobuf(r)));
     *response.status_mut() = StatusCode::OK;
     *response.headers_mut() = common::get_cors_headers();
     response.headers_mut().insert(
@@ -68,6 +136,67 @@ fn protobuf_response<R: Message>(r: &R) -> Response<Body> {
     response
 }
 
+fn encode_protobuf<R: Message>(r: &R) -> Vec<u8> {
+    let mut buf = Vec::<u8>::new();
+    r.encode(&mut buf)
+        .expect("impossible: Serialization failed");
+    buf
+}
+
+impl CatchUpPackageService {
+    // The size of `cup` encoded in the served format, or an upper bound of it.
+    fn encoded_size(&self, cup: &CUPWithOriginalProtobuf) -> usize {
+        match self.format {
+            CatchUpPackageFormat::Protobuf => cup.protobuf.encoded_len(),
+            CatchUpPackageFormat::Cbor => {
+                cup.protobuf.content.len()
+                    + cup.protobuf.signature.len()
+                    + cup
+                        .protobuf
+                        .signer
+                        .as_ref()
+                        .map_or(0, |signer| signer.encoded_len())
+                    + CBOR_CATCH_UP_PACKAGE_OVERHEAD_BYTES
+            }
+        }
+    }
+
+    fn cup_response(&self, cup: &CUPWithOriginalProtobuf) -> Response<Body> {
+        // CUPs can be large, so their size is reserved before encoding them.
+        let reservation = match self
+            .response_budget
+            .try_reserve(self.encoded_size(cup) as u64)
+        {
+            Ok(reservation) => reservation,
+            Err(exceeded) => {
+                self.metrics
+                    .response_budget_rejections_total
+                    .with_label_values(&[ApiReqType::CatchUpPackage.into()])
+                    .inc();
+                return exceeded.into_response();
+            }
+        };
+        let mut response = match self.format {
+            CatchUpPackageFormat::Protobuf => protobuf_response(&cup.protobuf),
+            CatchUpPackageFormat::Cbor => common::cbor_response(&CborCatchUpPackage {
+                version: CBOR_CATCH_UP_PACKAGE_VERSION,
+                height: cup.cup.height().get(),
+                content: Blob(cup.protobuf.content.clone()),
+                signature: Blob(cup.protobuf.signature.clone()),
+                signer: cup
+                    .protobuf
+                    .signer
+                    .as_ref()
+                    .map(|signer| Blob(encode_protobuf(signer))),
+            }),
+        };
+        response
+            .headers_mut()
+            .insert(header::VARY, header::HeaderValue::from_static("accept"));
+        reservation.attach(response)
+    }
+}
+
 impl Service<Vec<u8>> for CatchUpPackageService {
     type Response = Response<Body>;
     type Error = Infallible;
@@ -85,17 +214,20 @@ impl Service<Vec<u8>> for CatchUpPackageService {
                 to_legacy_request_type(ApiReqType::CatchUpPackage),
                 ApiReqType::CatchUpPackage.into(),
                 UNKNOWN_LABEL,
+                NO_API_VERSION,
             ])
             .observe(body.len() as f64);
 
         let cup = self.consensus_pool_cache.cup_with_protobuf();
+        // Only POST requests with a `Content-Type` of `application/cbor` are
+        // routed here, so a non-empty body is parsed as CBOR.
         let res = if body.is_empty() {
-            Ok(protobuf_response(&cup.protobuf))
+            Ok(self.cup_response(&cup))
         } else {
             match serde_cbor::from_slice::<CatchUpPackageParam>(&body) {
                 Ok(param) => {
                     if CatchUpPackageParam::from(&cup.cup) > param {
-                        Ok(protobuf_response(&cup.protobuf))
+                        Ok(self.cup_response(&cup))
                     } else {
                         Ok(common::empty_response())
                     }
@@ -109,3 +241,31 @@ impl Service<Vec<u8>> for CatchUpPackageService {
         Box::pin(async move { res })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_is_negotiated_by_accept_header() {
+        let format = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            CatchUpPackageFormat::from_accept_header(&headers)
+        };
+        assert_eq!(format("application/cbor"), CatchUpPackageFormat::Cbor);
+        assert_eq!(
+            format("application/cbor;q=0.9, application/x-protobuf"),
+            CatchUpPackageFormat::Cbor
+        );
+        assert_eq!(
+            format("application/x-protobuf, application/cbor"),
+            CatchUpPackageFormat::Protobuf
+        );
+        assert_eq!(format("*/*"), CatchUpPackageFormat::Protobuf);
+        assert_eq!(
+            CatchUpPackageFormat::from_accept_header(&HeaderMap::new()),
+            CatchUpPackageFormat::Protobuf
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Module that derives the address of the client sending a request.
+//!
+//! If the replica runs behind a reverse proxy (e.g. nginx or HAProxy), the
+//! TCP peer is the proxy and not the client. Proxies listed in the
+//! `trusted_proxies` config report the original client address in the
+//! `Forwarded` (RFC 7239) or `X-Forwarded-For` headers. These headers are
+//! ignored for all other peers, as anyone can set them.
+
+use hyper::HeaderMap;
+use ic_logger::{warn, ReplicaLogger};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The address of the client that sent a request. Attached to every request
+/// as an extension, recorded on the request span and checked by the access
+/// control of the pprof endpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ClientAddr(pub IpAddr);
+
+/// The set of reverse proxies trusted to report the client address.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    /// Parses the CIDR ranges from the config. Invalid entries are logged and
+    /// skipped, a single IP address is treated as a host range.
+    pub(crate) fn new(log: &ReplicaLogger, cidrs: &[String]) -> Self {
+        let nets = cidrs
+            .iter()
+            .filter_map(|cidr| {
+                match cidr
+                    .parse::<IpNet>()
+                    .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                {
+                    Ok(net) => Some(net),
+                    Err(err) => {
+                        warn!(log, "Ignoring invalid trusted proxy {}: {}", cidr, err);
+                        None
+                    }
+                }
+            })
+            .collect();
+        Self(nets)
+    }
+
+    pub(crate) fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = normalize(*addr);
+        self.0.iter().any(|net| net.contains(&addr))
+    }
+
+    /// Returns the address of the client for a request received from `peer`.
+    ///
+    /// The forwarded chain is walked from the right (the hop closest to us)
+    /// and the first address not belonging to a trusted proxy is returned. If
+    /// `peer` is not trusted, or the chain can't be parsed, `peer` is the
+    /// client.
+    pub(crate) fn client_addr(&self, peer: SocketAddr, headers: &HeaderMap) -> ClientAddr {
+        let peer = normalize(peer.ip());
+        if !self.contains(&peer) {
+            return ClientAddr(peer);
+        }
+        let chain = match forwarded_chain(headers) {
+            Some(chain) if !chain.is_empty() => chain,
+            _ => return ClientAddr(peer),
+        };
+        let mut client = peer;
+        for hop in chain.iter().rev() {
+            match hop {
+                Some(addr) => {
+                    client = *addr;
+                    if !self.contains(addr) {
+                        break;
+                    }
+                }
+                // An obfuscated or malformed hop. Everything further left
+                // can't be trusted.
+                None => break,
+            }
+        }
+        ClientAddr(client)
+    }
+}
+
+/// Returns true if the request carries any forwarding headers.
+pub(crate) fn has_forwarded_headers(headers: &HeaderMap) -> bool {
+    headers.contains_key(hyper::header::FORWARDED) || headers.contains_key(X_FORWARDED_FOR)
+}
+
+// Returns the forwarded chain, leftmost entry being the original client. The
+// `Forwarded` header takes precedence over `X-Forwarded-For`. Entries which
+// can't be parsed as an address are `None`.
+fn forwarded_chain(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    if headers.contains_key(hyper::header::FORWARDED) {
+        let mut chain = vec![];
+        for value in headers.get_all(hyper::header::FORWARDED) {
+            for element in value.to_str().ok()?.split(',') {
+                let node = element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then(|| value)
+                });
+                chain.push(node.and_then(parse_node));
+            }
+        }
+        return Some(chain);
+    }
+    let mut chain = vec![];
+    for value in headers.get_all(X_FORWARDED_FOR) {
+        chain.extend(value.to_str().ok()?.split(',').map(parse_node));
+    }
+    Some(chain)
+}
+
+// Parses a node as found in `Forwarded` or `X-Forwarded-For`, e.g.
+// `192.0.2.43`, `"[2001:db8:cafe::17]:4711"` or `unknown`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+        .map(normalize)
+}
+
+// Maps IPv4-mapped IPv6 addresses (as reported when listening on `[::]`) to
+// their IPv4 form.
+fn normalize(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4() {
+            Some(v4) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => IpAddr::V4(v4),
+            _ => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use ic_logger::replica_logger::no_op_logger;
+
+    fn proxies(cidrs: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(
+            &no_op_logger(),
+            &cidrs.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+        )
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let peer = "192.0.2.1:1234".parse().unwrap();
+        assert_eq!(
+            proxies.client_addr(peer, &headers(X_FORWARDED_FOR, "203.0.113.7")),
+            ClientAddr(ip("192.0.2.1"))
+        );
+    }
+
+    #[test]
+    fn trusted_peer_x_forwarded_for() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let peer = "10.0.0.1:1234".parse().unwrap();
+        // The rightmost untrusted hop is the client, spoofed entries to its
+        // left are ignored.
+        assert_eq!(
+            proxies.client_addr(
+                peer,
+                &headers(X_FORWARDED_FOR, "1.1.1.1, 203.0.113.7, 10.0.0.2")
+            ),
+            ClientAddr(ip("203.0.113.7"))
+        );
+        assert_eq!(
+            proxies.client_addr(peer, &HeaderMap::new()),
+            ClientAddr(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn trusted_peer_forwarded() {
+        let proxies = proxies(&["::1", "10.0.0.0/8"]);
+        let peer = "[::ffff:10.0.0.1]:1234".parse().unwrap();
+        assert_eq!(
+            proxies.client_addr(
+                peer,
+                &headers(
+                    "forwarded",
+                    "for=192.0.2.60;proto=http, for=\"[2001:db8:cafe::17]:4711\""
+                )
+            ),
+            ClientAddr(ip("2001:db8:cafe::17"))
+        );
+        assert_eq!(
+            proxies.client_addr(peer, &headers("forwarded", "for=unknown")),
+            ClientAddr(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn invalid_proxies_are_skipped() {
+        let proxies = proxies(&["not-a-cidr", "127.0.0.1"]);
+        assert!(proxies.contains(&ip("127.0.0.1")));
+        assert!(!proxies.contains(&ip("127.0.0.2")));
+    }
+}
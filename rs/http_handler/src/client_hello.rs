@@ -0,0 +1,226 @@
+//! Module that detects whether a connection starts with a TLS ClientHello.
+//!
+//! The TLS handshake itself is performed by the crypto component, which owns
+//! the rustls server config and needs the untouched `TcpStream`. Hence the
+//! ClientHello is only peeked at here: it decides whether the connection is
+//! served over HTTPS, and the offered server name and ALPN protocols are
+//! recorded for observability.
+//!
+//! No ALPN protocol is negotiated, as the crypto component configures none.
+//! hyper tells HTTP/1.1 and HTTP/2 apart by the connection preface instead,
+//! so the offered protocols only show which clients would use HTTP/2.
+
+// TLS record content type of handshake messages.
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+// Handshake message type of a ClientHello.
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_ALPN: u16 = 16;
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// The size of the buffer used to peek at the ClientHello. A TLS record is at
+/// most 16 KiB, but ClientHellos usually fit in a few hundred bytes.
+pub(crate) const CLIENT_HELLO_PEEK_BYTES: usize = 2048;
+
+/// The fields of a ClientHello relevant for serving the connection.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct ClientHello {
+    /// The host name sent in the SNI extension.
+    pub server_name: Option<String>,
+    /// The protocols offered in the ALPN extension, in order of preference.
+    pub alpn_protocols: Vec<Vec<u8>>,
+}
+
+impl ClientHello {
+    /// Returns the protocol the client prefers among the ones we serve, as a
+    /// metric label.
+    pub(crate) fn preferred_protocol(&self) -> &'static str {
+        self.alpn_protocols
+            .iter()
+            .find_map(|p| match p.as_slice() {
+                b"h2" => Some("h2"),
+                b"http/1.1" => Some("http/1.1"),
+                _ => None,
+            })
+            .unwrap_or("none")
+    }
+}
+
+/// Returns true if `buf`, the first bytes received on a connection, start a
+/// TLS handshake record. Plaintext HTTP requests start with an ASCII method
+/// or the HTTP/2 preface, which never match.
+pub(crate) fn is_tls_handshake(buf: &[u8]) -> bool {
+    match buf {
+        [CONTENT_TYPE_HANDSHAKE] => true,
+        // The record's major protocol version is 3 for all TLS versions.
+        [CONTENT_TYPE_HANDSHAKE, 3, ..] => true,
+        _ => false,
+    }
+}
+
+/// Parses the ClientHello at the start of `buf`. Returns `None` if `buf` does
+/// not hold a complete ClientHello, e.g. because it was only partially
+/// received.
+pub(crate) fn parse_client_hello(buf: &[u8]) -> Option<ClientHello> {
+    let mut record = Reader(buf);
+    if record.u8()? != CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+    record.take(2)?;
+    let mut handshake = Reader(record.take(record.u16()? as usize)?);
+    if handshake.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return None;
+    }
+    let len = handshake.u24()?;
+    let mut hello = Reader(handshake.take(len)?);
+    // Legacy version and random.
+    hello.take(2 + 32)?;
+    let session_id_len = hello.u8()? as usize;
+    hello.take(session_id_len)?;
+    let cipher_suites_len = hello.u16()? as usize;
+    hello.take(cipher_suites_len)?;
+    let compression_methods_len = hello.u8()? as usize;
+    hello.take(compression_methods_len)?;
+
+    let mut client_hello = ClientHello::default();
+    if hello.0.is_empty() {
+        return Some(client_hello);
+    }
+    let extensions_len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.take(extension_len)?);
+        match extension_type {
+            EXTENSION_SERVER_NAME => {
+                let list_len = extension.u16()? as usize;
+                let mut names = Reader(extension.take(list_len)?);
+                while !names.0.is_empty() {
+                    let name_type = names.u8()?;
+                    let name_len = names.u16()? as usize;
+                    let name = names.take(name_len)?;
+                    if name_type == SERVER_NAME_TYPE_HOST_NAME {
+                        client_hello.server_name = Some(String::from_utf8_lossy(name).into_owned());
+                    }
+                }
+            }
+            EXTENSION_ALPN => {
+                let list_len = extension.u16()? as usize;
+                let mut protocols = Reader(extension.take(list_len)?);
+                while !protocols.0.is_empty() {
+                    let protocol_len = protocols.u8()? as usize;
+                    client_hello
+                        .alpn_protocols
+                        .push(protocols.take(protocol_len)?.to_vec());
+                }
+            }
+            _ => (),
+        }
+    }
+    Some(client_hello)
+}
+
+// A cursor over big-endian encoded TLS structures.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_u16_len(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn client_hello(server_name: &[u8], alpn: &[&[u8]]) -> Vec<u8> {
+        let mut sni = vec![SERVER_NAME_TYPE_HOST_NAME];
+        sni.extend(with_u16_len(server_name));
+        let mut alpn_list = vec![];
+        for protocol in alpn {
+            alpn_list.push(protocol.len() as u8);
+            alpn_list.extend_from_slice(protocol);
+        }
+        let mut extensions = vec![];
+        extensions.extend(EXTENSION_SERVER_NAME.to_be_bytes());
+        extensions.extend(with_u16_len(&with_u16_len(&sni)));
+        extensions.extend(EXTENSION_ALPN.to_be_bytes());
+        extensions.extend(with_u16_len(&with_u16_len(&alpn_list)));
+
+        let mut hello = vec![3, 3];
+        hello.extend([0; 32]);
+        hello.push(0);
+        hello.extend(with_u16_len(&[0x13, 0x01]));
+        hello.extend([1, 0]);
+        hello.extend(with_u16_len(&extensions));
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 3, 1];
+        record.extend(with_u16_len(&handshake));
+        record
+    }
+
+    #[test]
+    fn detects_tls_handshake() {
+        let hello = client_hello(b"example.com", &[b"h2"]);
+        assert!(is_tls_handshake(&hello));
+        assert!(is_tls_handshake(&hello[..1]));
+        assert!(!is_tls_handshake(b"GET / HTTP/1.1\r\n"));
+        assert!(!is_tls_handshake(b"PRI * HTTP/2.0\r\n"));
+        assert!(!is_tls_handshake(&[]));
+    }
+
+    #[test]
+    fn parses_server_name_and_alpn() {
+        let hello = client_hello(b"example.com", &[b"h2", b"http/1.1"]);
+        let parsed = parse_client_hello(&hello).unwrap();
+        assert_eq!(
+            parsed,
+            ClientHello {
+                server_name: Some("example.com".to_string()),
+                alpn_protocols: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            }
+        );
+        assert_eq!(parsed.preferred_protocol(), "h2");
+    }
+
+    #[test]
+    fn partial_client_hello_is_not_parsed() {
+        let hello = client_hello(b"example.com", &[b"http/1.1"]);
+        for len in 0..hello.len() {
+            assert_eq!(parse_client_hello(&hello[..len]), None);
+        }
+        assert_eq!(
+            parse_client_hello(&hello).unwrap().preferred_protocol(),
+            "http/1.1"
+        );
+    }
+}
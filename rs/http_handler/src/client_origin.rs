@@ -0,0 +1,234 @@
+//! Module that derives coarse origins of connections for the metrics.
+//!
+//! With the MMDB files configured in `client_asn_database_file` and
+//! `client_country_database_file`, connections are counted by the autonomous
+//! system and the country of their peer, so that operators can see shifts in
+//! where traffic comes from without logging client addresses. The origins are
+//! only ever used as metric labels. Connections from trusted proxies are
+//! counted under the `proxy` origin, as their peer is not the client.
+use ic_logger::{info, warn, ReplicaLogger};
+use maxminddb::{geoip2, Reader};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The label of origins not found in the databases.
+const UNKNOWN_ORIGIN: &str = "unknown";
+
+/// The label of connections from trusted proxies.
+const PROXY_ORIGIN: &str = "proxy";
+
+/// The label of origins beyond the maximum number of distinct labels.
+const OTHER_ORIGIN: &str = "other";
+
+/// The maximum number of distinct autonomous systems in the metrics. There
+/// are tens of thousands of them, but the traffic of a node usually comes
+/// from a few.
+const MAX_ASN_LABELS: usize = 256;
+
+/// The period after which the autonomous systems in the metrics are replaced
+/// with the most frequent ones of the period.
+const ASN_LABELS_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// The coarse origin of a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ClientOrigin {
+    /// The autonomous system of the peer, e.g. `AS13335`.
+    pub asn: Option<String>,
+    /// The ISO 3166-1 code of the country of the peer, e.g. `CH`.
+    pub country: Option<String>,
+}
+
+/// The MMDB databases connections are looked up in. Looking up a connection
+/// yields no origin for the databases that are not configured.
+#[derive(Default)]
+pub(crate) struct ClientOrigins {
+    asn_database: Option<Reader<Vec<u8>>>,
+    country_database: Option<Reader<Vec<u8>>>,
+    asn_labels: BoundedLabels,
+}
+
+impl ClientOrigins {
+    /// Opens the databases at the given paths. Databases that can't be opened
+    /// are logged and skipped.
+    pub(crate) fn new(
+        log: &ReplicaLogger,
+        asn_database_file: Option<&Path>,
+        country_database_file: Option<&Path>,
+    ) -> Self {
+        Self {
+            asn_database: asn_database_file.and_then(|path| open_database(log, path)),
+            country_database: country_database_file.and_then(|path| open_database(log, path)),
+            asn_labels: BoundedLabels::new(MAX_ASN_LABELS, ASN_LABELS_PERIOD),
+        }
+    }
+
+    /// Returns true if any database is configured.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.asn_database.is_some() || self.country_database.is_some()
+    }
+
+    /// Returns the origin of a connection from `peer`.
+    pub(crate) fn lookup(&self, peer: IpAddr) -> ClientOrigin {
+        let asn = self.asn_database.as_ref().map(|database| {
+            let asn = database
+                .lookup::<geoip2::Asn>(peer)
+                .ok()
+                .and_then(|asn| asn.autonomous_system_number)
+                .map(|number| format!("AS{}", number));
+            match asn {
+                Some(asn) => self.asn_labels.label(asn, Instant::now()),
+                None => UNKNOWN_ORIGIN.to_string(),
+            }
+        });
+        let country = self.country_database.as_ref().map(|database| {
+            database
+                .lookup::<geoip2::Country>(peer)
+                .ok()
+                .and_then(|country| country.country?.iso_code)
+                .unwrap_or(UNKNOWN_ORIGIN)
+                .to_string()
+        });
+        ClientOrigin { asn, country }
+    }
+
+    /// Returns the origin of connections from trusted proxies.
+    pub(crate) fn proxy(&self) -> ClientOrigin {
+        ClientOrigin {
+            asn: self.asn_database.as_ref().map(|_| PROXY_ORIGIN.to_string()),
+            country: self
+                .country_database
+                .as_ref()
+                .map(|_| PROXY_ORIGIN.to_string()),
+        }
+    }
+}
+
+fn open_database(log: &ReplicaLogger, path: &Path) -> Option<Reader<Vec<u8>>> {
+    match Reader::open_readfile(path) {
+        Ok(database) => {
+            info!(
+                log,
+                "Counting connections by origin with {}, built at {}",
+                database.metadata.database_type,
+                database.metadata.build_epoch
+            );
+            Some(database)
+        }
+        Err(err) => {
+            warn!(log, "Cannot open MMDB file {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+// A set of label values bounding the number of time series. The values
+// labeled as themselves are the `max` most frequent ones of the previous
+// period, topped up with new values in the order they are seen. Other values
+// are replaced with `OTHER_ORIGIN`.
+//
+// The series of values that drop out of the most frequent ones are kept, so
+// the number of series grows only as fast as the most frequent values change.
+#[derive(Default)]
+struct BoundedLabels {
+    max: usize,
+    period: Duration,
+    state: Mutex<LabelsState>,
+}
+
+#[derive(Default)]
+struct LabelsState {
+    // The values labeled as themselves.
+    labeled: HashSet<String>,
+    // The number of times each value was seen in the current period.
+    counts: HashMap<String, u64>,
+    period_start: Option<Instant>,
+}
+
+impl BoundedLabels {
+    fn new(max: usize, period: Duration) -> Self {
+        Self {
+            max,
+            period,
+            state: Mutex::default(),
+        }
+    }
+
+    fn label(&self, value: String, now: Instant) -> String {
+        let mut state = self.state.lock().unwrap();
+        let period_start = *state.period_start.get_or_insert(now);
+        if now.saturating_duration_since(period_start) >= self.period {
+            let mut counts: Vec<_> = std::mem::take(&mut state.counts).into_iter().collect();
+            counts.sort_by(|(value_a, count_a), (value_b, count_b)| {
+                count_b.cmp(count_a).then_with(|| value_a.cmp(value_b))
+            });
+            state.labeled = counts
+                .into_iter()
+                .take(self.max)
+                .map(|(value, _)| value)
+                .collect();
+            state.period_start = Some(now);
+        }
+        *state.counts.entry(value.clone()).or_default() += 1;
+        if state.labeled.contains(&value) {
+            return value;
+        }
+        if state.labeled.len() >= self.max {
+            return OTHER_ORIGIN.to_string();
+        }
+        state.labeled.insert(value.clone());
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_logger::replica_logger::no_op_logger;
+
+    #[test]
+    fn label_values_are_bounded() {
+        let now = Instant::now();
+        let labels = BoundedLabels::new(2, Duration::from_secs(60));
+        let label = |value: &str| labels.label(value.to_string(), now);
+        assert_eq!(label("AS1"), "AS1");
+        assert_eq!(label("AS2"), "AS2");
+        assert_eq!(label("AS3"), OTHER_ORIGIN);
+        assert_eq!(label("AS1"), "AS1");
+    }
+
+    #[test]
+    fn most_frequent_label_values_are_kept_after_a_period() {
+        let start = Instant::now();
+        let labels = BoundedLabels::new(2, Duration::from_secs(60));
+        for value in ["AS1", "AS2", "AS3", "AS3", "AS3", "AS2"] {
+            labels.label(value.to_string(), start);
+        }
+
+        // AS3 and AS2 were the most frequent, AS1 the least.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(labels.label("AS3".to_string(), later), "AS3");
+        assert_eq!(labels.label("AS1".to_string(), later), OTHER_ORIGIN);
+        assert_eq!(labels.label("AS2".to_string(), later), "AS2");
+    }
+
+    #[test]
+    fn missing_databases_yield_no_origin() {
+        let origins = ClientOrigins::new(
+            &no_op_logger(),
+            Some(Path::new("/nonexistent/GeoLite2-ASN.mmdb")),
+            None,
+        );
+        assert!(!origins.is_enabled());
+        let origin = ClientOrigin {
+            asn: None,
+            country: None,
+        };
+        assert_eq!(origins.lookup("192.0.2.1".parse().unwrap()), origin);
+        assert_eq!(origins.proxy(), origin);
+    }
+}
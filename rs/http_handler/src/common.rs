@@ -1,6 +1,7 @@
+use crate::problem_details::{with_error_cause, ErrorCause};
 use crate::state_reader_executor::StateReaderExecutor;
 use crate::HttpError;
-use hyper::{Body, HeaderMap, Response, StatusCode};
+use hyper::{header::HeaderValue, Body, HeaderMap, Response, StatusCode};
 use ic_crypto_tree_hash::Path;
 use ic_crypto_tree_hash::{sparse_labeled_tree_from_paths, Label};
 use ic_error_types::UserError;
@@ -12,7 +13,9 @@ use ic_types::{
 };
 use ic_validator::RequestValidationError;
 use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
 use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 use std::task::Poll;
 use tower::{load_shed::error::Overloaded, BoxError};
@@ -120,7 +123,10 @@ pub(crate) fn make_response(user_error: UserError) -> Response<Body> {
         C::CanisterInstructionLimitExceeded => StatusCode::INTERNAL_SERVER_ERROR,
         C::CanisterInstallCodeRateLimited => StatusCode::TOO_MANY_REQUESTS,
     };
-    make_plaintext_response(status, user_error.description().to_string())
+    with_error_cause(
+        make_plaintext_response(status, user_error.description().to_string()),
+        ErrorCause::User(user_error.code()),
+    )
 }
 
 pub(crate) fn map_box_error_to_response(err: BoxError) -> Response<Body> {
@@ -128,9 +134,12 @@ pub(crate) fn map_box_error_to_response(err: BoxError) -> Response<Body> {
         return make_response(user_error.clone());
     }
     if err.is::<Overloaded>() {
-        return make_plaintext_response(
-            StatusCode::SERVICE_UNAVAILABLE,
-            "The service is overloaded.".to_string(),
+        return with_error_cause(
+            make_plaintext_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The service is overloaded.".to_string(),
+            ),
+            ErrorCause::Overloaded,
         );
     }
     make_plaintext_response(
@@ -155,7 +164,7 @@ pub(crate) fn get_cors_headers() -> HeaderMap {
     );
     headers.insert(
         header::ACCESS_CONTROL_ALLOW_HEADERS,
-        header::HeaderValue::from_static("Accept, Authorization, Content-Type"),
+        header::HeaderValue::from_static("Accept, Authorization, Content-Digest, Content-Type"),
     );
     headers
 }
@@ -170,8 +179,13 @@ pub(crate) fn into_cbor<R: Serialize>(r: &R) -> Vec<u8> {
 
 /// Write the "self describing" CBOR tag and serialize the response
 pub(crate) fn cbor_response<R: Serialize>(r: &R) -> Response<Body> {
+    cbor_body_response(Body::from(into_cbor(r)))
+}
+
+/// A response with an already serialized CBOR body.
+pub(crate) fn cbor_body_response(body: Body) -> Response<Body> {
     use hyper::header;
-    let mut response = Response::new(Body::from(into_cbor(r)));
+    let mut response = Response::new(body);
     *response.status_mut() = StatusCode::OK;
     *response.headers_mut() = get_cors_headers();
     response.headers_mut().insert(
@@ -181,6 +195,42 @@ pub(crate) fn cbor_response<R: Serialize>(r: &R) -> Response<Body> {
     response
 }
 
+/// Returns the entity tag of a response body, for use in the `ETag` header.
+pub(crate) fn entity_tag(body: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Turns a successful `response` into a `304 Not Modified` if its `ETag`
+/// matches one of the `If-None-Match` values of the request. Entity tags are
+/// compared weakly, as required for `If-None-Match`.
+pub(crate) fn into_not_modified_if_matching(
+    if_none_match: &[HeaderValue],
+    response: Response<Body>,
+) -> Response<Body> {
+    use hyper::header;
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let matches = match response.headers().get(header::ETAG) {
+        Some(etag) if response.status() == StatusCode::OK => {
+            let etag = strip_weak(etag.to_str().unwrap_or_default());
+            if_none_match
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|tag| tag.trim() == "*" || strip_weak(tag) == etag)
+        }
+        _ => false,
+    };
+    if !matches {
+        return response;
+    }
+    let (mut parts, _body) = response.into_parts();
+    parts.status = StatusCode::NOT_MODIFIED;
+    parts.headers.remove(header::CONTENT_TYPE);
+    Response::from_parts(parts, Body::empty())
+}
+
 /// Empty response.
 pub(crate) fn empty_response() -> Response<Body> {
     let mut response = Response::new(Body::from(""));
@@ -239,7 +289,7 @@ pub(crate) mod test {
 
     fn check_cors_headers(hm: &HeaderMap) {
         let acl_headers = hm.get_all(header::ACCESS_CONTROL_ALLOW_HEADERS).iter();
-        assert!(acl_headers.eq(["Accept, Authorization, Content-Type"].iter()));
+        assert!(acl_headers.eq(["Accept, Authorization, Content-Digest, Content-Type"].iter()));
         let acl_methods = hm.get_all(header::ACCESS_CONTROL_ALLOW_METHODS).iter();
         assert!(acl_methods.eq(["POST, GET"].iter()));
         let acl_origin = hm.get_all(header::ACCESS_CONTROL_ALLOW_ORIGIN).iter();
@@ -308,4 +358,36 @@ pub(crate) mod test {
             }),
         );
     }
+
+    #[test]
+    fn matching_entity_tags_turn_responses_into_not_modified() {
+        let etag = entity_tag(b"status");
+        let response = |etag: &str| {
+            let mut response = cbor_response(b"status");
+            response
+                .headers_mut()
+                .insert(header::ETAG, HeaderValue::from_str(etag).unwrap());
+            response
+        };
+        let if_none_match = |value: String| vec![HeaderValue::from_str(&value).unwrap()];
+
+        let unmodified =
+            into_not_modified_if_matching(&if_none_match(etag.clone()), response(&etag));
+        assert_eq!(unmodified.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(unmodified.headers().get(header::ETAG).unwrap(), &etag);
+        assert_eq!(unmodified.headers().get(header::CONTENT_TYPE), None);
+
+        let weak_in_list = format!("\"other\", W/{}", etag);
+        let unmodified =
+            into_not_modified_if_matching(&if_none_match(weak_in_list), response(&etag));
+        assert_eq!(unmodified.status(), StatusCode::NOT_MODIFIED);
+
+        let modified =
+            into_not_modified_if_matching(&if_none_match("\"other\"".to_string()), response(&etag));
+        assert_eq!(modified.status(), StatusCode::OK);
+        assert_eq!(
+            into_not_modified_if_matching(&[], response(&etag)).status(),
+            StatusCode::OK
+        );
+    }
 }
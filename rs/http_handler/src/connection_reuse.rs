@@ -0,0 +1,89 @@
+//! Keep-alive reuse of connections, to guide the tuning of the keep-alive
+//! settings of boundary nodes and clients.
+//!
+//! The number of requests served over a connection is recorded when it
+//! closes, by protocol and HTTP version. Connections closed after a single
+//! request are counted separately, as they point to clients opening a
+//! connection per request and paying for a TCP, and possibly TLS, handshake
+//! each time.
+
+use crate::{metrics::HttpHandlerMetrics, types::AppLayer};
+use hyper::Version;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+/// Counts the requests served over a connection. Clones share the count, so
+/// that the service serving the connection can count its requests, and the
+/// connection can record them once it closes.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionReuse {
+    version: Arc<Mutex<Option<Version>>>,
+    requests: Arc<AtomicU64>,
+}
+
+impl ConnectionReuse {
+    /// Counts a request received over the connection.
+    pub(crate) fn observe_request(&self, version: Version) {
+        // The version is negotiated per connection, the first request is as
+        // good as any.
+        self.version.lock().unwrap().get_or_insert(version);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the requests served over the connection, once it closed.
+    pub(crate) fn record(&self, app_layer: AppLayer, metrics: &HttpHandlerMetrics) {
+        // Connections closed before any request are covered by the connection
+        // setup and duration metrics.
+        if let Some(version) = *self.version.lock().unwrap() {
+            metrics.observe_connection_requests(
+                app_layer,
+                version,
+                self.requests.load(Ordering::Relaxed),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_metrics::MetricsRegistry;
+
+    #[test]
+    fn requests_are_recorded_when_the_connection_closes() {
+        let metrics = HttpHandlerMetrics::new(&MetricsRegistry::new());
+        let requests_per_connection = || {
+            metrics
+                .requests_per_connection
+                .with_label_values(&[AppLayer::Https.into(), "HTTP/2.0"])
+        };
+        let single_request_connections = || {
+            metrics
+                .single_request_connections_total
+                .with_label_values(&[AppLayer::Https.into(), "HTTP/2.0"])
+                .get()
+        };
+
+        let reuse = ConnectionReuse::default();
+        // Clones, e.g. of the service serving the connection, share the count.
+        reuse.clone().observe_request(Version::HTTP_2);
+        reuse.clone().observe_request(Version::HTTP_2);
+        assert_eq!(requests_per_connection().get_sample_count(), 0);
+        reuse.record(AppLayer::Https, &metrics);
+        assert_eq!(requests_per_connection().get_sample_count(), 1);
+        assert_eq!(requests_per_connection().get_sample_sum(), 2.0);
+        assert_eq!(single_request_connections(), 0);
+
+        let reuse = ConnectionReuse::default();
+        reuse.observe_request(Version::HTTP_2);
+        reuse.record(AppLayer::Https, &metrics);
+        assert_eq!(requests_per_connection().get_sample_count(), 2);
+        assert_eq!(single_request_connections(), 1);
+
+        // Connections without requests are not recorded.
+        ConnectionReuse::default().record(AppLayer::Https, &metrics);
+        assert_eq!(requests_per_connection().get_sample_count(), 2);
+    }
+}
@@ -1,16 +1,27 @@
 //! Module that serves the human-readable replica dashboard, which provide
 //! information about the state of the replica.
+//!
+//! The static assets of the dashboard, i.e. its style sheets and scripts, are
+//! embedded from `templates/assets` and served under `/_/assets`, so that
+//! browsers can cache them instead of receiving them with every page. The
+//! page announces them in `Link` preload headers, which proxies in front of
+//! the replica can turn into `103 Early Hints`, as hyper can't send interim
+//! responses itself.
 
 use crate::{
-    common::{make_plaintext_response, CONTENT_TYPE_HTML},
+    common::{entity_tag, get_cors_headers, make_plaintext_response, CONTENT_TYPE_HTML},
+    metrics::HttpHandlerMetrics,
+    response_budget::ResponseBudget,
     state_reader_executor::StateReaderExecutor,
+    types::ApiReqType,
     EndpointService,
 };
 use askama::Template;
-use hyper::{Body, Response, StatusCode};
+use hyper::{header::HeaderValue, Body, Response, StatusCode};
 use ic_config::http_handler::Config;
 use ic_registry_subnet_type::SubnetType;
 use ic_types::{Height, ReplicaVersion};
+use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -21,14 +32,113 @@ use tower::{
 
 // See build.rs
 include!(concat!(env!("OUT_DIR"), "/dashboard.rs"));
+include!(concat!(env!("OUT_DIR"), "/dashboard_assets.rs"));
 
 const MAX_DASHBOARD_CONCURRENT_REQUESTS: usize = 100;
 
+/// The largest rendered dashboard. It lists all canisters, so it is rendered
+/// within a reservation of this size from the response budget, and not
+/// served if larger.
+const MAX_DASHBOARD_BYTES: usize = 8 * 1024 * 1024;
+
+const DASHBOARD_ASSETS_URL_PATH: &str = "/_/assets";
+
+// Assets only change with the replica version, and are revalidated by their
+// `ETag` once stale.
+const ASSET_CACHE_CONTROL: &str = "public, max-age=3600";
+
+struct DashboardAsset {
+    name: &'static str,
+    content_type: &'static str,
+    content: &'static [u8],
+}
+
+impl DashboardAsset {
+    // The value of the `Link` header preloading the asset.
+    fn preload_link(&self) -> String {
+        let destination = if self.content_type.starts_with("text/css") {
+            "style"
+        } else {
+            "script"
+        };
+        format!(
+            "<{}/{}>; rel=preload; as={}",
+            DASHBOARD_ASSETS_URL_PATH, self.name, destination
+        )
+    }
+}
+
+/// Returns the response serving the dashboard asset `name`, or a `404 Not
+/// Found` if there is no such asset.
+pub(crate) fn asset_response(name: &str) -> Response<Body> {
+    use hyper::header;
+    let asset = match DASHBOARD_ASSETS.iter().find(|asset| asset.name == name) {
+        Some(asset) => asset,
+        None => {
+            return make_plaintext_response(
+                StatusCode::NOT_FOUND,
+                format!("Unknown dashboard asset {}.", name),
+            )
+        }
+    };
+    let mut response = Response::new(Body::from(asset.content));
+    *response.headers_mut() = get_cors_headers();
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(asset.content_type),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(ASSET_CACHE_CONTROL),
+    );
+    if let Ok(etag) = HeaderValue::from_str(&entity_tag(asset.content)) {
+        headers.insert(header::ETAG, etag);
+    }
+    response
+}
+
+// Collects the rendered dashboard, failing once it exceeds `max_bytes`.
+struct CappedWriter {
+    content: String,
+    max_bytes: usize,
+    exceeded: bool,
+}
+
+impl CappedWriter {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            content: String::new(),
+            max_bytes,
+            exceeded: false,
+        }
+    }
+}
+
+impl fmt::Write for CappedWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.content.len() + s.len();
+        if len > self.max_bytes {
+            self.exceeded = true;
+            return Err(fmt::Error);
+        }
+        if len > self.content.capacity() {
+            // Don't let the buffer grow beyond the reserved bytes.
+            let capacity = (2 * self.content.capacity()).clamp(len, self.max_bytes);
+            self.content.reserve_exact(capacity - self.content.len());
+        }
+        self.content.push_str(s);
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct DashboardService {
     config: Config,
     subnet_type: SubnetType,
     state_reader_executor: StateReaderExecutor,
+    metrics: HttpHandlerMetrics,
+    response_budget: ResponseBudget,
 }
 
 impl DashboardService {
@@ -36,11 +146,15 @@ impl DashboardService {
         config: Config,
         subnet_type: SubnetType,
         state_reader_executor: StateReaderExecutor,
+        metrics: HttpHandlerMetrics,
+        response_budget: ResponseBudget,
     ) -> EndpointService {
         let base_service = Self {
             config,
             subnet_type,
             state_reader_executor,
+            metrics,
+            response_budget,
         };
         BoxCloneService::new(
             ServiceBuilder::new()
@@ -67,7 +181,19 @@ impl Service<Body> for DashboardService {
         let http_config = self.config.clone();
         let subnet_type = self.subnet_type;
         let state_reader_executor = self.state_reader_executor.clone();
+        let reservation = self.response_budget.try_reserve(MAX_DASHBOARD_BYTES as u64);
+        let metrics = self.metrics.clone();
         Box::pin(async move {
+            let reservation = match reservation {
+                Ok(reservation) => reservation,
+                Err(exceeded) => {
+                    metrics
+                        .response_budget_rejections_total
+                        .with_label_values(&[ApiReqType::Dashboard.into()])
+                        .inc();
+                    return Ok(exceeded.into_response());
+                }
+            };
             let labeled_state = match state_reader_executor.get_latest_state().await {
                 Ok(ls) => ls,
                 Err(e) => return Ok(make_plaintext_response(e.status, e.message)),
@@ -88,16 +214,29 @@ impl Service<Body> for DashboardService {
                 replica_version: ReplicaVersion::default(),
             };
 
-            let res = match dashboard.render() {
-                Ok(content) => {
-                    let mut response = Response::new(Body::from(content));
+            let mut writer = CappedWriter::new(MAX_DASHBOARD_BYTES);
+            let res = match dashboard.render_into(&mut writer) {
+                Ok(()) => {
+                    let mut response = Response::new(Body::from(writer.content));
                     *response.status_mut() = StatusCode::OK;
                     response.headers_mut().insert(
                         header::CONTENT_TYPE,
                         header::HeaderValue::from_static(CONTENT_TYPE_HTML),
                     );
-                    response
+                    for asset in DASHBOARD_ASSETS {
+                        if let Ok(link) = HeaderValue::from_str(&asset.preload_link()) {
+                            response.headers_mut().append(header::LINK, link);
+                        }
+                    }
+                    reservation.attach(response)
                 }
+                Err(_) if writer.exceeded => make_plaintext_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!(
+                        "The dashboard exceeds the maximum size of {} bytes.",
+                        MAX_DASHBOARD_BYTES
+                    ),
+                ),
                 // If there was an internal error, the error description is text, not HTML, and
                 // therefore we don't attach the header
                 Err(e) => make_plaintext_response(
@@ -110,3 +249,54 @@ impl Service<Body> for DashboardService {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header;
+    use std::fmt::Write;
+
+    #[test]
+    fn rendering_is_capped() {
+        let mut writer = CappedWriter::new(10);
+        assert!(writer.write_str("0123456789").is_ok());
+        assert!(!writer.exceeded);
+        assert!(writer.write_str("a").is_err());
+        assert!(writer.exceeded);
+        assert_eq!(writer.content, "0123456789");
+        assert!(writer.content.capacity() <= 10);
+    }
+
+    #[test]
+    fn assets_are_served_cacheable() {
+        let response = asset_response("dashboard.css");
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get(header::CONTENT_TYPE).unwrap(),
+            "text/css; charset=utf-8"
+        );
+        assert_eq!(
+            headers.get(header::CACHE_CONTROL).unwrap(),
+            ASSET_CACHE_CONTROL
+        );
+        assert!(headers.get(header::ETAG).is_some());
+
+        assert_eq!(
+            asset_response("missing.css").status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn assets_are_preloaded() {
+        let css = DASHBOARD_ASSETS
+            .iter()
+            .find(|asset| asset.name == "dashboard.css")
+            .unwrap();
+        assert_eq!(
+            css.preload_link(),
+            "</_/assets/dashboard.css>; rel=preload; as=style"
+        );
+    }
+}
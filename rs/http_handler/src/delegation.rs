@@ -0,0 +1,123 @@
+//! Module that deals with requests to /api/v2/subnet/.../delegation
+use crate::{
+    common::{cbor_response, make_plaintext_response},
+    EndpointService,
+};
+use hyper::{Body, Response, StatusCode};
+use ic_types::messages::{CertificateDelegation, ReplicaHealthStatus};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use tower::{util::BoxCloneService, BoxError, Service};
+
+/// Returns the NNS delegation of this subnet, as included in the certificates
+/// of its responses. Agents and boundary nodes can prefetch and cache it,
+/// instead of extracting it from every response.
+#[derive(Clone)]
+pub(crate) struct DelegationService {
+    health_status: Arc<RwLock<ReplicaHealthStatus>>,
+    delegation_from_nns: Arc<RwLock<Option<CertificateDelegation>>>,
+}
+
+impl DelegationService {
+    pub(crate) fn new_service(
+        health_status: Arc<RwLock<ReplicaHealthStatus>>,
+        delegation_from_nns: Arc<RwLock<Option<CertificateDelegation>>>,
+    ) -> EndpointService {
+        BoxCloneService::new(Self {
+            health_status,
+            delegation_from_nns,
+        })
+    }
+}
+
+impl Service<Body> for DelegationService {
+    type Response = Response<Body>;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _unused: Body) -> Self::Future {
+        let delegation = self.delegation_from_nns.read().unwrap().clone();
+        let res = match delegation {
+            Some(delegation) => cbor_response(&delegation),
+            // The delegation is loaded before the replica becomes healthy. A
+            // healthy replica without one is on the NNS subnet, which doesn't
+            // need a delegation.
+            None if *self.health_status.read().unwrap() == ReplicaHealthStatus::Healthy => {
+                make_plaintext_response(
+                    StatusCode::NOT_FOUND,
+                    "This subnet has no NNS delegation.".to_string(),
+                )
+            }
+            None => make_plaintext_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Replica is starting. Check the /api/v2/status for more information.".to_string(),
+            ),
+        };
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::CONTENT_TYPE_CBOR;
+    use hyper::header;
+    use ic_types::messages::Blob;
+    use tower::ServiceExt;
+
+    async fn delegation_response(
+        health_status: ReplicaHealthStatus,
+        delegation: Option<CertificateDelegation>,
+    ) -> Response<Body> {
+        DelegationService::new_service(
+            Arc::new(RwLock::new(health_status)),
+            Arc::new(RwLock::new(delegation)),
+        )
+        .oneshot(Body::empty())
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn delegation_is_served_as_cbor() {
+        let delegation = CertificateDelegation {
+            subnet_id: Blob(vec![1, 2, 3]),
+            certificate: Blob(vec![4, 5, 6]),
+        };
+
+        let response =
+            delegation_response(ReplicaHealthStatus::Healthy, Some(delegation.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], CONTENT_TYPE_CBOR);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            serde_cbor::from_slice::<CertificateDelegation>(&body).unwrap(),
+            delegation
+        );
+    }
+
+    #[tokio::test]
+    async fn healthy_replicas_without_a_delegation_are_on_the_nns_subnet() {
+        let response = delegation_response(ReplicaHealthStatus::Healthy, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn starting_replicas_without_a_delegation_are_unavailable() {
+        for health_status in [
+            ReplicaHealthStatus::Starting,
+            ReplicaHealthStatus::WaitingForCertifiedState,
+            ReplicaHealthStatus::WaitingForRootDelegation,
+        ] {
+            let response = delegation_response(health_status, None).await;
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Draining of connections, at the end of their lifetime or on shutdown.
+//!
+//! A draining connection stops accepting new requests: hyper sends a GOAWAY
+//! frame on HTTP/2 connections, and closes HTTP/1.1 connections once the
+//! request in flight, if any, was answered. Clients thus retry on a new
+//! connection instead of seeing a reset. Requests in flight have a grace
+//! period to complete, after which the connection is dropped. So are HTTP/1.1
+//! connections that did not receive any request yet, which hyper does not
+//! close on drain.
+
+use crate::{
+    metrics::HttpHandlerMetrics,
+    types::{ApiReqType, DrainReason},
+    HttpError,
+};
+use futures::future;
+use hyper::{server::conn::Connection, Body, Request, Response};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, watch},
+    time::{sleep, timeout},
+};
+use tower::util::BoxService;
+
+pub(crate) type ConnectionService = BoxService<Request<Body>, Response<Body>, HttpError>;
+
+/// How long the shutdown waits beyond the grace period, so that connections
+/// dropped at the end of the grace period are accounted for in the report.
+const ABORT_SLACK: Duration = Duration::from_millis(500);
+
+/// Shuts the HTTP server down gracefully.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shutdown: Arc<watch::Sender<bool>>,
+    closed: Arc<tokio::sync::Mutex<mpsc::Receiver<()>>>,
+    grace_period: Duration,
+    stats: Arc<DrainStats>,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting connections and drains the open ones. Returns once all
+    /// connections are closed, or shortly after the drain grace period, with
+    /// a report of how the shutdown went.
+    pub async fn shutdown(&self) -> ShutdownReport {
+        let outstanding_requests = self.stats.in_flight_requests();
+        let _ = self.shutdown.send(true);
+        let _ = timeout(self.grace_period + ABORT_SLACK, async {
+            self.closed.lock().await.recv().await
+        })
+        .await;
+        ShutdownReport {
+            drained_connections: self.stats.drained_connections.load(Ordering::Relaxed),
+            aborted_connections: self.stats.aborted_connections.load(Ordering::Relaxed),
+            outstanding_requests,
+        }
+    }
+}
+
+/// How the connections open at shutdown were closed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// The connections closed once their requests in flight completed.
+    pub drained_connections: u64,
+    /// The connections dropped at the end of the grace period.
+    pub aborted_connections: u64,
+    /// The requests in flight when the shutdown started, per endpoint.
+    pub outstanding_requests: BTreeMap<&'static str, u64>,
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "drained {} connections, aborted {} connections, outstanding requests: ",
+            self.drained_connections, self.aborted_connections
+        )?;
+        if self.outstanding_requests.is_empty() {
+            return write!(f, "none");
+        }
+        for (i, (endpoint, count)) in self.outstanding_requests.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={}", endpoint, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the connections and requests affected by a shutdown.
+#[derive(Default)]
+pub(crate) struct DrainStats {
+    drained_connections: AtomicU64,
+    aborted_connections: AtomicU64,
+    in_flight: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl DrainStats {
+    /// Counts a request to the endpoint of `api_req_type` as in flight until
+    /// the returned guard is dropped.
+    pub(crate) fn start_request(self: &Arc<Self>, api_req_type: ApiReqType) -> InFlightRequest {
+        let endpoint = api_req_type.into();
+        *self.in_flight.lock().unwrap().entry(endpoint).or_default() += 1;
+        InFlightRequest {
+            stats: Arc::clone(self),
+            endpoint,
+        }
+    }
+
+    fn in_flight_requests(&self) -> BTreeMap<&'static str, u64> {
+        self.in_flight.lock().unwrap().clone()
+    }
+
+    fn observe_drain(&self, completed: bool) {
+        let counter = if completed {
+            &self.drained_connections
+        } else {
+            &self.aborted_connections
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A request in flight, see `DrainStats::start_request`.
+pub(crate) struct InFlightRequest {
+    stats: Arc<DrainStats>,
+    endpoint: &'static str,
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        let mut in_flight = self.stats.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(self.endpoint) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(self.endpoint);
+            }
+        }
+    }
+}
+
+/// Tells the accept loop and the connections that the server shuts down.
+#[derive(Clone)]
+pub(crate) struct DrainSignal {
+    shutdown: watch::Receiver<bool>,
+    stats: Arc<DrainStats>,
+    // Dropped along with the signal, so that the `ShutdownHandle` knows when
+    // all connections are closed.
+    _open: mpsc::Sender<()>,
+}
+
+impl DrainSignal {
+    /// Resolves once the server shuts down.
+    pub(crate) async fn draining(&mut self) {
+        while !*self.shutdown.borrow() {
+            if self.shutdown.changed().await.is_err() {
+                // The handle was dropped without shutting down.
+                future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// The statistics reported on shutdown.
+    pub(crate) fn stats(&self) -> Arc<DrainStats> {
+        Arc::clone(&self.stats)
+    }
+}
+
+pub(crate) fn shutdown_channel(grace_period: Duration) -> (ShutdownHandle, DrainSignal) {
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let (open_sender, open_receiver) = mpsc::channel(1);
+    let stats = Arc::new(DrainStats::default());
+    (
+        ShutdownHandle {
+            shutdown: Arc::new(shutdown_sender),
+            closed: Arc::new(tokio::sync::Mutex::new(open_receiver)),
+            grace_period,
+            stats: Arc::clone(&stats),
+        },
+        DrainSignal {
+            shutdown: shutdown_receiver,
+            stats,
+            _open: open_sender,
+        },
+    )
+}
+
+/// Serves `connection` until it is closed, draining it after `max_lifetime`
+/// or on shutdown. Returns `None` if requests in flight did not complete
+/// within `grace_period` and the connection was dropped.
+pub(crate) async fn serve_until_drained<I>(
+    connection: Connection<I, ConnectionService>,
+    mut drain_signal: DrainSignal,
+    max_lifetime: Option<Duration>,
+    grace_period: Duration,
+    metrics: &HttpHandlerMetrics,
+) -> Option<Result<(), hyper::Error>>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::pin!(connection);
+    let end_of_lifetime = async {
+        match max_lifetime {
+            Some(max_lifetime) => sleep(max_lifetime).await,
+            None => future::pending().await,
+        }
+    };
+    let reason = tokio::select! {
+        result = connection.as_mut() => return Some(result),
+        _ = end_of_lifetime => DrainReason::MaxLifetime,
+        _ = drain_signal.draining() => DrainReason::Shutdown,
+    };
+
+    connection.as_mut().graceful_shutdown();
+    metrics.connections_draining.inc();
+    let drain_start = Instant::now();
+    let result = timeout(grace_period, connection).await.ok();
+    metrics.connections_draining.dec();
+    if let DrainReason::Shutdown = reason {
+        drain_signal.stats.observe_drain(result.is_some());
+    }
+    metrics.observe_connection_drain(reason, result.is_some(), drain_start.elapsed());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{client::conn::handshake, server::conn::Http};
+    use ic_metrics::MetricsRegistry;
+    use tokio::{io::duplex, sync::oneshot};
+    use tower::service_fn;
+
+    const GRACE_PERIOD: Duration = Duration::from_millis(100);
+
+    // Serves one connection with `service` in the background, returning a
+    // client for it and the result of `serve_until_drained`.
+    async fn serve(
+        service: ConnectionService,
+        drain_signal: DrainSignal,
+        max_lifetime: Option<Duration>,
+        metrics: HttpHandlerMetrics,
+    ) -> (
+        hyper::client::conn::SendRequest<Body>,
+        tokio::task::JoinHandle<Option<Result<(), hyper::Error>>>,
+    ) {
+        let (client_io, server_io) = duplex(1024);
+        let connection = Http::new().serve_connection(server_io, service);
+        let server = tokio::spawn(async move {
+            serve_until_drained(
+                connection,
+                drain_signal,
+                max_lifetime,
+                GRACE_PERIOD,
+                &metrics,
+            )
+            .await
+        });
+        let (client, client_connection) = handshake(client_io).await.unwrap();
+        tokio::spawn(client_connection);
+        (client, server)
+    }
+
+    fn drains(metrics: &HttpHandlerMetrics, reason: &str, status: &str) -> u64 {
+        metrics
+            .connection_drain_duration
+            .with_label_values(&[reason, status])
+            .get_sample_count()
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_drained() {
+        let metrics = HttpHandlerMetrics::new(&MetricsRegistry::new());
+        let ok = || {
+            BoxService::new(service_fn(|_| async {
+                Ok::<_, HttpError>(Response::new(Body::from("ok")))
+            }))
+        };
+
+        let (handle, drain_signal) = shutdown_channel(GRACE_PERIOD);
+        let (mut client, server) = serve(ok(), drain_signal, None, metrics.clone()).await;
+        let response = client.send_request(Request::new(Body::empty())).await;
+        assert!(response.unwrap().status().is_success());
+        let report = handle.shutdown().await;
+        assert!(matches!(server.await.unwrap(), Some(Ok(()))));
+        assert_eq!(drains(&metrics, "shutdown", "success"), 1);
+        assert_eq!(report.drained_connections, 1);
+        assert_eq!(report.aborted_connections, 0);
+
+        let (_handle, drain_signal) = shutdown_channel(GRACE_PERIOD);
+        let (mut client, server) = serve(
+            ok(),
+            drain_signal,
+            Some(Duration::from_millis(50)),
+            metrics.clone(),
+        )
+        .await;
+        let response = client.send_request(Request::new(Body::empty())).await;
+        assert!(response.unwrap().status().is_success());
+        assert!(matches!(server.await.unwrap(), Some(Ok(()))));
+        assert_eq!(drains(&metrics, "max_lifetime", "success"), 1);
+        assert_eq!(metrics.connections_draining.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn requests_in_flight_are_dropped_after_the_grace_period() {
+        let metrics = HttpHandlerMetrics::new(&MetricsRegistry::new());
+        let (started_sender, started_receiver) = oneshot::channel();
+        let mut started_sender = Some(started_sender);
+        let stuck = BoxService::new(service_fn(move |_| {
+            let _ = started_sender.take().unwrap().send(());
+            future::pending::<Result<Response<Body>, HttpError>>()
+        }));
+
+        let (handle, drain_signal) = shutdown_channel(GRACE_PERIOD);
+        let (mut client, server) = serve(stuck, drain_signal, None, metrics.clone()).await;
+        let response = tokio::spawn(client.send_request(Request::new(Body::empty())));
+        started_receiver.await.unwrap();
+        let report = handle.shutdown().await;
+
+        assert!(server.await.unwrap().is_none());
+        assert!(response.await.unwrap().is_err());
+        assert_eq!(drains(&metrics, "shutdown", "error"), 1);
+        assert_eq!(report.drained_connections, 0);
+        assert_eq!(report.aborted_connections, 1);
+    }
+
+    #[tokio::test]
+    async fn requests_in_flight_at_shutdown_are_reported() {
+        let (handle, drain_signal) = shutdown_channel(GRACE_PERIOD);
+        let stats = drain_signal.stats();
+        let query = stats.start_request(ApiReqType::Query);
+        let _call = stats.start_request(ApiReqType::Call);
+        let _other_query = stats.start_request(ApiReqType::Query);
+        drop(query);
+        drop(drain_signal);
+
+        let report = handle.shutdown().await;
+        assert_eq!(
+            report.outstanding_requests,
+            BTreeMap::from([("call", 1), ("query", 1)])
+        );
+        assert_eq!(
+            report.to_string(),
+            "drained 0 connections, aborted 0 connections, outstanding requests: call=1, query=1"
+        );
+    }
+}
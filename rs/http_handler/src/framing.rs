@@ -0,0 +1,330 @@
+//! Defenses against HTTP request smuggling.
+//!
+//! A proxy in front of the replica and the replica itself must agree on where
+//! a request ends, otherwise a client can hide a second request in the body of
+//! the first, which the proxy never sees. If `reject_ambiguous_requests` is
+//! set, requests whose framing or target may be interpreted differently by
+//! another HTTP implementation are rejected before they are routed.
+//!
+//! hyper already rejects `Transfer-Encoding` on HTTP/1.0 requests, encodings
+//! not ending in `chunked`, and differing `Content-Length` headers. It drops a
+//! `Content-Length` following a `Transfer-Encoding` while parsing, so that one
+//! preceding it is the only one left to reject here.
+//!
+//! Once a request is rejected, the bytes that follow it on the connection
+//! can't be trusted to start the next request, so HTTP/1.x connections are
+//! closed after the rejection.
+
+use crate::common::make_plaintext_response;
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    Body, HeaderMap, Request, Response, StatusCode, Uri, Version,
+};
+use strum_macros::IntoStaticStr;
+
+/// Headers that may appear at most once in a request. Duplicates with the same
+/// value are merged, duplicates with different values are rejected.
+const SINGLETON_HEADERS: [HeaderName; 4] = [
+    header::HOST,
+    header::CONTENT_LENGTH,
+    header::CONTENT_TYPE,
+    header::TRANSFER_ENCODING,
+];
+
+/// Why the framing of a request is ambiguous.
+#[derive(Clone, Debug, PartialEq, Eq, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum AmbiguousFraming {
+    /// Both `Transfer-Encoding` and `Content-Length` are set.
+    TransferEncodingWithContentLength,
+    /// The `Transfer-Encoding` is anything but a single `chunked`.
+    UnsupportedTransferEncoding,
+    /// A header that may appear once appears with different values.
+    ConflictingHeader(HeaderName),
+    /// The target of an HTTP/1.x request is in absolute form, as sent to a
+    /// forward proxy, and the peer is not a trusted proxy.
+    AbsoluteFormTarget,
+}
+
+impl std::fmt::Display for AmbiguousFraming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TransferEncodingWithContentLength => {
+                write!(f, "Both Transfer-Encoding and Content-Length are set.")
+            }
+            Self::UnsupportedTransferEncoding => {
+                write!(f, "The only supported Transfer-Encoding is chunked.")
+            }
+            Self::ConflictingHeader(name) => {
+                write!(f, "The header {} is set more than once.", name)
+            }
+            Self::AbsoluteFormTarget => write!(f, "The request target must be in origin form."),
+        }
+    }
+}
+
+/// Checks that the framing and target of `req` are unambiguous, merging the
+/// duplicates of headers that may only appear once if they are identical.
+pub(crate) fn check_framing<B>(
+    req: &mut Request<B>,
+    from_trusted_proxy: bool,
+) -> Result<(), AmbiguousFraming> {
+    if !from_trusted_proxy && is_absolute_form(req.version(), req.uri()) {
+        return Err(AmbiguousFraming::AbsoluteFormTarget);
+    }
+    let headers = req.headers_mut();
+    for name in SINGLETON_HEADERS.iter() {
+        merge_duplicates(headers, name)?;
+    }
+    if let Some(value) = headers.get(header::TRANSFER_ENCODING) {
+        if headers.contains_key(header::CONTENT_LENGTH) {
+            return Err(AmbiguousFraming::TransferEncodingWithContentLength);
+        }
+        if !value.as_bytes().eq_ignore_ascii_case(b"chunked") {
+            return Err(AmbiguousFraming::UnsupportedTransferEncoding);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the `400 Bad Request` rejecting a request of HTTP `version` for
+/// `reason`, closing the connection if it is HTTP/1.x.
+pub(crate) fn ambiguous_framing_response(
+    reason: &AmbiguousFraming,
+    version: Version,
+) -> Response<Body> {
+    let mut response = make_plaintext_response(StatusCode::BAD_REQUEST, reason.to_string());
+    // HTTP/2 frames requests itself and forbids the `Connection` header.
+    if version <= Version::HTTP_11 {
+        response
+            .headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    response
+}
+
+// HTTP/2 requests always carry a scheme and an authority, only HTTP/1.x
+// targets can be in absolute form.
+fn is_absolute_form(version: Version, uri: &Uri) -> bool {
+    version <= Version::HTTP_11 && uri.authority().is_some()
+}
+
+// Leaves a single `name` header if all its values are identical.
+fn merge_duplicates(headers: &mut HeaderMap, name: &HeaderName) -> Result<(), AmbiguousFraming> {
+    let mut values = headers.get_all(name).iter();
+    let first = match values.next() {
+        Some(first) => first.clone(),
+        None => return Ok(()),
+    };
+    let mut duplicated = false;
+    for value in values {
+        if *value != first {
+            return Err(AmbiguousFraming::ConflictingHeader(name.clone()));
+        }
+        duplicated = true;
+    }
+    if duplicated {
+        headers.insert(name, first);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::post(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, HeaderValue::from_str(value).unwrap());
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn unambiguous_requests_are_accepted() {
+        for headers in [
+            vec![("content-length", "10")],
+            vec![("transfer-encoding", "chunked")],
+            vec![("transfer-encoding", "Chunked")],
+            vec![("host", "ic0.app"), ("content-type", "application/cbor")],
+        ] {
+            let mut req = request("/api/v2/status", &headers);
+            assert_eq!(check_framing(&mut req, false), Ok(()));
+        }
+    }
+
+    // Variations of the classic CL.TE and TE.CL attacks, and of the
+    // obfuscated `Transfer-Encoding` headers of TE.TE attacks.
+    #[test]
+    fn ambiguous_framing_is_rejected() {
+        let cases = [
+            (
+                vec![("content-length", "6"), ("transfer-encoding", "chunked")],
+                AmbiguousFraming::TransferEncodingWithContentLength,
+            ),
+            (
+                vec![("transfer-encoding", "xchunked")],
+                AmbiguousFraming::UnsupportedTransferEncoding,
+            ),
+            (
+                vec![("transfer-encoding", "chunked, chunked")],
+                AmbiguousFraming::UnsupportedTransferEncoding,
+            ),
+            (
+                vec![("transfer-encoding", "gzip, chunked")],
+                AmbiguousFraming::UnsupportedTransferEncoding,
+            ),
+            (
+                vec![("transfer-encoding", "chunked"), ("transfer-encoding", "x")],
+                AmbiguousFraming::ConflictingHeader(header::TRANSFER_ENCODING),
+            ),
+            (
+                vec![("content-length", "0"), ("content-length", "44")],
+                AmbiguousFraming::ConflictingHeader(header::CONTENT_LENGTH),
+            ),
+            (
+                vec![("host", "ic0.app"), ("host", "internal")],
+                AmbiguousFraming::ConflictingHeader(header::HOST),
+            ),
+            (
+                vec![
+                    ("content-type", "application/cbor"),
+                    ("content-type", "text/plain"),
+                ],
+                AmbiguousFraming::ConflictingHeader(header::CONTENT_TYPE),
+            ),
+        ];
+        for (headers, expected) in cases {
+            let mut req = request("/api/v2/status", &headers);
+            assert_eq!(
+                check_framing(&mut req, true),
+                Err(expected),
+                "{:?}",
+                headers
+            );
+        }
+    }
+
+    // The `Transfer-Encoding` mutations of smuggler.py and of the request
+    // smuggling labs of PortSwigger, as far as they survive hyper's parser,
+    // which trims surrounding whitespace and rejects control characters.
+    #[test]
+    fn obfuscated_transfer_encodings_are_rejected() {
+        for value in [
+            "chunk",
+            "chunked;",
+            "chunked,",
+            ",chunked",
+            "\"chunked\"",
+            "'chunked'",
+            "chu nked",
+            "chunked\tx",
+            "identity",
+            "identity, chunked",
+            "cow, chunked",
+            "chunked, identity",
+            "x-chunked",
+            "chunked-false",
+        ] {
+            let mut req = request("/api/v2/status", &[("transfer-encoding", value)]);
+            assert_eq!(
+                check_framing(&mut req, true),
+                Err(AmbiguousFraming::UnsupportedTransferEncoding),
+                "{:?}",
+                value
+            );
+        }
+
+        // Non-ASCII lookalikes of `chunked`.
+        for value in [&b"chunked\xff"[..], b"ch\xc3\xbcnked", b"\xc2\xa0chunked"] {
+            let mut req = request("/api/v2/status", &[]);
+            req.headers_mut().insert(
+                header::TRANSFER_ENCODING,
+                HeaderValue::from_bytes(value).unwrap(),
+            );
+            assert_eq!(
+                check_framing(&mut req, true),
+                Err(AmbiguousFraming::UnsupportedTransferEncoding),
+                "{:?}",
+                value
+            );
+        }
+
+        // A second, differently spelled header hiding behind a valid one, or
+        // a valid one behind a bogus one.
+        for values in [
+            ["chunked", "identity"],
+            ["x", "chunked"],
+            ["chunked", "Chunked"],
+        ] {
+            let mut req = request(
+                "/api/v2/status",
+                &[
+                    ("transfer-encoding", values[0]),
+                    ("transfer-encoding", values[1]),
+                ],
+            );
+            assert_eq!(
+                check_framing(&mut req, true),
+                Err(AmbiguousFraming::ConflictingHeader(
+                    header::TRANSFER_ENCODING
+                )),
+                "{:?}",
+                values
+            );
+        }
+    }
+
+    #[test]
+    fn rejections_close_http_1_connections() {
+        let reason = AmbiguousFraming::TransferEncodingWithContentLength;
+        for version in [Version::HTTP_10, Version::HTTP_11] {
+            let response = ambiguous_framing_response(&reason, version);
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(response.headers()[header::CONNECTION], "close");
+        }
+        let response = ambiguous_framing_response(&reason, Version::HTTP_2);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(!response.headers().contains_key(header::CONNECTION));
+    }
+
+    #[test]
+    fn identical_duplicates_are_merged() {
+        let mut req = request(
+            "/api/v2/status",
+            &[
+                ("transfer-encoding", "chunked"),
+                ("transfer-encoding", "chunked"),
+                ("content-type", "application/cbor"),
+                ("content-type", "application/cbor"),
+            ],
+        );
+        assert_eq!(check_framing(&mut req, false), Ok(()));
+        assert_eq!(
+            req.headers()
+                .get_all(header::TRANSFER_ENCODING)
+                .iter()
+                .count(),
+            1
+        );
+        assert_eq!(
+            req.headers().get_all(header::CONTENT_TYPE).iter().count(),
+            1
+        );
+    }
+
+    #[test]
+    fn absolute_form_targets_are_only_accepted_from_trusted_proxies() {
+        let mut req = request("http://internal/api/v2/status", &[]);
+        assert_eq!(
+            check_framing(&mut req, false),
+            Err(AmbiguousFraming::AbsoluteFormTarget)
+        );
+        assert_eq!(check_framing(&mut req, true), Ok(()));
+
+        let mut req = request("http://internal/api/v2/status", &[]);
+        *req.version_mut() = Version::HTTP_2;
+        assert_eq!(check_framing(&mut req, false), Ok(()));
+    }
+}
@@ -0,0 +1,161 @@
+//! Entry points for the fuzz targets in `rs/http_handler/fuzz`. Only compiled
+//! with the `fuzzing_code` feature.
+//!
+//! The endpoint services are replaced by stubs that receive the body through
+//! the real `BodyReceiverLayer` and reply with `200 OK`, so that the fuzzers
+//! exercise HTTP parsing, routing and body handling without a replica behind
+//! them.
+use crate::{
+    alternate_nodes::AlternateNodes, body::BodyReceiverLayer, client_addr::TrustedProxies,
+    connection_reuse::ConnectionReuse, create_main_service, idempotency::IdempotencyKeys,
+    limits::LimitProfile, maintenance::MaintenanceMode, make_http, make_routes,
+    metrics::HttpHandlerMetrics, pprof::PprofAccess, read_state::CanisterInfoReader,
+    response_budget::ResponseBudget, security_headers::SecurityHeaders,
+    state_reader_executor::StateReaderExecutor, subnet_public_key::SubnetPublicKeyReader,
+    tls_config::TlsConfigWatcher, types::*, EndpointService, EndpointServices, HttpHandler,
+    ReplicaHealthStatus,
+};
+use hyper::{Body, Response};
+use ic_config::http_handler::Config;
+use ic_interfaces::registry::RegistryClient;
+use ic_interfaces_state_manager::StateReader;
+use ic_logger::replica_logger::no_op_logger;
+use ic_metrics::MetricsRegistry;
+use ic_registry_subnet_type::SubnetType;
+use ic_replicated_state::ReplicatedState;
+use ic_types::{NodeId, PrincipalId, SubnetId};
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tower::{service_fn, util::BoxCloneService, ServiceBuilder};
+
+// The size of the in-memory pipe between the fuzzer and the server.
+const DUPLEX_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Serves the raw bytes of an HTTP/1.1 or HTTP/2 (prior knowledge) request
+/// as if received on a plaintext connection of an application subnet, and
+/// returns the raw bytes of the response.
+///
+/// Requests to `/_/pprof/` are answered with an empty response without being
+/// served, as they run CPU profiles for tens of seconds.
+pub async fn serve_raw_request(
+    registry_client: Arc<dyn RegistryClient>,
+    state_reader: Arc<dyn StateReader<State = ReplicatedState>>,
+    request: &[u8],
+) -> Vec<u8> {
+    if request
+        .windows(b"/_/pprof/".len())
+        .any(|w| w == b"/_/pprof/")
+    {
+        return vec![];
+    }
+
+    let metrics = HttpHandlerMetrics::new(&MetricsRegistry::new());
+    let limits = LimitProfile::for_subnet_type(SubnetType::Application);
+    let routes = make_routes(
+        EndpointServices {
+            call: stub_service(metrics.clone(), ApiReqType::Call),
+            query: stub_service(metrics.clone(), ApiReqType::Query),
+            read_state: stub_service(metrics.clone(), ApiReqType::ReadState),
+            catchup_protobuf: stub_service(metrics.clone(), ApiReqType::CatchUpPackage),
+            catchup_cbor: stub_service(metrics.clone(), ApiReqType::CatchUpPackage),
+            status: stub_service(metrics.clone(), ApiReqType::Status),
+            dashboard: stub_service(metrics.clone(), ApiReqType::Dashboard),
+            delegation: stub_service(metrics.clone(), ApiReqType::Delegation),
+        },
+        // Answers with `503 Service Unavailable` without reading the state.
+        CanisterInfoReader::new(
+            Arc::new(RwLock::new(ReplicaHealthStatus::Starting)),
+            Arc::default(),
+            StateReaderExecutor::new(Arc::clone(&state_reader)),
+            None,
+        ),
+        SubnetPublicKeyReader::new(
+            Arc::new(RwLock::new(ReplicaHealthStatus::Starting)),
+            Arc::default(),
+            StateReaderExecutor::new(Arc::clone(&state_reader)),
+            None,
+        ),
+        PprofAccess::new(None),
+    );
+    let subnet_id = SubnetId::from(PrincipalId::new_subnet_test_id(0));
+    let alternate_nodes = Arc::new(AlternateNodes::new(
+        Arc::clone(&registry_client),
+        subnet_id,
+        NodeId::from(PrincipalId::new_node_test_id(0)),
+        3,
+    ));
+    let http_handler = HttpHandler {
+        subnet_id,
+        registry_client,
+        routes,
+        trusted_proxies: Arc::new(TrustedProxies::default()),
+        client_origins: Arc::default(),
+        tls_config: TlsConfigWatcher::default(),
+        max_connection_write_bytes_per_second: None,
+        max_connection_lifetime: None,
+        connection_drain_grace_period: Duration::ZERO,
+        tls_handshake_timeout: Duration::from_secs(10),
+        drain_stats: Arc::default(),
+        idempotency_keys: Arc::new(IdempotencyKeys::default()),
+        limits: Arc::new(limits.clone()),
+        state_reader_executor: StateReaderExecutor::new(state_reader),
+        header_limits: limits.header_limits(),
+        reject_ambiguous_requests: true,
+        alternate_nodes,
+        maintenance_mode: Arc::new(MaintenanceMode::new(Duration::from_secs(30))),
+        security_headers: SecurityHeaders::default(),
+        response_budget: ResponseBudget::new(
+            Config::default().max_buffered_response_bytes,
+            metrics.buffered_response_bytes.clone(),
+        ),
+    };
+    let service = create_main_service(
+        no_op_logger(),
+        metrics,
+        http_handler,
+        AppLayer::Http,
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        ConnectionReuse::default(),
+    );
+
+    let (mut client, server) = tokio::io::duplex(DUPLEX_BUFFER_BYTES);
+    let serve = async move {
+        // Errors are expected for malformed requests, only panics are
+        // interesting.
+        let _ = make_http(&limits).serve_connection(server, service).await;
+    };
+    let send = async move {
+        let mut response = vec![];
+        if client.write_all(request).await.is_ok() && client.shutdown().await.is_ok() {
+            let _ = client.read_to_end(&mut response).await;
+        }
+        response
+    };
+    tokio::join!(serve, send).1
+}
+
+type StubFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+fn stub_service(metrics: HttpHandlerMetrics, api_req_type: ApiReqType) -> EndpointService {
+    BoxCloneService::new(
+        ServiceBuilder::new()
+            .layer(BodyReceiverLayer::new(metrics, api_req_type))
+            .service(service_fn(|_body: Vec<u8>| {
+                Box::pin(async { Ok(Response::new(Body::empty())) }) as StubFuture
+            })),
+    )
+}
+
+/// Parses arbitrary bytes as the response of an NNS node to the request for
+/// the delegation of this subnet, bounding the sizes of its certificate and
+/// tree as at startup.
+pub fn parse_delegation_response(data: &[u8]) {
+    let _ = crate::root_delegation::parse_delegation_response(data);
+}
@@ -0,0 +1,236 @@
+//! Module that deduplicates retried call submissions carrying an
+//! `idempotency-key` header.
+//!
+//! Clients on flaky networks may not see the `202 Accepted` of a call and
+//! submit it again. If the call carried an idempotency key, the retry is
+//! answered with the message id of the original submission, and its status
+//! in the latest certified state if known, instead of being submitted again.
+//! Keys are scoped to the request path, i.e. to the effective canister, and
+//! to the sender of the call, and are remembered for a few minutes only.
+//!
+//! Keys are only remembered for calls that were submitted, i.e. whose
+//! signature was verified, and a retry is only answered with the original
+//! submission if it is the very same call, i.e. has the same message id.
+//! Other calls under the same key are submitted as usual, so that a key can
+//! neither be used to learn about the calls of others, nor to keep a sender
+//! from submitting calls.
+use crate::common::get_cors_headers;
+use hyper::{header::HeaderValue, Body, HeaderMap, Response, StatusCode};
+use ic_types::{
+    messages::{MessageId, SignedIngress, SignedRequestBytes},
+    UserId,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+pub(crate) const MESSAGE_ID_HEADER: &str = "x-ic-message-id";
+pub(crate) const REQUEST_STATUS_HEADER: &str = "x-ic-request-status";
+
+/// How long the message id of a submission is remembered for.
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The maximum number of remembered keys. Once reached, further keys are not
+/// remembered until old ones expire.
+const MAX_IDEMPOTENCY_KEYS: usize = 100_000;
+
+/// The maximum length of an idempotency key. Longer keys are ignored.
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 256;
+
+#[derive(Default)]
+struct Entries {
+    message_ids: HashMap<String, (MessageId, Instant)>,
+    // Keys in the order they were inserted in, hence by expiry.
+    expiry_order: VecDeque<(String, Instant)>,
+}
+
+impl Entries {
+    fn prune(&mut self, now: Instant) {
+        while let Some((key, expiry)) = self.expiry_order.front() {
+            if *expiry > now {
+                break;
+            }
+            // The key may have been inserted again since, with a later expiry.
+            if self
+                .message_ids
+                .get(key)
+                .map_or(false, |(_, current)| current == expiry)
+            {
+                self.message_ids.remove(key);
+            }
+            self.expiry_order.pop_front();
+        }
+    }
+}
+
+/// The message ids of recent call submissions, by idempotency key.
+#[derive(Default)]
+pub(crate) struct IdempotencyKeys {
+    entries: Mutex<Entries>,
+}
+
+impl IdempotencyKeys {
+    /// Returns the message id submitted under `key`, if it has not expired.
+    pub(crate) fn get(&self, key: &str, now: Instant) -> Option<MessageId> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.prune(now);
+        entries.message_ids.get(key).map(|(id, _)| id.clone())
+    }
+
+    /// Remembers that `message_id` was submitted under `key`.
+    pub(crate) fn insert(&self, key: String, message_id: MessageId, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.prune(now);
+        if entries.expiry_order.len() >= MAX_IDEMPOTENCY_KEYS {
+            return;
+        }
+        let expiry = now + IDEMPOTENCY_KEY_TTL;
+        entries.expiry_order.push_back((key.clone(), expiry));
+        entries.message_ids.insert(key, (message_id, expiry));
+    }
+}
+
+/// Returns the idempotency key sent with a call, if the request carries a
+/// valid one.
+pub(crate) fn idempotency_key_header(headers: &HeaderMap) -> Option<&str> {
+    let key = headers.get(IDEMPOTENCY_KEY_HEADER)?.to_str().ok()?.trim();
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+        return None;
+    }
+    Some(key)
+}
+
+/// Returns the key under which the message id of a call to `path` by
+/// `sender` is remembered, given the idempotency key sent with the call.
+pub(crate) fn idempotency_key(path: &str, sender: &UserId, key: &str) -> String {
+    format!("{} {} {}", path, sender, key)
+}
+
+/// Returns the sender and the message id of the call in `body`, if it holds
+/// one. The signature of the call is not verified.
+pub(crate) fn call_identity(body: &[u8]) -> Option<(UserId, MessageId)> {
+    let msg: SignedIngress = SignedRequestBytes::from(body.to_vec()).try_into().ok()?;
+    Some((msg.sender(), msg.id()))
+}
+
+/// Adds the message id, and its status if known, to a `202 Accepted`
+/// response.
+pub(crate) fn add_message_id_headers(
+    response: &mut Response<Body>,
+    message_id: &MessageId,
+    status: Option<&'static str>,
+) {
+    let headers = response.headers_mut();
+    headers.insert(
+        MESSAGE_ID_HEADER,
+        HeaderValue::from_str(&message_id.to_string()).expect("hex is a valid header value"),
+    );
+    if let Some(status) = status {
+        headers.insert(REQUEST_STATUS_HEADER, HeaderValue::from_static(status));
+    }
+}
+
+/// The response to a retried submission of `message_id`.
+pub(crate) fn replayed_response(
+    message_id: &MessageId,
+    status: Option<&'static str>,
+) -> Response<Body> {
+    let mut response = Response::new(Body::from(""));
+    *response.status_mut() = StatusCode::ACCEPTED;
+    *response.headers_mut() = get_cors_headers();
+    add_message_id_headers(&mut response, message_id, status);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_types::{
+        messages::{Blob, HttpCallContent, HttpCanisterUpdate, HttpRequestEnvelope},
+        PrincipalId,
+    };
+    use std::convert::TryFrom;
+
+    fn message_id(byte: u8) -> MessageId {
+        MessageId::from([byte; 32])
+    }
+
+    #[test]
+    fn keys_are_validated() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(idempotency_key_header(&headers), None);
+        headers.insert(IDEMPOTENCY_KEY_HEADER, HeaderValue::from_static(" abc "));
+        assert_eq!(idempotency_key_header(&headers), Some("abc"));
+        headers.insert(
+            IDEMPOTENCY_KEY_HEADER,
+            HeaderValue::from_str(&"a".repeat(MAX_IDEMPOTENCY_KEY_LEN + 1)).unwrap(),
+        );
+        assert_eq!(idempotency_key_header(&headers), None);
+    }
+
+    #[test]
+    fn keys_are_scoped_to_the_path_and_the_sender() {
+        let alice = UserId::from(PrincipalId::new_user_test_id(1));
+        let bob = UserId::from(PrincipalId::new_user_test_id(2));
+        let keys = [
+            idempotency_key("/a/call", &alice, "abc"),
+            idempotency_key("/b/call", &alice, "abc"),
+            idempotency_key("/a/call", &bob, "abc"),
+        ];
+        assert_ne!(keys[0], keys[1]);
+        assert_ne!(keys[0], keys[2]);
+    }
+
+    #[test]
+    fn calls_are_identified_by_sender_and_message_id() {
+        let sender = PrincipalId::new_user_test_id(1);
+        let envelope = |nonce: &[u8]| HttpRequestEnvelope::<HttpCallContent> {
+            content: HttpCallContent::Call {
+                update: HttpCanisterUpdate {
+                    canister_id: Blob(vec![42; 8]),
+                    method_name: "foo".to_string(),
+                    arg: Blob(vec![]),
+                    sender: Blob(sender.into_vec()),
+                    ingress_expiry: 1,
+                    nonce: Some(Blob(nonce.to_vec())),
+                },
+            },
+            sender_pubkey: None,
+            sender_sig: None,
+            sender_delegation: None,
+        };
+        let body = |nonce: &[u8]| Vec::from(SignedRequestBytes::try_from(envelope(nonce)).unwrap());
+        let expected_id = SignedIngress::try_from(envelope(b"1")).unwrap().id();
+
+        assert_eq!(
+            call_identity(&body(b"1")),
+            Some((UserId::from(sender), expected_id.clone()))
+        );
+        // Another call of the same sender has another message id.
+        assert_ne!(call_identity(&body(b"2")).unwrap().1, expected_id);
+        assert_eq!(call_identity(b"not a call"), None);
+    }
+
+    #[test]
+    fn message_ids_expire() {
+        let keys = IdempotencyKeys::default();
+        let start = Instant::now();
+        keys.insert("a".to_string(), message_id(1), start);
+        keys.insert(
+            "b".to_string(),
+            message_id(2),
+            start + Duration::from_secs(60),
+        );
+
+        assert_eq!(keys.get("a", start), Some(message_id(1)));
+        assert_eq!(keys.get("c", start), None);
+
+        let later = start + IDEMPOTENCY_KEY_TTL;
+        assert_eq!(keys.get("a", later), None);
+        assert_eq!(keys.get("b", later), Some(message_id(2)));
+    }
+}
@@ -0,0 +1,231 @@
+//! Module that caches rejections of the ingress filter.
+//!
+//! During bursts of calls to a canister that does not exist or is stopped,
+//! every call executes the ingress filter only to be rejected for the same
+//! reason. Such rejections are remembered for a short time, by effective
+//! canister, method and class of sender, and answered without executing the
+//! filter again.
+//!
+//! Only rejections that can't depend on the arguments or the sender of a call
+//! are cached, i.e. the ones about the state of the canister. Acceptances are
+//! never cached, as `canister_inspect_message` may reject some calls to a
+//! method but not others. Calls to the management canister are not cached
+//! either, as their effective canister is taken from their arguments. All
+//! verdicts are forgotten once the latest state or the registry version
+//! changes, as the canister may have been created or started since.
+use ic_error_types::{ErrorCode, UserError};
+use ic_types::{messages::SignedIngressContent, CanisterId, Height, RegistryVersion};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long a rejection is remembered for, at most.
+const INGRESS_FILTER_CACHE_TTL: Duration = Duration::from_secs(2);
+
+/// The maximum number of remembered rejections. Once reached, further ones
+/// are not remembered until the cache is invalidated.
+const MAX_INGRESS_FILTER_CACHE_ENTRIES: usize = 10_000;
+
+/// The classes of senders, which rejections are remembered separately for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum SenderClass {
+    Anonymous,
+    Authenticated,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct FilterKey {
+    effective_canister_id: CanisterId,
+    method_name: String,
+    sender_class: SenderClass,
+}
+
+impl FilterKey {
+    fn new(ingress: &SignedIngressContent) -> Self {
+        let sender_class = if ingress.sender().get().is_anonymous() {
+            SenderClass::Anonymous
+        } else {
+            SenderClass::Authenticated
+        };
+        Self {
+            effective_canister_id: ingress.canister_id(),
+            method_name: ingress.method_name().to_string(),
+            sender_class,
+        }
+    }
+}
+
+/// The registry version and height of the latest state that the remembered
+/// rejections were observed at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FilterInputs {
+    pub registry_version: RegistryVersion,
+    pub state_height: Height,
+}
+
+#[derive(Default)]
+struct Entries {
+    inputs: Option<FilterInputs>,
+    rejections: HashMap<FilterKey, (UserError, Instant)>,
+}
+
+impl Entries {
+    // Forgets all rejections if the inputs of the filter changed.
+    fn invalidate(&mut self, inputs: FilterInputs) {
+        if self.inputs != Some(inputs) {
+            self.inputs = Some(inputs);
+            self.rejections.clear();
+        }
+    }
+}
+
+/// Recent rejections of the ingress filter that hold for all calls to the
+/// same method of a canister.
+#[derive(Default)]
+pub(crate) struct IngressFilterCache {
+    entries: Mutex<Entries>,
+}
+
+impl IngressFilterCache {
+    /// Returns the remembered rejection of calls like `ingress`, if any.
+    pub(crate) fn get(
+        &self,
+        ingress: &SignedIngressContent,
+        inputs: FilterInputs,
+        now: Instant,
+    ) -> Option<UserError> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.invalidate(inputs);
+        let key = FilterKey::new(ingress);
+        match entries.rejections.get(&key) {
+            Some((err, expiry)) if *expiry > now => Some(err.clone()),
+            Some(_) => {
+                entries.rejections.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Remembers the verdict of the ingress filter on `ingress`, if it is a
+    /// rejection that holds for all calls to the same method.
+    /// `addressed_to_subnet` tells whether `ingress` is a call to the
+    /// management canister.
+    pub(crate) fn insert(
+        &self,
+        ingress: &SignedIngressContent,
+        addressed_to_subnet: bool,
+        verdict: &Result<(), UserError>,
+        inputs: FilterInputs,
+        now: Instant,
+    ) {
+        let err = match verdict {
+            Err(err) if !addressed_to_subnet && is_cacheable(err.code()) => err,
+            _ => return,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.invalidate(inputs);
+        if entries.rejections.len() >= MAX_INGRESS_FILTER_CACHE_ENTRIES {
+            return;
+        }
+        entries.rejections.insert(
+            FilterKey::new(ingress),
+            (err.clone(), now + INGRESS_FILTER_CACHE_TTL),
+        );
+    }
+}
+
+// Returns true for the rejections about the state of the canister, which
+// don't depend on the arguments or the sender of a call.
+fn is_cacheable(code: ErrorCode) -> bool {
+    matches!(
+        code,
+        ErrorCode::CanisterNotFound
+            | ErrorCode::CanisterWasmModuleNotFound
+            | ErrorCode::CanisterEmpty
+            | ErrorCode::CanisterStopped
+            | ErrorCode::CanisterStopping
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_test_utilities::types::{ids::canister_test_id, messages::SignedIngressBuilder};
+
+    fn ingress(canister: u64, method: &str, nonce: u64) -> SignedIngressContent {
+        SignedIngressBuilder::new()
+            .canister_id(canister_test_id(canister))
+            .method_name(method)
+            .nonce(nonce)
+            .build()
+            .content()
+            .clone()
+    }
+
+    fn inputs(registry_version: u64, state_height: u64) -> FilterInputs {
+        FilterInputs {
+            registry_version: RegistryVersion::from(registry_version),
+            state_height: Height::from(state_height),
+        }
+    }
+
+    fn rejection(code: ErrorCode) -> Result<(), UserError> {
+        Err(UserError::new(code, "rejected"))
+    }
+
+    #[test]
+    fn rejections_apply_to_calls_to_the_same_method() {
+        let cache = IngressFilterCache::default();
+        let now = Instant::now();
+        let verdict = rejection(ErrorCode::CanisterNotFound);
+        cache.insert(&ingress(1, "a", 0), false, &verdict, inputs(1, 1), now);
+
+        // Calls differing in their arguments or nonce only are rejected alike.
+        assert_eq!(
+            cache.get(&ingress(1, "a", 1), inputs(1, 1), now),
+            verdict.clone().err()
+        );
+        assert_eq!(cache.get(&ingress(1, "b", 0), inputs(1, 1), now), None);
+        assert_eq!(cache.get(&ingress(2, "a", 0), inputs(1, 1), now), None);
+
+        let later = now + INGRESS_FILTER_CACHE_TTL;
+        assert_eq!(cache.get(&ingress(1, "a", 0), inputs(1, 1), later), None);
+    }
+
+    #[test]
+    fn rejections_are_forgotten_when_the_inputs_change() {
+        let cache = IngressFilterCache::default();
+        let now = Instant::now();
+        let verdict = rejection(ErrorCode::CanisterStopped);
+        for changed_inputs in [inputs(2, 1), inputs(1, 2)] {
+            cache.insert(&ingress(1, "a", 0), false, &verdict, inputs(1, 1), now);
+            assert!(cache.get(&ingress(1, "a", 0), inputs(1, 1), now).is_some());
+            assert_eq!(cache.get(&ingress(1, "a", 0), changed_inputs, now), None);
+            assert_eq!(cache.get(&ingress(1, "a", 0), inputs(1, 1), now), None);
+        }
+    }
+
+    #[test]
+    fn only_rejections_about_the_canister_are_cached() {
+        let cache = IngressFilterCache::default();
+        let now = Instant::now();
+        let verdicts = [
+            Ok(()),
+            rejection(ErrorCode::CanisterRejectedMessage),
+            rejection(ErrorCode::CanisterOutOfCycles),
+        ];
+        for verdict in &verdicts {
+            cache.insert(&ingress(1, "a", 0), false, verdict, inputs(1, 1), now);
+            assert_eq!(cache.get(&ingress(1, "a", 0), inputs(1, 1), now), None);
+        }
+
+        // The effective canister of calls to the management canister depends
+        // on their arguments.
+        let verdict = rejection(ErrorCode::CanisterNotFound);
+        cache.insert(&ingress(1, "a", 0), true, &verdict, inputs(1, 1), now);
+        assert_eq!(cache.get(&ingress(1, "a", 0), inputs(1, 1), now), None);
+    }
+}
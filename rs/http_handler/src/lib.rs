@@ -5,43 +5,113 @@
 //! As much as possible the naming of structs in this module should match the
 //! naming used in the [Interface
 //! Specification](https://sdk.dfinity.org/docs/interface-spec/index.html)
+mod alternate_nodes;
 mod body;
+mod builder;
 mod call;
+mod canister_concurrency;
+mod catch_panic;
 mod catch_up_package;
+mod client_addr;
+mod client_hello;
+mod client_origin;
 mod common;
+mod connection_reuse;
 mod dashboard;
+mod delegation;
+mod drain;
+mod framing;
+#[cfg(feature = "fuzzing_code")]
+pub mod fuzzing;
+mod idempotency;
+mod ingress_filter_cache;
+mod limits;
+mod maintenance;
+mod metered_stream;
 mod metrics;
+mod outbound;
 mod pprof;
+mod problem_details;
 mod query;
 mod read_state;
+mod replay;
+mod response_budget;
+mod root_delegation;
+mod routes;
+mod security_headers;
 mod state_reader_executor;
 mod status;
+mod subnet_clock;
+mod subnet_public_key;
+mod tls_config;
+mod trace_context;
 mod types;
 mod validator_executor;
 
 use crate::{
-    call::CallService,
-    catch_up_package::CatchUpPackageService,
+    alternate_nodes::AlternateNodes,
+    body::{
+        check_content_length, parse_content_digest, receive_body, verify_content_digest,
+        CONTENT_DIGEST,
+    },
+    builder::HttpHandlerBuilder,
+    call::{add_cost_preview, wants_cost_preview, CallService},
+    catch_up_package::{
+        CatchUpPackageFormat, CatchUpPackageService, MAX_CATCH_UP_PACKAGE_CONCURRENT_REQUESTS,
+    },
+    client_addr::{has_forwarded_headers, ClientAddr, TrustedProxies},
+    client_hello::{is_tls_handshake, parse_client_hello, CLIENT_HELLO_PEEK_BYTES},
+    client_origin::ClientOrigins,
     common::{
-        get_cors_headers, get_root_public_key, make_plaintext_response, map_box_error_to_response,
+        get_cors_headers, get_latest_certified_state, get_root_public_key,
+        into_not_modified_if_matching, make_plaintext_response, map_box_error_to_response,
+    },
+    connection_reuse::ConnectionReuse,
+    dashboard::{asset_response, DashboardService},
+    delegation::DelegationService,
+    drain::{serve_until_drained, shutdown_channel, DrainSignal, DrainStats},
+    framing::{ambiguous_framing_response, check_framing},
+    idempotency::{
+        add_message_id_headers, call_identity, idempotency_key, idempotency_key_header,
+        replayed_response, IdempotencyKeys,
     },
-    dashboard::DashboardService,
+    limits::{unknown_body_request_types, HeaderLimits, LimitProfile},
+    maintenance::{switch_on_signals, MaintenanceMode},
+    metered_stream::MeteredStream,
     metrics::{
-        LABEL_REQUEST_TYPE, LABEL_STATUS, LABEL_TYPE, REQUESTS_LABEL_NAMES, REQUESTS_NUM_LABELS,
+        LABEL_API_VERSION, LABEL_REQUEST_TYPE, LABEL_STATUS, LABEL_TYPE, REQUESTS_LABEL_NAMES,
+        REQUESTS_NUM_LABELS,
     },
+    outbound::OutboundClient,
+    pprof::PprofAccess,
+    problem_details::{accepts_cbor, into_problem_details},
     query::QueryService,
-    read_state::ReadStateService,
+    read_state::{CanisterInfoReader, ReadStateService},
+    replay::ReplayDetector,
+    response_budget::ResponseBudget,
+    root_delegation::{
+        parse_delegation_response, spawn_registry_version_poller, wait_for_registry_version_after,
+        ParsedDelegation, DELEGATION_REGISTRY_UPDATE_TIMEOUT, DELEGATION_RESPONSE_TIMEOUT,
+        MAX_DELEGATION_RESPONSE_BYTES,
+    },
+    routes::{allow_header, Deprecation, Route, RouteMatch, RouteTable},
+    security_headers::SecurityHeaders,
     state_reader_executor::StateReaderExecutor,
     status::StatusService,
+    subnet_clock::SubnetClock,
+    subnet_public_key::{PublicKeyFormat, SubnetPublicKeyReader},
+    tls_config::TlsConfigWatcher,
+    trace_context::start_request_span,
     types::*,
     validator_executor::ValidatorExecutor,
 };
 use byte_unit::Byte;
+pub use drain::{ShutdownHandle, ShutdownReport};
 use http::method::Method;
-use hyper::{server::conn::Http, Body, Client, Request, Response, StatusCode};
+use hyper::{server::conn::Http, Body, Request, Response, StatusCode};
 use ic_async_utils::ObservableCountingSemaphore;
 use ic_certification::validate_subnet_delegation_certificate;
-use ic_config::http_handler::Config;
+use ic_config::http_handler::{Config, RouteDeprecation};
 use ic_crypto_tls_interfaces::TlsHandshake;
 use ic_crypto_tree_hash::{lookup_path, LabeledTree, Path};
 use ic_crypto_utils_threshold_sig::parse_threshold_sig_key_from_der;
@@ -61,30 +131,36 @@ use ic_replicated_state::{NodeTopology, ReplicatedState};
 use ic_types::{
     malicious_flags::MaliciousFlags,
     messages::{
-        Blob, Certificate, CertificateDelegation, HttpReadState, HttpReadStateContent,
-        HttpReadStateResponse, HttpRequestEnvelope, ReplicaHealthStatus,
+        Blob, CertificateDelegation, HttpReadState, HttpReadStateContent, HttpRequestEnvelope,
+        MessageId, ReplicaHealthStatus,
     },
-    time::current_time_and_expiry_time,
-    SubnetId,
+    time::{current_time_and_expiry_time, Clock, Stopwatch, SystemClock},
+    NodeId, PrincipalId, SubnetId,
 };
 use metrics::HttpHandlerMetrics;
+use opentelemetry::{
+    trace::{FutureExt, TraceContextExt},
+    KeyValue,
+};
 use rand::Rng;
+pub use status::BootTime;
 use std::{
-    convert::TryFrom,
+    convert::{Infallible, TryFrom},
     io::{Error, Write},
     net::SocketAddr,
     path::PathBuf,
+    str::FromStr,
     sync::{Arc, RwLock},
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tempfile::NamedTempFile;
 use tokio::{
     net::{TcpListener, TcpStream},
-    time::{sleep, timeout, Instant},
+    time::sleep,
 };
 use tower::{
-    load_shed::LoadShed, service_fn, util::BoxCloneService, util::BoxService, BoxError, Service,
-    ServiceBuilder, ServiceExt,
+    limit::concurrency::GlobalConcurrencyLimitLayer, load_shed::LoadShed, service_fn,
+    util::BoxCloneService, util::BoxService, BoxError, Service, ServiceBuilder, ServiceExt,
 };
 
 // Constants defining the limits of the HttpHandler.
@@ -108,19 +184,13 @@ use tower::{
 // In the HttpHandler we can have at most 'MAX_OUTSTANDING_CONNECTIONS'
 // live TCP connections. If we are at the limit, we won't
 // accept new TCP connections.
-const MAX_OUTSTANDING_CONNECTIONS: usize = 20000;
+//
+// This and the following limits are the defaults on application subnets, see
+// 'LimitProfile'.
+pub(crate) const MAX_OUTSTANDING_CONNECTIONS: usize = 20000;
 
 // Sets the SETTINGS_MAX_CONCURRENT_STREAMS option for HTTP2 connections.
-const HTTP_MAX_CONCURRENT_STREAMS: u32 = 256;
-
-// The maximum time we should wait for a peeking the first bytes on a TCP
-// connection. Effectively, if we can't read the first bytes within the
-// timeout the connection is broken.
-// If you modify this constant please also adjust:
-// - `ic_canister_client::agent::MAX_POLL_INTERVAL`,
-// - `canister_test::canister::MAX_BACKOFF_INTERVAL`.
-// See VER-1060 for details.
-const MAX_TCP_PEEK_TIMEOUT_SECS: u64 = 11;
+pub(crate) const HTTP_MAX_CONCURRENT_STREAMS: u32 = 256;
 
 // Request with body size bigger than 'MAX_REQUEST_SIZE_BYTES' will be rejected
 // and appropriate error code will be returned to the user.
@@ -131,12 +201,36 @@ pub(crate) const MAX_REQUEST_SIZE_BYTES: Byte = Byte::from_bytes(5 * 1024 * 1024
 // appropriate error code will be returned to the user.
 pub(crate) const MAX_REQUEST_RECEIVE_DURATION: Duration = Duration::from_secs(300); // 5 min
 
+// Requests with more than 'MAX_REQUEST_HEADER_COUNT' headers, or with header
+// names and values of more than 'MAX_REQUEST_HEADER_BYTES' in total, are
+// rejected with a `431 Request Header Fields Too Large` before being routed.
+// The byte limit also bounds the buffer in which HTTP/1.1 request heads are
+// parsed, so that flooding a connection with headers can't exhaust memory.
+pub(crate) const MAX_REQUEST_HEADER_COUNT: usize = 64;
+pub(crate) const MAX_REQUEST_HEADER_BYTES: usize = 64 * 1024; // 64KiB
+
+// The smallest HTTP/1.1 read buffer accepted by hyper.
+const MIN_HTTP1_BUFFER_BYTES: usize = 8192;
+
+// The maximum time we should wait for a peeking the first bytes on a TCP
+// connection. Effectively, if we can't read the first bytes within the
+// timeout the connection is broken.
+// If you modify this constant please also adjust:
+// - `ic_canister_client::agent::MAX_POLL_INTERVAL`,
+// - `canister_test::canister::MAX_BACKOFF_INTERVAL`.
+// See VER-1060 for details.
+const MAX_TCP_PEEK_TIMEOUT_SECS: u64 = 11;
+
 const HTTP_DASHBOARD_URL_PATH: &str = "/_/dashboard";
 const CONTENT_TYPE_CBOR: &str = "application/cbor";
 
 // Placeholder used when we can't determine the approriate prometheus label.
 const UNKNOWN_LABEL: &str = "unknown";
 
+// The API version of the call, query and read_state endpoints, as reported in
+// the metrics of their endpoint services.
+const API_VERSION_V2: &str = "v2";
+
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) struct HttpError {
     pub status: StatusCode,
@@ -153,17 +247,211 @@ impl std::error::Error for HttpError {}
 
 pub(crate) type EndpointService = BoxCloneService<Body, Response<Body>, BoxError>;
 
+/// An endpoint service given the received request body, before the layers of
+/// its request type are applied, see `builder::EndpointLayers`.
+pub(crate) type BaseEndpointService = BoxCloneService<Vec<u8>, Response<Body>, Infallible>;
+
+/// How the router serves the requests of a route.
+#[derive(Clone)]
+enum Handler {
+    /// Served by an endpoint service, given the request body.
+    Service(EndpointService),
+    /// Served by the call service, unless the call is a retry carrying a
+    /// known idempotency key.
+    Call(EndpointService),
+    /// Served in the format requested in the `Accept` header.
+    CatchUpPackage {
+        protobuf: EndpointService,
+        cbor: EndpointService,
+    },
+    /// Served by the delegation service, if the path names this subnet.
+    Delegation(EndpointService),
+    /// Served from the certified state, for the canister named in the path.
+    CanisterInfo(CanisterInfoReader),
+    /// Served from the certified state, for the subnet named in the path.
+    SubnetPublicKey(SubnetPublicKeyReader),
+    RedirectToDashboard,
+    /// Served from the static assets of the dashboard.
+    DashboardAsset,
+    PprofHome(PprofAccess),
+    PprofProfile(PprofAccess),
+    PprofFlamegraph(PprofAccess),
+}
+
 /// The struct that handles incoming HTTP requests for the IC replica.
 /// This is collection of thread-safe data members.
 #[derive(Clone)]
 struct HttpHandler {
+    subnet_id: SubnetId,
     registry_client: Arc<dyn RegistryClient>,
-    call_service: EndpointService,
-    query_service: EndpointService,
-    catchup_service: EndpointService,
-    dashboard_service: EndpointService,
-    status_service: EndpointService,
-    read_state_service: EndpointService,
+    routes: RouteTable<Handler>,
+    trusted_proxies: Arc<TrustedProxies>,
+    client_origins: Arc<ClientOrigins>,
+    tls_config: TlsConfigWatcher,
+    max_connection_write_bytes_per_second: Option<u64>,
+    max_connection_lifetime: Option<Duration>,
+    connection_drain_grace_period: Duration,
+    tls_handshake_timeout: Duration,
+    drain_stats: Arc<DrainStats>,
+    idempotency_keys: Arc<IdempotencyKeys>,
+    limits: Arc<LimitProfile>,
+    state_reader_executor: StateReaderExecutor,
+    header_limits: HeaderLimits,
+    reject_ambiguous_requests: bool,
+    alternate_nodes: Arc<AlternateNodes>,
+    maintenance_mode: Arc<MaintenanceMode>,
+    security_headers: SecurityHeaders,
+    response_budget: ResponseBudget,
+}
+
+/// The endpoint services serving the routes of the HTTP handler.
+struct EndpointServices {
+    call: EndpointService,
+    query: EndpointService,
+    read_state: EndpointService,
+    catchup_protobuf: EndpointService,
+    catchup_cbor: EndpointService,
+    status: EndpointService,
+    dashboard: EndpointService,
+    delegation: EndpointService,
+}
+
+// Returns the routes served by the HTTP handler.
+fn make_routes(
+    services: EndpointServices,
+    canister_info: CanisterInfoReader,
+    subnet_public_key: SubnetPublicKeyReader,
+    pprof_access: PprofAccess,
+) -> RouteTable<Handler> {
+    RouteTable::default()
+        .route(
+            Method::POST,
+            "/api/v2/canister/:effective_canister_id/call",
+            ApiReqType::Call,
+            Handler::Call(services.call),
+        )
+        .route(
+            Method::POST,
+            "/api/v2/canister/:effective_canister_id/query",
+            ApiReqType::Query,
+            Handler::Service(services.query),
+        )
+        .route(
+            Method::POST,
+            "/api/v2/canister/:effective_canister_id/read_state",
+            ApiReqType::ReadState,
+            Handler::Service(services.read_state),
+        )
+        .route(
+            Method::GET,
+            "/api/v2/canister/:effective_canister_id/info",
+            ApiReqType::CanisterInfo,
+            Handler::CanisterInfo(canister_info),
+        )
+        .route(
+            Method::POST,
+            "/_/catch_up_package",
+            ApiReqType::CatchUpPackage,
+            Handler::CatchUpPackage {
+                protobuf: services.catchup_protobuf,
+                cbor: services.catchup_cbor,
+            },
+        )
+        .route(
+            Method::GET,
+            "/api/v2/status",
+            ApiReqType::Status,
+            Handler::Service(services.status),
+        )
+        .route(
+            Method::GET,
+            "/",
+            ApiReqType::RedirectToDashboard,
+            Handler::RedirectToDashboard,
+        )
+        .route(
+            Method::GET,
+            "/_/",
+            ApiReqType::RedirectToDashboard,
+            Handler::RedirectToDashboard,
+        )
+        .route(
+            Method::GET,
+            HTTP_DASHBOARD_URL_PATH,
+            ApiReqType::Dashboard,
+            Handler::Service(services.dashboard),
+        )
+        .route(
+            Method::GET,
+            "/_/assets/:asset",
+            ApiReqType::DashboardAsset,
+            Handler::DashboardAsset,
+        )
+        .route(
+            Method::GET,
+            "/_/pprof",
+            ApiReqType::PprofHome,
+            Handler::PprofHome(pprof_access.clone()),
+        )
+        .route(
+            Method::GET,
+            "/_/pprof/profile",
+            ApiReqType::PprofProfile,
+            Handler::PprofProfile(pprof_access.clone()),
+        )
+        .route(
+            Method::GET,
+            "/_/pprof/flamegraph",
+            ApiReqType::PprofFlamegraph,
+            Handler::PprofFlamegraph(pprof_access),
+        )
+        .route(
+            Method::GET,
+            "/api/v2/subnet/:subnet_id/delegation",
+            ApiReqType::Delegation,
+            Handler::Delegation(services.delegation),
+        )
+        .route(
+            Method::GET,
+            "/api/v2/subnet/:subnet_id/public_key",
+            ApiReqType::SubnetPublicKey,
+            Handler::SubnetPublicKey(subnet_public_key),
+        )
+}
+
+// Marks the routes in `deprecated_routes` as deprecated, skipping those that
+// are not routed or not valid.
+fn deprecate_routes(
+    log: &ReplicaLogger,
+    routes: &mut RouteTable<Handler>,
+    deprecated_routes: &[RouteDeprecation],
+) {
+    for config in deprecated_routes {
+        match Deprecation::from_config(config) {
+            Ok(deprecation) => {
+                if !routes.deprecate(&config.route, deprecation) {
+                    warn!(log, "Cannot deprecate {}, it is not routed", config.route);
+                }
+            }
+            Err(err) => warn!(log, "Cannot deprecate {}: {}", config.route, err),
+        }
+    }
+}
+
+// Returns the configuration of the HTTP server, applying `limits`.
+fn make_http(limits: &LimitProfile) -> Http {
+    let mut http = Http::new();
+    http.http2_max_concurrent_streams(limits.http_max_concurrent_streams);
+    http.max_buf_size(limits.max_request_header_bytes.max(MIN_HTTP1_BUFFER_BYTES));
+    // Bound the headers HTTP/2 buffers as well. The list size counts 32 bytes
+    // of overhead per header on top of its name and value, which the header
+    // limits don't, so requests within the header limits are never refused by
+    // the connection.
+    let max_header_list_size = limits
+        .max_request_header_bytes
+        .saturating_add(limits.max_request_header_count.saturating_mul(32));
+    http.http2_max_header_list_size(u32::try_from(max_header_list_size).unwrap_or(u32::MAX));
+    http
 }
 
 // Crates a detached tokio blocking task that initializes the server (reading
@@ -174,6 +462,7 @@ fn start_server_initialization(
     nns_subnet_id: SubnetId,
     registry_client: Arc<dyn RegistryClient>,
     state_reader_executor: StateReaderExecutor,
+    outbound_client: OutboundClient,
     delegation_from_nns: Arc<RwLock<Option<CertificateDelegation>>>,
     health_status: Arc<RwLock<ReplicaHealthStatus>>,
     rt_handle: tokio::runtime::Handle,
@@ -200,6 +489,7 @@ fn start_server_initialization(
             nns_subnet_id,
             registry_client,
             state_reader_executor,
+            outbound_client,
         )
         .await
         {
@@ -259,9 +549,32 @@ fn create_port_file(path: PathBuf, port: u16) {
     });
 }
 
-/// Creates HTTP server, binds to HTTP port and handles HTTP requests forever.
-/// This ***async*** function ***never*** returns unless binding to the HTTP
-/// port fails.
+// Reads the token required to access the profiling endpoints, if configured.
+// If it cannot be read, the endpoints are served to local clients only.
+fn read_pprof_token(log: &ReplicaLogger, config: &Config) -> Option<String> {
+    let path = config.pprof_token_file.as_ref()?;
+    match std::fs::read_to_string(path) {
+        Ok(token) if !token.trim().is_empty() => Some(token.trim().to_string()),
+        Ok(_) => {
+            warn!(log, "The pprof token file {} is empty", path.display());
+            None
+        }
+        Err(err) => {
+            warn!(
+                log,
+                "Could not read the pprof token file {}: {}",
+                path.display(),
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Creates HTTP server, binds to HTTP port and handles HTTP requests until
+/// shut down through the returned handle. The caller listens for termination
+/// signals, and shuts the server down if `shutdown_on_sigterm` is set.
+/// The uptime reported in the status is measured from `boot_time`.
 /// The function spawns a tokio task per connection.
 #[allow(clippy::too_many_arguments)]
 pub fn start_server(
@@ -277,14 +590,30 @@ pub fn start_server(
     registry_client: Arc<dyn RegistryClient>,
     tls_handshake: Arc<dyn TlsHandshake + Send + Sync>,
     ingress_verifier: Arc<dyn IngressSigVerifier + Send + Sync>,
+    node_id: NodeId,
     subnet_id: SubnetId,
     nns_subnet_id: SubnetId,
     log: ReplicaLogger,
     consensus_pool_cache: Arc<dyn ConsensusPoolCache>,
     subnet_type: SubnetType,
     malicious_flags: MaliciousFlags,
-) {
+    boot_time: BootTime,
+) -> ShutdownHandle {
     let metrics = HttpHandlerMetrics::new(&metrics_registry);
+    metrics
+        .boot_time_seconds
+        .set((boot_time.time().as_nanos_since_unix_epoch() / 1_000_000_000) as i64);
+    let limits = LimitProfile::for_subnet_type(subnet_type).with_overrides(&config);
+    info!(
+        log,
+        "Using limits {:?} for subnet type {:?}", limits, subnet_type
+    );
+    for request_type in unknown_body_request_types(&config) {
+        warn!(
+            log,
+            "Ignoring the body size limit of {}, requests of that type have no body", request_type
+        );
+    }
 
     let listen_addr = config.listen_addr;
     let port_file_path = config.port_file_path.clone();
@@ -295,11 +624,31 @@ pub fn start_server(
     let mut addr = "[::]:8080".parse::<SocketAddr>().unwrap();
     addr.set_port(listen_addr.port());
     info!(log, "Starting HTTP server...");
+    let (shutdown_handle, mut drain_signal) = shutdown_channel(Duration::from_secs(
+        config.connection_drain_grace_period_seconds,
+    ));
+    let maintenance_mode = Arc::new(MaintenanceMode::new(Duration::from_secs(
+        config.maintenance_retry_after_seconds,
+    )));
+    if config.maintenance_mode_on_signals {
+        let maintenance_mode = Arc::clone(&maintenance_mode);
+        let metrics = metrics.clone();
+        let log = log.clone();
+        rt_handle.spawn(async move { switch_on_signals(&maintenance_mode, metrics, log).await });
+    }
     rt_handle.clone().spawn(async move {
         let delegation_from_nns = Arc::new(RwLock::new(None));
         let health_status = Arc::new(RwLock::new(ReplicaHealthStatus::Starting));
         let state_reader_executor = StateReaderExecutor::new(state_reader);
-        let validator_executor = ValidatorExecutor::new(ingress_verifier, log.clone());
+        let subnet_clock = SubnetClock::default();
+        let validator_executor =
+            ValidatorExecutor::new(ingress_verifier, subnet_clock.clone(), log.clone());
+        let builder =
+            HttpHandlerBuilder::new(log.clone(), metrics.clone(), config.clone(), limits.clone());
+        let response_budget = ResponseBudget::new(
+            config.max_buffered_response_bytes,
+            metrics.buffered_response_bytes.clone(),
+        );
 
         let call_service = CallService::new_service(
             log.clone(),
@@ -309,8 +658,11 @@ pub fn start_server(
             validator_executor.clone(),
             ingress_sender,
             ingress_filter,
+            state_reader_executor.clone(),
+            config.reject_calls_to_stopped_canisters,
             malicious_flags.clone(),
         );
+        let replay_detector = Arc::new(ReplayDetector::new(config.reject_replayed_requests));
         let query_service = QueryService::new_service(
             log.clone(),
             metrics.clone(),
@@ -319,6 +671,9 @@ pub fn start_server(
             validator_executor.clone(),
             Arc::clone(&registry_client),
             query_execution_service,
+            limits.max_concurrent_queries_per_canister,
+            config.max_queued_queries_per_canister,
+            Arc::clone(&replay_detector),
             malicious_flags.clone(),
         );
         let read_state_service = ReadStateService::new_service(
@@ -329,45 +684,149 @@ pub fn start_server(
             state_reader_executor.clone(),
             validator_executor,
             Arc::clone(&registry_client),
+            limits.read_state_path_limits(),
+            replay_detector,
+            response_budget.clone(),
             malicious_flags,
         );
         let status_service = StatusService::new_service(
             log.clone(),
             config.clone(),
+            subnet_id,
             nns_subnet_id,
             state_reader_executor.clone(),
             Arc::clone(&health_status),
+            metrics.ingress_queue_depth.clone(),
+            boot_time,
         );
         let dashboard_service = DashboardService::new_service(
             config.clone(),
             subnet_type,
             state_reader_executor.clone(),
+            metrics.clone(),
+            response_budget.clone(),
+        );
+        // Both formats are served within the same concurrency limit.
+        let catchup_concurrency_limit =
+            GlobalConcurrencyLimitLayer::new(MAX_CATCH_UP_PACKAGE_CONCURRENT_REQUESTS);
+        let catchup_service = CatchUpPackageService::new_service(
+            metrics.clone(),
+            Arc::clone(&consensus_pool_cache),
+            CatchUpPackageFormat::Protobuf,
+            limits.max_request_size_bytes_for(ApiReqType::CatchUpPackage),
+            response_budget.clone(),
+            catchup_concurrency_limit.clone(),
+        );
+        let catchup_cbor_service = CatchUpPackageService::new_service(
+            metrics.clone(),
+            consensus_pool_cache,
+            CatchUpPackageFormat::Cbor,
+            limits.max_request_size_bytes_for(ApiReqType::CatchUpPackage),
+            response_budget.clone(),
+            catchup_concurrency_limit,
+        );
+        let delegation_service = DelegationService::new_service(
+            Arc::clone(&health_status),
+            Arc::clone(&delegation_from_nns),
+        );
+        let canister_info = CanisterInfoReader::new(
+            Arc::clone(&health_status),
+            Arc::clone(&delegation_from_nns),
+            state_reader_executor.clone(),
+            builder.concurrency_limit(ApiReqType::CanisterInfo),
+        );
+        let trusted_proxies = Arc::new(TrustedProxies::new(&log, &config.trusted_proxies));
+        let pprof_access = PprofAccess::new(read_pprof_token(&log, &config));
+        subnet_clock.spawn_sampling_task(
+            metrics.clone(),
+            state_reader_executor.clone(),
+            Arc::clone(&health_status),
+            &rt_handle,
+        );
+        let tls_config = TlsConfigWatcher::default();
+        tls_config.spawn_refresh_task(
+            log.clone(),
+            metrics.clone(),
+            node_id,
+            Arc::clone(&registry_client),
+            &rt_handle,
         );
-        let catchup_service =
-            CatchUpPackageService::new_service(metrics.clone(), consensus_pool_cache);
 
         info!(log, "Binding HTTP server to address {}", addr);
         let tcp_listener = TcpListener::bind(addr).await.unwrap();
 
+        let outbound_client = if config.fetch_delegation_over_tls {
+            OutboundClient::with_tls(Arc::clone(&tls_handshake))
+        } else {
+            OutboundClient::default()
+        };
         start_server_initialization(
             log.clone(),
             subnet_id,
             nns_subnet_id,
             registry_client.clone(),
-            state_reader_executor,
+            state_reader_executor.clone(),
+            outbound_client,
             Arc::clone(&delegation_from_nns),
             Arc::clone(&health_status),
             rt_handle.clone(),
         );
 
+        let routes = builder.routes(
+            EndpointServices {
+                call: builder.endpoint(ApiReqType::Call, call_service),
+                query: builder.endpoint(ApiReqType::Query, query_service),
+                read_state: builder.endpoint(ApiReqType::ReadState, read_state_service),
+                catchup_protobuf: builder.wrap(ApiReqType::CatchUpPackage, catchup_service),
+                catchup_cbor: builder.wrap(ApiReqType::CatchUpPackage, catchup_cbor_service),
+                status: builder.wrap(ApiReqType::Status, status_service),
+                dashboard: builder.wrap(ApiReqType::Dashboard, dashboard_service),
+                delegation: builder.wrap(ApiReqType::Delegation, delegation_service),
+            },
+            canister_info,
+            SubnetPublicKeyReader::new(
+                Arc::clone(&health_status),
+                Arc::clone(&delegation_from_nns),
+                state_reader_executor.clone(),
+                builder.concurrency_limit(ApiReqType::SubnetPublicKey),
+            ),
+            pprof_access,
+        );
+        let alternate_nodes = Arc::new(AlternateNodes::new(
+            Arc::clone(&registry_client),
+            subnet_id,
+            node_id,
+            config.max_alternate_nodes,
+        ));
         let http_handler = HttpHandler {
+            subnet_id,
             registry_client,
-            call_service,
-            query_service,
-            status_service,
-            catchup_service,
-            dashboard_service,
-            read_state_service,
+            routes,
+            trusted_proxies,
+            client_origins: Arc::new(ClientOrigins::new(
+                &log,
+                config.client_asn_database_file.as_deref(),
+                config.client_country_database_file.as_deref(),
+            )),
+            tls_config,
+            max_connection_write_bytes_per_second: config.max_connection_write_bytes_per_second,
+            max_connection_lifetime: config
+                .max_connection_lifetime_seconds
+                .map(Duration::from_secs),
+            connection_drain_grace_period: Duration::from_secs(
+                config.connection_drain_grace_period_seconds,
+            ),
+            tls_handshake_timeout: Duration::from_secs(config.tls_handshake_timeout_seconds),
+            drain_stats: drain_signal.stats(),
+            idempotency_keys: Arc::new(IdempotencyKeys::default()),
+            limits: Arc::new(limits.clone()),
+            state_reader_executor,
+            header_limits: limits.header_limits(),
+            reject_ambiguous_requests: config.reject_ambiguous_requests,
+            alternate_nodes,
+            maintenance_mode,
+            security_headers: builder.security_headers(),
+            response_budget,
         };
 
         // If addr == 0, then a random port will be assigned. In this case it
@@ -378,61 +837,87 @@ pub fn start_server(
         }
 
         let outstanding_connections = ObservableCountingSemaphore::new(
-            MAX_OUTSTANDING_CONNECTIONS,
+            limits.max_outstanding_connections,
             metrics.connections.clone(),
         );
-        let mut http = Http::new();
-        http.http2_max_concurrent_streams(HTTP_MAX_CONCURRENT_STREAMS);
+        let http = make_http(&limits);
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock::new());
         loop {
             let log = log.clone();
             let http = http.clone();
             let http_handler = http_handler.clone();
             let tls_handshake = Arc::clone(&tls_handshake);
             let metrics = metrics.clone();
+            let connection_drain_signal = drain_signal.clone();
             let request_permit = outstanding_connections.acquire().await;
-            match tcp_listener.accept().await {
-                Ok((tcp_stream, _)) => {
+            let accepted = tokio::select! {
+                accepted = tcp_listener.accept() => accepted,
+                _ = drain_signal.draining() => {
+                    info!(log, "Stopped accepting connections, draining open ones");
+                    break;
+                }
+            };
+            match accepted {
+                Ok((tcp_stream, peer_addr)) => {
                     metrics.connections_total.inc();
                     // Start recording connection setup duration.
-                    let connection_start_time = Instant::now();
+                    let connection_stopwatch = Stopwatch::start(Arc::clone(&clock));
                     rt_handle.spawn(async move {
                         // Do a move of the permit so it gets dropped at the end of the scope.
                         let _request_permit_deleter = request_permit;
-                        let mut b = [0_u8; 1];
-                        let app_layer = match timeout(
+                        let client_origins = &http_handler.client_origins;
+                        if client_origins.is_enabled() {
+                            let peer = peer_addr.ip();
+                            // The peer of connections from trusted proxies is
+                            // not the client.
+                            let origin = if http_handler.trusted_proxies.contains(&peer) {
+                                client_origins.proxy()
+                            } else {
+                                client_origins.lookup(peer)
+                            };
+                            metrics.observe_client_origin(&origin);
+                        }
+                        // The connection holds a permit while waiting for its
+                        // first bytes, so silent connections are closed once
+                        // the peek times out, to free the permit.
+                        let mut buf = [0_u8; CLIENT_HELLO_PEEK_BYTES];
+                        let peek = tokio::time::timeout(
                             Duration::from_secs(MAX_TCP_PEEK_TIMEOUT_SECS),
-                            tcp_stream.peek(&mut b),
-                        )
-                        .await
-                        {
-                            // The peek operation didn't timeout, and the peek oparation didn't return
-                            // an error.
-                            Ok(Ok(_)) => {
-                                if b[0] == 22 {
-                                    AppLayer::Https
-                                } else {
-                                    AppLayer::Http
-                                }
-                            }
-                            Ok(Err(err)) => {
-                                error!(log, "Can't peek into TCP stream, error = {}", err);
-                                metrics.observe_connection_error(
-                                    ConnectionError::Peek,
-                                    connection_start_time,
-                                );
-                                AppLayer::Http
-                            }
-                            Err(err) => {
+                            tcp_stream.peek(&mut buf),
+                        );
+                        let app_layer = match peek.await {
+                            Err(_) => {
                                 warn!(
                                     log,
-                                    "TCP peeking timeout after {}s, error = {}",
+                                    "TCP peeking timeout after {}s, peer_addr = {}",
                                     MAX_TCP_PEEK_TIMEOUT_SECS,
-                                    err
+                                    peer_addr
                                 );
-
                                 metrics.observe_connection_error(
                                     ConnectionError::PeekTimeout,
-                                    connection_start_time,
+                                    &connection_stopwatch,
+                                );
+                                return;
+                            }
+                            Ok(Ok(n)) if is_tls_handshake(&buf[..n]) => {
+                                if let Some(client_hello) = parse_client_hello(&buf[..n]) {
+                                    metrics.observe_client_hello(&client_hello);
+                                    debug!(
+                                        log,
+                                        "Received ClientHello from {}, server_name = {:?}, alpn = {}",
+                                        peer_addr,
+                                        client_hello.server_name,
+                                        client_hello.preferred_protocol()
+                                    );
+                                }
+                                AppLayer::Https
+                            }
+                            Ok(Ok(_)) => AppLayer::Http,
+                            Ok(Err(err)) => {
+                                error!(log, "Can't peek into TCP stream, error = {}", err);
+                                metrics.observe_connection_error(
+                                    ConnectionError::Peek,
+                                    &connection_stopwatch,
                                 );
                                 AppLayer::Http
                             }
@@ -442,10 +927,12 @@ pub fn start_server(
                             app_layer,
                             http,
                             tcp_stream,
+                            peer_addr,
                             tls_handshake,
                             http_handler,
                             metrics,
-                            connection_start_time,
+                            connection_stopwatch,
+                            connection_drain_signal,
                         )
                         .await;
                     });
@@ -453,40 +940,106 @@ pub fn start_server(
                 // Don't exit the loop on a connection error. We will want to
                 // continue serving.
                 Err(err) => {
-                    metrics.observe_connection_error(ConnectionError::Accept, Instant::now());
+                    metrics.observe_connection_error(
+                        ConnectionError::Accept,
+                        &Stopwatch::start(Arc::clone(&clock)),
+                    );
                     error!(log, "Can't accept TCP connection, error = {}", err);
                 }
             }
         }
     });
+    shutdown_handle
 }
 
 fn create_main_service(
+    log: ReplicaLogger,
     metrics: HttpHandlerMetrics,
     http_handler: HttpHandler,
     app_layer: AppLayer,
+    peer_addr: SocketAddr,
+    connection_reuse: ConnectionReuse,
 ) -> BoxService<Request<Body>, Response<Body>, HttpError> {
     let metrics_for_map_request = metrics.clone();
+    let metrics_for_map_result = metrics.clone();
+    let trusted_proxies = Arc::clone(&http_handler.trusted_proxies);
+    let from_trusted_proxy = trusted_proxies.contains(&peer_addr.ip());
     let route_service = service_fn(move |req: RequestWithTimer| {
         let metrics = metrics.clone();
         let http_handler = http_handler.clone();
-        async move { Ok::<_, HttpError>(make_router(metrics, http_handler, app_layer, req).await) }
+        // The request span is the current context while the request is
+        // routed and served, including in the endpoint services.
+        let trace_context = start_request_span(req.0.headers(), req.0.method(), req.0.uri().path());
+        if let Some(ClientAddr(client_ip)) = req.0.extensions().get::<ClientAddr>() {
+            trace_context
+                .span()
+                .set_attribute(KeyValue::new("http.client_ip", client_ip.to_string()));
+        }
+        let accepts_cbor = accepts_cbor(req.0.headers());
+        let deprecated_route = http_handler
+            .routes
+            .deprecated_route(req.0.method(), req.0.uri().path())
+            .cloned();
+        let alternate_nodes = Arc::clone(&http_handler.alternate_nodes);
+        async move {
+            let (response, timer) = make_router(
+                metrics.clone(),
+                http_handler,
+                app_layer,
+                from_trusted_proxy,
+                req,
+            )
+            .with_context(trace_context.clone())
+            .await;
+            let mut response = if accepts_cbor {
+                into_problem_details(response).await
+            } else {
+                response
+            };
+            if let Some(route) = deprecated_route {
+                metrics
+                    .deprecated_requests_total
+                    .with_label_values(&[route.api_req_type().into(), route.api_version()])
+                    .inc();
+                if let Some(deprecation) = route.deprecation() {
+                    deprecation.add_headers(response.headers_mut());
+                }
+            }
+            alternate_nodes.add_header(&mut response);
+            trace_context.span().set_attribute(KeyValue::new(
+                "http.status_code",
+                i64::from(response.status().as_u16()),
+            ));
+            Ok::<_, HttpError>((response, timer))
+        }
     });
     BoxService::new(
         ServiceBuilder::new()
             // Attach a timer as soon as we see a request.
-            .map_request(move |request| {
+            .map_request(move |mut request: Request<Body>| {
+                // Determine the client address, taking into account the
+                // forwarding headers set by trusted reverse proxies.
+                let client_addr = trusted_proxies.client_addr(peer_addr, request.headers());
+                if !from_trusted_proxy && has_forwarded_headers(request.headers()) {
+                    debug!(
+                        log,
+                        "Ignoring forwarding headers from untrusted peer {}", peer_addr
+                    );
+                }
+                request.extensions_mut().insert(client_addr);
+                connection_reuse.observe_request(request.version());
                 // Start recording request duration.
                 let request_timer = HistogramVecTimer::start_timer(
                     metrics_for_map_request.requests.clone(),
                     &REQUESTS_LABEL_NAMES,
-                    [UNKNOWN_LABEL, UNKNOWN_LABEL, UNKNOWN_LABEL],
+                    [UNKNOWN_LABEL, UNKNOWN_LABEL, UNKNOWN_LABEL, UNKNOWN_LABEL],
                 );
                 (request, request_timer)
             })
             .service(route_service)
             .map_result(move |result| match result {
                 Ok((response, request_timer)) => {
+                    metrics_for_map_result.observe_request_slo(&request_timer);
                     let status = response.status();
                     // This is a workaround for `StatusCode::as_str()` not returning a `&'static
                     // str`. It ensures `request_timer` is dropped before `status`.
@@ -507,55 +1060,121 @@ async fn serve_connection(
     app_layer: AppLayer,
     http: Http,
     tcp_stream: TcpStream,
+    peer_addr: SocketAddr,
     tls_handshake: Arc<dyn TlsHandshake + Send + Sync>,
     http_handler: HttpHandler,
     metrics: HttpHandlerMetrics,
-    connection_start_time: Instant,
+    connection_stopwatch: Stopwatch,
+    drain_signal: DrainSignal,
 ) {
-    let service = create_main_service(metrics.clone(), http_handler.clone(), app_layer);
+    let max_lifetime = http_handler.max_connection_lifetime;
+    let grace_period = http_handler.connection_drain_grace_period;
+    let connection_reuse = ConnectionReuse::default();
+    let service = create_main_service(
+        log.clone(),
+        metrics.clone(),
+        http_handler.clone(),
+        app_layer,
+        peer_addr,
+        connection_reuse.clone(),
+    );
     let connection_result = match app_layer {
         AppLayer::Https => {
-            let peer_addr = tcp_stream.peer_addr();
-            let tls_stream = match tls_handshake
-                .perform_tls_server_handshake_without_client_auth(
-                    tcp_stream,
-                    http_handler.registry_client.get_latest_version(),
-                )
-                .await
-            {
-                Err(err) => {
-                    metrics.observe_connection_error(
-                        ConnectionError::TlsHandshake,
-                        connection_start_time,
-                    );
-                    warn!(
-                        log,
-                        "TLS handshake failed, error = {}, peer_addr = {:?}", err, peer_addr,
-                    );
-                    return;
-                }
-                Ok(tls_stream) => tls_stream,
-            };
-            metrics.observe_successful_connection_setup(app_layer, connection_start_time);
-            http.serve_connection(tls_stream, service).await
+            let handshake = tls_handshake.perform_tls_server_handshake_without_client_auth(
+                tcp_stream,
+                http_handler
+                    .tls_config
+                    .registry_version(http_handler.registry_client.get_latest_version()),
+            );
+            let tls_stream =
+                match tokio::time::timeout(http_handler.tls_handshake_timeout, handshake).await {
+                    Err(_) => {
+                        metrics.observe_connection_error(
+                            ConnectionError::TlsHandshakeTimeout,
+                            &connection_stopwatch,
+                        );
+                        warn!(
+                            log,
+                            "TLS handshake timed out after {:?}, peer_addr = {}",
+                            http_handler.tls_handshake_timeout,
+                            peer_addr,
+                        );
+                        return;
+                    }
+                    Ok(Err(err)) => {
+                        metrics.observe_connection_error(
+                            ConnectionError::TlsHandshake,
+                            &connection_stopwatch,
+                        );
+                        warn!(
+                            log,
+                            "TLS handshake failed, error = {}, peer_addr = {}", err, peer_addr,
+                        );
+                        return;
+                    }
+                    Ok(Ok(tls_stream)) => tls_stream,
+                };
+            metrics.observe_successful_connection_setup(app_layer, &connection_stopwatch);
+            let tls_stream = MeteredStream::new(
+                tls_stream,
+                http_handler.max_connection_write_bytes_per_second,
+                app_layer,
+                metrics.clone(),
+            );
+            serve_until_drained(
+                http.serve_connection(tls_stream, service),
+                drain_signal,
+                max_lifetime,
+                grace_period,
+                &metrics,
+            )
+            .await
         }
         AppLayer::Http => {
-            metrics.observe_successful_connection_setup(app_layer, connection_start_time);
-            http.serve_connection(tcp_stream, service).await
+            metrics.observe_successful_connection_setup(app_layer, &connection_stopwatch);
+            let tcp_stream = MeteredStream::new(
+                tcp_stream,
+                http_handler.max_connection_write_bytes_per_second,
+                app_layer,
+                metrics.clone(),
+            );
+            serve_until_drained(
+                http.serve_connection(tcp_stream, service),
+                drain_signal,
+                max_lifetime,
+                grace_period,
+                &metrics,
+            )
+            .await
         }
     };
+    connection_reuse.record(app_layer, &metrics);
 
     match connection_result {
-        Err(err) => {
-            metrics.observe_abrupt_conn_termination(app_layer, connection_start_time);
+        None => {
+            metrics.observe_abrupt_conn_termination(app_layer, &connection_stopwatch);
+            info!(
+                log,
+                "The connection was dropped after {:?}, requests in flight did not complete within the drain grace period of {:?}",
+                connection_stopwatch.elapsed(),
+                grace_period
+            );
+        }
+        Some(Err(err)) => {
+            // hyper answers HTTP/1.1 request heads exceeding its buffer with a
+            // `431 Request Header Fields Too Large` and closes the connection.
+            if err.is_parse_too_large() {
+                metrics.observe_header_rejection("http1_head");
+            }
+            metrics.observe_abrupt_conn_termination(app_layer, &connection_stopwatch);
             info!(
                 log,
                 "The connection was closed abruptly after {:?}, error = {}",
-                connection_start_time.elapsed(),
+                connection_stopwatch.elapsed(),
                 err
             );
         }
-        Ok(()) => metrics.observe_graceful_conn_termination(app_layer, connection_start_time),
+        Some(Ok(())) => metrics.observe_graceful_conn_termination(app_layer, &connection_stopwatch),
     }
 }
 
@@ -576,142 +1195,292 @@ fn set_timer_labels(
     timer.set_label(LABEL_REQUEST_TYPE, api_req_type.into());
 }
 
+fn set_route_timer_labels(
+    timer: &mut HistogramVecTimer<'static, REQUESTS_NUM_LABELS>,
+    route: &Route,
+) {
+    set_timer_labels(timer, route.api_req_type());
+    timer.set_label(LABEL_API_VERSION, route.api_version());
+}
+
 async fn make_router(
     metrics: HttpHandlerMetrics,
     http_handler: HttpHandler,
     app_layer: AppLayer,
-    (req, mut timer): RequestWithTimer,
+    from_trusted_proxy: bool,
+    (mut req, mut timer): RequestWithTimer,
 ) -> ResponseWithTimer {
-    let call_service = http_handler.call_service.clone();
-    let query_service = http_handler.query_service.clone();
-    let status_service = http_handler.status_service.clone();
-    let catch_up_package_service = http_handler.catchup_service.clone();
-    let dashboard_service = http_handler.dashboard_service.clone();
-    let read_state_service = http_handler.read_state_service.clone();
-
     metrics
         .protocol_version_total
         .with_label_values(&[app_layer.into(), &format!("{:?}", req.version())])
         .inc();
-    let svc = match req.method().clone() {
-        Method::POST => {
-            // Check the content-type header
-            if !req
-                .headers()
-                .get_all(http::header::CONTENT_TYPE)
-                .iter()
-                .any(|value| {
-                    if let Ok(v) = value.to_str() {
-                        return v.to_lowercase() == CONTENT_TYPE_CBOR;
-                    }
-                    false
-                })
-            {
+    if let Err(limit) = http_handler.header_limits.check(req.headers()) {
+        set_timer_labels(&mut timer, ApiReqType::HeadersTooLarge);
+        metrics.observe_header_rejection(limit.into());
+        return (
+            make_plaintext_response(
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                format!(
+                    "Request headers exceed the limit of {} headers and {} bytes.",
+                    http_handler.header_limits.max_count, http_handler.header_limits.max_bytes
+                ),
+            ),
+            timer,
+        );
+    }
+    if http_handler.reject_ambiguous_requests {
+        if let Err(reason) = check_framing(&mut req, from_trusted_proxy) {
+            set_timer_labels(&mut timer, ApiReqType::InvalidArgument);
+            metrics.observe_ambiguous_request(&reason);
+            return (ambiguous_framing_response(&reason, req.version()), timer);
+        }
+    }
+    // The idempotency key of a call, under which its message id is remembered
+    // once submitted.
+    let mut call_idempotency_key = None;
+    // Whether the size-based cost of a call is returned in the response.
+    let mut call_cost_preview = false;
+    // Responses to GET requests carrying an entity tag are turned into
+    // `304 Not Modified` if the client already has them.
+    let if_none_match: Vec<_> = if req.method() == Method::GET {
+        req.headers()
+            .get_all(http::header::IF_NONE_MATCH)
+            .iter()
+            .cloned()
+            .collect()
+    } else {
+        vec![]
+    };
+    if req.method() == Method::OPTIONS {
+        set_timer_labels(&mut timer, ApiReqType::Options);
+        return (
+            options_response(&http_handler.routes, req.uri().path()),
+            timer,
+        );
+    }
+    // Check the content-type header
+    if req.method() == Method::POST
+        && !req
+            .headers()
+            .get_all(http::header::CONTENT_TYPE)
+            .iter()
+            .any(|value| {
+                if let Ok(v) = value.to_str() {
+                    return v.to_lowercase() == CONTENT_TYPE_CBOR;
+                }
+                false
+            })
+    {
+        set_timer_labels(&mut timer, ApiReqType::InvalidArgument);
+        return (
+            make_plaintext_response(
+                StatusCode::BAD_REQUEST,
+                format!("Unexpected content-type, expected {}.", CONTENT_TYPE_CBOR),
+            ),
+            timer,
+        );
+    }
+    // The SHA-256 digest of the body, verified while the body is received.
+    let mut content_digest = match req.headers().get(CONTENT_DIGEST) {
+        Some(value) if req.method() == Method::POST => match parse_content_digest(value) {
+            Ok(digest) => digest,
+            Err(err) => {
                 set_timer_labels(&mut timer, ApiReqType::InvalidArgument);
-                return (
-                    make_plaintext_response(
-                        StatusCode::BAD_REQUEST,
-                        format!("Unexpected content-type, expected {}.", CONTENT_TYPE_CBOR),
-                    ),
-                    timer,
-                );
+                return (make_plaintext_response(StatusCode::BAD_REQUEST, err), timer);
             }
+        },
+        _ => None,
+    };
 
-            // Check the path
-            let path = req.uri().path();
-            match *path.split('/').collect::<Vec<&str>>().as_slice() {
-                ["", "api", "v2", "canister", _, "call"] => {
-                    set_timer_labels(&mut timer, ApiReqType::Call);
-                    call_service
-                }
-                ["", "api", "v2", "canister", _, "query"] => {
-                    set_timer_labels(&mut timer, ApiReqType::Query);
-                    query_service
-                }
-                ["", "api", "v2", "canister", _, "read_state"] => {
-                    set_timer_labels(&mut timer, ApiReqType::ReadState);
-                    read_state_service
-                }
-                ["", "_", "catch_up_package"] => {
-                    set_timer_labels(&mut timer, ApiReqType::CatchUpPackage);
-                    catch_up_package_service
+    // The path is borrowed from a clone of the URI, as the body of calls may
+    // be received before the request is passed on.
+    let uri = req.uri().clone();
+    let path = uri.path();
+    let (api_req_type, handler, params) = match http_handler.routes.lookup(req.method(), path) {
+        // The handler is cloned, as endpoint services cannot be shared across
+        // the await points below.
+        RouteMatch::Found(route, handler, params) => {
+            set_route_timer_labels(&mut timer, route);
+            (route.api_req_type(), handler.clone(), params)
+        }
+        RouteMatch::MethodNotAllowed(allowed) => {
+            set_timer_labels(&mut timer, ApiReqType::InvalidArgument);
+            let allow = allow_header(&allowed);
+            let mut response = make_plaintext_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                format!(
+                    "Unsupported method: {}. supported methods: {}.",
+                    req.method(),
+                    allow.to_str().unwrap_or_default()
+                ),
+            );
+            response.headers_mut().insert(http::header::ALLOW, allow);
+            return (response, timer);
+        }
+        RouteMatch::NotFound => {
+            set_timer_labels(&mut timer, ApiReqType::InvalidArgument);
+            return (
+                make_plaintext_response(
+                    StatusCode::NOT_FOUND,
+                    format!("Unexpected {} request path.", req.method()),
+                ),
+                timer,
+            );
+        }
+    };
+    // Bodies with a digest are wrapped to verify it, which drops their size
+    // hint, so their `Content-Length` is checked here instead of when they
+    // are received.
+    if content_digest.is_some() {
+        let limit = http_handler.limits.max_request_size_bytes_for(api_req_type);
+        if let Err(err) = check_content_length(req.body(), limit) {
+            metrics.observe_body_error(api_req_type, &err);
+            return (
+                make_plaintext_response(err.status(), err.to_string()),
+                timer,
+            );
+        }
+    }
+    let _in_flight = http_handler.drain_stats.start_request(api_req_type);
+    let svc = match handler {
+        Handler::Service(service) => service,
+        Handler::Call(service) => {
+            if let Some(client_key) = idempotency_key_header(req.headers()).map(str::to_string) {
+                // The body is received here to scope the key to the sender,
+                // and to check that a retry is the very same call.
+                let body = std::mem::replace(req.body_mut(), Body::empty());
+                let body = match content_digest.take() {
+                    Some(digest) => verify_content_digest(body, digest),
+                    None => body,
+                };
+                let body = match receive_body(
+                    body,
+                    MAX_REQUEST_RECEIVE_DURATION,
+                    http_handler
+                        .limits
+                        .max_request_size_bytes_for(ApiReqType::Call),
+                )
+                .await
+                {
+                    Ok(body) => body,
+                    Err(err) => {
+                        return (
+                            make_plaintext_response(err.status(), err.to_string()),
+                            timer,
+                        )
+                    }
+                };
+                if let Some((sender, message_id)) = call_identity(&body) {
+                    let key = idempotency_key(path, &sender, &client_key);
+                    if http_handler
+                        .idempotency_keys
+                        .get(&key, Instant::now())
+                        .as_ref()
+                        == Some(&message_id)
+                    {
+                        metrics.call_idempotent_retries_total.inc();
+                        let status =
+                            get_latest_certified_state(&http_handler.state_reader_executor)
+                                .await
+                                .map(|state| state.get_ingress_status(&message_id).as_str());
+                        return (replayed_response(&message_id, status), timer);
+                    }
+                    call_idempotency_key = Some(key);
                 }
+                *req.body_mut() = Body::from(body);
+            }
+            // Retries of calls submitted before are still answered above, as
+            // they submit nothing.
+            if http_handler.maintenance_mode.is_enabled() {
+                metrics.maintenance_rejections_total.inc();
+                return (http_handler.maintenance_mode.rejection(), timer);
+            }
+            call_cost_preview = wants_cost_preview(req.uri(), req.headers());
+            service
+        }
+        Handler::CatchUpPackage { protobuf, cbor } => {
+            match CatchUpPackageFormat::from_accept_header(req.headers()) {
+                CatchUpPackageFormat::Protobuf => protobuf,
+                CatchUpPackageFormat::Cbor => cbor,
+            }
+        }
+        Handler::Delegation(service) => {
+            let subnet_id = params.get("subnet_id").unwrap_or_default();
+            match PrincipalId::from_str(subnet_id) {
+                Ok(id) if SubnetId::from(id) == http_handler.subnet_id => service,
                 _ => {
-                    set_timer_labels(&mut timer, ApiReqType::InvalidArgument);
                     return (
                         make_plaintext_response(
                             StatusCode::NOT_FOUND,
-                            "Unexpected POST request path.".to_string(),
+                            format!("Unknown subnet {}.", subnet_id),
                         ),
                         timer,
                     );
                 }
             }
         }
-        Method::GET => match req.uri().path() {
-            "/api/v2/status" => {
-                set_timer_labels(&mut timer, ApiReqType::Status);
-                status_service
-            }
-            "/" | "/_/" => {
-                set_timer_labels(&mut timer, ApiReqType::RedirectToDashboard);
-                return (redirect_to_dasboard_response(), timer);
-            }
-            HTTP_DASHBOARD_URL_PATH => {
-                set_timer_labels(&mut timer, ApiReqType::Dashboard);
-                dashboard_service
-            }
-            "/_/pprof" => {
-                set_timer_labels(&mut timer, ApiReqType::PprofHome);
-                return (pprof::home(), timer);
-            }
-            "/_/pprof/profile" => {
-                set_timer_labels(&mut timer, ApiReqType::PprofProfile);
-                return (pprof::cpu_profile(req.into_parts().0).await, timer);
-            }
-            "/_/pprof/flamegraph" => {
-                set_timer_labels(&mut timer, ApiReqType::PprofFlamegraph);
-                return (pprof::cpu_flamegraph(req.into_parts().0).await, timer);
-            }
-            _ => {
-                set_timer_labels(&mut timer, ApiReqType::InvalidArgument);
-                return (
-                    make_plaintext_response(
-                        StatusCode::NOT_FOUND,
-                        "Unexpected GET request path.".to_string(),
-                    ),
-                    timer,
-                );
-            }
-        },
-        Method::OPTIONS => {
-            set_timer_labels(&mut timer, ApiReqType::Options);
-            return (no_content_response(), timer);
+        Handler::CanisterInfo(canister_info) => {
+            canister_info.service(params.get("effective_canister_id").unwrap_or_default())
         }
-        _ => {
-            set_timer_labels(&mut timer, ApiReqType::InvalidArgument);
+        Handler::SubnetPublicKey(subnet_public_key) => subnet_public_key.service(
+            params.get("subnet_id").unwrap_or_default(),
+            PublicKeyFormat::from_accept_header(req.headers()),
+        ),
+        Handler::RedirectToDashboard => return (redirect_to_dasboard_response(), timer),
+        Handler::DashboardAsset => {
+            let mut response = asset_response(params.get("asset").unwrap_or_default());
+            http_handler.security_headers.apply(&mut response);
             return (
-                make_plaintext_response(
-                    StatusCode::METHOD_NOT_ALLOWED,
-                    format!(
-                        "Unsupported method: {}. supported methods: POST, GET, OPTIONS.",
-                        req.method()
-                    ),
-                ),
+                into_not_modified_if_matching(&if_none_match, response),
                 timer,
             );
         }
+        Handler::PprofHome(access) => return (pprof::home(&access, req.into_parts().0), timer),
+        Handler::PprofProfile(access) => {
+            return (pprof::cpu_profile(&access, req.into_parts().0).await, timer)
+        }
+        Handler::PprofFlamegraph(access) => {
+            return (
+                pprof::cpu_flamegraph(&access, req.into_parts().0).await,
+                timer,
+            )
+        }
     };
-    (
-        LoadShed::new(svc)
-            .ready()
-            .await
-            .expect("The load shedder must always be ready.")
-            .call(req.into_body())
-            .await
-            .unwrap_or_else(|err| map_box_error_to_response(err)),
-        timer,
-    )
+    let body = match content_digest {
+        Some(digest) => verify_content_digest(req.into_body(), digest),
+        None => req.into_body(),
+    };
+    let mut response = LoadShed::new(svc)
+        .ready()
+        .await
+        .expect("The load shedder must always be ready.")
+        .call(body)
+        .await
+        .unwrap_or_else(|err| map_box_error_to_response(err));
+    if let Some(key) = call_idempotency_key {
+        if let Some(message_id) = response.extensions().get::<MessageId>().cloned() {
+            add_message_id_headers(&mut response, &message_id, None);
+            http_handler
+                .idempotency_keys
+                .insert(key, message_id, Instant::now());
+        }
+    }
+    if call_cost_preview {
+        add_cost_preview(&mut response);
+    }
+    let response = into_not_modified_if_matching(&if_none_match, response);
+    metrics.observe_response_body_size(api_req_type, &response);
+    let response = http_handler
+        .response_budget
+        .reserve(response)
+        .unwrap_or_else(|exceeded| {
+            metrics
+                .response_budget_rejections_total
+                .with_label_values(&[api_req_type.into()])
+                .inc();
+            exceeded.into_response()
+        });
+    (response, timer)
 }
 
 // Fetches a delegation from the NNS subnet to allow this subnet to issue
@@ -722,6 +1491,7 @@ async fn load_root_delegation(
     nns_subnet_id: SubnetId,
     registry_client: Arc<dyn RegistryClient>,
     state_reader_executor: StateReaderExecutor,
+    outbound_client: OutboundClient,
 ) -> Result<Option<CertificateDelegation>, Error> {
     if subnet_id == nns_subnet_id {
         info!(log, "On the NNS subnet. Skipping fetching the delegation.");
@@ -729,6 +1499,7 @@ async fn load_root_delegation(
         return Ok(None);
     }
 
+    let mut registry_versions = spawn_registry_version_poller(Arc::clone(&registry_client));
     let mut fetching_root_delagation_attempts = 0;
     loop {
         fetching_root_delagation_attempts += 1;
@@ -751,9 +1522,9 @@ async fn load_root_delegation(
             sleep(backoff).await
         }
 
-        let node =
-            match get_random_node_from_nns_subnet(&state_reader_executor, nns_subnet_id).await {
-                Ok(node_topology) => node_topology,
+        let nodes =
+            match get_random_nodes_from_nns_subnet(&state_reader_executor, nns_subnet_id).await {
+                Ok(nodes) => nodes,
                 Err(err) => {
                     fatal!(
                         log,
@@ -789,126 +1560,65 @@ async fn load_root_delegation(
         };
 
         let body = serde_cbor::ser::to_vec(&envelope).unwrap();
-        let http_client = Client::new();
-        let ip_addr = node.ip_address.parse().unwrap();
-        // any effective canister id can be used when invoking read_state here
-        let address = format!(
-            "http://{}/api/v2/canister/aaaaa-aa/read_state",
-            SocketAddr::new(ip_addr, node.http_port)
-        );
         info!(
             log,
-            "Attempt to fetch delegation from root subnet node with url `{}`", address
+            "Attempt to fetch delegation from root subnet nodes {:?}",
+            nodes.iter().map(|(node_id, _)| node_id).collect::<Vec<_>>()
         );
 
-        let nns_request = match Request::builder()
-            .method(hyper::Method::POST)
-            .uri(&address)
-            .header(hyper::header::CONTENT_TYPE, CONTENT_TYPE_CBOR)
-            .body(Body::from(body))
+        // any effective canister id can be used when invoking read_state here
+        let raw_response_res = match outbound_client
+            .post(
+                &nodes,
+                "/api/v2/canister/aaaaa-aa/read_state",
+                CONTENT_TYPE_CBOR,
+                body,
+                registry_client.get_latest_version(),
+            )
+            .await
         {
-            Ok(r) => r,
-            Err(err) => {
-                log_err_and_backoff(log, &err).await;
-                continue;
+            Ok((address, res)) => {
+                info!(log, "Fetching delegation from root subnet node {}", address);
+                res
             }
-        };
-
-        let raw_response_res = match http_client.request(nns_request).await {
-            Ok(res) => res,
             Err(err) => {
                 log_err_and_backoff(log, &err).await;
                 continue;
             }
         };
 
-        match hyper::body::to_bytes(raw_response_res).await {
+        match receive_body(
+            raw_response_res.into_body(),
+            DELEGATION_RESPONSE_TIMEOUT,
+            Byte::from_bytes(MAX_DELEGATION_RESPONSE_BYTES.into()),
+        )
+        .await
+        {
             Ok(raw_response) => {
                 debug!(log, "Response from nns subnet: {:?}", raw_response);
 
-                let response: HttpReadStateResponse = match serde_cbor::from_slice(&raw_response) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        log_err_and_backoff(log, &e).await;
-                        continue;
-                    }
-                };
-
-                let parsed_delegation: Certificate =
-                    match serde_cbor::from_slice(&response.certificate) {
-                        Ok(r) => r,
-                        Err(e) => {
-                            log_err_and_backoff(
-                                log,
-                                &format!("failed to parse delegation certificate: {}", e),
-                            )
-                            .await;
-                            continue;
-                        }
-                    };
-
-                let labeled_tree = match LabeledTree::try_from(parsed_delegation.tree) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        log_err_and_backoff(
-                            log,
-                            &format!("invalid hash tree in the delegation certificate: {:?}", e),
-                        )
-                        .await;
-                        continue;
-                    }
-                };
-
-                let registry_version = registry_client.get_latest_version();
-                let own_public_key_from_registry = match registry_client
-                    .get_threshold_signing_public_key_for_subnet(subnet_id, registry_version)
-                {
-                    Ok(Some(pk)) => pk,
-                    Ok(None) => {
-                        log_err_and_backoff(
-                            log,
-                            &format!("subnet {} public key from registry is empty", subnet_id),
-                        )
-                        .await;
-                        continue;
-                    }
+                let ParsedDelegation {
+                    certificate,
+                    tree: labeled_tree,
+                } = match parse_delegation_response(&raw_response) {
+                    Ok(parsed) => parsed,
                     Err(err) => {
-                        log_err_and_backoff(
-                            log,
-                            &format!(
-                                "subnet {} public key could not be extracted from registry: {:?}",
-                                subnet_id, err,
-                            ),
-                        )
-                        .await;
+                        log_err_and_backoff(log, &err).await;
                         continue;
                     }
                 };
 
-                match lookup_path(
+                let public_key_from_certificate = match lookup_path(
                     &labeled_tree,
                     &[b"subnet", subnet_id.get_ref().as_ref(), b"public_key"],
                 ) {
                     Some(LabeledTree::Leaf(pk_bytes)) => {
-                        let public_key_from_certificate =
-                            match parse_threshold_sig_key_from_der(pk_bytes) {
-                                Ok(pk) => pk,
-                                Err(err) => {
-                                    log_err_and_backoff(log, &err).await;
-                                    continue;
-                                }
-                            };
-
-                        if public_key_from_certificate != own_public_key_from_registry {
-                            log_err_and_backoff(
-                                log,
-                                &format!(
-                                    "mismatch of registry and certificate public keys for subnet {}",
-                                    subnet_id
-                                ),
-                            )
-                            .await;
-                            continue;
+                        match parse_threshold_sig_key_from_der(pk_bytes) {
+                            Ok(pk) => pk,
+                            Err(err) => {
+                                log_err_and_backoff(log, &err).await;
+                                continue;
+                            }
                         }
                     }
                     _ => {
@@ -922,6 +1632,54 @@ async fn load_root_delegation(
                         .await;
                         continue;
                     }
+                };
+
+                // The public key may be missing or differ because the local
+                // registry is behind, so it is looked up again at newer
+                // versions as they arrive before fetching the delegation again.
+                let mut registry_version = registry_client.get_latest_version();
+                let matches_registry = loop {
+                    let err = match registry_client
+                        .get_threshold_signing_public_key_for_subnet(subnet_id, registry_version)
+                    {
+                        Ok(Some(pk)) if pk == public_key_from_certificate => break true,
+                        Ok(Some(_)) => format!(
+                            "mismatch of registry and certificate public keys for subnet {}",
+                            subnet_id
+                        ),
+                        Ok(None) => {
+                            format!("subnet {} public key from registry is empty", subnet_id)
+                        }
+                        Err(err) => format!(
+                            "subnet {} public key could not be extracted from registry: {:?}",
+                            subnet_id, err,
+                        ),
+                    };
+                    match wait_for_registry_version_after(
+                        &mut registry_versions,
+                        registry_version,
+                        DELEGATION_REGISTRY_UPDATE_TIMEOUT,
+                    )
+                    .await
+                    {
+                        Some(version) => {
+                            info!(
+                                log,
+                                "Validating the delegation again at registry version {}, error at registry version {}: {}",
+                                version,
+                                registry_version,
+                                err
+                            );
+                            registry_version = version;
+                        }
+                        None => {
+                            log_err_and_backoff(log, &err).await;
+                            break false;
+                        }
+                    }
+                };
+                if !matches_registry {
+                    continue;
                 }
                 let root_pk_blob =
                     match get_root_public_key(log, &state_reader_executor, &nns_subnet_id).await {
@@ -945,7 +1703,7 @@ async fn load_root_delegation(
                         }
                     };
                 if let Err(err) = validate_subnet_delegation_certificate(
-                    &response.certificate,
+                    &certificate,
                     &subnet_id,
                     &root_threshold_public_key,
                 ) {
@@ -959,7 +1717,7 @@ async fn load_root_delegation(
 
                 let delegation = CertificateDelegation {
                     subnet_id: Blob(subnet_id.get().to_vec()),
-                    certificate: response.certificate,
+                    certificate,
                 };
 
                 info!(log, "Setting NNS delegation to: {:?}", delegation);
@@ -973,11 +1731,15 @@ async fn load_root_delegation(
     }
 }
 
-async fn get_random_node_from_nns_subnet(
+// The number of NNS nodes connections are attempted to when fetching the
+// delegation.
+const MAX_DELEGATION_FETCH_NODES: usize = 4;
+
+async fn get_random_nodes_from_nns_subnet(
     state_reader_executor: &StateReaderExecutor,
     nns_subnet_id: SubnetId,
-) -> Result<NodeTopology, String> {
-    use rand::seq::IteratorRandom;
+) -> Result<Vec<(NodeId, NodeTopology)>, String> {
+    use rand::seq::{IteratorRandom, SliceRandom};
 
     let latest_state = state_reader_executor
         .get_latest_state()
@@ -990,22 +1752,41 @@ async fn get_random_node_from_nns_subnet(
         String::from("NNS subnet not found in network topology. Skipping fetching the delegation.")
     })?;
 
-    // Randomly choose a node from the nns subnet.
+    // Randomly choose a few nodes from the nns subnet.
     let mut rng = rand::thread_rng();
-    nns_subnet_topology
+    let mut nodes = nns_subnet_topology
         .nodes
-        .values()
-        .choose(&mut rng)
-        .cloned()
-        .ok_or_else(|| {
-            String::from("NNS subnet contains no nodes. Skipping fetching the delegation.")
-        })
+        .iter()
+        .map(|(node_id, node)| (*node_id, node.clone()))
+        .choose_multiple(&mut rng, MAX_DELEGATION_FETCH_NODES);
+    if nodes.is_empty() {
+        return Err(String::from(
+            "NNS subnet contains no nodes. Skipping fetching the delegation.",
+        ));
+    }
+    // `choose_multiple` does not randomize the order of the chosen nodes.
+    nodes.shuffle(&mut rng);
+    Ok(nodes)
 }
 
-fn no_content_response() -> Response<Body> {
+// Answers an `OPTIONS` request with the methods allowed for `path`, in the
+// `Allow` header and as the methods allowed for CORS requests.
+fn options_response(routes: &RouteTable<Handler>, path: &str) -> Response<Body> {
+    let allowed = routes.allowed_methods(path);
+    if allowed.is_empty() {
+        return make_plaintext_response(
+            StatusCode::NOT_FOUND,
+            "Unexpected OPTIONS request path.".to_string(),
+        );
+    }
+    let allow = allow_header(&allowed);
     let mut response = Response::new(Body::from(""));
     *response.status_mut() = StatusCode::NO_CONTENT;
     *response.headers_mut() = get_cors_headers();
+    response
+        .headers_mut()
+        .insert(http::header::ACCESS_CONTROL_ALLOW_METHODS, allow.clone());
+    response.headers_mut().insert(http::header::ALLOW, allow);
     response
 }
 
@@ -0,0 +1,364 @@
+//! Module that selects the limits of the HTTP handler for the subnet type.
+//!
+//! System subnets (e.g. the NNS) host few, critical canisters and are tuned
+//! conservatively by default, while application subnets are tuned for
+//! throughput, whether verified or not. Every limit can be overridden in the
+//! [`Config`].
+use crate::{
+    read_state::{
+        MAX_READ_STATE_CONCURRENT_REQUESTS, MAX_READ_STATE_PATHS, MAX_READ_STATE_PATH_BYTES,
+    },
+    types::ApiReqType,
+    HTTP_MAX_CONCURRENT_STREAMS, MAX_OUTSTANDING_CONNECTIONS, MAX_REQUEST_HEADER_BYTES,
+    MAX_REQUEST_HEADER_COUNT, MAX_REQUEST_SIZE_BYTES,
+};
+use byte_unit::Byte;
+use hyper::HeaderMap;
+use ic_config::http_handler::Config;
+use ic_crypto_tree_hash::Path;
+use ic_registry_subnet_type::SubnetType;
+use std::collections::BTreeMap;
+use strum::IntoStaticStr;
+
+/// The request types of the endpoints that receive a request body.
+const BODY_REQUEST_TYPES: [ApiReqType; 4] = [
+    ApiReqType::Call,
+    ApiReqType::Query,
+    ApiReqType::ReadState,
+    ApiReqType::CatchUpPackage,
+];
+
+/// The limits applied by the HTTP handler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LimitProfile {
+    /// The maximum number of live TCP connections.
+    pub max_outstanding_connections: usize,
+    /// The SETTINGS_MAX_CONCURRENT_STREAMS option for HTTP2 connections.
+    pub http_max_concurrent_streams: u32,
+    /// The maximum size of the body of `call`, `query` and `read_state`
+    /// requests.
+    pub max_request_size_bytes: Byte,
+    /// The maximum size of the body of requests of some request types, by
+    /// request type, taking precedence over `max_request_size_bytes`.
+    pub max_request_size_bytes_by_request_type: BTreeMap<String, Byte>,
+    /// The maximum number of `read_state` requests processed concurrently.
+    pub max_read_state_concurrent_requests: usize,
+    /// The maximum number of queries executing concurrently per canister, if
+    /// capped.
+    pub max_concurrent_queries_per_canister: Option<usize>,
+    /// The maximum number of headers of a request.
+    pub max_request_header_count: usize,
+    /// The maximum total size of the names and values of the headers of a
+    /// request.
+    pub max_request_header_bytes: usize,
+    /// The maximum number of paths of a `read_state` request.
+    pub max_read_state_paths: usize,
+    /// The maximum total size of the labels of the paths of a `read_state`
+    /// request.
+    pub max_read_state_path_bytes: usize,
+}
+
+impl LimitProfile {
+    /// Returns the default limits for a subnet of type `subnet_type`.
+    pub(crate) fn for_subnet_type(subnet_type: SubnetType) -> Self {
+        match subnet_type {
+            SubnetType::System => Self {
+                max_outstanding_connections: 5_000,
+                http_max_concurrent_streams: 64,
+                max_request_size_bytes: Byte::from_bytes(4 * 1024 * 1024), // 4MB
+                max_request_size_bytes_by_request_type: BTreeMap::new(),
+                max_read_state_concurrent_requests: 50,
+                max_concurrent_queries_per_canister: Some(4),
+                max_request_header_count: MAX_REQUEST_HEADER_COUNT,
+                max_request_header_bytes: MAX_REQUEST_HEADER_BYTES,
+                max_read_state_paths: MAX_READ_STATE_PATHS,
+                max_read_state_path_bytes: MAX_READ_STATE_PATH_BYTES,
+            },
+            SubnetType::Application | SubnetType::VerifiedApplication => Self {
+                max_outstanding_connections: MAX_OUTSTANDING_CONNECTIONS,
+                http_max_concurrent_streams: HTTP_MAX_CONCURRENT_STREAMS,
+                max_request_size_bytes: MAX_REQUEST_SIZE_BYTES,
+                max_request_size_bytes_by_request_type: BTreeMap::new(),
+                max_read_state_concurrent_requests: MAX_READ_STATE_CONCURRENT_REQUESTS,
+                max_concurrent_queries_per_canister: None,
+                max_request_header_count: MAX_REQUEST_HEADER_COUNT,
+                max_request_header_bytes: MAX_REQUEST_HEADER_BYTES,
+                max_read_state_paths: MAX_READ_STATE_PATHS,
+                max_read_state_path_bytes: MAX_READ_STATE_PATH_BYTES,
+            },
+        }
+    }
+
+    /// Returns the limits with the ones set in `config` taking precedence.
+    pub(crate) fn with_overrides(self, config: &Config) -> Self {
+        Self {
+            max_outstanding_connections: config
+                .max_outstanding_connections
+                .unwrap_or(self.max_outstanding_connections),
+            http_max_concurrent_streams: config
+                .http_max_concurrent_streams
+                .unwrap_or(self.http_max_concurrent_streams),
+            max_request_size_bytes: config
+                .max_request_size_bytes
+                .map(|bytes| Byte::from_bytes(bytes as u128))
+                .unwrap_or(self.max_request_size_bytes),
+            max_request_size_bytes_by_request_type: self
+                .max_request_size_bytes_by_request_type
+                .into_iter()
+                .chain(config.max_request_size_bytes_by_request_type.iter().map(
+                    |(request_type, bytes)| {
+                        (request_type.clone(), Byte::from_bytes(*bytes as u128))
+                    },
+                ))
+                .collect(),
+            max_read_state_concurrent_requests: config
+                .max_read_state_concurrent_requests
+                .unwrap_or(self.max_read_state_concurrent_requests),
+            max_concurrent_queries_per_canister: config
+                .max_concurrent_queries_per_canister
+                .or(self.max_concurrent_queries_per_canister),
+            max_request_header_count: config
+                .max_request_header_count
+                .unwrap_or(self.max_request_header_count),
+            max_request_header_bytes: config
+                .max_request_header_bytes
+                .unwrap_or(self.max_request_header_bytes),
+            max_read_state_paths: config
+                .max_read_state_paths
+                .unwrap_or(self.max_read_state_paths),
+            max_read_state_path_bytes: config
+                .max_read_state_path_bytes
+                .unwrap_or(self.max_read_state_path_bytes),
+        }
+    }
+
+    /// Returns the maximum size of the body of requests of type
+    /// `api_req_type`.
+    pub(crate) fn max_request_size_bytes_for(&self, api_req_type: ApiReqType) -> Byte {
+        let request_type: &str = api_req_type.into();
+        self.max_request_size_bytes_by_request_type
+            .get(request_type)
+            .copied()
+            .unwrap_or(self.max_request_size_bytes)
+    }
+
+    /// Returns the limits on the headers of a request.
+    pub(crate) fn header_limits(&self) -> HeaderLimits {
+        HeaderLimits {
+            max_count: self.max_request_header_count,
+            max_bytes: self.max_request_header_bytes,
+        }
+    }
+
+    /// Returns the limits on the paths of a `read_state` request.
+    pub(crate) fn read_state_path_limits(&self) -> ReadStatePathLimits {
+        ReadStatePathLimits {
+            max_count: self.max_read_state_paths,
+            max_bytes: self.max_read_state_path_bytes,
+        }
+    }
+}
+
+/// Returns the request types in `max_request_size_bytes_by_request_type` of
+/// `config` that do not receive a request body, and are hence ignored.
+pub(crate) fn unknown_body_request_types(config: &Config) -> Vec<&str> {
+    config
+        .max_request_size_bytes_by_request_type
+        .keys()
+        .map(String::as_str)
+        .filter(|request_type| {
+            !BODY_REQUEST_TYPES
+                .iter()
+                .any(|api_req_type| <&str>::from(*api_req_type) == *request_type)
+        })
+        .collect()
+}
+
+/// The limits on the headers of a request, checked before it is routed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct HeaderLimits {
+    pub max_count: usize,
+    pub max_bytes: usize,
+}
+
+/// The header limit exceeded by a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum HeaderLimitExceeded {
+    HeaderCount,
+    HeaderBytes,
+}
+
+impl HeaderLimits {
+    /// Checks that `headers` are within the limits. The size of the headers is
+    /// the total size of their names and values.
+    pub(crate) fn check(&self, headers: &HeaderMap) -> Result<(), HeaderLimitExceeded> {
+        if headers.len() > self.max_count {
+            return Err(HeaderLimitExceeded::HeaderCount);
+        }
+        let bytes: usize = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        if bytes > self.max_bytes {
+            return Err(HeaderLimitExceeded::HeaderBytes);
+        }
+        Ok(())
+    }
+}
+
+/// The limits on the paths of a `read_state` request, checked before the
+/// paths are turned into a labeled tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ReadStatePathLimits {
+    pub max_count: usize,
+    pub max_bytes: usize,
+}
+
+/// The path limit exceeded by a `read_state` request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum ReadStatePathLimitExceeded {
+    PathCount,
+    PathBytes,
+}
+
+impl ReadStatePathLimits {
+    /// Checks that `paths` are within the limits. The size of the paths is the
+    /// total size of their labels.
+    pub(crate) fn check(&self, paths: &[Path]) -> Result<(), ReadStatePathLimitExceeded> {
+        if paths.len() > self.max_count {
+            return Err(ReadStatePathLimitExceeded::PathCount);
+        }
+        let bytes: usize = paths
+            .iter()
+            .flat_map(|path| path.iter())
+            .map(|label| label.as_bytes().len())
+            .sum();
+        if bytes > self.max_bytes {
+            return Err(ReadStatePathLimitExceeded::PathBytes);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_crypto_tree_hash::Label;
+
+    #[test]
+    fn system_subnets_are_tuned_conservatively() {
+        let system = LimitProfile::for_subnet_type(SubnetType::System);
+        let application = LimitProfile::for_subnet_type(SubnetType::Application);
+        assert!(system.max_outstanding_connections < application.max_outstanding_connections);
+        assert!(system.http_max_concurrent_streams < application.http_max_concurrent_streams);
+        assert!(system.max_request_size_bytes < application.max_request_size_bytes);
+        assert!(
+            system.max_read_state_concurrent_requests
+                < application.max_read_state_concurrent_requests
+        );
+        assert!(system.max_concurrent_queries_per_canister.is_some());
+    }
+
+    #[test]
+    fn config_overrides_profile() {
+        let config = Config {
+            max_outstanding_connections: Some(10),
+            max_request_size_bytes: Some(1024),
+            max_concurrent_queries_per_canister: Some(2),
+            ..Config::default()
+        };
+        let profile = LimitProfile::for_subnet_type(SubnetType::System);
+        assert_eq!(
+            profile.clone().with_overrides(&config),
+            LimitProfile {
+                max_outstanding_connections: 10,
+                max_request_size_bytes: Byte::from_bytes(1024),
+                max_concurrent_queries_per_canister: Some(2),
+                ..profile.clone()
+            }
+        );
+        assert_eq!(profile.clone().with_overrides(&Config::default()), profile);
+    }
+
+    #[test]
+    fn body_size_can_be_overridden_per_request_type() {
+        let config = Config {
+            max_request_size_bytes: Some(1024),
+            max_request_size_bytes_by_request_type: vec![
+                ("call".to_string(), 4096),
+                ("catch_up_package".to_string(), 16),
+                ("status".to_string(), 1),
+            ]
+            .into_iter()
+            .collect(),
+            ..Config::default()
+        };
+        let profile =
+            LimitProfile::for_subnet_type(SubnetType::Application).with_overrides(&config);
+        assert_eq!(
+            profile.max_request_size_bytes_for(ApiReqType::Call),
+            Byte::from_bytes(4096)
+        );
+        assert_eq!(
+            profile.max_request_size_bytes_for(ApiReqType::CatchUpPackage),
+            Byte::from_bytes(16)
+        );
+        assert_eq!(
+            profile.max_request_size_bytes_for(ApiReqType::Query),
+            Byte::from_bytes(1024)
+        );
+        // Request types without a body can't be limited.
+        assert_eq!(unknown_body_request_types(&config), vec!["status"]);
+    }
+
+    #[test]
+    fn headers_are_checked_against_limits() {
+        let limits = HeaderLimits {
+            max_count: 2,
+            max_bytes: 32,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "127.0.0.1".parse().unwrap());
+        headers.insert("accept", "*/*".parse().unwrap());
+        assert_eq!(limits.check(&headers), Ok(()));
+
+        headers.append("accept", "application/cbor".parse().unwrap());
+        assert_eq!(
+            limits.check(&headers),
+            Err(HeaderLimitExceeded::HeaderCount)
+        );
+
+        headers.remove("accept");
+        headers.insert("user-agent", "a".repeat(16).parse().unwrap());
+        assert_eq!(
+            limits.check(&headers),
+            Err(HeaderLimitExceeded::HeaderBytes)
+        );
+    }
+
+    #[test]
+    fn read_state_paths_are_checked_against_limits() {
+        let limits = ReadStatePathLimits {
+            max_count: 2,
+            max_bytes: 16,
+        };
+        let path = |labels: &[&str]| Path::new(labels.iter().map(|l| Label::from(*l)).collect());
+        let mut paths = vec![path(&["time"]), path(&["subnet"])];
+        assert_eq!(limits.check(&paths), Ok(()));
+
+        paths.push(path(&["time"]));
+        assert_eq!(
+            limits.check(&paths),
+            Err(ReadStatePathLimitExceeded::PathCount)
+        );
+
+        paths.truncate(1);
+        paths.push(path(&["request_status", "0123456789"]));
+        assert_eq!(
+            limits.check(&paths),
+            Err(ReadStatePathLimitExceeded::PathBytes)
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! Read-only maintenance mode, to drain a node of calls before an upgrade.
+//!
+//! In maintenance mode, calls are rejected with `503 Service Unavailable` and
+//! a `Retry-After` header, so that agents submit them to other nodes, while
+//! queries, `read_state` and status requests are still served. Agents polling
+//! for the status of calls submitted earlier thus keep getting answers until
+//! the node goes down.
+//!
+//! If `maintenance_mode_on_signals` is set, `SIGUSR1` switches maintenance
+//! mode on and `SIGUSR2` switches it off again.
+
+use crate::{common::make_plaintext_response, metrics::HttpHandlerMetrics};
+use hyper::{
+    header::{self, HeaderValue},
+    Body, Response, StatusCode,
+};
+use ic_logger::{info, warn, ReplicaLogger};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Whether calls are rejected to drain the node.
+pub(crate) struct MaintenanceMode {
+    enabled: AtomicBool,
+    retry_after: Duration,
+}
+
+impl MaintenanceMode {
+    /// Rejected calls are to be retried after `retry_after`.
+    pub(crate) fn new(retry_after: Duration) -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            retry_after,
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Switches maintenance mode on or off, returning whether it was on.
+    pub(crate) fn set(&self, enabled: bool) -> bool {
+        self.enabled.swap(enabled, Ordering::Relaxed)
+    }
+
+    /// The response to calls while in maintenance mode.
+    pub(crate) fn rejection(&self) -> Response<Body> {
+        let mut response = make_plaintext_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The replica is in maintenance mode and does not accept calls, retry later or on another node.".to_string(),
+        );
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(self.retry_after.as_secs()),
+        );
+        response
+    }
+}
+
+/// Switches `maintenance_mode` on on `SIGUSR1` and off on `SIGUSR2`, until the
+/// process exits.
+pub(crate) async fn switch_on_signals(
+    maintenance_mode: &MaintenanceMode,
+    metrics: HttpHandlerMetrics,
+    log: ReplicaLogger,
+) {
+    let (mut sigusr1, mut sigusr2) = match (
+        signal(SignalKind::user_defined1()),
+        signal(SignalKind::user_defined2()),
+    ) {
+        (Ok(sigusr1), Ok(sigusr2)) => (sigusr1, sigusr2),
+        (Err(err), _) | (_, Err(err)) => {
+            warn!(log, "Can't listen for SIGUSR1 and SIGUSR2, error = {}", err);
+            return;
+        }
+    };
+    loop {
+        let enabled = tokio::select! {
+            _ = sigusr1.recv() => true,
+            _ = sigusr2.recv() => false,
+        };
+        if maintenance_mode.set(enabled) != enabled {
+            metrics.maintenance_mode.set(enabled as i64);
+            if enabled {
+                info!(log, "Entered maintenance mode, rejecting calls");
+            } else {
+                info!(log, "Left maintenance mode, accepting calls");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_are_rejected_with_retry_after() {
+        let maintenance_mode = MaintenanceMode::new(Duration::from_secs(30));
+        assert!(!maintenance_mode.is_enabled());
+        assert!(!maintenance_mode.set(true));
+        assert!(maintenance_mode.is_enabled());
+        assert!(maintenance_mode.set(true));
+
+        let response = maintenance_mode.rejection();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "30");
+
+        assert!(maintenance_mode.set(false));
+        assert!(!maintenance_mode.is_enabled());
+    }
+}
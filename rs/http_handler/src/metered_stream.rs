@@ -0,0 +1,260 @@
+//! Module that counts the bytes read from and written to a connection, and
+//! optionally caps the rate at which bytes are written to it.
+//!
+//! Without a cap, a single client streaming large `read_state` responses can
+//! saturate the NIC of the node at the expense of all other clients.
+use crate::{metrics::HttpHandlerMetrics, types::AppLayer};
+use ic_types::time::{RateTracker, Time};
+use std::{
+    cmp::min,
+    future::Future,
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep_until, Instant, Sleep},
+};
+
+// The largest write a throttled connection waits to accumulate tokens for.
+// Writes of more bytes are split, so that a throttled connection makes
+// progress in chunks of reasonable size instead of byte by byte.
+const MAX_THROTTLED_WRITE_BYTES: u64 = 16 * 1024;
+
+// The number of steps the one second window of a write limit slides in.
+const WRITE_LIMIT_BUCKETS: u32 = 10;
+
+/// Caps the bytes written over any second. As the window slides in steps of
+/// a bucket, written bytes count against the cap for up to a bucket longer
+/// than a second.
+#[derive(Debug)]
+struct WriteLimit {
+    bytes_per_second: u64,
+    written: RateTracker,
+    // The origin of the times the written bytes are recorded at.
+    origin: Instant,
+}
+
+impl WriteLimit {
+    fn new(bytes_per_second: u64, now: Instant) -> Self {
+        Self {
+            bytes_per_second,
+            written: RateTracker::new(Duration::from_secs(1), WRITE_LIMIT_BUCKETS),
+            origin: now,
+        }
+    }
+
+    // The time relative to `origin`.
+    fn relative_time(&self, now: Instant) -> Time {
+        Time::from_nanos_since_unix_epoch(
+            now.saturating_duration_since(self.origin).as_nanos() as u64
+        )
+    }
+
+    /// Returns the number of bytes of a write of `len` bytes that may be
+    /// written at `now`, or the instant at which to try again if none.
+    fn admit(&mut self, len: usize, now: Instant) -> Result<usize, Instant> {
+        let wanted = min(
+            len as u64,
+            min(self.bytes_per_second, MAX_THROTTLED_WRITE_BYTES),
+        );
+        let written = self.written.count(self.relative_time(now));
+        if written + wanted <= self.bytes_per_second {
+            return Ok(wanted as usize);
+        }
+        // Bytes were written, as `wanted` alone is within the cap, so some leave
+        // the window eventually.
+        Err(self.written.next_eviction().map_or(now, |next_eviction| {
+            self.origin + Duration::from_nanos(next_eviction.as_nanos_since_unix_epoch())
+        }))
+    }
+
+    /// Records `written` bytes written at `now`.
+    fn consume(&mut self, written: usize, now: Instant) {
+        let now = self.relative_time(now);
+        self.written.record_n(now, written as u64);
+    }
+}
+
+/// A stream that counts the bytes read from and written to `inner`, and
+/// records them in the connection metrics when dropped.
+pub(crate) struct MeteredStream<S> {
+    inner: S,
+    bytes_read: u64,
+    bytes_written: u64,
+    write_limit: Option<WriteLimit>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+    app_layer: AppLayer,
+    metrics: HttpHandlerMetrics,
+}
+
+impl<S> MeteredStream<S> {
+    /// Wraps `inner`, writing at most `max_write_bytes_per_second` to it if
+    /// set.
+    pub(crate) fn new(
+        inner: S,
+        max_write_bytes_per_second: Option<u64>,
+        app_layer: AppLayer,
+        metrics: HttpHandlerMetrics,
+    ) -> Self {
+        Self {
+            inner,
+            bytes_read: 0,
+            bytes_written: 0,
+            write_limit: max_write_bytes_per_second
+                .filter(|bytes_per_second| *bytes_per_second > 0)
+                .map(|bytes_per_second| WriteLimit::new(bytes_per_second, Instant::now())),
+            write_delay: None,
+            app_layer,
+            metrics,
+        }
+    }
+}
+
+impl<S> Drop for MeteredStream<S> {
+    fn drop(&mut self) {
+        self.metrics
+            .observe_connection_bytes(self.app_layer, self.bytes_read, self.bytes_written);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.bytes_read += (buf.filled().len() - filled_before) as u64;
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let len = loop {
+            if let Some(write_delay) = this.write_delay.as_mut() {
+                if write_delay.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.write_delay = None;
+            }
+            match this.write_limit.as_mut() {
+                None => break buf.len(),
+                Some(write_limit) => match write_limit.admit(buf.len(), Instant::now()) {
+                    Ok(len) => break len,
+                    Err(deadline) => {
+                        this.metrics.connection_write_throttled_total.inc();
+                        this.write_delay = Some(Box::pin(sleep_until(deadline)));
+                    }
+                },
+            }
+        };
+        let result = Pin::new(&mut this.inner).poll_write(cx, &buf[..len]);
+        if let Poll::Ready(Ok(written)) = result {
+            this.bytes_written += written as u64;
+            if let Some(write_limit) = this.write_limit.as_mut() {
+                write_limit.consume(written, Instant::now());
+            }
+        }
+        result
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        if self.write_limit.is_some() {
+            let buf = bufs
+                .iter()
+                .find(|buf| !buf.is_empty())
+                .map_or(&[][..], |buf| &**buf);
+            return self.poll_write(cx, buf);
+        }
+        let result = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(written)) = result {
+            self.bytes_written += written as u64;
+        }
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.write_limit.is_none() && self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_metrics::MetricsRegistry;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn write_limit_caps_the_bytes_written_per_second() {
+        let start = Instant::now();
+        let mut limit = WriteLimit::new(1000, start);
+
+        assert_eq!(limit.admit(4000, start), Ok(1000));
+        limit.consume(1000, start);
+        // The bytes leave the window a bucket after the second they were
+        // written in.
+        let retry_at = start + Duration::from_millis(1100);
+        assert_eq!(limit.admit(4000, start), Err(retry_at));
+        assert_eq!(
+            limit.admit(100, start + Duration::from_millis(500)),
+            Err(retry_at)
+        );
+        assert_eq!(limit.admit(4000, retry_at), Ok(1000));
+
+        // Writes below the limit are admitted in full.
+        limit.consume(400, retry_at);
+        assert_eq!(limit.admit(600, retry_at), Ok(600));
+        assert_eq!(
+            limit.admit(601, retry_at),
+            Err(retry_at + Duration::from_millis(1100))
+        );
+    }
+
+    #[tokio::test]
+    async fn bytes_are_counted_in_both_directions() {
+        let metrics = HttpHandlerMetrics::new(&MetricsRegistry::new());
+        let (mut client, server) = duplex(1024);
+        let mut server =
+            MeteredStream::new(server, Some(1_000_000), AppLayer::Http, metrics.clone());
+
+        client.write_all(b"ping").await.unwrap();
+        let mut request = [0; 4];
+        server.read_exact(&mut request).await.unwrap();
+        server.write_all(b"pong!").await.unwrap();
+        drop(server);
+
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"pong!");
+        let bytes = |direction| {
+            metrics
+                .connection_bytes
+                .with_label_values(&[direction, AppLayer::Http.into()])
+                .get_sample_sum()
+        };
+        assert_eq!(bytes("read"), 4.0);
+        assert_eq!(bytes("written"), 5.0);
+    }
+}
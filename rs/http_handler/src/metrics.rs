@@ -1,12 +1,22 @@
-use crate::types::*;
+use crate::{
+    body::BodyError, client_hello::ClientHello, client_origin::ClientOrigin,
+    framing::AmbiguousFraming, response_budget::body_size, types::*,
+};
+use hyper::{Body, Response, Version};
 use ic_metrics::{
     buckets::{add_bucket, decimal_buckets},
+    histogram_vec_timer::HistogramVecTimer,
     MetricsRegistry,
 };
-use prometheus::{HistogramVec, IntCounter, IntCounterVec, IntGauge};
-use tokio::time::Instant;
+use ic_types::time::{Skew, Stopwatch};
+use prometheus::{Gauge, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge};
+use std::time::Duration;
 
+pub const LABEL_API_VERSION: &str = "api_version";
+pub const LABEL_ASN: &str = "asn";
+pub const LABEL_COUNTRY: &str = "country";
 pub const LABEL_DETAIL: &str = "detail";
+pub const LABEL_DIRECTION: &str = "direction";
 pub const LABEL_PROTOCOL: &str = "protocol";
 pub const LABEL_REQUEST_TYPE: &str = "request_type";
 pub const LABEL_STATUS: &str = "status";
@@ -16,9 +26,24 @@ pub const LABEL_VERSION: &str = "version";
 const STATUS_SUCCESS: &str = "success";
 const STATUS_ERROR: &str = "error";
 
-pub const REQUESTS_NUM_LABELS: usize = 3;
-pub const REQUESTS_LABEL_NAMES: [&str; REQUESTS_NUM_LABELS] =
-    [LABEL_TYPE, LABEL_REQUEST_TYPE, LABEL_STATUS];
+const DIRECTION_READ: &str = "read";
+const DIRECTION_WRITTEN: &str = "written";
+
+// Latency objectives, by request type. Requests slower than the threshold
+// count against the error budget of the corresponding SLO.
+const SLO_LATENCY_THRESHOLDS: [(ApiReqType, Duration); 3] = [
+    (ApiReqType::Call, Duration::from_secs(1)),
+    (ApiReqType::Query, Duration::from_millis(500)),
+    (ApiReqType::ReadState, Duration::from_millis(500)),
+];
+
+pub const REQUESTS_NUM_LABELS: usize = 4;
+pub const REQUESTS_LABEL_NAMES: [&str; REQUESTS_NUM_LABELS] = [
+    LABEL_TYPE,
+    LABEL_REQUEST_TYPE,
+    LABEL_STATUS,
+    LABEL_API_VERSION,
+];
 
 // Struct holding only Prometheus metric objects. Hence, it is thread-safe iff
 // the data members are thread-safe.
@@ -26,9 +51,44 @@ pub const REQUESTS_LABEL_NAMES: [&str; REQUESTS_NUM_LABELS] =
 pub(crate) struct HttpHandlerMetrics {
     pub(crate) requests: HistogramVec,
     pub(crate) requests_body_size_bytes: HistogramVec,
+    pub(crate) responses_body_size_bytes: HistogramVec,
+    pub(crate) buffered_response_bytes: IntGauge,
+    pub(crate) response_budget_rejections_total: IntCounterVec,
     pub(crate) protocol_version_total: IntCounterVec,
     pub(crate) connections: IntGauge,
     pub(crate) connections_total: IntCounter,
+    pub(crate) connections_draining: IntGauge,
+    pub(crate) connection_drain_duration: HistogramVec,
+    pub(crate) query_canister_queue_duration: Histogram,
+    pub(crate) query_canister_queued: IntGauge,
+    pub(crate) query_canister_rejections_total: IntCounter,
+    pub(crate) ingress_queue_depth: IntGauge,
+    pub(crate) ingress_filter_cache_hits_total: IntCounter,
+    pub(crate) boot_time_seconds: IntGauge,
+    pub(crate) call_idempotent_retries_total: IntCounter,
+    pub(crate) tls_certificate_rotations_total: IntCounter,
+    pub(crate) tls_registry_version: IntGauge,
+    pub(crate) connection_bytes: HistogramVec,
+    pub(crate) connection_write_throttled_total: IntCounter,
+    pub(crate) requests_per_connection: HistogramVec,
+    pub(crate) single_request_connections_total: IntCounterVec,
+    pub(crate) panics_total: IntCounterVec,
+    pub(crate) batch_time_regressions_total: IntCounter,
+    pub(crate) deprecated_requests_total: IntCounterVec,
+    pub(crate) maintenance_mode: IntGauge,
+    pub(crate) maintenance_rejections_total: IntCounter,
+    slo_requests_total: IntCounterVec,
+    slo_slow_requests_total: IntCounterVec,
+    body_errors_total: IntCounterVec,
+    header_rejections_total: IntCounterVec,
+    ambiguous_requests_total: IntCounterVec,
+    clock_skew_seconds: Gauge,
+    read_state_paths: Histogram,
+    read_state_path_rejections_total: IntCounterVec,
+    replayed_requests_total: IntCounterVec,
+    tls_client_hello_total: IntCounterVec,
+    connections_by_asn_total: IntCounterVec,
+    connections_by_country_total: IntCounterVec,
     connection_setup_duration: HistogramVec,
     connection_duration: HistogramVec,
 }
@@ -44,7 +104,7 @@ impl HttpHandlerMetrics {
         Self {
             requests: metrics_registry.histogram_vec(
                 "replica_http_request_duration_seconds",
-                "HTTP/HTTPS request latencies in seconds. These do not include connection errors, see `replica_connection_errors` for those.",
+                "HTTP/HTTPS request latencies in seconds, by request type, status and API version. These do not include connection errors, see `replica_connection_errors` for those.",
                 // We need more than what the default offers (max 10.0), so we
                 // could better check the acceptance of our scenario tests. In
                 // addition, this code uses decimal buckets just like all other
@@ -62,11 +122,27 @@ impl HttpHandlerMetrics {
             ),
             requests_body_size_bytes: metrics_registry.histogram_vec(
                 "replica_http_request_body_size_bytes",
-                "HTTP/HTTPS request body sizes in bytes.",
+                "HTTP/HTTPS request body sizes in bytes, by request type and API version.",
                 // 10 B - 5 MB
                 decimal_buckets(1, 6),
                 &REQUESTS_LABEL_NAMES,
             ),
+            responses_body_size_bytes: metrics_registry.histogram_vec(
+                "replica_http_response_body_size_bytes",
+                "HTTP/HTTPS response body sizes in bytes of the endpoint services, by request type. Streamed responses of unknown size are left out.",
+                // 10 B - 50 MB
+                decimal_buckets(1, 7),
+                &[LABEL_REQUEST_TYPE],
+            ),
+            buffered_response_bytes: metrics_registry.int_gauge(
+                "replica_http_buffered_response_bytes",
+                "Total size in bytes of the response bodies held in memory until written to clients.",
+            ),
+            response_budget_rejections_total: metrics_registry.int_counter_vec(
+                "replica_http_response_budget_rejections_total",
+                "Count of responses replaced with a 503 as their body exceeded the memory available for responses, by request type.",
+                &[LABEL_REQUEST_TYPE],
+            ),
             protocol_version_total: metrics_registry.int_counter_vec(
                 "replica_http_requests_protocol_version_total",
                 "Count of received requests, by protocol (HTTP/HTTPS) and version.",
@@ -80,6 +156,153 @@ impl HttpHandlerMetrics {
                 "replica_http_tcp_connections_total",
                 "Total number of accepted TCP connections."
             ),
+            connections_draining: metrics_registry.int_gauge(
+                "replica_http_draining_tcp_connections",
+                "Number of open tcp connections waiting for their requests in flight to complete before closing."
+            ),
+            query_canister_queue_duration: metrics_registry.histogram(
+                "replica_http_query_canister_queue_duration_seconds",
+                "Time queries waited for their canister to be below its concurrent query cap.",
+                // 1ms, 2ms, 5ms, 10ms, 20ms, ..., 10s, 20s, 50s
+                decimal_buckets(-3, 1),
+            ),
+            query_canister_queued: metrics_registry.int_gauge(
+                "replica_http_query_canister_queued",
+                "Number of queries waiting for their canister to be below its concurrent query cap."
+            ),
+            ingress_queue_depth: metrics_registry.int_gauge(
+                "replica_http_ingress_queue_depth",
+                "Number of call requests waiting for or in submission to the ingress pool."
+            ),
+            ingress_filter_cache_hits_total: metrics_registry.int_counter(
+                "replica_http_ingress_filter_cache_hits_total",
+                "Count of call requests rejected with a cached verdict of the ingress filter."
+            ),
+            boot_time_seconds: metrics_registry.int_gauge(
+                "replica_http_boot_time_seconds",
+                "Time the replica started, in seconds since the UNIX epoch."
+            ),
+            call_idempotent_retries_total: metrics_registry.int_counter(
+                "replica_http_call_idempotent_retries_total",
+                "Count of call requests answered with the message id previously submitted under the same idempotency key."
+            ),
+            tls_certificate_rotations_total: metrics_registry.int_counter(
+                "replica_http_tls_certificate_rotations_total",
+                "Count of TLS certificate rotations of this node picked up without a restart."
+            ),
+            tls_registry_version: metrics_registry.int_gauge(
+                "replica_http_tls_registry_version",
+                "Registry version at which TLS handshakes are performed."
+            ),
+            connection_bytes: metrics_registry.histogram_vec(
+                "replica_http_connection_bytes",
+                "Bytes read from and written to HTTP connections over their lifetime, by direction and protocol (HTTP/HTTPS).",
+                // 10 B - 5 GB
+                decimal_buckets(1, 9),
+                &[LABEL_DIRECTION, LABEL_PROTOCOL],
+            ),
+            requests_per_connection: metrics_registry.histogram_vec(
+                "replica_http_requests_per_connection",
+                "Number of requests served over HTTP connections over their lifetime, by protocol (HTTP/HTTPS) and HTTP version.",
+                // 1, 2, 5, 10, 20, ..., 1000, 2000, 5000
+                decimal_buckets(0, 3),
+                &[LABEL_PROTOCOL, LABEL_VERSION],
+            ),
+            single_request_connections_total: metrics_registry.int_counter_vec(
+                "replica_http_single_request_connections_total",
+                "Count of HTTP connections closed after a single request, by protocol (HTTP/HTTPS) and HTTP version. A high share of all connections points to clients not reusing connections.",
+                &[LABEL_PROTOCOL, LABEL_VERSION],
+            ),
+            connection_write_throttled_total: metrics_registry.int_counter(
+                "replica_http_connection_write_throttled_total",
+                "Count of writes to a connection delayed by the per-connection bandwidth cap."
+            ),
+            panics_total: metrics_registry.int_counter_vec(
+                "replica_http_panics_total",
+                "Count of panics while handling requests, by request type. Each was answered with a 500 and logged with its incident id.",
+                &[LABEL_REQUEST_TYPE],
+            ),
+            query_canister_rejections_total: metrics_registry.int_counter(
+                "replica_http_query_canister_rejections_total",
+                "Count of queries rejected because their canister had too many queries executing and queued."
+            ),
+            slo_requests_total: metrics_registry.int_counter_vec(
+                "replica_http_slo_requests_total",
+                "Count of requests subject to a latency SLO, by request type.",
+                &[LABEL_REQUEST_TYPE],
+            ),
+            slo_slow_requests_total: metrics_registry.int_counter_vec(
+                "replica_http_slo_slow_requests_total",
+                "Count of requests slower than their latency SLO threshold (call: 1s, query and read_state: 500ms), by request type.",
+                &[LABEL_REQUEST_TYPE],
+            ),
+            body_errors_total: metrics_registry.int_counter_vec(
+                "replica_http_body_errors_total",
+                "Count of rejected request bodies, by request type and error (too_large, timeout, malformed).",
+                &[LABEL_REQUEST_TYPE, LABEL_DETAIL],
+            ),
+            header_rejections_total: metrics_registry.int_counter_vec(
+                "replica_http_header_rejections_total",
+                "Count of requests rejected for their headers, by exceeded limit (header_count, header_bytes, or http1_head for HTTP/1.1 request heads exceeding the parse buffer).",
+                &[LABEL_DETAIL],
+            ),
+            ambiguous_requests_total: metrics_registry.int_counter_vec(
+                "replica_http_ambiguous_requests_total",
+                "Count of requests rejected for their ambiguous framing, by reason (transfer_encoding_with_content_length, unsupported_transfer_encoding, conflicting_header or absolute_form_target).",
+                &[LABEL_DETAIL],
+            ),
+            deprecated_requests_total: metrics_registry.int_counter_vec(
+                "replica_http_deprecated_requests_total",
+                "Count of requests to routes announced as deprecated, by request type and API version.",
+                &[LABEL_REQUEST_TYPE, LABEL_API_VERSION],
+            ),
+            maintenance_mode: metrics_registry.int_gauge(
+                "replica_http_maintenance_mode",
+                "1 if the HTTP handler is in maintenance mode, rejecting calls, 0 otherwise.",
+            ),
+            maintenance_rejections_total: metrics_registry.int_counter(
+                "replica_http_maintenance_rejections_total",
+                "Count of calls rejected while in maintenance mode.",
+            ),
+            batch_time_regressions_total: metrics_registry.int_counter(
+                "replica_http_batch_time_regressions_total",
+                "Count of samples of the time of the latest batch that were earlier than a previous sample, and clamped to it.",
+            ),
+            clock_skew_seconds: metrics_registry.gauge(
+                "replica_http_clock_skew_seconds",
+                "Estimated skew of the time of the subnet relative to the system time, against which the expiry of requests is validated. Negative if the subnet is behind.",
+            ),
+            read_state_paths: metrics_registry.histogram(
+                "replica_http_read_state_paths",
+                "Number of paths per read_state request, with bulk request status paths expanded.",
+                // 1, 2, 5, 10, 20, ..., 1000, 2000, 5000
+                decimal_buckets(0, 3),
+            ),
+            read_state_path_rejections_total: metrics_registry.int_counter_vec(
+                "replica_http_read_state_path_rejections_total",
+                "Count of read_state requests rejected for their paths, by exceeded limit (path_count or path_bytes).",
+                &[LABEL_DETAIL],
+            ),
+            replayed_requests_total: metrics_registry.int_counter_vec(
+                "replica_http_replayed_requests_total",
+                "Count of query and read_state requests received again within seconds with the same sender, nonce and content, by request type and status (error if rejected).",
+                &[LABEL_REQUEST_TYPE, LABEL_STATUS],
+            ),
+            tls_client_hello_total: metrics_registry.int_counter_vec(
+                "replica_http_tls_client_hello_total",
+                "Count of received TLS ClientHellos, by preferred ALPN protocol (h2, http/1.1 or none).",
+                &[LABEL_PROTOCOL],
+            ),
+            connections_by_asn_total: metrics_registry.int_counter_vec(
+                "replica_http_tcp_connections_by_asn_total",
+                "Total number of accepted TCP connections, by autonomous system of the peer (proxy for trusted proxies, other beyond the 256 most frequent ones of the previous hour).",
+                &[LABEL_ASN],
+            ),
+            connections_by_country_total: metrics_registry.int_counter_vec(
+                "replica_http_tcp_connections_by_country_total",
+                "Total number of accepted TCP connections, by country of the peer (proxy for trusted proxies).",
+                &[LABEL_COUNTRY],
+            ),
             connection_setup_duration: metrics_registry.histogram_vec(
                 "replica_http_connection_setup_duration_seconds",
                 "HTTP connection setup durations, by status and detail (protocol on status=\"success\", error type on status=\"error\").",
@@ -92,14 +315,144 @@ impl HttpHandlerMetrics {
                 decimal_buckets(-3, 3),
                 &[LABEL_STATUS, LABEL_PROTOCOL],
             ),
+            connection_drain_duration: metrics_registry.histogram_vec(
+                "replica_http_connection_drain_duration_seconds",
+                "HTTP connection drain durations, by reason (max_lifetime or shutdown) and status (error if requests in flight did not complete within the grace period).",
+                // 1ms, 2ms, 5ms, 10ms, 20ms, ..., 10s, 20s, 50s
+                decimal_buckets(-3, 1),
+                &[LABEL_DETAIL, LABEL_STATUS],
+            ),
+        }
+    }
+
+    /// Counts a request against its latency SLO, if its request type has one.
+    /// The ratio of `replica_http_slo_slow_requests_total` to
+    /// `replica_http_slo_requests_total` is the SLO error rate, which burn-rate
+    /// alerts can be defined on directly.
+    pub(crate) fn observe_request_slo(
+        &self,
+        request_timer: &HistogramVecTimer<'static, REQUESTS_NUM_LABELS>,
+    ) {
+        let request_type = match REQUESTS_LABEL_NAMES
+            .iter()
+            .zip(request_timer.label_values())
+            .find(|(name, _)| **name == LABEL_REQUEST_TYPE)
+        {
+            Some((_, request_type)) => *request_type,
+            None => return,
+        };
+        let threshold = match SLO_LATENCY_THRESHOLDS
+            .iter()
+            .find(|(api_req_type, _)| <&str>::from(*api_req_type) == request_type)
+        {
+            Some((_, threshold)) => *threshold,
+            None => return,
+        };
+        self.slo_requests_total
+            .with_label_values(&[request_type])
+            .inc();
+        if request_timer.elapsed() > threshold {
+            self.slo_slow_requests_total
+                .with_label_values(&[request_type])
+                .inc();
+        }
+    }
+
+    /// Counts a rejected request body, by request type and error.
+    pub(crate) fn observe_body_error(&self, api_req_type: ApiReqType, error: &BodyError) {
+        self.body_errors_total
+            .with_label_values(&[api_req_type.into(), error.into()])
+            .inc();
+    }
+
+    /// Counts a request rejected for its headers, by exceeded limit.
+    pub(crate) fn observe_header_rejection(&self, limit: &'static str) {
+        self.header_rejections_total
+            .with_label_values(&[limit])
+            .inc();
+    }
+
+    /// Counts a request rejected for its ambiguous framing.
+    pub(crate) fn observe_ambiguous_request(&self, reason: &AmbiguousFraming) {
+        self.ambiguous_requests_total
+            .with_label_values(&[reason.into()])
+            .inc();
+    }
+
+    /// Records the estimated skew of the subnet time.
+    pub(crate) fn observe_clock_skew(&self, skew: Skew) {
+        self.clock_skew_seconds.set(match skew {
+            Skew::Ahead(skew) => skew.as_secs_f64(),
+            Skew::Behind(skew) => -skew.as_secs_f64(),
+        });
+    }
+
+    /// Records the number of paths of a read_state request.
+    pub(crate) fn observe_read_state_paths(&self, paths: usize) {
+        self.read_state_paths.observe(paths as f64);
+    }
+
+    pub(crate) fn observe_read_state_path_rejection(&self, limit: &'static str) {
+        self.read_state_path_rejections_total
+            .with_label_values(&[limit])
+            .inc();
+    }
+
+    /// Records the body size of a response to a request of `api_req_type`, if
+    /// known before it is sent, i.e. from its size hint or, for streamed
+    /// bodies, its `Content-Length`.
+    pub(crate) fn observe_response_body_size(
+        &self,
+        api_req_type: ApiReqType,
+        response: &Response<Body>,
+    ) {
+        if let Some(size) = body_size(response) {
+            self.responses_body_size_bytes
+                .with_label_values(&[api_req_type.into()])
+                .observe(size as f64);
+        }
+    }
+
+    /// Counts a replayed request, by whether it was rejected.
+    pub(crate) fn observe_replayed_request(&self, api_req_type: ApiReqType, rejected: bool) {
+        let status = if rejected {
+            STATUS_ERROR
+        } else {
+            STATUS_SUCCESS
+        };
+        self.replayed_requests_total
+            .with_label_values(&[api_req_type.into(), status])
+            .inc();
+    }
+
+    /// Counts a received TLS ClientHello, by the ALPN protocol preferred by the
+    /// client.
+    pub(crate) fn observe_client_hello(&self, client_hello: &ClientHello) {
+        self.tls_client_hello_total
+            .with_label_values(&[client_hello.preferred_protocol()])
+            .inc();
+    }
+
+    /// Counts an accepted connection by the origins of its peer that are
+    /// known.
+    pub(crate) fn observe_client_origin(&self, origin: &ClientOrigin) {
+        if let Some(asn) = &origin.asn {
+            self.connections_by_asn_total
+                .with_label_values(&[asn])
+                .inc();
+        }
+        if let Some(country) = &origin.country {
+            self.connections_by_country_total
+                .with_label_values(&[country])
+                .inc();
         }
     }
 
     /// Records the duration of a failed connection setup, by error.
-    pub(crate) fn observe_connection_error(&self, error: ConnectionError, start_time: Instant) {
+    pub(crate) fn observe_connection_error(&self, error: ConnectionError, stopwatch: &Stopwatch) {
         self.connection_setup_duration
             .with_label_values(&[STATUS_ERROR, error.into()])
-            .observe(start_time.elapsed().as_secs_f64());
+            .observe(stopwatch.elapsed().as_secs_f64());
     }
 
     /// Records the duration of a successful connection setup, by app layer
@@ -107,26 +460,177 @@ impl HttpHandlerMetrics {
     pub(crate) fn observe_successful_connection_setup(
         &self,
         app_layer: AppLayer,
-        start_time: Instant,
+        stopwatch: &Stopwatch,
     ) {
         self.connection_setup_duration
             .with_label_values(&[STATUS_SUCCESS, app_layer.into()])
-            .observe(start_time.elapsed().as_secs_f64());
+            .observe(stopwatch.elapsed().as_secs_f64());
+    }
+
+    /// Records the bytes read from and written to a closed connection.
+    pub(crate) fn observe_connection_bytes(
+        &self,
+        app_layer: AppLayer,
+        bytes_read: u64,
+        bytes_written: u64,
+    ) {
+        self.connection_bytes
+            .with_label_values(&[DIRECTION_READ, app_layer.into()])
+            .observe(bytes_read as f64);
+        self.connection_bytes
+            .with_label_values(&[DIRECTION_WRITTEN, app_layer.into()])
+            .observe(bytes_written as f64);
+    }
+
+    /// Records the number of requests served over a closed connection.
+    pub(crate) fn observe_connection_requests(
+        &self,
+        app_layer: AppLayer,
+        version: Version,
+        requests: u64,
+    ) {
+        let version = format!("{:?}", version);
+        let labels = [app_layer.into(), version.as_str()];
+        self.requests_per_connection
+            .with_label_values(&labels)
+            .observe(requests as f64);
+        if requests == 1 {
+            self.single_request_connections_total
+                .with_label_values(&labels)
+                .inc();
+        }
+    }
+
+    /// Records the duration of a connection drain, by reason and whether the
+    /// requests in flight completed.
+    pub(crate) fn observe_connection_drain(
+        &self,
+        reason: DrainReason,
+        completed: bool,
+        duration: Duration,
+    ) {
+        let status = if completed {
+            STATUS_SUCCESS
+        } else {
+            STATUS_ERROR
+        };
+        self.connection_drain_duration
+            .with_label_values(&[reason.into(), status])
+            .observe(duration.as_secs_f64());
     }
 
     pub(crate) fn observe_graceful_conn_termination(
         &self,
         app_layer: AppLayer,
-        start_time: Instant,
+        stopwatch: &Stopwatch,
     ) {
         self.connection_duration
             .with_label_values(&[STATUS_SUCCESS, app_layer.into()])
-            .observe(start_time.elapsed().as_secs_f64());
+            .observe(stopwatch.elapsed().as_secs_f64());
     }
 
-    pub(crate) fn observe_abrupt_conn_termination(&self, app_layer: AppLayer, start_time: Instant) {
+    pub(crate) fn observe_abrupt_conn_termination(
+        &self,
+        app_layer: AppLayer,
+        stopwatch: &Stopwatch,
+    ) {
         self.connection_duration
             .with_label_values(&[STATUS_ERROR, app_layer.into()])
-            .observe(start_time.elapsed().as_secs_f64());
+            .observe(stopwatch.elapsed().as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UNKNOWN_LABEL;
+    use ic_types::time::ManualClock;
+    use std::sync::Arc;
+
+    fn start_timer(
+        metrics: &HttpHandlerMetrics,
+        request_type: &'static str,
+    ) -> HistogramVecTimer<'static, REQUESTS_NUM_LABELS> {
+        HistogramVecTimer::start_timer(
+            metrics.requests.clone(),
+            &REQUESTS_LABEL_NAMES,
+            [UNKNOWN_LABEL, request_type, UNKNOWN_LABEL, UNKNOWN_LABEL],
+        )
+    }
+
+    #[test]
+    fn request_slo_is_only_observed_for_request_types_with_a_threshold() {
+        let metrics = HttpHandlerMetrics::new(&MetricsRegistry::new());
+
+        metrics.observe_request_slo(&start_timer(&metrics, ApiReqType::Query.into()));
+        metrics.observe_request_slo(&start_timer(&metrics, ApiReqType::Status.into()));
+
+        assert_eq!(
+            metrics
+                .slo_requests_total
+                .with_label_values(&[ApiReqType::Query.into()])
+                .get(),
+            1
+        );
+        assert_eq!(
+            metrics
+                .slo_slow_requests_total
+                .with_label_values(&[ApiReqType::Query.into()])
+                .get(),
+            0
+        );
+        assert_eq!(
+            metrics
+                .slo_requests_total
+                .with_label_values(&[ApiReqType::Status.into()])
+                .get(),
+            0
+        );
+    }
+
+    #[test]
+    fn connection_setup_duration_is_measured_on_the_given_clock() {
+        let metrics = HttpHandlerMetrics::new(&MetricsRegistry::new());
+        let clock = Arc::new(ManualClock::new());
+        let stopwatch = Stopwatch::start(clock.clone());
+
+        clock.advance(Duration::from_millis(250));
+        metrics.observe_successful_connection_setup(AppLayer::Https, &stopwatch);
+
+        let histogram = metrics
+            .connection_setup_duration
+            .with_label_values(&[STATUS_SUCCESS, AppLayer::Https.into()]);
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert_eq!(histogram.get_sample_sum(), 0.25);
+    }
+
+    #[test]
+    fn response_body_sizes_are_recorded_if_known() {
+        let metrics = HttpHandlerMetrics::new(&MetricsRegistry::new());
+        let histogram = metrics
+            .responses_body_size_bytes
+            .with_label_values(&[ApiReqType::ReadState.into()]);
+
+        metrics.observe_response_body_size(
+            ApiReqType::ReadState,
+            &Response::new(Body::from(vec![0; 300])),
+        );
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert_eq!(histogram.get_sample_sum(), 300.0);
+
+        let (_sender, body) = Body::channel();
+        metrics.observe_response_body_size(ApiReqType::ReadState, &Response::new(body));
+        assert_eq!(histogram.get_sample_count(), 1);
+
+        // Streamed bodies are recorded by their `Content-Length`.
+        let (_sender, body) = Body::channel();
+        let mut response = Response::new(body);
+        response.headers_mut().insert(
+            hyper::header::CONTENT_LENGTH,
+            hyper::header::HeaderValue::from(200u64),
+        );
+        metrics.observe_response_body_size(ApiReqType::ReadState, &response);
+        assert_eq!(histogram.get_sample_count(), 2);
+        assert_eq!(histogram.get_sample_sum(), 500.0);
     }
 }
@@ -0,0 +1,285 @@
+//! Module that sends HTTP requests to other nodes, e.g. to fetch the
+//! delegation of a subnet from the NNS subnet.
+//!
+//! Connections are attempted to the addresses of several nodes following
+//! "happy eyeballs" (RFC 8305): IPv6 and IPv4 addresses are interleaved, and
+//! a new attempt is started every `ATTEMPT_DELAY` or as soon as the previous
+//! one fails, without cancelling the attempts in progress. The first
+//! established connection is used. This keeps fetching reliable on IPv6-only
+//! nodes and when some nodes or address families are unreachable.
+use futures::stream::{FuturesUnordered, StreamExt};
+use hyper::{client::conn, header, Body, Method, Request, Response};
+use ic_crypto_tls_interfaces::TlsHandshake;
+use ic_replicated_state::NodeTopology;
+use ic_types::{NodeId, RegistryVersion};
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{lookup_host, TcpStream},
+    time::{sleep, timeout},
+};
+
+/// The delay after which the next connection attempt is started, if the
+/// previous ones are still pending. Recommended by RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The maximum duration of a single connection attempt, including the TLS
+/// handshake if any.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum duration of a request, once connected.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub(crate) enum OutboundError {
+    /// None of the addresses of the nodes accepted a connection.
+    Connect(io::Error),
+    /// The TLS handshake with the node failed.
+    Tls(String),
+    /// Sending the request or receiving the response failed.
+    Http(hyper::Error),
+    /// The node did not respond within `REQUEST_TIMEOUT`.
+    Timeout,
+}
+
+impl fmt::Display for OutboundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connect(err) => write!(f, "failed to connect: {}", err),
+            Self::Tls(err) => write!(f, "TLS handshake failed: {}", err),
+            Self::Http(err) => write!(f, "request failed: {}", err),
+            Self::Timeout => write!(f, "request timed out"),
+        }
+    }
+}
+
+/// A client sending requests to the first of a set of nodes accepting a
+/// connection, optionally over TLS authenticated against the registry.
+/// Connects over plain HTTP by default.
+#[derive(Clone, Default)]
+pub(crate) struct OutboundClient {
+    tls_handshake: Option<Arc<dyn TlsHandshake + Send + Sync>>,
+}
+
+impl OutboundClient {
+    /// A client connecting over TLS, authenticating nodes against their
+    /// certificate in the registry.
+    pub(crate) fn with_tls(tls_handshake: Arc<dyn TlsHandshake + Send + Sync>) -> Self {
+        Self {
+            tls_handshake: Some(tls_handshake),
+        }
+    }
+
+    /// Posts `body` to `path` on the first of `nodes` to accept a connection.
+    /// Returns the address of the node that responded along with its
+    /// response.
+    pub(crate) async fn post(
+        &self,
+        nodes: &[(NodeId, NodeTopology)],
+        path: &str,
+        content_type: &'static str,
+        body: Vec<u8>,
+        registry_version: RegistryVersion,
+    ) -> Result<(SocketAddr, Response<Body>), OutboundError> {
+        let candidates = interleave_families(resolve(nodes).await);
+        let (node_id, addr, tcp_stream) = connect(candidates, ATTEMPT_DELAY, CONNECT_TIMEOUT)
+            .await
+            .map_err(OutboundError::Connect)?;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header(header::HOST, addr.to_string())
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .expect("The request is well-formed.");
+        let response = match &self.tls_handshake {
+            None => send(tcp_stream, request).await,
+            Some(tls_handshake) => {
+                let tls_stream = timeout(
+                    CONNECT_TIMEOUT,
+                    tls_handshake.perform_tls_client_handshake(
+                        tcp_stream,
+                        node_id,
+                        registry_version,
+                    ),
+                )
+                .await
+                .map_err(|_| OutboundError::Tls("timed out".to_string()))?
+                .map_err(|err| OutboundError::Tls(err.to_string()))?;
+                send(tls_stream, request).await
+            }
+        }?;
+        Ok((addr, response))
+    }
+}
+
+// Sends `request` over a new HTTP/1.1 connection on `stream`.
+async fn send<S>(stream: S, request: Request<Body>) -> Result<Response<Body>, OutboundError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let exchange = async move {
+        let (mut sender, connection) = conn::handshake(stream).await?;
+        // The connection is driven until the response body has been read
+        // or dropped.
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+        sender.send_request(request).await
+    };
+    timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| OutboundError::Timeout)?
+        .map_err(OutboundError::Http)
+}
+
+// Returns the socket addresses of `nodes`, resolving host names, if any.
+async fn resolve(nodes: &[(NodeId, NodeTopology)]) -> Vec<(NodeId, SocketAddr)> {
+    let mut addrs = vec![];
+    for (node_id, node) in nodes {
+        match node.ip_address.parse::<IpAddr>() {
+            Ok(ip_addr) => addrs.push((*node_id, SocketAddr::new(ip_addr, node.http_port))),
+            Err(_) => {
+                if let Ok(resolved) = lookup_host((node.ip_address.as_str(), node.http_port)).await
+                {
+                    addrs.extend(resolved.map(|addr| (*node_id, addr)));
+                }
+            }
+        }
+    }
+    addrs
+}
+
+// Orders `addrs` alternating between IPv6 and IPv4 addresses, starting with
+// IPv6, and otherwise preserving their order.
+fn interleave_families(addrs: Vec<(NodeId, SocketAddr)>) -> Vec<(NodeId, SocketAddr)> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|(_, addr)| addr.is_ipv6());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut interleaved = vec![];
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+// Connects to the first of `candidates` to accept a connection, starting a
+// new attempt every `attempt_delay` or as soon as an attempt fails.
+async fn connect(
+    candidates: Vec<(NodeId, SocketAddr)>,
+    attempt_delay: Duration,
+    connect_timeout: Duration,
+) -> io::Result<(NodeId, SocketAddr, TcpStream)> {
+    let attempt = |(node_id, addr): (NodeId, SocketAddr)| async move {
+        match timeout(connect_timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(tcp_stream)) => Ok((node_id, addr, tcp_stream)),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("connecting to {} timed out", addr),
+            )),
+        }
+    };
+    let mut pending = candidates.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(candidate) => attempts.push(attempt(candidate)),
+                None => {
+                    return Err(last_error.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+                    }))
+                }
+            }
+        }
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(connected) => return Ok(connected),
+                Err(err) => {
+                    last_error = Some(err);
+                    if let Some(candidate) = pending.next() {
+                        attempts.push(attempt(candidate));
+                    }
+                }
+            },
+            _ = sleep(attempt_delay), if !pending.as_slice().is_empty() => {
+                if let Some(candidate) = pending.next() {
+                    attempts.push(attempt(candidate));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_test_utilities::types::ids::node_test_id;
+    use tokio::net::TcpListener;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn address_families_are_interleaved_starting_with_ipv6() {
+        let addrs = vec![
+            (node_test_id(1), addr("10.0.0.1:8080")),
+            (node_test_id(2), addr("10.0.0.2:8080")),
+            (node_test_id(3), addr("10.0.0.3:8080")),
+            (node_test_id(4), addr("[2001:db8::1]:8080")),
+        ];
+        let interleaved: Vec<_> = interleave_families(addrs)
+            .into_iter()
+            .map(|(node_id, _)| node_id)
+            .collect();
+        assert_eq!(
+            interleaved,
+            vec![
+                node_test_id(4),
+                node_test_id(1),
+                node_test_id(2),
+                node_test_id(3)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn connects_to_the_next_candidate_when_an_attempt_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = listener.local_addr().unwrap();
+        // Nothing listens on the address of a dropped listener.
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let (node_id, connected, _) = connect(
+            vec![(node_test_id(1), unreachable), (node_test_id(2), reachable)],
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+        assert_eq!((node_id, connected), (node_test_id(2), reachable));
+
+        let err = connect(
+            vec![(node_test_id(1), unreachable)],
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
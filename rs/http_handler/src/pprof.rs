@@ -1,10 +1,19 @@
-use crate::common::{
-    get_cors_headers, make_plaintext_response, CONTENT_TYPE_HTML, CONTENT_TYPE_PROTOBUF,
+//! Module that serves CPU profiles under `/_/pprof`.
+//!
+//! Profiling is CPU-expensive, so access is restricted: if a token is
+//! configured, requests must carry it as a bearer token in the
+//! `Authorization` header; otherwise only clients on the loopback interface
+//! are served. At most one profile is collected at a time, for a bounded
+//! duration and frequency.
+use crate::{
+    client_addr::ClientAddr,
+    common::{get_cors_headers, make_plaintext_response, CONTENT_TYPE_HTML, CONTENT_TYPE_PROTOBUF},
 };
 use http::{header, request::Parts};
 use hyper::{self, Body, Response, StatusCode};
 use ic_pprof::{flamegraph, profile, Error};
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
 
 pub const CONTENT_TYPE_SVG: &str = "image/svg+xml";
 /// Default CPU profile duration.
@@ -12,6 +21,69 @@ pub const DEFAULT_DURATION_SECONDS: u64 = 30;
 /// Default sampling frequency. 250Hz is the default Linux software clock
 /// frequency.
 pub const DEFAULT_FREQUENCY: i32 = 250;
+/// Maximum CPU profile duration.
+pub const MAX_DURATION_SECONDS: u64 = 60;
+/// Maximum sampling frequency.
+pub const MAX_FREQUENCY: i32 = 1000;
+
+/// Restricts access to the profiling endpoints. Clones share the limit on
+/// concurrent profiles.
+#[derive(Clone)]
+pub(crate) struct PprofAccess {
+    token: Option<Arc<str>>,
+    profiles: Arc<Semaphore>,
+}
+
+impl PprofAccess {
+    /// Restricts access to requests carrying `token` if set, or else to
+    /// loopback clients.
+    pub(crate) fn new(token: Option<String>) -> Self {
+        Self {
+            token: token.map(Arc::from),
+            profiles: Arc::new(Semaphore::new(1)),
+        }
+    }
+
+    /// Returns the response to send instead of serving the request, if the
+    /// client may not access the profiling endpoints.
+    fn authorize(&self, parts: &Parts) -> Result<(), Response<Body>> {
+        match &self.token {
+            Some(token) => {
+                let bearer = parts
+                    .headers
+                    .get(header::AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "));
+                match bearer {
+                    Some(bearer) if constant_time_eq(bearer.as_bytes(), token.as_bytes()) => Ok(()),
+                    _ => {
+                        let mut response = make_plaintext_response(
+                            StatusCode::UNAUTHORIZED,
+                            "A valid bearer token is required.".to_string(),
+                        );
+                        response.headers_mut().insert(
+                            header::WWW_AUTHENTICATE,
+                            header::HeaderValue::from_static("Bearer"),
+                        );
+                        Err(response)
+                    }
+                }
+            }
+            None => match parts.extensions.get::<ClientAddr>() {
+                Some(ClientAddr(ip)) if ip.is_loopback() => Ok(()),
+                _ => Err(make_plaintext_response(
+                    StatusCode::FORBIDDEN,
+                    "Profiles are only served to local clients.".to_string(),
+                )),
+            },
+        }
+    }
+}
+
+// Compares `a` and `b` in time depending on their length only.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
 
 /// `/_/pprof` root page, listing the available profiles.
 const PPROF_HOME_HTML: &str = r#"<html>
@@ -37,7 +109,10 @@ Types of profiles available:
 </html>"#;
 
 /// Returns the `/_/pprof` root page, listing the available profiles.
-pub(crate) fn home() -> Response<Body> {
+pub(crate) fn home(access: &PprofAccess, parts: Parts) -> Response<Body> {
+    if let Err(response) = access.authorize(&parts) {
+        return response;
+    }
     let mut response = Response::new(Body::from(PPROF_HOME_HTML));
     *response.status_mut() = StatusCode::OK;
     *response.headers_mut() = get_cors_headers();
@@ -57,7 +132,14 @@ pub(crate) fn home() -> Response<Body> {
 /// `frequency` and its accuracy are limited (on Linux) by the resolution of
 /// the software clock, which is 250Hz by default. See
 /// [`man 7 time`](https://linux.die.net/man/7/time) for details.
-pub(crate) async fn cpu_profile(parts: Parts) -> Response<Body> {
+pub(crate) async fn cpu_profile(access: &PprofAccess, parts: Parts) -> Response<Body> {
+    if let Err(response) = access.authorize(&parts) {
+        return response;
+    }
+    let _profile = match access.profiles.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => return profile_in_progress_response(),
+    };
     match query(parts) {
         Ok((duration, frequency)) => {
             into_response(profile(duration, frequency).await, CONTENT_TYPE_PROTOBUF)
@@ -66,7 +148,14 @@ pub(crate) async fn cpu_profile(parts: Parts) -> Response<Body> {
     }
 }
 
-pub(crate) async fn cpu_flamegraph(parts: Parts) -> Response<Body> {
+pub(crate) async fn cpu_flamegraph(access: &PprofAccess, parts: Parts) -> Response<Body> {
+    if let Err(response) = access.authorize(&parts) {
+        return response;
+    }
+    let _profile = match access.profiles.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => return profile_in_progress_response(),
+    };
     match query(parts) {
         Ok((duration, frequency)) => {
             into_response(flamegraph(duration, frequency).await, CONTENT_TYPE_SVG)
@@ -75,8 +164,19 @@ pub(crate) async fn cpu_flamegraph(parts: Parts) -> Response<Body> {
     }
 }
 
+fn profile_in_progress_response() -> Response<Body> {
+    make_plaintext_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        "A profile is already being collected, try again later.".to_string(),
+    )
+}
+
 fn query(parts: Parts) -> Result<(Duration, i32), String> {
-    let query_pairs: HashMap<_, _> = match parts.uri.query() {
+    query_params(parts.uri.query())
+}
+
+fn query_params(query: Option<&str>) -> Result<(Duration, i32), String> {
+    let query_pairs: HashMap<_, _> = match query {
         Some(query) => url::form_urlencoded::parse(query.as_bytes()).collect(),
         None => Default::default(),
     };
@@ -90,6 +190,12 @@ fn query(parts: Parts) -> Result<(Duration, i32), String> {
         },
         None => DEFAULT_DURATION_SECONDS,
     };
+    if seconds > MAX_DURATION_SECONDS {
+        return Err(format!(
+            "Profiles may last at most {} seconds.",
+            MAX_DURATION_SECONDS
+        ));
+    }
     let duration = Duration::from_secs(seconds);
 
     let frequency: i32 = match query_pairs.get("frequency") {
@@ -101,6 +207,12 @@ fn query(parts: Parts) -> Result<(Duration, i32), String> {
         },
         None => DEFAULT_FREQUENCY,
     };
+    if !(1..=MAX_FREQUENCY).contains(&frequency) {
+        return Err(format!(
+            "The sampling frequency must be between 1 and {} Hz.",
+            MAX_FREQUENCY
+        ));
+    }
     Ok((duration, frequency))
 }
 
@@ -125,3 +237,63 @@ fn ok_response(body: Vec<u8>, content_type: &'static str) -> Response<Body> {
     );
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Request;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn parts(client: IpAddr, authorization: Option<&'static str>) -> Parts {
+        let mut request = Request::get("/_/pprof/profile");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let mut request = request.body(()).unwrap();
+        request.extensions_mut().insert(ClientAddr(client));
+        request.into_parts().0
+    }
+
+    #[test]
+    fn only_local_clients_are_served_without_a_token() {
+        let access = PprofAccess::new(None);
+        let remote = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert!(access
+            .authorize(&parts(IpAddr::V4(Ipv4Addr::LOCALHOST), None))
+            .is_ok());
+        assert_eq!(
+            access.authorize(&parts(remote, None)).unwrap_err().status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[test]
+    fn a_valid_bearer_token_is_required_if_configured() {
+        let access = PprofAccess::new(Some("secret".to_string()));
+        let remote = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert!(access
+            .authorize(&parts(remote, Some("Bearer secret")))
+            .is_ok());
+        for authorization in [None, Some("Bearer secreT"), Some("secret")] {
+            let response = access
+                .authorize(&parts(IpAddr::V4(Ipv4Addr::LOCALHOST), authorization))
+                .unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[test]
+    fn duration_and_frequency_are_capped() {
+        assert_eq!(
+            query_params(None),
+            Ok((
+                Duration::from_secs(DEFAULT_DURATION_SECONDS),
+                DEFAULT_FREQUENCY
+            ))
+        );
+        assert!(query_params(Some("seconds=60&frequency=1000")).is_ok());
+        assert!(query_params(Some("seconds=61")).is_err());
+        assert!(query_params(Some("frequency=1001")).is_err());
+        assert!(query_params(Some("frequency=0")).is_err());
+    }
+}
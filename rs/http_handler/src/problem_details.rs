@@ -0,0 +1,346 @@
+//! Module that converts plaintext error responses into problem details.
+//!
+//! Error responses are plaintext by default. Clients that accept
+//! `application/cbor` receive a CBOR map with a stable `code` to branch on, the
+//! human readable `message`, optional `details` and whether the request may be
+//! `retryable` as is, instead.
+//!
+//! The code is derived from the status of the response, unless the code that
+//! produced the error attached its [`ErrorCause`] to the response.
+use crate::{limits::ReadStatePathLimitExceeded, CONTENT_TYPE_CBOR};
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderMap, HeaderValue},
+    Body, Response, StatusCode,
+};
+use ic_error_types::{ErrorCode, RejectCode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Plaintext error messages are truncated to this size, as they are buffered
+/// to be converted.
+const MAX_ERROR_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// The body of an error response, for clients accepting CBOR.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct ProblemDetails {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub details: BTreeMap<&'static str, String>,
+    pub retryable: bool,
+}
+
+impl ProblemDetails {
+    fn new(
+        status: StatusCode,
+        message: String,
+        headers: &HeaderMap,
+        cause: Option<&ErrorCause>,
+    ) -> Self {
+        let mut details = BTreeMap::new();
+        details.insert("status", status.as_u16().to_string());
+        if let Some(retry_after) = headers
+            .get(header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+        {
+            details.insert("retry_after", retry_after.to_string());
+        }
+        match cause {
+            Some(cause) => {
+                match cause {
+                    ErrorCause::User(code) => {
+                        details.insert("user_error_code", (*code as u64).to_string());
+                    }
+                    ErrorCause::ReadStatePathLimit(limit) => {
+                        details.insert("limit", <&str>::from(*limit).to_string());
+                    }
+                    ErrorCause::Overloaded => {}
+                }
+                Self {
+                    code: cause.code(),
+                    message,
+                    details,
+                    retryable: cause.is_retryable(),
+                }
+            }
+            None => Self {
+                code: error_code(status),
+                message,
+                details,
+                retryable: is_retryable(status),
+            },
+        }
+    }
+}
+
+/// The cause of an error response, attached to it as an extension where the
+/// error is produced, for errors that the status alone doesn't describe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ErrorCause {
+    /// The request was rejected with a user error, e.g. by the ingress filter
+    /// or the canister.
+    User(ErrorCode),
+    /// The request was shed because the replica is overloaded.
+    Overloaded,
+    /// The paths of a `read_state` request exceed the given limit.
+    ReadStatePathLimit(ReadStatePathLimitExceeded),
+}
+
+impl ErrorCause {
+    fn code(&self) -> &'static str {
+        match self {
+            ErrorCause::User(_) => "user_error",
+            ErrorCause::Overloaded => "overloaded",
+            ErrorCause::ReadStatePathLimit(_) => "read_state_paths_too_large",
+        }
+    }
+
+    // Unlike the status, which is the same for a full output queue and a
+    // canister without cycles, the reject code of a user error tells whether
+    // the same request may succeed later.
+    fn is_retryable(&self) -> bool {
+        match self {
+            ErrorCause::User(code) => RejectCode::from(*code) == RejectCode::SysTransient,
+            ErrorCause::Overloaded => true,
+            ErrorCause::ReadStatePathLimit(_) => false,
+        }
+    }
+}
+
+/// Attaches `cause` to the error `response`, for its problem details.
+pub(crate) fn with_error_cause(mut response: Response<Body>, cause: ErrorCause) -> Response<Body> {
+    response.extensions_mut().insert(cause);
+    response
+}
+
+/// Returns the stable error code for responses with the given status.
+fn error_code(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "bad_request",
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => "unauthorized",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::METHOD_NOT_ALLOWED => "method_not_allowed",
+        StatusCode::REQUEST_TIMEOUT => "request_timeout",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+        StatusCode::TOO_MANY_REQUESTS => "too_many_requests",
+        StatusCode::SERVICE_UNAVAILABLE => "service_unavailable",
+        StatusCode::GATEWAY_TIMEOUT => "timeout",
+        status if status.is_client_error() => "client_error",
+        _ => "internal_error",
+    }
+}
+
+/// Returns true if the same request may succeed later.
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Returns true if the client lists `application/cbor` in its `Accept` header.
+pub(crate) fn accepts_cbor(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_range| {
+            media_range.split(';').next().map_or(false, |media_type| {
+                media_type.trim().eq_ignore_ascii_case(CONTENT_TYPE_CBOR)
+            })
+        })
+}
+
+// Error responses without a content type are plaintext.
+fn is_plaintext_error(response: &Response<Body>) -> bool {
+    (response.status().is_client_error() || response.status().is_server_error())
+        && response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map_or(true, |value| value.as_bytes().starts_with(b"text/plain"))
+}
+
+/// Converts a plaintext error response into one with a CBOR problem details
+/// body. Other responses are returned as they are.
+pub(crate) async fn into_problem_details(response: Response<Body>) -> Response<Body> {
+    if !is_plaintext_error(&response) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let message = read_error_message(body).await;
+    let problem = ProblemDetails::new(
+        parts.status,
+        message,
+        &parts.headers,
+        parts.extensions.get::<ErrorCause>(),
+    );
+    let body = serde_cbor::to_vec(&problem).expect("Problem details are serializable.");
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(CONTENT_TYPE_CBOR),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+// Reads a plaintext error message, up to `MAX_ERROR_MESSAGE_BYTES`.
+async fn read_error_message(mut body: Body) -> String {
+    let mut message = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => {
+                let remaining = MAX_ERROR_MESSAGE_BYTES - message.len();
+                message.extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+                if message.len() == MAX_ERROR_MESSAGE_BYTES {
+                    break;
+                }
+            }
+            Err(err) => return err.to_string(),
+        }
+    }
+    String::from_utf8_lossy(&message).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{make_plaintext_response, make_response, map_box_error_to_response};
+    use ic_error_types::UserError;
+    use serde_cbor::Value;
+    use tower::load_shed::error::Overloaded;
+
+    #[test]
+    fn detects_cbor_in_accept_header() {
+        let accepts = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+            accepts_cbor(&headers)
+        };
+        assert!(accepts("application/cbor"));
+        assert!(accepts("text/plain, Application/CBOR;q=0.9"));
+        assert!(!accepts("text/plain"));
+        assert!(!accepts("application/cbor-seq"));
+        assert!(!accepts_cbor(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn converts_plaintext_errors() {
+        let mut response = make_plaintext_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many queries.".to_string(),
+        );
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+        let response = into_problem_details(response).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            CONTENT_TYPE_CBOR
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let value: Value = serde_cbor::from_slice(&body).unwrap();
+        let text = |s: &str| Value::Text(s.to_string());
+        let mut details = BTreeMap::new();
+        details.insert(text("retry_after"), text("1"));
+        details.insert(text("status"), text("429"));
+        let mut expected = BTreeMap::new();
+        expected.insert(text("code"), text("too_many_requests"));
+        expected.insert(text("message"), text("Too many queries."));
+        expected.insert(text("details"), Value::Map(details));
+        expected.insert(text("retryable"), Value::Bool(true));
+        assert_eq!(value, Value::Map(expected));
+    }
+
+    // Returns the code, the details and the retryable flag of the problem
+    // details of `response`.
+    async fn problem(response: Response<Body>) -> (String, BTreeMap<String, String>, bool) {
+        let response = into_problem_details(response).await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let value: Value = serde_cbor::from_slice(&body).unwrap();
+        let mut map = match value {
+            Value::Map(map) => map,
+            _ => panic!("Expected a map, got {:?}", value),
+        };
+        let text = |value: Option<Value>| match value {
+            Some(Value::Text(text)) => text,
+            value => panic!("Expected a text, got {:?}", value),
+        };
+        let code = text(map.remove(&Value::Text("code".to_string())));
+        let details = match map.remove(&Value::Text("details".to_string())) {
+            Some(Value::Map(details)) => details
+                .into_iter()
+                .map(|(key, value)| (text(Some(key)), text(Some(value))))
+                .collect(),
+            value => panic!("Expected details, got {:?}", value),
+        };
+        let retryable = map[&Value::Text("retryable".to_string())] == Value::Bool(true);
+        (code, details, retryable)
+    }
+
+    #[tokio::test]
+    async fn codes_user_errors_by_their_cause() {
+        // Both are 503s, but only the full queue empties.
+        let (code, details, retryable) = problem(make_response(UserError::new(
+            ErrorCode::CanisterOutOfCycles,
+            "Out of cycles.",
+        )))
+        .await;
+        assert_eq!(code, "user_error");
+        assert_eq!(details["status"], "503");
+        assert_eq!(details["user_error_code"], "501");
+        assert!(!retryable);
+
+        let (code, details, retryable) = problem(make_response(UserError::new(
+            ErrorCode::CanisterOutputQueueFull,
+            "Queue full.",
+        )))
+        .await;
+        assert_eq!(code, "user_error");
+        assert_eq!(details["user_error_code"], "201");
+        assert!(retryable);
+    }
+
+    #[tokio::test]
+    async fn codes_shed_requests_as_overloaded() {
+        let (code, details, retryable) =
+            problem(map_box_error_to_response(Box::new(Overloaded::new()))).await;
+        assert_eq!(code, "overloaded");
+        assert_eq!(details["status"], "503");
+        assert!(retryable);
+
+        let (code, _, retryable) = problem(map_box_error_to_response("unexpected".into())).await;
+        assert_eq!(code, "internal_error");
+        assert!(!retryable);
+    }
+
+    #[tokio::test]
+    async fn truncates_long_messages() {
+        let message = "a".repeat(MAX_ERROR_MESSAGE_BYTES + 1);
+        let response = make_plaintext_response(StatusCode::BAD_REQUEST, message);
+        let response = into_problem_details(response).await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let value: Value = serde_cbor::from_slice(&body).unwrap();
+        match value {
+            Value::Map(map) => assert_eq!(
+                map[&Value::Text("message".to_string())],
+                Value::Text("a".repeat(MAX_ERROR_MESSAGE_BYTES))
+            ),
+            _ => panic!("Expected a map, got {:?}", value),
+        }
+    }
+
+    #[tokio::test]
+    async fn leaves_other_responses_unchanged() {
+        let response = make_plaintext_response(StatusCode::OK, "ok".to_string());
+        let response = into_problem_details(response).await;
+        assert!(response.headers().get(header::CONTENT_TYPE).is_none());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+    }
+}
@@ -1,11 +1,12 @@
 //! Module that deals with requests to /api/v2/canister/.../query
 
 use crate::{
-    body::BodyReceiverLayer,
+    canister_concurrency::CanisterConcurrencyLimiter,
     common::{cbor_response, make_plaintext_response},
+    replay::ReplayDetector,
     types::{to_legacy_request_type, ApiReqType},
     validator_executor::ValidatorExecutor,
-    EndpointService, HttpHandlerMetrics, ReplicaHealthStatus, UNKNOWN_LABEL,
+    BaseEndpointService, HttpHandlerMetrics, ReplicaHealthStatus, API_VERSION_V2, UNKNOWN_LABEL,
 };
 use futures_util::FutureExt;
 use hyper::{Body, Response, StatusCode};
@@ -23,7 +24,7 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::{Arc, RwLock};
 use std::task::{Context, Poll};
-use tower::{util::BoxCloneService, Service, ServiceBuilder};
+use tower::{util::BoxCloneService, Service};
 
 #[derive(Clone)]
 pub(crate) struct QueryService {
@@ -34,6 +35,8 @@ pub(crate) struct QueryService {
     validator_executor: ValidatorExecutor,
     registry_client: Arc<dyn RegistryClient>,
     query_execution_service: QueryExecutionService,
+    canister_limiter: Option<Arc<CanisterConcurrencyLimiter>>,
+    replay_detector: Arc<ReplayDetector>,
     malicious_flags: MaliciousFlags,
 }
 
@@ -47,9 +50,19 @@ impl QueryService {
         validator_executor: ValidatorExecutor,
         registry_client: Arc<dyn RegistryClient>,
         query_execution_service: QueryExecutionService,
+        max_concurrent_queries_per_canister: Option<usize>,
+        max_queued_queries_per_canister: usize,
+        replay_detector: Arc<ReplayDetector>,
         malicious_flags: MaliciousFlags,
-    ) -> EndpointService {
-        let base_service = BoxCloneService::new(ServiceBuilder::new().service(Self {
+    ) -> BaseEndpointService {
+        let canister_limiter = max_concurrent_queries_per_canister.map(|max_concurrent| {
+            Arc::new(CanisterConcurrencyLimiter::new(
+                max_concurrent,
+                max_queued_queries_per_canister,
+                metrics.clone(),
+            ))
+        });
+        BoxCloneService::new(Self {
             log,
             metrics,
             health_status,
@@ -57,13 +70,10 @@ impl QueryService {
             validator_executor,
             registry_client,
             query_execution_service,
+            canister_limiter,
+            replay_detector,
             malicious_flags,
-        }));
-        BoxCloneService::new(
-            ServiceBuilder::new()
-                .layer(BodyReceiverLayer::default())
-                .service(base_service),
-        )
+        })
     }
 }
 
@@ -85,6 +95,7 @@ impl Service<Vec<u8>> for QueryService {
                 to_legacy_request_type(ApiReqType::Query),
                 ApiReqType::Query.into(),
                 UNKNOWN_LABEL,
+                API_VERSION_V2,
             ])
             .observe(body.len() as f64);
         if *self.health_status.read().unwrap() != ReplicaHealthStatus::Healthy {
@@ -150,6 +161,9 @@ impl Service<Vec<u8>> for QueryService {
         let registry_client = self.registry_client.get_latest_version();
         let malicious_flags = self.malicious_flags.clone();
         let validator_executor = self.validator_executor.clone();
+        let canister_limiter = self.canister_limiter.clone();
+        let replay_detector = self.replay_detector.clone();
+        let metrics = self.metrics.clone();
         Box::pin(async move {
             match validator_executor
                 .get_authorized_canisters(&request, registry_client, &malicious_flags)
@@ -166,6 +180,26 @@ impl Service<Vec<u8>> for QueryService {
                     return Ok(res);
                 }
             };
+            if let Some(res) = replay_detector.check(&metrics, ApiReqType::Query, &request) {
+                return Ok(res);
+            }
+            // Held until the query has been executed.
+            let _canister_permit = match canister_limiter {
+                Some(limiter) => match limiter.acquire(request.content().receiver).await {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        let res = make_plaintext_response(
+                            StatusCode::TOO_MANY_REQUESTS,
+                            format!(
+                                "Too many queries for canister {}, try again later.",
+                                request.content().receiver
+                            ),
+                        );
+                        return Ok(res);
+                    }
+                },
+                None => None,
+            };
             old_query_execution_service
                 .call((request.take_content(), delegation_from_nns))
                 .map(|result| {
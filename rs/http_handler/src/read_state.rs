@@ -1,14 +1,18 @@
 //! Module that deals with requests to /api/v2/canister/.../read_state
 
 use crate::{
-    body::BodyReceiverLayer,
     common::{cbor_response, into_cbor, make_plaintext_response},
+    limits::ReadStatePathLimits,
+    problem_details::{with_error_cause, ErrorCause},
+    replay::ReplayDetector,
+    response_budget::{body_size, ResponseBudget, ResponseBudgetExceeded},
     state_reader_executor::StateReaderExecutor,
     types::{to_legacy_request_type, ApiReqType},
     validator_executor::ValidatorExecutor,
-    EndpointService, HttpError, HttpHandlerMetrics, ReplicaHealthStatus, UNKNOWN_LABEL,
+    BaseEndpointService, EndpointService, HttpError, HttpHandlerMetrics, ReplicaHealthStatus,
+    API_VERSION_V2, UNKNOWN_LABEL,
 };
-use hyper::{Body, Response, StatusCode};
+use hyper::{header, Body, Response, StatusCode};
 use ic_crypto_tree_hash::{sparse_labeled_tree_from_paths, Label, Path};
 use ic_interfaces::registry::RegistryClient;
 use ic_logger::{trace, ReplicaLogger};
@@ -20,20 +24,42 @@ use ic_types::{
         HttpRequest, HttpRequestEnvelope, MessageId, ReadState, SignedRequestBytes,
         EXPECTED_MESSAGE_ID_LENGTH,
     },
-    CanisterId, UserId,
+    CanisterId, Time, UserId,
 };
 use ic_validator::CanisterIdSet;
+use serde::Serialize;
 use std::convert::{Infallible, TryFrom};
 use std::future::Future;
 use std::pin::Pin;
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use std::task::{Context, Poll};
 use tower::{
-    limit::concurrency::GlobalConcurrencyLimitLayer, util::BoxCloneService, Service, ServiceBuilder,
+    limit::concurrency::GlobalConcurrencyLimitLayer, service_fn, util::BoxCloneService, BoxError,
+    Service, ServiceBuilder,
 };
 
 const MAX_READ_STATE_REQUEST_IDS: u8 = 100;
-const MAX_READ_STATE_CONCURRENT_REQUESTS: usize = 100;
+// Upper bound on the number of request IDs resolved by a single
+// `request_status_bulk` path.
+const MAX_READ_STATE_REQUEST_STATUS_BULK_IDS: usize = 1000;
+const REQUEST_STATUS_BULK_LABEL: &[u8] = b"request_status_bulk";
+// Upper bound on the total size of the custom sections requested via
+// `/canister/<id>/metadata/<name>` paths in a single request.
+const MAX_READ_STATE_METADATA_BYTES: usize = 8 * 1024 * 1024;
+// The bytes of the response budget reserved for a response before its
+// certificate is built, in addition to the requested custom sections. Larger
+// certificates, e.g. with many request statuses, grow the reservation once
+// encoded.
+const READ_STATE_RESPONSE_BASE_BYTES: usize = 64 * 1024;
+pub(crate) const MAX_READ_STATE_CONCURRENT_REQUESTS: usize = 100;
+// Default upper bounds on the number of paths of a request and on the total
+// size of their labels. Building and pruning the labeled tree is superlinear
+// in the number of paths.
+pub(crate) const MAX_READ_STATE_PATHS: usize = 1000;
+pub(crate) const MAX_READ_STATE_PATH_BYTES: usize = 64 * 1024;
+// The time of the certified state a response was derived from.
+const CERTIFICATE_TIME_HEADER: &str = "x-ic-certificate-time";
 
 #[derive(Clone)]
 pub(crate) struct ReadStateService {
@@ -44,6 +70,9 @@ pub(crate) struct ReadStateService {
     state_reader_executor: StateReaderExecutor,
     validator_executor: ValidatorExecutor,
     registry_client: Arc<dyn RegistryClient>,
+    path_limits: ReadStatePathLimits,
+    replay_detector: Arc<ReplayDetector>,
+    response_budget: ResponseBudget,
     malicious_flags: MaliciousFlags,
 }
 
@@ -57,9 +86,12 @@ impl ReadStateService {
         state_reader_executor: StateReaderExecutor,
         validator_executor: ValidatorExecutor,
         registry_client: Arc<dyn RegistryClient>,
+        path_limits: ReadStatePathLimits,
+        replay_detector: Arc<ReplayDetector>,
+        response_budget: ResponseBudget,
         malicious_flags: MaliciousFlags,
-    ) -> EndpointService {
-        let base_service = Self {
+    ) -> BaseEndpointService {
+        BoxCloneService::new(Self {
             log,
             metrics,
             health_status,
@@ -67,20 +99,11 @@ impl ReadStateService {
             state_reader_executor,
             validator_executor,
             registry_client,
+            path_limits,
+            replay_detector,
+            response_budget,
             malicious_flags,
-        };
-        let base_service = BoxCloneService::new(
-            ServiceBuilder::new()
-                .layer(GlobalConcurrencyLimitLayer::new(
-                    MAX_READ_STATE_CONCURRENT_REQUESTS,
-                ))
-                .service(base_service),
-        );
-        BoxCloneService::new(
-            ServiceBuilder::new()
-                .layer(BodyReceiverLayer::default())
-                .service(base_service),
-        )
+        })
     }
 }
 
@@ -102,6 +125,7 @@ impl Service<Vec<u8>> for ReadStateService {
                 to_legacy_request_type(ApiReqType::ReadState),
                 ApiReqType::ReadState.into(),
                 UNKNOWN_LABEL,
+                API_VERSION_V2,
             ])
             .observe(body.len() as f64);
 
@@ -140,7 +164,24 @@ impl Service<Vec<u8>> for ReadStateService {
         };
         // Collect requested path.
         let read_state = request.content().clone();
-        let mut paths: Vec<Path> = read_state.paths.clone();
+        let mut paths: Vec<Path> = expand_request_status_bulk(&read_state.paths);
+        // The limits apply to the paths the tree is pruned with, i.e. after
+        // bulk request status paths are expanded.
+        self.metrics.observe_read_state_paths(paths.len());
+        if let Err(limit) = self.path_limits.check(&paths) {
+            self.metrics.observe_read_state_path_rejection(limit.into());
+            let res = with_error_cause(
+                make_plaintext_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!(
+                        "The requested paths exceed the limit of {} paths and {} bytes.",
+                        self.path_limits.max_count, self.path_limits.max_bytes
+                    ),
+                ),
+                ErrorCause::ReadStatePathLimit(limit),
+            );
+            return Box::pin(async move { Ok(res) });
+        }
 
         // Always add "time" to the paths even if not explicitly requested.
         paths.push(Path::from(Label::from("time")));
@@ -150,6 +191,9 @@ impl Service<Vec<u8>> for ReadStateService {
         let malicious_flags = self.malicious_flags.clone();
         let state_reader_executor = self.state_reader_executor.clone();
         let validator_executor = self.validator_executor.clone();
+        let replay_detector = self.replay_detector.clone();
+        let response_budget = self.response_budget.clone();
+        let metrics = self.metrics.clone();
         Box::pin(async move {
             let targets = match validator_executor
                 .get_authorized_canisters(&request, registry_client, &malicious_flags)
@@ -161,8 +205,11 @@ impl Service<Vec<u8>> for ReadStateService {
                     return Ok(res);
                 }
             };
+            if let Some(res) = replay_detector.check(&metrics, ApiReqType::ReadState, &request) {
+                return Ok(res);
+            }
             // Verify authorization for requested paths.
-            if let Err(HttpError { status, message }) = verify_paths(
+            let metadata_bytes = match verify_paths(
                 &state_reader_executor,
                 &read_state.source,
                 &read_state.paths,
@@ -170,8 +217,26 @@ impl Service<Vec<u8>> for ReadStateService {
             )
             .await
             {
-                return Ok(make_plaintext_response(status, message));
-            }
+                Ok(metadata_bytes) => metadata_bytes,
+                Err(HttpError { status, message }) => {
+                    return Ok(make_plaintext_response(status, message))
+                }
+            };
+            // Reserve the certificate from the response budget before it is
+            // built, so that a response that does not fit isn't built at all.
+            let reject = |exceeded: ResponseBudgetExceeded| {
+                metrics
+                    .response_budget_rejections_total
+                    .with_label_values(&[ApiReqType::ReadState.into()])
+                    .inc();
+                exceeded.into_response()
+            };
+            let mut reservation = match response_budget
+                .try_reserve((metadata_bytes + READ_STATE_RESPONSE_BASE_BYTES) as u64)
+            {
+                Ok(reservation) => reservation,
+                Err(exceeded) => return Ok(reject(exceeded)),
+            };
 
             let res = match state_reader_executor
                 .read_certified_state(&labeled_tree)
@@ -182,7 +247,7 @@ impl Service<Vec<u8>> for ReadStateService {
             };
 
             let res = match res {
-                Some((_state, tree, certification)) => {
+                Some((state, tree, certification)) => {
                     let signature = certification.signed.signature.signature.get().0;
                     let res = HttpReadStateResponse {
                         certificate: Blob(into_cbor(&Certificate {
@@ -191,7 +256,14 @@ impl Service<Vec<u8>> for ReadStateService {
                             delegation: delegation_from_nns,
                         })),
                     };
-                    cbor_response(&res)
+                    let mut response = cbor_response(&res);
+                    add_certificate_time_header(&mut response, state.metadata.batch_time);
+                    // Attaching the reservation shrinks it to the size of the body.
+                    let size = body_size(&response).unwrap_or_default();
+                    match reservation.try_grow_to(size) {
+                        Ok(()) => reservation.attach(response),
+                        Err(exceeded) => reject(exceeded),
+                    }
                 }
                 None => make_plaintext_response(
                     StatusCode::SERVICE_UNAVAILABLE,
@@ -204,15 +276,165 @@ impl Service<Vec<u8>> for ReadStateService {
     }
 }
 
+/// Serves `GET /api/v2/canister/<id>/info`, the certified module hash and
+/// controllers of a canister, sparing clients the construction of a signed
+/// `read_state` request for these paths. Both are readable by anyone.
+#[derive(Clone)]
+pub(crate) struct CanisterInfoReader {
+    health_status: Arc<RwLock<ReplicaHealthStatus>>,
+    delegation_from_nns: Arc<RwLock<Option<CertificateDelegation>>>,
+    state_reader_executor: StateReaderExecutor,
+    concurrency_limit: Option<GlobalConcurrencyLimitLayer>,
+}
+
+/// The CBOR body of a canister info response. The `certificate` certifies the
+/// `/canister/<id>/module_hash` and `/canister/<id>/controllers` paths, and
+/// `time`, as a `read_state` response for them would.
+#[derive(Debug, PartialEq, Serialize)]
+struct CanisterInfoResponse {
+    module_hash: Option<Blob>,
+    controllers: Vec<Blob>,
+    certificate: Blob,
+}
+
+impl CanisterInfoReader {
+    /// Returns a reader whose requests are limited by `concurrency_limit`,
+    /// if any, like the `read_state` requests they compete with.
+    pub(crate) fn new(
+        health_status: Arc<RwLock<ReplicaHealthStatus>>,
+        delegation_from_nns: Arc<RwLock<Option<CertificateDelegation>>>,
+        state_reader_executor: StateReaderExecutor,
+        concurrency_limit: Option<GlobalConcurrencyLimitLayer>,
+    ) -> Self {
+        Self {
+            health_status,
+            delegation_from_nns,
+            state_reader_executor,
+            concurrency_limit,
+        }
+    }
+
+    /// Returns the service answering a canister info request for the
+    /// canister with the textual id `canister_id`. Requests beyond the
+    /// concurrency limit are shed.
+    pub(crate) fn service(&self, canister_id: &str) -> EndpointService {
+        let reader = self.clone();
+        let canister_id = canister_id.to_string();
+        BoxCloneService::new(
+            ServiceBuilder::new()
+                .option_layer(self.concurrency_limit.clone())
+                .service(service_fn(move |_body: Body| {
+                    let reader = reader.clone();
+                    let canister_id = canister_id.clone();
+                    async move { Ok::<_, BoxError>(reader.response(&canister_id).await) }
+                })),
+        )
+    }
+
+    /// Returns the response to a canister info request for the canister with
+    /// the textual id `canister_id`.
+    async fn response(&self, canister_id: &str) -> Response<Body> {
+        if *self.health_status.read().unwrap() != ReplicaHealthStatus::Healthy {
+            return make_plaintext_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Replica is starting. Check the /api/v2/status for more information.".to_string(),
+            );
+        }
+        let canister_id = match CanisterId::from_str(canister_id) {
+            Ok(canister_id) => canister_id,
+            Err(err) => {
+                return make_plaintext_response(
+                    StatusCode::BAD_REQUEST,
+                    format!("Could not parse Canister ID: {}.", err),
+                )
+            }
+        };
+        let delegation_from_nns = self.delegation_from_nns.read().unwrap().clone();
+
+        let canister_label = Label::from(canister_id.get_ref().as_slice());
+        let mut paths: Vec<Path> = ["module_hash", "controllers"]
+            .iter()
+            .map(|leaf| {
+                Path::new(vec![
+                    Label::from("canister"),
+                    canister_label.clone(),
+                    Label::from(*leaf),
+                ])
+            })
+            .collect();
+        paths.push(Path::from(Label::from("time")));
+        let labeled_tree = sparse_labeled_tree_from_paths(&mut paths);
+
+        let (state, tree, certification) = match self
+            .state_reader_executor
+            .read_certified_state(&labeled_tree)
+            .await
+        {
+            Ok(Some(certified_state)) => certified_state,
+            Ok(None) => {
+                return make_plaintext_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Certified state is not available yet. Please try again...".to_string(),
+                )
+            }
+            Err(e) => return make_plaintext_response(e.status, e.message),
+        };
+        let (module_hash, controllers) = match canister_info(&state, &canister_id) {
+            Some(info) => info,
+            None => {
+                return make_plaintext_response(
+                    StatusCode::NOT_FOUND,
+                    format!("Canister {} not found.", canister_id),
+                )
+            }
+        };
+        let signature = certification.signed.signature.signature.get().0;
+        let mut response = cbor_response(&CanisterInfoResponse {
+            module_hash,
+            controllers,
+            certificate: Blob(into_cbor(&Certificate {
+                tree,
+                signature: Blob(signature),
+                delegation: delegation_from_nns,
+            })),
+        });
+        add_certificate_time_header(&mut response, state.metadata.batch_time);
+        response
+    }
+}
+
+// Returns the module hash and the principals of the controllers of the
+// canister in `state`, or `None` if there is no such canister.
+fn canister_info(
+    state: &ReplicatedState,
+    canister_id: &CanisterId,
+) -> Option<(Option<Blob>, Vec<Blob>)> {
+    let canister = state.canister_state(canister_id)?;
+    let module_hash = canister
+        .execution_state
+        .as_ref()
+        .map(|execution_state| Blob(execution_state.wasm_binary.binary.module_hash().to_vec()));
+    let controllers = canister
+        .system_state
+        .controllers
+        .iter()
+        .map(|controller| Blob(controller.as_slice().to_vec()))
+        .collect();
+    Some((module_hash, controllers))
+}
+
 // Verifies that the `user` is authorized to retrieve the `paths` requested.
+// Returns the total size of the custom sections requested.
 async fn verify_paths(
     state_reader_executor: &StateReaderExecutor,
     user: &UserId,
     paths: &[Path],
     targets: &CanisterIdSet,
-) -> Result<(), HttpError> {
+) -> Result<usize, HttpError> {
     let state = state_reader_executor.get_latest_state().await?.take();
     let mut num_request_ids = 0;
+    let mut num_bulk_request_ids = 0;
+    let mut metadata_bytes = 0;
 
     // Convert the paths to slices to make it easier to match below.
     let paths: Vec<Vec<&[u8]>> = paths
@@ -234,7 +456,17 @@ async fn verify_paths(
 
                 match CanisterId::try_from(*canister_id) {
                     Ok(canister_id) => {
-                        can_read_canister_metadata(user, &canister_id, &name, &state)?
+                        metadata_bytes +=
+                            can_read_canister_metadata(user, &canister_id, &name, &state)?;
+                        if metadata_bytes > MAX_READ_STATE_METADATA_BYTES {
+                            return Err(HttpError {
+                                status: StatusCode::PAYLOAD_TOO_LARGE,
+                                message: format!(
+                                    "The requested custom sections exceed {} bytes, request them separately.",
+                                    MAX_READ_STATE_METADATA_BYTES
+                                ),
+                            });
+                        }
                     }
                     Err(err) => {
                         return Err(HttpError {
@@ -260,31 +492,31 @@ async fn verify_paths(
                     });
                 }
 
-                // Verify that the request was signed by the same user.
-                if let Ok(message_id) = MessageId::try_from(*request_id) {
-                    let ingress_status = state.get_ingress_status(&message_id);
-
-                    if let Some(ingress_user_id) = ingress_status.user_id() {
-                        if let Some(receiver) = ingress_status.receiver() {
-                            if ingress_user_id != *user || !targets.contains(&receiver) {
-                                return Err(HttpError {
-                                    status: StatusCode::FORBIDDEN,
-                                    message:
-                                        "Request IDs must be for requests signed by the caller."
-                                            .to_string(),
-                                });
-                            }
-                        }
-                    }
-                } else {
+                verify_request_id(&state, user, targets, request_id)?;
+            }
+            [REQUEST_STATUS_BULK_LABEL, request_ids @ ..] => {
+                num_bulk_request_ids += request_ids.len();
+
+                if request_ids.is_empty() {
                     return Err(HttpError {
                         status: StatusCode::BAD_REQUEST,
+                        message: "request_status_bulk requires at least one request ID."
+                            .to_string(),
+                    });
+                }
+                if num_bulk_request_ids > MAX_READ_STATE_REQUEST_STATUS_BULK_IDS {
+                    return Err(HttpError {
+                        status: StatusCode::TOO_MANY_REQUESTS,
                         message: format!(
-                            "Request IDs must be {} bytes in length.",
-                            EXPECTED_MESSAGE_ID_LENGTH
+                            "Can only request up to {} request IDs in request_status_bulk.",
+                            MAX_READ_STATE_REQUEST_STATUS_BULK_IDS
                         ),
                     });
                 }
+
+                for request_id in request_ids {
+                    verify_request_id(&state, user, targets, request_id)?;
+                }
             }
             _ => {
                 // All other paths are unsupported.
@@ -296,15 +528,66 @@ async fn verify_paths(
         }
     }
 
+    Ok(metadata_bytes)
+}
+
+// Verifies that the request with `request_id` was signed by `user` and
+// targets a canister the `user` is authorized for.
+fn verify_request_id(
+    state: &ReplicatedState,
+    user: &UserId,
+    targets: &CanisterIdSet,
+    request_id: &[u8],
+) -> Result<(), HttpError> {
+    let message_id = MessageId::try_from(request_id).map_err(|_| HttpError {
+        status: StatusCode::BAD_REQUEST,
+        message: format!(
+            "Request IDs must be {} bytes in length.",
+            EXPECTED_MESSAGE_ID_LENGTH
+        ),
+    })?;
+    let ingress_status = state.get_ingress_status(&message_id);
+
+    if let Some(ingress_user_id) = ingress_status.user_id() {
+        if let Some(receiver) = ingress_status.receiver() {
+            if ingress_user_id != *user || !targets.contains(&receiver) {
+                return Err(HttpError {
+                    status: StatusCode::FORBIDDEN,
+                    message: "Request IDs must be for requests signed by the caller.".to_string(),
+                });
+            }
+        }
+    }
     Ok(())
 }
 
+// Replaces every `/request_status_bulk/<id_1>/.../<id_n>` path with the
+// individual `/request_status/<id_i>` paths, so that a single pruned tree
+// covering all of them is returned.
+fn expand_request_status_bulk(paths: &[Path]) -> Vec<Path> {
+    let mut expanded = Vec::with_capacity(paths.len());
+    for path in paths {
+        match path.split_first() {
+            Some((label, request_ids)) if label.as_bytes() == REQUEST_STATUS_BULK_LABEL => {
+                expanded.extend(request_ids.iter().map(|request_id| {
+                    Path::new(vec![Label::from("request_status"), request_id.clone()])
+                }));
+            }
+            _ => expanded.push(path.clone()),
+        }
+    }
+    expanded
+}
+
+// Verifies that `user` may read the custom section `custom_section_name` of
+// `canister_id` and returns its size in bytes. Private sections can only be
+// read by the controllers of the canister.
 fn can_read_canister_metadata(
     user: &UserId,
     canister_id: &CanisterId,
     custom_section_name: &str,
     state: &ReplicatedState,
-) -> Result<(), HttpError> {
+) -> Result<usize, HttpError> {
     let canister = state
         .canister_states
         .get(canister_id)
@@ -335,26 +618,46 @@ fn can_read_canister_metadata(
                     ),
                 });
             }
+            Ok(custom_section.content.len())
         }
-        None => {
-            return Err(HttpError {
-                status: StatusCode::NOT_FOUND,
-                message: format!("Canister {} has no module.", canister_id),
-            })
-        }
+        None => Err(HttpError {
+            status: StatusCode::NOT_FOUND,
+            message: format!("Canister {} has no module.", canister_id),
+        }),
     }
-    Ok(())
+}
+
+// Sets the header holding the time of the certified state, i.e. the `time`
+// leaf of the certificate, so that clients and load balancers can tell a
+// replica serving stale state and retry elsewhere. The time is given in
+// nanoseconds since the UNIX epoch and as an RFC 3339 timestamp, e.g.
+// `1659357296123456789; rfc3339=2022-08-01T12:34:56.123456789Z`.
+fn add_certificate_time_header(response: &mut Response<Body>, time: Time) {
+    let nanos = time.as_nanos_since_unix_epoch();
+    let value = match time.to_rfc3339() {
+        Some(rfc3339) => format!("{}; rfc3339={}", nanos, rfc3339),
+        None => nanos.to_string(),
+    };
+    response.headers_mut().insert(
+        CERTIFICATE_TIME_HEADER,
+        header::HeaderValue::from_str(&value).expect("digits and RFC 3339 are valid header values"),
+    );
 }
 
 #[cfg(test)]
 mod test {
     use crate::{
         common::test::{array, assert_cbor_ser_equal, bytes, int},
-        read_state::{can_read_canister_metadata, verify_paths},
+        limits::{ReadStatePathLimitExceeded, ReadStatePathLimits},
+        read_state::{
+            add_certificate_time_header, can_read_canister_metadata, canister_info,
+            expand_request_status_bulk, verify_paths, CERTIFICATE_TIME_HEADER,
+            MAX_READ_STATE_REQUEST_STATUS_BULK_IDS,
+        },
         state_reader_executor::StateReaderExecutor,
         HttpError,
     };
-    use hyper::StatusCode;
+    use hyper::{Body, Response, StatusCode};
     use ic_crypto_tree_hash::{Digest, Label, MixedHashTree, Path};
     use ic_interfaces_state_manager::Labeled;
     use ic_registry_subnet_type::SubnetType;
@@ -365,7 +668,7 @@ mod test {
         state_manager::MockStateManager,
         types::ids::{canister_test_id, subnet_test_id, user_test_id},
     };
-    use ic_types::Height;
+    use ic_types::{messages::Blob, Height, Time};
     use ic_validator::CanisterIdSet;
     use std::{collections::BTreeMap, sync::Arc};
 
@@ -488,8 +791,47 @@ mod test {
         );
     }
 
-    #[tokio::test]
-    async fn async_verify_path() {
+    #[test]
+    fn canister_info_has_module_hash_and_controllers() {
+        let canister_id = canister_test_id(100);
+        let controller = user_test_id(24);
+        let mut state = ReplicatedState::new_rooted_at(
+            subnet_test_id(1),
+            SubnetType::Application,
+            "Initial".into(),
+        );
+        insert_dummy_canister(&mut state, canister_id, controller.get());
+
+        let (module_hash, controllers) = canister_info(&state, &canister_id).unwrap();
+        let expected_hash = state
+            .canister_state(&canister_id)
+            .and_then(|canister| canister.execution_state.as_ref())
+            .map(|execution_state| execution_state.wasm_binary.binary.module_hash().to_vec());
+        assert_eq!(module_hash.map(|hash| hash.0), expected_hash);
+        assert_eq!(
+            controllers,
+            vec![Blob(controller.get().as_slice().to_vec())]
+        );
+
+        assert_eq!(canister_info(&state, &canister_test_id(101)), None);
+    }
+
+    #[test]
+    fn certificate_time_header_has_nanos_and_rfc3339() {
+        let mut response = Response::new(Body::empty());
+        add_certificate_time_header(
+            &mut response,
+            Time::from_nanos_since_unix_epoch(1_659_357_296_123_456_789),
+        );
+        assert_eq!(
+            response.headers().get(CERTIFICATE_TIME_HEADER).unwrap(),
+            "1659357296123456789; rfc3339=2022-08-01T12:34:56.123456789Z"
+        );
+    }
+
+    // A `StateReaderExecutor` over an empty state, in which all request IDs
+    // have an unknown status.
+    fn empty_state_reader_executor() -> StateReaderExecutor {
         let subnet_id = subnet_test_id(1);
         let mut mock_state_manager = MockStateManager::new();
         mock_state_manager
@@ -510,8 +852,12 @@ mod test {
                 )
             });
 
-        let state_manager = Arc::new(mock_state_manager);
-        let sre = StateReaderExecutor::new(state_manager.clone());
+        StateReaderExecutor::new(Arc::new(mock_state_manager))
+    }
+
+    #[tokio::test]
+    async fn async_verify_path() {
+        let sre = empty_state_reader_executor();
         assert_eq!(
             verify_paths(
                 &sre,
@@ -520,7 +866,108 @@ mod test {
                 &CanisterIdSet::All
             )
             .await,
-            Ok(())
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn request_status_bulk_is_expanded() {
+        let paths = vec![
+            Path::from(Label::from("time")),
+            Path::new(vec![
+                Label::from("request_status_bulk"),
+                Label::from(vec![1u8; 32]),
+                Label::from(vec![2u8; 32]),
+            ]),
+        ];
+        assert_eq!(
+            expand_request_status_bulk(&paths),
+            vec![
+                Path::from(Label::from("time")),
+                Path::new(vec![
+                    Label::from("request_status"),
+                    Label::from(vec![1u8; 32])
+                ]),
+                Path::new(vec![
+                    Label::from("request_status"),
+                    Label::from(vec![2u8; 32])
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn path_limits_apply_to_expanded_paths() {
+        let limits = ReadStatePathLimits {
+            max_count: 2,
+            max_bytes: 1024,
+        };
+        let paths = vec![Path::new(vec![
+            Label::from("request_status_bulk"),
+            Label::from(vec![1u8; 32]),
+            Label::from(vec![2u8; 32]),
+            Label::from(vec![3u8; 32]),
+        ])];
+        assert_eq!(limits.check(&paths), Ok(()));
+        assert_eq!(
+            limits.check(&expand_request_status_bulk(&paths)),
+            Err(ReadStatePathLimitExceeded::PathCount)
+        );
+    }
+
+    // A `request_status_bulk` path with `num_request_ids` request IDs.
+    fn request_status_bulk_path(num_request_ids: usize) -> Path {
+        let mut labels = vec![Label::from("request_status_bulk")];
+        labels.extend(vec![Label::from(vec![1u8; 32]); num_request_ids]);
+        Path::new(labels)
+    }
+
+    #[tokio::test]
+    async fn request_status_bulk_is_capped() {
+        let sre = empty_state_reader_executor();
+        let verify = |paths: Vec<Path>| {
+            let sre = sre.clone();
+            async move { verify_paths(&sre, &user_test_id(1), &paths, &CanisterIdSet::All).await }
+        };
+        let too_many_request_ids = Err(HttpError {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            message: format!(
+                "Can only request up to {} request IDs in request_status_bulk.",
+                MAX_READ_STATE_REQUEST_STATUS_BULK_IDS
+            ),
+        });
+
+        assert_eq!(
+            verify(vec![request_status_bulk_path(
+                MAX_READ_STATE_REQUEST_STATUS_BULK_IDS
+            )])
+            .await,
+            Ok(0)
+        );
+        assert_eq!(
+            verify(vec![request_status_bulk_path(
+                MAX_READ_STATE_REQUEST_STATUS_BULK_IDS + 1
+            )])
+            .await,
+            too_many_request_ids
+        );
+
+        // The cap is on the request IDs of all bulk paths of a request.
+        assert_eq!(
+            verify(vec![
+                request_status_bulk_path(MAX_READ_STATE_REQUEST_STATUS_BULK_IDS - 1),
+                request_status_bulk_path(1),
+            ])
+            .await,
+            Ok(0)
+        );
+        assert_eq!(
+            verify(vec![
+                request_status_bulk_path(MAX_READ_STATE_REQUEST_STATUS_BULK_IDS),
+                request_status_bulk_path(1),
+            ])
+            .await,
+            too_many_request_ids
         );
     }
 }
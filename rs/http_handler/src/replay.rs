@@ -0,0 +1,116 @@
+//! Module that detects replayed `query` and `read_state` requests.
+//!
+//! Agents set a nonce to make otherwise identical requests distinct. A request
+//! with a nonce received again shortly after, i.e. with the same sender, nonce
+//! and content, hence the same request id, points at an agent or a proxy
+//! retrying in a loop. Replays are counted, and rejected with a
+//! `429 Too Many Requests` if enforced. Requests are only recorded once they
+//! passed validation, so that forged copies cannot get the original rejected.
+use crate::{common::make_plaintext_response, metrics::HttpHandlerMetrics, types::ApiReqType};
+use hyper::{Body, Response, StatusCode};
+use ic_types::messages::{HttpRequest, HttpRequestContent, MessageId};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long a request is remembered for.
+const REPLAY_WINDOW: Duration = Duration::from_secs(10);
+
+/// The maximum number of remembered requests. Once reached, further requests
+/// are not remembered until old ones expire.
+const MAX_REMEMBERED_REQUESTS: usize = 100_000;
+
+#[derive(Default)]
+struct Entries {
+    request_ids: HashSet<MessageId>,
+    // Request ids in the order they were inserted in, hence by expiry.
+    expiry_order: VecDeque<(MessageId, Instant)>,
+}
+
+impl Entries {
+    fn prune(&mut self, now: Instant) {
+        while let Some((request_id, expiry)) = self.expiry_order.front() {
+            if *expiry > now {
+                break;
+            }
+            self.request_ids.remove(request_id);
+            self.expiry_order.pop_front();
+        }
+    }
+}
+
+/// The ids of recent requests with a nonce.
+pub(crate) struct ReplayDetector {
+    enforce: bool,
+    entries: Mutex<Entries>,
+}
+
+impl ReplayDetector {
+    /// Returns a detector rejecting replays if `enforce` is set.
+    pub(crate) fn new(enforce: bool) -> Self {
+        Self {
+            enforce,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Remembers `request_id`, returning whether it was already remembered.
+    fn record(&self, request_id: MessageId, now: Instant) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        entries.prune(now);
+        if entries.request_ids.contains(&request_id) {
+            return true;
+        }
+        if entries.expiry_order.len() < MAX_REMEMBERED_REQUESTS {
+            entries.request_ids.insert(request_id.clone());
+            entries
+                .expiry_order
+                .push_back((request_id, now + REPLAY_WINDOW));
+        }
+        false
+    }
+
+    /// Records `request` if it has a nonce. Returns the response rejecting
+    /// it if it is a replay and replays are rejected.
+    pub(crate) fn check<C: HttpRequestContent>(
+        &self,
+        metrics: &HttpHandlerMetrics,
+        api_req_type: ApiReqType,
+        request: &HttpRequest<C>,
+    ) -> Option<Response<Body>> {
+        request.nonce()?;
+        if !self.record(request.id(), Instant::now()) {
+            return None;
+        }
+        metrics.observe_replayed_request(api_req_type, self.enforce);
+        if !self.enforce {
+            return None;
+        }
+        Some(make_plaintext_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "Request {} was received less than {}s ago, use a new nonce for retries.",
+                request.id(),
+                REPLAY_WINDOW.as_secs()
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_replays_within_the_window() {
+        let detector = ReplayDetector::new(true);
+        let now = Instant::now();
+        let id = MessageId::from([1; 32]);
+        assert!(!detector.record(id.clone(), now));
+        assert!(detector.record(id.clone(), now + REPLAY_WINDOW / 2));
+        assert!(!detector.record(MessageId::from([2; 32]), now));
+        assert!(!detector.record(id, now + REPLAY_WINDOW));
+    }
+}
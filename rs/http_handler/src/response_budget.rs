@@ -0,0 +1,323 @@
+//! A memory budget shared by the response bodies of all endpoints.
+//!
+//! The endpoints build their response bodies in memory, e.g. encoded CUPs,
+//! `read_state` certificates and the rendered dashboard, and hold them until
+//! they are written to the client. With many large responses to slow clients,
+//! that can exhaust the memory of small nodes. Endpoints building large bodies
+//! thus reserve their size, or an estimate of it, from the budget before
+//! building them, see [`ResponseBudget::try_reserve`], and all other responses
+//! of known size reserve it once built. The bytes are held until the body is dropped, and
+//! responses that don't fit are replaced with a `503 Service Unavailable`.
+
+use crate::common::make_plaintext_response;
+use futures_util::StreamExt;
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderValue},
+    Body, Response, StatusCode,
+};
+use prometheus::IntGauge;
+use std::{convert::TryFrom, fmt, sync::Arc};
+use tokio::sync::Semaphore;
+
+/// The `Retry-After` of responses rejected for exceeding the budget, in
+/// seconds. Responses in flight are usually written within that time.
+const RETRY_AFTER_SECONDS: u64 = 1;
+
+/// The largest budget, i.e. the most permits a semaphore can hold.
+const MAX_BUDGET_BYTES: u64 = (usize::MAX >> 3) as u64;
+
+/// The bytes of response bodies that may be held in memory at once.
+#[derive(Clone)]
+pub(crate) struct ResponseBudget {
+    available: Arc<Semaphore>,
+    max_bytes: u64,
+    reserved_bytes: IntGauge,
+}
+
+/// A response that does not fit in the [`ResponseBudget`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ResponseBudgetExceeded {
+    /// The size of the body of the rejected response.
+    pub size: u64,
+    /// The size of the budget.
+    pub max_bytes: u64,
+}
+
+impl fmt::Display for ResponseBudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The response of {} bytes exceeds the memory available for responses of {} bytes, retry later.",
+            self.size, self.max_bytes
+        )
+    }
+}
+
+impl ResponseBudgetExceeded {
+    /// The response replacing the rejected one.
+    pub(crate) fn into_response(self) -> Response<Body> {
+        let mut response =
+            make_plaintext_response(StatusCode::SERVICE_UNAVAILABLE, self.to_string());
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECONDS));
+        response
+    }
+}
+
+/// Bytes reserved from the [`ResponseBudget`], released when dropped.
+pub(crate) struct ResponseReservation {
+    available: Arc<Semaphore>,
+    size: u64,
+    max_bytes: u64,
+    reserved_bytes: IntGauge,
+}
+
+// Marks responses whose body holds a reservation already.
+#[derive(Clone, Copy)]
+struct Reserved;
+
+impl ResponseReservation {
+    /// Reserves the bytes up to `size` beyond the reserved ones, e.g. once a
+    /// body built within an estimated size turned out larger.
+    pub(crate) fn try_grow_to(&mut self, size: u64) -> Result<(), ResponseBudgetExceeded> {
+        if size <= self.size {
+            return Ok(());
+        }
+        let exceeded = ResponseBudgetExceeded {
+            size,
+            max_bytes: self.max_bytes,
+        };
+        let permits = u32::try_from(size - self.size).map_err(|_| exceeded)?;
+        // The permits are returned by the reservation, as it may be shrunk.
+        self.available
+            .try_acquire_many(permits)
+            .map_err(|_| exceeded)?
+            .forget();
+        self.reserved_bytes.add((size - self.size) as i64);
+        self.size = size;
+        Ok(())
+    }
+
+    /// Releases the reserved bytes beyond `size`, e.g. once a body built
+    /// within a reserved maximum size turned out smaller.
+    pub(crate) fn shrink_to(&mut self, size: u64) {
+        if size < self.size {
+            self.release(self.size - size);
+            self.size = size;
+        }
+    }
+
+    /// Holds the reservation until the body of `response` is dropped, i.e.
+    /// written to the client or abandoned. The reservation is shrunk to the
+    /// size of the body, if known.
+    pub(crate) fn attach(mut self, response: Response<Body>) -> Response<Body> {
+        let size = body_size(&response);
+        if let Some(size) = size {
+            self.shrink_to(size);
+        }
+        let (mut parts, body) = response.into_parts();
+        let body = Body::wrap_stream(body.map(move |chunk| {
+            let _reservation = &self;
+            chunk
+        }));
+        // The wrapped body has no size hint anymore.
+        if let Some(size) = size {
+            parts
+                .headers
+                .insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+        }
+        parts.extensions.insert(Reserved);
+        Response::from_parts(parts, body)
+    }
+
+    fn release(&self, size: u64) {
+        // Reservations never exceed the budget, which fits in a `usize`.
+        self.available.add_permits(size as usize);
+        self.reserved_bytes.sub(size as i64);
+    }
+}
+
+impl Drop for ResponseReservation {
+    fn drop(&mut self) {
+        self.release(self.size);
+    }
+}
+
+impl ResponseBudget {
+    /// A budget of `max_bytes`, with the bytes reserved at any time recorded
+    /// in `reserved_bytes`.
+    pub(crate) fn new(max_bytes: u64, reserved_bytes: IntGauge) -> Self {
+        let max_bytes = max_bytes.min(MAX_BUDGET_BYTES);
+        Self {
+            available: Arc::new(Semaphore::new(max_bytes as usize)),
+            max_bytes,
+            reserved_bytes,
+        }
+    }
+
+    /// Reserves `size` bytes for a response body about to be built, so that
+    /// it is not built at all if it does not fit.
+    pub(crate) fn try_reserve(
+        &self,
+        size: u64,
+    ) -> Result<ResponseReservation, ResponseBudgetExceeded> {
+        let mut reservation = ResponseReservation {
+            available: Arc::clone(&self.available),
+            size: 0,
+            max_bytes: self.max_bytes,
+            reserved_bytes: self.reserved_bytes.clone(),
+        };
+        reservation.try_grow_to(size)?;
+        Ok(reservation)
+    }
+
+    /// Reserves the size of the body of `response` until the body is dropped,
+    /// i.e. written to the client or abandoned. Bodies of unknown size, i.e.
+    /// streamed without a `Content-Length`, are not held in memory and
+    /// reserve nothing, nor do bodies that were reserved before being built.
+    pub(crate) fn reserve(
+        &self,
+        response: Response<Body>,
+    ) -> Result<Response<Body>, ResponseBudgetExceeded> {
+        if response.extensions().get::<Reserved>().is_some() {
+            return Ok(response);
+        }
+        match body_size(&response) {
+            Some(size) if size > 0 => Ok(self.try_reserve(size)?.attach(response)),
+            _ => Ok(response),
+        }
+    }
+}
+
+/// The size of the body of `response`, from its size hint or, for streamed
+/// bodies, its `Content-Length`.
+pub(crate) fn body_size(response: &Response<Body>) -> Option<u64> {
+    response.body().size_hint().exact().or_else(|| {
+        response
+            .headers()
+            .get(header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(max_bytes: u64) -> ResponseBudget {
+        ResponseBudget::new(
+            max_bytes,
+            IntGauge::new("reserved_bytes", "Reserved bytes.").unwrap(),
+        )
+    }
+
+    fn response(size: usize) -> Response<Body> {
+        Response::new(Body::from(vec![0; size]))
+    }
+
+    #[tokio::test]
+    async fn bytes_are_reserved_until_the_body_is_dropped() {
+        let budget = budget(100);
+        let first = budget.reserve(response(60)).unwrap();
+        assert_eq!(budget.reserved_bytes.get(), 60);
+        assert_eq!(first.headers().get(header::CONTENT_LENGTH).unwrap(), "60");
+        assert_eq!(
+            budget.reserve(response(60)).unwrap_err(),
+            ResponseBudgetExceeded {
+                size: 60,
+                max_bytes: 100
+            }
+        );
+        let second = budget.reserve(response(40)).unwrap();
+        assert_eq!(budget.reserved_bytes.get(), 100);
+
+        // Writing the body to the client releases its bytes.
+        let body = hyper::body::to_bytes(first.into_body()).await.unwrap();
+        assert_eq!(body.len(), 60);
+        assert_eq!(budget.reserved_bytes.get(), 40);
+        drop(second);
+        assert_eq!(budget.reserved_bytes.get(), 0);
+        assert!(budget.reserve(response(100)).is_ok());
+    }
+
+    #[test]
+    fn bodies_reserved_before_being_built_are_not_reserved_again() {
+        let budget = budget(100);
+        let mut reservation = budget.try_reserve(80).unwrap();
+        assert!(budget.try_reserve(30).is_err());
+        reservation.shrink_to(50);
+        assert_eq!(budget.reserved_bytes.get(), 50);
+
+        // The reservation is shrunk to the size of the body it is attached to.
+        let reserved = reservation.attach(response(40));
+        assert_eq!(budget.reserved_bytes.get(), 40);
+        assert_eq!(
+            reserved.headers().get(header::CONTENT_LENGTH).unwrap(),
+            "40"
+        );
+        let reserved = budget.reserve(reserved).unwrap();
+        assert_eq!(budget.reserved_bytes.get(), 40);
+        drop(reserved);
+        assert_eq!(budget.reserved_bytes.get(), 0);
+        assert!(budget.try_reserve(100).is_ok());
+    }
+
+    #[test]
+    fn reservations_grow_within_the_budget() {
+        let budget = budget(100);
+        let mut reservation = budget.try_reserve(40).unwrap();
+        reservation.try_grow_to(70).unwrap();
+        assert_eq!(budget.reserved_bytes.get(), 70);
+        reservation.try_grow_to(30).unwrap();
+        assert_eq!(budget.reserved_bytes.get(), 70);
+        assert_eq!(
+            reservation.try_grow_to(120),
+            Err(ResponseBudgetExceeded {
+                size: 120,
+                max_bytes: 100
+            })
+        );
+        assert_eq!(budget.reserved_bytes.get(), 70);
+        drop(reservation);
+        assert_eq!(budget.reserved_bytes.get(), 0);
+        assert!(budget.try_reserve(100).is_ok());
+    }
+
+    #[test]
+    fn responses_of_unknown_size_reserve_nothing() {
+        let budget = budget(10);
+        let (_sender, body) = Body::channel();
+        assert!(budget.reserve(Response::new(body)).is_ok());
+        assert!(budget.reserve(response(0)).is_ok());
+        assert_eq!(budget.reserved_bytes.get(), 0);
+
+        // Streamed bodies of known length are held in memory all the same.
+        let mut streamed =
+            Response::new(Body::wrap_stream(futures_util::stream::iter(vec![Ok::<
+                _,
+                std::io::Error,
+            >(
+                vec![0; 20],
+            )])));
+        streamed
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(20));
+        assert!(budget.reserve(streamed).is_err());
+    }
+
+    #[test]
+    fn rejections_are_retryable() {
+        let response = ResponseBudgetExceeded {
+            size: 20,
+            max_bytes: 10,
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "1");
+    }
+}
@@ -0,0 +1,410 @@
+//! Parsing of the responses to the `read_state` requests for the delegation
+//! of this subnet, sent to NNS nodes at startup.
+//!
+//! Responses are parsed before their certificate is verified, so a malicious
+//! or compromised NNS node controls everything parsed here. The sizes of the
+//! response and of its certificate, and the depth and number of nodes of the
+//! tree of the certificate, are bounded, so that such a node can neither
+//! exhaust the memory of the replica nor overflow its stack while it starts.
+//!
+//! On freshly joined nodes, the local registry may lag behind the one the
+//! delegation was certified at, so that the public key of this subnet is not
+//! found or differs. The delegation is then validated again against newer
+//! registry versions as they arrive, rather than fetched again after a backoff.
+//! The registry client doesn't notify of new versions, so a single task polls
+//! it and publishes the latest version to the waiters through a `watch`
+//! channel.
+use ic_crypto_tree_hash::{LabeledTree, MixedHashTree};
+use ic_interfaces::registry::RegistryClient;
+use ic_types::{
+    messages::{Blob, Certificate, HttpReadStateResponse},
+    RegistryVersion,
+};
+use std::{convert::TryFrom, fmt, sync::Arc, time::Duration};
+use tokio::{
+    sync::watch,
+    time::{sleep, timeout},
+};
+
+/// The maximum size of a response to a delegation request, in bytes.
+pub(crate) const MAX_DELEGATION_RESPONSE_BYTES: u64 = 1024 * 1024;
+
+/// The time within which a response to a delegation request must be received.
+pub(crate) const DELEGATION_RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The time a delegation is validated again against newer registry versions,
+/// before it is fetched again.
+pub(crate) const DELEGATION_REGISTRY_UPDATE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The interval at which the local registry is checked for a newer version.
+const REGISTRY_VERSION_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The maximum size of the certificate of a delegation, in bytes.
+const MAX_DELEGATION_CERTIFICATE_BYTES: usize = 512 * 1024;
+
+/// The maximum depth of the tree of the certificate of a delegation. The trees
+/// certified by the NNS subnet are balanced, so that this allows for far more
+/// subnets than will ever exist.
+const MAX_DELEGATION_TREE_DEPTH: usize = 64;
+
+/// The maximum number of nodes of the tree of the certificate of a delegation.
+const MAX_DELEGATION_TREE_NODES: usize = 16 * 1024;
+
+/// Errors when parsing a response to a delegation request.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum DelegationParseError {
+    /// The response is not a CBOR-encoded `read_state` response.
+    MalformedResponse(String),
+    /// The certificate exceeds `MAX_DELEGATION_CERTIFICATE_BYTES`.
+    CertificateTooLarge { size: usize },
+    /// The certificate is not a CBOR-encoded certificate.
+    MalformedCertificate(String),
+    /// The tree of the certificate exceeds `MAX_DELEGATION_TREE_DEPTH`.
+    TreeTooDeep,
+    /// The tree of the certificate exceeds `MAX_DELEGATION_TREE_NODES`.
+    TreeTooLarge,
+    /// The tree of the certificate is not a valid hash tree.
+    InvalidTree(String),
+}
+
+impl fmt::Display for DelegationParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedResponse(err) => {
+                write!(f, "failed to parse the delegation response: {}", err)
+            }
+            Self::CertificateTooLarge { size } => write!(
+                f,
+                "the delegation certificate of {} bytes exceeds the limit of {} bytes",
+                size, MAX_DELEGATION_CERTIFICATE_BYTES
+            ),
+            Self::MalformedCertificate(err) => {
+                write!(f, "failed to parse delegation certificate: {}", err)
+            }
+            Self::TreeTooDeep => write!(
+                f,
+                "the hash tree of the delegation certificate exceeds the maximum depth of {}",
+                MAX_DELEGATION_TREE_DEPTH
+            ),
+            Self::TreeTooLarge => write!(
+                f,
+                "the hash tree of the delegation certificate exceeds the maximum of {} nodes",
+                MAX_DELEGATION_TREE_NODES
+            ),
+            Self::InvalidTree(err) => {
+                write!(
+                    f,
+                    "invalid hash tree in the delegation certificate: {}",
+                    err
+                )
+            }
+        }
+    }
+}
+
+/// A delegation response, with the tree of its certificate.
+#[derive(Debug)]
+pub(crate) struct ParsedDelegation {
+    /// The CBOR-encoded certificate, yet to be verified.
+    pub(crate) certificate: Blob,
+    pub(crate) tree: LabeledTree<Vec<u8>>,
+}
+
+/// Parses `raw_response`, a response to a delegation request, which must not
+/// exceed `MAX_DELEGATION_RESPONSE_BYTES`.
+pub(crate) fn parse_delegation_response(
+    raw_response: &[u8],
+) -> Result<ParsedDelegation, DelegationParseError> {
+    let response: HttpReadStateResponse = serde_cbor::from_slice(raw_response)
+        .map_err(|err| DelegationParseError::MalformedResponse(err.to_string()))?;
+    let size = response.certificate.0.len();
+    if size > MAX_DELEGATION_CERTIFICATE_BYTES {
+        return Err(DelegationParseError::CertificateTooLarge { size });
+    }
+    let certificate: Certificate = serde_cbor::from_slice(&response.certificate.0)
+        .map_err(|err| DelegationParseError::MalformedCertificate(err.to_string()))?;
+    check_tree_bounds(&certificate.tree)?;
+    let tree = LabeledTree::try_from(certificate.tree)
+        .map_err(|err| DelegationParseError::InvalidTree(format!("{:?}", err)))?;
+    Ok(ParsedDelegation {
+        certificate: response.certificate,
+        tree,
+    })
+}
+
+/// Spawns a task publishing the latest version of the local registry to the
+/// returned receiver. The task stops once the receiver and its clones are
+/// dropped.
+pub(crate) fn spawn_registry_version_poller(
+    registry_client: Arc<dyn RegistryClient>,
+) -> watch::Receiver<RegistryVersion> {
+    let mut published = registry_client.get_latest_version();
+    let (sender, receiver) = watch::channel(published);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = sender.closed() => return,
+                _ = sleep(REGISTRY_VERSION_POLL_INTERVAL) => {}
+            }
+            let latest = registry_client.get_latest_version();
+            if latest != published {
+                if sender.send(latest).is_err() {
+                    return;
+                }
+                published = latest;
+            }
+        }
+    });
+    receiver
+}
+
+/// Waits for the registry versions published to `registry_versions` to move
+/// past `version`, returning the newer version, or `None` after `wait_time`
+/// or if no more versions are published.
+pub(crate) async fn wait_for_registry_version_after(
+    registry_versions: &mut watch::Receiver<RegistryVersion>,
+    version: RegistryVersion,
+    wait_time: Duration,
+) -> Option<RegistryVersion> {
+    timeout(wait_time, async {
+        loop {
+            let latest = *registry_versions.borrow_and_update();
+            if latest > version {
+                return Some(latest);
+            }
+            registry_versions.changed().await.ok()?;
+        }
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+// Checks the depth and number of nodes of `tree` without recursion, as the
+// tree is yet to be bounded.
+fn check_tree_bounds(tree: &MixedHashTree) -> Result<(), DelegationParseError> {
+    let mut nodes = 0;
+    let mut stack = vec![(tree, 1)];
+    while let Some((node, depth)) = stack.pop() {
+        nodes += 1;
+        if nodes > MAX_DELEGATION_TREE_NODES {
+            return Err(DelegationParseError::TreeTooLarge);
+        }
+        if depth > MAX_DELEGATION_TREE_DEPTH {
+            return Err(DelegationParseError::TreeTooDeep);
+        }
+        match node {
+            MixedHashTree::Fork(children) => {
+                stack.push((&children.0, depth + 1));
+                stack.push((&children.1, depth + 1));
+            }
+            MixedHashTree::Labeled(_, child) => stack.push((child, depth + 1)),
+            MixedHashTree::Empty | MixedHashTree::Leaf(_) | MixedHashTree::Pruned(_) => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_crypto_tree_hash::Label;
+    use ic_interfaces::registry::RegistryClientVersionedResult;
+    use ic_types::{registry::RegistryClientError, Time};
+
+    fn labeled(label: &str, child: MixedHashTree) -> MixedHashTree {
+        MixedHashTree::Labeled(Label::from(label), Box::new(child))
+    }
+
+    fn response(tree: MixedHashTree) -> Vec<u8> {
+        let certificate = Certificate {
+            tree,
+            signature: Blob(vec![]),
+            delegation: None,
+        };
+        serde_cbor::to_vec(&HttpReadStateResponse {
+            certificate: Blob(serde_cbor::to_vec(&certificate).unwrap()),
+        })
+        .unwrap()
+    }
+
+    // A chain of `depth` labeled nodes ending in a leaf.
+    fn deep_tree(depth: usize) -> MixedHashTree {
+        (1..depth).fold(MixedHashTree::Leaf(vec![1]), |tree, _| labeled("a", tree))
+    }
+
+    #[test]
+    fn valid_responses_are_parsed() {
+        let tree = labeled(
+            "subnet",
+            MixedHashTree::Fork(Box::new((
+                labeled("a", MixedHashTree::Leaf(vec![1])),
+                labeled("b", MixedHashTree::Leaf(vec![2])),
+            ))),
+        );
+        let raw_response = response(tree);
+        let parsed = parse_delegation_response(&raw_response).unwrap();
+        match parsed.tree {
+            LabeledTree::SubTree(children) => {
+                assert_eq!(children.keys(), &[Label::from("subnet")]);
+            }
+            LabeledTree::Leaf(_) => panic!("Expected a subtree"),
+        }
+    }
+
+    #[test]
+    fn oversized_trees_are_rejected() {
+        assert!(parse_delegation_response(&response(deep_tree(MAX_DELEGATION_TREE_DEPTH))).is_ok());
+        // Deeper trees are rejected by the depth limit, well before they run
+        // into the recursion limit of the CBOR decoder.
+        assert_eq!(
+            parse_delegation_response(&response(deep_tree(MAX_DELEGATION_TREE_DEPTH + 1)))
+                .unwrap_err(),
+            DelegationParseError::TreeTooDeep
+        );
+
+        // A balanced tree of 2^15 - 1 nodes, within the maximum depth.
+        let balanced = (0..14).fold(MixedHashTree::Empty, |tree, _| {
+            MixedHashTree::Fork(Box::new((tree.clone(), tree)))
+        });
+        assert_eq!(
+            check_tree_bounds(&balanced),
+            Err(DelegationParseError::TreeTooLarge)
+        );
+    }
+
+    #[test]
+    fn oversized_certificates_are_rejected() {
+        let raw_response = serde_cbor::to_vec(&HttpReadStateResponse {
+            certificate: Blob(vec![0; MAX_DELEGATION_CERTIFICATE_BYTES + 1]),
+        })
+        .unwrap();
+        assert_eq!(
+            parse_delegation_response(&raw_response).unwrap_err(),
+            DelegationParseError::CertificateTooLarge {
+                size: MAX_DELEGATION_CERTIFICATE_BYTES + 1
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_responses_are_rejected() {
+        assert!(matches!(
+            parse_delegation_response(b"not cbor"),
+            Err(DelegationParseError::MalformedResponse(_))
+        ));
+        let raw_response = serde_cbor::to_vec(&HttpReadStateResponse {
+            certificate: Blob(vec![1, 2, 3]),
+        })
+        .unwrap();
+        assert!(matches!(
+            parse_delegation_response(&raw_response),
+            Err(DelegationParseError::MalformedCertificate(_))
+        ));
+        assert!(matches!(
+            parse_delegation_response(&response(MixedHashTree::Fork(Box::new((
+                MixedHashTree::Leaf(vec![1]),
+                MixedHashTree::Leaf(vec![2]),
+            ))))),
+            Err(DelegationParseError::InvalidTree(_))
+        ));
+    }
+
+    // A registry client only knowing its latest version.
+    struct LatestVersionOnly(RegistryVersion);
+
+    impl RegistryClient for LatestVersionOnly {
+        fn get_versioned_value(
+            &self,
+            _key: &str,
+            _version: RegistryVersion,
+        ) -> RegistryClientVersionedResult<Vec<u8>> {
+            unimplemented!()
+        }
+
+        fn get_key_family(
+            &self,
+            _key_prefix: &str,
+            _version: RegistryVersion,
+        ) -> Result<Vec<String>, RegistryClientError> {
+            unimplemented!()
+        }
+
+        fn get_latest_version(&self) -> RegistryVersion {
+            self.0
+        }
+
+        fn get_version_timestamp(&self, _registry_version: RegistryVersion) -> Option<Time> {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn registry_version_poller_stops_once_the_receiver_is_dropped() {
+        let registry_client = Arc::new(LatestVersionOnly(RegistryVersion::from(1)));
+        let receiver = spawn_registry_version_poller(Arc::clone(&registry_client) as Arc<_>);
+        assert_eq!(*receiver.borrow(), RegistryVersion::from(1));
+        drop(receiver);
+
+        // The poller drops its registry client once stopped, without waiting
+        // for the next poll.
+        timeout(REGISTRY_VERSION_POLL_INTERVAL / 2, async {
+            while Arc::strong_count(&registry_client) > 1 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("The poller should stop once the receiver is dropped");
+    }
+
+    #[tokio::test]
+    async fn newer_registry_versions_are_awaited() {
+        let (sender, mut receiver) = watch::channel(RegistryVersion::from(1));
+        let publisher = tokio::spawn(async move {
+            sleep(Duration::from_millis(10)).await;
+            sender.send(RegistryVersion::from(2)).unwrap();
+            sender
+        });
+        assert_eq!(
+            wait_for_registry_version_after(
+                &mut receiver,
+                RegistryVersion::from(1),
+                Duration::from_secs(10)
+            )
+            .await,
+            Some(RegistryVersion::from(2))
+        );
+        // The latest version is returned right away if newer.
+        assert_eq!(
+            wait_for_registry_version_after(
+                &mut receiver,
+                RegistryVersion::from(1),
+                Duration::from_millis(10)
+            )
+            .await,
+            Some(RegistryVersion::from(2))
+        );
+
+        assert_eq!(
+            wait_for_registry_version_after(
+                &mut receiver,
+                RegistryVersion::from(2),
+                Duration::from_millis(10)
+            )
+            .await,
+            None
+        );
+
+        // No version is awaited once no more are published.
+        drop(publisher.await.unwrap());
+        assert_eq!(
+            wait_for_registry_version_after(
+                &mut receiver,
+                RegistryVersion::from(2),
+                Duration::from_secs(10)
+            )
+            .await,
+            None
+        );
+    }
+}
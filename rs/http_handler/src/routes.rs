@@ -0,0 +1,417 @@
+//! Module with the table of routes served by the HTTP handler.
+//!
+//! A route maps a method and a path pattern to the request type reported in
+//! the metrics and to a handler. Patterns are absolute paths whose segments
+//! are either literals or parameters, written `:name`, matching any single
+//! segment. Looking up a path that matches the pattern of some routes but
+//! none with the method of the request yields the methods allowed for it,
+//! so that a `405 Method Not Allowed` with an `Allow` header can be
+//! generated. The same methods are listed in answers to `OPTIONS` requests.
+//!
+//! The API version of a route is the segment following `/api` in its pattern,
+//! e.g. `v2` for `/api/v2/status`. Routes can be marked deprecated, in which
+//! case responses announce it with the `Deprecation`, `Sunset` and `Link`
+//! headers, so that clients can migrate to newer versions of the endpoints.
+use crate::types::ApiReqType;
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap, Method,
+};
+use ic_config::http_handler::RouteDeprecation;
+use std::sync::Arc;
+
+/// The API version label of routes outside of `/api`.
+pub(crate) const NO_API_VERSION: &str = "none";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(&'static str),
+    Param(&'static str),
+}
+
+#[derive(Clone, Debug)]
+struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    fn parse(pattern: &'static str) -> Self {
+        assert!(
+            pattern.starts_with('/'),
+            "Route pattern {} is not an absolute path.",
+            pattern
+        );
+        Self(
+            pattern[1..]
+                .split('/')
+                .map(|segment| match segment.strip_prefix(':') {
+                    Some(name) => Segment::Param(name),
+                    None => Segment::Literal(segment),
+                })
+                .collect(),
+        )
+    }
+
+    fn matches<'p>(&self, path: &'p str) -> Option<PathParams<'p>> {
+        let segments: Vec<&str> = path.strip_prefix('/')?.split('/').collect();
+        if segments.len() != self.0.len() {
+            return None;
+        }
+        let mut params = PathParams::default();
+        for (pattern_segment, segment) in self.0.iter().zip(segments) {
+            match pattern_segment {
+                Segment::Literal(literal) if *literal == segment => (),
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => params.0.push((name, segment)),
+            }
+        }
+        Some(params)
+    }
+
+    fn api_version(&self) -> &'static str {
+        match self.0.as_slice() {
+            [Segment::Literal("api"), Segment::Literal(version), ..] => *version,
+            _ => NO_API_VERSION,
+        }
+    }
+}
+
+/// The values of the parameters of a route pattern in a request path.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct PathParams<'p>(Vec<(&'static str, &'p str)>);
+
+impl<'p> PathParams<'p> {
+    /// Returns the value of the parameter `name`, if the pattern has one.
+    pub(crate) fn get(&self, name: &str) -> Option<&'p str> {
+        self.0
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+/// The headers announcing the deprecation of a route: `Deprecation`, as
+/// proposed in draft-ietf-httpapi-deprecation-header, and optionally `Sunset`
+/// (RFC 8594) and a `Link` to documentation of the deprecation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Deprecation(Vec<(HeaderName, HeaderValue)>);
+
+impl Deprecation {
+    /// Returns the headers configured in `config`. Fails if a configured
+    /// value is not a valid header value.
+    pub(crate) fn from_config(config: &RouteDeprecation) -> Result<Self, String> {
+        let value = |name: &str, value: &str| {
+            HeaderValue::from_str(value)
+                .map_err(|_| format!("Invalid {} value {:?} for {}.", name, value, config.route))
+        };
+        let mut headers = vec![(
+            HeaderName::from_static("deprecation"),
+            value(
+                "deprecation",
+                config.deprecation.as_deref().unwrap_or("true"),
+            )?,
+        )];
+        if let Some(sunset) = &config.sunset {
+            headers.push((HeaderName::from_static("sunset"), value("sunset", sunset)?));
+        }
+        if let Some(link) = &config.link {
+            headers.push((
+                hyper::header::LINK,
+                value("link", &format!("<{}>; rel=\"deprecation\"", link))?,
+            ));
+        }
+        Ok(Self(headers))
+    }
+
+    /// Adds the headers to `headers`, keeping `Link` headers already present.
+    pub(crate) fn add_headers(&self, headers: &mut HeaderMap) {
+        for (name, value) in &self.0 {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Route {
+    method: Method,
+    pattern_str: &'static str,
+    pattern: Pattern,
+    api_req_type: ApiReqType,
+    api_version: &'static str,
+    deprecation: Option<Deprecation>,
+}
+
+impl Route {
+    pub(crate) fn api_req_type(&self) -> ApiReqType {
+        self.api_req_type
+    }
+
+    /// The API version of the route, or `NO_API_VERSION` if it is not under
+    /// `/api`.
+    pub(crate) fn api_version(&self) -> &'static str {
+        self.api_version
+    }
+
+    /// The headers announcing the deprecation of the route, if deprecated.
+    pub(crate) fn deprecation(&self) -> Option<&Deprecation> {
+        self.deprecation.as_ref()
+    }
+}
+
+/// The result of looking up a request in a `RouteTable`.
+pub(crate) enum RouteMatch<'r, 'p, H> {
+    /// The route of the request and its handler, with the parameters of its
+    /// path.
+    Found(&'r Route, &'r H, PathParams<'p>),
+    /// The path of the request is routed, but not for its method. Holds the
+    /// methods it is routed for.
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+/// A table of routes, cheap to clone if the handlers are. The handlers are
+/// not shared between clones, as endpoint services can be cloned but not
+/// shared between threads.
+#[derive(Clone)]
+pub(crate) struct RouteTable<H> {
+    routes: Arc<Vec<Route>>,
+    handlers: Vec<H>,
+}
+
+impl<H> Default for RouteTable<H> {
+    fn default() -> Self {
+        Self {
+            routes: Arc::new(vec![]),
+            handlers: vec![],
+        }
+    }
+}
+
+impl<H> RouteTable<H> {
+    /// Adds a route for `method` requests to paths matching `pattern`.
+    pub(crate) fn route(
+        mut self,
+        method: Method,
+        pattern: &'static str,
+        api_req_type: ApiReqType,
+        handler: H,
+    ) -> Self {
+        let parsed = Pattern::parse(pattern);
+        Arc::make_mut(&mut self.routes).push(Route {
+            method,
+            pattern_str: pattern,
+            api_version: parsed.api_version(),
+            pattern: parsed,
+            api_req_type,
+            deprecation: None,
+        });
+        self.handlers.push(handler);
+        self
+    }
+
+    /// Marks the routes with `pattern`, for all methods, as deprecated.
+    /// Returns false if no route has that pattern.
+    pub(crate) fn deprecate(&mut self, pattern: &str, deprecation: Deprecation) -> bool {
+        let mut found = false;
+        for route in Arc::make_mut(&mut self.routes).iter_mut() {
+            if route.pattern_str == pattern {
+                route.deprecation = Some(deprecation.clone());
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// Returns the first route for `method` requests to `path`.
+    pub(crate) fn lookup<'p>(&self, method: &Method, path: &'p str) -> RouteMatch<'_, 'p, H> {
+        let mut allowed = vec![];
+        for (route, handler) in self.routes.iter().zip(&self.handlers) {
+            if let Some(params) = route.pattern.matches(path) {
+                if route.method == method {
+                    return RouteMatch::Found(route, handler, params);
+                }
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method.clone());
+                }
+            }
+        }
+        if allowed.is_empty() {
+            RouteMatch::NotFound
+        } else {
+            RouteMatch::MethodNotAllowed(allowed)
+        }
+    }
+
+    /// Returns the route for `method` requests to `path` if it is deprecated.
+    pub(crate) fn deprecated_route(&self, method: &Method, path: &str) -> Option<&Route> {
+        if self.routes.iter().all(|route| route.deprecation.is_none()) {
+            return None;
+        }
+        match self.lookup(method, path) {
+            RouteMatch::Found(route, _, _) => route.deprecation.as_ref().map(|_| route),
+            _ => None,
+        }
+    }
+
+    /// Returns the methods `path` is routed for, in the order of the routes.
+    /// `*` stands for the server as a whole, i.e. all routed methods.
+    pub(crate) fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let mut allowed = vec![];
+        for route in self.routes.iter() {
+            if (path == "*" || route.pattern.matches(path).is_some())
+                && !allowed.contains(&route.method)
+            {
+                allowed.push(route.method.clone());
+            }
+        }
+        allowed
+    }
+}
+
+/// Returns the value of the `Allow` header for a path routed for `allowed`.
+/// `OPTIONS` is always allowed, as it is answered for every routed path.
+pub(crate) fn allow_header(allowed: &[Method]) -> HeaderValue {
+    let methods: Vec<&str> = allowed
+        .iter()
+        .filter(|method| **method != Method::OPTIONS)
+        .chain(std::iter::once(&Method::OPTIONS))
+        .map(Method::as_str)
+        .collect();
+    HeaderValue::from_str(&methods.join(", ")).expect("Method names are valid header values.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes() -> RouteTable<&'static str> {
+        RouteTable::default()
+            .route(
+                Method::POST,
+                "/api/v2/canister/:canister_id/call",
+                ApiReqType::Call,
+                "call",
+            )
+            .route(Method::GET, "/api/v2/status", ApiReqType::Status, "status")
+            .route(
+                Method::GET,
+                "/api/v2/subnet/:subnet_id/delegation",
+                ApiReqType::Delegation,
+                "delegation",
+            )
+    }
+
+    fn found(method: Method, path: &str) -> Option<(&'static str, PathParams<'_>)> {
+        match routes().lookup(&method, path) {
+            RouteMatch::Found(_, handler, params) => Some((*handler, params)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn paths_are_matched_segment_by_segment() {
+        let (handler, params) = found(Method::POST, "/api/v2/canister/aaaaa-aa/call").unwrap();
+        assert_eq!(handler, "call");
+        assert_eq!(params.get("canister_id"), Some("aaaaa-aa"));
+        assert_eq!(params.get("subnet_id"), None);
+
+        let (handler, params) = found(Method::GET, "/api/v2/status").unwrap();
+        assert_eq!(handler, "status");
+        assert_eq!(params, PathParams::default());
+
+        assert!(found(Method::GET, "/api/v2/status/").is_none());
+        assert!(found(Method::POST, "/api/v2/canister/aaaaa-aa/call/more").is_none());
+        assert!(found(Method::POST, "api/v2/canister/aaaaa-aa/call").is_none());
+    }
+
+    #[test]
+    fn methods_allowed_for_a_path_are_returned() {
+        match routes().lookup(&Method::GET, "/api/v2/canister/aaaaa-aa/call") {
+            RouteMatch::MethodNotAllowed(allowed) => assert_eq!(allowed, vec![Method::POST]),
+            _ => panic!("Expected the method not to be allowed"),
+        }
+        assert!(matches!(
+            routes().lookup(&Method::GET, "/api/v3/status"),
+            RouteMatch::NotFound
+        ));
+    }
+
+    #[test]
+    fn api_versions_are_taken_from_the_patterns() {
+        let routes = routes().route(
+            Method::GET,
+            "/_/dashboard",
+            ApiReqType::Dashboard,
+            "dashboard",
+        );
+        let version = |method, path| match routes.lookup(&method, path) {
+            RouteMatch::Found(route, _, _) => route.api_version(),
+            _ => panic!("Expected {} to be routed", path),
+        };
+        assert_eq!(version(Method::GET, "/api/v2/status"), "v2");
+        assert_eq!(
+            version(Method::POST, "/api/v2/canister/aaaaa-aa/call"),
+            "v2"
+        );
+        assert_eq!(version(Method::GET, "/_/dashboard"), NO_API_VERSION);
+    }
+
+    #[test]
+    fn deprecated_routes_carry_their_headers() {
+        let deprecation = Deprecation::from_config(&RouteDeprecation {
+            route: "/api/v2/status".to_string(),
+            deprecation: None,
+            sunset: Some("Sat, 01 Jul 2023 00:00:00 GMT".to_string()),
+            link: Some("https://example.com/migrate-to-v3".to_string()),
+        })
+        .unwrap();
+        let mut routes = routes();
+        assert!(routes.deprecate("/api/v2/status", deprecation));
+        assert!(!routes.deprecate("/api/v3/status", Deprecation(vec![])));
+
+        let mut headers = HeaderMap::new();
+        match routes.lookup(&Method::GET, "/api/v2/status") {
+            RouteMatch::Found(route, _, _) => {
+                route.deprecation().unwrap().add_headers(&mut headers)
+            }
+            _ => panic!("Expected /api/v2/status to be routed"),
+        }
+        assert_eq!(headers["deprecation"], "true");
+        assert_eq!(headers["sunset"], "Sat, 01 Jul 2023 00:00:00 GMT");
+        assert_eq!(
+            headers[hyper::header::LINK],
+            "<https://example.com/migrate-to-v3>; rel=\"deprecation\""
+        );
+        match routes.lookup(&Method::GET, "/api/v2/subnet/aaaaa-aa/delegation") {
+            RouteMatch::Found(route, _, _) => assert!(route.deprecation().is_none()),
+            _ => panic!("Expected the delegation to be routed"),
+        }
+
+        assert!(Deprecation::from_config(&RouteDeprecation {
+            route: "/api/v2/status".to_string(),
+            deprecation: Some("true\n".to_string()),
+            sunset: None,
+            link: None,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn allowed_methods_are_listed_per_path() {
+        let routes = routes().route(Method::POST, "/api/v2/status", ApiReqType::Status, "status");
+        assert_eq!(
+            routes.allowed_methods("/api/v2/status"),
+            vec![Method::GET, Method::POST]
+        );
+        assert_eq!(
+            routes.allowed_methods("/api/v2/canister/aaaaa-aa/call"),
+            vec![Method::POST]
+        );
+        assert_eq!(routes.allowed_methods("*"), vec![Method::POST, Method::GET]);
+        assert!(routes.allowed_methods("/api/v3/status").is_empty());
+
+        assert_eq!(
+            allow_header(&routes.allowed_methods("/api/v2/status")),
+            "GET, POST, OPTIONS"
+        );
+        assert_eq!(allow_header(&[]), "OPTIONS");
+    }
+}
@@ -0,0 +1,157 @@
+//! Security headers of the responses of the dashboard and status endpoints,
+//! for operators exposing them to browsers over HTTPS.
+//!
+//! All these responses carry `X-Content-Type-Options: nosniff`, and a
+//! `Strict-Transport-Security` header if `hsts_max_age_seconds` is set. HTML
+//! responses, i.e. the dashboard itself, carry the configured
+//! `Content-Security-Policy` as well.
+
+use crate::{common::CONTENT_TYPE_HTML, EndpointService};
+use hyper::{
+    header::{self, HeaderValue},
+    Body, Response,
+};
+use ic_config::http_handler::Config;
+use ic_logger::{warn, ReplicaLogger};
+use tower::{util::BoxCloneService, ServiceBuilder};
+
+/// The headers added to the responses of the dashboard and status endpoints.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SecurityHeaders {
+    strict_transport_security: Option<HeaderValue>,
+    content_security_policy: Option<HeaderValue>,
+}
+
+impl SecurityHeaders {
+    /// Invalid headers in `config` are logged and left out.
+    pub(crate) fn new(config: &Config, log: &ReplicaLogger) -> Self {
+        let strict_transport_security = config.hsts_max_age_seconds.map(|max_age| {
+            HeaderValue::from_str(&format!("max-age={}", max_age)).expect("Invalid header value")
+        });
+        let content_security_policy = config
+            .dashboard_content_security_policy
+            .as_deref()
+            .and_then(|policy| match HeaderValue::from_str(policy) {
+                Ok(value) => Some(value),
+                Err(err) => {
+                    warn!(
+                        log,
+                        "Ignoring the dashboard content security policy {:?}, error = {}",
+                        policy,
+                        err
+                    );
+                    None
+                }
+            });
+        Self {
+            strict_transport_security,
+            content_security_policy,
+        }
+    }
+
+    /// Adds the headers to `response`.
+    pub(crate) fn apply(&self, response: &mut Response<Body>) {
+        let is_html = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map_or(false, |content_type| content_type == CONTENT_TYPE_HTML);
+        let headers = response.headers_mut();
+        headers.insert(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+        if let Some(value) = &self.strict_transport_security {
+            headers.insert(header::STRICT_TRANSPORT_SECURITY, value.clone());
+        }
+        if let (true, Some(value)) = (is_html, &self.content_security_policy) {
+            headers.insert(header::CONTENT_SECURITY_POLICY, value.clone());
+        }
+    }
+
+    /// Wraps `service`, adding the headers to its responses.
+    pub(crate) fn layer(&self, service: EndpointService) -> EndpointService {
+        let security_headers = self.clone();
+        BoxCloneService::new(
+            ServiceBuilder::new()
+                .map_response(move |mut response| {
+                    security_headers.apply(&mut response);
+                    response
+                })
+                .service(service),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::make_plaintext_response;
+    use hyper::StatusCode;
+    use ic_logger::replica_logger::no_op_logger;
+
+    fn html_response() -> Response<Body> {
+        let mut response = Response::new(Body::from("<html></html>"));
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(CONTENT_TYPE_HTML),
+        );
+        response
+    }
+
+    #[test]
+    fn headers_are_added() {
+        let config = Config {
+            hsts_max_age_seconds: Some(31_536_000),
+            dashboard_content_security_policy: Some("default-src 'self'".to_string()),
+            ..Config::default()
+        };
+        let security_headers = SecurityHeaders::new(&config, &no_op_logger());
+
+        let mut response = html_response();
+        security_headers.apply(&mut response);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+            "nosniff"
+        );
+        assert_eq!(
+            headers.get(header::STRICT_TRANSPORT_SECURITY).unwrap(),
+            "max-age=31536000"
+        );
+        assert_eq!(
+            headers.get(header::CONTENT_SECURITY_POLICY).unwrap(),
+            "default-src 'self'"
+        );
+
+        // The content security policy only applies to HTML.
+        let mut response = make_plaintext_response(StatusCode::OK, "ok".to_string());
+        security_headers.apply(&mut response);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+            "nosniff"
+        );
+        assert!(headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+        assert!(!headers.contains_key(header::CONTENT_SECURITY_POLICY));
+    }
+
+    #[test]
+    fn unset_and_invalid_headers_are_left_out() {
+        let config = Config {
+            hsts_max_age_seconds: None,
+            dashboard_content_security_policy: Some("default-src\n'self'".to_string()),
+            ..Config::default()
+        };
+        let security_headers = SecurityHeaders::new(&config, &no_op_logger());
+
+        let mut response = html_response();
+        security_headers.apply(&mut response);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+            "nosniff"
+        );
+        assert!(!headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+        assert!(!headers.contains_key(header::CONTENT_SECURITY_POLICY));
+    }
+}
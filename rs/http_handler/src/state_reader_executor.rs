@@ -6,7 +6,7 @@ use hyper::StatusCode;
 use ic_crypto_tree_hash::{LabeledTree, MixedHashTree};
 use ic_interfaces_state_manager::{Labeled, StateReader};
 use ic_replicated_state::ReplicatedState;
-use ic_types::consensus::certification::Certification;
+use ic_types::{consensus::certification::Certification, Height};
 use std::sync::{Arc, Mutex};
 use threadpool::ThreadPool;
 use tokio::sync::oneshot;
@@ -43,6 +43,17 @@ impl StateReaderExecutor {
         })
     }
 
+    /// Returns the height of the latest certified state. This is cheap enough
+    /// to not be offloaded to the executor threads.
+    pub fn latest_certified_height(&self) -> Height {
+        self.state_reader.latest_certified_height()
+    }
+
+    /// Returns the height of the latest state, certified or not.
+    pub fn latest_state_height(&self) -> Height {
+        self.state_reader.latest_state_height()
+    }
+
     pub async fn read_certified_state(
         &self,
         labeled_tree: &LabeledTree<()>,
@@ -1,17 +1,24 @@
 //! Module that deals with requests to /api/v2/status
 use crate::{common, state_reader_executor::StateReaderExecutor, EndpointService};
-use hyper::{Body, Response};
+use hyper::{
+    body::Bytes,
+    header::{self, HeaderValue},
+    Body, Response,
+};
 use ic_config::http_handler::Config;
 use ic_logger::ReplicaLogger;
 use ic_types::{
-    messages::{HttpStatusResponse, ReplicaHealthStatus},
+    messages::{CanisterRangesSummary, HttpStatusResponse, ReplicaHealthStatus},
     replica_version::REPLICA_BINARY_HASH,
-    ReplicaVersion, SubnetId,
+    time::current_time,
+    Height, ReplicaVersion, SubnetId, Time,
 };
+use prometheus::IntGauge;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::{Context, Poll};
+use std::time::Instant;
 use tower::{
     limit::concurrency::GlobalConcurrencyLimitLayer, util::BoxCloneService, BoxError, Service,
     ServiceBuilder,
@@ -22,29 +29,77 @@ use tower::{
 const IC_API_VERSION: &str = "0.18.0";
 const MAX_STATUS_CONCURRENT_REQUESTS: usize = 100;
 
+// The status, valid as long as the replica health status and the latest
+// certified height are the ones it was built for. The ingress queue depth it
+// reports may hence be up to a round old. The uptime is set per response, and
+// is not covered by the entity tag, which is hence weak.
+struct CachedStatus {
+    health_status: ReplicaHealthStatus,
+    certified_height: Height,
+    status: HttpStatusResponse,
+    etag: HeaderValue,
+}
+
+/// The time the replica started, from which its uptime is measured. Taken
+/// at the start of the replica's `main`, so that the uptime covers setting up
+/// the replica, not just the HTTP handler.
+#[derive(Clone, Copy)]
+pub struct BootTime {
+    time: Time,
+    instant: Instant,
+}
+
+impl BootTime {
+    pub fn now() -> Self {
+        Self {
+            time: current_time(),
+            instant: Instant::now(),
+        }
+    }
+
+    pub(crate) fn time(&self) -> Time {
+        self.time
+    }
+
+    fn uptime_seconds(&self) -> u64 {
+        self.instant.elapsed().as_secs()
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct StatusService {
     log: ReplicaLogger,
     config: Config,
+    subnet_id: SubnetId,
     nns_subnet_id: SubnetId,
     state_reader_executor: StateReaderExecutor,
     replica_health_status: Arc<RwLock<ReplicaHealthStatus>>,
+    ingress_queue_depth: IntGauge,
+    boot_time: BootTime,
+    cache: Arc<Mutex<Option<CachedStatus>>>,
 }
 
 impl StatusService {
     pub(crate) fn new_service(
         log: ReplicaLogger,
         config: Config,
+        subnet_id: SubnetId,
         nns_subnet_id: SubnetId,
         state_reader_executor: StateReaderExecutor,
         replica_health_status: Arc<RwLock<ReplicaHealthStatus>>,
+        ingress_queue_depth: IntGauge,
+        boot_time: BootTime,
     ) -> EndpointService {
         let base_service = Self {
             log,
             config,
+            subnet_id,
             nns_subnet_id,
             state_reader_executor,
             replica_health_status,
+            ingress_queue_depth,
+            boot_time,
+            cache: Arc::new(Mutex::new(None)),
         };
         BoxCloneService::new(
             ServiceBuilder::new()
@@ -68,10 +123,23 @@ impl Service<Body> for StatusService {
 
     fn call(&mut self, _unused: Body) -> Self::Future {
         let log = self.log.clone();
+        let subnet_id = self.subnet_id;
         let nns_subnet_id = self.nns_subnet_id;
         let root_key_status = self.config.show_root_key_in_status;
+        let canister_ranges_status = self.config.show_canister_ranges_in_status;
         let state_reader_executor = self.state_reader_executor.clone();
         let replica_health_status = self.replica_health_status.read().unwrap().clone();
+        let ingress_queue_depth = self.ingress_queue_depth.get().max(0) as u64;
+        let boot_time = self.boot_time;
+        let certified_height = state_reader_executor.latest_certified_height();
+        let cache = Arc::clone(&self.cache);
+        if let Some(cached) = cache.lock().unwrap().as_ref().filter(|cached| {
+            cached.health_status == replica_health_status
+                && cached.certified_height == certified_height
+        }) {
+            let response = status_response(&cached.status, &boot_time, cached.etag.clone());
+            return Box::pin(async move { Ok(response) });
+        }
         Box::pin(async move {
             // The root key is the public key of this Internet Computer instance,
             // and is the public key of the root (i.e. NNS) subnet.
@@ -80,15 +148,99 @@ impl Service<Body> for StatusService {
             } else {
                 None
             };
-            let response = HttpStatusResponse {
+            let canister_ranges = if canister_ranges_status {
+                get_canister_ranges_summary(&state_reader_executor, &subnet_id).await
+            } else {
+                None
+            };
+            let status = HttpStatusResponse {
                 ic_api_version: IC_API_VERSION.to_string(),
                 root_key,
                 impl_version: Some(ReplicaVersion::default().to_string()),
                 impl_hash: REPLICA_BINARY_HASH.get().map(|s| s.to_string()),
-                replica_health_status: Some(replica_health_status),
+                replica_health_status: Some(replica_health_status.clone()),
+                canister_ranges,
+                ingress_queue_depth: Some(ingress_queue_depth),
+                boot_time: Some(boot_time.time()),
+                uptime_seconds: None,
             };
 
-            Ok(common::cbor_response(&response))
+            let etag = HeaderValue::from_str(&format!(
+                "W/{}",
+                common::entity_tag(&common::into_cbor(&status))
+            ))
+            .expect("An entity tag is a valid header value.");
+            let response = status_response(&status, &boot_time, etag.clone());
+            *cache.lock().unwrap() = Some(CachedStatus {
+                health_status: replica_health_status,
+                certified_height,
+                status,
+                etag,
+            });
+            Ok(response)
         })
     }
 }
+
+// Returns the response reporting `status`, with the uptime as of now.
+fn status_response(
+    status: &HttpStatusResponse,
+    boot_time: &BootTime,
+    etag: HeaderValue,
+) -> Response<Body> {
+    let status = HttpStatusResponse {
+        uptime_seconds: Some(boot_time.uptime_seconds()),
+        ..status.clone()
+    };
+    let body = Bytes::from(common::into_cbor(&status));
+    let mut response = common::cbor_body_response(Body::from(body));
+    response.headers_mut().insert(header::ETAG, etag);
+    response
+}
+
+// Summarizes the canister ranges assigned to `subnet_id` and the canisters
+// hosted, as of the latest certified state.
+async fn get_canister_ranges_summary(
+    state_reader_executor: &StateReaderExecutor,
+    subnet_id: &SubnetId,
+) -> Option<CanisterRangesSummary> {
+    let state = common::get_latest_certified_state(state_reader_executor).await?;
+    let routing_table = &state.metadata.network_topology.routing_table;
+    Some(CanisterRangesSummary {
+        num_ranges: routing_table.ranges(*subnet_id).len() as u64,
+        num_canisters: state.num_canisters() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn uptime_is_set_per_response() {
+        let status = HttpStatusResponse {
+            ic_api_version: IC_API_VERSION.to_string(),
+            root_key: None,
+            impl_version: None,
+            impl_hash: None,
+            replica_health_status: Some(ReplicaHealthStatus::Healthy),
+            canister_ranges: None,
+            ingress_queue_depth: Some(0),
+            boot_time: Some(current_time()),
+            uptime_seconds: None,
+        };
+        let etag = HeaderValue::from_static("W/\"0123456789abcdef\"");
+        for uptime_seconds in [5, 65] {
+            let boot_time = BootTime {
+                time: current_time(),
+                instant: Instant::now() - Duration::from_secs(uptime_seconds),
+            };
+            let response = status_response(&status, &boot_time, etag.clone());
+            assert_eq!(response.headers()[header::ETAG], etag);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let reported: HttpStatusResponse = serde_cbor::from_slice(&body).unwrap();
+            assert_eq!(reported.uptime_seconds, Some(uptime_seconds));
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! The time of the subnet as seen from this node, against which the expiry of
+//! requests is validated.
+//!
+//! The ingress expiry of a request is eventually checked against the time of
+//! the block that includes it. If the system time of this node is skewed
+//! relative to the block time, requests are rejected (or accepted) here that
+//! consensus would accept (or reject). The [`SubnetClock`] corrects the
+//! system time by the skew estimated from the time of the latest executed
+//! batch, capped at `MAX_SKEW_CORRECTION` so that a stalled subnet does not
+//! turn back the clock arbitrarily.
+
+use crate::{
+    metrics::HttpHandlerMetrics, state_reader_executor::StateReaderExecutor, ReplicaHealthStatus,
+};
+use ic_constants::PERMITTED_DRIFT_AT_VALIDATOR;
+use ic_types::{
+    time::{current_time, MonotonicTime, Skew, SkewEstimator},
+    Time,
+};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+// Interval between two samples of the batch time.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+// Number of samples the skew is estimated from, i.e. over the last ~5 minutes.
+const SAMPLE_WINDOW: usize = 60;
+// The maximum correction of the system time.
+const MAX_SKEW_CORRECTION: Duration = PERMITTED_DRIFT_AT_VALIDATOR;
+
+/// The system time, corrected by the estimated skew relative to the time of
+/// the subnet.
+#[derive(Clone)]
+pub(crate) struct SubnetClock {
+    estimator: Arc<RwLock<SkewEstimator>>,
+}
+
+impl Default for SubnetClock {
+    fn default() -> Self {
+        Self {
+            estimator: Arc::new(RwLock::new(SkewEstimator::new(SAMPLE_WINDOW))),
+        }
+    }
+}
+
+impl SubnetClock {
+    /// Returns the current time of the subnet. The system time until the skew
+    /// was sampled.
+    pub(crate) fn now(&self) -> Time {
+        self.correct(current_time())
+    }
+
+    fn correct(&self, local: Time) -> Time {
+        match self.skew() {
+            Some(skew) => skew.to_remote(local),
+            None => local,
+        }
+    }
+
+    /// Returns the estimated skew of the subnet time relative to the system
+    /// time, capped at `MAX_SKEW_CORRECTION`.
+    pub(crate) fn skew(&self) -> Option<Skew> {
+        self.estimator
+            .read()
+            .unwrap()
+            .skew()
+            .map(|skew| skew.clamp(MAX_SKEW_CORRECTION))
+    }
+
+    fn observe(&self, local: Time, batch_time: Time) {
+        self.estimator.write().unwrap().observe(local, batch_time);
+    }
+
+    /// Spawns a task that periodically samples the time of the latest batch,
+    /// once the replica is healthy, i.e. caught up with the subnet.
+    pub(crate) fn spawn_sampling_task(
+        &self,
+        metrics: HttpHandlerMetrics,
+        state_reader_executor: StateReaderExecutor,
+        health_status: Arc<RwLock<ReplicaHealthStatus>>,
+        rt_handle: &tokio::runtime::Handle,
+    ) {
+        let clock = self.clone();
+        // The batch time is not supposed to go back. Should it, samples are
+        // clamped rather than turning back the estimated skew.
+        let batch_time = MonotonicTime::with_regression_hook({
+            let regressions = metrics.batch_time_regressions_total.clone();
+            move |_| regressions.inc()
+        });
+        rt_handle.spawn(async move {
+            loop {
+                sleep(SAMPLE_INTERVAL).await;
+                if *health_status.read().unwrap() != ReplicaHealthStatus::Healthy {
+                    continue;
+                }
+                if let Ok(state) = state_reader_executor.get_latest_state().await {
+                    clock.observe(
+                        current_time(),
+                        batch_time.observe(state.get_ref().metadata.batch_time),
+                    );
+                }
+                if let Some(skew) = clock.skew() {
+                    metrics.observe_clock_skew(skew);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_types::time::UNIX_EPOCH;
+
+    #[test]
+    fn time_is_corrected_by_capped_skew() {
+        let clock = SubnetClock::default();
+        let local = UNIX_EPOCH + Duration::from_secs(1_000);
+        assert_eq!(clock.correct(local), local);
+
+        clock.observe(local, local - Duration::from_secs(2));
+        assert_eq!(clock.correct(local), local - Duration::from_secs(2));
+
+        for _ in 0..SAMPLE_WINDOW {
+            clock.observe(local, local + Duration::from_secs(600));
+        }
+        assert_eq!(clock.correct(local), local + MAX_SKEW_CORRECTION);
+    }
+}
@@ -0,0 +1,287 @@
+//! Module that deals with requests to /api/v2/subnet/.../public_key
+//!
+//! Serves the threshold public key of a subnet, as recorded in the network
+//! topology of the certified state, so that third-party verifiers can
+//! bootstrap the validation of certificates without parsing the status
+//! response. The key is served DER-encoded by default, and PEM-encoded if the
+//! `Accept` header asks for it.
+//!
+//! The key is not a trust anchor: a replica could serve any key. The
+//! certificate of its `/subnet/<subnet_id>/public_key` path, with the
+//! delegation from the NNS if any, is sent in the `x-ic-certificate` header,
+//! and the key must only be used once the certificate was verified against
+//! the root public key of the IC.
+use crate::{
+    common::{get_cors_headers, into_cbor, make_plaintext_response},
+    state_reader_executor::StateReaderExecutor,
+    EndpointService, ReplicaHealthStatus,
+};
+use hyper::{
+    header::{self, HeaderValue},
+    Body, HeaderMap, Response, StatusCode,
+};
+use ic_crypto_tree_hash::{sparse_labeled_tree_from_paths, Label, Path};
+use ic_types::{
+    messages::{Blob, Certificate, CertificateDelegation},
+    PrincipalId, SubnetId,
+};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tower::{
+    limit::concurrency::GlobalConcurrencyLimitLayer, service_fn, util::BoxCloneService, BoxError,
+    ServiceBuilder,
+};
+
+const CONTENT_TYPE_DER: &str = "application/octet-stream";
+const CONTENT_TYPE_PEM: &str = "application/x-pem-file";
+
+/// The header holding the base64-encoded CBOR certificate of the key.
+const CERTIFICATE_HEADER: &str = "x-ic-certificate";
+
+/// The length of the base64 lines of PEM-encoded keys, as per RFC 7468.
+const PEM_LINE_LENGTH: usize = 64;
+
+/// The encodings the public key of a subnet is served in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PublicKeyFormat {
+    Der,
+    Pem,
+}
+
+impl PublicKeyFormat {
+    /// Returns the format requested by the `Accept` header: PEM if
+    /// `application/x-pem-file` is listed before `application/octet-stream`,
+    /// and DER otherwise.
+    pub(crate) fn from_accept_header(headers: &HeaderMap) -> Self {
+        headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|media_range| media_range.split(';').next())
+            .map(str::trim)
+            .find_map(|media_type| {
+                if media_type.eq_ignore_ascii_case(CONTENT_TYPE_PEM) {
+                    Some(PublicKeyFormat::Pem)
+                } else if media_type.eq_ignore_ascii_case(CONTENT_TYPE_DER) {
+                    Some(PublicKeyFormat::Der)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(PublicKeyFormat::Der)
+    }
+}
+
+/// Reads the public keys of subnets, and their certificates, from the
+/// certified state.
+#[derive(Clone)]
+pub(crate) struct SubnetPublicKeyReader {
+    health_status: Arc<RwLock<ReplicaHealthStatus>>,
+    delegation_from_nns: Arc<RwLock<Option<CertificateDelegation>>>,
+    state_reader_executor: StateReaderExecutor,
+    concurrency_limit: Option<GlobalConcurrencyLimitLayer>,
+}
+
+impl SubnetPublicKeyReader {
+    /// Returns a reader whose requests are limited by `concurrency_limit`,
+    /// if any, like the `read_state` requests they compete with.
+    pub(crate) fn new(
+        health_status: Arc<RwLock<ReplicaHealthStatus>>,
+        delegation_from_nns: Arc<RwLock<Option<CertificateDelegation>>>,
+        state_reader_executor: StateReaderExecutor,
+        concurrency_limit: Option<GlobalConcurrencyLimitLayer>,
+    ) -> Self {
+        Self {
+            health_status,
+            delegation_from_nns,
+            state_reader_executor,
+            concurrency_limit,
+        }
+    }
+
+    /// Returns the service answering a public key request for the subnet
+    /// with the textual id `subnet_id` in `format`. Requests beyond the
+    /// concurrency limit are shed.
+    pub(crate) fn service(&self, subnet_id: &str, format: PublicKeyFormat) -> EndpointService {
+        let reader = self.clone();
+        let subnet_id = subnet_id.to_string();
+        BoxCloneService::new(
+            ServiceBuilder::new()
+                .option_layer(self.concurrency_limit.clone())
+                .service(service_fn(move |_body: Body| {
+                    let reader = reader.clone();
+                    let subnet_id = subnet_id.clone();
+                    async move { Ok::<_, BoxError>(reader.response(&subnet_id, format).await) }
+                })),
+        )
+    }
+
+    /// Returns the response to a public key request for the subnet with the
+    /// textual id `subnet_id`, in `format`.
+    async fn response(&self, subnet_id: &str, format: PublicKeyFormat) -> Response<Body> {
+        if *self.health_status.read().unwrap() != ReplicaHealthStatus::Healthy {
+            return make_plaintext_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Replica is starting. Check the /api/v2/status for more information.".to_string(),
+            );
+        }
+        let subnet_id = match PrincipalId::from_str(subnet_id) {
+            Ok(id) => SubnetId::from(id),
+            Err(err) => {
+                return make_plaintext_response(
+                    StatusCode::BAD_REQUEST,
+                    format!("Could not parse Subnet ID: {}.", err),
+                )
+            }
+        };
+        let delegation_from_nns = self.delegation_from_nns.read().unwrap().clone();
+
+        let mut paths = vec![Path::new(vec![
+            Label::from("subnet"),
+            Label::from(subnet_id.get_ref().as_slice()),
+            Label::from("public_key"),
+        ])];
+        let labeled_tree = sparse_labeled_tree_from_paths(&mut paths);
+        let (state, tree, certification) = match self
+            .state_reader_executor
+            .read_certified_state(&labeled_tree)
+            .await
+        {
+            Ok(Some(certified_state)) => certified_state,
+            Ok(None) => {
+                return make_plaintext_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Certified state is not available yet. Please try again...".to_string(),
+                )
+            }
+            Err(e) => return make_plaintext_response(e.status, e.message),
+        };
+        let public_key = match state.metadata.network_topology.subnets.get(&subnet_id) {
+            Some(subnet) if !subnet.public_key.is_empty() => subnet.public_key.clone(),
+            // The network topology is empty until the first batch is
+            // executed.
+            _ if state.metadata.network_topology.subnets.is_empty() => {
+                return make_plaintext_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Replica is starting. Check the /api/v2/status for more information."
+                        .to_string(),
+                )
+            }
+            _ => {
+                return make_plaintext_response(
+                    StatusCode::NOT_FOUND,
+                    format!("Unknown subnet {}.", subnet_id),
+                )
+            }
+        };
+        let signature = certification.signed.signature.signature.get().0;
+        let certificate = into_cbor(&Certificate {
+            tree,
+            signature: Blob(signature),
+            delegation: delegation_from_nns,
+        });
+        public_key_response(&public_key, &certificate, format)
+    }
+}
+
+// Returns a response with the DER-encoded `public_key` in `format`, and its
+// CBOR-encoded `certificate`.
+fn public_key_response(
+    public_key: &[u8],
+    certificate: &[u8],
+    format: PublicKeyFormat,
+) -> Response<Body> {
+    let (body, content_type) = match format {
+        PublicKeyFormat::Der => (public_key.to_vec(), CONTENT_TYPE_DER),
+        PublicKeyFormat::Pem => (to_pem(public_key).into_bytes(), CONTENT_TYPE_PEM),
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.headers_mut() = get_cors_headers();
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+        .headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("accept"));
+    response.headers_mut().insert(
+        CERTIFICATE_HEADER,
+        HeaderValue::from_str(&base64::encode(certificate))
+            .expect("base64 is a valid header value"),
+    );
+    response
+}
+
+// Encodes the DER-encoded `public_key` as PEM.
+fn to_pem(public_key: &[u8]) -> String {
+    let encoded = base64::encode(public_key);
+    let mut pem = String::from("-----BEGIN PUBLIC KEY-----\n");
+    for line in encoded.as_bytes().chunks(PEM_LINE_LENGTH) {
+        // Base64 is ASCII.
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str("-----END PUBLIC KEY-----\n");
+    pem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The DER encoding of a BLS12-381 public key.
+    const PUBLIC_KEY_DER: &str = "308182301d060d2b0601040182dc7c0503010201060c2b0601040182dc7c05030201036100a398dd093da937ac09168b198e016ff590e707f186251c6b885b54845f3a43e536d5d283f0077dfe5021c9163e27dec9107f4bd0358e38355dd28fe6549e99833a5554eb1a18d2854c07a9599d38127ca1fa5bdbea95ff6a69bf173edce141bc";
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn der_is_served_unless_pem_is_preferred() {
+        assert_eq!(
+            PublicKeyFormat::from_accept_header(&HeaderMap::new()),
+            PublicKeyFormat::Der
+        );
+        assert_eq!(
+            PublicKeyFormat::from_accept_header(&accept("*/*")),
+            PublicKeyFormat::Der
+        );
+        assert_eq!(
+            PublicKeyFormat::from_accept_header(&accept(
+                "application/x-pem-file;q=0.9, application/octet-stream"
+            )),
+            PublicKeyFormat::Pem
+        );
+        assert_eq!(
+            PublicKeyFormat::from_accept_header(&accept(
+                "application/octet-stream, application/x-pem-file"
+            )),
+            PublicKeyFormat::Der
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_are_encoded_in_the_requested_format_with_their_certificate() {
+        let public_key = hex::decode(PUBLIC_KEY_DER).unwrap();
+
+        let response = public_key_response(&public_key, &[0xd9, 0xd9, 0xf7], PublicKeyFormat::Der);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], CONTENT_TYPE_DER);
+        assert_eq!(response.headers()[CERTIFICATE_HEADER], "2dn3");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, public_key);
+
+        let response = public_key_response(&public_key, &[0xd9, 0xd9, 0xf7], PublicKeyFormat::Pem);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], CONTENT_TYPE_PEM);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            body,
+            "-----BEGIN PUBLIC KEY-----\n\
+             MIGCMB0GDSsGAQQBgtx8BQMBAgEGDCsGAQQBgtx8BQMCAQNhAKOY3Qk9qTesCRaL\n\
+             GY4Bb/WQ5wfxhiUca4hbVIRfOkPlNtXSg/AHff5QIckWPifeyRB/S9A1jjg1XdKP\n\
+             5lSemYM6VVTrGhjShUwHqVmdOBJ8ofpb2+qV/2ppvxc+3OFBvA==\n\
+             -----END PUBLIC KEY-----\n"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! Module that tracks the registry version at which TLS handshakes are
+//! performed, so that a rotation of the node's TLS key and certificate in the
+//! registry takes effect for new connections without a restart.
+use crate::metrics::HttpHandlerMetrics;
+use ic_interfaces::registry::RegistryClient;
+use ic_logger::{info, warn, ReplicaLogger};
+use ic_registry_client_helpers::crypto::CryptoRegistry;
+use ic_types::{NodeId, RegistryVersion};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time::sleep;
+
+// How often the registry is checked for a new TLS certificate of this node.
+// Handshakes don't wait for the check, see `TlsConfigWatcher::registry_version`.
+const TLS_CONFIG_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+// The registry version and the certificate of this node at that version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct TlsConfig {
+    registry_version: RegistryVersion,
+    certificate_der: Vec<u8>,
+}
+
+/// Outcome of checking the latest TLS certificate of this node.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum TlsConfigUpdate {
+    /// The certificate is the first one seen.
+    Initial,
+    /// The certificate is unchanged, only the registry version advanced.
+    Unchanged,
+    /// The certificate differs from the previous one.
+    Rotated,
+}
+
+/// The TLS configuration used for handshakes on new connections. Cloning
+/// shares the configuration.
+///
+/// Handshakes are performed at the newer of the registry version at which the
+/// certificate of this node was last read, and the latest registry version.
+/// The former keeps handshakes at a version at which the certificate was
+/// readable while the registry client lags behind, the latter makes a rotation
+/// take effect as soon as the registry client sees it, rather than with the
+/// next refresh. Established connections are not affected by a rotation.
+#[derive(Clone, Default)]
+pub(crate) struct TlsConfigWatcher {
+    config: Arc<RwLock<Option<TlsConfig>>>,
+}
+
+impl TlsConfigWatcher {
+    /// Returns the registry version to perform TLS handshakes at, given the
+    /// latest registry version `latest_version`.
+    pub(crate) fn registry_version(&self, latest_version: RegistryVersion) -> RegistryVersion {
+        self.config
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(latest_version, |config| {
+                config.registry_version.max(latest_version)
+            })
+    }
+
+    /// Records `certificate_der` as the certificate of this node at
+    /// `registry_version`.
+    pub(crate) fn update(
+        &self,
+        registry_version: RegistryVersion,
+        certificate_der: Vec<u8>,
+    ) -> TlsConfigUpdate {
+        let mut config = self.config.write().unwrap();
+        let update = match config.as_ref() {
+            None => TlsConfigUpdate::Initial,
+            Some(current) if current.certificate_der == certificate_der => {
+                TlsConfigUpdate::Unchanged
+            }
+            Some(_) => TlsConfigUpdate::Rotated,
+        };
+        *config = Some(TlsConfig {
+            registry_version,
+            certificate_der,
+        });
+        update
+    }
+
+    /// Spawns a task that periodically checks the registry for a new TLS
+    /// certificate of `node_id` and updates the configuration.
+    pub(crate) fn spawn_refresh_task(
+        &self,
+        log: ReplicaLogger,
+        metrics: HttpHandlerMetrics,
+        node_id: NodeId,
+        registry_client: Arc<dyn RegistryClient>,
+        rt_handle: &tokio::runtime::Handle,
+    ) {
+        let watcher = self.clone();
+        rt_handle.spawn(async move {
+            loop {
+                let registry_version = registry_client.get_latest_version();
+                match registry_client.get_tls_certificate(node_id, registry_version) {
+                    Ok(Some(certificate)) => {
+                        match watcher.update(registry_version, certificate.certificate_der) {
+                            TlsConfigUpdate::Rotated => {
+                                info!(
+                                    log,
+                                    "Rotated TLS certificate at registry version {}",
+                                    registry_version
+                                );
+                                metrics.tls_certificate_rotations_total.inc();
+                            }
+                            TlsConfigUpdate::Initial | TlsConfigUpdate::Unchanged => (),
+                        }
+                        metrics
+                            .tls_registry_version
+                            .set(registry_version.get() as i64);
+                    }
+                    Ok(None) => warn!(
+                        every_n_seconds => 60,
+                        log,
+                        "No TLS certificate of node {} at registry version {}",
+                        node_id,
+                        registry_version
+                    ),
+                    Err(err) => warn!(
+                        every_n_seconds => 60,
+                        log,
+                        "Failed to read TLS certificate of node {} at registry version {}: {}",
+                        node_id,
+                        registry_version,
+                        err
+                    ),
+                }
+                sleep(TLS_CONFIG_REFRESH_INTERVAL).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshakes_use_the_newer_registry_version() {
+        let watcher = TlsConfigWatcher::default();
+        assert_eq!(
+            watcher.registry_version(RegistryVersion::from(7)),
+            RegistryVersion::from(7)
+        );
+
+        assert_eq!(
+            watcher.update(RegistryVersion::from(3), vec![1]),
+            TlsConfigUpdate::Initial
+        );
+        assert_eq!(
+            watcher.update(RegistryVersion::from(5), vec![1]),
+            TlsConfigUpdate::Unchanged
+        );
+        // The latest version is used once the registry client is ahead of the
+        // last refresh, so that a rotation takes effect without waiting for it.
+        assert_eq!(
+            watcher.registry_version(RegistryVersion::from(7)),
+            RegistryVersion::from(7)
+        );
+        // The version of the certificate is kept if the registry client lags
+        // behind.
+        assert_eq!(
+            watcher.registry_version(RegistryVersion::from(4)),
+            RegistryVersion::from(5)
+        );
+
+        assert_eq!(
+            watcher.clone().update(RegistryVersion::from(6), vec![2]),
+            TlsConfigUpdate::Rotated
+        );
+        assert_eq!(
+            watcher.registry_version(RegistryVersion::from(2)),
+            RegistryVersion::from(6)
+        );
+    }
+}
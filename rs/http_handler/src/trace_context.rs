@@ -0,0 +1,119 @@
+//! Module that extracts the W3C trace context of incoming requests.
+//!
+//! Boundary nodes (and agents) may send the `traceparent` and `tracestate`
+//! headers defined in https://www.w3.org/TR/trace-context/. A server span is
+//! started for every request as a child of the received context, and attached
+//! to the request future, so that downstream services and logs can refer to
+//! it through `opentelemetry::Context::current()`. Spans are recorded by the
+//! globally installed tracer provider, if any.
+use hyper::{HeaderMap, Method};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, TextMapPropagator},
+    sdk::propagation::TraceContextPropagator,
+    trace::{SpanKind, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+
+const TRACER_NAME: &str = "ic-http-handler";
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Returns the trace context propagated in the `traceparent` and `tracestate`
+/// headers. Missing or malformed headers result in an empty context.
+pub(crate) fn extract_trace_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+/// Starts the server span of a request, as a child of the propagated trace
+/// context, and returns a context holding it. The span ends when the returned
+/// context is dropped.
+pub(crate) fn start_request_span(headers: &HeaderMap, method: &Method, path: &str) -> Context {
+    let parent = extract_trace_context(headers);
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder("http_request")
+        .with_kind(SpanKind::Server)
+        .with_attributes(vec![
+            KeyValue::new("http.method", method.to_string()),
+            KeyValue::new("http.target", path.to_string()),
+        ])
+        .start_with_context(&tracer, &parent);
+    parent.with_span(span)
+}
+
+/// Returns the trace id of the current context, for inclusion in logs, if
+/// the request is part of a trace.
+pub(crate) fn current_trace_id() -> Option<String> {
+    let context = Context::current();
+    let span_context = context.span().span_context().clone();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use opentelemetry::trace::{FutureExt, TraceId};
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    fn headers(traceparent: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", HeaderValue::from_static(traceparent));
+        headers.insert("tracestate", HeaderValue::from_static("congo=t61rcWkgMzE"));
+        headers
+    }
+
+    #[test]
+    fn extracts_valid_trace_context() {
+        let context = extract_trace_context(&headers(TRACEPARENT));
+        let span = context.span();
+        let span_context = span.span_context();
+        assert!(span_context.is_remote());
+        assert_eq!(
+            span_context.trace_id(),
+            TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap()
+        );
+        assert_eq!(span_context.trace_state().get("congo"), Some("t61rcWkgMzE"));
+    }
+
+    #[test]
+    fn ignores_malformed_trace_context() {
+        for traceparent in [
+            "",
+            "00-xyz-b7ad6b7169203331-01",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        ] {
+            let context = extract_trace_context(&headers(traceparent));
+            assert!(!context.span().span_context().is_valid());
+        }
+        assert!(!extract_trace_context(&HeaderMap::new())
+            .span()
+            .span_context()
+            .is_valid());
+    }
+
+    #[tokio::test]
+    async fn request_span_is_current_in_request_future() {
+        let context = start_request_span(&headers(TRACEPARENT), &Method::POST, "/api/v2/status");
+        let trace_id = async { current_trace_id() }.with_context(context).await;
+        assert_eq!(
+            trace_id,
+            Some("0af7651916cd43dd8448eb211c80319c".to_string())
+        );
+        assert_eq!(current_trace_id(), None);
+    }
+}
@@ -14,16 +14,26 @@ pub(crate) enum ApiReqType {
     Query,
     /// `read_state`
     ReadState,
+    /// `canister/<canister_id>/info`
+    CanisterInfo,
+    /// `subnet/<subnet_id>/delegation`
+    Delegation,
+    /// `subnet/<subnet_id>/public_key`
+    SubnetPublicKey,
     /// In case an error occurred and the request type is unknown.
     CatchUpPackage,
     Status,
     Dashboard,
+    /// A static asset of the dashboard, under `/_/assets`.
+    DashboardAsset,
     RedirectToDashboard,
     Options,
     PprofHome,
     PprofProfile,
     PprofFlamegraph,
     InvalidArgument,
+    /// Rejected for exceeding the header limits, before being routed.
+    HeadersTooLarge,
 }
 
 #[derive(Debug, Copy, Clone, IntoStaticStr)]
@@ -45,11 +55,21 @@ pub(crate) fn to_legacy_request_type(req_type: ApiReqType) -> &'static str {
 #[strum(serialize_all = "snake_case")]
 pub(crate) enum ConnectionError {
     TlsHandshake,
+    /// The TLS handshake did not complete within the configured timeout.
+    TlsHandshakeTimeout,
     Accept,
     Peek,
     PeekTimeout,
 }
 
+/// Why a connection is drained.
+#[derive(Clone, Copy, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum DrainReason {
+    MaxLifetime,
+    Shutdown,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -59,13 +79,23 @@ mod tests {
         assert_eq!(StaticStr::from(ApiReqType::Call), "call");
         assert_eq!(StaticStr::from(ApiReqType::Query), "query");
         assert_eq!(StaticStr::from(ApiReqType::ReadState), "read_state");
+        assert_eq!(StaticStr::from(ApiReqType::CanisterInfo), "canister_info");
         assert_eq!(StaticStr::from(ApiReqType::Status), "status");
+        assert_eq!(StaticStr::from(ApiReqType::Delegation), "delegation");
+        assert_eq!(
+            StaticStr::from(ApiReqType::SubnetPublicKey),
+            "subnet_public_key"
+        );
         assert_eq!(
             StaticStr::from(ApiReqType::CatchUpPackage),
             "catch_up_package"
         );
         assert_eq!(StaticStr::from(ApiReqType::Options), "options");
         assert_eq!(StaticStr::from(ApiReqType::Dashboard), "dashboard");
+        assert_eq!(
+            StaticStr::from(ApiReqType::DashboardAsset),
+            "dashboard_asset"
+        );
         assert_eq!(
             StaticStr::from(ApiReqType::RedirectToDashboard),
             "redirect_to_dashboard"
@@ -80,6 +110,10 @@ mod tests {
             StaticStr::from(ApiReqType::PprofFlamegraph),
             "pprof_flamegraph"
         );
+        assert_eq!(
+            StaticStr::from(ApiReqType::HeadersTooLarge),
+            "headers_too_large"
+        );
 
         assert_eq!(to_legacy_request_type(ApiReqType::Call), "submit");
 
@@ -90,6 +124,10 @@ mod tests {
             StaticStr::from(ConnectionError::TlsHandshake),
             "tls_handshake"
         );
+        assert_eq!(
+            StaticStr::from(ConnectionError::TlsHandshakeTimeout),
+            "tls_handshake_timeout"
+        );
         assert_eq!(StaticStr::from(ConnectionError::Accept), "accept");
         assert_eq!(StaticStr::from(ConnectionError::Peek), "peek");
         assert_eq!(
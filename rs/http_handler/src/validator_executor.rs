@@ -1,15 +1,15 @@
 // The valiadator executor provides non blocking access to the crypto services needed in the http handler.
-use crate::{common::validation_error_to_http_error, HttpError};
+use crate::{common::validation_error_to_http_error, subnet_clock::SubnetClock, HttpError};
 use http::StatusCode;
 use ic_interfaces::crypto::IngressSigVerifier;
 use ic_logger::{debug, ReplicaLogger};
 use ic_types::{
     malicious_flags::MaliciousFlags,
-    messages::{HttpRequest, HttpRequestContent, SignedIngress},
-    time::current_time,
-    RegistryVersion,
+    messages::{Authentication, HttpRequest, HttpRequestContent, SignedIngress},
+    RegistryVersion, Time,
 };
 use ic_validator::{get_authorized_canisters, validate_request, CanisterIdSet};
+use std::fmt;
 use std::sync::{Arc, Mutex};
 use threadpool::ThreadPool;
 use tokio::sync::oneshot;
@@ -17,9 +17,156 @@ use tokio::sync::oneshot;
 // Number of threads used for the ingress validator executor.
 const VALIDATOR_EXECUTOR_THREADS: usize = 1;
 
+// The maximum number of delegations in the `sender_delegation` chain.
+const MAX_DELEGATION_CHAIN_LENGTH: usize = 20;
+
+// The maximum number of canisters a single delegation may target.
+const MAX_TARGETS_PER_DELEGATION: usize = 1000;
+
+// The maximum total size of the sender's public key, signature and
+// delegations of a request.
+const MAX_AUTHENTICATION_OVERHEAD_BYTES: usize = 64 * 1024;
+
+/// A violation of the limits on the authentication part of a request envelope.
+/// These are checked before the (expensive) signature verification, and each
+/// is reported with its own code, so that wallets using long delegation chains
+/// can tell what to fix.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum DelegationLimitError {
+    ChainTooLong {
+        max: usize,
+        received: usize,
+    },
+    DelegationExpired {
+        index: usize,
+        expiration: Time,
+        current_time: Time,
+    },
+    TooManyTargets {
+        index: usize,
+        max: usize,
+        received: usize,
+    },
+    OverheadTooLarge {
+        max: usize,
+        received: usize,
+    },
+}
+
+impl DelegationLimitError {
+    /// A stable identifier of the violated limit.
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Self::ChainTooLong { .. } => "delegation_chain_too_long",
+            Self::DelegationExpired { .. } => "delegation_expired",
+            Self::TooManyTargets { .. } => "delegation_too_many_targets",
+            Self::OverheadTooLarge { .. } => "authentication_overhead_too_large",
+        }
+    }
+}
+
+impl fmt::Display for DelegationLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ChainTooLong { max, received } => write!(
+                f,
+                "The sender delegation chain has {} delegations, at most {} are allowed",
+                received, max
+            ),
+            Self::DelegationExpired {
+                index,
+                expiration,
+                current_time,
+            } => write!(
+                f,
+                "Sender delegation {} expired at {}, local replica time is {}",
+                index, expiration, current_time
+            ),
+            Self::TooManyTargets {
+                index,
+                max,
+                received,
+            } => write!(
+                f,
+                "Sender delegation {} has {} targets, at most {} are allowed",
+                index, received, max
+            ),
+            Self::OverheadTooLarge { max, received } => write!(
+                f,
+                "The sender public key, signature and delegations take {} bytes, at most {} are allowed",
+                received, max
+            ),
+        }
+    }
+}
+
+impl From<DelegationLimitError> for HttpError {
+    fn from(err: DelegationLimitError) -> Self {
+        HttpError {
+            status: StatusCode::BAD_REQUEST,
+            message: format!("Invalid sender delegation ({}): {}.", err.code(), err),
+        }
+    }
+}
+
+/// Checks the `sender_delegation` chain of a request against the limits of
+/// the HTTP handler, in the order: chain length, expiry and targets of each
+/// delegation, total size.
+pub(crate) fn check_delegation_limits(
+    authentication: &Authentication,
+    current_time: Time,
+) -> Result<(), DelegationLimitError> {
+    let signature = match authentication {
+        Authentication::Authenticated(signature) => signature,
+        Authentication::Anonymous => return Ok(()),
+    };
+    let delegations = signature.sender_delegation.as_deref().unwrap_or_default();
+    if delegations.len() > MAX_DELEGATION_CHAIN_LENGTH {
+        return Err(DelegationLimitError::ChainTooLong {
+            max: MAX_DELEGATION_CHAIN_LENGTH,
+            received: delegations.len(),
+        });
+    }
+
+    let mut overhead = signature.signature.len() + signature.signer_pubkey.len();
+    for (index, signed_delegation) in delegations.iter().enumerate() {
+        let delegation = signed_delegation.delegation();
+        if delegation.expiration() < current_time {
+            return Err(DelegationLimitError::DelegationExpired {
+                index,
+                expiration: delegation.expiration(),
+                current_time,
+            });
+        }
+        // Malformed targets are reported by the validator.
+        if let Ok(Some(targets)) = delegation.targets() {
+            if targets.len() > MAX_TARGETS_PER_DELEGATION {
+                return Err(DelegationLimitError::TooManyTargets {
+                    index,
+                    max: MAX_TARGETS_PER_DELEGATION,
+                    received: targets.len(),
+                });
+            }
+            overhead += targets
+                .iter()
+                .map(|target| target.get_ref().as_slice().len())
+                .sum::<usize>();
+        }
+        overhead += delegation.pubkey().len() + signed_delegation.signature().0.len();
+    }
+    if overhead > MAX_AUTHENTICATION_OVERHEAD_BYTES {
+        return Err(DelegationLimitError::OverheadTooLarge {
+            max: MAX_AUTHENTICATION_OVERHEAD_BYTES,
+            received: overhead,
+        });
+    }
+    Ok(())
+}
+
 #[derive(Clone)]
 pub(crate) struct ValidatorExecutor {
     validator: Arc<dyn IngressSigVerifier + Send + Sync>,
+    subnet_clock: SubnetClock,
     threadpool: Arc<Mutex<ThreadPool>>,
     logger: ReplicaLogger,
 }
@@ -27,10 +174,12 @@ pub(crate) struct ValidatorExecutor {
 impl ValidatorExecutor {
     pub fn new(
         validator: Arc<dyn IngressSigVerifier + Send + Sync>,
+        subnet_clock: SubnetClock,
         logger: ReplicaLogger,
     ) -> Self {
         ValidatorExecutor {
             validator,
+            subnet_clock,
             threadpool: Arc::new(Mutex::new(ThreadPool::new(VALIDATOR_EXECUTOR_THREADS))),
             logger,
         }
@@ -42,6 +191,10 @@ impl ValidatorExecutor {
         registry_version: RegistryVersion,
         malicious_flags: &MaliciousFlags,
     ) -> Result<(), HttpError> {
+        // Requests are validated against the time of the subnet, which
+        // eventually checks their expiry against the block time.
+        let now = self.subnet_clock.now();
+        check_delegation_limits(request.authentication(), now)?;
         let (tx, rx) = oneshot::channel();
 
         let r = request.clone();
@@ -52,7 +205,7 @@ impl ValidatorExecutor {
                 let _ = tx.send(validate_request(
                     r.as_ref(),
                     validator.as_ref(),
-                    current_time(),
+                    now,
                     registry_version,
                     &mf,
                 ));
@@ -75,6 +228,8 @@ impl ValidatorExecutor {
         registry_version: RegistryVersion,
         #[allow(unused_variables)] malicious_flags: &MaliciousFlags,
     ) -> Result<CanisterIdSet, HttpError> {
+        let now = self.subnet_clock.now();
+        check_delegation_limits(request.authentication(), now)?;
         let (tx, rx) = oneshot::channel();
 
         let r = request.clone();
@@ -85,7 +240,7 @@ impl ValidatorExecutor {
                 let _ = tx.send(get_authorized_canisters(
                     &r,
                     validator.as_ref(),
-                    current_time(),
+                    now,
                     registry_version,
                     &mf,
                 ));
@@ -108,7 +263,12 @@ impl ValidatorExecutor {
 
 #[cfg(test)]
 mod tests {
-    use super::{validate_request, validation_error_to_http_error, ValidatorExecutor};
+    use super::{
+        check_delegation_limits, validate_request, validation_error_to_http_error,
+        DelegationLimitError, ValidatorExecutor, MAX_AUTHENTICATION_OVERHEAD_BYTES,
+        MAX_DELEGATION_CHAIN_LENGTH, MAX_TARGETS_PER_DELEGATION,
+    };
+    use crate::subnet_clock::SubnetClock;
     use ic_logger::replica_logger::no_op_logger;
     use ic_test_utilities::{
         crypto::temp_crypto_component_with_fake_registry,
@@ -122,13 +282,15 @@ mod tests {
     use ic_types::RegistryVersion;
     use ic_types::{
         messages::{
-            Blob, HttpQueryContent, HttpRequest, HttpRequestEnvelope, HttpUserQuery, UserQuery,
+            Authentication, Blob, Delegation, HttpQueryContent, HttpRequest, HttpRequestEnvelope,
+            HttpUserQuery, SignedDelegation, UserQuery, UserSignature,
         },
         time::current_time_and_expiry_time,
     };
     use ic_validator::get_authorized_canisters;
     use std::convert::TryFrom;
     use std::sync::Arc;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn async_get_authorized_canisters() {
@@ -151,7 +313,8 @@ mod tests {
         };
         let request = HttpRequest::<UserQuery>::try_from(request).unwrap();
         let sig_verifier = Arc::new(temp_crypto_component_with_fake_registry(node_test_id(0)));
-        let validator = ValidatorExecutor::new(sig_verifier.clone(), no_op_logger());
+        let validator =
+            ValidatorExecutor::new(sig_verifier.clone(), SubnetClock::default(), no_op_logger());
 
         assert_eq!(
             validator
@@ -183,7 +346,8 @@ mod tests {
             .nonce(42)
             .build();
         let sig_verifier = Arc::new(temp_crypto_component_with_fake_registry(node_test_id(0)));
-        let validator = ValidatorExecutor::new(sig_verifier.clone(), no_op_logger());
+        let validator =
+            ValidatorExecutor::new(sig_verifier.clone(), SubnetClock::default(), no_op_logger());
 
         assert_eq!(
             validator
@@ -207,4 +371,76 @@ mod tests {
             ))
         )
     }
+
+    fn authenticated(delegations: Vec<SignedDelegation>) -> Authentication {
+        Authentication::Authenticated(UserSignature {
+            signature: vec![0; 64],
+            signer_pubkey: vec![0; 32],
+            sender_delegation: Some(delegations),
+        })
+    }
+
+    #[test]
+    fn delegation_limits_are_enforced() {
+        let now = current_time();
+        let expiration = now + Duration::from_secs(60);
+        let delegation =
+            || SignedDelegation::new(Delegation::new(vec![0; 32], expiration), vec![0; 64]);
+
+        assert_eq!(
+            check_delegation_limits(&Authentication::Anonymous, now),
+            Ok(())
+        );
+        assert_eq!(
+            check_delegation_limits(
+                &authenticated(vec![delegation(); MAX_DELEGATION_CHAIN_LENGTH]),
+                now
+            ),
+            Ok(())
+        );
+
+        let err = check_delegation_limits(
+            &authenticated(vec![delegation(); MAX_DELEGATION_CHAIN_LENGTH + 1]),
+            now,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            DelegationLimitError::ChainTooLong {
+                max: MAX_DELEGATION_CHAIN_LENGTH,
+                received: MAX_DELEGATION_CHAIN_LENGTH + 1
+            }
+        );
+        assert_eq!(err.code(), "delegation_chain_too_long");
+
+        let expired = SignedDelegation::new(
+            Delegation::new(vec![0; 32], now - Duration::from_secs(1)),
+            vec![0; 64],
+        );
+        assert!(matches!(
+            check_delegation_limits(&authenticated(vec![delegation(), expired]), now),
+            Err(DelegationLimitError::DelegationExpired { index: 1, .. })
+        ));
+
+        let targets = (0..=MAX_TARGETS_PER_DELEGATION as u64)
+            .map(canister_test_id)
+            .collect();
+        let too_many_targets = SignedDelegation::new(
+            Delegation::new_with_targets(vec![0; 32], expiration, targets),
+            vec![0; 64],
+        );
+        assert!(matches!(
+            check_delegation_limits(&authenticated(vec![too_many_targets]), now),
+            Err(DelegationLimitError::TooManyTargets { index: 0, .. })
+        ));
+
+        let large = SignedDelegation::new(
+            Delegation::new(vec![0; MAX_AUTHENTICATION_OVERHEAD_BYTES], expiration),
+            vec![0; 64],
+        );
+        assert!(matches!(
+            check_delegation_limits(&authenticated(vec![large]), now),
+            Err(DelegationLimitError::OverheadTooLarge { .. })
+        ));
+    }
 }
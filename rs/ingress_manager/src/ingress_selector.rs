@@ -100,18 +100,23 @@ impl<'a> IngressSelector for IngressManager {
                         // Calculate the size and abort once we have hit the limit
                         accumulated_size += ingress_obj.signed_ingress.count_bytes();
                         if accumulated_size > byte_limit.get() as usize {
+                            self.record_skip_reason(SkipReason::BlockFull);
                             return SelectResult::Abort;
                         }
 
                         SelectResult::Selected(ingress_obj.signed_ingress.clone())
                     }
                     Err(ValidationError::Permanent(
-                        IngressPermanentError::IngressPayloadTooBig(_, _),
-                    )) => SelectResult::Abort,
-                    Err(ValidationError::Permanent(
-                        IngressPermanentError::IngressPayloadTooManyMessages(_, _),
-                    )) => SelectResult::Abort,
-                    _ => SelectResult::Skip,
+                        IngressPermanentError::IngressPayloadTooBig(_, _)
+                        | IngressPermanentError::IngressPayloadTooManyMessages(_, _),
+                    )) => {
+                        self.record_skip_reason(SkipReason::BlockFull);
+                        SelectResult::Abort
+                    }
+                    Err(err) => {
+                        self.record_skip_reason(SkipReason::from_error(&err));
+                        SelectResult::Skip
+                    }
                 }
             }),
         );
@@ -287,7 +292,76 @@ impl<'a> IngressSelector for IngressManager {
     }
 }
 
+/// The reason an ingress message in the pool was not included in a payload.
+///
+/// Messages whose expiry time is outside of the range valid for the payload
+/// are not visited by the selection at all, and thus not counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SkipReason {
+    /// The message expired, or its delegations did.
+    Expired,
+    /// The message is larger than the maximum size of ingress messages.
+    TooLarge,
+    /// The message was included in a block before.
+    Duplicate,
+    /// The canister paying for the induction of the message can't afford it,
+    /// together with the messages to it selected before.
+    InsufficientCycles,
+    /// The canister paying for the induction of the message does not exist.
+    CanisterNotFound,
+    /// The message is not valid, e.g. its signature or its arguments.
+    Invalid,
+    /// The payload reached its byte or message limit, so the selection stopped
+    /// at this message. Counted once per payload, not for each of the
+    /// remaining messages.
+    BlockFull,
+}
+
+impl SkipReason {
+    fn from_error(err: &IngressPayloadValidationError) -> Self {
+        match err {
+            ValidationError::Permanent(IngressPermanentError::IngressExpired(_, _)) => {
+                SkipReason::Expired
+            }
+            ValidationError::Permanent(IngressPermanentError::IngressMessageTooBig(_, _)) => {
+                SkipReason::TooLarge
+            }
+            ValidationError::Permanent(IngressPermanentError::DuplicatedIngressMessage(_)) => {
+                SkipReason::Duplicate
+            }
+            ValidationError::Permanent(IngressPermanentError::InsufficientCycles(_)) => {
+                SkipReason::InsufficientCycles
+            }
+            ValidationError::Permanent(IngressPermanentError::CanisterNotFound(_)) => {
+                SkipReason::CanisterNotFound
+            }
+            _ => SkipReason::Invalid,
+        }
+    }
+
+    /// The label of the reason in the metrics.
+    fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::Expired => "expired",
+            SkipReason::TooLarge => "too_large",
+            SkipReason::Duplicate => "duplicate",
+            SkipReason::InsufficientCycles => "insufficient_cycles",
+            SkipReason::CanisterNotFound => "canister_not_found",
+            SkipReason::Invalid => "invalid",
+            SkipReason::BlockFull => "block_full",
+        }
+    }
+}
+
 impl IngressManager {
+    // Counts a message not included in the payload for `reason`.
+    fn record_skip_reason(&self, reason: SkipReason) {
+        self.metrics
+            .ingress_selector_skipped_messages
+            .with_label_values(&[reason.as_str()])
+            .inc();
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn validate_ingress(
         &self,
@@ -1442,6 +1516,119 @@ mod tests {
         )
     }
 
+    #[tokio::test]
+    async fn test_get_payload_counts_skipped_messages() {
+        let subnet_id = subnet_test_id(0);
+        let registry = setup_registry(subnet_id, MAX_SIZE);
+        let time = mock_time();
+        let message = |canister, nonce, size| {
+            SignedIngressBuilder::new()
+                .canister_id(canister_test_id(canister))
+                .method_payload(vec![0; size])
+                .expiry_time(time + MAX_INGRESS_TTL)
+                .nonce(nonce)
+                .build()
+        };
+        let selected = message(0, 1, 0);
+        let duplicate = message(0, 2, 0);
+        let too_large = message(0, 3, MAX_SIZE);
+        // Canister 1 has no cycles at all.
+        let insufficient_cycles = message(1, 4, 0);
+        let canister_not_found = message(2, 5, 0);
+
+        setup_with_params(
+            None,
+            Some((registry, subnet_id)),
+            None,
+            Some(
+                ReplicatedStateBuilder::default()
+                    .with_canister(
+                        CanisterStateBuilder::default()
+                            .with_canister_id(canister_test_id(0))
+                            .build(),
+                    )
+                    .with_canister(
+                        CanisterStateBuilder::default()
+                            .with_canister_id(canister_test_id(1))
+                            .with_cycles(0u128)
+                            .build(),
+                    )
+                    .build(),
+            ),
+            |ingress_manager, ingress_pool| {
+                let time_source = FastForwardTimeSource::new();
+                let validation_context = ValidationContext {
+                    time: time + MAX_INGRESS_TTL,
+                    registry_version: RegistryVersion::from(1),
+                    certified_height: Height::from(0),
+                };
+
+                let ingress_messages = vec![
+                    selected.clone(),
+                    duplicate.clone(),
+                    too_large.clone(),
+                    insufficient_cycles.clone(),
+                    canister_not_found.clone(),
+                ];
+                for m in ingress_messages.iter() {
+                    let message_id = IngressMessageId::from(m);
+                    let attribute = IngressMessageAttribute::new(m);
+                    access_ingress_pool(&ingress_pool, |mut ingress_pool| {
+                        ingress_pool.insert(UnvalidatedArtifact {
+                            message: m.clone(),
+                            peer_id: node_test_id(0),
+                            timestamp: time_source.get_relative_time(),
+                        });
+                        ingress_pool.apply_changeset(vec![ChangeAction::MoveToValidated((
+                            message_id.clone(),
+                            node_test_id(0),
+                            m.count_bytes(),
+                            attribute,
+                            crypto_hash(m.binary()).get(),
+                        ))]);
+                    });
+                }
+
+                let past_ingress: HashSet<_> = vec![IngressMessageId::from(&duplicate)]
+                    .into_iter()
+                    .collect();
+                let payload = ingress_manager.get_ingress_payload(
+                    &past_ingress,
+                    &validation_context,
+                    NumBytes::new(10 * MAX_SIZE as u64),
+                );
+                assert_eq!(payload.message_count(), 1);
+
+                let skipped = |reason: SkipReason| {
+                    ingress_manager
+                        .metrics
+                        .ingress_selector_skipped_messages
+                        .with_label_values(&[reason.as_str()])
+                        .get()
+                };
+                for reason in [
+                    SkipReason::Duplicate,
+                    SkipReason::TooLarge,
+                    SkipReason::InsufficientCycles,
+                    SkipReason::CanisterNotFound,
+                ] {
+                    assert_eq!(skipped(reason), 1);
+                }
+                assert_eq!(skipped(SkipReason::BlockFull), 0);
+
+                // Not even the valid message fits in a single byte, so the
+                // selection stops at the first one it validates.
+                let payload = ingress_manager.get_ingress_payload(
+                    &past_ingress,
+                    &validation_context,
+                    NumBytes::new(1),
+                );
+                assert_eq!(payload.message_count(), 0);
+                assert_eq!(skipped(SkipReason::BlockFull), 1);
+            },
+        )
+    }
+
     #[tokio::test]
     // Validation should fail if receiving canisters has insufficient balance.
     async fn test_validate_canister_has_insufficient_balance() {
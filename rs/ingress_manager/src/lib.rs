@@ -29,7 +29,7 @@ use ic_types::{
     time::{Time, UNIX_EPOCH},
     Height, RegistryVersion, SubnetId,
 };
-use prometheus::{Histogram, IntGauge};
+use prometheus::{Histogram, IntCounterVec, IntGauge};
 use std::{
     collections::{BTreeMap, HashSet},
     ops::RangeInclusive,
@@ -74,6 +74,7 @@ struct IngressManagerMetrics {
     ingress_selector_get_payload_time: Histogram,
     ingress_selector_validate_payload_time: Histogram,
     ingress_payload_cache_size: IntGauge,
+    ingress_selector_skipped_messages: IntCounterVec,
 }
 
 impl IngressManagerMetrics {
@@ -98,6 +99,11 @@ impl IngressManagerMetrics {
                 "ingress_payload_cache_size",
                 "The number of HashSets in payload builder's ingress payload cache.",
             ),
+            ingress_selector_skipped_messages: metrics_registry.int_counter_vec(
+                "ingress_selector_skipped_messages_total",
+                "The number of ingress messages skipped while building payloads, by reason",
+                &["reason"],
+            ),
         }
     }
 }
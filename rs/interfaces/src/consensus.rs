@@ -15,11 +15,13 @@ use crate::{
     },
     validation::ValidationError,
 };
-use ic_base_types::{NumBytes, SubnetId};
+use ic_base_types::{NodeId, NumBytes, SubnetId};
 use ic_types::{
     artifact::{ConsensusMessageAttribute, ConsensusMessageFilter, ConsensusMessageId, PriorityFn},
+    batch::PayloadSection,
     registry::RegistryClientError,
 };
+use std::collections::BTreeMap;
 
 /// Consensus artifact processing interface.
 pub trait Consensus: Send {
@@ -56,12 +58,84 @@ pub trait ConsensusGossip: Send + Sync {
 pub enum PayloadPermanentError {
     XNetPayloadValidationError(InvalidXNetPayload),
     IngressPayloadValidationError(IngressPermanentError),
-    PayloadTooBig {
-        expected: NumBytes,
-        received: NumBytes,
-    },
+    /// The payload exceeds the maximum block payload size.
+    PayloadSizeExceeded(PayloadSizeBreakdown),
     SelfValidatingPayloadValidationError(InvalidSelfValidatingPayload),
     CanisterHttpPayloadValidationError(CanisterHttpPermanentValidationError),
+    CanaryPayloadValidationError(InvalidCanaryPayload),
+    PayloadBuildStatsValidationError(InvalidPayloadBuildStats),
+    /// The section is disabled in the registry, but not empty.
+    SectionDisabled(PayloadSection),
+    /// The contents of the section are not in canonical order, which the
+    /// registry requires.
+    NonCanonicalOrder(PayloadSection),
+    /// The XNet section holds compressed stream slices that are invalid.
+    InvalidXNetCompression(InvalidXNetCompression),
+}
+
+/// The sizes adding up to a payload that exceeds the maximum block payload
+/// size, so that the excess can be attributed to the sections of the block
+/// maker.
+#[derive(Debug)]
+pub struct PayloadSizeBreakdown {
+    /// The maximum block payload size.
+    pub expected: NumBytes,
+    /// The size of the payload up to and including `exceeded_by`.
+    pub received: NumBytes,
+    /// The size of the DKG dealings and of the ECDSA payload.
+    pub non_batch: NumBytes,
+    /// The size of each section validated until the maximum was exceeded.
+    pub sections: BTreeMap<PayloadSection, NumBytes>,
+    /// The section whose size made the payload exceed the maximum.
+    pub exceeded_by: PayloadSection,
+}
+
+/// Reasons for the compressed stream slices of an XNet section to be invalid.
+#[derive(Debug)]
+pub enum InvalidXNetCompression {
+    /// The section holds compressed slices, but the registry does not enable
+    /// compression.
+    NotEnabled,
+    /// The slice from `subnet_id` can't be decompressed, or decompresses to
+    /// more than `max` bytes along with the other slices.
+    Malformed {
+        subnet_id: SubnetId,
+        max: NumBytes,
+        reason: String,
+    },
+}
+
+/// Reasons for a canary payload section to be invalid.
+#[derive(Debug)]
+pub enum InvalidCanaryPayload {
+    /// The section is larger than allowed by the registry.
+    TooLarge { max: NumBytes, received: NumBytes },
+    /// The content of the section does not match the block height.
+    UnexpectedContent,
+}
+
+/// Reasons for the build stats of a payload to be inconsistent with the
+/// payload or the proposal.
+#[derive(Debug)]
+pub enum InvalidPayloadBuildStats {
+    /// The stats are not reported by the signer of the proposal.
+    UnexpectedBlockMaker { block_maker: NodeId, signer: NodeId },
+    /// The stats contain more than one entry for a section.
+    DuplicateSection(PayloadSection),
+    /// The byte limit of a section exceeds the block size limit.
+    ByteLimitTooLarge {
+        section: PayloadSection,
+        max: NumBytes,
+        byte_limit: NumBytes,
+    },
+    /// The bytes included in a section exceed its byte limit, or are less than
+    /// the size of the section in the payload.
+    UnexpectedBytesIncluded {
+        section: PayloadSection,
+        byte_limit: NumBytes,
+        bytes_included: NumBytes,
+        size: NumBytes,
+    },
 }
 
 #[derive(Debug)]
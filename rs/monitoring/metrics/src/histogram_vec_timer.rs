@@ -58,6 +58,11 @@ impl<'a, const LABEL_COUNT: usize> HistogramVecTimer<'a, LABEL_COUNT> {
         &self.label_values
     }
 
+    /// Returns the time elapsed since the timer was started.
+    pub fn elapsed(&self) -> std::time::Duration {
+        self.start.elapsed()
+    }
+
     /// Updates the value of a single existing label.
     ///
     /// Panics if `k` does not match an existing label name.
@@ -324,6 +324,45 @@ pub struct SubnetFeatures {
     /// supported.
     #[prost(message, optional, tag = "6")]
     pub bitcoin: ::core::option::Option<BitcoinFeatureInfo>,
+    /// If set, block makers include a canary payload section of this many bytes
+    /// in data blocks. The section has no function and is used to rehearse the
+    /// rollout of new payload sections.
+    #[prost(uint64, optional, tag = "7")]
+    pub canary_payload_bytes: ::core::option::Option<u64>,
+    /// Payload sections that block makers leave empty, and that must be empty
+    /// in valid blocks. Allows disabling a misbehaving feature without a
+    /// replica upgrade.
+    #[prost(message, optional, tag = "8")]
+    pub disabled_payload_sections: ::core::option::Option<DisabledPayloadSections>,
+    /// If set, block makers order the contents of payload sections
+    /// canonically, e.g. ingress messages by expiry and then id, and blocks
+    /// with sections in any other order are invalid. Makes the payloads of
+    /// different block makers reproducible and diffable.
+    #[prost(bool, tag = "9")]
+    pub canonical_payload_order: bool,
+    /// If set, block makers compress the certified stream slices in the XNet
+    /// section with zstd, and blocks with compressed slices are only valid if
+    /// set. Reduces the size of blocks on subnets with heavy XNet traffic.
+    #[prost(bool, tag = "10")]
+    pub xnet_compression: bool,
+    /// If set, the DKG dealings and the ECDSA payload of data blocks count
+    /// against the maximum block payload size along with the batch payload, up
+    /// to that size, so that a block with an empty batch payload is always
+    /// valid.
+    #[prost(bool, tag = "11")]
+    pub count_non_batch_payload_size: bool,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DisabledPayloadSections {
+    /// Responses from the bitcoin adapter.
+    #[prost(bool, tag = "1")]
+    pub bitcoin: bool,
+    /// Responses to canister http requests.
+    #[prost(bool, tag = "2")]
+    pub canister_http: bool,
+    /// Streams from other subnets.
+    #[prost(bool, tag = "3")]
+    pub xnet: bool,
 }
 /// Per subnet ECDSA configuration
 #[derive(Clone, PartialEq, ::prost::Message)]
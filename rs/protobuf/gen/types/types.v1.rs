@@ -479,6 +479,11 @@ pub struct Block {
     pub ecdsa_summary: ::core::option::Option<EcdsaSummaryPayload>,
     #[prost(message, optional, tag = "14")]
     pub canister_http_payload: ::core::option::Option<CanisterHttpPayload>,
+    #[prost(message, optional, tag = "15")]
+    pub canary_payload: ::core::option::Option<CanaryPayload>,
+    /// Optional statistics of the block maker on building the batch payload.
+    #[prost(message, optional, tag = "16")]
+    pub payload_build_stats: ::core::option::Option<PayloadBuildStats>,
     #[prost(bytes = "vec", tag = "11")]
     pub payload_hash: ::prost::alloc::vec::Vec<u8>,
 }
@@ -573,6 +578,29 @@ pub struct CanisterHttpPayload {
     pub timeouts: ::prost::alloc::vec::Vec<u64>,
 }
 #[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, ::prost::Message)]
+pub struct CanaryPayload {
+    #[prost(bytes = "vec", tag = "1")]
+    pub data: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, ::prost::Message)]
+pub struct SectionBuildStats {
+    #[prost(enumeration = "PayloadSection", tag = "1")]
+    pub section: i32,
+    #[prost(uint64, tag = "2")]
+    pub build_duration_micros: u64,
+    #[prost(uint64, tag = "3")]
+    pub byte_limit: u64,
+    #[prost(uint64, tag = "4")]
+    pub bytes_included: u64,
+}
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, ::prost::Message)]
+pub struct PayloadBuildStats {
+    #[prost(message, optional, tag = "1")]
+    pub block_maker: ::core::option::Option<NodeId>,
+    #[prost(message, repeated, tag = "2")]
+    pub sections: ::prost::alloc::vec::Vec<SectionBuildStats>,
+}
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, ::prost::Message)]
 pub struct IngressIdOffset {
     #[prost(uint64, tag = "1")]
     pub expiry: u64,
@@ -588,3 +616,25 @@ pub struct IngressPayload {
     #[prost(bytes = "vec", tag = "2")]
     pub buffer: ::prost::alloc::vec::Vec<u8>,
 }
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    ::prost::Enumeration,
+)]
+#[repr(i32)]
+pub enum PayloadSection {
+    Unspecified = 0,
+    Ingress = 1,
+    Xnet = 2,
+    SelfValidating = 3,
+    CanisterHttp = 4,
+    Canary = 5,
+}
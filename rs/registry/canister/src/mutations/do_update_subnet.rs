@@ -406,6 +406,11 @@ mod tests {
                 canister_sandboxing: false,
                 http_requests: false,
                 bitcoin: None,
+                canary_payload_bytes: None,
+                disabled_payload_sections: None,
+                canonical_payload_order: false,
+                xnet_compression: false,
+                count_non_batch_payload_size: false,
             }),
             ecdsa_config: Some(EcdsaConfig {
                 quadruples_to_create_in_advance: 10,
@@ -527,6 +532,11 @@ mod tests {
                 canister_sandboxing: false,
                 http_requests: false,
                 bitcoin: None,
+                canary_payload_bytes: None,
+                disabled_payload_sections: None,
+                canonical_payload_order: false,
+                xnet_compression: false,
+                count_non_batch_payload_size: false,
             }),
             ecdsa_config: Some(EcdsaConfig {
                 quadruples_to_create_in_advance: 10,
@@ -577,6 +587,11 @@ mod tests {
                         canister_sandboxing: false,
                         http_requests: false,
                         bitcoin: None,
+                        canary_payload_bytes: None,
+                        disabled_payload_sections: None,
+                        canonical_payload_order: false,
+                        xnet_compression: false,
+                        count_non_batch_payload_size: false,
                     }
                     .into()
                 ),
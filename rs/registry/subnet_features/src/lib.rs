@@ -6,6 +6,10 @@ use std::{convert::TryFrom, str::FromStr};
 
 pub const DEFAULT_ECDSA_MAX_QUEUE_SIZE: u32 = 20;
 
+/// The size of the canary payload section enabled by the `canary_payload`
+/// feature string.
+pub const DEFAULT_CANARY_PAYLOAD_BYTES: u64 = 1024;
+
 /// List of features that can be enabled or disabled on the given subnet.
 #[derive(CandidType, Clone, Copy, Default, Deserialize, Debug, Eq, PartialEq, Serialize)]
 pub struct SubnetFeatures {
@@ -19,6 +23,29 @@ pub struct SubnetFeatures {
 
     /// Determines whether or not the bitcoin feature is enabled on the subnet.
     pub bitcoin: Option<BitcoinFeature>,
+
+    /// If set, block makers include a canary payload section of this many
+    /// bytes in data blocks, to rehearse the rollout of new payload sections.
+    pub canary_payload_bytes: Option<u64>,
+
+    /// Payload sections that block makers leave empty, and that must be empty
+    /// in valid blocks, to disable a misbehaving feature without a replica
+    /// upgrade.
+    pub disabled_payload_sections: Option<DisabledPayloadSections>,
+
+    /// If set, block makers order the contents of payload sections
+    /// canonically, and blocks with sections in any other order are invalid,
+    /// so that the payloads of different block makers can be diffed.
+    pub canonical_payload_order: bool,
+
+    /// If set, block makers compress the stream slices in the XNet section,
+    /// and blocks with compressed slices are only valid if set.
+    pub xnet_compression: bool,
+
+    /// If set, the DKG dealings and the ECDSA payload of data blocks count
+    /// against the maximum block payload size, up to that size, so that they
+    /// can't make a block with an empty batch payload invalid.
+    pub count_non_batch_payload_size: bool,
 }
 
 impl SubnetFeatures {
@@ -28,6 +55,41 @@ impl SubnetFeatures {
             status: BitcoinFeatureStatus::Disabled,
         })
     }
+
+    pub fn disabled_payload_sections(&self) -> DisabledPayloadSections {
+        self.disabled_payload_sections.unwrap_or_default()
+    }
+}
+
+/// Payload sections that can be disabled through the registry.
+#[derive(CandidType, Clone, Copy, Default, Deserialize, Debug, Eq, PartialEq, Serialize)]
+pub struct DisabledPayloadSections {
+    /// Responses from the bitcoin adapter.
+    pub bitcoin: bool,
+    /// Responses to canister http requests.
+    pub canister_http: bool,
+    /// Streams from other subnets.
+    pub xnet: bool,
+}
+
+impl From<DisabledPayloadSections> for pb::DisabledPayloadSections {
+    fn from(sections: DisabledPayloadSections) -> Self {
+        Self {
+            bitcoin: sections.bitcoin,
+            canister_http: sections.canister_http,
+            xnet: sections.xnet,
+        }
+    }
+}
+
+impl From<pb::DisabledPayloadSections> for DisabledPayloadSections {
+    fn from(sections: pb::DisabledPayloadSections) -> Self {
+        Self {
+            bitcoin: sections.bitcoin,
+            canister_http: sections.canister_http,
+            xnet: sections.xnet,
+        }
+    }
 }
 
 impl From<SubnetFeatures> for pb::SubnetFeatures {
@@ -46,6 +108,11 @@ impl From<SubnetFeatures> for pb::SubnetFeatures {
                     },
                     status: bitcoin_feature.status.into(),
                 }),
+            canary_payload_bytes: features.canary_payload_bytes,
+            disabled_payload_sections: features.disabled_payload_sections.map(Into::into),
+            canonical_payload_order: features.canonical_payload_order,
+            xnet_compression: features.xnet_compression,
+            count_non_batch_payload_size: features.count_non_batch_payload_size,
         }
     }
 }
@@ -78,6 +145,11 @@ impl From<pb::SubnetFeatures> for SubnetFeatures {
                         })
                 }
             },
+            canary_payload_bytes: features.canary_payload_bytes,
+            disabled_payload_sections: features.disabled_payload_sections.map(Into::into),
+            canonical_payload_order: features.canonical_payload_order,
+            xnet_compression: features.xnet_compression,
+            count_non_batch_payload_size: features.count_non_batch_payload_size,
         }
     }
 }
@@ -97,6 +169,30 @@ impl FromStr for SubnetFeatures {
             match feature {
                 "canister_sandboxing" => features.canister_sandboxing = true,
                 "http_requests" => features.http_requests = true,
+                "canonical_payload_order" => features.canonical_payload_order = true,
+                "xnet_compression" => features.xnet_compression = true,
+                "count_non_batch_payload_size" => features.count_non_batch_payload_size = true,
+                "canary_payload" => {
+                    features.canary_payload_bytes = Some(DEFAULT_CANARY_PAYLOAD_BYTES)
+                }
+                "disable_bitcoin_payload" => {
+                    features
+                        .disabled_payload_sections
+                        .get_or_insert_with(Default::default)
+                        .bitcoin = true
+                }
+                "disable_canister_http_payload" => {
+                    features
+                        .disabled_payload_sections
+                        .get_or_insert_with(Default::default)
+                        .canister_http = true
+                }
+                "disable_xnet_payload" => {
+                    features
+                        .disabled_payload_sections
+                        .get_or_insert_with(Default::default)
+                        .xnet = true
+                }
                 "bitcoin_testnet" => {
                     if features.bitcoin.is_some() {
                         // Feature was already set. Return an error.
@@ -282,11 +378,38 @@ mod tests {
                 bitcoin: Some(BitcoinFeature {
                     network: BitcoinNetwork::Testnet,
                     status: BitcoinFeatureStatus::Enabled
-                })
+                }),
+                canary_payload_bytes: None,
+                disabled_payload_sections: None,
+                canonical_payload_order: false,
+                xnet_compression: false,
+                count_non_batch_payload_size: false,
             }
         );
     }
 
+    #[test]
+    fn test_payload_sections_can_be_disabled() {
+        let result =
+            SubnetFeatures::from_str("disable_bitcoin_payload,disable_xnet_payload").unwrap();
+        assert_eq!(
+            result.disabled_payload_sections(),
+            DisabledPayloadSections {
+                bitcoin: true,
+                canister_http: false,
+                xnet: true,
+            }
+        );
+        assert_eq!(
+            SubnetFeatures::from(pb::SubnetFeatures::from(result)),
+            result
+        );
+        assert_eq!(
+            SubnetFeatures::default().disabled_payload_sections(),
+            DisabledPayloadSections::default()
+        );
+    }
+
     #[test]
     fn test_bitcoin_mainnet_paused() {
         let result =
@@ -300,7 +423,12 @@ mod tests {
                 bitcoin: Some(BitcoinFeature {
                     network: BitcoinNetwork::Mainnet,
                     status: BitcoinFeatureStatus::Paused
-                })
+                }),
+                canary_payload_bytes: None,
+                disabled_payload_sections: None,
+                canonical_payload_order: false,
+                xnet_compression: false,
+                count_non_batch_payload_size: false,
             }
         );
     }
@@ -317,7 +445,12 @@ mod tests {
                 bitcoin: Some(BitcoinFeature {
                     network: BitcoinNetwork::Mainnet,
                     status: BitcoinFeatureStatus::Enabled
-                })
+                }),
+                canary_payload_bytes: None,
+                disabled_payload_sections: None,
+                canonical_payload_order: false,
+                xnet_compression: false,
+                count_non_batch_payload_size: false,
             }
         );
     }
@@ -2,7 +2,8 @@ use ic_consensus::consensus::{payload_builder::PayloadBuilder, SubnetRecords};
 use ic_interfaces::{consensus::PayloadValidationError, validation::ValidationResult};
 use ic_types::{
     batch::{BatchPayload, ValidationContext},
-    consensus::Payload,
+    consensus::{Block, Payload},
+    crypto::CryptoHashOf,
     Height, Time,
 };
 
@@ -14,6 +15,7 @@ impl PayloadBuilder for MockPayloadBuilder {
     fn get_payload<'a>(
         &self,
         _height: Height,
+        _parent_hash: &CryptoHashOf<Block>,
         _past_payloads: &[(Height, Time, Payload)],
         _context: &ValidationContext,
         _subnet_records: &SubnetRecords,
@@ -63,6 +63,9 @@ fn get_replica_binary_hash() -> std::result::Result<(PathBuf, String), String> {
 }
 
 fn main() -> io::Result<()> {
+    // The uptime of the replica is measured from here.
+    let boot_time = ic_http_handler::BootTime::now();
+
     // We do not support 32 bits architectures and probably never will.
     assert_eq_size!(usize, u64);
 
@@ -304,7 +307,7 @@ fn main() -> io::Result<()> {
 
     let malicious_behaviour = &config.malicious_behaviour;
 
-    ic_http_handler::start_server(
+    let http_shutdown = ic_http_handler::start_server(
         rt_http.handle().clone(),
         metrics_registry,
         config.http_handler.clone(),
@@ -315,12 +318,14 @@ fn main() -> io::Result<()> {
         registry,
         Arc::clone(&crypto) as Arc<dyn TlsHandshake + Send + Sync>,
         Arc::clone(&crypto) as Arc<dyn IngressSigVerifier + Send + Sync>,
+        node_id,
         subnet_id,
         root_subnet_id,
         logger.clone(),
         consensus_pool_cache,
         subnet_type,
         malicious_behaviour.malicious_flags.clone(),
+        boot_time,
     );
 
     std::thread::sleep(Duration::from_millis(5000));
@@ -341,13 +346,20 @@ fn main() -> io::Result<()> {
         });
     }
 
+    let shutdown_http_on_sigterm = config.http_handler.shutdown_on_sigterm;
     let save_logger = logger.clone();
     rt_main.block_on(async move {
         let _drop_async_log_guard = async_log_guard;
         let _drop_sigpipe_handler = sigpipe_handler;
         info!(logger, "IC Replica Running");
         // Blocking on `SIGINT` or `SIGTERM`.
-        shutdown_signal(logger.inner_logger.root.clone()).await
+        shutdown_signal(logger.inner_logger.root.clone()).await;
+        if shutdown_http_on_sigterm {
+            // Let clients finish their requests, and move to other nodes.
+            info!(logger, "Shutting down the HTTP server");
+            let report = http_shutdown.shutdown().await;
+            info!(logger, "Shut down the HTTP server, {}", report);
+        }
     });
     info!(save_logger, "IC Replica Terminating");
 
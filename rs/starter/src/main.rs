@@ -619,6 +619,11 @@ fn to_subnet_features(features: &[String]) -> SubnetFeatures {
         http_requests,
         bitcoin_testnet_feature: None,
         bitcoin,
+        canary_payload_bytes: None,
+        disabled_payload_sections: None,
+        canonical_payload_order: false,
+        xnet_compression: false,
+        count_non_batch_payload_size: false,
     }
 }
 
@@ -1,5 +1,6 @@
 use ic_types::batch::{
-    BatchPayload, CanisterHttpPayload, IngressPayload, SelfValidatingPayload, XNetPayload,
+    BatchPayload, CanaryPayload, CanisterHttpPayload, IngressPayload, SelfValidatingPayload,
+    XNetPayload,
 };
 
 pub struct PayloadBuilder {
@@ -15,6 +16,8 @@ impl Default for PayloadBuilder {
                 // TODO(MR-70): use payload builder
                 self_validating: SelfValidatingPayload::default(),
                 canister_http: CanisterHttpPayload::default(),
+                canary: CanaryPayload::default(),
+                build_stats: None,
             },
         }
     }
@@ -0,0 +1,22 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ic_types::Time;
+use std::convert::TryInto;
+
+/*
+Check that formatting a time and parsing it back yields the same time, for
+the first 8 bytes as nanoseconds since the UNIX epoch, and that times parsed
+from the bytes as a string roundtrip as well.
+*/
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(nanos) = data.get(..8) {
+        let time = Time::from_nanos_since_unix_epoch(u64::from_le_bytes(nanos.try_into().unwrap()));
+        assert_eq!(time.to_string().parse::<Time>(), Ok(time));
+    }
+
+    if let Some(Ok(time)) = std::str::from_utf8(data).ok().map(str::parse::<Time>) {
+        assert_eq!(time.to_string().parse::<Time>(), Ok(time));
+    }
+});
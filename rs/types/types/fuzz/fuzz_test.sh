@@ -0,0 +1,8 @@
+#!/usr/bin/env bash
+
+set -euo pipefail
+
+# Demo how to execute tests. Copy the seeds to a scratch corpus directory so
+# they aren't modified.
+# mkdir -p corpus/time && cp seeds/time/* corpus/time/
+# rs/types/types/fuzz/time -max_total_time=15 corpus/time
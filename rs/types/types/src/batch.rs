@@ -1,11 +1,17 @@
 //! Contains Batch, Payload, and specific Payload types that are passed between
 //! Consensus and Message Routing.
 
+mod build_stats;
+mod canary;
 mod canister_http;
 mod ingress;
 mod self_validating;
 mod xnet;
 
+pub use self::build_stats::{
+    PayloadBuildStats, PayloadSection, SectionBuildStats, MAX_PAYLOAD_BUILD_STATS_SECTIONS,
+};
+pub use self::canary::{CanaryPayload, MAX_CANARY_PAYLOAD_SIZE};
 pub use self::canister_http::{CanisterHttpPayload, MAX_CANISTER_HTTP_PAYLOAD_SIZE};
 pub use self::ingress::{IngressPayload, IngressPayloadError, InvalidIngressPayload};
 pub use self::self_validating::{SelfValidatingPayload, MAX_BITCOIN_BLOCK_SIZE};
@@ -77,6 +83,10 @@ pub struct BatchPayload {
     pub xnet: XNetPayload,
     pub self_validating: SelfValidatingPayload,
     pub canister_http: CanisterHttpPayload,
+    pub canary: CanaryPayload,
+    /// Statistics of the block maker on building this payload, if it chose to
+    /// include them.
+    pub build_stats: Option<PayloadBuildStats>,
 }
 
 /// Return ingress messages, xnet messages, and responses from the bitcoin adapter.
@@ -92,12 +102,16 @@ impl BatchPayload {
         xnet: XNetPayload,
         self_validating: SelfValidatingPayload,
         canister_http: CanisterHttpPayload,
+        canary: CanaryPayload,
+        build_stats: Option<PayloadBuildStats>,
     ) -> Self {
         BatchPayload {
             ingress,
             xnet,
             self_validating,
             canister_http,
+            canary,
+            build_stats,
         }
     }
 
@@ -117,6 +131,7 @@ impl BatchPayload {
             && self.xnet.stream_slices.is_empty()
             && self.self_validating.is_empty()
             && self.canister_http.is_empty()
+            && self.canary.is_empty()
     }
 }
 #[cfg(test)]
@@ -132,6 +147,7 @@ mod tests {
         assert_eq!(XNetPayload::default().count_bytes(), 0);
         assert_eq!(SelfValidatingPayload::default().count_bytes(), 0);
         assert_eq!(CanisterHttpPayload::default().count_bytes(), 0);
+        assert_eq!(CanaryPayload::default().count_bytes(), 0);
     }
 
     #[test]
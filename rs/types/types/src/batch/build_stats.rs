@@ -0,0 +1,202 @@
+use crate::{node_id_into_protobuf, NodeId, PrincipalId};
+use ic_protobuf::types::v1 as pb;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// The upper bound on the number of section entries in [`PayloadBuildStats`],
+/// so that the stats stay negligible in size compared to the payload.
+pub const MAX_PAYLOAD_BUILD_STATS_SECTIONS: usize = 16;
+
+/// A section of the [`BatchPayload`](super::BatchPayload).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum PayloadSection {
+    Ingress,
+    XNet,
+    SelfValidating,
+    CanisterHttp,
+    Canary,
+}
+
+impl PayloadSection {
+    /// The name of the section, e.g. as a metric label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PayloadSection::Ingress => "Ingress",
+            PayloadSection::XNet => "XNet",
+            PayloadSection::SelfValidating => "SelfValidating",
+            PayloadSection::CanisterHttp => "CanisterHttp",
+            PayloadSection::Canary => "Canary",
+        }
+    }
+}
+
+/// Statistics of the block maker on building one section of the payload.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SectionBuildStats {
+    pub section: PayloadSection,
+    /// The time the block maker spent building the section.
+    pub build_duration_micros: u64,
+    /// The number of bytes the section was allowed to fill, i.e. what was left
+    /// of the block size limit when the section was built.
+    pub byte_limit: u64,
+    /// The number of bytes the block maker included in the section.
+    pub bytes_included: u64,
+}
+
+/// Optional statistics attached to a batch payload by its block maker, to
+/// analyze across the network which block makers underfill blocks and why.
+///
+/// The stats are self-reported and only checked for consistency with the
+/// payload and the proposal during validation, so they must not be used for
+/// anything but analysis. They are not delivered to Message Routing.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PayloadBuildStats {
+    /// The node that built the payload. Must be the signer of the proposal.
+    pub block_maker: NodeId,
+    /// The sections in the order they were built.
+    pub sections: Vec<SectionBuildStats>,
+}
+
+impl PayloadBuildStats {
+    /// Returns the stats of `section`, if any.
+    pub fn section(&self, section: PayloadSection) -> Option<&SectionBuildStats> {
+        self.sections.iter().find(|stats| stats.section == section)
+    }
+}
+
+impl From<PayloadSection> for pb::PayloadSection {
+    fn from(section: PayloadSection) -> Self {
+        match section {
+            PayloadSection::Ingress => pb::PayloadSection::Ingress,
+            PayloadSection::XNet => pb::PayloadSection::Xnet,
+            PayloadSection::SelfValidating => pb::PayloadSection::SelfValidating,
+            PayloadSection::CanisterHttp => pb::PayloadSection::CanisterHttp,
+            PayloadSection::Canary => pb::PayloadSection::Canary,
+        }
+    }
+}
+
+impl TryFrom<i32> for PayloadSection {
+    type Error = String;
+    fn try_from(section: i32) -> Result<Self, Self::Error> {
+        match pb::PayloadSection::from_i32(section) {
+            Some(pb::PayloadSection::Ingress) => Ok(PayloadSection::Ingress),
+            Some(pb::PayloadSection::Xnet) => Ok(PayloadSection::XNet),
+            Some(pb::PayloadSection::SelfValidating) => Ok(PayloadSection::SelfValidating),
+            Some(pb::PayloadSection::CanisterHttp) => Ok(PayloadSection::CanisterHttp),
+            Some(pb::PayloadSection::Canary) => Ok(PayloadSection::Canary),
+            Some(pb::PayloadSection::Unspecified) | None => {
+                Err(format!("Error: Unknown payload section {}", section))
+            }
+        }
+    }
+}
+
+impl From<&PayloadBuildStats> for pb::PayloadBuildStats {
+    fn from(stats: &PayloadBuildStats) -> Self {
+        Self {
+            block_maker: Some(node_id_into_protobuf(stats.block_maker)),
+            sections: stats
+                .sections
+                .iter()
+                .map(|section| pb::SectionBuildStats {
+                    section: pb::PayloadSection::from(section.section) as i32,
+                    build_duration_micros: section.build_duration_micros,
+                    byte_limit: section.byte_limit,
+                    bytes_included: section.bytes_included,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<pb::PayloadBuildStats> for PayloadBuildStats {
+    type Error = String;
+    fn try_from(stats: pb::PayloadBuildStats) -> Result<Self, Self::Error> {
+        // Unlike `node_id_try_from_protobuf`, don't panic on a missing
+        // principal, as the stats are received from other nodes.
+        let block_maker = stats
+            .block_maker
+            .and_then(|node_id| node_id.principal_id)
+            .ok_or_else(|| String::from("Error: PayloadBuildStats missing block_maker"))?;
+        let block_maker = NodeId::from(
+            PrincipalId::try_from(block_maker)
+                .map_err(|err| format!("Error: Invalid block_maker: {:?}", err))?,
+        );
+        if stats.sections.len() > MAX_PAYLOAD_BUILD_STATS_SECTIONS {
+            return Err(format!(
+                "Error: PayloadBuildStats has {} sections, more than the maximum of {}",
+                stats.sections.len(),
+                MAX_PAYLOAD_BUILD_STATS_SECTIONS
+            ));
+        }
+        let sections = stats
+            .sections
+            .into_iter()
+            .map(|section| {
+                Ok(SectionBuildStats {
+                    section: PayloadSection::try_from(section.section)?,
+                    build_duration_micros: section.build_duration_micros,
+                    byte_limit: section.byte_limit,
+                    bytes_included: section.bytes_included,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            block_maker,
+            sections,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn section_names_match_their_debug_representation() {
+        // The names used to be formatted with `Debug`, and are kept as metric
+        // labels.
+        for section in [
+            PayloadSection::Ingress,
+            PayloadSection::XNet,
+            PayloadSection::SelfValidating,
+            PayloadSection::CanisterHttp,
+            PayloadSection::Canary,
+        ] {
+            assert_eq!(section.as_str(), format!("{:?}", section));
+        }
+    }
+
+    #[test]
+    fn payload_build_stats_protobuf_roundtrip() {
+        let stats = PayloadBuildStats {
+            block_maker: NodeId::from(PrincipalId::new_node_test_id(3)),
+            sections: vec![
+                SectionBuildStats {
+                    section: PayloadSection::XNet,
+                    build_duration_micros: 1500,
+                    byte_limit: 4 * 1024 * 1024,
+                    bytes_included: 1024,
+                },
+                SectionBuildStats {
+                    section: PayloadSection::Ingress,
+                    build_duration_micros: 300,
+                    byte_limit: 4 * 1024 * 1024 - 1024,
+                    bytes_included: 0,
+                },
+            ],
+        };
+        let pb_stats = pb::PayloadBuildStats::from(&stats);
+        assert_eq!(PayloadBuildStats::try_from(pb_stats), Ok(stats));
+    }
+
+    #[test]
+    fn payload_build_stats_without_block_maker_are_rejected() {
+        let pb_stats = pb::PayloadBuildStats {
+            block_maker: Some(pb::NodeId { principal_id: None }),
+            sections: vec![],
+        };
+        assert!(PayloadBuildStats::try_from(pb_stats).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use crate::{CountBytes, Height};
+use ic_protobuf::types::v1 as pb;
+use serde::{Deserialize, Serialize};
+
+/// The upper bound on the size of the canary payload, regardless of the size
+/// configured in the registry.
+pub const MAX_CANARY_PAYLOAD_SIZE: usize = 1024 * 1024; // 1 MiB
+
+/// A payload section without any function, used to rehearse the rollout of new
+/// payload sections: it is serialized, accounted for in the block size and
+/// validated like any other section, but never delivered to Message Routing.
+///
+/// Its presence and size are controlled by the `canary_payload_bytes` subnet
+/// feature. The content is derived from the block height, so that all
+/// replicas can validate it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CanaryPayload {
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+impl CanaryPayload {
+    /// Returns the canary payload of `size` bytes for the block at `height`.
+    pub fn new(height: Height, size: usize) -> Self {
+        let pattern = height.get().to_le_bytes();
+        Self {
+            data: pattern.iter().copied().cycle().take(size).collect(),
+        }
+    }
+
+    /// Returns true if the payload is the canary payload of its size for the
+    /// block at `height`.
+    pub fn is_valid_at(&self, height: Height) -> bool {
+        *self == Self::new(height, self.data.len())
+    }
+
+    /// Returns true, if this is an empty payload
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+impl CountBytes for CanaryPayload {
+    fn count_bytes(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl From<&CanaryPayload> for pb::CanaryPayload {
+    fn from(payload: &CanaryPayload) -> Self {
+        Self {
+            data: payload.data.clone(),
+        }
+    }
+}
+
+impl From<pb::CanaryPayload> for CanaryPayload {
+    fn from(payload: pb::CanaryPayload) -> Self {
+        Self { data: payload.data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canary_payload_is_only_valid_at_its_height() {
+        let payload = CanaryPayload::new(Height::new(7), 20);
+        assert_eq!(payload.count_bytes(), 20);
+        assert!(payload.is_valid_at(Height::new(7)));
+        assert!(!payload.is_valid_at(Height::new(8)));
+        assert!(CanaryPayload::default().is_valid_at(Height::new(8)));
+    }
+}
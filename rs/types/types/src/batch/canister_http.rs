@@ -39,6 +39,21 @@ impl CanisterHttpPayload {
     pub fn is_empty(&self) -> bool {
         self.num_responses() == 0
     }
+
+    /// Returns true, if the responses and the timeouts are sorted by callback
+    /// id
+    pub fn is_canonically_ordered(&self) -> bool {
+        self.responses
+            .windows(2)
+            .all(|w| w[0].content.id < w[1].content.id)
+            && self.timeouts.windows(2).all(|w| w[0] < w[1])
+    }
+
+    /// Sorts the responses and the timeouts by callback id
+    pub fn sort_canonically(&mut self) {
+        self.responses.sort_by_key(|response| response.content.id);
+        self.timeouts.sort();
+    }
 }
 
 impl From<&CanisterHttpPayload> for pb::CanisterHttpPayload {
@@ -93,6 +93,23 @@ impl IngressPayload {
         self.id_and_pos.is_empty()
     }
 
+    /// Return true if the messages are in canonical order, i.e. sorted by
+    /// expiry and then message id.
+    pub fn is_canonically_ordered(&self) -> bool {
+        self.id_and_pos.windows(2).all(|w| w[0].0 < w[1].0)
+    }
+
+    /// Return the payload with its messages in canonical order, i.e. sorted
+    /// by expiry and then message id.
+    pub fn into_canonical_order(self) -> Result<Self, InvalidIngressPayload> {
+        if self.is_canonically_ordered() {
+            return Ok(self);
+        }
+        let mut msgs = Vec::<SignedIngress>::try_from(self)?;
+        msgs.sort_by_key(IngressMessageId::from);
+        Ok(Self::from(msgs))
+    }
+
     /// Return the ingress message at a given index, which is expected to be
     /// less than `message_count`.
     pub fn get(
@@ -39,6 +39,18 @@ impl SelfValidatingPayload {
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
+
+    /// Returns true if the responses are sorted by callback id
+    pub fn is_canonically_ordered(&self) -> bool {
+        self.0
+            .windows(2)
+            .all(|w| w[0].callback_id < w[1].callback_id)
+    }
+
+    /// Sorts the responses by callback id
+    pub fn sort_canonically(&mut self) {
+        self.0.sort_by_key(|response| response.callback_id);
+    }
 }
 
 impl From<&SelfValidatingPayload> for pb::SelfValidatingPayload {
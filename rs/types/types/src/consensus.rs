@@ -1187,6 +1187,8 @@ impl From<&Block> for pb::Block {
             ingress_payload,
             self_validating_payload,
             canister_http_payload,
+            canary_payload,
+            payload_build_stats,
             ecdsa_summary,
         ) = if payload.is_summary() {
             (
@@ -1195,6 +1197,8 @@ impl From<&Block> for pb::Block {
                 None,
                 None,
                 None,
+                None,
+                None,
                 payload
                     .as_summary()
                     .ecdsa
@@ -1209,6 +1213,8 @@ impl From<&Block> for pb::Block {
                 Some(pb::IngressPayload::from(&batch.ingress)),
                 Some(pb::SelfValidatingPayload::from(&batch.self_validating)),
                 Some(pb::CanisterHttpPayload::from(&batch.canister_http)),
+                Some(pb::CanaryPayload::from(&batch.canary)),
+                batch.build_stats.as_ref().map(pb::PayloadBuildStats::from),
                 None,
             )
         };
@@ -1225,6 +1231,8 @@ impl From<&Block> for pb::Block {
             ingress_payload,
             self_validating_payload,
             canister_http_payload,
+            canary_payload,
+            payload_build_stats,
             ecdsa_summary,
             payload_hash: block.payload.get_hash().clone().get().0,
         }
@@ -1260,6 +1268,14 @@ impl TryFrom<pb::Block> for Block {
                 .map(crate::batch::CanisterHttpPayload::try_from)
                 .transpose()?
                 .unwrap_or_default(),
+            block
+                .canary_payload
+                .map(crate::batch::CanaryPayload::from)
+                .unwrap_or_default(),
+            block
+                .payload_build_stats
+                .map(crate::batch::PayloadBuildStats::try_from)
+                .transpose()?,
         );
         let payload = match dkg_payload {
             dkg::Payload::Summary(summary) => {
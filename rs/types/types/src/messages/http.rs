@@ -634,6 +634,32 @@ pub struct HttpStatusResponse {
     pub impl_hash: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub replica_health_status: Option<ReplicaHealthStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canister_ranges: Option<CanisterRangesSummary>,
+    /// The number of call requests waiting for or in submission to the
+    /// ingress pool of the replica. Agents and boundary nodes may prefer other
+    /// replicas while it is high.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ingress_queue_depth: Option<u64>,
+    /// The time the replica started. A recent boot time tells a restart apart
+    /// from a replica that was unreachable.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_time: Option<Time>,
+    /// The number of seconds the replica has been up since `boot_time`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime_seconds: Option<u64>,
+}
+
+/// A summary of the canister ranges assigned to the subnet, as reported by
+/// `/api/v2/status`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct CanisterRangesSummary {
+    /// The number of canister ranges assigned to the subnet in the routing
+    /// table.
+    pub num_ranges: u64,
+    /// The number of canisters hosted on the subnet.
+    pub num_canisters: u64,
 }
 
 #[cfg(test)]
@@ -773,6 +799,10 @@ mod test {
                 impl_version: Some("0.0".to_string()),
                 impl_hash: None,
                 replica_health_status: Some(ReplicaHealthStatus::Starting),
+                canister_ranges: None,
+                ingress_queue_depth: None,
+                boot_time: None,
+                uptime_seconds: None,
             },
             Value::Map(btreemap! {
                 text("ic_api_version") => text("foobar"),
@@ -791,6 +821,10 @@ mod test {
                 impl_version: Some("0.0".to_string()),
                 impl_hash: None,
                 replica_health_status: Some(ReplicaHealthStatus::Healthy),
+                canister_ranges: None,
+                ingress_queue_depth: None,
+                boot_time: None,
+                uptime_seconds: None,
             },
             Value::Map(btreemap! {
                 text("ic_api_version") => text("foobar"),
@@ -810,6 +844,10 @@ mod test {
                 impl_version: Some("0.0".to_string()),
                 impl_hash: None,
                 replica_health_status: None,
+                canister_ranges: None,
+                ingress_queue_depth: None,
+                boot_time: None,
+                uptime_seconds: None,
             },
             Value::Map(btreemap! {
                 text("ic_api_version") => text("foobar"),
@@ -819,6 +857,38 @@ mod test {
         );
     }
 
+    #[test]
+    fn encoding_status_with_canister_ranges() {
+        assert_cbor_ser_equal(
+            &HttpStatusResponse {
+                ic_api_version: "foobar".to_string(),
+                root_key: None,
+                impl_version: Some("0.0".to_string()),
+                impl_hash: None,
+                replica_health_status: Some(ReplicaHealthStatus::Healthy),
+                canister_ranges: Some(CanisterRangesSummary {
+                    num_ranges: 2,
+                    num_canisters: 42,
+                }),
+                ingress_queue_depth: Some(3),
+                boot_time: Some(Time::from_nanos_since_unix_epoch(1_000_000_000)),
+                uptime_seconds: Some(60),
+            },
+            Value::Map(btreemap! {
+                text("ic_api_version") => text("foobar"),
+                text("impl_version") => text("0.0"),
+                text("replica_health_status") => text("healthy"),
+                text("canister_ranges") => Value::Map(btreemap! {
+                    text("num_ranges") => int(2),
+                    text("num_canisters") => int(42),
+                }),
+                text("ingress_queue_depth") => int(3),
+                text("boot_time") => int(1_000_000_000),
+                text("uptime_seconds") => int(60),
+            }),
+        );
+    }
+
     #[test]
     fn encoding_delegation() {
         assert_cbor_ser_equal(
@@ -1,20 +1,27 @@
-// DFN-467: clippy complains about the code generated by derive(Arbitrary)
-#![cfg_attr(test, allow(clippy::unit_arg))]
 //! Defines the [`Time`] type used by the Internet Computer.
+//!
+//! With the `proptest` feature, [`Time`] implements `Arbitrary` and the
+//! [`strategies`] module provides strategies generating times in bounded
+//! ranges, for use in property tests of other crates.
 
 use ic_constants::{MAX_INGRESS_TTL, PERMITTED_DRIFT};
-#[cfg(test)]
-use proptest_derive::Arbitrary;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 
 /// Time since UNIX_EPOCH (in nanoseconds). Just like 'std::time::Instant' or
 /// 'std::time::SystemTime', [Time] does not implement the [Default] trait.
 /// Please use `ic_test_utilities::mock_time` if you ever need such a value.
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Serialize, Deserialize)]
-#[cfg_attr(test, derive(Arbitrary))]
+///
+/// [Time] is serialized as an RFC 3339 string (e.g.
+/// `"2022-08-01T12:34:56.123456789Z"`) by human-readable serializers such as
+/// JSON or YAML, and as a `u64` of nanoseconds otherwise (e.g. CBOR). Both
+/// forms are accepted when deserializing from a human-readable format.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub struct Time(u64);
 
 /// The unix epoch.
@@ -66,6 +73,22 @@ impl Time {
     fn from_duration(t: Duration) -> Self {
         Time(t.as_nanos() as u64)
     }
+
+    /// Returns the start of the interval of length `interval` holding this
+    /// time, with intervals aligned to UNIX_EPOCH.
+    ///
+    /// Panics if `interval` is zero or longer than `u64::MAX` nanoseconds.
+    pub fn floor_to(self, interval: Duration) -> Time {
+        let interval = interval_nanos(interval);
+        Time(self.0 - self.0 % interval)
+    }
+}
+
+fn interval_nanos(interval: Duration) -> u64 {
+    match u64::try_from(interval.as_nanos()) {
+        Ok(nanos) if nanos > 0 => nanos,
+        _ => panic!("Invalid interval {:?}", interval),
+    }
 }
 
 impl TryFrom<Duration> for Time {
@@ -85,19 +108,146 @@ impl From<Time> for Duration {
     }
 }
 
+impl Serialize for Time {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            if let Some(rfc3339) = self.to_rfc3339() {
+                return serializer.serialize_str(&rfc3339);
+            }
+        }
+        serializer.serialize_u64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Time {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TimeVisitor;
+
+        impl<'de> de::Visitor<'de> for TimeVisitor {
+            type Value = Time;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "nanoseconds since UNIX epoch or an RFC 3339 timestamp")
+            }
+
+            fn visit_u64<E: de::Error>(self, nanos: u64) -> Result<Time, E> {
+                Ok(Time(nanos))
+            }
+
+            fn visit_i64<E: de::Error>(self, nanos: i64) -> Result<Time, E> {
+                u64::try_from(nanos)
+                    .map(Time)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(nanos), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Time, E> {
+                Time::from_rfc3339(s).ok_or_else(|| E::invalid_value(de::Unexpected::Str(s), &self))
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(TimeVisitor)
+        } else {
+            deserializer.deserialize_u64(TimeVisitor)
+        }
+    }
+}
+
 #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
-impl fmt::Display for Time {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use chrono::{TimeZone, Utc};
+impl Time {
+    /// Formats the time as an RFC 3339 timestamp in UTC, with as many
+    /// fractional digits as needed. Returns `None` if the time is too far in
+    /// the future to be represented.
+    pub fn to_rfc3339(self) -> Option<String> {
+        use chrono::{SecondsFormat, TimeZone, Utc};
         use std::convert::TryInto;
 
-        match self.0.try_into() {
-            Ok(signed) => write!(f, "{}", Utc.timestamp_nanos(signed)),
-            Err(_) => write!(f, "{}ns", self.0),
+        let signed: i64 = self.0.try_into().ok()?;
+        Some(
+            Utc.timestamp_nanos(signed)
+                .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        )
+    }
+
+    /// Parses an RFC 3339 timestamp. Returns `None` if `s` is not a valid
+    /// timestamp, or if it is outside the range of [`Time`].
+    pub fn from_rfc3339(s: &str) -> Option<Self> {
+        let date_time = chrono::DateTime::parse_from_rfc3339(s).ok()?;
+        Self::from_date_time(&date_time)
+    }
+
+    // Not using `Utc.timestamp_nanos()`, which only covers times up to
+    // `i64::MAX` nanoseconds.
+    fn to_date_time(self) -> chrono::DateTime<chrono::Utc> {
+        use chrono::{TimeZone, Utc};
+
+        Utc.timestamp(
+            (self.0 / NANOS_PER_SEC) as i64,
+            (self.0 % NANOS_PER_SEC) as u32,
+        )
+    }
+
+    // Not using `timestamp_nanos()`, which panics on overflow.
+    fn from_date_time<Tz: chrono::TimeZone>(date_time: &chrono::DateTime<Tz>) -> Option<Self> {
+        let nanos = date_time.timestamp() as i128 * NANOS_PER_SEC as i128
+            + date_time.timestamp_subsec_nanos() as i128;
+        u64::try_from(nanos).ok().map(Time)
+    }
+
+    // Parses the format of `Display`, or an RFC 3339 timestamp.
+    fn parse_date_time(s: &str) -> Option<Self> {
+        match s.strip_suffix(DISPLAY_TIME_ZONE) {
+            Some(date_time) => {
+                let naive =
+                    chrono::NaiveDateTime::parse_from_str(date_time, DISPLAY_FORMAT).ok()?;
+                Self::from_date_time(&chrono::DateTime::<chrono::Utc>::from_utc(
+                    naive,
+                    chrono::Utc,
+                ))
+            }
+            None => Self::from_rfc3339(s),
         }
     }
 }
 
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Time {
+    /// RFC 3339 formatting is not supported on this target.
+    pub fn to_rfc3339(self) -> Option<String> {
+        None
+    }
+
+    /// RFC 3339 parsing is not supported on this target.
+    pub fn from_rfc3339(_s: &str) -> Option<Self> {
+        None
+    }
+
+    fn parse_date_time(_s: &str) -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The format `Display` writes times in, followed by [`DISPLAY_TIME_ZONE`].
+/// The fraction of the second has as many digits as needed, in groups of 3.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+const DISPLAY_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+const DISPLAY_TIME_ZONE: &str = " UTC";
+
+/// Formats the time in UTC, e.g. `2022-08-01 12:34:56.123456789 UTC`, with
+/// nanosecond precision. Unlike [`Time::to_rfc3339`], all times are formatted
+/// as dates.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_date_time())
+    }
+}
+
 #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
 impl fmt::Display for Time {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -105,6 +255,34 @@ impl fmt::Display for Time {
     }
 }
 
+/// A string that is not a [`Time`] as written by `Display`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseTimeError(String);
+
+impl fmt::Display for ParseTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid time {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseTimeError {}
+
+/// Parses the output of `Display`, so that `time.to_string().parse()` yields
+/// `time` for all times. RFC 3339 timestamps and nanoseconds followed by `ns`,
+/// as formerly written for times after `i64::MAX` nanoseconds, are accepted
+/// as well.
+impl FromStr for Time {
+    type Err = ParseTimeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_suffix("ns")
+            .and_then(|nanos| nanos.parse().ok())
+            .map(Time)
+            .or_else(|| Time::parse_date_time(s))
+            .ok_or_else(|| ParseTimeError(s.to_string()))
+    }
+}
+
 /// Returns the current time.
 ///
 /// WARNING: this function should not be used in any deterministic part of the
@@ -145,3 +323,667 @@ pub fn current_time_and_expiry_time() -> (Time, Time) {
         UNIX_EPOCH + (since_epoch + MAX_INGRESS_TTL - PERMITTED_DRIFT),
     )
 }
+
+#[cfg(any(test, feature = "proptest"))]
+impl proptest::arbitrary::Arbitrary for Time {
+    type Parameters = ();
+    type Strategy = proptest::strategy::BoxedStrategy<Time>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        use proptest::strategy::Strategy;
+        proptest::arbitrary::any::<u64>().prop_map(Time).boxed()
+    }
+}
+
+/// Proptest strategies generating [`Time`]s in bounded ranges.
+#[cfg(any(test, feature = "proptest"))]
+pub mod strategies {
+    use super::*;
+    use proptest::strategy::Strategy;
+
+    /// Times in `start..end`.
+    ///
+    /// Panics if `start >= end`, as there is no time to generate.
+    pub fn time_in(start: Time, end: Time) -> impl Strategy<Value = Time> {
+        assert!(start < end, "time_in: empty range {:?}..{:?}", start, end);
+        (start.0..end.0).prop_map(Time)
+    }
+
+    /// Times in `now..=now + duration`, saturating at the maximum time.
+    pub fn time_within(now: Time, duration: Duration) -> impl Strategy<Value = Time> {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        (now.0..=now.0.saturating_add(nanos)).prop_map(Time)
+    }
+
+    /// Ingress expiry times valid at `now`, i.e. within `MAX_INGRESS_TTL` of
+    /// `now`, allowing for `PERMITTED_DRIFT` in the future.
+    pub fn expiry_within_ingress_ttl(now: Time) -> impl Strategy<Value = Time> {
+        time_within(now, MAX_INGRESS_TTL + PERMITTED_DRIFT)
+    }
+}
+
+/// A point in time relative to the (arbitrary) origin of the [`Clock`] that
+/// produced it. Only meaningful compared to other `RelativeTime`s of the same
+/// clock.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub struct RelativeTime(Duration);
+
+impl RelativeTime {
+    /// Returns the time elapsed since the origin of the clock.
+    pub fn since_origin(self) -> Duration {
+        self.0
+    }
+
+    /// Returns the time elapsed from `earlier` to `self`, zero if `earlier`
+    /// is later.
+    pub fn saturating_duration_since(self, earlier: RelativeTime) -> Duration {
+        self.0.checked_sub(earlier.0).unwrap_or_default()
+    }
+}
+
+impl std::ops::Add<Duration> for RelativeTime {
+    type Output = RelativeTime;
+    fn add(self, dur: Duration) -> RelativeTime {
+        RelativeTime(self.0 + dur)
+    }
+}
+
+/// A monotonic clock for measuring durations, e.g. for metrics and timeouts.
+///
+/// Unlike [`Time`], the readings of a clock are not related to wall-clock
+/// time. Components measuring durations take an `Arc<dyn Clock>`, so that
+/// tests can use a [`ManualClock`] and assert timing-dependent behavior
+/// deterministically.
+pub trait Clock: Send + Sync {
+    /// Returns the current reading of the clock.
+    fn now(&self) -> RelativeTime;
+}
+
+/// A [`Clock`] backed by [`std::time::Instant`], with the time of its
+/// creation as origin.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+#[derive(Clone, Debug)]
+pub struct SystemClock {
+    origin: std::time::Instant,
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            origin: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+impl Clock for SystemClock {
+    fn now(&self) -> RelativeTime {
+        RelativeTime(self.origin.elapsed())
+    }
+}
+
+/// A [`Clock`] that only advances when told to, for tests.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: std::sync::Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advances the clock by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> RelativeTime {
+        RelativeTime(*self.now.lock().unwrap())
+    }
+}
+
+/// Measures the time elapsed since it was started, on a [`Clock`].
+#[derive(Clone)]
+pub struct Stopwatch {
+    clock: std::sync::Arc<dyn Clock>,
+    start: RelativeTime,
+}
+
+impl Stopwatch {
+    /// Starts a stopwatch at the current reading of `clock`.
+    pub fn start(clock: std::sync::Arc<dyn Clock>) -> Self {
+        let start = clock.now();
+        Self { clock, start }
+    }
+
+    /// Returns the time elapsed since the stopwatch was started.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.start)
+    }
+
+    /// Returns the time elapsed since the stopwatch was started, and starts
+    /// it again.
+    pub fn restart(&mut self) -> Duration {
+        let now = self.clock.now();
+        let elapsed = now.saturating_duration_since(self.start);
+        self.start = now;
+        elapsed
+    }
+}
+
+impl fmt::Debug for Stopwatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stopwatch")
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+/// Counts events over a sliding window of time, e.g. to rate limit requests or
+/// to track the arrival rate of a queue.
+///
+/// Events are aggregated into `num_buckets` buckets per window, so memory use
+/// is bounded regardless of the event rate. As a consequence the window slides
+/// in steps of `window / num_buckets`, and [`RateTracker::count`] may include
+/// events up to one bucket older than `window`.
+///
+/// The current time is passed in explicitly, so that the tracker can be
+/// driven by any [`Time`] source (typically `TimeSource::get_relative_time`)
+/// and is deterministic in tests.
+#[derive(Clone, Debug)]
+pub struct RateTracker {
+    window_nanos: u64,
+    bucket_nanos: u64,
+    /// Start time (in nanoseconds since UNIX epoch) and number of events of
+    /// the non-empty buckets, oldest first.
+    buckets: VecDeque<(u64, u64)>,
+    total: u64,
+}
+
+impl RateTracker {
+    /// Creates a tracker over the given `window`, divided into `num_buckets`.
+    ///
+    /// Panics if `num_buckets` is zero or larger than the number of
+    /// nanoseconds in `window`.
+    pub fn new(window: Duration, num_buckets: u32) -> Self {
+        let window_nanos = window.as_nanos() as u64;
+        assert!(
+            num_buckets > 0 && window_nanos >= num_buckets as u64,
+            "A window of {:?} can't be divided into {} buckets",
+            window,
+            num_buckets
+        );
+        Self {
+            window_nanos,
+            bucket_nanos: window_nanos / num_buckets as u64,
+            buckets: VecDeque::with_capacity(num_buckets as usize + 1),
+            total: 0,
+        }
+    }
+
+    /// The duration of the window events are counted over.
+    pub fn window(&self) -> Duration {
+        Duration::from_nanos(self.window_nanos)
+    }
+
+    /// Records a single event at time `now`.
+    pub fn record(&mut self, now: Time) {
+        self.record_n(now, 1)
+    }
+
+    /// Records `n` events at time `now`. Events recorded with a time earlier
+    /// than a previous event (e.g. after a clock adjustment) are counted in the
+    /// latest bucket.
+    pub fn record_n(&mut self, now: Time, n: u64) {
+        self.evict(now);
+        let start = now
+            .floor_to(Duration::from_nanos(self.bucket_nanos))
+            .as_nanos_since_unix_epoch();
+        match self.buckets.back_mut() {
+            Some((last_start, count)) if *last_start >= start => *count += n,
+            _ => self.buckets.push_back((start, n)),
+        }
+        self.total += n;
+    }
+
+    /// Returns the number of events recorded in the window ending at `now`.
+    pub fn count(&mut self, now: Time) -> u64 {
+        self.evict(now);
+        self.total
+    }
+
+    /// Returns the time at which the oldest events recorded leave the window,
+    /// or `None` if no events are recorded.
+    pub fn next_eviction(&self) -> Option<Time> {
+        self.buckets.front().map(|(start, _)| {
+            Time::from_nanos_since_unix_epoch(
+                start
+                    .saturating_add(self.window_nanos)
+                    .saturating_add(self.bucket_nanos),
+            )
+        })
+    }
+
+    /// Returns the average number of events per second over the window ending
+    /// at `now`.
+    pub fn rate_per_sec(&mut self, now: Time) -> f64 {
+        self.count(now) as f64 / self.window().as_secs_f64()
+    }
+
+    // Drops the buckets that ended before the window ending at `now` started.
+    fn evict(&mut self, now: Time) {
+        let now = now.as_nanos_since_unix_epoch();
+        while let Some(&(start, count)) = self.buckets.front() {
+            if now.saturating_sub(start) < self.window_nanos + self.bucket_nanos {
+                break;
+            }
+            self.buckets.pop_front();
+            self.total -= count;
+        }
+    }
+}
+
+/// The skew of a remote clock relative to the local one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Skew {
+    /// The remote clock is ahead of the local one.
+    Ahead(Duration),
+    /// The remote clock is behind the local one.
+    Behind(Duration),
+}
+
+impl Skew {
+    /// Returns the reading of the remote clock at the time the local clock
+    /// reads `local`, saturating at the bounds of [`Time`].
+    pub fn to_remote(self, local: Time) -> Time {
+        match self {
+            Skew::Ahead(skew) => Time(local.0.saturating_add(saturating_nanos(skew))),
+            Skew::Behind(skew) => Time(local.0.saturating_sub(saturating_nanos(skew))),
+        }
+    }
+
+    /// Returns the skew with its magnitude capped at `max`.
+    pub fn clamp(self, max: Duration) -> Skew {
+        match self {
+            Skew::Ahead(skew) => Skew::Ahead(skew.min(max)),
+            Skew::Behind(skew) => Skew::Behind(skew.min(max)),
+        }
+    }
+}
+
+fn saturating_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// Estimates the [`Skew`] of a remote wall clock relative to the local one,
+/// from pairs of readings of both clocks taken at the same time, e.g. the
+/// system time and the time of the latest block.
+///
+/// The estimate is the median skew of the last `window` samples, so that
+/// outliers, e.g. a block delayed by a slow round, do not move it.
+#[derive(Clone, Debug)]
+pub struct SkewEstimator {
+    window: usize,
+    /// Remote minus local time of the samples in nanoseconds, oldest first.
+    offsets: VecDeque<i128>,
+}
+
+impl SkewEstimator {
+    /// Creates an estimator over the last `window` samples.
+    ///
+    /// Panics if `window` is zero.
+    pub fn new(window: usize) -> Self {
+        assert!(window > 0, "The window of a SkewEstimator can't be empty");
+        Self {
+            window,
+            offsets: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// Records a sample, evicting the oldest one if the window is full.
+    pub fn observe(&mut self, local: Time, remote: Time) {
+        if self.offsets.len() == self.window {
+            self.offsets.pop_front();
+        }
+        self.offsets.push_back(remote.0 as i128 - local.0 as i128);
+    }
+
+    /// Returns the estimated skew, or `None` before the first sample.
+    pub fn skew(&self) -> Option<Skew> {
+        let mut offsets: Vec<_> = self.offsets.iter().copied().collect();
+        offsets.sort_unstable();
+        let median = match offsets.len() {
+            0 => return None,
+            n if n % 2 == 1 => offsets[n / 2],
+            n => (offsets[n / 2 - 1] + offsets[n / 2]) / 2,
+        };
+        let magnitude = Duration::from_nanos(u64::try_from(median.abs()).unwrap_or(u64::MAX));
+        Some(if median >= 0 {
+            Skew::Ahead(magnitude)
+        } else {
+            Skew::Behind(magnitude)
+        })
+    }
+}
+
+/// The latest [`Time`] read from a source that should never go back, e.g.
+/// block or certified times. A read earlier than a previous one is clamped to
+/// it, and the regression is reported to the hook, if any, e.g. to count it
+/// in a metric.
+pub struct MonotonicTime {
+    latest: AtomicU64,
+    on_regression: Option<Box<dyn Fn(Duration) + Send + Sync>>,
+}
+
+impl MonotonicTime {
+    /// Creates a `MonotonicTime` starting at [`UNIX_EPOCH`].
+    pub fn new() -> Self {
+        Self {
+            latest: AtomicU64::new(UNIX_EPOCH.0),
+            on_regression: None,
+        }
+    }
+
+    /// Creates a `MonotonicTime` calling `on_regression` with how far back a
+    /// read went, whenever a read is earlier than a previous one.
+    pub fn with_regression_hook(on_regression: impl Fn(Duration) + Send + Sync + 'static) -> Self {
+        Self {
+            on_regression: Some(Box::new(on_regression)),
+            ..Self::new()
+        }
+    }
+
+    /// Records a read of `time`, returning the latest time read so far.
+    pub fn observe(&self, time: Time) -> Time {
+        let previous = self.latest.fetch_max(time.0, Ordering::Relaxed);
+        if previous <= time.0 {
+            return time;
+        }
+        if let Some(on_regression) = &self.on_regression {
+            on_regression(Duration::from_nanos(previous - time.0));
+        }
+        Time(previous)
+    }
+
+    /// Returns the latest time read so far, [`UNIX_EPOCH`] before the first
+    /// read.
+    pub fn get(&self) -> Time {
+        Time(self.latest.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for MonotonicTime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for MonotonicTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MonotonicTime")
+            .field("latest", &self.get())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn at_secs(secs: u64) -> Time {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn floor_to_aligns_to_the_epoch() {
+        let hour = Duration::from_secs(3_600);
+        let time = at_secs(5 * 3_600 + 42) + Duration::from_nanos(7);
+        assert_eq!(time.floor_to(hour), at_secs(5 * 3_600));
+
+        // Boundaries belong to the interval they start.
+        assert_eq!(at_secs(3_600).floor_to(hour), at_secs(3_600));
+        assert_eq!(UNIX_EPOCH.floor_to(hour), UNIX_EPOCH);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid interval")]
+    fn zero_intervals_are_rejected() {
+        at_secs(1).floor_to(Duration::ZERO);
+    }
+
+    #[test]
+    #[should_panic(expected = "time_in: empty range")]
+    fn empty_time_ranges_are_rejected() {
+        let _ = strategies::time_in(at_secs(2), at_secs(2));
+    }
+
+    #[test]
+    fn rate_tracker_counts_events_in_window() {
+        let mut tracker = RateTracker::new(Duration::from_secs(10), 10);
+        for secs in 0..10 {
+            tracker.record(at_secs(secs));
+        }
+        assert_eq!(tracker.count(at_secs(9)), 10);
+        assert_eq!(tracker.rate_per_sec(at_secs(9)), 1.0);
+
+        // The window slides one bucket at a time.
+        assert_eq!(tracker.count(at_secs(11)), 9);
+        assert_eq!(tracker.count(at_secs(15)), 5);
+        assert_eq!(tracker.count(at_secs(100)), 0);
+
+        tracker.record_n(at_secs(100), 7);
+        assert_eq!(tracker.count(at_secs(100)), 7);
+        // The bucket of the events leaves the window a bucket after it ends.
+        assert_eq!(tracker.next_eviction(), Some(at_secs(111)));
+        assert_eq!(tracker.count(at_secs(111)), 0);
+        assert_eq!(tracker.next_eviction(), None);
+    }
+
+    #[test]
+    fn rate_tracker_handles_time_going_backwards() {
+        let mut tracker = RateTracker::new(Duration::from_secs(10), 5);
+        tracker.record(at_secs(20));
+        tracker.record(at_secs(5));
+        assert_eq!(tracker.count(at_secs(20)), 2);
+        assert_eq!(tracker.count(at_secs(3)), 2);
+        assert_eq!(tracker.count(at_secs(32)), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn rate_tracker_rejects_zero_buckets() {
+        RateTracker::new(Duration::from_secs(1), 0);
+    }
+
+    #[test]
+    fn skew_estimator_reports_median_skew() {
+        let mut estimator = SkewEstimator::new(3);
+        assert_eq!(estimator.skew(), None);
+
+        estimator.observe(at_secs(100), at_secs(98));
+        assert_eq!(estimator.skew(), Some(Skew::Behind(Duration::from_secs(2))));
+
+        // An outlier does not move the estimate.
+        estimator.observe(at_secs(101), at_secs(99));
+        estimator.observe(at_secs(102), at_secs(50));
+        assert_eq!(estimator.skew(), Some(Skew::Behind(Duration::from_secs(2))));
+
+        // Older samples are evicted.
+        estimator.observe(at_secs(103), at_secs(106));
+        estimator.observe(at_secs(104), at_secs(107));
+        assert_eq!(estimator.skew(), Some(Skew::Ahead(Duration::from_secs(3))));
+    }
+
+    #[test]
+    fn skew_is_applied_saturating() {
+        let skew = Skew::Behind(Duration::from_secs(5));
+        assert_eq!(skew.to_remote(at_secs(10)), at_secs(5));
+        assert_eq!(skew.to_remote(at_secs(1)), UNIX_EPOCH);
+        assert_eq!(
+            skew.clamp(Duration::from_secs(1)),
+            Skew::Behind(Duration::from_secs(1))
+        );
+        assert_eq!(
+            Skew::Ahead(Duration::MAX).to_remote(at_secs(1)),
+            Time::from_nanos_since_unix_epoch(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn serializes_as_rfc3339_when_human_readable() {
+        let time = Time::from_nanos_since_unix_epoch(1_659_357_296_123_456_789);
+        let json = serde_json::to_string(&time).unwrap();
+        assert_eq!(json, "\"2022-08-01T12:34:56.123456789Z\"");
+        assert_eq!(serde_json::from_str::<Time>(&json).unwrap(), time);
+
+        // Integers are still accepted, for existing config files.
+        assert_eq!(
+            serde_json::from_str::<Time>("1659357296123456789").unwrap(),
+            time
+        );
+        assert_eq!(
+            serde_json::from_str::<Time>("\"2022-08-01T14:34:56.123456789+02:00\"").unwrap(),
+            time
+        );
+        assert!(serde_json::from_str::<Time>("\"1969-12-31T23:59:59Z\"").is_err());
+        assert!(serde_json::from_str::<Time>("-1").is_err());
+        assert!(serde_json::from_str::<Time>("\"9999-01-01T00:00:00Z\"").is_err());
+    }
+
+    #[test]
+    fn serializes_as_nanos_when_not_human_readable() {
+        let time = Time::from_nanos_since_unix_epoch(1_659_357_296_123_456_789);
+        let cbor = serde_cbor::to_vec(&time).unwrap();
+        assert_eq!(
+            cbor,
+            serde_cbor::to_vec(&1_659_357_296_123_456_789_u64).unwrap()
+        );
+        assert_eq!(serde_cbor::from_slice::<Time>(&cbor).unwrap(), time);
+    }
+
+    #[test]
+    fn out_of_range_time_serializes_as_nanos() {
+        let time = Time::from_nanos_since_unix_epoch(u64::MAX);
+        let json = serde_json::to_string(&time).unwrap();
+        assert_eq!(json, u64::MAX.to_string());
+        assert_eq!(serde_json::from_str::<Time>(&json).unwrap(), time);
+    }
+
+    #[test]
+    fn display_roundtrips_across_the_whole_range() {
+        for (nanos, display) in [
+            (0, "1970-01-01 00:00:00 UTC"),
+            (1_000_000, "1970-01-01 00:00:00.001 UTC"),
+            (
+                1_659_357_296_123_456_789,
+                "2022-08-01 12:34:56.123456789 UTC",
+            ),
+            (i64::MAX as u64 + 1, "2262-04-11 23:47:16.854775808 UTC"),
+            (u64::MAX, "2554-07-21 23:34:33.709551615 UTC"),
+        ] {
+            let time = Time::from_nanos_since_unix_epoch(nanos);
+            assert_eq!(time.to_string(), display);
+            assert_eq!(display.parse::<Time>(), Ok(time));
+        }
+    }
+
+    #[test]
+    fn parses_rfc3339_and_nanos() {
+        let time = Time::from_nanos_since_unix_epoch(1_659_357_296_123_456_789);
+        assert_eq!("2022-08-01T14:34:56.123456789+02:00".parse(), Ok(time));
+        assert_eq!("1659357296123456789ns".parse(), Ok(time));
+        assert_eq!(
+            "2262-04-11T23:47:16.854775808Z".parse(),
+            Ok(Time(i64::MAX as u64 + 1))
+        );
+
+        for invalid in [
+            "",
+            "ns",
+            "-1ns",
+            "1969-12-31 23:59:59 UTC",
+            "2554-07-21 23:34:33.709551616 UTC",
+            "2022-08-01 12:34:56 CET",
+            "2022-08-01 12:34:56",
+        ] {
+            assert!(invalid.parse::<Time>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn stopwatch_measures_manual_clock() {
+        let clock = std::sync::Arc::new(ManualClock::new());
+        let mut stopwatch = Stopwatch::start(clock.clone());
+        assert_eq!(stopwatch.elapsed(), Duration::ZERO);
+
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(stopwatch.elapsed(), Duration::from_millis(1500));
+        assert_eq!(stopwatch.restart(), Duration::from_millis(1500));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(stopwatch.elapsed(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn system_clock_is_monotonic() {
+        let clock = SystemClock::new();
+        let earlier = clock.now();
+        assert!(clock.now() >= earlier);
+        assert_eq!(
+            earlier.saturating_duration_since(clock.now()),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn monotonic_time_does_not_go_back() {
+        let regressions = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let time = MonotonicTime::with_regression_hook({
+            let regressions = std::sync::Arc::clone(&regressions);
+            move |by| regressions.lock().unwrap().push(by)
+        });
+        assert_eq!(time.get(), UNIX_EPOCH);
+
+        assert_eq!(time.observe(at_secs(10)), at_secs(10));
+        assert_eq!(time.observe(at_secs(7)), at_secs(10));
+        assert_eq!(time.observe(at_secs(10)), at_secs(10));
+        assert_eq!(time.observe(at_secs(12)), at_secs(12));
+        assert_eq!(time.get(), at_secs(12));
+        assert_eq!(*regressions.lock().unwrap(), vec![Duration::from_secs(3)]);
+    }
+
+    proptest! {
+        #[test]
+        fn expiry_strategy_stays_within_ingress_ttl(
+            (now, expiry) in strategies::time_in(UNIX_EPOCH, Time(u64::MAX / 2))
+                .prop_flat_map(|now| (Just(now), strategies::expiry_within_ingress_ttl(now)))
+        ) {
+            prop_assert!(now <= expiry);
+            prop_assert!(expiry <= now + MAX_INGRESS_TTL + PERMITTED_DRIFT);
+        }
+
+        #[test]
+        fn display_roundtrips(time in any::<Time>()) {
+            prop_assert_eq!(time.to_string().parse::<Time>(), Ok(time));
+        }
+
+        #[test]
+        fn parsed_times_roundtrip(s in "[0-9]{4}-[0-9]{2}-[0-9]{2}[ T][0-9]{2}:[0-9]{2}:[0-9]{2}(\\.[0-9]{1,12})?( UTC|Z|[+-][0-9]{2}:[0-9]{2})") {
+            if let Ok(time) = s.parse::<Time>() {
+                prop_assert_eq!(time.to_string().parse::<Time>(), Ok(time));
+            }
+        }
+    }
+}